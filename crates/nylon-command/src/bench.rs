@@ -0,0 +1,860 @@
+//! Load test of the proxy against a loopback upstream
+//!
+//! A reference config is written to a temporary directory and served by a
+//! child `nylon run`, in front of an upstream in this process that answers
+//! every request with `ok` and echoes WebSocket frames. Each scenario drives
+//! keep-alive connections for a fixed time and reports throughput and
+//! latency; results can be saved and compared with an earlier run.
+
+use crate::handler::{Result, ServiceError};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Load before a scenario is measured
+const WARMUP: Duration = Duration::from_secs(1);
+
+/// How long the child proxy gets to start listening
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BenchScenario {
+    /// Proxy to the upstream with no middleware
+    Plain,
+    /// Request and response header modifiers
+    Headers,
+    /// A Lua script in the request and response phases
+    Lua,
+    /// A WASM plugin session that reads the path and answers each phase
+    Plugin,
+    /// WebSocket messages echoed through the proxy
+    Websocket,
+}
+
+impl BenchScenario {
+    fn name(self) -> &'static str {
+        match self {
+            BenchScenario::Plain => "plain",
+            BenchScenario::Headers => "headers",
+            BenchScenario::Lua => "lua",
+            BenchScenario::Plugin => "plugin",
+            BenchScenario::Websocket => "websocket",
+        }
+    }
+
+    fn host(self) -> &'static str {
+        match self {
+            BenchScenario::Plain | BenchScenario::Websocket => "plain.bench",
+            BenchScenario::Headers => "headers.bench",
+            BenchScenario::Lua => "lua.bench",
+            BenchScenario::Plugin => "plugin.bench",
+        }
+    }
+}
+
+pub struct BenchOptions {
+    /// Version of the binary under test, recorded with the results
+    pub version: String,
+    pub duration_secs: u64,
+    pub connections: usize,
+    pub scenarios: Vec<BenchScenario>,
+    /// Save the results as JSON
+    pub output: Option<String>,
+    /// Results of an earlier run to compare with
+    pub baseline: Option<String>,
+    /// Allowed regression against the baseline, in percent
+    pub tolerance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioResult {
+    pub scenario: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub rps: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub version: String,
+    pub duration_secs: u64,
+    pub connections: usize,
+    pub results: Vec<ScenarioResult>,
+}
+
+const CONFIG_YAML: &str = r#"http:
+  - 127.0.0.1:{proxy_port}
+config_dir: "{dir}/proxy"
+acme: "{dir}/acme"
+pingora:
+  daemon: false
+  grace_period_seconds: 0
+  graceful_shutdown_timeout_seconds: 0
+"#;
+
+const PROXY_YAML: &str = r#"plugins:
+  - name: bench
+    type: wasm
+    file: "{dir}/bench.wat"
+
+services:
+  - name: upstream
+    service_type: http
+    algorithm: round_robin
+    endpoints:
+      - ip: 127.0.0.1
+        port: {upstream_port}
+
+routes:
+  - route:
+      type: host
+      value: plain.bench
+    name: plain
+    paths:
+      - path:
+          - /
+          - /{*path}
+        service:
+          name: upstream
+
+  - route:
+      type: host
+      value: headers.bench
+    name: headers
+    paths:
+      - path:
+          - /
+          - /{*path}
+        service:
+          name: upstream
+        middleware:
+          - plugin: RequestHeaderModifier
+            payload:
+              set:
+                - name: x-request-id
+                  value: "${uuid(v7)}"
+                - name: x-forwarded-for
+                  value: "${request(client_ip)}"
+          - plugin: ResponseHeaderModifier
+            payload:
+              set:
+                - name: cache-control
+                  value: no-store
+
+  - route:
+      type: host
+      value: lua.bench
+    name: lua
+    paths:
+      - path:
+          - /
+          - /{*path}
+        service:
+          name: upstream
+        middleware:
+          - plugin: Lua
+            payload:
+              request: |
+                nylon.set_request_header("x-bench-path", nylon.req.path)
+              response: |
+                nylon.set_response_header("x-served-by", "nylon")
+
+  - route:
+      type: host
+      value: plugin.bench
+    name: plugin
+    paths:
+      - path:
+          - /
+          - /{*path}
+        service:
+          name: upstream
+        middleware:
+          - plugin: bench
+            entry: bench
+"#;
+
+/// Plugin for the `plugin` scenario: at the start of a phase it asks for the
+/// request path (method 204) and answers the reply with `NEXT` (method 1), so
+/// every request opens a session and makes two round trips through it
+const PLUGIN_WAT: &str = r#"(module
+  (import "nylon" "emit" (func $emit (param i32 i32 i32 i32 i32)))
+  (memory (export "memory") 1)
+  (func (export "nylon_alloc") (param $len i32) (result i32)
+    (if (i32.gt_u (local.get $len) (i32.const 60000)) (then unreachable))
+    (i32.const 1024))
+  (func (export "register_session_stream") (param i32 i32 i32) (result i32)
+    (i32.const 1))
+  (func (export "event_stream")
+    (param $sid i32) (param $phase i32) (param $method i32) (param $ptr i32) (param $len i32)
+    (if (i32.eqz (local.get $method))
+      (then
+        (call $emit (local.get $sid) (local.get $phase) (i32.const 204) (i32.const 0) (i32.const 0)))
+      (else
+        (if (i32.eq (local.get $method) (i32.const 204))
+          (then
+            (call $emit (local.get $sid) (local.get $phase) (i32.const 1) (i32.const 0) (i32.const 0)))))))
+  (func (export "close_session_stream") (param i32)))
+"#;
+
+/// Run the scenarios; fails on errors or a regression against the baseline
+pub fn run(options: BenchOptions, socket_path: &str) -> Result<()> {
+    refuse_running_daemon(socket_path)?;
+    let scenarios = if options.scenarios.is_empty() {
+        BenchScenario::value_variants().to_vec()
+    } else {
+        options.scenarios.clone()
+    };
+    let baseline = match &options.baseline {
+        Some(path) => {
+            let content = fs::read_to_string(path)?;
+            Some(serde_json::from_str::<BenchReport>(&content).map_err(|e| {
+                ServiceError::Operation(format!("Invalid baseline {}: {}", path, e))
+            })?)
+        }
+        None => None,
+    };
+
+    let upstream = TcpListener::bind("127.0.0.1:0")?;
+    let upstream_port = upstream.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in upstream.incoming().flatten() {
+            thread::spawn(move || serve_upstream(stream));
+        }
+    });
+
+    let proxy = ChildProxy::start(upstream_port)?;
+    println!(
+        "Proxy on 127.0.0.1:{}, upstream on 127.0.0.1:{}, {} connections, {}s per scenario",
+        proxy.port, upstream_port, options.connections, options.duration_secs
+    );
+
+    let duration = Duration::from_secs(options.duration_secs.max(1));
+    let connections = options.connections.max(1);
+    let mut results = vec![];
+    for scenario in scenarios {
+        drive(proxy.port, scenario, connections, WARMUP);
+        let samples = drive(proxy.port, scenario, connections, duration);
+        let result = summarize(scenario, samples, duration);
+        results.push(result);
+    }
+    drop(proxy);
+
+    println!(
+        "{:<10} {:>10} {:>8} {:>10} {:>9} {:>9} {:>9} {:>9}",
+        "SCENARIO", "REQUESTS", "ERRORS", "RPS", "P50 MS", "P90 MS", "P99 MS", "MAX MS"
+    );
+    for r in &results {
+        println!(
+            "{:<10} {:>10} {:>8} {:>10.0} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            r.scenario, r.requests, r.errors, r.rps, r.p50_ms, r.p90_ms, r.p99_ms, r.max_ms
+        );
+    }
+
+    let report = BenchReport {
+        version: options.version.clone(),
+        duration_secs: duration.as_secs(),
+        connections,
+        results,
+    };
+    if let Some(path) = &options.output {
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| ServiceError::Operation(e.to_string()))?;
+        fs::write(path, json)?;
+        println!("Results saved to {}", path);
+    }
+
+    let mut failures = vec![];
+    for r in &report.results {
+        if r.errors > 0 {
+            failures.push(format!("{}: {} errors", r.scenario, r.errors));
+        }
+    }
+    if let Some(baseline) = &baseline {
+        failures.extend(regressions(baseline, &report, options.tolerance));
+    }
+    if !failures.is_empty() {
+        for failure in &failures {
+            println!("FAIL {}", failure);
+        }
+        return Err(ServiceError::Operation(format!(
+            "{} benchmark checks failed",
+            failures.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A second proxy would take over the command socket of the daemon
+fn refuse_running_daemon(socket_path: &str) -> Result<()> {
+    #[cfg(unix)]
+    if std::os::unix::net::UnixStream::connect(socket_path).is_ok() {
+        return Err(ServiceError::Operation(format!(
+            "A nylon daemon is listening on {}; stop it before running the benchmark",
+            socket_path
+        )));
+    }
+    #[cfg(not(unix))]
+    let _ = socket_path;
+    Ok(())
+}
+
+/// Drops more than `tolerance` percent in RPS, or rises in p99 latency
+fn regressions(baseline: &BenchReport, current: &BenchReport, tolerance: f64) -> Vec<String> {
+    let factor = tolerance / 100.0;
+    let mut failures = vec![];
+    for r in &current.results {
+        let Some(base) = baseline.results.iter().find(|b| b.scenario == r.scenario) else {
+            continue;
+        };
+        if r.rps < base.rps * (1.0 - factor) {
+            failures.push(format!(
+                "{}: {:.0} RPS, baseline {:.0}",
+                r.scenario, r.rps, base.rps
+            ));
+        }
+        if r.p99_ms > base.p99_ms * (1.0 + factor) {
+            failures.push(format!(
+                "{}: p99 {:.2}ms, baseline {:.2}ms",
+                r.scenario, r.p99_ms, base.p99_ms
+            ));
+        }
+    }
+    failures
+}
+
+/// A `nylon run` of the reference config, killed when dropped
+struct ChildProxy {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+}
+
+impl ChildProxy {
+    fn start(upstream_port: u16) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("nylon-bench-{}", std::process::id()));
+        fs::create_dir_all(dir.join("proxy"))?;
+        fs::create_dir_all(dir.join("acme"))?;
+        // Free a port for the proxy; it binds it again right after
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let config_path = dir.join("config.yaml");
+        fs::write(
+            &config_path,
+            CONFIG_YAML
+                .replace("{proxy_port}", &port.to_string())
+                .replace("{dir}", &dir.to_string_lossy()),
+        )?;
+        fs::write(
+            dir.join("proxy").join("proxy.yaml"),
+            PROXY_YAML
+                .replace("{upstream_port}", &upstream_port.to_string())
+                .replace("{dir}", &dir.to_string_lossy()),
+        )?;
+        fs::write(dir.join("bench.wat"), PLUGIN_WAT)?;
+
+        let exe =
+            std::env::current_exe().map_err(|e| ServiceError::ExecutablePath(e.to_string()))?;
+        let child = Command::new(exe)
+            .arg("run")
+            .arg("-c")
+            .arg(&config_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut proxy = ChildProxy { child, dir, port };
+
+        let started = Instant::now();
+        loop {
+            if request(port, BenchScenario::Plain.host()).is_ok() {
+                return Ok(proxy);
+            }
+            if let Ok(Some(status)) = proxy.child.try_wait() {
+                return Err(ServiceError::Operation(format!(
+                    "Proxy exited during startup: {}",
+                    status
+                )));
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                return Err(ServiceError::Operation(
+                    "Proxy did not start in time".to_string(),
+                ));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for ChildProxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Latencies in microseconds and the number of errors
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+fn drive(port: u16, scenario: BenchScenario, connections: usize, duration: Duration) -> Samples {
+    let deadline = Instant::now() + duration;
+    let workers = (0..connections)
+        .map(|_| thread::spawn(move || worker(port, scenario, deadline)))
+        .collect::<Vec<_>>();
+    let mut samples = Samples::default();
+    for worker in workers {
+        if let Ok(s) = worker.join() {
+            samples.latencies.extend(s.latencies);
+            samples.errors += s.errors;
+        }
+    }
+    samples
+}
+
+/// Send requests on one connection until the deadline, reconnecting after errors
+fn worker(port: u16, scenario: BenchScenario, deadline: Instant) -> Samples {
+    let mut samples = Samples::default();
+    let mut conn: Option<Connection> = None;
+    while Instant::now() < deadline {
+        let mut c = match conn.take() {
+            Some(c) => c,
+            None => match Connection::open(port, scenario) {
+                Ok(c) => c,
+                Err(_) => {
+                    samples.errors += 1;
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            },
+        };
+        let started = Instant::now();
+        let ok = match scenario {
+            BenchScenario::Websocket => c.echo(),
+            _ => c.get(scenario.host()),
+        };
+        match ok {
+            Ok(()) => {
+                samples.latencies.push(started.elapsed().as_micros() as u64);
+                conn = Some(c);
+            }
+            Err(_) => samples.errors += 1,
+        }
+    }
+    samples
+}
+
+fn summarize(scenario: BenchScenario, mut samples: Samples, duration: Duration) -> ScenarioResult {
+    samples.latencies.sort_unstable();
+    let percentile = |p: f64| {
+        if samples.latencies.is_empty() {
+            return 0.0;
+        }
+        let index = ((samples.latencies.len() - 1) as f64 * p).round() as usize;
+        samples.latencies[index] as f64 / 1000.0
+    };
+    ScenarioResult {
+        scenario: scenario.name().to_string(),
+        requests: samples.latencies.len() as u64,
+        errors: samples.errors,
+        rps: samples.latencies.len() as f64 / duration.as_secs_f64(),
+        p50_ms: percentile(0.50),
+        p90_ms: percentile(0.90),
+        p99_ms: percentile(0.99),
+        max_ms: percentile(1.0),
+    }
+}
+
+/// A keep-alive client connection with bytes read past the last response
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn open(port: u16, scenario: BenchScenario) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut conn = Connection {
+            stream,
+            buf: Vec::with_capacity(4096),
+        };
+        if scenario == BenchScenario::Websocket {
+            conn.upgrade(scenario.host())?;
+        }
+        Ok(conn)
+    }
+
+    fn get(&mut self, host: &str) -> std::io::Result<()> {
+        write!(
+            self.stream,
+            "GET /bench HTTP/1.1\r\nHost: {}\r\nUser-Agent: nylon-bench\r\n\r\n",
+            host
+        )?;
+        let status = self.read_response()?;
+        if status != 200 {
+            return Err(invalid(format!("status {}", status)));
+        }
+        Ok(())
+    }
+
+    fn upgrade(&mut self, host: &str) -> std::io::Result<()> {
+        write!(
+            self.stream,
+            "GET /ws HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            host
+        )?;
+        let (status, _) = self.read_head()?;
+        if status != 101 {
+            return Err(invalid(format!("upgrade answered with status {}", status)));
+        }
+        Ok(())
+    }
+
+    /// Send a masked text frame and wait for the echo
+    fn echo(&mut self) -> std::io::Result<()> {
+        const MESSAGE: &[u8] = b"nylon-bench";
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | MESSAGE.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(MESSAGE.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame)?;
+        let payload = read_frame(&mut self.stream, &mut self.buf)?;
+        if payload != MESSAGE {
+            return Err(invalid("echo does not match".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Read a response and its body; returns the status
+    fn read_response(&mut self) -> std::io::Result<u16> {
+        let (status, head) = self.read_head()?;
+        let head = head.to_ascii_lowercase();
+        let length = head.lines().find_map(|line| {
+            line.strip_prefix("content-length:")
+                .and_then(|v| v.trim().parse::<usize>().ok())
+        });
+        match length {
+            Some(length) => {
+                fill(&mut self.stream, &mut self.buf, length)?;
+                self.buf.drain(..length);
+            }
+            None if head.contains("transfer-encoding: chunked") => loop {
+                let end = fill_until(&mut self.stream, &mut self.buf, b"\r\n")?;
+                let size =
+                    usize::from_str_radix(String::from_utf8_lossy(&self.buf[..end]).trim(), 16)
+                        .map_err(|e| invalid(e.to_string()))?;
+                self.buf.drain(..end + 2);
+                fill(&mut self.stream, &mut self.buf, size + 2)?;
+                self.buf.drain(..size + 2);
+                if size == 0 {
+                    break;
+                }
+            },
+            None => return Err(invalid("response has no length".to_string())),
+        }
+        Ok(status)
+    }
+
+    /// Read a response head; returns the status and the head
+    fn read_head(&mut self) -> std::io::Result<(u16, String)> {
+        let end = fill_until(&mut self.stream, &mut self.buf, b"\r\n\r\n")?;
+        let head = String::from_utf8_lossy(&self.buf[..end]).to_string();
+        self.buf.drain(..end + 4);
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| invalid("malformed status line".to_string()))?;
+        Ok((status, head))
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Read until `buf` holds at least `len` bytes
+fn fill(stream: &mut impl Read, buf: &mut Vec<u8>, len: usize) -> std::io::Result<()> {
+    let mut chunk = [0u8; 4096];
+    while buf.len() < len {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(())
+}
+
+/// Read until `buf` contains `pattern`; returns where it starts
+fn fill_until(stream: &mut impl Read, buf: &mut Vec<u8>, pattern: &[u8]) -> std::io::Result<usize> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(pos) = buf.windows(pattern.len()).position(|w| w == pattern) {
+            return Ok(pos);
+        }
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Read one WebSocket frame and return its unmasked payload
+fn read_frame(stream: &mut impl Read, buf: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
+    fill(stream, buf, 2)?;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut offset) = match buf[1] & 0x7f {
+        126 => {
+            fill(stream, buf, 4)?;
+            (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4)
+        }
+        127 => return Err(invalid("frame too large".to_string())),
+        len => (len as usize, 2),
+    };
+    let mask = if masked {
+        fill(stream, buf, offset + 4)?;
+        let mask = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    fill(stream, buf, offset + len)?;
+    let mut payload = buf[offset..offset + len].to_vec();
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    buf.drain(..offset + len);
+    Ok(payload)
+}
+
+/// Answer `ok` to every request, or echo frames after a WebSocket upgrade
+fn serve_upstream(mut stream: TcpStream) {
+    let _ = stream.set_nodelay(true);
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        let Ok(end) = fill_until(&mut stream, &mut buf, b"\r\n\r\n") else {
+            return;
+        };
+        let head = String::from_utf8_lossy(&buf[..end]).to_string();
+        buf.drain(..end + 4);
+        let lower = head.to_ascii_lowercase();
+        if let Some(length) = lower.lines().find_map(|line| {
+            line.strip_prefix("content-length:")
+                .and_then(|v| v.trim().parse::<usize>().ok())
+        }) {
+            if fill(&mut stream, &mut buf, length).is_err() {
+                return;
+            }
+            buf.drain(..length);
+        }
+
+        let key = head.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("sec-websocket-key")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(key) = key {
+            let accept = openssl::base64::encode_block(&openssl::sha::sha1(
+                format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes(),
+            ));
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            );
+            if stream.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+            echo_frames(stream, buf);
+            return;
+        }
+
+        let response =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nok";
+        if stream.write_all(response).is_err() {
+            return;
+        }
+        if lower.contains("connection: close") {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+fn echo_frames(mut stream: TcpStream, mut buf: Vec<u8>) {
+    while let Ok(payload) = read_frame(&mut stream, &mut buf) {
+        let mut frame = vec![0x81];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&payload);
+        if stream.write_all(&frame).is_err() {
+            return;
+        }
+    }
+}
+
+/// A single request on its own connection, to tell when the proxy is up
+fn request(port: u16, host: &str) -> std::io::Result<()> {
+    let mut conn = Connection::open(port, BenchScenario::Plain)?;
+    conn.get(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(scenario: &str, rps: f64, p99_ms: f64) -> ScenarioResult {
+        ScenarioResult {
+            scenario: scenario.to_string(),
+            requests: 1000,
+            errors: 0,
+            rps,
+            p50_ms: 1.0,
+            p90_ms: 2.0,
+            p99_ms,
+            max_ms: p99_ms * 2.0,
+        }
+    }
+
+    fn report(results: Vec<ScenarioResult>) -> BenchReport {
+        BenchReport {
+            version: "test".to_string(),
+            duration_secs: 10,
+            connections: 32,
+            results,
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = report(vec![
+            result("plain", 1000.0, 10.0),
+            result("lua", 500.0, 20.0),
+        ]);
+
+        // Within the tolerance either way
+        let current = report(vec![
+            result("plain", 950.0, 10.5),
+            result("lua", 600.0, 15.0),
+        ]);
+        assert!(regressions(&baseline, &current, 10.0).is_empty());
+
+        let current = report(vec![
+            result("plain", 800.0, 10.0),
+            result("lua", 500.0, 25.0),
+        ]);
+        assert_eq!(
+            regressions(&baseline, &current, 10.0),
+            vec![
+                "plain: 800 RPS, baseline 1000".to_string(),
+                "lua: p99 25.00ms, baseline 20.00ms".to_string(),
+            ]
+        );
+
+        // Scenarios missing from the baseline are not compared
+        let current = report(vec![result("plugin", 1.0, 1000.0)]);
+        assert!(regressions(&baseline, &current, 10.0).is_empty());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let samples = Samples {
+            latencies: (1..=100).rev().map(|ms| ms * 1000).collect(),
+            errors: 3,
+        };
+        let r = summarize(BenchScenario::Plugin, samples, Duration::from_secs(2));
+        assert_eq!(r.scenario, "plugin");
+        assert_eq!(r.requests, 100);
+        assert_eq!(r.errors, 3);
+        assert_eq!(r.rps, 50.0);
+        assert_eq!(r.p50_ms, 51.0);
+        assert_eq!(r.p90_ms, 90.0);
+        assert_eq!(r.p99_ms, 99.0);
+        assert_eq!(r.max_ms, 100.0);
+    }
+
+    #[test]
+    fn test_summarize_without_samples() {
+        let samples = Samples {
+            latencies: vec![],
+            errors: 5,
+        };
+        let r = summarize(BenchScenario::Plain, samples, Duration::from_secs(1));
+        assert_eq!(r.requests, 0);
+        assert_eq!(r.errors, 5);
+        assert_eq!(r.rps, 0.0);
+        assert_eq!(r.p99_ms, 0.0);
+    }
+
+    #[test]
+    fn test_read_frame() {
+        // Unmasked, followed by the start of the next frame
+        let mut stream: &[u8] = &[0x81, 0x02, b'o', b'k', 0x81];
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut stream, &mut buf).unwrap(), b"ok");
+        assert_eq!(buf, vec![0x81]);
+
+        // Masked
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        let mut stream = frame.as_slice();
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut stream, &mut buf).unwrap(), b"hello");
+        assert!(buf.is_empty());
+
+        // 16-bit length
+        let payload = vec![b'x'; 300];
+        let mut frame = vec![0x82, 126];
+        frame.extend_from_slice(&300u16.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        let mut stream = frame.as_slice();
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut stream, &mut buf).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_read_frame_errors() {
+        let mut stream: &[u8] = &[0x82, 127, 0, 0];
+        let err = read_frame(&mut stream, &mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut stream: &[u8] = &[0x81, 0x05, b'o'];
+        let err = read_frame(&mut stream, &mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
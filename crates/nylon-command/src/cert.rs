@@ -0,0 +1,39 @@
+use clap::Subcommand;
+
+#[derive(Debug, Subcommand)]
+pub enum CertCommands {
+    // List certificates loaded by the daemon
+    #[command(name = "list")]
+    #[command(about = "List certificates currently served by the running daemon.")]
+    List,
+
+    // Force renewal of an ACME certificate
+    #[command(name = "renew")]
+    #[command(about = "Renew the ACME certificate for a domain now.")]
+    Renew {
+        #[arg(help = "Domain name, example: example.com")]
+        domain: String,
+    },
+
+    // Revoke an ACME certificate
+    #[command(name = "revoke")]
+    #[command(about = "Revoke the ACME certificate for a domain and drop it from the daemon.")]
+    Revoke {
+        #[arg(help = "Domain name, example: example.com")]
+        domain: String,
+    },
+
+    // Import an existing certificate
+    #[command(name = "import")]
+    #[command(about = "Import a PEM certificate and key for a domain into the running daemon.")]
+    Import {
+        #[arg(help = "Domain name, example: example.com")]
+        domain: String,
+        #[arg(long, help = "Path to the PEM encoded certificate")]
+        cert: String,
+        #[arg(long, help = "Path to the PEM encoded private key")]
+        key: String,
+        #[arg(long, help = "Path to a PEM encoded intermediate chain (repeatable)")]
+        chain: Vec<String>,
+    },
+}
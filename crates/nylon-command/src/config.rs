@@ -0,0 +1,33 @@
+use clap::{Subcommand, ValueEnum};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConfigKind {
+    /// The file passed with `-c`
+    Runtime,
+    /// A file in `config_dir`
+    Proxy,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    // Print the JSON Schema of a config file
+    #[command(name = "schema")]
+    #[command(about = "Print the JSON Schema of the runtime config or of a proxy config file.")]
+    Schema {
+        #[arg(value_enum, help = "Which config file to describe")]
+        kind: ConfigKind,
+    },
+
+    // List the configurations applied by the daemon
+    #[command(name = "history")]
+    #[command(about = "List the configurations the running daemon applied, newest first.")]
+    History,
+
+    // Apply an earlier configuration again
+    #[command(name = "rollback")]
+    #[command(about = "Apply a configuration from the history again, without reading the files.")]
+    Rollback {
+        #[arg(help = "Version from `nylon config history`, example: 3")]
+        version: u64,
+    },
+}
@@ -1,4 +1,12 @@
+use crate::cert::CertCommands;
+use crate::config::ConfigCommands;
+use crate::paths::{
+    DEFAULT_ACME_DIR, DEFAULT_CONFIG_PATH, DEFAULT_PROXY_CONFIG_DIR, DEFAULT_STATIC_DIR, NYLON_DIR,
+};
+use crate::plugin::PluginCommands;
+use crate::proxy::{OutputFormat, ProxyCommands};
 use crate::service::ServiceCommands;
+use crate::socket::{CommandRequest, CommandResponse, send_request};
 use service_manager::*;
 use std::env;
 use std::ffi::OsString;
@@ -9,12 +17,8 @@ use tracing::{error, info, warn};
 
 const SERVICE_NAME: &str = "nylon";
 const SERVICE_DESCRIPTION: &str = "Nylon - The Extensible Proxy Server";
-const DEFAULT_CONFIG_PATH: &str = "/etc/nylon/config.yaml";
-const DEFAULT_PROXY_CONFIG_DIR: &str = "/etc/nylon/proxy";
-const DEFAULT_ACME_DIR: &str = "/etc/nylon/acme";
-const DEFAULT_STATIC_DIR: &str = "/etc/nylon/static";
 
-// Default configuration template
+// Default configuration template, with /etc/nylon standing for the platform's NYLON_DIR
 const DEFAULT_CONFIG_YAML: &str = r#"# Nylon Proxy Server Configuration
 # Generated automatically during installation
 
@@ -131,7 +135,7 @@ fn create_default_config() -> Result<()> {
     // Create main config file if it doesn't exist
     if !Path::new(DEFAULT_CONFIG_PATH).exists() {
         info!("Creating default config: {}", DEFAULT_CONFIG_PATH);
-        fs::write(DEFAULT_CONFIG_PATH, DEFAULT_CONFIG_YAML)?;
+        fs::write(DEFAULT_CONFIG_PATH, for_platform(DEFAULT_CONFIG_YAML))?;
         info!("✓ Default config created");
     } else {
         warn!(
@@ -153,7 +157,7 @@ fn create_default_config() -> Result<()> {
     let base_proxy_path = format!("{}/base.yaml", DEFAULT_PROXY_CONFIG_DIR);
     if !Path::new(&base_proxy_path).exists() {
         info!("Creating base proxy config: {}", base_proxy_path);
-        fs::write(&base_proxy_path, DEFAULT_PROXY_YAML)?;
+        fs::write(&base_proxy_path, for_platform(DEFAULT_PROXY_YAML))?;
         info!("✓ Base proxy config created");
     }
 
@@ -180,8 +184,13 @@ fn create_default_config() -> Result<()> {
     Ok(())
 }
 
+/// Point the templates at the platform's config directory
+fn for_platform(template: &str) -> String {
+    template.replace("/etc/nylon", NYLON_DIR)
+}
+
 /// Handle service commands
-pub fn handle_service_command(command: ServiceCommands) -> Result<()> {
+pub fn handle_service_command(command: ServiceCommands, socket_path: &str) -> Result<()> {
     match command {
         ServiceCommands::Install => install_service(),
         ServiceCommands::Uninstall => uninstall_service(),
@@ -189,7 +198,8 @@ pub fn handle_service_command(command: ServiceCommands) -> Result<()> {
         ServiceCommands::Stop => stop_service(),
         ServiceCommands::Restart => restart_service(),
         ServiceCommands::Status => status_service(),
-        ServiceCommands::Reload => reload_service(),
+        ServiceCommands::Reload => reload_service(socket_path),
+        ServiceCommands::Upgrade => upgrade_service(socket_path),
     }
 }
 
@@ -212,14 +222,20 @@ fn install_service() -> Result<()> {
     // Create custom systemd service content with reload support
     let service_contents = create_systemd_service_content(&exe_path);
 
+    #[allow(unused_mut)]
+    let mut args = vec![
+        OsString::from("run"),
+        OsString::from("-c"),
+        OsString::from(DEFAULT_CONFIG_PATH),
+    ];
+    // The service control manager waits for the process to report that it started
+    #[cfg(windows)]
+    args.push(OsString::from("--service"));
+
     let service = ServiceInstallCtx {
         label: label.clone(),
         program: exe_path,
-        args: vec![
-            OsString::from("run"),
-            OsString::from("-c"),
-            OsString::from(DEFAULT_CONFIG_PATH),
-        ],
+        args,
         contents: service_contents,
         username: None,
         working_directory: None,
@@ -239,7 +255,7 @@ fn install_service() -> Result<()> {
     info!("  • ACME certs: {}", DEFAULT_ACME_DIR);
     info!("");
     info!("Service features:");
-    info!("  • Reload config without restart: systemctl reload nylon");
+    info!("  • Reload config without restart: nylon service reload");
     info!("  • Auto-restart on failure");
     info!("");
     info!("Next steps:");
@@ -249,7 +265,7 @@ fn install_service() -> Result<()> {
     );
     info!("  2. Start the service: nylon service start");
     info!("  3. Visit http://localhost:8088");
-    info!("  4. Reload config anytime: systemctl reload nylon");
+    info!("  4. Reload config anytime: nylon service reload");
 
     Ok(())
 }
@@ -264,7 +280,8 @@ Description={}
 After=network.target
 
 [Service]
-Type=simple
+Type=notify
+NotifyAccess=all
 ExecStart={} run -c {}
 ExecStop=/usr/bin/pkill -9 {}
 ExecReload=/usr/bin/pkill -HUP {}
@@ -422,9 +439,33 @@ fn status_service() -> Result<()> {
 }
 
 /// Reload the service configuration
-fn reload_service() -> Result<()> {
+fn reload_service(socket_path: &str) -> Result<()> {
     info!("Reloading {} service configuration...", SERVICE_NAME);
 
+    // Prefer the command socket, it returns a report of what the reload changed
+    match send_request(socket_path, &CommandRequest::Reload) {
+        Ok(response) => {
+            if let Some(data) = &response.data {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(data).unwrap_or_else(|_| data.to_string())
+                );
+            }
+            if response.ok {
+                info!("✓ {}", response.message);
+                return Ok(());
+            }
+            error!("{}", response.message);
+            return Err(ServiceError::Operation(response.message));
+        }
+        Err(e) => {
+            warn!(
+                "Command socket unavailable ({}), falling back to a reload signal",
+                e
+            );
+        }
+    }
+
     // For reload, we need to send a signal to the running process
     // This is platform-specific and might require additional implementation
 
@@ -456,3 +497,174 @@ fn reload_service() -> Result<()> {
 
     Ok(())
 }
+
+/// Upgrade the running daemon to this binary
+///
+/// The daemon starts the binary itself, so the new process stays under the
+/// same service manager, and passes its listen sockets over.
+fn upgrade_service(socket_path: &str) -> Result<()> {
+    let binary = get_executable_path()?;
+    info!(
+        "Upgrading {} service to {}...",
+        SERVICE_NAME,
+        binary.display()
+    );
+    let request = CommandRequest::Upgrade {
+        binary: binary.to_string_lossy().to_string(),
+    };
+    let response =
+        send_request(socket_path, &request).map_err(|e| daemon_unreachable(socket_path, e))?;
+    if !response.ok {
+        error!("{}", response.message);
+        return Err(ServiceError::Operation(response.message));
+    }
+    info!("✓ {}", response.message);
+    info!("The old process exits once its connections finish");
+    Ok(())
+}
+
+/// Handle certificate commands by forwarding them to the running daemon
+pub fn handle_cert_command(command: CertCommands, socket_path: &str) -> Result<()> {
+    let request = match command {
+        CertCommands::List => CommandRequest::CertList,
+        CertCommands::Renew { domain } => CommandRequest::CertRenew { domain },
+        CertCommands::Revoke { domain } => CommandRequest::CertRevoke { domain },
+        CertCommands::Import {
+            domain,
+            cert,
+            key,
+            chain,
+        } => {
+            let chain = chain
+                .iter()
+                .map(fs::read_to_string)
+                .collect::<io::Result<Vec<_>>>()?;
+            CommandRequest::CertImport {
+                domain,
+                cert: fs::read_to_string(&cert)?,
+                key: fs::read_to_string(&key)?,
+                chain,
+            }
+        }
+    };
+    forward_request(&request, socket_path)
+}
+
+/// Handle plugin commands; list and reload are forwarded to the running daemon
+pub fn handle_plugin_command(
+    command: PluginCommands,
+    socket_path: &str,
+    version: &str,
+) -> Result<()> {
+    let request = match command {
+        PluginCommands::List => CommandRequest::PluginList,
+        PluginCommands::Reload { name } => CommandRequest::PluginReload { name },
+        PluginCommands::New { name, lang, dir } => {
+            return crate::scaffold::new(&name, lang, &dir, version);
+        }
+        PluginCommands::Check { file } => return crate::scaffold::check(&file),
+    };
+    forward_request(&request, socket_path)
+}
+
+/// Handle config commands that need the running daemon
+pub fn handle_config_command(command: ConfigCommands, socket_path: &str) -> Result<()> {
+    let request = match command {
+        ConfigCommands::History => CommandRequest::ConfigHistory,
+        ConfigCommands::Rollback { version } => CommandRequest::ConfigRollback { version },
+        ConfigCommands::Schema { .. } => {
+            return Err(ServiceError::Operation(
+                "The schema is printed without the daemon".to_string(),
+            ));
+        }
+    };
+    forward_request(&request, socket_path)
+}
+
+/// Handle proxy commands by asking the running daemon
+pub fn handle_proxy_command(command: ProxyCommands, socket_path: &str) -> Result<()> {
+    let (request, output) = match command {
+        ProxyCommands::Routes { output } => (CommandRequest::ProxyRoutes, output),
+        ProxyCommands::Services { output } => (CommandRequest::ProxyServices, output),
+        ProxyCommands::TestRoute {
+            host,
+            path,
+            method,
+            selector,
+            output,
+            ..
+        } => (
+            CommandRequest::ProxyTestRoute {
+                host,
+                path,
+                method,
+                selector,
+            },
+            output,
+        ),
+    };
+    let response =
+        send_request(socket_path, &request).map_err(|e| daemon_unreachable(socket_path, e))?;
+    print_proxy_response(&request, response, output)
+}
+
+/// Print the answer to a proxy request as a table or as JSON
+pub fn print_proxy_response(
+    request: &CommandRequest,
+    response: CommandResponse,
+    output: OutputFormat,
+) -> Result<()> {
+    if !response.ok {
+        error!("{}", response.message);
+        return Err(ServiceError::Operation(response.message));
+    }
+    let data = response.data.unwrap_or_default();
+    match (output, request) {
+        (OutputFormat::Json, _) => println!(
+            "{}",
+            serde_json::to_string_pretty(&data).unwrap_or_else(|_| data.to_string())
+        ),
+        (OutputFormat::Table, CommandRequest::ProxyRoutes) => crate::proxy::print_routes(&data),
+        (OutputFormat::Table, CommandRequest::ProxyServices) => crate::proxy::print_services(&data),
+        (OutputFormat::Table, _) => crate::proxy::print_route_match(&data),
+    }
+    Ok(())
+}
+
+/// Run a smoke test spec against the running proxy
+pub fn handle_smoke_command(spec: &str, base_url: Option<String>) -> Result<()> {
+    crate::smoke::run(spec, base_url)
+}
+
+/// Run the load test scenarios against a proxy started for the purpose
+pub fn handle_bench_command(options: crate::bench::BenchOptions, socket_path: &str) -> Result<()> {
+    crate::bench::run(options, socket_path)
+}
+
+/// Send a request to the daemon and print its answer
+fn forward_request(request: &CommandRequest, socket_path: &str) -> Result<()> {
+    let response =
+        send_request(socket_path, request).map_err(|e| daemon_unreachable(socket_path, e))?;
+
+    if !response.ok {
+        error!("{}", response.message);
+        return Err(ServiceError::Operation(response.message));
+    }
+
+    info!("✓ {}", response.message);
+    if let Some(data) = response.data {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&data).unwrap_or_else(|_| data.to_string())
+        );
+    }
+
+    Ok(())
+}
+
+fn daemon_unreachable(socket_path: &str, e: io::Error) -> ServiceError {
+    ServiceError::Operation(format!(
+        "Failed to reach the daemon on {} (is nylon running?): {}",
+        socket_path, e
+    ))
+}
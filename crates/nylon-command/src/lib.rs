@@ -1,9 +1,29 @@
+mod bench;
+mod cert;
+mod config;
 pub mod handler;
+// shared with the server without depending on it
+#[path = "../../nylon-types/src/paths.rs"]
+pub mod paths;
+mod plugin;
+mod proxy;
+mod scaffold;
 mod service;
+mod smoke;
+pub mod socket;
 
 use clap::{Parser, Subcommand};
 
-pub use handler::{ServiceError, handle_service_command};
+pub use bench::{BenchOptions, BenchScenario};
+pub use cert::CertCommands;
+pub use config::{ConfigCommands, ConfigKind};
+pub use handler::{
+    ServiceError, handle_bench_command, handle_cert_command, handle_config_command,
+    handle_plugin_command, handle_proxy_command, handle_service_command, handle_smoke_command,
+    print_proxy_response,
+};
+pub use plugin::{PluginCommands, PluginLang};
+pub use proxy::{OutputFormat, ProxyCommands};
 pub use service::ServiceCommands;
 
 #[derive(Parser, Debug)]
@@ -20,13 +40,91 @@ pub enum Commands {
     #[command(subcommand)]
     Service(ServiceCommands),
 
+    #[command(name = "cert")]
+    #[command(
+        about = "Manage TLS certificates of the running daemon (list, renew, revoke, import)"
+    )]
+    #[command(subcommand)]
+    Cert(CertCommands),
+
+    #[command(name = "plugin")]
+    #[command(about = "Manage plugins of the running daemon (list, reload) or create new ones")]
+    #[command(subcommand)]
+    Plugin(PluginCommands),
+
+    #[command(name = "proxy")]
+    #[command(about = "Inspect the routes and services of the running daemon")]
+    #[command(subcommand)]
+    Proxy(ProxyCommands),
+
+    #[command(name = "smoke")]
+    #[command(about = "Run a smoke test spec against the running proxy")]
+    Smoke {
+        #[arg(help = "Path to the smoke test spec, example: smoke.yaml")]
+        spec: String,
+        #[arg(
+            long,
+            help = "Override the base_url of the spec, example: http://127.0.0.1:8088"
+        )]
+        base_url: Option<String>,
+    },
+
+    #[command(name = "bench")]
+    #[command(about = "Load test a proxy of a reference config against a loopback upstream")]
+    Bench {
+        #[arg(long, default_value_t = 10, help = "Seconds to run each scenario")]
+        duration: u64,
+        #[arg(long, default_value_t = 32, help = "Concurrent keep-alive connections")]
+        connections: usize,
+        #[arg(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            help = "Scenarios to run, all by default"
+        )]
+        scenario: Vec<BenchScenario>,
+        #[arg(long, help = "Save the results as JSON, example: bench.json")]
+        output: Option<String>,
+        #[arg(long, help = "Compare with the saved results of an earlier run")]
+        baseline: Option<String>,
+        #[arg(
+            long,
+            default_value_t = 10.0,
+            help = "Allowed regression against the baseline, in percent"
+        )]
+        tolerance: f64,
+    },
+
+    #[command(name = "config")]
+    #[command(about = "Describe the config file formats")]
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    #[command(name = "validate")]
+    #[command(about = "Check a config file and its config directory without starting the server")]
+    Validate {
+        #[arg(long, short = 'c', default_value = paths::DEFAULT_CONFIG_PATH)]
+        #[arg(help = "Path to the config file")]
+        config: String,
+    },
+
     // run with no command
     #[command(name = "run")]
     #[command(about = "Run the proxy server with a config file")]
     Run {
-        #[arg(long, short = 'c', default_value = "/etc/nylon/config.yaml")]
-        #[arg(help = "Path to the config file example: /etc/nylon/config.yaml")]
+        #[arg(long, short = 'c', default_value = paths::DEFAULT_CONFIG_PATH)]
+        #[arg(help = "Path to the config file")]
         config: String,
+        #[arg(long, hide = true)]
+        #[arg(
+            help = "Report to the Windows service control manager (set by `nylon service install`)"
+        )]
+        service: bool,
+        #[arg(long, hide = true)]
+        #[arg(
+            help = "Take the listen sockets over from the running daemon (set by `nylon service upgrade`)"
+        )]
+        upgrade: bool,
     },
 }
 
@@ -0,0 +1,45 @@
+use clap::{Subcommand, ValueEnum};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PluginLang {
+    Rust,
+    Go,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PluginCommands {
+    // List loaded plugin versions
+    #[command(name = "list")]
+    #[command(about = "List the plugin versions loaded by the running daemon.")]
+    List,
+
+    // Load a new version of a plugin
+    #[command(name = "reload")]
+    #[command(
+        about = "Load the plugin file again; the old version finishes its open sessions first."
+    )]
+    Reload {
+        #[arg(help = "Plugin name as configured, example: auth")]
+        name: String,
+    },
+
+    // Generate a plugin project
+    #[command(name = "new")]
+    #[command(about = "Create a shared library plugin project for this version of nylon.")]
+    New {
+        #[arg(help = "Plugin name, also the entry it registers, example: auth")]
+        name: String,
+        #[arg(long, value_enum, default_value = "rust")]
+        lang: PluginLang,
+        #[arg(long, default_value = ".", help = "Directory to create the project in")]
+        dir: String,
+    },
+
+    // Check the exports of a built plugin
+    #[command(name = "check")]
+    #[command(about = "Check that a built plugin exports the symbols nylon loads.")]
+    Check {
+        #[arg(help = "Path to the shared library, example: ./target/release/libauth.so")]
+        file: String,
+    },
+}
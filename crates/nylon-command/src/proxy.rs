@@ -0,0 +1,187 @@
+use clap::{Subcommand, ValueEnum};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProxyCommands {
+    // Show the routing table of the daemon
+    #[command(name = "routes")]
+    #[command(
+        about = "Show the routes of the running daemon with their paths, services and middleware."
+    )]
+    Routes {
+        #[arg(long, short = 'o', value_enum, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    // Show which route would handle a request
+    #[command(name = "test-route")]
+    #[command(
+        about = "Show the route, service and middleware that would handle a request, or why none does."
+    )]
+    TestRoute {
+        #[arg(long, help = "Host header, example: example.com")]
+        host: String,
+        #[arg(long, help = "Request path, example: /api/v1/users")]
+        path: String,
+        #[arg(long, short = 'X', default_value = "GET")]
+        method: String,
+        #[arg(long, help = "Value of the header_selector header")]
+        selector: Option<String>,
+        #[arg(
+            long,
+            short = 'c',
+            help = "Match against this config file instead of the running daemon"
+        )]
+        config: Option<String>,
+        #[arg(long, short = 'o', value_enum, default_value = "table")]
+        output: OutputFormat,
+    },
+
+    // Show the services of the daemon
+    #[command(name = "services")]
+    #[command(about = "Show the services of the running daemon with the health of their backends.")]
+    Services {
+        #[arg(long, short = 'o', value_enum, default_value = "table")]
+        output: OutputFormat,
+    },
+}
+
+/// One row per route path
+pub(crate) fn print_routes(routes: &Value) {
+    let mut rows = vec![];
+    for route in routes.as_array().into_iter().flatten() {
+        let matcher = format!(
+            "{}={}",
+            text(&route["match"]["type"]),
+            text(&route["match"]["value"])
+        );
+        for path in route["paths"].as_array().into_iter().flatten() {
+            let mut service = text(&path["service"]);
+            if let Some(rewrite) = path["rewrite"].as_str() {
+                service = format!("{} (rewrite {})", service, rewrite);
+            }
+            rows.push(vec![
+                text(&route["name"]),
+                matcher.clone(),
+                list(&path["path"]),
+                list(&path["methods"]),
+                service,
+                list(&path["middleware"]),
+            ]);
+        }
+    }
+    print_table(
+        &["ROUTE", "MATCH", "PATH", "METHODS", "SERVICE", "MIDDLEWARE"],
+        rows,
+    );
+}
+
+/// One row per service endpoint
+pub(crate) fn print_services(services: &Value) {
+    let mut rows = vec![];
+    for service in services.as_array().into_iter().flatten() {
+        let row = |endpoint: String, weight: String, health: String| {
+            vec![
+                text(&service["name"]),
+                text(&service["type"]),
+                text(&service["algorithm"]),
+                endpoint,
+                weight,
+                health,
+            ]
+        };
+        let endpoints = service["endpoints"].as_array().cloned().unwrap_or_default();
+        if endpoints.is_empty() {
+            rows.push(row("-".to_string(), "-".to_string(), "-".to_string()));
+        }
+        for endpoint in endpoints {
+            let health = match endpoint["healthy"].as_bool() {
+                Some(true) => "healthy",
+                Some(false) => "unhealthy",
+                None => "-",
+            };
+            rows.push(row(
+                text(&endpoint["address"]),
+                text(&endpoint["weight"]),
+                health.to_string(),
+            ));
+        }
+    }
+    print_table(
+        &[
+            "SERVICE",
+            "TYPE",
+            "ALGORITHM",
+            "ENDPOINT",
+            "WEIGHT",
+            "HEALTH",
+        ],
+        rows,
+    );
+}
+
+/// The route a request matched
+pub(crate) fn print_route_match(matched: &Value) {
+    let mut params: Vec<String> = matched["params"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| format!("{}={}", name, text(value)))
+        .collect();
+    params.sort();
+    let fields = [
+        ("ROUTE", text(&matched["route"])),
+        ("PATTERN", text(&matched["pattern"])),
+        ("SERVICE", text(&matched["service"])),
+        ("REWRITE", text(&matched["rewrite"])),
+        ("FALLBACK", text(&matched["fallback"])),
+        ("MIDDLEWARE", list(&matched["middleware"])),
+        ("PARAMS", list(&Value::from(params))),
+    ];
+    for (name, value) in fields {
+        println!("{:<12}{}", name, value);
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn list(value: &Value) -> String {
+    match value.as_array() {
+        Some(items) if items.is_empty() => "-".to_string(),
+        Some(items) => items.iter().map(text).collect::<Vec<_>>().join(", "),
+        None => text(value),
+    }
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.iter().map(|h| h.to_string()).collect());
+    for row in rows {
+        line(row);
+    }
+}
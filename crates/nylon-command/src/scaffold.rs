@@ -0,0 +1,396 @@
+//! Plugin projects and checks of built plugins
+//!
+//! New projects are generated against the method ids and FlatBuffers schema
+//! of this build, so they match the daemon they are made with.
+
+use crate::handler::{Result, ServiceError};
+use crate::plugin::PluginLang;
+use constants::{ffi_symbols, methods};
+use std::fs;
+use std::path::Path;
+
+// The daemon's method ids and FFI symbols, without depending on the whole plugin crate
+#[allow(dead_code)]
+#[path = "../../nylon-plugin/src/constants.rs"]
+mod constants;
+
+const SCHEMA: &str = include_str!("../../../proto/plugin.fbs");
+const SCHEMA_RUST: &str = include_str!("../../../sdk/rust/src/fbs/plugin_generated.rs");
+const GO_SDK_MOD: &str = include_str!("../../../sdk/go/go.mod");
+
+const RUST_CARGO_TOML: &str = r#"[package]
+name = "{{name}}"
+version = "0.1.0"
+edition = "2024"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+flatbuffers = "25.9"
+"#;
+
+const RUST_LIB: &str = r#"//! {{name}}: a Nylon plugin
+
+#[allow(unused, clippy::all)]
+#[allow(unsafe_op_in_unsafe_fn)]
+mod plugin_generated;
+
+use plugin_generated::nylon_plugin::{HeaderKeyValue, HeaderKeyValueArgs};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+// Method ids of the Nylon version this project was generated with
+const NEXT: u32 = {{NEXT}};
+const SET_RESPONSE_HEADER: u32 = {{SET_RESPONSE_HEADER}};
+
+// Phase 0 carries replies to read methods
+const PHASE_ZERO: u8 = 0;
+const PHASE_REQUEST_FILTER: u8 = 1;
+
+#[repr(C)]
+pub struct FfiBuffer {
+    pub sid: u32,
+    pub phase: u8,
+    pub method: u32,
+    pub ptr: *const u8,
+    pub len: u64,
+}
+
+type EventCallback = extern "C" fn(*const FfiBuffer);
+
+struct Session {
+    entry: String,
+    callback: EventCallback,
+}
+
+static SESSIONS: LazyLock<Mutex<HashMap<u32, Session>>> = LazyLock::new(Default::default);
+
+fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() || len == 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(ptr, len) }
+}
+
+fn emit(callback: EventCallback, sid: u32, method: u32, data: &[u8]) {
+    let buffer = FfiBuffer {
+        sid,
+        phase: PHASE_ZERO,
+        method,
+        ptr: data.as_ptr(),
+        len: data.len() as u64,
+    };
+    callback(&buffer);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn initialize(config: *const u8, len: u32) {
+    let config = bytes(config, len as usize);
+    println!("[{{name}}] initialized with {}", String::from_utf8_lossy(config));
+}
+
+/// Nylon copies every payload, so there is nothing to free
+#[unsafe(no_mangle)]
+pub extern "C" fn plugin_free(_ptr: *mut u8) {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn register_session_stream(
+    sid: u32,
+    entry: *const u8,
+    len: u32,
+    callback: EventCallback,
+) -> bool {
+    let entry = String::from_utf8_lossy(bytes(entry, len as usize)).to_string();
+    let Ok(mut sessions) = SESSIONS.lock() else {
+        return false;
+    };
+    sessions.insert(sid, Session { entry, callback });
+    true
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn event_stream(buffer: *const FfiBuffer) {
+    let buffer = unsafe { &*buffer };
+    let session = SESSIONS.lock().ok().and_then(|sessions| {
+        sessions
+            .get(&buffer.sid)
+            .map(|s| (s.entry.clone(), s.callback))
+    });
+    let Some((entry, callback)) = session else {
+        return;
+    };
+    if buffer.phase == PHASE_ZERO {
+        return;
+    }
+    if buffer.phase == PHASE_REQUEST_FILTER {
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let key = fbb.create_string("x-plugin");
+        let value = fbb.create_string(&entry);
+        let header = HeaderKeyValue::create(
+            &mut fbb,
+            &HeaderKeyValueArgs {
+                key: Some(key),
+                value: Some(value),
+            },
+        );
+        fbb.finish(header, None);
+        emit(callback, buffer.sid, SET_RESPONSE_HEADER, fbb.finished_data());
+    }
+    emit(callback, buffer.sid, NEXT, &[]);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn close_session_stream(sid: u32) {
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.remove(&sid);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn shutdown() {
+    println!("[{{name}}] shutdown");
+}
+"#;
+
+const GO_MOD: &str = r#"module {{name}}
+
+go 1.24.3
+
+require (
+	github.com/AssetsArt/nylon/sdk/go v{{version}}
+	github.com/google/flatbuffers {{flatbuffers}}
+)
+"#;
+
+const GO_MAIN: &str = r#"package main
+
+import "C"
+import (
+	"fmt"
+
+	"github.com/AssetsArt/nylon/sdk/go/sdk"
+)
+
+func main() {}
+
+func init() {
+	plugin := sdk.NewNylonPlugin()
+
+	plugin.Initialize(func(config map[string]interface{}) {
+		fmt.Println("[{{name}}] initialized with", config)
+	})
+
+	plugin.Shutdown(func() {
+		fmt.Println("[{{name}}] shutdown")
+	})
+
+	plugin.AddPhaseHandler("{{name}}", func(phase *sdk.PhaseHandler) {
+		phase.RequestFilter(func(ctx *sdk.PhaseRequestFilter) {
+			ctx.Response().SetHeader("x-plugin", "{{name}}")
+			ctx.Next()
+		})
+	})
+}
+"#;
+
+/// Version the Go SDK requires of `module`
+fn go_requirement<'a>(go_mod: &'a str, module: &str) -> Option<&'a str> {
+    go_mod.lines().find_map(|line| {
+        let line = line.trim().trim_start_matches("require").trim();
+        let (path, version) = line.split_once(char::is_whitespace)?;
+        (path == module).then(|| version.split_whitespace().next())?
+    })
+}
+
+fn render(template: &str, name: &str, version: &str) -> String {
+    template
+        .replace("{{name}}", name)
+        .replace("{{version}}", version)
+        .replace(
+            "{{flatbuffers}}",
+            go_requirement(GO_SDK_MOD, "github.com/google/flatbuffers").unwrap_or_default(),
+        )
+        .replace("{{NEXT}}", &methods::NEXT.to_string())
+        .replace(
+            "{{SET_RESPONSE_HEADER}}",
+            &methods::SET_RESPONSE_HEADER.to_string(),
+        )
+}
+
+/// Generate a plugin project in `dir/name` for nylon `version`
+pub fn new(name: &str, lang: PluginLang, dir: &str, version: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ServiceError::Operation(format!(
+            "Invalid plugin name '{}': use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    let root = Path::new(dir).join(name);
+    if root.exists() {
+        return Err(ServiceError::Operation(format!(
+            "{} already exists",
+            root.display()
+        )));
+    }
+
+    let files: Vec<(&str, String)> = match lang {
+        PluginLang::Rust => vec![
+            ("Cargo.toml", render(RUST_CARGO_TOML, name, version)),
+            ("src/lib.rs", render(RUST_LIB, name, version)),
+            ("src/plugin_generated.rs", SCHEMA_RUST.to_string()),
+            ("proto/plugin.fbs", SCHEMA.to_string()),
+        ],
+        PluginLang::Go => vec![
+            ("go.mod", render(GO_MOD, name, version)),
+            ("main.go", render(GO_MAIN, name, version)),
+        ],
+    };
+    for (file, content) in files {
+        let path = root.join(file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, content)?;
+    }
+
+    let (build, library) = match lang {
+        PluginLang::Rust => (
+            "cargo build --release".to_string(),
+            format!("target/release/lib{}.so", name.replace('-', "_")),
+        ),
+        PluginLang::Go => (
+            format!("go mod tidy && go build -buildmode=c-shared -o {}.so", name),
+            format!("{}.so", name),
+        ),
+    };
+    println!("Created {}", root.display());
+    println!();
+    println!("Build it with:");
+    println!("  cd {} && {}", root.display(), build);
+    println!();
+    println!("Then add it to the config:");
+    println!("  plugins:");
+    println!("    - name: {}", name);
+    println!("      type: ffi");
+    println!("      file: {}", root.join(&library).display());
+    println!("  middleware:");
+    println!("    - plugin: {}", name);
+    println!("      entry: \"{}\"", name);
+    Ok(())
+}
+
+/// Check that a shared library exports every symbol Nylon loads
+pub fn check(file: &str) -> Result<()> {
+    let lib = unsafe { libloading::Library::new(file) }
+        .map_err(|e| ServiceError::Operation(format!("Failed to load {}: {}", file, e)))?;
+    let mut missing = vec![];
+    for name in ffi_symbols::REQUIRED {
+        let found = unsafe { lib.get::<*const ()>(name.as_bytes()) }.is_ok();
+        println!("{} {}", if found { "✓" } else { "✗" }, name);
+        if !found {
+            missing.push(*name);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(ServiceError::Operation(format!(
+            "{} does not export {}",
+            file,
+            missing.join(", ")
+        )));
+    }
+    println!("{} is a valid plugin", file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nylon-scaffold-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_render() {
+        let lib = render(RUST_LIB, "auth", "1.0.0");
+        assert!(lib.contains(&format!("const NEXT: u32 = {};", methods::NEXT)));
+        assert!(lib.contains(&format!(
+            "const SET_RESPONSE_HEADER: u32 = {};",
+            methods::SET_RESPONSE_HEADER
+        )));
+        assert!(lib.contains("//! auth: a Nylon plugin"));
+        assert!(!lib.contains("{{"));
+    }
+
+    #[test]
+    fn test_go_mod_pins_versions() {
+        let go_mod = render(GO_MOD, "auth", "1.0.0-beta.4");
+        assert!(go_mod.contains("github.com/AssetsArt/nylon/sdk/go v1.0.0-beta.4"));
+        let flatbuffers = go_requirement(GO_SDK_MOD, "github.com/google/flatbuffers").unwrap();
+        assert!(go_mod.contains(&format!("github.com/google/flatbuffers {}", flatbuffers)));
+        assert_eq!(
+            go_requirement("require (\n\tgithub.com/a/b v1.2.3\n)", "github.com/a/b"),
+            Some("v1.2.3")
+        );
+        assert_eq!(go_requirement("go 1.24.3", "github.com/a/b"), None);
+    }
+
+    #[test]
+    fn test_new_rust() {
+        let dir = temp_dir("rust");
+        new("my-auth", PluginLang::Rust, dir.to_str().unwrap(), "1.0.0").unwrap();
+        let root = dir.join("my-auth");
+        let cargo = fs::read_to_string(root.join("Cargo.toml")).unwrap();
+        assert!(cargo.contains("name = \"my-auth\""));
+        assert!(root.join("src/lib.rs").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("src/plugin_generated.rs")).unwrap(),
+            SCHEMA_RUST
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("proto/plugin.fbs")).unwrap(),
+            SCHEMA
+        );
+
+        // An existing project is never overwritten
+        assert!(new("my-auth", PluginLang::Rust, dir.to_str().unwrap(), "1.0.0").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_new_go() {
+        let dir = temp_dir("go");
+        new("auth", PluginLang::Go, dir.to_str().unwrap(), "1.0.0").unwrap();
+        let root = dir.join("auth");
+        let main = fs::read_to_string(root.join("main.go")).unwrap();
+        assert!(main.contains("plugin.AddPhaseHandler(\"auth\""));
+        let go_mod = fs::read_to_string(root.join("go.mod")).unwrap();
+        assert!(go_mod.starts_with("module auth\n"));
+        assert!(go_mod.contains("github.com/AssetsArt/nylon/sdk/go v1.0.0"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_new_invalid_name() {
+        let dir = temp_dir("invalid");
+        for name in ["", "../escape", "a/b", "has space"] {
+            assert!(new(name, PluginLang::Rust, dir.to_str().unwrap(), "1.0.0").is_err());
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_check_missing_library() {
+        assert!(check("/nonexistent/libplugin.so").is_err());
+    }
+}
@@ -38,4 +38,11 @@ pub enum ServiceCommands {
         about = "Reload route and service configurations without applying global configuration changes."
     )]
     Reload,
+
+    // Upgrade the running daemon to this binary
+    #[command(name = "upgrade")]
+    #[command(
+        about = "Replace the running daemon with this binary, passing its listen sockets over without dropping connections."
+    )]
+    Upgrade,
 }
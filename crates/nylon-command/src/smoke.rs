@@ -0,0 +1,378 @@
+//! Smoke tests against a running proxy
+//!
+//! A spec lists requests and what their responses must contain. Each request
+//! is sent on its own connection with `Connection: close`, so the client only
+//! needs to read until the server hangs up.
+
+use crate::handler::{Result, ServiceError};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+fn default_base_url() -> String {
+    "http://127.0.0.1:8088".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SmokeSpec {
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Accept any certificate from an https base URL
+    #[serde(default)]
+    pub insecure: bool,
+    pub tests: Vec<SmokeTest>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SmokeTest {
+    pub name: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    /// Host header, for host based routes
+    pub host: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Expect {
+    pub status: Option<u16>,
+    /// Header name to a substring its value must contain
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body_contains: Vec<String>,
+}
+
+struct Target {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+struct SmokeResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Run the spec at `path`; fails when any test fails
+pub fn run(path: &str, base_url: Option<String>) -> Result<()> {
+    let content = fs::read_to_string(path)?;
+    let mut spec: SmokeSpec = serde_yaml_ng::from_str(&content)
+        .map_err(|e| ServiceError::Operation(format!("Invalid smoke spec {}: {}", path, e)))?;
+    if let Some(base_url) = base_url {
+        spec.base_url = base_url;
+    }
+    let target = parse_base_url(&spec.base_url)?;
+
+    let mut failed = 0;
+    for test in &spec.tests {
+        let started = Instant::now();
+        let result = send(&spec, &target, test).and_then(|res| check(test, &res));
+        let elapsed = started.elapsed().as_millis();
+        match result {
+            Ok(()) => println!("PASS {} ({}ms)", test.name, elapsed),
+            Err(reason) => {
+                failed += 1;
+                println!("FAIL {} ({}ms): {}", test.name, elapsed, reason);
+            }
+        }
+    }
+    println!("{} passed, {} failed", spec.tests.len() - failed, failed);
+
+    if failed > 0 {
+        return Err(ServiceError::Operation(format!(
+            "{} of {} smoke tests failed",
+            failed,
+            spec.tests.len()
+        )));
+    }
+    Ok(())
+}
+
+fn parse_base_url(base_url: &str) -> Result<Target> {
+    let invalid = || ServiceError::Operation(format!("Invalid base_url: {}", base_url));
+    let (tls, rest) = match base_url.split_once("://") {
+        Some(("http", rest)) => (false, rest),
+        Some(("https", rest)) => (true, rest),
+        _ => return Err(invalid()),
+    };
+    let authority = rest.trim_end_matches('/');
+    // An IPv6 address is bracketed, its colons are not a port
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => {
+            (host, port.parse::<u16>().map_err(|_| invalid())?)
+        }
+        _ => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Target {
+        tls,
+        host: host.trim_matches(['[', ']']).to_string(),
+        port,
+    })
+}
+
+fn send(
+    spec: &SmokeSpec,
+    target: &Target,
+    test: &SmokeTest,
+) -> std::result::Result<SmokeResponse, String> {
+    let timeout = Duration::from_millis(spec.timeout_ms);
+    let addr = (target.host.as_str(), target.port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("cannot resolve {}", target.host))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| e.to_string())?;
+
+    let host = test.host.clone().unwrap_or_else(|| target.host.clone());
+    let body = test.body.as_deref().unwrap_or_default();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        test.method,
+        test.path,
+        host,
+        body.len()
+    );
+    for (name, value) in &test.headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let mut raw = vec![];
+    if target.tls {
+        let mut connector = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+        if spec.insecure {
+            connector.set_verify(SslVerifyMode::NONE);
+        }
+        let mut stream = connector
+            .build()
+            .connect(&host, stream)
+            .map_err(|e| e.to_string())?;
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+        read_to_close(&mut stream, &mut raw)?;
+    } else {
+        let mut stream = stream;
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+        read_to_close(&mut stream, &mut raw)?;
+    }
+    parse_response(&raw)
+}
+
+/// Read until the server closes; TLS peers may skip close_notify
+fn read_to_close(stream: &mut impl Read, raw: &mut Vec<u8>) -> std::result::Result<(), String> {
+    match stream.read_to_end(raw) {
+        Ok(_) => Ok(()),
+        Err(_) if !raw.is_empty() => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_response(raw: &[u8]) -> std::result::Result<SmokeResponse, String> {
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("incomplete response")?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or("invalid status line")?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let mut body = raw[split + 4..].to_vec();
+    let chunked = headers
+        .iter()
+        .any(|(name, value)| name == "transfer-encoding" && value.contains("chunked"));
+    if chunked {
+        body = dechunk(&body);
+    }
+    Ok(SmokeResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    while let Some(end) = data.windows(2).position(|w| w == b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        let start = end + 2;
+        if size == 0 || data.len() < start + size {
+            break;
+        }
+        body.extend_from_slice(&data[start..start + size]);
+        data = data.get(start + size + 2..).unwrap_or_default();
+    }
+    body
+}
+
+fn check(test: &SmokeTest, res: &SmokeResponse) -> std::result::Result<(), String> {
+    if let Some(status) = test.expect.status
+        && status != res.status
+    {
+        return Err(format!("expected status {}, got {}", status, res.status));
+    }
+    for (name, expected) in &test.expect.headers {
+        let name = name.to_ascii_lowercase();
+        let found = res
+            .headers
+            .iter()
+            .any(|(n, value)| *n == name && value.contains(expected.as_str()));
+        if !found {
+            return Err(format!("header {} does not contain {:?}", name, expected));
+        }
+    }
+    let body = String::from_utf8_lossy(&res.body);
+    for expected in &test.expect.body_contains {
+        if !body.contains(expected.as_str()) {
+            return Err(format!("body does not contain {:?}", expected));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_case(expect: &str) -> SmokeTest {
+        serde_yaml_ng::from_str(&format!("name: t\npath: /\nexpect: {}", expect)).unwrap()
+    }
+
+    #[test]
+    fn test_spec_defaults() {
+        let spec: SmokeSpec =
+            serde_yaml_ng::from_str("tests:\n  - name: home\n    path: /\n").unwrap();
+        assert_eq!(spec.base_url, "http://127.0.0.1:8088");
+        assert_eq!(spec.timeout_ms, 10_000);
+        assert!(!spec.insecure);
+        assert_eq!(spec.tests[0].method, "GET");
+        assert!(spec.tests[0].expect.status.is_none());
+    }
+
+    #[test]
+    fn test_parse_base_url() {
+        let target = parse_base_url("https://example.com/").unwrap();
+        assert!(target.tls);
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.port, 443);
+
+        let target = parse_base_url("http://127.0.0.1:8088").unwrap();
+        assert!(!target.tls);
+        assert_eq!(target.port, 8088);
+
+        let target = parse_base_url("http://[::1]:9000").unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.port, 9000);
+
+        let target = parse_base_url("http://[::1]").unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.port, 80);
+
+        assert!(parse_base_url("ftp://example.com").is_err());
+        assert!(parse_base_url("example.com").is_err());
+        assert!(parse_base_url("http://example.com:http").is_err());
+        assert!(parse_base_url("http://:80").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let raw = b"HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nX-Id:  7 \r\n\r\nhello";
+        let res = parse_response(raw).unwrap();
+        assert_eq!(res.status, 201);
+        assert_eq!(
+            res.headers,
+            vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("x-id".to_string(), "7".to_string()),
+            ]
+        );
+        assert_eq!(res.body, b"hello");
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_response(b"garbage\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let res = parse_response(raw).unwrap();
+        assert_eq!(res.body, b"hello world");
+    }
+
+    #[test]
+    fn test_dechunk_truncated() {
+        assert_eq!(dechunk(b"5\r\nhel"), b"");
+        assert_eq!(dechunk(b"2\r\nhi\r\nzz\r\n"), b"hi");
+    }
+
+    #[test]
+    fn test_check() {
+        let res = SmokeResponse {
+            status: 200,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/html; charset=utf-8".to_string(),
+            )],
+            body: b"<title>Home</title>".to_vec(),
+        };
+        let test = test_case(
+            "{status: 200, headers: {Content-Type: text/html}, body_contains: [\"<title>\"]}",
+        );
+        assert!(check(&test, &res).is_ok());
+
+        let test = test_case("{status: 404}");
+        assert_eq!(
+            check(&test, &res).unwrap_err(),
+            "expected status 404, got 200"
+        );
+        let test = test_case("{headers: {content-type: json}}");
+        assert!(check(&test, &res).is_err());
+        let test = test_case("{headers: {x-missing: a}}");
+        assert!(check(&test, &res).is_err());
+        let test = test_case("{body_contains: [missing]}");
+        assert!(check(&test, &res).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Command socket protocol
+//!
+//! The daemon listens on a unix socket (a named pipe on Windows) and accepts
+//! one JSON encoded [`CommandRequest`] per connection (terminated by a
+//! newline), answering with a single [`CommandResponse`].
+
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::time::Duration;
+
+/// Requests accepted by the daemon command socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum CommandRequest {
+    Reload,
+    CertList,
+    CertRenew {
+        domain: String,
+    },
+    CertRevoke {
+        domain: String,
+    },
+    CertImport {
+        domain: String,
+        cert: String,
+        key: String,
+        #[serde(default)]
+        chain: Vec<String>,
+    },
+    PluginList,
+    PluginReload {
+        name: String,
+    },
+    ConfigHistory,
+    ConfigRollback {
+        version: u64,
+    },
+    ProxyRoutes,
+    ProxyServices,
+    ProxyTestRoute {
+        host: String,
+        path: String,
+        method: String,
+        #[serde(default)]
+        selector: Option<String>,
+    },
+    /// Start `binary` and hand it the listen sockets
+    Upgrade {
+        binary: String,
+    },
+}
+
+/// Response returned by the daemon command socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub ok: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl CommandResponse {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+/// Send a request to the daemon and wait for its response
+///
+/// Certificate issuance can take a while, so the read timeout is generous.
+pub fn send_request(
+    socket_path: &str,
+    request: &CommandRequest,
+) -> std::io::Result<CommandResponse> {
+    let mut stream = connect(socket_path)?;
+    let mut payload = serde_json::to_vec(request)?;
+    payload.push(b'\n');
+    stream.write_all(&payload)?;
+    stream.flush()?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response = serde_json::from_str(line.trim())?;
+    Ok(response)
+}
+
+#[cfg(unix)]
+fn connect(socket_path: &str) -> std::io::Result<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(300)))?;
+    Ok(stream)
+}
+
+/// Named pipes are opened like files; they have no read timeout
+#[cfg(windows)]
+fn connect(socket_path: &str) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(socket_path)
+}
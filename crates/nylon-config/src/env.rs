@@ -0,0 +1,529 @@
+//! Environment variables and secrets in configuration files
+//!
+//! Placeholders are replaced in the string values of a parsed file, so a
+//! value can never add keys or change the structure around it:
+//!
+//! - `${env:VAR}` is the value of `VAR`, and `${env:VAR:-fallback}` uses
+//!   `fallback` when `VAR` is unset or empty. A variable without a fallback
+//!   that is not set fails the load.
+//! - `${file:/path}` is the content of a file without its trailing newline.
+//!   The file may not be writable by its group or accessible by others.
+//! - `${vault:path#field}` is a field of a HashiCorp Vault secret, read from
+//!   `VAULT_ADDR` with `VAULT_TOKEN` (KV v1 and v2).
+//!
+//! In YAML, a value that is a single placeholder takes the type of what it
+//! resolves to, so `port: ${env:PORT}` is a number. `$${env:VAR}` keeps the
+//! text as is.
+
+use nylon_error::NylonError;
+use std::collections::HashMap;
+use std::path::Path;
+
+const KINDS: [&str; 3] = ["env", "file", "vault"];
+
+/// Read a config file, decrypting SOPS documents, and replace its placeholders
+pub fn read_config(path: &str) -> Result<String, NylonError> {
+    let content = crate::sops::read_to_string(path)?;
+    let format = Format::of(path);
+    interpolate(&content, format).map_err(|e| match e {
+        NylonError::ConfigError(msg) => NylonError::ConfigError(format!("{}: {}", path, msg)),
+        e => e,
+    })
+}
+
+/// Syntax of a config file, from its extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    pub fn of(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+/// Replace `${env:...}`, `${file:...}` and `${vault:...}` placeholders in the string values of a file
+///
+/// A file without placeholders is returned as is. Otherwise it is parsed,
+/// its strings are resolved and it is written back in the same format.
+/// Errors name the key of the value.
+pub fn interpolate(content: &str, format: Format) -> Result<String, NylonError> {
+    if find_placeholder(content).is_none() {
+        return Ok(content.to_string());
+    }
+    let mut resolver = Resolver::default();
+    let resolved = match format {
+        Format::Yaml => serde_yaml_ng::from_str(content)
+            .map_err(|e| e.to_string())
+            .and_then(|mut value: serde_yaml_ng::Value| {
+                resolver.yaml(&mut value, "")?;
+                serde_yaml_ng::to_string(&value).map_err(|e| e.to_string())
+            }),
+        Format::Json => serde_json::from_str(content)
+            .map_err(|e| e.to_string())
+            .and_then(|mut value: serde_json::Value| {
+                resolver.json(&mut value, "")?;
+                serde_json::to_string(&value).map_err(|e| e.to_string())
+            }),
+        Format::Toml => content
+            .parse::<toml::Table>()
+            .map_err(|e| e.to_string())
+            .and_then(|mut table| {
+                for (name, value) in table.iter_mut() {
+                    resolver.toml(value, name)?;
+                }
+                toml::to_string(&table).map_err(|e| e.to_string())
+            }),
+    };
+    resolved.map_err(NylonError::ConfigError)
+}
+
+/// Whether `typed` is written out as exactly `text`
+fn same_text(typed: &serde_yaml_ng::Value, text: &str) -> bool {
+    serde_yaml_ng::to_string(typed).is_ok_and(|written| written.trim_end() == text)
+}
+
+/// Resolves the placeholders of one file
+#[derive(Default)]
+struct Resolver {
+    /// Vault secrets read so far, by path
+    vault: HashMap<String, serde_json::Value>,
+}
+
+impl Resolver {
+    fn yaml(&mut self, value: &mut serde_yaml_ng::Value, key: &str) -> Result<(), String> {
+        use serde_yaml_ng::Value;
+        match value {
+            Value::String(s) => {
+                let whole = is_single_placeholder(s);
+                let resolved = self.replace(s).map_err(|e| format!("{}: {}", key, e))?;
+                // `port: ${env:PORT}` is a number, as if the value had been written
+                // there, but only if it is written back as the same text; `0x1F`,
+                // `1e3`, `+1` or `True` stay strings, so a secret is never rewritten
+                *value = match serde_yaml_ng::from_str(&resolved) {
+                    Ok(typed @ (Value::Number(_) | Value::Bool(_)))
+                        if whole && same_text(&typed, &resolved) =>
+                    {
+                        typed
+                    }
+                    _ => Value::String(resolved),
+                };
+            }
+            Value::Sequence(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.yaml(item, &format!("{}[{}]", key, i))?;
+                }
+            }
+            Value::Mapping(map) => {
+                for (name, item) in map.iter_mut() {
+                    let name = match name {
+                        Value::String(name) => name.clone(),
+                        name => serde_yaml_ng::to_string(name)
+                            .unwrap_or_default()
+                            .trim_end()
+                            .to_string(),
+                    };
+                    self.yaml(item, &child(key, &name))?;
+                }
+            }
+            Value::Tagged(tagged) => self.yaml(&mut tagged.value, key)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn json(&mut self, value: &mut serde_json::Value, key: &str) -> Result<(), String> {
+        use serde_json::Value;
+        match value {
+            Value::String(s) => *s = self.replace(s).map_err(|e| format!("{}: {}", key, e))?,
+            Value::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.json(item, &format!("{}[{}]", key, i))?;
+                }
+            }
+            Value::Object(map) => {
+                for (name, item) in map.iter_mut() {
+                    self.json(item, &child(key, name))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn toml(&mut self, value: &mut toml::Value, key: &str) -> Result<(), String> {
+        use toml::Value;
+        match value {
+            Value::String(s) => *s = self.replace(s).map_err(|e| format!("{}: {}", key, e))?,
+            Value::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.toml(item, &format!("{}[{}]", key, i))?;
+                }
+            }
+            Value::Table(table) => {
+                for (name, item) in table.iter_mut() {
+                    self.toml(item, &child(key, name))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replace the placeholders of one string value
+    fn replace(&mut self, s: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some((start, kind)) = find_placeholder(rest) {
+            let open = &rest[start..start + kind.len() + 3];
+            let after = &rest[start + open.len()..];
+            // `$${kind:` is an escaped placeholder
+            if rest[..start].ends_with('$') {
+                out.push_str(&rest[..start - 1]);
+                out.push_str(open);
+                rest = after;
+                continue;
+            }
+            out.push_str(&rest[..start]);
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unclosed ${{{}:...}}", kind))?;
+            let expr = &after[..end];
+            let value = match kind {
+                "env" => resolve_env(expr),
+                "file" => read_secret_file(expr),
+                _ => read_vault(expr, &mut self.vault),
+            };
+            out.push_str(&value?);
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+fn child(key: &str, name: &str) -> String {
+    if key.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", key, name)
+    }
+}
+
+/// Whether `s` is nothing but one placeholder
+fn is_single_placeholder(s: &str) -> bool {
+    matches!(find_placeholder(s), Some((0, _))) && s.ends_with('}') && s.matches('}').count() == 1
+}
+
+/// The first placeholder in `s` and its kind
+fn find_placeholder(s: &str) -> Option<(usize, &'static str)> {
+    KINDS
+        .iter()
+        .filter_map(|kind| s.find(&format!("${{{}:", kind)).map(|i| (i, *kind)))
+        .min()
+}
+
+fn resolve_env(expr: &str) -> Result<String, String> {
+    let (name, fallback) = match expr.split_once(":-") {
+        Some((name, fallback)) => (name, Some(fallback)),
+        None => (expr, None),
+    };
+    if name.is_empty() {
+        return Err(format!("empty variable name in ${{env:{}}}", expr));
+    }
+    match (std::env::var(name), fallback) {
+        (Ok(value), Some(fallback)) if value.is_empty() => Ok(fallback.to_string()),
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(fallback)) => Ok(fallback.to_string()),
+        (Err(_), None) => Err(format!("environment variable {} is not set", name)),
+    }
+}
+
+fn read_secret_file(path: &str) -> Result<String, String> {
+    let path = &secret_path(path)?;
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o027 != 0 {
+            return Err(format!(
+                "{} has mode {:o}; secret files may not be writable by the group or accessible by others (use 600 or 640)",
+                path, mode
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    let value = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The file a secret path names
+///
+/// Kubernetes mounts secrets as links into a `..data` folder next to them,
+/// so a link is followed as long as it stays in its own directory. A link
+/// anywhere else could swap in a file whose mode was never checked.
+fn secret_path(path: &str) -> Result<String, String> {
+    let link = std::fs::symlink_metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+    if !link.file_type().is_symlink() {
+        return Ok(path.to_string());
+    }
+    let target = std::fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+    let dir = Path::new(path)
+        .parent()
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .and_then(|dir| std::fs::canonicalize(dir).ok())
+        .ok_or_else(|| format!("{}: no parent directory", path))?;
+    if !target.starts_with(&dir) {
+        return Err(format!(
+            "{} links to {} outside its directory",
+            path,
+            target.display()
+        ));
+    }
+    Ok(target.to_string_lossy().to_string())
+}
+
+fn read_vault(
+    reference: &str,
+    cache: &mut HashMap<String, serde_json::Value>,
+) -> Result<String, String> {
+    let (path, field) = reference.split_once('#').ok_or_else(|| {
+        format!(
+            "expected ${{vault:path#field}}, got ${{vault:{}}}",
+            reference
+        )
+    })?;
+    if !cache.contains_key(path) {
+        cache.insert(path.to_string(), fetch_vault(path)?);
+    }
+    let data = &cache[path]["data"];
+    // KV v2 nests the secret one level deeper than KV v1
+    let secret = match data.get("data") {
+        Some(inner) if inner.is_object() => inner,
+        _ => data,
+    };
+    match &secret[field] {
+        serde_json::Value::Null => Err(format!("vault secret {} has no field {}", path, field)),
+        serde_json::Value::String(value) => Ok(value.clone()),
+        value => Ok(value.to_string()),
+    }
+}
+
+fn fetch_vault(path: &str) -> Result<serde_json::Value, String> {
+    let addr = std::env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR is not set".to_string())?;
+    let token = std::env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN is not set".to_string())?;
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let mut request = crate::remote::agent()
+        .get(&url)
+        .header("X-Vault-Token", &token);
+    if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+        request = request.header("X-Vault-Namespace", &namespace);
+    }
+    let mut response = request.call().map_err(|e| format!("{}: {}", url, e))?;
+    if response.status().as_u16() != 200 {
+        return Err(format!("{}: HTTP {}", url, response.status()));
+    }
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(|e| format!("{}: {}", url, e))?;
+    serde_json::from_str(&body).map_err(|e| format!("{}: {}", url, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(content: &str) -> serde_yaml_ng::Value {
+        serde_yaml_ng::from_str(&interpolate(content, Format::Yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_replaces_variables_and_fallbacks() {
+        unsafe { std::env::set_var("NYLON_TEST_ENV_HOST", "redis.internal") };
+        let value = yaml(
+            "host: ${env:NYLON_TEST_ENV_HOST}\nport: ${env:NYLON_TEST_ENV_UNSET:-6379}\nurl: \"redis://${env:NYLON_TEST_ENV_HOST}:6379\"\n",
+        );
+        assert_eq!(value["host"], "redis.internal");
+        assert_eq!(value["port"], 6379);
+        assert_eq!(value["url"], "redis://redis.internal:6379");
+    }
+
+    #[test]
+    fn test_numeric_looking_secrets_keep_their_text() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            port: u16,
+            ratio: f64,
+            enabled: bool,
+            secrets: Vec<String>,
+        }
+
+        let vars = [
+            ("NYLON_TEST_ENV_PORT", "8080"),
+            ("NYLON_TEST_ENV_RATIO", "0.5"),
+            ("NYLON_TEST_ENV_ENABLED", "true"),
+            ("NYLON_TEST_ENV_HEX", "0x1F"),
+            ("NYLON_TEST_ENV_EXP", "1e3"),
+            ("NYLON_TEST_ENV_PLUS", "+1"),
+            ("NYLON_TEST_ENV_TRUE", "True"),
+            ("NYLON_TEST_ENV_ZERO", "007"),
+            ("NYLON_TEST_ENV_DIGITS", "1234"),
+        ];
+        for (name, value) in vars {
+            unsafe { std::env::set_var(name, value) };
+        }
+        let content = interpolate(
+            "port: ${env:NYLON_TEST_ENV_PORT}\n\
+ratio: ${env:NYLON_TEST_ENV_RATIO}\n\
+enabled: ${env:NYLON_TEST_ENV_ENABLED}\n\
+secrets:\n\
+  - ${env:NYLON_TEST_ENV_HEX}\n\
+  - ${env:NYLON_TEST_ENV_EXP}\n\
+  - ${env:NYLON_TEST_ENV_PLUS}\n\
+  - ${env:NYLON_TEST_ENV_TRUE}\n\
+  - ${env:NYLON_TEST_ENV_ZERO}\n\
+  - ${env:NYLON_TEST_ENV_DIGITS}\n\
+  - ${env:NYLON_TEST_ENV_ENABLED}\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        let config: Config = serde_yaml_ng::from_str(&content).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.ratio, 0.5);
+        assert!(config.enabled);
+        assert_eq!(
+            config.secrets,
+            ["0x1F", "1e3", "+1", "True", "007", "1234", "true"]
+        );
+    }
+
+    #[test]
+    fn test_escapes_and_comments_are_kept() {
+        let value = yaml("# ${env:NYLON_TEST_ENV_UNSET}\nvalue: $${env:NYLON_TEST_ENV_UNSET}\n");
+        assert_eq!(value["value"], "${env:NYLON_TEST_ENV_UNSET}");
+    }
+
+    #[test]
+    fn test_values_cannot_change_the_structure() {
+        unsafe {
+            std::env::set_var("NYLON_TEST_ENV_INJECT", "x\nadmin: true\n# ");
+            std::env::set_var("NYLON_TEST_ENV_FLOW", "{a: 1}");
+        }
+        let value =
+            yaml("password: ${env:NYLON_TEST_ENV_INJECT}\nname: ${env:NYLON_TEST_ENV_FLOW}\n");
+        assert_eq!(value["password"], "x\nadmin: true\n# ");
+        assert_eq!(value["name"], "{a: 1}");
+        assert!(value.get("admin").is_none());
+    }
+
+    #[test]
+    fn test_json_and_toml_strings() {
+        unsafe { std::env::set_var("NYLON_TEST_ENV_QUOTE", "a\"b") };
+        let json = interpolate(
+            r#"{"name": "${env:NYLON_TEST_ENV_QUOTE}", "port": 80}"#,
+            Format::Json,
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["name"], "a\"b");
+        assert_eq!(json["port"], 80);
+
+        let toml = interpolate(
+            "[redis]\nhost = \"${env:NYLON_TEST_ENV_QUOTE}\"\n",
+            Format::Toml,
+        )
+        .unwrap();
+        let toml: toml::Table = toml.parse().unwrap();
+        assert_eq!(toml["redis"]["host"].as_str(), Some("a\"b"));
+    }
+
+    #[test]
+    fn test_files_without_placeholders_are_untouched() {
+        let content = "base: &base\n  a: 1\nother:\n  <<: *base\n";
+        assert_eq!(interpolate(content, Format::Yaml).unwrap(), content);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_files_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("nylon-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let content = format!("password: ${{file:{}}}\n", path.display());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(yaml(&content)["password"], "s3cret");
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = interpolate(&content, Format::Yaml).unwrap_err();
+        assert!(err.to_string().contains("mode 644"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_links_stay_in_their_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = std::env::temp_dir().join(format!("nylon-secret-links-{}", std::process::id()));
+        let data = root.join("mount/..data");
+        std::fs::create_dir_all(&data).unwrap();
+        let secret = data.join("password");
+        std::fs::write(&secret, "line one\nline: two\n").unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o400)).unwrap();
+
+        // the Kubernetes layout
+        let mounted = root.join("mount/password");
+        std::os::unix::fs::symlink("..data/password", &mounted).unwrap();
+        let content = format!("password: ${{file:{}}}\n", mounted.display());
+        assert_eq!(yaml(&content)["password"], "line one\nline: two");
+
+        let outside = root.join("mount/escape");
+        std::os::unix::fs::symlink(root.join("elsewhere"), &outside).unwrap();
+        std::fs::write(root.join("elsewhere"), "x").unwrap();
+        let content = format!("password: ${{file:{}}}\n", outside.display());
+        let err = interpolate(&content, Format::Yaml).unwrap_err();
+        assert!(err.to_string().contains("outside its directory"), "{}", err);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_unset_variable_fails() {
+        let err = interpolate(
+            "a: 1\nredis:\n  hosts:\n    - ${env:NYLON_TEST_ENV_UNSET}\n",
+            Format::Yaml,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("redis.hosts[0]"), "{}", err);
+        assert!(err.to_string().contains("NYLON_TEST_ENV_UNSET"));
+    }
+
+    #[test]
+    fn test_format_of() {
+        assert_eq!(Format::of("a/b.json"), Format::Json);
+        assert_eq!(Format::of("a/b.toml"), Format::Toml);
+        assert_eq!(Format::of("a/b.yml"), Format::Yaml);
+    }
+}
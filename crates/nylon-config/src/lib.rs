@@ -1,4 +1,9 @@
+pub mod env;
+pub mod plugins;
 pub mod proxy;
+pub mod remote;
 pub mod runtime;
+pub mod schema;
 pub mod services;
+pub mod sops;
 mod utils;
@@ -0,0 +1,82 @@
+use nylon_error::NylonError;
+use nylon_types::plugins::{MAX_CONCURRENT_SESSIONS, PluginItem};
+
+pub trait PluginItemExt {
+    fn is_valid(&self) -> Result<(), NylonError>;
+}
+
+impl PluginItemExt for PluginItem {
+    fn is_valid(&self) -> Result<(), NylonError> {
+        if let Some(max) = self.max_concurrent_sessions
+            && !(1..=MAX_CONCURRENT_SESSIONS).contains(&max)
+        {
+            return Err(NylonError::ConfigError(format!(
+                "Plugin {}: max_concurrent_sessions must be between 1 and {}",
+                self.name, MAX_CONCURRENT_SESSIONS
+            )));
+        }
+        if self.event_queue_capacity == Some(0) {
+            return Err(NylonError::ConfigError(format!(
+                "Plugin {}: event_queue_capacity must be at least 1",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(extra: serde_json::Value) -> PluginItem {
+        let mut value = serde_json::json!({
+            "name": "auth",
+            "file": "./auth.so",
+            "type": "ffi",
+        });
+        if let (Some(value), Some(extra)) = (value.as_object_mut(), extra.as_object()) {
+            value.extend(extra.clone());
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_max_concurrent_sessions() {
+        assert!(plugin(serde_json::json!({})).is_valid().is_ok());
+        assert!(
+            plugin(serde_json::json!({ "max_concurrent_sessions": 1 }))
+                .is_valid()
+                .is_ok()
+        );
+        assert!(
+            plugin(serde_json::json!({ "max_concurrent_sessions": MAX_CONCURRENT_SESSIONS }))
+                .is_valid()
+                .is_ok()
+        );
+        assert!(
+            plugin(serde_json::json!({ "max_concurrent_sessions": 0 }))
+                .is_valid()
+                .is_err()
+        );
+        assert!(
+            plugin(serde_json::json!({ "max_concurrent_sessions": usize::MAX }))
+                .is_valid()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_event_queue_capacity() {
+        assert!(
+            plugin(serde_json::json!({ "event_queue_capacity": 0 }))
+                .is_valid()
+                .is_err()
+        );
+        assert!(
+            plugin(serde_json::json!({ "event_queue_capacity": 1 }))
+                .is_valid()
+                .is_ok()
+        );
+    }
+}
@@ -1,18 +1,22 @@
 use crate::{
+    plugins::PluginItemExt,
     runtime::RuntimeConfig,
     services::{EndpointExt, HealthCheckExt},
     utils::read_dir_recursive,
 };
 use async_trait::async_trait;
 use nylon_error::NylonError;
-use nylon_plugin::loaders;
+use nylon_plugin::{loaders, plugin_manager::PluginManager};
 use nylon_store as store;
 use nylon_types::{
     proxy::ProxyConfig,
+    route::MiddlewareItem,
     route::RouteConfig,
     services::{ServiceItem, ServiceType},
+    template::{extract_and_parse_templates, walk_json},
     tls::TlsConfig,
 };
+use std::{collections::HashMap, path::Path};
 
 const MAX_DEPTH: u16 = 10;
 
@@ -21,6 +25,7 @@ pub trait ProxyConfigExt {
     fn merge(&mut self, other: ProxyConfig);
     fn validate(&self) -> Result<(), NylonError>;
     async fn store(&self) -> Result<(), NylonError>;
+    async fn check(&self) -> Vec<NylonError>;
     fn from_file(path: &str) -> Result<ProxyConfig, NylonError>;
     fn from_dir(dir: &str) -> Result<ProxyConfig, NylonError>;
 }
@@ -28,22 +33,16 @@ pub trait ProxyConfigExt {
 #[async_trait]
 impl ProxyConfigExt for ProxyConfig {
     fn from_file(path: &str) -> Result<Self, NylonError> {
-        let content =
-            std::fs::read_to_string(path).map_err(|e| NylonError::ConfigError(e.to_string()))?;
-        serde_yaml_ng::from_str(&content).map_err(|e| NylonError::ConfigError(e.to_string()))
+        let content = crate::env::read_config(path)?;
+        parse_file(path, &content)
     }
 
     fn from_dir(dir: &str) -> Result<Self, NylonError> {
-        let files = read_dir_recursive(&dir.to_string(), MAX_DEPTH)?;
-        let mut config = ProxyConfig::default();
-        for file in files {
-            let content = std::fs::read_to_string(file)
-                .map_err(|e| NylonError::ConfigError(e.to_string()))?;
-            let file_config: ProxyConfig = serde_yaml_ng::from_str(&content)
-                .map_err(|e| NylonError::ConfigError(e.to_string()))?;
-            config.merge(file_config);
+        let (config, errors) = parse_dir(dir)?;
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(config),
         }
-        Ok(config)
     }
 
     fn merge(&mut self, other: Self) {
@@ -130,6 +129,7 @@ impl ProxyConfigExt for ProxyConfig {
                     plugin.name
                 )));
             }
+            plugin.is_valid()?;
         }
         // check if middleware groups are unique
         let mut seen = std::collections::HashSet::new();
@@ -141,6 +141,12 @@ impl ProxyConfigExt for ProxyConfig {
                 )));
             }
         }
+        // check middleware groups for missing references, cycles, and size
+        if let Some(middleware_groups) = &self.middleware_groups {
+            for items in middleware_groups.values() {
+                store::routes::expand_middleware(items, middleware_groups)?;
+            }
+        }
         // validate http service
         for service in self.services.iter().flatten() {
             if service.service_type == ServiceType::Http {
@@ -191,6 +197,139 @@ impl ProxyConfigExt for ProxyConfig {
                         )));
                     }
                 }
+            } else if service.service_type == ServiceType::Template
+                && let Some(template) = &service.template_conf
+            {
+                if let Some(status) = template.status
+                    && !(100..=599).contains(&status)
+                {
+                    return Err(NylonError::ConfigError(format!(
+                        "Template service {} has an invalid status {}",
+                        service.name, status
+                    )));
+                }
+                extract_and_parse_templates(&template.body)?;
+                for value in template.headers.iter().flat_map(|h| h.values()) {
+                    extract_and_parse_templates(value)?;
+                }
+            }
+        }
+        // validate path responses
+        for route in self.routes.iter().flatten() {
+            for (key, page) in route.error_pages.iter().flatten() {
+                let valid_key = key == "default"
+                    || key
+                        .parse::<u16>()
+                        .is_ok_and(|status| (400..=599).contains(&status))
+                    || key == "4xx"
+                    || key == "5xx";
+                if !valid_key {
+                    return Err(NylonError::ConfigError(format!(
+                        "Error page {} of route {} must be a 4xx/5xx status, 4xx, 5xx or default",
+                        key, route.name
+                    )));
+                }
+                match (&page.file, page.html.is_some() || page.json.is_some()) {
+                    (Some(_), true) | (None, false) => {
+                        return Err(NylonError::ConfigError(format!(
+                            "Error page {} of route {} needs either a file or html/json templates",
+                            key, route.name
+                        )));
+                    }
+                    (Some(file), false) if !Path::new(file).is_file() => {
+                        return Err(NylonError::ConfigError(format!(
+                            "Error page {} of route {}: {} does not exist",
+                            key, route.name, file
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+            for path in &route.paths {
+                let template = self
+                    .services
+                    .iter()
+                    .flatten()
+                    .find(|s| {
+                        s.name == path.service.name && s.service_type == ServiceType::Template
+                    })
+                    .map(|s| &s.template_conf);
+                match (&path.response, template) {
+                    (Some(_), None) => {
+                        return Err(NylonError::ConfigError(format!(
+                            "Path {} of route {} has a response but {} is not a template service",
+                            path.path, route.name, path.service.name
+                        )));
+                    }
+                    (None, Some(None)) => {
+                        return Err(NylonError::ConfigError(format!(
+                            "Path {} of route {} needs a response, template service {} has no template",
+                            path.path, route.name, path.service.name
+                        )));
+                    }
+                    (Some(response), _) => {
+                        if let Some(status) = response.status
+                            && !(100..=599).contains(&status)
+                        {
+                            return Err(NylonError::ConfigError(format!(
+                                "Path {} of route {} has an invalid status {}",
+                                path.path, route.name, status
+                            )));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // validate path fallbacks and device services
+        for route in self.routes.iter().flatten() {
+            for name in route
+                .paths
+                .iter()
+                .filter_map(|p| p.devices.as_ref())
+                .flat_map(|d| d.values())
+            {
+                let Some(service) = self.services.iter().flatten().find(|s| &s.name == name) else {
+                    return Err(NylonError::ConfigError(format!(
+                        "Device service {} of route {} does not exist",
+                        name, route.name
+                    )));
+                };
+                if service.service_type == ServiceType::Plugin {
+                    return Err(NylonError::ConfigError(format!(
+                        "Device service {} of route {} cannot be a plugin service",
+                        name, route.name
+                    )));
+                }
+                if service.service_type == ServiceType::Template && service.template_conf.is_none()
+                {
+                    return Err(NylonError::ConfigError(format!(
+                        "Device service {} of route {} is a template service without a template",
+                        name, route.name
+                    )));
+                }
+            }
+            for fallback in route.paths.iter().filter_map(|p| p.fallback.as_ref()) {
+                let Some(service) = self.services.iter().flatten().find(|s| &s.name == fallback)
+                else {
+                    return Err(NylonError::ConfigError(format!(
+                        "Fallback service {} of route {} does not exist",
+                        fallback, route.name
+                    )));
+                };
+                if service.service_type == ServiceType::Plugin {
+                    return Err(NylonError::ConfigError(format!(
+                        "Fallback service {} of route {} cannot be a plugin service",
+                        fallback, route.name
+                    )));
+                }
+                if service.service_type == ServiceType::Template && service.template_conf.is_none()
+                {
+                    return Err(NylonError::ConfigError(format!(
+                        "Fallback service {} of route {} is a template service without a template",
+                        fallback, route.name
+                    )));
+                }
             }
         }
         Ok(())
@@ -227,8 +366,7 @@ impl ProxyConfigExt for ProxyConfig {
         )?;
 
         // store header selector
-        store::insert(
-            store::KEY_HEADER_SELECTOR,
+        store::routes::set_header_selector(
             self.header_selector
                 .clone()
                 .unwrap_or(store::DEFAULT_HEADER_SELECTOR.to_string()),
@@ -238,7 +376,573 @@ impl ProxyConfigExt for ProxyConfig {
         for plugin in self.plugins.iter().flatten() {
             loaders::load(plugin);
         }
+        loaders::retire_removed(
+            &self
+                .plugins
+                .iter()
+                .flatten()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>(),
+        );
+
+        // keep the applied config around so reloads can report what changed
+        store::insert(store::KEY_PROXY_CONFIG, self.clone());
 
         Ok(())
     }
+
+    /// Build everything `store` does, collecting every error instead of stopping at the first
+    async fn check(&self) -> Vec<NylonError> {
+        let mut errors = vec![];
+        if let Err(e) = self.validate() {
+            errors.push(e);
+        }
+        let acme_dir = RuntimeConfig::get()
+            .ok()
+            .map(|runtime_config| runtime_config.acme.to_string_lossy().to_string());
+        if let Err(e) = store::tls::store(
+            self.tls.iter().flatten().collect::<Vec<&TlsConfig>>(),
+            acme_dir,
+        ) {
+            errors.push(e);
+        }
+        let services = self
+            .services
+            .iter()
+            .flatten()
+            .collect::<Vec<&ServiceItem>>();
+        if let Err(e) = store::lb_backends::store(&services).await {
+            errors.push(e);
+        }
+        if let Err(e) = store::routes::store(
+            self.routes.iter().flatten().collect::<Vec<&RouteConfig>>(),
+            &services,
+            &self.middleware_groups,
+        ) {
+            errors.push(e);
+        }
+        for plugin in self.plugins.iter().flatten() {
+            if let Err(e) = loaders::try_load(plugin) {
+                errors.push(NylonError::ConfigError(format!(
+                    "plugin {}: {}",
+                    plugin.name, e
+                )));
+            }
+        }
+        errors
+    }
+}
+
+/// Parse every file of a config directory; a file that fails is reported and left out
+///
+/// A remote `dir` is read from its cache; see [`crate::remote::fetch`].
+///
+/// Files are merged in path order. A name defined in more than one file
+/// belongs to the file with the highest `priority`, and the same name at the
+/// same priority in two files is a conflict.
+pub fn parse_dir(dir: &str) -> Result<(ProxyConfig, Vec<NylonError>), NylonError> {
+    let dir = match crate::remote::Source::parse(dir) {
+        Some(_) => crate::remote::cached(dir)?.to_string_lossy().to_string(),
+        None => dir.to_string(),
+    };
+    let mut files = read_dir_recursive(&dir, MAX_DEPTH)?;
+    files.sort();
+    let mut parsed = vec![];
+    let mut errors = vec![];
+    for file in files {
+        let path = file.to_string_lossy().to_string();
+        match crate::env::read_config(&path).and_then(|content| parse_file(&path, &content)) {
+            Ok(file_config) => parsed.push((path, file_config)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    // owner of each name: priority, file and a file that conflicts with it
+    let mut owners: HashMap<EntryKey, (i32, String, Option<String>)> = HashMap::new();
+    for (path, file_config) in &parsed {
+        for key in entry_keys(file_config) {
+            match owners.get_mut(&key) {
+                Some((priority, _, _)) if *priority > file_config.priority => {}
+                Some((priority, owner, conflict)) if *priority == file_config.priority => {
+                    if owner != path && conflict.is_none() {
+                        *conflict = Some(path.clone());
+                    }
+                }
+                _ => {
+                    owners.insert(key, (file_config.priority, path.clone(), None));
+                }
+            }
+        }
+    }
+    let mut conflicts: Vec<_> = owners
+        .iter()
+        .filter_map(|((kind, name), (priority, owner, conflict))| {
+            conflict.as_ref().map(|other| {
+                NylonError::ConfigError(format!(
+                    "{} {} is defined in both {} and {} with priority {}; raise the priority of the file that should win",
+                    kind, name, owner, other, priority
+                ))
+            })
+        })
+        .collect();
+    conflicts.sort_by_key(|e| e.to_string());
+    errors.extend(conflicts);
+
+    let mut config = ProxyConfig::default();
+    for (path, mut file_config) in parsed {
+        retain_owned(&mut file_config, |key| {
+            owners.get(&key).is_some_and(|(_, owner, _)| *owner == path)
+        });
+        config.merge(file_config);
+    }
+    Ok((config, errors))
+}
+
+/// Kind and name of an entry that may be defined in only one file
+type EntryKey = (&'static str, String);
+
+fn entry_keys(config: &ProxyConfig) -> Vec<EntryKey> {
+    let mut keys = vec![];
+    if config.header_selector.is_some() {
+        keys.push(("header_selector", String::new()));
+    }
+    for service in config.services.iter().flatten() {
+        keys.push(("service", service.name.clone()));
+    }
+    for route in config.routes.iter().flatten() {
+        keys.push(("route", route.name.clone()));
+    }
+    for plugin in config.plugins.iter().flatten() {
+        keys.push(("plugin", plugin.name.clone()));
+    }
+    for name in config
+        .middleware_groups
+        .iter()
+        .flat_map(|groups| groups.keys())
+    {
+        keys.push(("middleware group", name.clone()));
+    }
+    for tls in config.tls.iter().flatten() {
+        for domain in &tls.domains {
+            keys.push(("TLS domain", domain.clone()));
+        }
+    }
+    keys
+}
+
+/// Drop the entries of a file that another file overrides
+fn retain_owned(config: &mut ProxyConfig, owned: impl Fn(EntryKey) -> bool) {
+    if config.header_selector.is_some() && !owned(("header_selector", String::new())) {
+        config.header_selector = None;
+    }
+    if let Some(services) = config.services.as_mut() {
+        services.retain(|service| owned(("service", service.name.clone())));
+    }
+    if let Some(routes) = config.routes.as_mut() {
+        routes.retain(|route| owned(("route", route.name.clone())));
+    }
+    if let Some(plugins) = config.plugins.as_mut() {
+        plugins.retain(|plugin| owned(("plugin", plugin.name.clone())));
+    }
+    if let Some(groups) = config.middleware_groups.as_mut() {
+        groups.retain(|name, _| owned(("middleware group", name.clone())));
+    }
+    if let Some(tls) = config.tls.as_mut() {
+        tls.retain(|tls| {
+            tls.domains
+                .iter()
+                .all(|domain| owned(("TLS domain", domain.clone())))
+        });
+    }
+}
+
+/// Parse a proxy config file in the format named by its extension
+///
+/// `.json` and `.toml` files use the same schema as YAML, which is the
+/// default for every other extension. Errors name the field they are about.
+fn parse_file(path: &str, content: &str) -> Result<ProxyConfig, NylonError> {
+    let parsed = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("json") => {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(content))
+                .map_err(|e| e.to_string())
+        }
+        Some("toml") => serde_path_to_error::deserialize(toml::Deserializer::new(content))
+            .map_err(|e| e.to_string()),
+        _ => crate::utils::parse_yaml(content).map_err(|e| e.to_string()),
+    };
+    let config = parsed.map_err(|e| NylonError::ConfigError(format!("{}: {}", path, e)))?;
+    check_templates(&config).map_err(|e| NylonError::ConfigError(format!("{}: {}", path, e)))?;
+    Ok(config)
+}
+
+/// Parse every `${...}` template and check builtin plugin payloads of a file,
+/// naming the key of the first invalid one
+fn check_templates(config: &ProxyConfig) -> Result<(), String> {
+    let check = |key: String, value: &str| {
+        extract_and_parse_templates(value)
+            .map(|_| ())
+            .map_err(|e| match e {
+                NylonError::ConfigError(msg) => format!("{}: {}", key, msg),
+                e => format!("{}: {}", key, e),
+            })
+    };
+    let check_payload = |key: String, payload: &Option<serde_json::Value>| {
+        let Some(payload) = payload else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        walk_json(payload, String::new(), &mut |path, value| {
+            if result.is_ok()
+                && let Some(s) = value.as_str()
+            {
+                result = check(format!("{}.payload.{}", key, path), s);
+            }
+        });
+        result
+    };
+    let check_middleware = |key: String, middleware: &[MiddlewareItem]| {
+        for (i, m) in middleware.iter().enumerate() {
+            check_payload(format!("{}[{}]", key, i), &m.payload)?;
+            if let Some(plugin) = &m.plugin {
+                PluginManager::check_payload(plugin, m.payload.as_ref())
+                    .map_err(|e| format!("{}[{}].payload: {}", key, i, e))?;
+            }
+        }
+        Ok::<(), String>(())
+    };
+
+    for service in config.services.iter().flatten() {
+        let key = format!("services.{}", service.name);
+        if let Some(plugin) = &service.plugin {
+            check_payload(format!("{}.plugin", key), &plugin.payload)?;
+        }
+        if let Some(template) = service
+            .static_conf
+            .as_ref()
+            .and_then(|conf| conf.autoindex_template.as_ref())
+        {
+            check(format!("{}.static.autoindex_template", key), template)?;
+        }
+        if let Some(template) = &service.template_conf {
+            check(format!("{}.template.body", key), &template.body)?;
+            for (name, value) in template.headers.iter().flatten() {
+                check(format!("{}.template.headers.{}", key, name), value)?;
+            }
+        }
+    }
+    for route in config.routes.iter().flatten() {
+        let key = format!("routes.{}", route.name);
+        for (status, page) in route.error_pages.iter().flatten() {
+            for (kind, template) in [("html", &page.html), ("json", &page.json)] {
+                if let Some(template) = template {
+                    check(format!("{}.error_pages.{}.{}", key, status, kind), template)?;
+                }
+            }
+        }
+        check_middleware(
+            format!("{}.middleware", key),
+            route.middleware.as_deref().unwrap_or_default(),
+        )?;
+        for (i, path) in route.paths.iter().enumerate() {
+            if let Some(response) = &path.response {
+                check(
+                    format!("{}.paths[{}].response.body", key, i),
+                    &response.body,
+                )?;
+                for (name, value) in response.headers.iter().flatten() {
+                    check(
+                        format!("{}.paths[{}].response.headers.{}", key, i, name),
+                        value,
+                    )?;
+                }
+            }
+            check_middleware(
+                format!("{}.paths[{}].middleware", key, i),
+                path.middleware.as_deref().unwrap_or_default(),
+            )?;
+        }
+    }
+    for (name, middleware) in config.middleware_groups.iter().flatten() {
+        check_middleware(format!("middleware_groups.{}", name), middleware)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+services:
+  - name: api
+    service_type: http
+    endpoints:
+      - ip: 10.0.0.1
+        port: 8080
+routes:
+  - name: main
+    route:
+      type: host
+      value: example.com
+    paths:
+      - path: /api/*
+        service:
+          name: api
+"#;
+
+    const JSON: &str = r#"{
+  "services": [
+    {"name": "api", "service_type": "http", "endpoints": [{"ip": "10.0.0.1", "port": 8080}]}
+  ],
+  "routes": [
+    {
+      "name": "main",
+      "route": {"type": "host", "value": "example.com"},
+      "paths": [{"path": "/api/*", "service": {"name": "api"}}]
+    }
+  ]
+}"#;
+
+    const TOML: &str = r#"
+[[services]]
+name = "api"
+service_type = "http"
+endpoints = [{ ip = "10.0.0.1", port = 8080 }]
+
+[[routes]]
+name = "main"
+route = { type = "host", value = "example.com" }
+paths = [{ path = "/api/*", service = { name = "api" } }]
+"#;
+
+    fn summary(config: &ProxyConfig) -> (String, u16, String, String) {
+        let service = &config.services.as_ref().unwrap()[0];
+        let route = &config.routes.as_ref().unwrap()[0];
+        (
+            service.name.clone(),
+            service.endpoints.as_ref().unwrap()[0].port,
+            route.route.value.clone(),
+            route.paths[0].service.name.clone(),
+        )
+    }
+
+    #[test]
+    fn test_json_and_toml_match_yaml() {
+        let yaml = parse_file("proxy/a.yaml", YAML).unwrap();
+        let json = parse_file("proxy/a.json", JSON).unwrap();
+        let toml = parse_file("proxy/a.toml", TOML).unwrap();
+        assert_eq!(summary(&json), summary(&yaml));
+        assert_eq!(summary(&toml), summary(&yaml));
+    }
+
+    #[test]
+    fn test_errors_name_the_file_and_field() {
+        let json = JSON.replace("8080", "\"http\"");
+        let err = parse_file("proxy/a.json", &json).unwrap_err().to_string();
+        assert!(err.contains("proxy/a.json"), "{}", err);
+        assert!(err.contains("services[0].endpoints[0].port"), "{}", err);
+
+        let toml = TOML.replace("name = \"main\"", "name = \"main\"\nunknown = 1");
+        let err = parse_file("proxy/a.toml", &toml).unwrap_err().to_string();
+        assert!(err.contains("proxy/a.toml"), "{}", err);
+        assert!(err.contains("unknown"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_templates_name_their_key() {
+        let yaml = YAML.replace(
+            "        service:\n          name: api\n",
+            "        service:\n          name: api\n        middleware:\n          - plugin: RequestHeaderModifier\n            payload:\n              set:\n                - name: x-user\n                  value: \"${cokie(user)}\"\n",
+        );
+        let err = parse_file("proxy/a.yaml", &yaml).unwrap_err().to_string();
+        assert!(err.contains("proxy/a.yaml"), "{}", err);
+        assert!(
+            err.contains("routes.main.paths[0].middleware[0].payload.set[0].value"),
+            "{}",
+            err
+        );
+        assert!(err.contains("unknown function cokie()"), "{}", err);
+    }
+
+    #[test]
+    fn test_builtin_payloads_are_checked_at_load() {
+        let yaml = YAML.replace(
+            "        service:\n          name: api\n",
+            "        service:\n          name: api\n        middleware:\n          - plugin: Oidc\n            payload:\n              issuer: https://accounts.example.com\n              client_id: nylon\n",
+        );
+        let err = parse_file("proxy/a.yaml", &yaml).unwrap_err().to_string();
+        assert!(
+            err.contains("routes.main.paths[0].middleware[0].payload"),
+            "{}",
+            err
+        );
+        assert!(err.contains("cookie_secret"), "{}", err);
+
+        let yaml = yaml.replace(
+            "client_id: nylon\n",
+            "client_id: nylon\n              cookie_secret: \"${env(OIDC_COOKIE_SECRET)}\"\n",
+        );
+        assert!(parse_file("proxy/a.yaml", &yaml).is_ok());
+    }
+
+    fn template_config(service: &str, paths: &str) -> Result<(), String> {
+        let yaml = format!(
+            "services:\n  - name: stub\n    service_type: template\n{}\n  - name: api\n    service_type: http\n    endpoints:\n      - ip: 10.0.0.1\n        port: 8080\nroutes:\n  - name: main\n    route:\n      type: host\n      value: example.com\n    paths:\n{}",
+            service, paths
+        );
+        let config = parse_file("proxy/a.yaml", &yaml).map_err(|e| e.to_string())?;
+        config.validate().map_err(|e| e.to_string())
+    }
+
+    const STUB_TEMPLATE: &str = "    template:\n      body: stub";
+    const RESPONSE: &str = "        response:\n          status: 503\n          body: down\n";
+
+    #[test]
+    fn test_paths_may_override_a_template() {
+        let path = format!(
+            "      - path: /\n        service:\n          name: stub\n{}",
+            RESPONSE
+        );
+        assert_eq!(template_config(STUB_TEMPLATE, &path), Ok(()));
+        // without a template of its own, every path has to set a response
+        assert_eq!(template_config("", &path), Ok(()));
+
+        let path = "      - path: /\n        service:\n          name: stub\n";
+        let err = template_config("", path).unwrap_err();
+        assert!(err.contains("needs a response"), "{}", err);
+        assert_eq!(template_config(STUB_TEMPLATE, path), Ok(()));
+    }
+
+    #[test]
+    fn test_path_responses_are_validated() {
+        let path = format!(
+            "      - path: /\n        service:\n          name: api\n{}",
+            RESPONSE
+        );
+        let err = template_config(STUB_TEMPLATE, &path).unwrap_err();
+        assert!(err.contains("api is not a template service"), "{}", err);
+
+        let path = format!(
+            "      - path: /\n        service:\n          name: stub\n{}",
+            RESPONSE.replace("503", "999")
+        );
+        let err = template_config(STUB_TEMPLATE, &path).unwrap_err();
+        assert!(err.contains("invalid status 999"), "{}", err);
+
+        let path = format!(
+            "      - path: /\n        service:\n          name: stub\n{}",
+            RESPONSE.replace("down", "${nope()}")
+        );
+        let err = template_config(STUB_TEMPLATE, &path).unwrap_err();
+        assert!(
+            err.contains("routes.main.paths[0].response.body"),
+            "{}",
+            err
+        );
+
+        let response = RESPONSE.replace("status: 503", "type: response");
+        let path = format!(
+            "      - path: /\n        service:\n          name: stub\n{}",
+            response
+        );
+        let err = template_config(STUB_TEMPLATE, &path).unwrap_err();
+        assert!(err.contains("unknown field `type`"), "{}", err);
+    }
+
+    #[test]
+    fn test_fallbacks_need_a_template() {
+        let path =
+            "      - path: /\n        service:\n          name: api\n        fallback: stub\n";
+        let err = template_config("", path).unwrap_err();
+        assert!(err.contains("without a template"), "{}", err);
+        assert_eq!(template_config(STUB_TEMPLATE, path), Ok(()));
+    }
+
+    #[test]
+    fn test_other_extensions_are_yaml() {
+        let config = parse_file("proxy/a.conf", YAML).unwrap();
+        assert_eq!(summary(&config).0, "api");
+        assert!(parse_file("proxy/a.json", YAML).is_err());
+    }
+
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("nylon-dir-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir.to_string_lossy().to_string()
+    }
+
+    fn service(name: &str, port: u16) -> String {
+        format!(
+            "  - name: {}\n    service_type: http\n    endpoints:\n      - ip: 10.0.0.1\n        port: {}\n",
+            name, port
+        )
+    }
+
+    fn ports(config: &ProxyConfig) -> Vec<(String, u16)> {
+        let mut ports: Vec<_> = config
+            .services
+            .iter()
+            .flatten()
+            .map(|s| (s.name.clone(), s.endpoints.as_ref().unwrap()[0].port))
+            .collect();
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn test_higher_priority_file_wins() {
+        let base = format!("services:\n{}{}", service("api", 80), service("web", 81));
+        let over = format!("priority: 10\nservices:\n{}", service("api", 8080));
+        // the override sorts first, so path order does not decide
+        let dir = config_dir(
+            "priority",
+            &[("a/override.yaml", &over), ("b/base.yaml", &base)],
+        );
+        let (config, errors) = parse_dir(&dir).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            ports(&config),
+            vec![("api".to_string(), 8080), ("web".to_string(), 81)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_priority_is_a_conflict() {
+        let a = format!("services:\n{}", service("api", 80));
+        let b = format!("services:\n{}", service("api", 81));
+        let c = format!("priority: 5\nservices:\n{}", service("web", 82));
+        let dir = config_dir(
+            "conflict",
+            &[("a.yaml", &a), ("b.yaml", &b), ("c.yaml", &c)],
+        );
+        let (config, errors) = parse_dir(&dir).unwrap();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let err = errors[0].to_string();
+        assert!(err.contains("service api"), "{}", err);
+        assert!(err.contains("a.yaml") && err.contains("b.yaml"), "{}", err);
+        // the first file keeps the name, other names are merged
+        assert_eq!(
+            ports(&config),
+            vec![("api".to_string(), 80), ("web".to_string(), 82)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_a_bad_file_is_reported_and_left_out() {
+        let good = format!("services:\n{}", service("api", 80));
+        let dir = config_dir("bad", &[("good.yaml", &good), ("bad.yaml", "services: [")]);
+        let (config, errors) = parse_dir(&dir).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("bad.yaml"));
+        assert_eq!(ports(&config), vec![("api".to_string(), 80)]);
+        assert!(ProxyConfig::from_dir(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,435 @@
+//! Proxy config from a remote source
+//!
+//! `config_dir` may name a remote source instead of a directory:
+//!
+//! - `http://...` or `https://...`: one config document, revalidated with its ETag
+//! - `etcd://host:port/prefix`: every key under `/prefix` through the etcd v3 JSON gateway
+//! - `consul://host:port/prefix`: every key under `prefix` in Consul KV
+//!
+//! Fetched files are written to a local cache that is parsed like a config
+//! directory, so a node still starts from its last good copy when the source
+//! cannot be reached. Fetching blocks, so async code runs [`fetch`] and
+//! [`poll`] on a blocking thread; parsing only reads the cache.
+
+use crate::runtime::{RemoteConfig, RuntimeConfig};
+use crate::utils::read_dir_recursive;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use nylon_error::NylonError;
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIGNATURE_HEADER: &str = "x-nylon-signature";
+/// Key next to each etcd or Consul key holding its signature
+const SIGNATURE_SUFFIX: &str = ".sig";
+const MAX_DEPTH: u16 = 10;
+
+/// File name (relative to the cache) -> content
+type Files = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Http(String),
+    Etcd { endpoint: String, prefix: String },
+    Consul { endpoint: String, prefix: String },
+}
+
+impl Source {
+    /// The remote source named by `config_dir`, if it is not a local path
+    pub fn parse(dir: &str) -> Option<Self> {
+        if dir.starts_with("http://") || dir.starts_with("https://") {
+            return Some(Self::Http(dir.to_string()));
+        }
+        let (scheme, rest) = dir.split_once("://")?;
+        let (host, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let endpoint = format!("http://{}", host);
+        match scheme {
+            "etcd" => Some(Self::Etcd {
+                endpoint,
+                prefix: format!("/{}", prefix),
+            }),
+            "consul" => Some(Self::Consul {
+                endpoint,
+                prefix: prefix.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Refresh the cache of `dir` if it is a remote source; true when it changed
+///
+/// A failed fetch is only an error when nothing has been cached yet.
+pub fn fetch(dir: &str) -> Result<bool, NylonError> {
+    let Some(source) = Source::parse(dir) else {
+        return Ok(false);
+    };
+    let config = remote_config();
+    match sync(&config, &source, dir) {
+        Ok(changed) => Ok(changed),
+        Err(e) => {
+            let files = cache_dir(&config, dir).join("files");
+            if !files.is_dir() {
+                return Err(e);
+            }
+            tracing::warn!("{}; using the cached copy in {}", e, files.display());
+            Ok(false)
+        }
+    }
+}
+
+/// The cached copy of a remote source, to be parsed like a config directory
+pub fn cached(dir: &str) -> Result<PathBuf, NylonError> {
+    let files = cache_dir(&remote_config(), dir).join("files");
+    if !files.is_dir() {
+        return Err(error(dir, "not fetched yet"));
+    }
+    Ok(files)
+}
+
+/// Fetch the configured `config_dir` if it is remote; true when it changed
+pub fn poll() -> Result<bool, NylonError> {
+    let runtime = RuntimeConfig::get()?;
+    let dir = runtime.config_dir.to_string_lossy().to_string();
+    match Source::parse(&dir) {
+        Some(source) => sync(&runtime.remote_config.unwrap_or_default(), &source, &dir),
+        None => Ok(false),
+    }
+}
+
+fn remote_config() -> RemoteConfig {
+    RuntimeConfig::get()
+        .ok()
+        .and_then(|c| c.remote_config)
+        .unwrap_or_default()
+}
+
+fn cache_dir(config: &RemoteConfig, dir: &str) -> PathBuf {
+    let name: String = dir
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    config.cache_dir.join(name)
+}
+
+fn sync(config: &RemoteConfig, source: &Source, dir: &str) -> Result<bool, NylonError> {
+    let root = cache_dir(config, dir);
+    let etag_path = root.join("etag");
+    let (files, etag) = match source {
+        Source::Http(url) => {
+            let etag = fs::read_to_string(&etag_path).ok();
+            match fetch_http(config, url, etag.as_deref())? {
+                Some(fetched) => fetched,
+                None => return Ok(false),
+            }
+        }
+        Source::Etcd { endpoint, prefix } => (fetch_etcd(config, endpoint, prefix)?, None),
+        Source::Consul { endpoint, prefix } => (fetch_consul(config, endpoint, prefix)?, None),
+    };
+    let files = match (source, &config.signing_key) {
+        (Source::Http(_), _) | (_, None) => files,
+        (_, Some(key)) => verify_files(key, files).map_err(|e| error(dir, e))?,
+    };
+    let changed = write_cache(&root.join("files"), &files).map_err(|e| error(dir, e))?;
+    if let Some(etag) = etag {
+        fs::write(&etag_path, etag).map_err(|e| error(dir, e))?;
+    }
+    Ok(changed)
+}
+
+pub(crate) fn agent() -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .http_status_as_error(false)
+        .build()
+        .into()
+}
+
+/// The document and its ETag, or `None` when it is not modified
+fn fetch_http(
+    config: &RemoteConfig,
+    url: &str,
+    etag: Option<&str>,
+) -> Result<Option<(Files, Option<String>)>, NylonError> {
+    let mut request = agent().get(url);
+    if let Some(etag) = etag {
+        request = request.header("If-None-Match", etag);
+    }
+    if let Some(token) = &config.token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    let mut response = request.call().map_err(|e| error(url, e))?;
+    match response.status().as_u16() {
+        304 => return Ok(None),
+        200 => {}
+        status => return Err(error(url, format!("HTTP {}", status))),
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header("etag");
+    let signature = header(SIGNATURE_HEADER);
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(|e| error(url, e))?;
+    if let Some(key) = &config.signing_key {
+        verify(key, &body, signature.as_deref()).map_err(|e| error(url, e))?;
+    }
+
+    // the extension of the URL picks the format, as it does for files
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| name.contains('.'))
+        .unwrap_or("config.yaml");
+    Ok(Some((Files::from([(name.to_string(), body)]), etag)))
+}
+
+fn fetch_etcd(config: &RemoteConfig, endpoint: &str, prefix: &str) -> Result<Files, NylonError> {
+    let url = format!("{}/v3/kv/range", endpoint);
+    // every key from the prefix up to the prefix with its last byte incremented
+    let mut range_end = prefix.as_bytes().to_vec();
+    if let Some(last) = range_end.last_mut() {
+        *last = last.saturating_add(1);
+    }
+    let body = serde_json::json!({
+        "key": STANDARD.encode(prefix),
+        "range_end": STANDARD.encode(&range_end),
+    });
+    let mut request = agent()
+        .post(&url)
+        .header("Content-Type", "application/json");
+    if let Some(token) = &config.token {
+        request = request.header("Authorization", token);
+    }
+    let mut response = request.send(body.to_string()).map_err(|e| error(&url, e))?;
+    if response.status().as_u16() != 200 {
+        return Err(error(&url, format!("HTTP {}", response.status())));
+    }
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(|e| error(&url, e))?;
+    let body: serde_json::Value = serde_json::from_str(&body).map_err(|e| error(&url, e))?;
+
+    let mut files = Files::new();
+    for kv in body["kvs"].as_array().into_iter().flatten() {
+        let key = decode(&kv["key"]).ok_or_else(|| error(&url, "malformed key"))?;
+        // etcd leaves out empty values
+        let value = decode(&kv["value"]).unwrap_or_default();
+        if let Some(name) = file_name(&key, prefix) {
+            files.insert(name, value);
+        }
+    }
+    Ok(files)
+}
+
+fn fetch_consul(config: &RemoteConfig, endpoint: &str, prefix: &str) -> Result<Files, NylonError> {
+    let url = format!("{}/v1/kv/{}?recurse=true", endpoint, prefix);
+    let mut request = agent().get(&url);
+    if let Some(token) = &config.token {
+        request = request.header("X-Consul-Token", token);
+    }
+    let mut response = request.call().map_err(|e| error(&url, e))?;
+    match response.status().as_u16() {
+        // no keys under the prefix
+        404 => return Ok(Files::new()),
+        200 => {}
+        status => return Err(error(&url, format!("HTTP {}", status))),
+    }
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(|e| error(&url, e))?;
+    let body: serde_json::Value = serde_json::from_str(&body).map_err(|e| error(&url, e))?;
+
+    let mut files = Files::new();
+    for kv in body.as_array().into_iter().flatten() {
+        let Some(key) = kv["Key"].as_str() else {
+            continue;
+        };
+        if let Some(name) = file_name(key, prefix) {
+            files.insert(name, decode(&kv["Value"]).unwrap_or_default());
+        }
+    }
+    Ok(files)
+}
+
+fn decode(value: &serde_json::Value) -> Option<String> {
+    let bytes = STANDARD.decode(value.as_str()?).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Path of a key below the prefix; folders and keys that would leave the cache are skipped
+fn file_name(key: &str, prefix: &str) -> Option<String> {
+    let rest = key.strip_prefix(prefix)?;
+    if !prefix.is_empty() && !prefix.ends_with('/') && !rest.starts_with('/') {
+        return None;
+    }
+    let name = rest.trim_start_matches('/');
+    if name.is_empty()
+        || name
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn verify(key: &str, body: &str, signature: Option<&str>) -> Result<(), String> {
+    let signature = signature.ok_or("missing signature")?;
+    let hex = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let expected = decode_hex(hex).ok_or("malformed signature")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).map_err(|e| e.to_string())?;
+    mac.update(body.as_bytes());
+    mac.verify_slice(&expected)
+        .map_err(|_| "signature does not match".to_string())
+}
+
+/// Check every key against its `<key>.sig` key and drop the signatures
+fn verify_files(key: &str, mut files: Files) -> Result<Files, String> {
+    let names: Vec<String> = files
+        .keys()
+        .filter(|name| name.ends_with(SIGNATURE_SUFFIX))
+        .cloned()
+        .collect();
+    let signatures: Files = names
+        .iter()
+        .filter_map(|name| files.remove_entry(name))
+        .collect();
+    for (name, content) in &files {
+        let signature = signatures.get(&format!("{}{}", name, SIGNATURE_SUFFIX));
+        verify(key, content, signature.map(|s| s.trim()))
+            .map_err(|e| format!("{}: {}", name, e))?;
+    }
+    Ok(files)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Replace the cached files; false when they are unchanged
+fn write_cache(dir: &Path, files: &Files) -> std::io::Result<bool> {
+    if read_cache(dir).as_ref() == Some(files) {
+        return Ok(false);
+    }
+    let staging = dir.with_extension("new");
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging)?;
+    for (name, content) in files {
+        let path = staging.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, content)?;
+    }
+    let _ = fs::remove_dir_all(dir);
+    fs::rename(&staging, dir)?;
+    Ok(true)
+}
+
+fn read_cache(dir: &Path) -> Option<Files> {
+    let paths = read_dir_recursive(&dir.to_string_lossy().to_string(), MAX_DEPTH).ok()?;
+    let mut files = Files::new();
+    for path in paths {
+        let name = path.strip_prefix(dir).ok()?.to_string_lossy().to_string();
+        files.insert(name, fs::read_to_string(&path).ok()?);
+    }
+    Some(files)
+}
+
+fn error(source: &str, e: impl Display) -> NylonError {
+    NylonError::ConfigError(format!("{}: {}", source, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sources() {
+        assert_eq!(
+            Source::parse("etcd://10.0.0.1:2379/nylon"),
+            Some(Source::Etcd {
+                endpoint: "http://10.0.0.1:2379".to_string(),
+                prefix: "/nylon".to_string(),
+            })
+        );
+        assert_eq!(
+            Source::parse("consul://consul:8500/nylon/prod"),
+            Some(Source::Consul {
+                endpoint: "http://consul:8500".to_string(),
+                prefix: "nylon/prod".to_string(),
+            })
+        );
+        assert!(matches!(
+            Source::parse("https://config.internal/nylon.yaml"),
+            Some(Source::Http(_))
+        ));
+        assert_eq!(Source::parse("/etc/nylon/config"), None);
+    }
+
+    #[test]
+    fn test_file_names_stay_in_the_cache() {
+        assert_eq!(
+            file_name("/nylon/routes/api.yaml", "/nylon"),
+            Some("routes/api.yaml".to_string())
+        );
+        assert_eq!(file_name("/nylon/routes/", "/nylon"), None);
+        assert_eq!(file_name("/nylon/../etc/passwd", "/nylon"), None);
+        assert_eq!(file_name("/other/api.yaml", "/nylon"), None);
+        assert_eq!(file_name("/nylon-old/api.yaml", "/nylon"), None);
+    }
+
+    fn sign(key: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("sha256={}", hex)
+    }
+
+    #[test]
+    fn test_key_value_files_must_be_signed() {
+        let body = "services: []\n";
+        let signed = Files::from([
+            ("api.yaml".to_string(), body.to_string()),
+            ("api.yaml.sig".to_string(), format!("{}\n", sign("k", body))),
+        ]);
+        assert_eq!(
+            verify_files("k", signed.clone()).unwrap(),
+            Files::from([("api.yaml".to_string(), body.to_string())])
+        );
+
+        let err = verify_files("other", signed).unwrap_err();
+        assert!(err.contains("api.yaml"), "{}", err);
+
+        let unsigned = Files::from([("api.yaml".to_string(), body.to_string())]);
+        assert!(verify_files("k", unsigned).unwrap_err().contains("missing"));
+    }
+}
@@ -1,16 +1,17 @@
 use nylon_error::NylonError;
+use nylon_types::paths::{DEFAULT_ACME_DIR, NYLON_DIR};
+use nylon_types::template::extract_and_parse_templates;
 use nylon_types::websocket::WebSocketAdapterConfig;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, str::FromStr};
-
-const DEFAULT_NYLON_DIR: &str = "/etc/nylon";
+use std::{collections::BTreeMap, path::PathBuf, str::FromStr};
 
 fn default_config_dir() -> PathBuf {
-    PathBuf::from(format!("{}/config", DEFAULT_NYLON_DIR))
+    PathBuf::from(format!("{}/config", NYLON_DIR))
 }
 
 fn default_acme_dir() -> PathBuf {
-    PathBuf::from(format!("{}/acme", DEFAULT_NYLON_DIR))
+    PathBuf::from(DEFAULT_ACME_DIR)
 }
 
 fn default_daemon() -> bool {
@@ -37,24 +38,99 @@ fn default_shutdown_timeout() -> u64 {
     10
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+fn default_otlp_endpoint() -> String {
+    "http://127.0.0.1:4318/v1/traces".to_string()
+}
+
+fn default_service_name() -> String {
+    "nylon".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_keepalive_idle() -> u64 {
+    60
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_count() -> usize {
+    5
+}
+
+fn default_min_body_rate_grace() -> u64 {
+    5
+}
+
+fn default_load_sample_ms() -> u64 {
+    1000
+}
+
+fn default_config_history() -> usize {
+    10
+}
+
+fn default_remote_poll_interval() -> u64 {
+    30
+}
+
+fn default_remote_cache_dir() -> PathBuf {
+    PathBuf::from(format!("{}/remote", NYLON_DIR))
+}
+
+fn default_access_log_sinks() -> Vec<AccessLogSink> {
+    vec![AccessLogSink::Stdout]
+}
+
+fn default_log_max_size_mb() -> u64 {
+    100
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+fn default_syslog_address() -> String {
+    "/dev/log".to_string()
+}
+
+fn default_syslog_tag() -> String {
+    "nylon".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct RuntimeConfig {
     /// HTTP listening addresses
     #[serde(default)]
-    pub http: Vec<String>,
+    pub http: Vec<Listener>,
 
     /// HTTPS listening addresses
     #[serde(default)]
-    pub https: Vec<String>,
+    pub https: Vec<Listener>,
 
     /// Prometheus metrics addresses
     #[serde(default)]
     pub metrics: Vec<String>,
 
-    /// Path to directory containing service and route definitions
+    /// Path to directory containing service and route definitions, or a
+    /// remote source (`https://...`, `etcd://host:port/prefix`,
+    /// `consul://host:port/prefix`)
     #[serde(default = "default_config_dir")]
     pub config_dir: PathBuf,
 
+    /// Fetching and watching a remote `config_dir`
+    #[serde(default)]
+    pub remote_config: Option<RemoteConfig>,
+
+    /// Applied configurations kept for `nylon config rollback`
+    #[serde(default = "default_config_history")]
+    pub config_history: usize,
+
     /// Path to directory containing ACME certificates
     #[serde(default = "default_acme_dir")]
     pub acme: PathBuf,
@@ -66,9 +142,274 @@ pub struct RuntimeConfig {
     /// WebSocket adapter configuration
     #[serde(default)]
     pub websocket: Option<WebSocketAdapterConfig>,
+
+    /// OpenTelemetry tracing configuration
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+
+    /// Access log configuration
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+
+    /// Log a warning for requests slower than this (milliseconds)
+    #[serde(default)]
+    pub slow_request_ms: Option<u64>,
+
+    /// Log a warning for responses larger than this (bytes)
+    #[serde(default)]
+    pub large_response_bytes: Option<u64>,
+
+    /// MaxMind databases for the `geo()` template function
+    #[serde(default)]
+    pub geoip: Option<GeoIpConfig>,
+
+    /// How much of an internal error clients see; logs always get all of it
+    #[serde(default)]
+    pub error_detail: ErrorDetail,
+
+    /// Requests in flight allowed on this node; more get `503`
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+
+    /// Shed a share of requests while CPU or memory use is above a watermark
+    #[serde(default)]
+    pub load_shedding: Option<LoadSheddingConfig>,
+
+    /// Route lookups kept in the route cache; `0` turns it off
+    #[serde(default)]
+    pub route_cache_capacity: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    /// CPU use (percent of all cores) above which requests are shed
+    pub cpu_percent: Option<f64>,
+    /// Memory use (percent of the total) above which requests are shed
+    pub memory_percent: Option<f64>,
+    /// How often usage is sampled (milliseconds)
+    #[serde(default = "default_load_sample_ms")]
+    pub interval_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorDetail {
+    /// Status, error code and message
+    #[default]
+    Full,
+    /// Status and error code
+    CodeOnly,
+    /// Status and its reason phrase
+    Redacted,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// GeoLite2/GeoIP2 City or Country database (.mmdb)
+    #[serde(default)]
+    pub database: Option<PathBuf>,
+
+    /// GeoLite2/GeoIP2 ASN database (.mmdb)
+    #[serde(default)]
+    pub asn_database: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP/HTTP traces endpoint
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+
+    /// Service name reported to the collector
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+
+    /// Ratio of new traces to sample (0.0 - 1.0); sampled parents are always honored
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+/// A listen address, alone or with options for that listener
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(untagged)]
+pub enum Listener {
+    Address(String),
+    Options(ListenerOptions),
+}
+
+impl Listener {
+    pub fn address(&self) -> &str {
+        match self {
+            Listener::Address(address) => address,
+            Listener::Options(options) => &options.address,
+        }
+    }
+
+    pub fn options(&self) -> ListenerOptions {
+        match self {
+            Listener::Address(address) => ListenerOptions {
+                address: address.clone(),
+                ..Default::default()
+            },
+            Listener::Options(options) => options.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerOptions {
+    pub address: String,
+
+    /// Offer HTTP/2: ALPN `h2` on https (on by default), h2c with prior
+    /// knowledge on http (off by default)
+    #[serde(default)]
+    pub h2: Option<bool>,
+
+    /// Keepalive probes on accepted connections
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+
+    /// Set SO_REUSEPORT on the listen socket
+    #[serde(default)]
+    pub reuseport: bool,
+
+    /// Only accept connections that arrive on this interface (Linux)
+    #[serde(default)]
+    pub bind_device: Option<String>,
+
+    /// Limits that close connections of slow clients
+    #[serde(default)]
+    pub slow_clients: Option<SlowClientLimits>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SlowClientLimits {
+    /// Longest a request header may take, however often the client sends a byte
+    #[serde(default)]
+    pub header_timeout_secs: Option<u64>,
+
+    /// Longest wait for the next part of a request body
+    #[serde(default)]
+    pub body_timeout_secs: Option<u64>,
+
+    /// Slowest average request body upload in bytes per second
+    #[serde(default)]
+    pub min_body_rate: Option<u64>,
+
+    /// Time a body upload gets before `min_body_rate` applies
+    #[serde(default = "default_min_body_rate_grace")]
+    pub min_body_rate_grace_secs: u64,
+
+    /// Requests per client IP that may be waiting for their body at once
+    #[serde(default)]
+    pub max_idle_requests_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TcpKeepaliveConfig {
+    /// Idle time before the first probe
+    #[serde(default = "default_keepalive_idle")]
+    pub idle_secs: u64,
+
+    /// Time between probes
+    #[serde(default = "default_keepalive_interval")]
+    pub interval_secs: u64,
+
+    /// Unanswered probes before the connection is dropped
+    #[serde(default = "default_keepalive_count")]
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// Seconds between polls for changes (0 disables watching)
+    #[serde(default = "default_remote_poll_interval")]
+    pub poll_interval_secs: u64,
+
+    /// Where the last fetched config is kept; used when the source is down
+    #[serde(default = "default_remote_cache_dir")]
+    pub cache_dir: PathBuf,
+
+    /// Bearer token for HTTP and etcd, ACL token for Consul
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// HMAC-SHA256 key that HTTP documents and etcd or Consul keys must be signed with
+    #[serde(default)]
+    pub signing_key: Option<String>,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_remote_poll_interval(),
+            cache_dir: default_remote_cache_dir(),
+            token: None,
+            signing_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Record format
+    #[serde(default)]
+    pub format: AccessLogFormat,
+
+    /// Line template for `format: template`
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// JSON record fields (name -> template); replaces the default field set
+    #[serde(default)]
+    pub fields: Option<BTreeMap<String, String>>,
+
+    /// Where records are written
+    #[serde(default = "default_access_log_sinks")]
+    pub sinks: Vec<AccessLogSink>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Combined,
+    Template,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AccessLogSink {
+    Stdout,
+    File {
+        path: PathBuf,
+        /// Rotate once the file grows past this size (0 disables rotation)
+        #[serde(default = "default_log_max_size_mb")]
+        max_size_mb: u64,
+        /// Number of rotated files to keep
+        #[serde(default = "default_log_max_files")]
+        max_files: usize,
+    },
+    Syslog {
+        /// Unix socket path or `host:port` for UDP
+        #[serde(default = "default_syslog_address")]
+        address: String,
+        #[serde(default = "default_syslog_tag")]
+        tag: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PingoraConfig {
     /// Run in daemon mode
     #[serde(default = "default_daemon")]
@@ -126,9 +467,20 @@ impl Default for RuntimeConfig {
             https: vec![],
             metrics: vec![],
             config_dir: default_config_dir(),
+            remote_config: None,
+            config_history: default_config_history(),
             acme: default_acme_dir(),
             pingora: PingoraConfig::default(),
             websocket: None,
+            tracing: None,
+            access_log: None,
+            slow_request_ms: None,
+            large_response_bytes: None,
+            geoip: None,
+            error_detail: ErrorDetail::default(),
+            max_in_flight: None,
+            load_shedding: None,
+            route_cache_capacity: None,
         }
     }
 }
@@ -163,11 +515,38 @@ impl FromStr for RuntimeConfig {
     ///
     /// # Returns
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        serde_yaml_ng::from_str(s).map_err(|e| NylonError::ConfigError(e.to_string()))
+        let config: Self =
+            crate::utils::parse_yaml(s).map_err(|e| NylonError::ConfigError(e.to_string()))?;
+        config.check_templates()?;
+        Ok(config)
     }
 }
 
 impl RuntimeConfig {
+    /// Parse the access log templates, naming the key of the first invalid one
+    fn check_templates(&self) -> Result<(), NylonError> {
+        let Some(access_log) = &self.access_log else {
+            return Ok(());
+        };
+        let check = |key: String, value: &str| {
+            extract_and_parse_templates(value)
+                .map(|_| ())
+                .map_err(|e| match e {
+                    NylonError::ConfigError(msg) => {
+                        NylonError::ConfigError(format!("{}: {}", key, msg))
+                    }
+                    e => NylonError::ConfigError(format!("{}: {}", key, e)),
+                })
+        };
+        if let Some(template) = &access_log.template {
+            check("access_log.template".to_string(), template)?;
+        }
+        for (name, template) in access_log.fields.iter().flatten() {
+            check(format!("access_log.fields.{}", name), template)?;
+        }
+        Ok(())
+    }
+
     /// Load the runtime config from a file
     ///
     /// # Arguments
@@ -178,8 +557,7 @@ impl RuntimeConfig {
     ///
     /// * `Result<Self, NylonError>` - The result of the operation
     pub fn from_file(path: &str) -> Result<Self, NylonError> {
-        let content =
-            std::fs::read_to_string(path).map_err(|e| NylonError::ConfigError(e.to_string()))?;
+        let content = crate::env::read_config(path)?;
         Self::from_str(&content)
     }
 
@@ -190,6 +568,10 @@ impl RuntimeConfig {
     /// * `Result<(), NylonError>` - The result of the operation
     pub fn store(&self) -> Result<(), NylonError> {
         nylon_store::insert(nylon_store::KEY_RUNTIME_CONFIG, self.clone());
+        nylon_store::routes::set_route_cache_capacity(
+            self.route_cache_capacity
+                .unwrap_or(nylon_store::routes::DEFAULT_ROUTE_CACHE_CAPACITY),
+        );
         Ok(())
     }
 
@@ -221,7 +603,6 @@ https:
 metrics:
   - "10.10.0.3:6192"
 config_dir: /etc/nylon/config
-debug: true
 pingora:
   daemon: true
   threads: 6
@@ -237,4 +618,97 @@ pingora:
         assert!(config.pingora.daemon);
         assert_eq!(config.pingora.threads, 6);
     }
+
+    #[test]
+    fn test_parse_listener_options() {
+        let yaml = r#"
+http:
+  - "0.0.0.0:80"
+  - address: "10.10.0.3:8080"
+    h2: true
+    reuseport: true
+    bind_device: eth1
+    tcp_keepalive:
+      idle_secs: 30
+    slow_clients:
+      header_timeout_secs: 10
+      body_timeout_secs: 10
+      min_body_rate: 1024
+"#;
+
+        let config = RuntimeConfig::from_str(yaml).unwrap();
+        assert_eq!(config.http[0].address(), "0.0.0.0:80");
+        assert_eq!(config.http[0].options().h2, None);
+        let options = config.http[1].options();
+        assert_eq!(options.address, "10.10.0.3:8080");
+        assert_eq!(options.h2, Some(true));
+        assert!(options.reuseport);
+        assert_eq!(options.bind_device.as_deref(), Some("eth1"));
+        let keepalive = options.tcp_keepalive.unwrap();
+        assert_eq!(keepalive.idle_secs, 30);
+        assert_eq!(keepalive.count, 5);
+        let slow = options.slow_clients.unwrap();
+        assert_eq!(slow.header_timeout_secs, Some(10));
+        assert_eq!(slow.body_timeout_secs, Some(10));
+        assert_eq!(slow.min_body_rate, Some(1024));
+        assert_eq!(slow.min_body_rate_grace_secs, 5);
+        assert_eq!(slow.max_idle_requests_per_ip, None);
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let err = RuntimeConfig::from_str("pingora:\n  treads: 6\n").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("pingora"), "{}", msg);
+        assert!(msg.contains("unknown field `treads`"), "{}", msg);
+    }
+
+    #[test]
+    fn test_invalid_access_log_templates_name_their_key() {
+        let yaml = "access_log:\n  fields:\n    ip: \"${client_ip}\"\n    agent: \"${heder(user-agent)}\"\n";
+        let msg = RuntimeConfig::from_str(yaml).unwrap_err().to_string();
+        assert!(msg.contains("access_log.fields.agent"), "{}", msg);
+        assert!(msg.contains("unknown function heder()"), "{}", msg);
+
+        let yaml = "access_log:\n  format: template\n  template: \"${path() ${method()}\"\n";
+        let msg = RuntimeConfig::from_str(yaml).unwrap_err().to_string();
+        assert!(msg.contains("access_log.template"), "{}", msg);
+    }
+
+    #[test]
+    fn test_parse_access_log() {
+        let yaml = r#"
+access_log:
+  format: combined
+  sinks:
+    - type: stdout
+    - type: file
+      path: /var/log/nylon/access.log
+      max_size_mb: 50
+    - type: syslog
+"#;
+
+        let config = RuntimeConfig::from_str(yaml).unwrap();
+        let access_log = config.access_log.unwrap();
+        assert_eq!(access_log.format, AccessLogFormat::Combined);
+        assert_eq!(access_log.sinks.len(), 3);
+        match &access_log.sinks[1] {
+            AccessLogSink::File {
+                max_size_mb,
+                max_files,
+                ..
+            } => {
+                assert_eq!(*max_size_mb, 50);
+                assert_eq!(*max_files, 5);
+            }
+            other => panic!("unexpected sink: {:?}", other),
+        }
+        match &access_log.sinks[2] {
+            AccessLogSink::Syslog { address, tag } => {
+                assert_eq!(address, "/dev/log");
+                assert_eq!(tag, "nylon");
+            }
+            other => panic!("unexpected sink: {:?}", other),
+        }
+    }
 }
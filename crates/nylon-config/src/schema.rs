@@ -0,0 +1,15 @@
+//! JSON Schema of the config files, for editors and CI checks
+
+use crate::runtime::RuntimeConfig;
+use nylon_types::proxy::ProxyConfig;
+use schemars::{Schema, schema_for};
+
+/// Schema of the runtime config file
+pub fn runtime() -> Schema {
+    schema_for!(RuntimeConfig)
+}
+
+/// Schema of one file in `config_dir`
+pub fn proxy() -> Schema {
+    schema_for!(ProxyConfig)
+}
@@ -0,0 +1,396 @@
+//! SOPS encrypted configuration files
+//!
+//! Files encrypted with [SOPS](https://github.com/getsops/sops) using age
+//! recipients are decrypted at load time. The age identity is read from
+//! `SOPS_AGE_KEY` or the file named by `SOPS_AGE_KEY_FILE`; decrypted values
+//! only ever exist in memory.
+//!
+//! The document's message authentication code is checked after decryption,
+//! so a changed, added or removed value is refused. SOPS also covers YAML
+//! comments with it, which are lost on parsing; files with comments fail the
+//! check.
+
+use aes_gcm::{
+    AesGcm,
+    aead::{Aead, KeyInit, Payload, consts::U32, generic_array::GenericArray},
+    aes::Aes256,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use nylon_error::NylonError;
+use serde_yaml_ng::{Mapping, Number, Value};
+use sha2::{Digest, Sha512};
+use std::io::Read;
+use std::str::FromStr;
+
+/// SOPS uses AES-256-GCM with a 32 byte IV
+type SopsCipher = AesGcm<Aes256, U32>;
+
+const SOPS_KEY: &str = "sops";
+
+/// Read a config file, decrypting it when it is a SOPS document
+pub fn read_to_string(path: &str) -> Result<String, NylonError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| NylonError::ConfigError(format!("{}: {}", path, e)))?;
+    decrypt(&content).map_err(|e| match e {
+        NylonError::ConfigError(msg) => NylonError::ConfigError(format!("{}: {}", path, msg)),
+        e => e,
+    })
+}
+
+/// Decrypt a SOPS document, or return plain YAML unchanged
+pub fn decrypt(content: &str) -> Result<String, NylonError> {
+    let Some(mut document) = encrypted_document(content) else {
+        return Ok(content.to_string());
+    };
+    let metadata = document
+        .remove(SOPS_KEY)
+        .ok_or_else(|| NylonError::ConfigError("missing sops metadata".to_string()))?;
+    let data_key = data_key(&metadata)?;
+    let tree = decrypt_document(document, &metadata, &data_key)?;
+    // JSON documents stay JSON so they are parsed by the same format
+    if content.trim_start().starts_with('{') {
+        return serde_json::to_string(&tree).map_err(|e| NylonError::ConfigError(e.to_string()));
+    }
+    serde_yaml_ng::to_string(&tree).map_err(|e| NylonError::ConfigError(e.to_string()))
+}
+
+/// The top level mapping, if the document carries SOPS metadata
+fn encrypted_document(content: &str) -> Option<Mapping> {
+    match serde_yaml_ng::from_str::<Value>(content).ok()? {
+        Value::Mapping(mapping) if mapping.contains_key(SOPS_KEY) => Some(mapping),
+        _ => None,
+    }
+}
+
+/// Unwrap the data key with one of the configured age identities
+fn data_key(metadata: &Value) -> Result<Vec<u8>, NylonError> {
+    let identities = age_identities()?;
+    let recipients = metadata
+        .get("age")
+        .and_then(Value::as_sequence)
+        .ok_or_else(|| {
+            NylonError::ConfigError("only age encrypted SOPS files are supported".to_string())
+        })?;
+
+    for recipient in recipients {
+        let Some(enc) = recipient.get("enc").and_then(Value::as_str) else {
+            continue;
+        };
+        let Ok(decryptor) = age::Decryptor::new(age::armor::ArmoredReader::new(enc.as_bytes()))
+        else {
+            continue;
+        };
+        let Ok(mut reader) = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+        else {
+            continue;
+        };
+        let mut key = vec![];
+        if reader.read_to_end(&mut key).is_ok() && key.len() == 32 {
+            return Ok(key);
+        }
+    }
+    Err(NylonError::ConfigError(
+        "no age identity can decrypt the SOPS data key".to_string(),
+    ))
+}
+
+fn age_identities() -> Result<Vec<age::x25519::Identity>, NylonError> {
+    let keys = match std::env::var("SOPS_AGE_KEY") {
+        Ok(keys) => keys,
+        Err(_) => {
+            let path = std::env::var("SOPS_AGE_KEY_FILE").map_err(|_| {
+                NylonError::ConfigError(
+                    "encrypted config requires SOPS_AGE_KEY or SOPS_AGE_KEY_FILE".to_string(),
+                )
+            })?;
+            std::fs::read_to_string(&path).map_err(|e| {
+                NylonError::ConfigError(format!("Unable to read age key file {}: {}", path, e))
+            })?
+        }
+    };
+    let identities: Vec<age::x25519::Identity> = keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .filter_map(|line| age::x25519::Identity::from_str(line).ok())
+        .collect();
+    if identities.is_empty() {
+        return Err(NylonError::ConfigError(
+            "no valid age identity found".to_string(),
+        ));
+    }
+    Ok(identities)
+}
+
+/// Decrypt the document and check it against the MAC in `metadata`
+fn decrypt_document(document: Mapping, metadata: &Value, key: &[u8]) -> Result<Value, NylonError> {
+    let mac_only_encrypted = metadata
+        .get("mac_only_encrypted")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let mut mac = Mac {
+        hasher: Sha512::new(),
+        only_encrypted: mac_only_encrypted,
+    };
+    let mut tree = Value::Mapping(document);
+    decrypt_tree(&mut tree, &mut vec![], key, &mut mac)?;
+
+    let missing = || NylonError::ConfigError("missing sops mac".to_string());
+    let last_modified = metadata
+        .get("lastmodified")
+        .and_then(Value::as_str)
+        .ok_or_else(missing)?;
+    let expected = metadata
+        .get("mac")
+        .and_then(Value::as_str)
+        .ok_or_else(missing)?;
+    let expected = match decrypt_value(expected, last_modified, key)? {
+        Value::String(expected) => expected,
+        _ => return Err(missing()),
+    };
+    let actual = mac
+        .hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<String>();
+    if !actual.eq_ignore_ascii_case(&expected) {
+        return Err(NylonError::ConfigError(
+            "SOPS MAC mismatch, the file was changed after it was encrypted".to_string(),
+        ));
+    }
+    Ok(tree)
+}
+
+/// Running MAC over the plaintext leaves, in document order
+struct Mac {
+    hasher: Sha512,
+    /// `mac_only_encrypted`: unencrypted values are not covered
+    only_encrypted: bool,
+}
+
+/// Decrypt every `ENC[...]` leaf; the path of keys is the authenticated data
+fn decrypt_tree(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    key: &[u8],
+    mac: &mut Mac,
+) -> Result<(), NylonError> {
+    match value {
+        Value::Mapping(mapping) => {
+            for (k, v) in mapping.iter_mut() {
+                let name = match k {
+                    Value::String(s) => s.clone(),
+                    other => serde_yaml_ng::to_string(other)
+                        .map(|s| s.trim_end().to_string())
+                        .unwrap_or_default(),
+                };
+                path.push(name);
+                decrypt_tree(v, path, key, mac)?;
+                path.pop();
+            }
+        }
+        // Sequence items share the path of their parent key
+        Value::Sequence(items) => {
+            for item in items.iter_mut() {
+                decrypt_tree(item, path, key, mac)?;
+            }
+        }
+        Value::String(s) if s.starts_with("ENC[") => {
+            let aad = format!("{}:", path.join(":"));
+            *value = decrypt_value(s, &aad, key)?;
+            mac.hasher.update(leaf_bytes(value));
+        }
+        Value::Tagged(tagged) => decrypt_tree(&mut tagged.value, path, key, mac)?,
+        leaf => {
+            if !mac.only_encrypted {
+                mac.hasher.update(leaf_bytes(leaf));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A leaf as SOPS writes it into the MAC
+fn leaf_bytes(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) if n.is_f64() => n.as_f64().unwrap_or_default().to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Decrypt `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`
+fn decrypt_value(encoded: &str, aad: &str, key: &[u8]) -> Result<Value, NylonError> {
+    let invalid = || NylonError::ConfigError(format!("invalid encrypted value at {}", aad));
+    let inner = encoded
+        .strip_prefix("ENC[AES256_GCM,")
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(invalid)?;
+
+    let (mut data, mut iv, mut tag, mut kind) = (None, None, None, None);
+    for part in inner.split(',') {
+        let (name, field) = part.split_once(':').ok_or_else(invalid)?;
+        match name {
+            "data" => data = Some(field),
+            "iv" => iv = Some(field),
+            "tag" => tag = Some(field),
+            "type" => kind = Some(field),
+            _ => {}
+        }
+    }
+    let decode = |field: Option<&str>| {
+        field
+            .and_then(|f| STANDARD.decode(f).ok())
+            .ok_or_else(invalid)
+    };
+    let mut ciphertext = decode(data)?;
+    let iv = decode(iv)?;
+    ciphertext.extend(decode(tag)?);
+    if iv.len() != 32 {
+        return Err(invalid());
+    }
+
+    let cipher = SopsCipher::new_from_slice(key).map_err(|_| invalid())?;
+    let plaintext = cipher
+        .decrypt(
+            GenericArray::from_slice(&iv),
+            Payload {
+                msg: &ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| NylonError::ConfigError(format!("unable to decrypt value at {}", aad)))?;
+    let plaintext = String::from_utf8(plaintext).map_err(|_| invalid())?;
+
+    Ok(match kind.unwrap_or("str") {
+        "int" => Value::Number(plaintext.parse::<i64>().map_err(|_| invalid())?.into()),
+        "float" => Value::Number(Number::from(
+            plaintext.parse::<f64>().map_err(|_| invalid())?,
+        )),
+        "bool" => match plaintext.as_str() {
+            "true" | "True" => Value::Bool(true),
+            "false" | "False" => Value::Bool(false),
+            _ => return Err(invalid()),
+        },
+        "str" | "bytes" => Value::String(plaintext),
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_value(plaintext: &str, kind: &str, aad: &str, key: &[u8]) -> String {
+        let iv = [7u8; 32];
+        let cipher = SopsCipher::new_from_slice(key).unwrap();
+        let mut sealed = cipher
+            .encrypt(
+                GenericArray::from_slice(&iv),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .unwrap();
+        let tag = sealed.split_off(sealed.len() - 16);
+        format!(
+            "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+            STANDARD.encode(sealed),
+            STANDARD.encode(iv),
+            STANDARD.encode(tag),
+            kind
+        )
+    }
+
+    /// Metadata with the MAC SOPS would write for `leaves`
+    fn metadata(leaves: &[&str], key: &[u8]) -> Value {
+        let mut hasher = Sha512::new();
+        for leaf in leaves {
+            hasher.update(leaf);
+        }
+        let mac = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        let last_modified = "2024-01-01T00:00:00Z";
+        serde_yaml_ng::from_str(&format!(
+            "lastmodified: '{}'\nmac: '{}'\n",
+            last_modified,
+            encrypt_value(&mac, "str", last_modified, key)
+        ))
+        .unwrap()
+    }
+
+    fn document(yaml: &str) -> Mapping {
+        serde_yaml_ng::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_plain_yaml_is_unchanged() {
+        let yaml = "http:\n  - 127.0.0.1:80\n";
+        assert_eq!(decrypt(yaml).unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_decrypt_tree() {
+        let key = [42u8; 32];
+        let yaml = format!(
+            "plugins:\n  - payload:\n      secret: '{}'\n      port: '{}'\n",
+            encrypt_value("s3cr3t", "str", "plugins:payload:secret:", &key),
+            encrypt_value("6379", "int", "plugins:payload:port:", &key),
+        );
+        let metadata = metadata(&["s3cr3t", "6379"], &key);
+        let tree = decrypt_document(document(&yaml), &metadata, &key).unwrap();
+
+        let payload = &tree["plugins"][0]["payload"];
+        assert_eq!(payload["secret"].as_str(), Some("s3cr3t"));
+        assert_eq!(payload["port"].as_i64(), Some(6379));
+    }
+
+    #[test]
+    fn test_decrypt_rejects_moved_value() {
+        let key = [42u8; 32];
+        let yaml = format!(
+            "other: '{}'\n",
+            encrypt_value("s3cr3t", "str", "secret:", &key)
+        );
+        let metadata = metadata(&["s3cr3t"], &key);
+        assert!(decrypt_document(document(&yaml), &metadata, &key).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_rejects_changed_plain_value() {
+        let key = [42u8; 32];
+        let secret = encrypt_value("s3cr3t", "str", "secret:", &key);
+        let metadata = metadata(&["s3cr3t", "8080"], &key);
+
+        let original = format!("secret: '{}'\nport: 8080\n", secret);
+        assert!(decrypt_document(document(&original), &metadata, &key).is_ok());
+
+        let changed = format!("secret: '{}'\nport: 9090\n", secret);
+        assert!(decrypt_document(document(&changed), &metadata, &key).is_err());
+        let added = format!("secret: '{}'\nport: 8080\ndebug: true\n", secret);
+        assert!(decrypt_document(document(&added), &metadata, &key).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_bool() {
+        let key = [42u8; 32];
+        let on = encrypt_value("true", "bool", "on:", &key);
+        let tree = decrypt_document(
+            document(&format!("on: '{}'\n", on)),
+            &metadata(&["true"], &key),
+            &key,
+        )
+        .unwrap();
+        assert_eq!(tree["on"].as_bool(), Some(true));
+
+        let yes = encrypt_value("yes", "bool", "on:", &key);
+        assert!(decrypt_value(&yes, "on:", &key).is_err());
+    }
+}
@@ -27,3 +27,10 @@ pub fn read_dir_recursive(dir: &String, max_depth: u16) -> Result<Vec<PathBuf>,
     }
     Ok(files)
 }
+
+/// Parse YAML, naming the field an error is about (`routes[0].paths[1].service: ...`)
+pub fn parse_yaml<T: serde::de::DeserializeOwned>(
+    content: &str,
+) -> Result<T, serde_path_to_error::Error<serde_yaml_ng::Error>> {
+    serde_path_to_error::deserialize(serde_yaml_ng::Deserializer::from_str(content))
+}
@@ -1,4 +1,3 @@
-use serde_json::{Value, json};
 use thiserror::Error;
 
 #[derive(Error, Debug, Clone)]
@@ -36,6 +35,18 @@ pub enum NylonError {
     #[error("An unexpected internal server error occurred: {0}")]
     InternalServerError(String),
 
+    #[error("Could not connect to the upstream: {0}")]
+    UpstreamConnectError(String),
+
+    #[error("The upstream did not respond in time: {0}")]
+    UpstreamTimeout(String),
+
+    #[error("Request body is too large: {0}")]
+    BodyTooLarge(String),
+
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+
     #[error(
         "[BUG] This should never happen. Please report it at https://github.com/AssetsArt/nylon: {0}"
     )]
@@ -46,10 +57,26 @@ impl NylonError {
     pub fn http_status(&self) -> u16 {
         match self {
             NylonError::HttpException(status, _, _) => *status,
+            NylonError::UpstreamConnectError(_) => 502,
+            NylonError::UpstreamTimeout(_) => 504,
+            NylonError::BodyTooLarge(_) => 413,
+            NylonError::RateLimited(_) => 429,
+            NylonError::RouteNotFound(_) => 404,
             _ => 500,
         }
     }
 
+    /// Whether sending the same request again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NylonError::UpstreamConnectError(_)
+            | NylonError::UpstreamTimeout(_)
+            | NylonError::RateLimited(_) => true,
+            NylonError::HttpException(status, _, _) => matches!(status, 429 | 502 | 503 | 504),
+            _ => false,
+        }
+    }
+
     pub fn error_code(&self) -> String {
         match self {
             NylonError::HttpException(_, error, _) => error.to_string(),
@@ -63,6 +90,10 @@ impl NylonError {
             NylonError::AcmeJWSError(_) => "ACME_JWS_ERROR".to_string(),
             NylonError::AcmeClientError(_) => "ACME_CLIENT_ERROR".to_string(),
             NylonError::InternalServerError(_) => "INTERNAL_SERVER_ERROR".to_string(),
+            NylonError::UpstreamConnectError(_) => "UPSTREAM_CONNECT_ERROR".to_string(),
+            NylonError::UpstreamTimeout(_) => "UPSTREAM_TIMEOUT".to_string(),
+            NylonError::BodyTooLarge(_) => "BODY_TOO_LARGE".to_string(),
+            NylonError::RateLimited(_) => "RATE_LIMITED".to_string(),
             NylonError::ShouldNeverHappen(_) => "SHOULD_NEVER_HAPPEN".to_string(),
         }
     }
@@ -80,18 +111,67 @@ impl NylonError {
             NylonError::AcmeJWSError(message) => message.to_string(),
             NylonError::AcmeClientError(message) => message.to_string(),
             NylonError::InternalServerError(message) => message.to_string(),
+            NylonError::UpstreamConnectError(message) => message.to_string(),
+            NylonError::UpstreamTimeout(message) => message.to_string(),
+            NylonError::BodyTooLarge(message) => message.to_string(),
+            NylonError::RateLimited(message) => message.to_string(),
             NylonError::ShouldNeverHappen(message) => format!(
                 "[BUG] This should never happen. Please report it at https://github.com/AssetsArt/nylon: {}",
                 message
             ),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_and_code() {
+        let cases = [
+            (
+                NylonError::HttpException(404, "NOT_FOUND", "missing"),
+                404,
+                "NOT_FOUND",
+            ),
+            (
+                NylonError::UpstreamConnectError("refused".into()),
+                502,
+                "UPSTREAM_CONNECT_ERROR",
+            ),
+            (
+                NylonError::UpstreamTimeout("slow".into()),
+                504,
+                "UPSTREAM_TIMEOUT",
+            ),
+            (
+                NylonError::BodyTooLarge("big".into()),
+                413,
+                "BODY_TOO_LARGE",
+            ),
+            (NylonError::RateLimited("busy".into()), 429, "RATE_LIMITED"),
+            (
+                NylonError::RouteNotFound("none".into()),
+                404,
+                "ROUTE_NOT_FOUND",
+            ),
+            (NylonError::ConfigError("bad".into()), 500, "CONFIG_ERROR"),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(error.http_status(), status, "{}", error);
+            assert_eq!(error.error_code(), code, "{}", error);
+        }
+    }
 
-    pub fn exception_json(&self) -> Value {
-        json!({
-            "status": self.http_status(),
-            "error": self.error_code(),
-            "message": self.message(),
-        })
+    #[test]
+    fn test_is_retryable() {
+        assert!(NylonError::UpstreamConnectError("refused".into()).is_retryable());
+        assert!(NylonError::UpstreamTimeout("slow".into()).is_retryable());
+        assert!(NylonError::RateLimited("busy".into()).is_retryable());
+        assert!(NylonError::HttpException(503, "UNAVAILABLE", "down").is_retryable());
+        assert!(!NylonError::HttpException(500, "ERROR", "oops").is_retryable());
+        assert!(!NylonError::BodyTooLarge("big".into()).is_retryable());
+        assert!(!NylonError::ConfigError("bad".into()).is_retryable());
     }
 }
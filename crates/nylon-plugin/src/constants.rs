@@ -6,6 +6,7 @@ pub mod methods {
     pub const NEXT: u32 = 1;
     pub const END: u32 = 2;
     pub const GET_PAYLOAD: u32 = 3;
+    pub const NEGOTIATE_ABI: u32 = 4;
 
     // Response methods
     pub const SET_RESPONSE_HEADER: u32 = 100;
@@ -35,18 +36,38 @@ pub mod methods {
     pub const READ_REQUEST_METHOD: u32 = 209;
     pub const READ_REQUEST_BYTES: u32 = 210;
     pub const READ_REQUEST_TIMESTAMP: u32 = 211;
+    pub const READ_ROUTE_INFO: u32 = 212;
+    pub const READ_REQUEST_BODY_STREAM: u32 = 213;
+    pub const READ_REQUEST_BODY_CHUNK: u32 = 214;
+    pub const SET_REQUEST_BODY_CHUNK: u32 = 215;
+    pub const SET_REQUEST_PATH: u32 = 216;
+    pub const SET_REQUEST_METHOD: u32 = 217;
+    pub const SET_REQUEST_QUERY: u32 = 218;
+    pub const SET_REQUEST_HEADER: u32 = 219;
+    pub const SET_UPSTREAM: u32 = 220;
+    pub const HTTP_FETCH: u32 = 221;
+    pub const SUBSCRIBE_UPSTREAM_PHASES: u32 = 222;
+    pub const READ_UPSTREAM_CONNECTION: u32 = 223;
 
     // WebSocket methods (Plugin -> Rust)
     pub const WEBSOCKET_UPGRADE: u32 = 300;
     pub const WEBSOCKET_SEND_TEXT: u32 = 301;
     pub const WEBSOCKET_SEND_BINARY: u32 = 302;
     pub const WEBSOCKET_CLOSE: u32 = 303;
+    pub const WEBSOCKET_SELECT_PROTOCOL: u32 = 304;
+    pub const WEBSOCKET_SET_CONNECTION_METADATA: u32 = 305;
+    pub const WEBSOCKET_READ_CONNECTION_METADATA: u32 = 306;
 
     // WebSocket room methods (Plugin -> Rust)
     pub const WEBSOCKET_JOIN_ROOM: u32 = 310;
     pub const WEBSOCKET_LEAVE_ROOM: u32 = 311;
     pub const WEBSOCKET_BROADCAST_ROOM_TEXT: u32 = 312;
     pub const WEBSOCKET_BROADCAST_ROOM_BINARY: u32 = 313;
+    pub const WEBSOCKET_ROOM_SIZE: u32 = 314;
+    pub const WEBSOCKET_ROOM_MEMBERS: u32 = 315;
+    pub const WEBSOCKET_WATCH_PRESENCE: u32 = 316;
+    pub const WEBSOCKET_BROADCAST_ALL_TEXT: u32 = 317;
+    pub const WEBSOCKET_BROADCAST_ALL_BINARY: u32 = 318;
 
     // WebSocket events (Rust -> Plugin)
     pub const WEBSOCKET_ON_OPEN: u32 = 350;
@@ -54,6 +75,21 @@ pub mod methods {
     pub const WEBSOCKET_ON_MESSAGE_BINARY: u32 = 352;
     pub const WEBSOCKET_ON_CLOSE: u32 = 353;
     pub const WEBSOCKET_ON_ERROR: u32 = 354;
+    pub const WEBSOCKET_ON_ROOM_JOIN: u32 = 355;
+    pub const WEBSOCKET_ON_ROOM_LEAVE: u32 = 356;
+
+    // Metric methods
+    pub const METRIC_INCR: u32 = 400;
+    pub const METRIC_OBSERVE: u32 = 401;
+}
+
+// Plugin ABI versions
+pub mod abi {
+    /// Raw byte payloads, e.g. the status as 2 big-endian bytes
+    pub const V1: u16 = 1;
+    /// FlatBuffers tables from `proto/plugin.fbs` for structured payloads
+    pub const V2: u16 = 2;
+    pub const LATEST: u16 = V2;
 }
 
 // FFI symbol names
@@ -64,10 +100,29 @@ pub mod ffi_symbols {
     pub const EVENT_STREAM: &str = "event_stream";
     pub const CLOSE_SESSION: &str = "close_session_stream";
     pub const SHUTDOWN: &str = "shutdown";
+
+    /// Every symbol a shared library plugin must export
+    pub const REQUIRED: &[&str] = &[
+        INITIALIZE,
+        PLUGIN_FREE,
+        REGISTER_SESSION,
+        EVENT_STREAM,
+        CLOSE_SESSION,
+        SHUTDOWN,
+    ];
 }
 
 // Builtin plugin names
 pub mod builtin_plugins {
     pub const REQUEST_HEADER_MODIFIER: &str = "RequestHeaderModifier";
     pub const RESPONSE_HEADER_MODIFIER: &str = "ResponseHeaderModifier";
+    pub const QUERY_TOKEN_AUTH: &str = "QueryTokenAuth";
+    pub const REPLAY_PROTECTION: &str = "ReplayProtection";
+    pub const RESPONSE_WATERMARK: &str = "ResponseWatermark";
+    pub const LUA: &str = "Lua";
+    pub const BODY_TRANSFORM: &str = "BodyTransform";
+    pub const WAF: &str = "Waf";
+    pub const BOT_GUARD: &str = "BotGuard";
+    pub const REQUEST_DECOMPRESSION: &str = "RequestDecompression";
+    pub const OIDC: &str = "Oidc";
 }
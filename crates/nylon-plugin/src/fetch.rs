@@ -0,0 +1,177 @@
+//! Outbound HTTP requests made on behalf of plugins
+//!
+//! Requests go through a shared pingora connector, so connections to the
+//! same peer are pooled across plugin sessions.
+
+use base64::Engine;
+use bytes::Bytes;
+use nylon_error::NylonError;
+use once_cell::sync::Lazy;
+use pingora::{connectors::http::Connector, http::RequestHeader, prelude::HttpPeer};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+
+/// Largest response body handed back to a plugin
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// Longest a sub-request may take, whatever the plugin asks for
+const MAX_TIMEOUT_MS: u64 = 60_000;
+
+static CONNECTOR: Lazy<Connector> = Lazy::new(|| Connector::new(None));
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    5_000
+}
+
+/// Sub-request sent by a plugin as JSON
+#[derive(Debug, Deserialize)]
+pub struct FetchRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Absolute `http://` or `https://` URL
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Base64 encoded body
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// Reply to a plugin; `error` is set instead of a status when the request failed
+#[derive(Debug, Default, Serialize)]
+pub struct FetchResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    /// Base64 encoded body
+    pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FetchResponse {
+    pub fn error(message: String) -> Self {
+        Self {
+            error: Some(message),
+            ..Default::default()
+        }
+    }
+}
+
+/// Perform a sub-request, giving up after its timeout (at most a minute)
+pub async fn fetch(req: FetchRequest) -> Result<FetchResponse, NylonError> {
+    let timeout = Duration::from_millis(req.timeout_ms.clamp(1, MAX_TIMEOUT_MS));
+    tokio::time::timeout(timeout, send(req, timeout))
+        .await
+        .map_err(|_| NylonError::UpstreamTimeout("fetch timed out".to_string()))?
+}
+
+async fn send(req: FetchRequest, timeout: Duration) -> Result<FetchResponse, NylonError> {
+    let uri = req
+        .url
+        .parse::<http::Uri>()
+        .map_err(|e| NylonError::RuntimeError(format!("Invalid fetch url {}: {}", req.url, e)))?;
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => {
+            return Err(NylonError::RuntimeError(format!(
+                "Fetch url must be http or https: {}",
+                req.url
+            )));
+        }
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| NylonError::RuntimeError(format!("Fetch url has no host: {}", req.url)))?;
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let addr = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| NylonError::RuntimeError(format!("Unable to resolve {}", host)))?;
+    let mut peer = HttpPeer::new(
+        addr,
+        tls,
+        if tls { host.to_string() } else { String::new() },
+    );
+    peer.options.connection_timeout = Some(timeout);
+    peer.options.read_timeout = Some(timeout);
+
+    let body = match &req.body {
+        Some(body) => base64::engine::general_purpose::STANDARD
+            .decode(body)
+            .map_err(|e| NylonError::RuntimeError(format!("Invalid fetch body: {}", e)))?,
+        None => vec![],
+    };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut header = RequestHeader::build(req.method.as_str(), path.as_bytes(), None)
+        .map_err(|e| NylonError::RuntimeError(format!("Invalid fetch request: {}", e)))?;
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or(host);
+    let insert = |header: &mut RequestHeader, name: String, value: String| {
+        header
+            .insert_header(name, value)
+            .map_err(|e| NylonError::RuntimeError(format!("Invalid fetch header: {}", e)))
+    };
+    insert(&mut header, "host".to_string(), authority.to_string())?;
+    insert(
+        &mut header,
+        "content-length".to_string(),
+        body.len().to_string(),
+    )?;
+    for (name, value) in req.headers {
+        insert(&mut header, name.to_ascii_lowercase(), value)?;
+    }
+
+    let (mut session, _) = CONNECTOR
+        .get_http_session(&peer)
+        .await
+        .map_err(|e| NylonError::UpstreamConnectError(format!("{}: {}", host, e)))?;
+    let io_error =
+        |e: Box<pingora::Error>| NylonError::RuntimeError(format!("fetch failed: {}", e));
+    session
+        .write_request_header(Box::new(header))
+        .await
+        .map_err(io_error)?;
+    if !body.is_empty() {
+        session
+            .write_request_body(Bytes::from(body), true)
+            .await
+            .map_err(io_error)?;
+    }
+    session.finish_request_body().await.map_err(io_error)?;
+    session.read_response_header().await.map_err(io_error)?;
+
+    let mut res = FetchResponse::default();
+    if let Some(header) = session.response_header() {
+        res.status = header.status.as_u16();
+        for (name, value) in header.headers.iter() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            res.headers
+                .entry(name.as_str().to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(&value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_response_body().await.map_err(io_error)? {
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(NylonError::RuntimeError(
+                "fetch response body too large".to_string(),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    res.body = base64::engine::general_purpose::STANDARD.encode(body);
+
+    CONNECTOR.release_http_session(session, &peer, None).await;
+    Ok(res)
+}
@@ -0,0 +1,246 @@
+//! gRPC plugin transport
+//!
+//! The plugin runs as its own gRPC server (`proto/nylon/plugin/v1/plugin.proto`),
+//! so it can be written in any language. Every session is one bidirectional
+//! `Session` stream carrying the same methods and payloads as shared library
+//! plugins.
+//!
+//! Events to the server wait in a queue of `event_queue_capacity`. A session
+//! whose server is unreachable, gone, or not keeping up is ended, so the
+//! request is handled by the plugin's `on_failure`.
+
+use dashmap::DashMap;
+use http::uri::PathAndQuery;
+use nylon_error::NylonError;
+use nylon_types::plugins::{FfiBuffer, PluginBackend, PluginEventCallback, PluginItem};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OnceCell, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    Code, Request, Status,
+    client::Grpc,
+    transport::{Channel, Endpoint},
+};
+use tonic_prost::ProstCodec;
+use tracing::{debug, error};
+
+const INITIALIZE_PATH: &str = "/nylon.plugin.v1.Plugin/Initialize";
+const SESSION_PATH: &str = "/nylon.plugin.v1.Plugin/Session";
+
+/// How long connecting to the plugin server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct InitializeRequest {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    config: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct InitializeResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PluginEvent {
+    #[prost(uint32, tag = "1")]
+    session_id: u32,
+    #[prost(uint32, tag = "2")]
+    phase: u32,
+    #[prost(uint32, tag = "3")]
+    method: u32,
+    #[prost(bytes = "vec", tag = "4")]
+    data: Vec<u8>,
+    #[prost(string, tag = "5")]
+    entry: String,
+}
+
+#[derive(Debug)]
+struct Inner {
+    name: String,
+    endpoint: Endpoint,
+    config: String,
+    /// Connected (and initialized) on the first session
+    channel: OnceCell<Channel>,
+    /// Outbound half of every open session
+    sessions: DashMap<u32, mpsc::Sender<PluginEvent>>,
+    /// Events a session may have waiting for the server
+    queue_capacity: usize,
+}
+
+/// A plugin served by an external gRPC server
+#[derive(Debug)]
+pub struct GrpcPlugin {
+    inner: Arc<Inner>,
+}
+
+impl GrpcPlugin {
+    /// `plugin.file` is the server address, e.g. `http://127.0.0.1:50051`
+    pub fn new(plugin: &PluginItem) -> Result<Self, NylonError> {
+        let endpoint = Endpoint::from_shared(plugin.file.clone())
+            .map_err(|e| NylonError::ConfigError(format!("gRPC plugin {}: {}", plugin.name, e)))?
+            .connect_timeout(CONNECT_TIMEOUT);
+        let config = match &plugin.config {
+            Some(config) => serde_json::to_string(config).unwrap_or_default(),
+            None => "".to_string(),
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                name: plugin.name.clone(),
+                endpoint,
+                config,
+                channel: OnceCell::new(),
+                sessions: DashMap::new(),
+                queue_capacity: plugin.event_queue_capacity(),
+            }),
+        })
+    }
+}
+
+impl Inner {
+    async fn channel(&self) -> Result<Channel, Status> {
+        self.channel
+            .get_or_try_init(|| async {
+                let channel = self
+                    .endpoint
+                    .connect()
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                self.initialize(channel.clone()).await?;
+                Ok(channel)
+            })
+            .await
+            .cloned()
+    }
+
+    async fn initialize(&self, channel: Channel) -> Result<(), Status> {
+        let mut grpc = Grpc::new(channel);
+        grpc.ready()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let request = InitializeRequest {
+            name: self.name.clone(),
+            config: self.config.clone(),
+        };
+        let result = grpc
+            .unary(
+                Request::new(request),
+                PathAndQuery::from_static(INITIALIZE_PATH),
+                ProstCodec::<InitializeRequest, InitializeResponse>::default(),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(status) if status.code() == Code::Unimplemented => Ok(()),
+            Err(status) => Err(status),
+        }
+    }
+
+    async fn run_session(
+        &self,
+        session_id: u32,
+        outbound: mpsc::Receiver<PluginEvent>,
+        callback: PluginEventCallback,
+    ) -> Result<(), Status> {
+        let mut grpc = Grpc::new(self.channel().await?);
+        grpc.ready()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let response = grpc
+            .streaming(
+                Request::new(ReceiverStream::new(outbound)),
+                PathAndQuery::from_static(SESSION_PATH),
+                ProstCodec::<PluginEvent, PluginEvent>::default(),
+            )
+            .await?;
+
+        let mut inbound = response.into_inner();
+        while let Some(event) = inbound.message().await? {
+            let buffer = FfiBuffer {
+                sid: session_id,
+                phase: event.phase as u8,
+                method: event.method,
+                ptr: event.data.as_ptr(),
+                len: event.data.len() as u64,
+            };
+            callback(&buffer);
+        }
+        Ok(())
+    }
+}
+
+impl PluginBackend for GrpcPlugin {
+    fn register_session(
+        &self,
+        session_id: u32,
+        entry: &str,
+        callback: PluginEventCallback,
+    ) -> bool {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            error!("gRPC plugin {} used outside of a runtime", self.inner.name);
+            return false;
+        };
+        let (tx, rx) = mpsc::channel(self.inner.queue_capacity);
+        let open = PluginEvent {
+            session_id,
+            entry: entry.to_string(),
+            ..Default::default()
+        };
+        if tx.try_send(open).is_err() {
+            return false;
+        }
+        self.inner.sessions.insert(session_id, tx);
+
+        let inner = self.inner.clone();
+        runtime.spawn(async move {
+            if let Err(e) = inner.run_session(session_id, rx, callback).await {
+                error!("gRPC plugin {} session {}: {}", inner.name, session_id, e);
+            }
+            debug!("gRPC plugin {} session {} ended", inner.name, session_id);
+            inner.sessions.remove(&session_id);
+            crate::stream::abort_session(session_id);
+        });
+        true
+    }
+
+    fn event_stream(&self, buffer: &FfiBuffer) {
+        let data = if buffer.ptr.is_null() {
+            vec![]
+        } else {
+            unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len as usize) }.to_vec()
+        };
+        let Some(session) = self.inner.sessions.get(&buffer.sid).map(|s| s.clone()) else {
+            debug!(
+                "gRPC plugin {}: no open session {}",
+                self.inner.name, buffer.sid
+            );
+            crate::stream::abort_session(buffer.sid);
+            return;
+        };
+        let sent = session.try_send(PluginEvent {
+            session_id: buffer.sid,
+            phase: buffer.phase as u32,
+            method: buffer.method,
+            data,
+            entry: String::new(),
+        });
+        if let Err(e) = sent {
+            error!(
+                "gRPC plugin {} session {}: {}",
+                self.inner.name, buffer.sid, e
+            );
+            self.inner.sessions.remove(&buffer.sid);
+            crate::stream::abort_session(buffer.sid);
+        }
+    }
+
+    /// Ending the outbound stream tells the server the session is over
+    fn close_session(&self, session_id: u32) {
+        self.inner.sessions.remove(&session_id);
+    }
+
+    fn shutdown(&self) {
+        self.inner.sessions.clear();
+    }
+}
@@ -0,0 +1,525 @@
+//! JavaScript plugin runtime
+//!
+//! Runs plugin scripts inside QuickJS. The prelude in `js/prelude.js` speaks
+//! the same session/method protocol as shared library plugins and gives the
+//! script `nylon.plugin(entry, handlers)`. TypeScript has to be compiled to
+//! JavaScript first.
+//!
+//! Each script runs on a thread of its own that takes calls from a bounded
+//! queue, so a slow script never holds up the proxy's workers.
+//!
+//! Host functions on the `nylon` global:
+//! - `__emit(sid, phase, method, data)` - send an event back; `data` is a string or `Uint8Array`
+//! - `__log(message)`
+//! - `encode(string)` / `decode(bytes)` - UTF-8 conversion
+//! - `base64Encode(string | bytes)` / `base64Decode(string)`
+
+use base64::Engine;
+use nylon_error::NylonError;
+use nylon_types::plugins::{FfiBuffer, PluginBackend, PluginEventCallback, PluginItem};
+use rquickjs::{
+    CatchResultExt, Context, Ctx, FromJs, Function, Object, Runtime, TypedArray, Value,
+    function::Opt,
+};
+use std::{
+    sync::mpsc::{Receiver, SyncSender, TrySendError, channel, sync_channel},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+const PRELUDE: &str = include_str!("js/prelude.js");
+
+/// Longest a single call into the script, including its promise jobs, may run
+const CALL_LIMIT: Duration = Duration::from_secs(1);
+
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Calls waiting for a script before new ones are turned away
+const CALL_QUEUE_CAPACITY: usize = 1024;
+
+/// Work handed to a script's thread
+enum Call {
+    Register {
+        sid: u32,
+        entry: String,
+        callback: PluginEventCallback,
+    },
+    Event {
+        sid: u32,
+        phase: u8,
+        method: u32,
+        data: Vec<u8>,
+    },
+    Close(u32),
+    Shutdown,
+}
+
+/// The QuickJS runtime of a script, owned by the script's thread
+struct JsInstance {
+    name: String,
+    runtime: Runtime,
+    context: Context,
+    callback: Arc<Mutex<Option<PluginEventCallback>>>,
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+/// A plugin script running in QuickJS
+///
+/// One runtime per plugin, so calls are queued for its thread.
+pub struct JsPlugin {
+    name: String,
+    calls: SyncSender<Call>,
+}
+
+impl std::fmt::Debug for JsPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JsPlugin")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+fn load_error(plugin: &PluginItem, e: impl std::fmt::Display) -> NylonError {
+    NylonError::ConfigError(format!("JS plugin {}: {}", plugin.name, e))
+}
+
+/// Bytes of a string or typed array passed from the script
+fn js_bytes<'js>(ctx: &Ctx<'js>, value: Value<'js>) -> rquickjs::Result<Vec<u8>> {
+    if let Some(s) = value.as_string() {
+        return Ok(s.to_string()?.into_bytes());
+    }
+    if value.is_undefined() || value.is_null() {
+        return Ok(vec![]);
+    }
+    let array = TypedArray::<u8>::from_js(ctx, value)?;
+    Ok(array.as_bytes().unwrap_or_default().to_vec())
+}
+
+fn install_host<'js>(
+    ctx: &Ctx<'js>,
+    callback: Arc<Mutex<Option<PluginEventCallback>>>,
+) -> rquickjs::Result<()> {
+    let nylon = Object::new(ctx.clone())?;
+    nylon.set(
+        "__emit",
+        Function::new(
+            ctx.clone(),
+            move |ctx: Ctx<'js>, sid: u32, phase: u8, method: u32, data: Opt<Value<'js>>| {
+                let data = match data.0 {
+                    Some(data) => js_bytes(&ctx, data)?,
+                    None => vec![],
+                };
+                if let Some(callback) = callback.lock().ok().and_then(|c| *c) {
+                    let buffer = FfiBuffer {
+                        sid,
+                        phase,
+                        method,
+                        ptr: data.as_ptr(),
+                        len: data.len() as u64,
+                    };
+                    callback(&buffer);
+                }
+                Ok::<_, rquickjs::Error>(())
+            },
+        )?,
+    )?;
+    nylon.set(
+        "__log",
+        Function::new(ctx.clone(), |message: String| {
+            info!(target: "nylon::js", "{}", message);
+        })?,
+    )?;
+    nylon.set(
+        "encode",
+        Function::new(ctx.clone(), |ctx: Ctx<'js>, s: String| {
+            TypedArray::<u8>::new(ctx, s.into_bytes())
+        })?,
+    )?;
+    nylon.set(
+        "decode",
+        Function::new(ctx.clone(), |ctx: Ctx<'js>, data: Value<'js>| {
+            js_bytes(&ctx, data).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        })?,
+    )?;
+    nylon.set(
+        "base64Encode",
+        Function::new(ctx.clone(), |ctx: Ctx<'js>, data: Value<'js>| {
+            js_bytes(&ctx, data)
+                .map(|bytes| base64::engine::general_purpose::STANDARD.encode(bytes))
+        })?,
+    )?;
+    nylon.set(
+        "base64Decode",
+        Function::new(ctx.clone(), |ctx: Ctx<'js>, data: String| {
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .unwrap_or_default();
+            TypedArray::<u8>::new(ctx, bytes)
+        })?,
+    )?;
+    ctx.globals().set("nylon", nylon)
+}
+
+impl JsInstance {
+    /// Evaluate the prelude and `source`, then initialize the script
+    fn new(name: String, source: String, config: String) -> Result<Self, String> {
+        let runtime = Runtime::new().map_err(|e| e.to_string())?;
+        runtime.set_memory_limit(MEMORY_LIMIT);
+        let deadline = Arc::new(Mutex::new(None::<Instant>));
+        let interrupt = deadline.clone();
+        runtime.set_interrupt_handler(Some(Box::new(move || {
+            interrupt
+                .lock()
+                .ok()
+                .and_then(|d| *d)
+                .is_some_and(|d| Instant::now() > d)
+        })));
+        let context = Context::full(&runtime).map_err(|e| e.to_string())?;
+        let callback = Arc::new(Mutex::new(None));
+
+        context.with(|ctx| {
+            install_host(&ctx, callback.clone())
+                .and_then(|_| ctx.eval::<(), _>(PRELUDE))
+                .and_then(|_| ctx.eval::<(), _>(source))
+                .catch(&ctx)
+                .map_err(|e| e.to_string())
+        })?;
+
+        let js = Self {
+            name,
+            runtime,
+            context,
+            callback,
+            deadline,
+        };
+        js.call(|ctx| {
+            let initialize: Function = ctx.globals().get("initialize")?;
+            initialize.call::<_, ()>((config,))
+        })
+        .ok_or("initialize failed")?;
+        Ok(js)
+    }
+
+    /// Run `f` in the script context, then drain the promise jobs it queued
+    fn call<R>(&self, f: impl for<'js> FnOnce(Ctx<'js>) -> rquickjs::Result<R>) -> Option<R> {
+        if let Ok(mut deadline) = self.deadline.lock() {
+            *deadline = Some(Instant::now() + CALL_LIMIT);
+        }
+        let result = self
+            .context
+            .with(|ctx| f(ctx.clone()).catch(&ctx).map_err(|e| e.to_string()));
+        let mut job_error = None;
+        while self.runtime.is_job_pending() {
+            if let Err(e) = self.runtime.execute_pending_job() {
+                job_error = Some(e.to_string());
+                break;
+            }
+        }
+        if let Ok(mut deadline) = self.deadline.lock() {
+            *deadline = None;
+        }
+        match (result, job_error) {
+            (Ok(result), None) => Some(result),
+            (Err(e), _) | (_, Some(e)) => {
+                error!("JS plugin {} failed: {}", self.name, e);
+                None
+            }
+        }
+    }
+}
+
+/// Thread of a script: run its calls one at a time
+fn run(js: JsInstance, calls: Receiver<Call>) {
+    while let Ok(call) = calls.recv() {
+        match call {
+            Call::Register {
+                sid,
+                entry,
+                callback,
+            } => {
+                if let Ok(mut current) = js.callback.lock() {
+                    *current = Some(callback);
+                }
+                let registered = js.call(|ctx| {
+                    let register: Function = ctx.globals().get("register_session_stream")?;
+                    register.call::<_, bool>((sid, entry.as_str()))
+                });
+                if registered != Some(true) {
+                    crate::stream::abort_session(sid);
+                }
+            }
+            Call::Event {
+                sid,
+                phase,
+                method,
+                data,
+            } => {
+                let delivered = js.call(|ctx| {
+                    let event_stream: Function = ctx.globals().get("event_stream")?;
+                    let data = TypedArray::<u8>::new(ctx.clone(), data)?;
+                    event_stream.call::<_, ()>((sid, phase, method, data))
+                });
+                // A failed call never answers; end the session instead of hanging it
+                if delivered.is_none() {
+                    crate::stream::abort_session(sid);
+                }
+            }
+            Call::Close(sid) => {
+                js.call(|ctx| {
+                    let close: Function = ctx.globals().get("close_session_stream")?;
+                    close.call::<_, ()>((sid,))
+                });
+            }
+            Call::Shutdown => {
+                js.call(|ctx| {
+                    let shutdown: Function = ctx.globals().get("shutdown")?;
+                    shutdown.call::<_, ()>(())
+                });
+                break;
+            }
+        }
+    }
+}
+
+impl JsPlugin {
+    /// Start the script of `plugin` on its thread and wait for it to initialize
+    pub fn load(plugin: &PluginItem) -> Result<Self, NylonError> {
+        let source = std::fs::read_to_string(&plugin.file).map_err(|e| load_error(plugin, e))?;
+        let config = match &plugin.config {
+            Some(config) => serde_json::to_string(config).unwrap_or_default(),
+            None => "".to_string(),
+        };
+
+        // QuickJS values never leave the thread that created them
+        let (calls, queue) = sync_channel(CALL_QUEUE_CAPACITY);
+        let (ready, started) = channel();
+        let name = plugin.name.clone();
+        std::thread::Builder::new()
+            .name(format!("js-{}", plugin.name))
+            .spawn(move || match JsInstance::new(name, source, config) {
+                Ok(js) => {
+                    let _ = ready.send(Ok(()));
+                    run(js, queue);
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                }
+            })
+            .map_err(|e| load_error(plugin, e))?;
+        started
+            .recv()
+            .map_err(|e| load_error(plugin, e))?
+            .map_err(|e| load_error(plugin, e))?;
+        Ok(Self {
+            name: plugin.name.clone(),
+            calls,
+        })
+    }
+
+    fn send(&self, call: Call) -> bool {
+        match self.calls.try_send(call) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                error!(
+                    "JS plugin {} has {} calls waiting, turning one away",
+                    self.name, CALL_QUEUE_CAPACITY
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("JS plugin {} is no longer running", self.name);
+                false
+            }
+        }
+    }
+}
+
+impl PluginBackend for JsPlugin {
+    /// Registration finishes on the script's thread; an unknown entry ends
+    /// the session there
+    fn register_session(
+        &self,
+        session_id: u32,
+        entry: &str,
+        callback: PluginEventCallback,
+    ) -> bool {
+        self.send(Call::Register {
+            sid: session_id,
+            entry: entry.to_string(),
+            callback,
+        })
+    }
+
+    fn event_stream(&self, buffer: &FfiBuffer) {
+        let data = if buffer.ptr.is_null() {
+            vec![]
+        } else {
+            unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len as usize) }.to_vec()
+        };
+        let queued = self.send(Call::Event {
+            sid: buffer.sid,
+            phase: buffer.phase,
+            method: buffer.method,
+            data,
+        });
+        if !queued {
+            crate::stream::abort_session(buffer.sid);
+        }
+    }
+
+    fn close_session(&self, session_id: u32) {
+        self.send(Call::Close(session_id));
+    }
+
+    fn shutdown(&self) {
+        self.send(Call::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::methods;
+    use nylon_sdk::fbs::plugin_generated::nylon_plugin::{
+        HeaderKeyValue, HeaderKeyValueArgs, Metric, NylonHttpHeaders, NylonHttpHeadersArgs,
+    };
+
+    const SCRIPT: &str = r#"
+nylon.plugin("echo", {
+  requestFilter: async (ctx) => {
+    const headers = await ctx.request.headers();
+    ctx.request.setHeader("x-echo", headers["x-in"]);
+    ctx.metrics.observe("latency", 1.5);
+  },
+});
+"#;
+
+    /// Events the scripts sent back, by session
+    static EVENTS: Mutex<Vec<(u32, u32, Vec<u8>)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(buffer: *const FfiBuffer) {
+        let buffer = unsafe { &*buffer };
+        let data = unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len as usize) };
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((buffer.sid, buffer.method, data.to_vec()));
+    }
+
+    fn wait_for(sid: u32, method: u32) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let found = EVENTS
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(s, m, _)| *s == sid && *m == method)
+                .map(|(_, _, data)| data.clone());
+            if let Some(data) = found {
+                return data;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("no event {} for session {}", method, sid);
+    }
+
+    fn load(name: &str, script: &str) -> JsPlugin {
+        let file =
+            std::env::temp_dir().join(format!("nylon-js-{}-{}.js", name, std::process::id()));
+        std::fs::write(&file, script).unwrap();
+        let plugin: PluginItem = serde_json::from_value(serde_json::json!({
+            "name": name,
+            "file": file.to_string_lossy(),
+            "type": "js",
+        }))
+        .unwrap();
+        let js = JsPlugin::load(&plugin).unwrap();
+        let _ = std::fs::remove_file(&file);
+        js
+    }
+
+    fn send(js: &JsPlugin, sid: u32, phase: u8, method: u32, data: &[u8]) {
+        js.event_stream(&FfiBuffer {
+            sid,
+            phase,
+            method,
+            ptr: data.as_ptr(),
+            len: data.len() as u64,
+        });
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut fbs = flatbuffers::FlatBufferBuilder::new();
+        let items: Vec<_> = pairs
+            .iter()
+            .map(|(key, value)| {
+                let key = fbs.create_string(key);
+                let value = fbs.create_string(value);
+                HeaderKeyValue::create(
+                    &mut fbs,
+                    &HeaderKeyValueArgs {
+                        key: Some(key),
+                        value: Some(value),
+                    },
+                )
+            })
+            .collect();
+        let items = fbs.create_vector(&items);
+        let headers = NylonHttpHeaders::create(
+            &mut fbs,
+            &NylonHttpHeadersArgs {
+                headers: Some(items),
+            },
+        );
+        fbs.finish(headers, None);
+        fbs.finished_data().to_vec()
+    }
+
+    #[test]
+    fn test_flatbuffers_round_trip() {
+        let js = load("echo", SCRIPT);
+        let sid = 4_084_001;
+        assert!(js.register_session(sid, "echo", record));
+        send(&js, sid, 1, 0, &[]);
+
+        wait_for(sid, methods::READ_REQUEST_HEADERS);
+        let data = headers(&[("content-type", "text/plain"), ("X-In", "héllo wörld")]);
+        send(&js, sid, 0, methods::READ_REQUEST_HEADERS, &data);
+
+        let data = wait_for(sid, methods::SET_REQUEST_HEADER);
+        let header = flatbuffers::root::<HeaderKeyValue>(&data).unwrap();
+        assert_eq!(header.key(), "x-echo");
+        assert_eq!(header.value(), "héllo wörld");
+
+        let data = wait_for(sid, methods::METRIC_OBSERVE);
+        let metric = flatbuffers::root::<Metric>(&data).unwrap();
+        assert_eq!(metric.name(), "latency");
+        assert_eq!(metric.value(), 1.5);
+
+        wait_for(sid, methods::NEXT);
+        js.shutdown();
+    }
+
+    #[test]
+    fn test_unknown_entry() {
+        let js = load("unknown", SCRIPT);
+        assert!(js.register_session(4_084_002, "missing", record));
+        js.close_session(4_084_002);
+        js.shutdown();
+    }
+
+    #[test]
+    fn test_load_error() {
+        let file = std::env::temp_dir().join(format!("nylon-js-bad-{}.js", std::process::id()));
+        std::fs::write(&file, "this is not javascript(").unwrap();
+        let plugin: PluginItem = serde_json::from_value(serde_json::json!({
+            "name": "bad",
+            "file": file.to_string_lossy(),
+            "type": "js",
+        }))
+        .unwrap();
+        assert!(JsPlugin::load(&plugin).is_err());
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,306 @@
+// Nylon JavaScript plugin prelude
+//
+// Speaks the session/method protocol through the host's `nylon.__emit` and
+// gives plugin scripts `nylon.plugin(entry, handlers)`. Method ids must match
+// crates/nylon-plugin/src/constants.rs.
+(function () {
+  "use strict";
+
+  const M = {
+    NEXT: 1,
+    END: 2,
+    GET_PAYLOAD: 3,
+    SET_RESPONSE_HEADER: 100,
+    REMOVE_RESPONSE_HEADER: 101,
+    SET_RESPONSE_STATUS: 102,
+    SET_RESPONSE_FULL_BODY: 103,
+    READ_RESPONSE_FULL_BODY: 107,
+    READ_RESPONSE_STATUS: 108,
+    READ_RESPONSE_HEADERS: 110,
+    READ_REQUEST_FULL_BODY: 200,
+    READ_REQUEST_HEADERS: 202,
+    READ_REQUEST_URL: 203,
+    READ_REQUEST_PATH: 204,
+    READ_REQUEST_QUERY: 205,
+    READ_REQUEST_PARAMS: 206,
+    READ_REQUEST_HOST: 207,
+    READ_REQUEST_CLIENT_IP: 208,
+    READ_REQUEST_METHOD: 209,
+    READ_ROUTE_INFO: 212,
+    SET_REQUEST_PATH: 216,
+    SET_REQUEST_METHOD: 217,
+    SET_REQUEST_QUERY: 218,
+    SET_REQUEST_HEADER: 219,
+    SET_UPSTREAM: 220,
+    HTTP_FETCH: 221,
+    SUBSCRIBE_UPSTREAM_PHASES: 222,
+    READ_UPSTREAM_CONNECTION: 223,
+    METRIC_INCR: 400,
+    METRIC_OBSERVE: 401,
+  };
+
+  // Index is the phase id sent by the host
+  const PHASES = [
+    null,
+    "requestFilter",
+    "responseFilter",
+    "responseBodyFilter",
+    "logging",
+    "requestBodyFilter",
+    "upstreamRequestFilter",
+    "connectedToUpstream",
+  ];
+
+  const host = globalThis.nylon;
+  const entries = {};
+  const sessions = {};
+  let onInitialize = null;
+  let onShutdown = null;
+
+  globalThis.console = {
+    log: (...args) => host.__log(args.join(" ")),
+    error: (...args) => host.__log(args.join(" ")),
+  };
+
+  const text = (bytes) => host.decode(bytes);
+  const json = (bytes) => (bytes && bytes.length ? JSON.parse(text(bytes)) : null);
+
+  // HeaderKeyValue flatbuffer: root offset, vtable, table, then both strings
+  function headerKeyValue(key, value) {
+    const k = host.encode(String(key));
+    const v = host.encode(String(value));
+    const pad = (n) => (n + 3) & ~3;
+    const keyAt = 24;
+    const valueAt = keyAt + pad(4 + k.length + 1);
+    const buf = new Uint8Array(valueAt + pad(4 + v.length + 1));
+    const view = new DataView(buf.buffer);
+    view.setUint32(0, 12, true);
+    view.setUint16(4, 8, true);
+    view.setUint16(6, 12, true);
+    view.setUint16(8, 4, true);
+    view.setUint16(10, 8, true);
+    view.setInt32(12, 8, true);
+    view.setUint32(16, keyAt - 16, true);
+    view.setUint32(20, valueAt - 20, true);
+    view.setUint32(keyAt, k.length, true);
+    buf.set(k, keyAt + 4);
+    view.setUint32(valueAt, v.length, true);
+    buf.set(v, valueAt + 4);
+    return buf;
+  }
+
+  // Metric flatbuffer: root offset, vtable, table with the 8-aligned value, then the name
+  function metric(name, value) {
+    const n = host.encode(String(name));
+    const nameAt = 28;
+    const buf = new Uint8Array(nameAt + ((4 + n.length + 1 + 3) & ~3));
+    const view = new DataView(buf.buffer);
+    view.setUint32(0, 12, true);
+    view.setUint16(4, 8, true);
+    view.setUint16(6, 16, true);
+    view.setUint16(8, 12, true);
+    view.setUint16(10, 4, true);
+    view.setInt32(12, 8, true);
+    view.setFloat64(16, Number(value), true);
+    view.setUint32(24, nameAt - 24, true);
+    view.setUint32(nameAt, n.length, true);
+    buf.set(n, nameAt + 4);
+    return buf;
+  }
+
+  // NylonHttpHeaders flatbuffer to an object keyed by lowercase name
+  function readHeaders(bytes) {
+    const headers = {};
+    if (!bytes || bytes.length < 4) {
+      return headers;
+    }
+    const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+    const u32 = (at) => view.getUint32(at, true);
+    const field = (table, index) => {
+      const vtable = table - view.getInt32(table, true);
+      const slot = 4 + index * 2;
+      const offset = slot < view.getUint16(vtable, true) ? view.getUint16(vtable + slot, true) : 0;
+      return offset ? table + offset : 0;
+    };
+    const str = (at) => {
+      const start = at + u32(at);
+      return text(bytes.subarray(start + 4, start + 4 + u32(start)));
+    };
+    const list = field(u32(0), 0);
+    if (!list) {
+      return headers;
+    }
+    const vec = list + u32(list);
+    for (let i = 0; i < u32(vec); i++) {
+      const item = vec + 4 + i * 4;
+      const table = item + u32(item);
+      headers[str(field(table, 0)).toLowerCase()] = str(field(table, 1));
+    }
+    return headers;
+  }
+
+  class Session {
+    constructor(sid, handlers) {
+      this.sid = sid;
+      this.handlers = handlers;
+      this.pending = {};
+    }
+
+    emit(method, data) {
+      host.__emit(this.sid, 0, method, data === undefined ? "" : data);
+    }
+
+    // Send a read method; the host answers with the same method id
+    call(method, data) {
+      return new Promise((resolve) => {
+        (this.pending[method] = this.pending[method] || []).push(resolve);
+        this.emit(method, data);
+      });
+    }
+
+    resolve(method, data) {
+      const queue = this.pending[method];
+      if (queue && queue.length) {
+        queue.shift()(data);
+      }
+    }
+  }
+
+  function context(session) {
+    let finished = false;
+    const finish = (method) => {
+      if (!finished) {
+        finished = true;
+        session.emit(method);
+      }
+    };
+    const read = (method) => () => session.call(method).then(text);
+
+    const request = {
+      method: read(M.READ_REQUEST_METHOD),
+      path: read(M.READ_REQUEST_PATH),
+      query: read(M.READ_REQUEST_QUERY),
+      url: read(M.READ_REQUEST_URL),
+      host: read(M.READ_REQUEST_HOST),
+      clientIp: read(M.READ_REQUEST_CLIENT_IP),
+      params: () => session.call(M.READ_REQUEST_PARAMS).then((b) => json(b) || {}),
+      headers: () => session.call(M.READ_REQUEST_HEADERS).then(readHeaders),
+      header: (name) => request.headers().then((h) => h[String(name).toLowerCase()]),
+      body: () => session.call(M.READ_REQUEST_FULL_BODY),
+      routeInfo: () => session.call(M.READ_ROUTE_INFO).then(json),
+      setHeader: (name, value) => session.emit(M.SET_REQUEST_HEADER, headerKeyValue(name, value)),
+      setPath: (path) => session.emit(M.SET_REQUEST_PATH, path),
+      setMethod: (method) => session.emit(M.SET_REQUEST_METHOD, method),
+      setQuery: (query) => session.emit(M.SET_REQUEST_QUERY, query),
+      setUpstream: (target) => session.emit(M.SET_UPSTREAM, target),
+    };
+
+    const response = {
+      status: () => session.call(M.READ_RESPONSE_STATUS).then((b) => parseInt(text(b), 10) || 0),
+      headers: () => session.call(M.READ_RESPONSE_HEADERS).then(readHeaders),
+      readBody: () => session.call(M.READ_RESPONSE_FULL_BODY),
+      setStatus: (code) =>
+        session.emit(M.SET_RESPONSE_STATUS, new Uint8Array([(code >> 8) & 0xff, code & 0xff])),
+      setHeader: (name, value) => session.emit(M.SET_RESPONSE_HEADER, headerKeyValue(name, value)),
+      removeHeader: (name) => session.emit(M.REMOVE_RESPONSE_HEADER, name),
+      body: (data) =>
+        session.emit(
+          M.SET_RESPONSE_FULL_BODY,
+          typeof data === "string" || data instanceof Uint8Array ? data : JSON.stringify(data),
+        ),
+    };
+
+    return {
+      request,
+      response,
+      payload: () => session.call(M.GET_PAYLOAD).then(json),
+      connection: () => session.call(M.READ_UPSTREAM_CONNECTION).then(json),
+      metrics: {
+        incr: (name, value = 1) => session.emit(M.METRIC_INCR, metric(name, value)),
+        observe: (name, value) => session.emit(M.METRIC_OBSERVE, metric(name, value)),
+      },
+      fetch: (req) => {
+        const body = req.body === undefined ? undefined : host.base64Encode(req.body);
+        return session
+          .call(M.HTTP_FETCH, JSON.stringify({ ...req, body }))
+          .then(json)
+          .then((res) => {
+            if (res.error) {
+              throw new Error(res.error);
+            }
+            return { ...res, body: host.base64Decode(res.body) };
+          });
+      },
+      next: () => finish(M.NEXT),
+      end: () => finish(M.END),
+    };
+  }
+
+  globalThis.register_session_stream = function (sid, entry) {
+    const handlers = entries[entry];
+    if (!handlers) {
+      console.error("[nylon] no handler registered for entry " + entry);
+      return false;
+    }
+    sessions[sid] = new Session(sid, handlers);
+    return true;
+  };
+
+  globalThis.event_stream = function (sid, phase, method, data) {
+    const session = sessions[sid];
+    if (!session) {
+      return;
+    }
+    if (phase === 0) {
+      session.resolve(method, data);
+      return;
+    }
+    const handlers = session.handlers;
+    if (phase === 1 && (handlers.upstreamRequestFilter || handlers.connectedToUpstream)) {
+      session.emit(M.SUBSCRIBE_UPSTREAM_PHASES);
+    }
+    const ctx = context(session);
+    const handler = handlers[PHASES[phase]];
+    if (!handler) {
+      ctx.next();
+      return;
+    }
+    // Handlers may be async; one that returns without ending the request continues it
+    Promise.resolve()
+      .then(() => handler(ctx))
+      .then(
+        () => ctx.next(),
+        (err) => {
+          console.error("[nylon] " + PHASES[phase] + " failed: " + err);
+          ctx.response.setStatus(500);
+          ctx.end();
+        },
+      );
+  };
+
+  globalThis.close_session_stream = function (sid) {
+    delete sessions[sid];
+  };
+
+  globalThis.initialize = function (config) {
+    if (onInitialize) {
+      onInitialize(config ? JSON.parse(config) : {});
+    }
+  };
+
+  globalThis.shutdown = function () {
+    if (onShutdown) {
+      onShutdown();
+    }
+  };
+
+  host.plugin = (entry, handlers) => {
+    entries[entry] = handlers;
+  };
+  host.onInitialize = (fn) => {
+    onInitialize = fn;
+  };
+  host.onShutdown = (fn) => {
+    onShutdown = fn;
+  };
+})();
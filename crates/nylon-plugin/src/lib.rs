@@ -2,28 +2,92 @@
 #![allow(clippy::too_many_arguments)]
 
 pub mod constants;
+mod fetch;
+pub mod grpc;
+pub mod js;
 pub mod loaders;
+pub mod metrics;
 mod native;
+mod payloads;
 pub mod plugin_manager;
 pub mod session_handler;
 pub mod stream;
 pub mod types;
+pub mod wasm;
 
 use crate::constants::methods;
 use crate::{
     plugin_manager::PluginManager,
     session_handler::SessionHandler,
-    stream::{PluginSessionStream, get_rx},
+    stream::PluginSessionStream,
     types::{BuiltinPlugin, MiddlewareContext, PluginResult},
 };
 use bytes::Bytes;
 use nylon_error::NylonError;
-use nylon_types::plugins::PluginPhase;
-use nylon_types::{context::NylonContext, plugins::SessionStream, template::Expr};
+use nylon_types::plugins::{PluginErrorAction, PluginPhase};
+use nylon_types::{
+    buffer_pool::PooledBuffer, context::NylonContext, plugins::SessionStream, template::Expr,
+};
 use pingora::proxy::{ProxyHttp, Session};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 use tokio::time::{self, Duration};
 
+/// Give up on a plugin for this request
+///
+/// The session is closed so a late answer cannot leak into a later phase.
+async fn abandon_plugin(
+    ctx: &mut NylonContext,
+    session_stream: Option<&SessionStream>,
+    key: &str,
+    action: PluginErrorAction,
+    (status, error, message): (u16, &str, String),
+) -> Result<PluginResult, NylonError> {
+    if let Some(session_stream) = session_stream {
+        let _ = session_stream.close().await;
+    }
+    ctx.session_ids.remove(key);
+    ctx.session_stream.remove(key);
+    if action == PluginErrorAction::Continue {
+        return Ok(PluginResult::default());
+    }
+
+    ctx.set_response_status.store(status, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": error,
+        "message": message,
+    })
+    .to_string()
+    .into_bytes();
+    Ok(PluginResult::new(true, false))
+}
+
+fn plugin_failure(plugin_name: &str) -> (u16, &'static str, String) {
+    (
+        502,
+        "BAD_GATEWAY",
+        format!("plugin {} is unavailable", plugin_name),
+    )
+}
+
+/// Whether the plugin session is in `sessions`, the subscribers of an opt-in phase
+fn is_subscribed(
+    ctx: &NylonContext,
+    plugin_name: &str,
+    entry: &Option<String>,
+    sessions: &HashSet<u32>,
+) -> bool {
+    let Some(entry) = entry else {
+        return false;
+    };
+    let key = format!("{}-{}", plugin_name, entry);
+    ctx.session_ids
+        .get(&key)
+        .is_some_and(|session_id| sessions.contains(session_id))
+}
+
 /// Execute a session stream for a plugin
 pub async fn session_stream<T>(
     proxy: &T,
@@ -42,53 +106,66 @@ where
 {
     let plugin = PluginManager::get_plugin(plugin_name)?;
     let key = format!("{}-{}", plugin_name, entry);
-    let mut session_id = {
-        let map = ctx
-            .session_ids
-            .read()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-        *map.get(&key).unwrap_or(&0)
-    };
-    let session_stream;
-    {
-        let map = ctx
-            .session_stream
-            .read()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-        if let Some(ss) = map.get(&key) {
-            session_stream = ss.clone();
-        } else {
-            drop(map);
-            let ss = SessionStream::new(plugin, session_id);
-            let mut w = ctx
-                .session_stream
-                .write()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-            w.insert(key.clone(), ss.clone());
-            session_stream = ss;
-        }
+    let item = loaders::plugin_item(plugin_name);
+    let deadline = item.as_ref().and_then(|item| {
+        item.timeout(&phase)
+            .map(|timeout| (time::Instant::now() + timeout, item.on_timeout))
+    });
+    let on_failure = item
+        .as_ref()
+        .map(|item| item.on_failure)
+        .unwrap_or_default();
+    let permissions = item.as_ref().and_then(|item| item.permissions.clone());
+    if !loaders::is_healthy(plugin_name) {
+        return abandon_plugin(ctx, None, &key, on_failure, plugin_failure(plugin_name)).await;
     }
+    let mut session_id = ctx.session_ids.get(&key).copied().unwrap_or(0);
+    let session_stream = ctx
+        .session_stream
+        .entry(key.clone())
+        .or_insert_with(|| SessionStream::new(plugin, session_id))
+        .clone();
     if session_id == 0 {
+        let permit = match &item {
+            Some(item) => match loaders::acquire_session(item).await {
+                Some(permit) => Some(permit),
+                None => {
+                    let busy = (
+                        503,
+                        "SERVICE_UNAVAILABLE",
+                        format!("plugin {} is busy", plugin_name),
+                    );
+                    return abandon_plugin(ctx, None, &key, on_failure, busy).await;
+                }
+            },
+            None => None,
+        };
         // open session
-        let new_session_id = session_stream.open(entry).await?;
+        let new_session_id = match session_stream.open(entry, plugin_name, item.as_ref()).await {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                loaders::record_failure(plugin_name, &e.to_string());
+                return abandon_plugin(ctx, None, &key, on_failure, plugin_failure(plugin_name))
+                    .await;
+            }
+        };
         session_id = new_session_id;
-        ctx.session_ids
-            .write()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-            .insert(key.clone(), new_session_id);
-    }
-    let rx_arc = match get_rx(session_id) {
-        Ok(rx) => rx,
-        Err(_) => {
-            let session_stream_clone = session_stream.clone();
-            tokio::spawn(async move {
-                let _ = session_stream_clone.event_stream(phase, 0, b"").await;
-            });
-            return Ok(PluginResult {
-                http_end: false,
-                stream_end: false,
-            });
+        if let Some(permit) = permit {
+            crate::stream::hold_permit(session_id, permit);
         }
+        ctx.session_ids.insert(key.clone(), new_session_id);
+    }
+    let Some(rx_arc) = crate::stream::session_rx(session_id).await else {
+        // The session was closed or aborted before this phase, e.g. its plugin went away
+        loaders::record_failure(plugin_name, "session is no longer open");
+        return abandon_plugin(
+            ctx,
+            Some(&session_stream),
+            &key,
+            on_failure,
+            plugin_failure(plugin_name),
+        )
+        .await;
     };
     let mut rx = match rx_arc.try_lock() {
         Ok(rx) => rx,
@@ -110,7 +187,9 @@ where
     });
     // WebSocket read/relay state
     let mut ws_active = false;
-    let mut read_buf: Vec<u8> = Vec::with_capacity(4096);
+    let mut read_buf = PooledBuffer::new();
+    // Opcode and data of a fragmented message received so far
+    let mut fragment: Option<(u8, Vec<u8>)> = None;
 
     fn build_ws_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
         let mut frame = Vec::with_capacity(2 + payload.len() + 8);
@@ -129,15 +208,34 @@ where
         frame
     }
 
-    // try to get WS rx to forward cluster messages when ws_active
-    let ws_rx_arc = crate::stream::get_ws_rx(session_stream.session_id).ok();
+    // WS send queue to forward cluster messages, set by the upgrade
+    let mut ws_queue = None;
 
-    // server keepalive ping every 20s once ws is active
-    let mut ping_interval = time::interval(Duration::from_secs(20));
+    // server keepalive ping once ws is active; clients that stay silent past the idle timeout are closed
+    let ws_settings = nylon_store::websockets::connection_settings();
+    let mut ping_interval = time::interval(ws_settings.ping_interval);
+    let mut last_activity = time::Instant::now();
+    // Start of the current one-second window and messages received in it
+    let mut rate_window = (time::Instant::now(), 0u32);
 
     loop {
         if !ws_active {
-            if let Some((method, data)) = rx.recv().await {
+            let received = match deadline {
+                Some((deadline, _)) => time::timeout_at(deadline, rx.recv()).await.ok(),
+                None => Some(rx.recv().await),
+            };
+            let Some(received) = received else {
+                tracing::warn!("Plugin {} timed out", plugin_name);
+                let action = deadline.map(|(_, action)| action).unwrap_or_default();
+                drop(rx);
+                let timeout = (
+                    504,
+                    "GATEWAY_TIMEOUT",
+                    format!("plugin {} timed out", plugin_name),
+                );
+                return abandon_plugin(ctx, Some(&session_stream), &key, action, timeout).await;
+            };
+            if let Some((method, data)) = received {
                 if method == methods::WEBSOCKET_UPGRADE {
                     ws_active = true;
                 }
@@ -148,6 +246,8 @@ where
                     ctx,
                     session,
                     &session_stream,
+                    plugin_name,
+                    permissions.as_deref(),
                     payload,
                     payload_ast,
                     response_body,
@@ -155,11 +255,25 @@ where
                 .await?
                 {
                     // println!("result: {:?}", result);
+                    loaders::record_success(plugin_name);
                     return Ok(result);
                 }
+                if ws_active && ws_queue.is_none() {
+                    ws_queue = crate::stream::get_ws_rx(session_stream.session_id).ok();
+                    last_activity = time::Instant::now();
+                }
             } else {
-                // channel closed
-                return Ok(PluginResult::default());
+                // The plugin dropped the session without answering
+                loaders::record_failure(plugin_name, "session closed unexpectedly");
+                drop(rx);
+                return abandon_plugin(
+                    ctx,
+                    Some(&session_stream),
+                    &key,
+                    on_failure,
+                    plugin_failure(plugin_name),
+                )
+                .await;
             }
             continue;
         }
@@ -174,6 +288,8 @@ where
                     ctx,
                     session,
                     &session_stream,
+                    plugin_name,
+                    permissions.as_deref(),
                     payload,
                     payload_ast,
                     response_body
@@ -184,23 +300,49 @@ where
             }
             // Cluster/local adapter -> client frames
             Some(msg) = async {
-                match &ws_rx_arc {
-                    Some(arc) => {
-                        let mut rx = arc.lock().await;
-                        rx.recv().await
-                    }
+                match &ws_queue {
+                    Some(queue) => queue.recv().await,
                     None => None
                 }
             } => {
                 let frame = match msg {
                     nylon_types::websocket::WebSocketMessage::Text(s) => build_ws_frame(0x1, s.as_bytes()),
                     nylon_types::websocket::WebSocketMessage::Binary(b) => build_ws_frame(0x2, &b),
-                    nylon_types::websocket::WebSocketMessage::Close { code:_, reason:_ } => build_ws_frame(0x8, &[]),
+                    nylon_types::websocket::WebSocketMessage::Close { code, reason } => {
+                        // Close the connection, e.g. when its send queue overflowed
+                        let payload = close_payload(code, &reason);
+                        let _ = session.response_duplex_vec(vec![
+                            pingora::protocols::http::HttpTask::Body(Some(Bytes::from(build_ws_frame(0x8, &payload))), false),
+                            pingora::protocols::http::HttpTask::Done
+                        ]).await;
+                        websocket_closed(&session_stream, code, &reason).await?;
+                        return Ok(PluginResult::new(false, true));
+                    }
                     nylon_types::websocket::WebSocketMessage::Ping(p) => build_ws_frame(0x9, &p),
                     nylon_types::websocket::WebSocketMessage::Pong(p) => build_ws_frame(0xA, &p),
+                    nylon_types::websocket::WebSocketMessage::Presence(presence) => {
+                        let method = if presence.joined { methods::WEBSOCKET_ON_ROOM_JOIN } else { methods::WEBSOCKET_ON_ROOM_LEAVE };
+                        let event = serde_json::json!({
+                            "room": presence.room,
+                            "connection_id": presence.connection_id,
+                            "metadata": presence.metadata,
+                        });
+                        session_stream.event_stream(PluginPhase::Zero, method, event.to_string().as_bytes()).await?;
+                        continue;
+                    }
                 };
                 let _ = session.response_duplex_vec(vec![pingora::protocols::http::HttpTask::Body(Some(Bytes::from(frame)), false)]).await;
             }
+            // No frame (not even a pong) within the idle timeout
+            _ = time::sleep_until(last_activity + ws_settings.idle_timeout), if !ws_settings.idle_timeout.is_zero() => {
+                let payload = close_payload(1001, "idle timeout");
+                let _ = session.response_duplex_vec(vec![
+                    pingora::protocols::http::HttpTask::Body(Some(Bytes::from(build_ws_frame(0x8, &payload))), false),
+                    pingora::protocols::http::HttpTask::Done
+                ]).await;
+                websocket_closed(&session_stream, 1001, "idle timeout").await?;
+                return Ok(PluginResult::new(false, true));
+            }
             // Server keepalive ping
             _ = ping_interval.tick() => {
                 let frame = build_ws_frame(0x9, &[]);
@@ -210,43 +352,53 @@ where
             result = session.read_request_body() => {
                 match result {
                     Ok(Some(chunk)) => {
+                        last_activity = time::Instant::now();
                         read_buf.extend_from_slice(&chunk);
                         // parse frames in read_buf
                         loop {
-                            if read_buf.len() < 2 { break; }
-                            let b0 = read_buf[0];
-                            let b1 = read_buf[1];
-                            let fin = (b0 & 0x80) != 0;
-                            let opcode = b0 & 0x0F;
-                            let masked = (b1 & 0x80) != 0;
-                            let mut idx = 2usize;
-                            let mut payload_len: usize = (b1 & 0x7F) as usize;
-                            if payload_len == 126 {
-                                if read_buf.len() < idx + 2 { break; }
-                                payload_len = u16::from_be_bytes([read_buf[idx], read_buf[idx+1]]) as usize;
-                                idx += 2;
-                            } else if payload_len == 127 {
-                                if read_buf.len() < idx + 8 { break; }
-                                payload_len = u64::from_be_bytes([
-                                    read_buf[idx],read_buf[idx+1],read_buf[idx+2],read_buf[idx+3],
-                                    read_buf[idx+4],read_buf[idx+5],read_buf[idx+6],read_buf[idx+7]
-                                ]) as usize;
-                                idx += 8;
-                            }
-                            let mut mask_key = [0u8;4];
-                            if masked {
-                                if read_buf.len() < idx + 4 { break; }
-                                mask_key.copy_from_slice(&read_buf[idx..idx+4]);
-                                idx += 4;
-                            }
-                            if read_buf.len() < idx + payload_len { break; }
-                            let mut payload = read_buf[idx..idx+payload_len].to_vec();
-                            if masked {
-                                for i in 0..payload_len { payload[i] ^= mask_key[i % 4]; }
-                            }
-                            // remove frame from buffer
-                            let remove_len = idx + payload_len;
-                            read_buf.drain(0..remove_len);
+                            let buffered = fragment.as_ref().map_or(0, |(_, data)| data.len());
+                            let (fin, opcode, payload) = match parse_frame(&read_buf, buffered, ws_settings.max_frame_bytes, ws_settings.max_message_bytes) {
+                                Frame::Incomplete => break,
+                                Frame::Complete { fin, opcode, payload, len } => {
+                                    // remove frame from buffer
+                                    read_buf.drain(..len);
+                                    (fin, opcode, payload)
+                                }
+                                Frame::Fail(code, reason) => {
+                                    // Close instead of buffering the rest
+                                    read_buf.clear();
+                                    (true, 0x8, close_payload(code, reason))
+                                }
+                            };
+
+                            // Reassemble fragmented messages; control frames may arrive between fragments
+                            let (opcode, payload) = match reassemble(&mut fragment, fin, opcode, payload) {
+                                Assembled::Partial => continue,
+                                Assembled::Message(opcode, payload) => (opcode, payload),
+                                Assembled::ProtocolError => {
+                                    // Close with 1002 (protocol error)
+                                    read_buf.clear();
+                                    (0x8, close_payload(1002, "protocol error"))
+                                }
+                            };
+
+                            // Close with 1008 (policy violation) when the client sends too fast
+                            let (opcode, payload) = match ws_settings.max_messages_per_sec {
+                                Some(limit) if matches!(opcode, 0x1 | 0x2) => {
+                                    let now = time::Instant::now();
+                                    if now.duration_since(rate_window.0) >= Duration::from_secs(1) {
+                                        rate_window = (now, 0);
+                                    }
+                                    rate_window.1 += 1;
+                                    if rate_window.1 > limit {
+                                        read_buf.clear();
+                                        (0x8, close_payload(1008, "rate limit exceeded"))
+                                    } else {
+                                        (opcode, payload)
+                                    }
+                                }
+                                _ => (opcode, payload),
+                            };
 
                             // handle opcodes
                             match opcode {
@@ -257,6 +409,8 @@ where
                                     session_stream.event_stream(PluginPhase::Zero, methods::WEBSOCKET_ON_MESSAGE_BINARY, &payload).await?;
                                 }
                                 0x8 => { // close
+                                    // A close body with a reserved code is a protocol error (1002)
+                                    let payload = if valid_close_payload(&payload) { payload } else { close_payload(1002, "protocol error") };
                                     // Send close frame response to client
                                     let frame = build_ws_frame(0x8, &payload);
                                     let _ = session.response_duplex_vec(vec![
@@ -265,13 +419,8 @@ where
                                     ]).await;
 
                                     // Notify plugin that connection is closing (await to ensure delivery)
-                                    session_stream.event_stream(PluginPhase::Zero, methods::WEBSOCKET_ON_CLOSE, &[]).await?;
-                                    // unregister and remove from adapter
-                                    let conn_id = format!("{}:{}", nylon_store::websockets::get_node_id().await.unwrap_or_default(), session_stream.session_id);
-                                    nylon_store::websockets::unregister_local_sender(&conn_id);
-                                    tokio::spawn(async move {
-                                        let _ = nylon_store::websockets::remove_connection(&conn_id).await;
-                                    });
+                                    let (code, reason) = parse_close_payload(&payload);
+                                    websocket_closed(&session_stream, code, &reason).await?;
 
                                     return Ok(PluginResult::new(false, true));
                                 }
@@ -282,17 +431,12 @@ where
                                 0xA => { /* pong: ignore */ }
                                 _ => { /* ignore */ }
                             }
-                            if !fin { /* fragmentation not supported in stub */ }
                         }
                     }
                     Ok(None) | Err(_) => {
                         // client closed or error
-                        session_stream.event_stream(PluginPhase::Zero, methods::WEBSOCKET_ON_CLOSE, &[]).await?;
-                        let conn_id = format!("{}:{}", nylon_store::websockets::get_node_id().await.unwrap_or_default(), session_stream.session_id);
-                        nylon_store::websockets::unregister_local_sender(&conn_id);
-                        tokio::spawn(async move {
-                            let _ = nylon_store::websockets::remove_connection(&conn_id).await;
-                        });
+                        // 1006: gone without a close frame
+                        websocket_closed(&session_stream, 1006, "").await?;
                         return Ok(PluginResult::new(false, true));
                     }
                 }
@@ -301,6 +445,216 @@ where
     }
 }
 
+/// Longest payload of a control frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// A client frame at the front of the read buffer
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    /// The buffer does not hold the whole frame yet
+    Incomplete,
+    /// An unmasked frame and the bytes it took in the buffer
+    Complete {
+        fin: bool,
+        opcode: u8,
+        payload: Vec<u8>,
+        len: usize,
+    },
+    /// Close the connection with this status code and reason
+    Fail(u16, &'static str),
+}
+
+/// Parse the frame at the front of `buf`
+///
+/// `buffered` is the size of the fragments already received of the current
+/// message. A data frame over `max_frame` bytes, or one that makes the message
+/// bigger than `max_message`, fails with 1009 before its payload is buffered.
+/// A control frame over 125 bytes or split in fragments fails with 1002.
+pub(crate) fn parse_frame(
+    buf: &[u8],
+    buffered: usize,
+    max_frame: usize,
+    max_message: usize,
+) -> Frame {
+    let [b0, b1, ..] = *buf else {
+        return Frame::Incomplete;
+    };
+    let fin = (b0 & 0x80) != 0;
+    let opcode = b0 & 0x0F;
+    let masked = (b1 & 0x80) != 0;
+    let (payload_len, mut idx) = match b1 & 0x7F {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4usize),
+            None => return Frame::Incomplete,
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(len);
+                (u64::from_be_bytes(bytes), 10usize)
+            }
+            None => return Frame::Incomplete,
+        },
+        len => (len as u64, 2usize),
+    };
+
+    if opcode >= 0x8 {
+        if !fin || payload_len > MAX_CONTROL_PAYLOAD as u64 {
+            return Frame::Fail(1002, "protocol error");
+        }
+    } else {
+        let too_big = match usize::try_from(payload_len) {
+            Ok(len) => {
+                len > max_frame
+                    || buffered
+                        .checked_add(len)
+                        .is_none_or(|total| total > max_message)
+            }
+            Err(_) => true,
+        };
+        if too_big {
+            return Frame::Fail(1009, "message too big");
+        }
+    }
+    // Both limits keep the length within usize from here on
+    let payload_len = payload_len as usize;
+
+    let mut mask_key = [0u8; 4];
+    if masked {
+        let Some(key_end) = idx.checked_add(4) else {
+            return Frame::Fail(1002, "protocol error");
+        };
+        let Some(key) = buf.get(idx..key_end) else {
+            return Frame::Incomplete;
+        };
+        mask_key.copy_from_slice(key);
+        idx = key_end;
+    }
+    let Some(end) = idx.checked_add(payload_len) else {
+        return Frame::Fail(1009, "message too big");
+    };
+    let Some(payload) = buf.get(idx..end) else {
+        return Frame::Incomplete;
+    };
+    let mut payload = payload.to_vec();
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask_key[i % 4];
+        }
+    }
+    Frame::Complete {
+        fin,
+        opcode,
+        payload,
+        len: end,
+    }
+}
+
+/// A client frame joined with the fragments before it
+#[derive(Debug, PartialEq)]
+pub(crate) enum Assembled {
+    /// More fragments are needed
+    Partial,
+    /// A whole message or a control frame
+    Message(u8, Vec<u8>),
+    /// A continuation without a first fragment, or a new message before the last one ended
+    ProtocolError,
+}
+
+/// Add a frame to the fragmented message in `fragment`
+pub(crate) fn reassemble(
+    fragment: &mut Option<(u8, Vec<u8>)>,
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+) -> Assembled {
+    match opcode {
+        0x0 => {
+            let Some((first, mut data)) = fragment.take() else {
+                return Assembled::ProtocolError;
+            };
+            data.extend_from_slice(&payload);
+            if !fin {
+                *fragment = Some((first, data));
+                return Assembled::Partial;
+            }
+            Assembled::Message(first, data)
+        }
+        0x1 | 0x2 if fragment.is_some() => Assembled::ProtocolError,
+        0x1 | 0x2 if !fin => {
+            *fragment = Some((opcode, payload));
+            Assembled::Partial
+        }
+        _ => Assembled::Message(opcode, payload),
+    }
+}
+
+/// Longest close reason that fits a control frame next to the status code
+const MAX_CLOSE_REASON: usize = 123;
+
+/// Body of a close frame: the status code followed by the reason, cut to 123 bytes
+pub(crate) fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut end = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+/// Whether a close frame may carry `code`; 1005, 1006 and 1015 only report
+/// what happened and are never sent
+pub(crate) fn sendable_close_code(code: u16) -> bool {
+    matches!(code, 1000..=4999) && !matches!(code, 1004..=1006 | 1015)
+}
+
+/// Whether a close frame body is empty or starts with a code that may be sent
+pub(crate) fn valid_close_payload(payload: &[u8]) -> bool {
+    match payload {
+        [] => true,
+        [_] => false,
+        [high, low, ..] => sendable_close_code(u16::from_be_bytes([*high, *low])),
+    }
+}
+
+/// Status code and reason of a close frame body; 1005 when it carries no code
+pub(crate) fn parse_close_payload(payload: &[u8]) -> (u16, String) {
+    if payload.len() < 2 {
+        return (1005, String::new());
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    (code, String::from_utf8_lossy(&payload[2..]).to_string())
+}
+
+/// Tell the plugin a WebSocket closed and drop the connection from the adapter
+pub(crate) async fn websocket_closed(
+    session_stream: &SessionStream,
+    code: u16,
+    reason: &str,
+) -> Result<(), NylonError> {
+    let closed = serde_json::json!({ "code": code, "reason": reason });
+    session_stream
+        .event_stream(
+            PluginPhase::Zero,
+            methods::WEBSOCKET_ON_CLOSE,
+            closed.to_string().as_bytes(),
+        )
+        .await?;
+    let conn_id = format!(
+        "{}:{}",
+        nylon_store::websockets::get_node_id()
+            .await
+            .unwrap_or_default(),
+        session_stream.session_id
+    );
+    nylon_store::websockets::unregister_local_sender(&conn_id);
+    tokio::spawn(async move {
+        let _ = nylon_store::websockets::remove_connection(&conn_id).await;
+    });
+    Ok(())
+}
+
 pub async fn run_middleware<T>(
     proxy: &T,
     phase: &PluginPhase,
@@ -323,6 +677,18 @@ where
     let Some(plugin_name) = plugin_name_opt else {
         return Ok((false, false));
     };
+    let subscribers = match phase {
+        PluginPhase::RequestBodyFilter => Some(&ctx.request_body_streams),
+        PluginPhase::UpstreamRequestFilter | PluginPhase::ConnectedToUpstream => {
+            Some(&ctx.upstream_subscribers)
+        }
+        _ => None,
+    };
+    if let Some(subscribers) = subscribers
+        && !is_subscribed(ctx, plugin_name, entry_opt, subscribers)
+    {
+        return Ok((false, false));
+    }
     match PluginManager::try_builtin(plugin_name.as_str()) {
         Some(BuiltinPlugin::RequestHeaderModifier) => {
             native::header_modifier::request(ctx, session, payload, payload_ast)?;
@@ -332,6 +698,78 @@ where
             native::header_modifier::response(ctx, session, payload, payload_ast)?;
             Ok((false, false))
         }
+        Some(BuiltinPlugin::QueryTokenAuth) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let rejected = native::query_token::request(ctx, session, payload, payload_ast)?;
+            Ok((rejected, false))
+        }
+        Some(BuiltinPlugin::ReplayProtection) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let rejected =
+                native::replay_protection::request(ctx, session, payload, payload_ast).await?;
+            Ok((rejected, false))
+        }
+        Some(BuiltinPlugin::Waf) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let rejected = native::waf::request(ctx, session, middleware.waf.as_ref()).await?;
+            Ok((rejected, false))
+        }
+        Some(BuiltinPlugin::BotGuard) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let answered = native::bot_guard::request(ctx, session, payload, payload_ast).await?;
+            Ok((answered, false))
+        }
+        Some(BuiltinPlugin::RequestDecompression) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let rejected = native::request_decompression::request(ctx, session, payload).await?;
+            Ok((rejected, false))
+        }
+        Some(BuiltinPlugin::Oidc) => {
+            if !matches!(phase, PluginPhase::RequestFilter) {
+                return Ok((false, false));
+            }
+            let answered = native::oidc::request(ctx, session, payload, payload_ast).await?;
+            Ok((answered, false))
+        }
+        Some(BuiltinPlugin::ResponseWatermark) => {
+            if matches!(phase, PluginPhase::ResponseFilter) {
+                native::watermark::response(ctx, session, payload, payload_ast)?;
+            }
+            Ok((false, false))
+        }
+        Some(BuiltinPlugin::Lua) => match phase {
+            PluginPhase::RequestFilter => {
+                let responded = native::lua::request(ctx, session, payload)?;
+                Ok((responded, false))
+            }
+            PluginPhase::ResponseFilter => {
+                native::lua::response(ctx, session, payload)?;
+                Ok((false, false))
+            }
+            _ => Ok((false, false)),
+        },
+        Some(BuiltinPlugin::BodyTransform) => {
+            match phase {
+                PluginPhase::RequestFilter => {
+                    native::body_transform::request(ctx, session, payload, payload_ast)?
+                }
+                PluginPhase::ResponseFilter => {
+                    native::body_transform::response(ctx, session, payload, payload_ast)?
+                }
+                _ => {}
+            }
+            Ok((false, false))
+        }
         _ => {
             // For non-builtin plugins, require entry
             let Some(entry) = entry_opt else {
@@ -353,3 +791,249 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    #[derive(Debug)]
+    struct Backend;
+
+    impl nylon_types::plugins::PluginBackend for Backend {
+        fn register_session(
+            &self,
+            _: u32,
+            _: &str,
+            _: nylon_types::plugins::PluginEventCallback,
+        ) -> bool {
+            true
+        }
+        fn event_stream(&self, _: &nylon_types::plugins::FfiBuffer) {}
+        fn close_session(&self, _: u32) {}
+        fn shutdown(&self) {}
+    }
+
+    #[test]
+    fn test_closed_session_is_abandoned() {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let stream = SessionStream::new(std::sync::Arc::new(Backend), 0);
+                let session_id = stream.open("entry", "gone", None).await.unwrap();
+                assert!(crate::stream::session_rx(session_id).await.is_some());
+                crate::stream::abort_session(session_id);
+                assert!(crate::stream::session_rx(session_id).await.is_none());
+
+                let mut ctx = NylonContext::default();
+                ctx.session_ids.insert("gone-entry".to_string(), session_id);
+                ctx.session_stream
+                    .insert("gone-entry".to_string(), stream.clone());
+                let result = abandon_plugin(
+                    &mut ctx,
+                    Some(&stream),
+                    "gone-entry",
+                    PluginErrorAction::Error,
+                    plugin_failure("gone"),
+                )
+                .await
+                .unwrap();
+                assert!(result.http_end);
+                assert_eq!(ctx.set_response_status.load(Ordering::Relaxed), 502);
+                assert!(ctx.session_ids.is_empty());
+                assert!(ctx.session_stream.is_empty());
+            });
+    }
+
+    /// A masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![((fin as u8) << 7) | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let key = [1u8, 2, 3, 4];
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let buf = frame(true, 0x1, b"hello");
+        assert_eq!(
+            parse_frame(&buf, 0, MB, MB),
+            Frame::Complete {
+                fin: true,
+                opcode: 0x1,
+                payload: b"hello".to_vec(),
+                len: buf.len(),
+            }
+        );
+
+        let long = vec![7u8; 300];
+        let buf = frame(false, 0x2, &long);
+        let Frame::Complete { fin, payload, .. } = parse_frame(&buf, 0, MB, MB) else {
+            panic!("expected a frame");
+        };
+        assert!(!fin);
+        assert_eq!(payload, long);
+
+        // Every prefix of a frame waits for more bytes
+        for end in 0..buf.len() {
+            assert_eq!(parse_frame(&buf[..end], 0, MB, MB), Frame::Incomplete);
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_limits() {
+        let buf = frame(true, 0x2, &[0u8; 200]);
+        assert_eq!(
+            parse_frame(&buf, 0, 100, MB),
+            Frame::Fail(1009, "message too big")
+        );
+        assert_eq!(
+            parse_frame(&buf, 900, MB, 1000),
+            Frame::Fail(1009, "message too big")
+        );
+        // Decided from the header alone, before the payload arrives
+        assert_eq!(
+            parse_frame(&buf[..4], 0, 100, MB),
+            Frame::Fail(1009, "message too big")
+        );
+
+        // A 64-bit length that does not fit any buffer
+        let mut huge = vec![0x82, 0x80 | 127];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            parse_frame(&huge, 0, usize::MAX, usize::MAX),
+            Frame::Fail(1009, "message too big")
+        );
+        assert_eq!(
+            parse_frame(&buf, usize::MAX, usize::MAX, usize::MAX),
+            Frame::Fail(1009, "message too big")
+        );
+    }
+
+    #[test]
+    fn test_parse_control_frame() {
+        let buf = frame(true, 0x9, &[0u8; 125]);
+        assert!(matches!(
+            parse_frame(&buf, 0, MB, MB),
+            Frame::Complete { opcode: 0x9, .. }
+        ));
+        let buf = frame(true, 0x9, &[0u8; 126]);
+        assert_eq!(
+            parse_frame(&buf, 0, MB, MB),
+            Frame::Fail(1002, "protocol error")
+        );
+        let buf = frame(false, 0x8, &close_payload(1000, ""));
+        assert_eq!(
+            parse_frame(&buf, 0, MB, MB),
+            Frame::Fail(1002, "protocol error")
+        );
+    }
+
+    #[test]
+    fn test_close_payload() {
+        assert_eq!(close_payload(1000, ""), vec![0x03, 0xe8]);
+        assert_eq!(close_payload(1001, "bye"), b"\x03\xe9bye".to_vec());
+
+        let payload = close_payload(1000, &"a".repeat(200));
+        assert_eq!(payload.len(), 125);
+        // Never cut a character in half
+        let payload = close_payload(1000, &"é".repeat(100));
+        assert_eq!(payload.len(), 2 + 122);
+        assert!(std::str::from_utf8(&payload[2..]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_close_payload() {
+        assert_eq!(parse_close_payload(&[]), (1005, String::new()));
+        assert_eq!(parse_close_payload(&[0x03, 0xe8]), (1000, String::new()));
+        assert_eq!(
+            parse_close_payload(&close_payload(4000, "done")),
+            (4000, "done".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sendable_close_code() {
+        for code in [1000, 1001, 1003, 1007, 1011, 3000, 4999] {
+            assert!(sendable_close_code(code), "{}", code);
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 5000] {
+            assert!(!sendable_close_code(code), "{}", code);
+        }
+        assert!(valid_close_payload(&[]));
+        assert!(!valid_close_payload(&[0x03]));
+        assert!(!valid_close_payload(&1006u16.to_be_bytes()));
+        assert!(valid_close_payload(&close_payload(1000, "ok")));
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let mut fragment = None;
+        assert_eq!(
+            reassemble(&mut fragment, false, 0x1, b"hel".to_vec()),
+            Assembled::Partial
+        );
+        // Control frames may come between fragments
+        assert_eq!(
+            reassemble(&mut fragment, true, 0x9, b"ping".to_vec()),
+            Assembled::Message(0x9, b"ping".to_vec())
+        );
+        assert_eq!(
+            reassemble(&mut fragment, false, 0x0, b"l".to_vec()),
+            Assembled::Partial
+        );
+        assert_eq!(
+            reassemble(&mut fragment, true, 0x0, b"o".to_vec()),
+            Assembled::Message(0x1, b"hello".to_vec())
+        );
+        assert!(fragment.is_none());
+
+        assert_eq!(
+            reassemble(&mut fragment, true, 0x2, vec![1, 2]),
+            Assembled::Message(0x2, vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn test_reassemble_orphan_continuation() {
+        let mut fragment = None;
+        assert_eq!(
+            reassemble(&mut fragment, true, 0x0, b"late".to_vec()),
+            Assembled::ProtocolError
+        );
+        assert_eq!(
+            reassemble(&mut fragment, false, 0x0, b"late".to_vec()),
+            Assembled::ProtocolError
+        );
+    }
+
+    #[test]
+    fn test_reassemble_data_frame_inside_message() {
+        let mut fragment = None;
+        reassemble(&mut fragment, false, 0x1, b"part".to_vec());
+        assert_eq!(
+            reassemble(&mut fragment, true, 0x1, b"new".to_vec()),
+            Assembled::ProtocolError
+        );
+        let mut fragment = None;
+        reassemble(&mut fragment, false, 0x2, vec![1]);
+        assert_eq!(
+            reassemble(&mut fragment, false, 0x2, vec![2]),
+            Assembled::ProtocolError
+        );
+    }
+}
@@ -1,99 +1,346 @@
+//! Plugin loading
+//!
+//! Every load records the version (content hash) of the plugin file. Loading
+//! an unchanged plugin again is a no-op; a changed one is loaded next to the
+//! old version, which keeps serving its open sessions until they are gone and
+//! is then shut down and unloaded. A plugin removed from the config is retired
+//! the same way.
+
 use crate::constants::ffi_symbols;
 use dashmap::DashMap;
 use libloading::{Library, Symbol};
+use nylon_error::NylonError;
 use nylon_types::plugins::{
     FfiCloseSessionFn, FfiEventStreamFn, FfiInitializeFn, FfiPlugin, FfiPluginFreeFn,
-    FfiRegisterSessionFn, FfiShutdownFn, PluginItem,
+    FfiRegisterSessionFn, FfiShutdownFn, PluginBackend, PluginItem, PluginOverflow, PluginType,
 };
-use std::sync::Arc;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A loaded plugin version
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginVersion {
+    pub name: String,
+    pub file: String,
+    /// Content hash of the library or module (`-` for gRPC plugins)
+    pub version: String,
+    pub loaded_at: String,
+    pub health: Option<PluginHealth>,
+    #[serde(skip)]
+    config: String,
+    /// Plugin settings, refreshed on every load
+    #[serde(skip)]
+    pub item: PluginItem,
+}
+
+/// Current version of every plugin
+static VERSIONS: Lazy<DashMap<String, PluginVersion>> = Lazy::new(DashMap::new);
+
+/// Replaced versions waiting for their sessions to finish
+static RETIRED: Lazy<Mutex<Vec<(PluginVersion, Arc<dyn PluginBackend>)>>> =
+    Lazy::new(|| Mutex::new(vec![]));
+
+/// Consecutive failures after which a plugin is marked unhealthy
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Failure bookkeeping of a plugin
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PluginHealth {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+static HEALTH: Lazy<DashMap<String, PluginHealth>> = Lazy::new(DashMap::new);
+
+/// Session slots of a plugin: its `max_concurrent_sessions` and the semaphore
+/// handing them out
+struct SessionLimit {
+    max: Option<usize>,
+    semaphore: Arc<Semaphore>,
+    rejected: u64,
+}
 
+static LIMITS: Lazy<DashMap<String, SessionLimit>> = Lazy::new(DashMap::new);
+
+/// Library copies opened so far, numbering the next one
+static LIBRARY_COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// Load a plugin unless the same version is already loaded
 pub fn load(plugin: &PluginItem) {
-    let file = plugin.file.clone();
-    let lib_store =
-        match nylon_store::get::<DashMap<String, Arc<Library>>>(nylon_store::KEY_LIBRARY_FILE) {
-            Some(lib) => lib,
-            None => {
-                let new_lib = DashMap::new();
-                nylon_store::insert(nylon_store::KEY_LIBRARY_FILE, new_lib.clone());
-                new_lib
-            }
-        };
+    if let Err(e) = try_load(plugin) {
+        eprintln!("Failed to load plugin {}: {}", plugin.name, e);
+    }
+}
 
-    let lib = match lib_store.get(&file) {
-        Some(lib) => lib,
-        None => {
-            let lib = unsafe {
-                match Library::new(&file) {
-                    Ok(lib) => lib,
-                    Err(e) => {
-                        eprintln!("Failed to load shared library: {}", e);
-                        return;
-                    }
-                }
-            };
-            lib_store.insert(file.clone(), Arc::new(lib));
-            match lib_store.get(&file) {
-                Some(lib) => lib,
-                None => {
-                    eprintln!("Failed to get loaded library");
-                    return;
-                }
-            }
+/// Load the plugin file unless it is already loaded, reporting failures
+pub fn try_load(plugin: &PluginItem) -> Result<PluginVersion, NylonError> {
+    load_version(plugin, false)
+}
+
+/// Load the plugin file again, even if it did not change
+pub fn reload(plugin: &PluginItem) -> Result<PluginVersion, NylonError> {
+    load_version(plugin, true)
+}
+
+/// Versions currently serving new sessions
+pub fn versions() -> Vec<PluginVersion> {
+    let mut versions: Vec<PluginVersion> = VERSIONS
+        .iter()
+        .map(|v| PluginVersion {
+            health: Some(health_of(v.key())),
+            ..v.value().clone()
+        })
+        .collect();
+    versions.sort_by(|a, b| a.name.cmp(&b.name));
+    versions
+}
+
+/// Settings of the current version of a plugin
+pub fn plugin_item(name: &str) -> Option<PluginItem> {
+    VERSIONS.get(name).map(|v| v.item.clone())
+}
+
+fn health_of(name: &str) -> PluginHealth {
+    let mut health = HEALTH.get(name).map(|h| h.clone()).unwrap_or_default();
+    health.healthy = health.consecutive_failures < FAILURE_THRESHOLD;
+    health
+}
+
+/// Health of every loaded plugin
+pub fn health() -> Vec<(String, PluginHealth)> {
+    VERSIONS
+        .iter()
+        .map(|v| (v.key().clone(), health_of(v.key())))
+        .collect()
+}
+
+pub fn is_healthy(name: &str) -> bool {
+    HEALTH
+        .get(name)
+        .is_none_or(|h| h.consecutive_failures < FAILURE_THRESHOLD)
+}
+
+/// A session of the plugin crashed, trapped or went away without answering
+pub fn record_failure(name: &str, error: &str) {
+    let mut health = HEALTH.entry(name.to_string()).or_default();
+    health.consecutive_failures += 1;
+    health.failures += 1;
+    health.last_error = Some(error.to_string());
+    if health.consecutive_failures == FAILURE_THRESHOLD {
+        tracing::error!("Plugin {} marked unhealthy: {}", name, error);
+    } else {
+        tracing::warn!("Plugin {} failed: {}", name, error);
+    }
+}
+
+pub fn record_success(name: &str) {
+    if let Some(mut health) = HEALTH.get_mut(name)
+        && health.consecutive_failures > 0
+    {
+        health.consecutive_failures = 0;
+    }
+}
+
+/// Semaphore for the sessions of `plugin`, replaced when its limit changes
+///
+/// Sessions holding a slot of a replaced semaphore keep it until they finish.
+fn session_semaphore(plugin: &PluginItem) -> Arc<Semaphore> {
+    let mut limit = LIMITS
+        .entry(plugin.name.clone())
+        .or_insert_with(|| SessionLimit {
+            max: None,
+            semaphore: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            rejected: 0,
+        });
+    if limit.max != plugin.max_concurrent_sessions {
+        limit.max = plugin.max_concurrent_sessions;
+        limit.semaphore = Arc::new(Semaphore::new(
+            plugin
+                .max_concurrent_sessions
+                .unwrap_or(Semaphore::MAX_PERMITS)
+                .min(Semaphore::MAX_PERMITS),
+        ));
+    }
+    limit.semaphore.clone()
+}
+
+/// Take a session slot of `plugin`, honoring its overflow policy
+///
+/// Returns `None` when the plugin is at `max_concurrent_sessions` and no slot
+/// came free in time. The slot is given back when the permit is dropped.
+pub async fn acquire_session(plugin: &PluginItem) -> Option<OwnedSemaphorePermit> {
+    let semaphore = session_semaphore(plugin);
+    let permit = match plugin.on_overflow {
+        PluginOverflow::Reject => semaphore.try_acquire_owned().ok(),
+        PluginOverflow::Queue => {
+            tokio::time::timeout(plugin.queue_timeout(), semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(|permit| permit.ok())
         }
     };
-    let plugin_free = unsafe {
-        let symbol: Symbol<FfiPluginFreeFn> = lib
-            .get(ffi_symbols::PLUGIN_FREE.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::PLUGIN_FREE);
-            });
-        std::mem::transmute::<Symbol<FfiPluginFreeFn>, Symbol<'static, FfiPluginFreeFn>>(symbol)
+    if permit.is_none() {
+        if let Some(mut limit) = LIMITS.get_mut(&plugin.name) {
+            limit.rejected += 1;
+        }
+        tracing::warn!("Plugin {} has no free session", plugin.name);
+    }
+    permit
+}
+
+/// Sessions every plugin has open, with the requests turned away for lack of one
+pub fn sessions_in_flight() -> Vec<(String, usize, u64)> {
+    LIMITS
+        .iter()
+        .map(|limit| {
+            let max = limit.max.unwrap_or(Semaphore::MAX_PERMITS);
+            let open = max.saturating_sub(limit.semaphore.available_permits());
+            (limit.key().clone(), open, limit.rejected)
+        })
+        .collect()
+}
+
+/// Load a fresh instance of every unhealthy plugin
+///
+/// The failed instance is retired, never reinitialized. Returns the plugins that were tried with the outcome.
+pub fn restart_unhealthy() -> Vec<(String, Result<PluginVersion, NylonError>)> {
+    let unhealthy: Vec<String> = HEALTH
+        .iter()
+        .filter(|h| h.consecutive_failures >= FAILURE_THRESHOLD)
+        .map(|h| h.key().clone())
+        .collect();
+    unhealthy
+        .into_iter()
+        .filter_map(|name| {
+            let item = plugin_item(&name)?;
+            Some((name, reload(&item)))
+        })
+        .collect()
+}
+
+/// Retire every plugin whose name is not in `names`
+///
+/// Open sessions keep the plugin until they finish, like a replaced version.
+pub fn retire_removed(names: &[&str]) {
+    let removed: Vec<String> = VERSIONS
+        .iter()
+        .map(|v| v.key().clone())
+        .filter(|name| !names.contains(&name.as_str()))
+        .collect();
+    if removed.is_empty() {
+        return;
+    }
+    let plugins =
+        nylon_store::get::<DashMap<String, Arc<dyn PluginBackend>>>(nylon_store::KEY_PLUGINS);
+    for name in removed {
+        let version = VERSIONS.remove(&name).map(|(_, version)| version);
+        let backend = plugins
+            .as_ref()
+            .and_then(|plugins| plugins.remove(&name))
+            .map(|(_, backend)| backend);
+        HEALTH.remove(&name);
+        LIMITS.remove(&name);
+        tracing::info!("Plugin {} removed from the config", name);
+        if let (Some(version), Some(backend)) = (version, backend)
+            && let Ok(mut retired) = RETIRED.lock()
+        {
+            retired.push((version, backend));
+        }
+    }
+    if let Some(plugins) = plugins {
+        nylon_store::insert(nylon_store::KEY_PLUGINS, plugins);
+    }
+}
+
+/// Shut down and unload retired versions nobody uses anymore
+///
+/// Returns the versions that were unloaded.
+pub fn reap_retired() -> Vec<PluginVersion> {
+    let drained = {
+        let Ok(mut retired) = RETIRED.lock() else {
+            return vec![];
+        };
+        let (drained, in_use): (Vec<_>, Vec<_>) = retired
+            .drain(..)
+            .partition(|(_, backend)| Arc::strong_count(backend) == 1);
+        *retired = in_use;
+        drained
     };
-    let register_session = unsafe {
-        let symbol: Symbol<FfiRegisterSessionFn> = lib
-            .get(ffi_symbols::REGISTER_SESSION.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::REGISTER_SESSION);
-            });
-        std::mem::transmute::<Symbol<FfiRegisterSessionFn>, Symbol<'static, FfiRegisterSessionFn>>(
-            symbol,
-        )
+    drained
+        .into_iter()
+        .map(|(version, backend)| {
+            backend.shutdown();
+            version
+        })
+        .collect()
+}
+
+fn load_version(plugin: &PluginItem, force: bool) -> Result<PluginVersion, NylonError> {
+    let version = match plugin.plugin_type {
+        PluginType::Grpc => "-".to_string(),
+        PluginType::Ffi | PluginType::Wasm | PluginType::Js => file_version(&plugin.file)?,
     };
-    let event_stream = unsafe {
-        let symbol: Symbol<FfiEventStreamFn> = lib
-            .get(ffi_symbols::EVENT_STREAM.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::EVENT_STREAM);
-            });
-        std::mem::transmute::<Symbol<FfiEventStreamFn>, Symbol<'static, FfiEventStreamFn>>(symbol)
+    let config = match &plugin.config {
+        Some(config) => serde_json::to_string(config).unwrap_or_default(),
+        None => "".to_string(),
     };
-    let close_session = unsafe {
-        let symbol: Symbol<FfiCloseSessionFn> = lib
-            .get(ffi_symbols::CLOSE_SESSION.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::CLOSE_SESSION);
-            });
-        std::mem::transmute::<Symbol<FfiCloseSessionFn>, Symbol<'static, FfiCloseSessionFn>>(symbol)
+    if !force
+        && let Some(mut current) = VERSIONS.get_mut(&plugin.name)
+        && current.file == plugin.file
+        && current.version == version
+        && current.config == config
+    {
+        current.item = plugin.clone();
+        return Ok(current.clone());
+    }
+
+    let backend: Arc<dyn PluginBackend> = match plugin.plugin_type {
+        PluginType::Ffi => Arc::new(load_ffi(plugin, &version, &config)?),
+        PluginType::Wasm => Arc::new(crate::wasm::WasmPlugin::load(plugin)?),
+        PluginType::Grpc => Arc::new(crate::grpc::GrpcPlugin::new(plugin)?),
+        PluginType::Js => Arc::new(crate::js::JsPlugin::load(plugin)?),
     };
-    let shutdown = unsafe {
-        let symbol: Symbol<FfiShutdownFn> = lib
-            .get(ffi_symbols::SHUTDOWN.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::SHUTDOWN);
-            });
-        std::mem::transmute::<Symbol<FfiShutdownFn>, Symbol<'static, FfiShutdownFn>>(symbol)
+    let loaded = PluginVersion {
+        name: plugin.name.clone(),
+        file: plugin.file.clone(),
+        version,
+        loaded_at: chrono::Utc::now().to_rfc3339(),
+        health: None,
+        config,
+        item: plugin.clone(),
     };
+    register(backend, loaded.clone());
+    if let Some(mut health) = HEALTH.get_mut(&loaded.name) {
+        health.consecutive_failures = 0;
+    }
+    tracing::info!(
+        "Plugin {} version {} loaded from {}",
+        loaded.name,
+        loaded.version,
+        loaded.file
+    );
+    Ok(loaded)
+}
 
-    let ffi_item = FfiPlugin {
-        _lib: lib.clone(),
-        plugin_free,
-        register_session,
-        event_stream,
-        close_session,
-        shutdown,
-    };
+/// Short content hash of a plugin file
+fn file_version(file: &str) -> Result<String, NylonError> {
+    let data = std::fs::read(file)
+        .map_err(|e| NylonError::ConfigError(format!("Plugin file {}: {}", file, e)))?;
+    let digest = Sha256::digest(&data);
+    Ok(digest[..6].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn register(backend: Arc<dyn PluginBackend>, version: PluginVersion) {
     let plugins =
-        match nylon_store::get::<DashMap<String, Arc<FfiPlugin>>>(nylon_store::KEY_PLUGINS) {
+        match nylon_store::get::<DashMap<String, Arc<dyn PluginBackend>>>(nylon_store::KEY_PLUGINS)
+        {
             Some(plugins) => plugins,
             None => {
                 let new_plugins = DashMap::new();
@@ -101,25 +348,69 @@ pub fn load(plugin: &PluginItem) {
                 new_plugins
             }
         };
-    plugins.insert(plugin.name.clone(), Arc::new(ffi_item));
+    let previous = plugins.insert(version.name.clone(), backend);
     nylon_store::insert(nylon_store::KEY_PLUGINS, plugins);
 
-    // initialize
-    let initialize = unsafe {
-        let symbol: Symbol<FfiInitializeFn> = lib
-            .get(ffi_symbols::INITIALIZE.as_bytes())
-            .unwrap_or_else(|_| {
-                panic!("Failed to load symbol: {}", ffi_symbols::INITIALIZE);
-            });
-        std::mem::transmute::<Symbol<FfiInitializeFn>, Symbol<'static, FfiInitializeFn>>(symbol)
-    };
-    let config = match &plugin.config {
-        Some(config) => serde_json::to_string(&config).unwrap_or_default(),
-        None => "".to_string(),
+    let previous_version = VERSIONS.insert(version.name.clone(), version);
+    if let (Some(backend), Some(version)) = (previous, previous_version)
+        && let Ok(mut retired) = RETIRED.lock()
+    {
+        retired.push((version, backend));
+    }
+}
+
+/// Open a library version from a private copy
+///
+/// The dynamic loader reuses an already loaded path, so a new build at the
+/// same path would otherwise never be picked up. Every load gets a copy of its
+/// own: initializing it never touches an instance that is still serving, and
+/// shutting down a retired instance leaves the new one alone.
+fn open_library(file: &str, version: &str) -> Result<Arc<Library>, NylonError> {
+    let extension = Path::new(file)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("so");
+    let copy = std::env::temp_dir().join(format!(
+        "nylon-plugin-{}-{}-{}.{}",
+        std::process::id(),
+        version,
+        LIBRARY_COPIES.fetch_add(1, Ordering::Relaxed),
+        extension
+    ));
+    std::fs::copy(file, &copy)
+        .map_err(|e| NylonError::ConfigError(format!("Failed to copy {}: {}", file, e)))?;
+    let lib = unsafe { Library::new(&copy) };
+    // The mapping stays valid after the file is gone
+    let _ = std::fs::remove_file(&copy);
+    Ok(Arc::new(lib.map_err(|e| {
+        NylonError::ConfigError(format!("Failed to load shared library {}: {}", file, e))
+    })?))
+}
+
+fn symbol<T>(lib: &Library, name: &str) -> Result<Symbol<'static, T>, NylonError> {
+    unsafe {
+        let symbol: Symbol<T> = lib
+            .get(name.as_bytes())
+            .map_err(|_| NylonError::ConfigError(format!("Failed to load symbol: {}", name)))?;
+        Ok(std::mem::transmute::<Symbol<T>, Symbol<'static, T>>(symbol))
+    }
+}
+
+fn load_ffi(plugin: &PluginItem, version: &str, config: &str) -> Result<FfiPlugin, NylonError> {
+    let lib = open_library(&plugin.file, version)?;
+    let ffi_item = FfiPlugin {
+        plugin_free: symbol::<FfiPluginFreeFn>(&lib, ffi_symbols::PLUGIN_FREE)?,
+        register_session: symbol::<FfiRegisterSessionFn>(&lib, ffi_symbols::REGISTER_SESSION)?,
+        event_stream: symbol::<FfiEventStreamFn>(&lib, ffi_symbols::EVENT_STREAM)?,
+        close_session: symbol::<FfiCloseSessionFn>(&lib, ffi_symbols::CLOSE_SESSION)?,
+        shutdown: symbol::<FfiShutdownFn>(&lib, ffi_symbols::SHUTDOWN)?,
+        _lib: lib.clone(),
     };
-    let config_ptr = config.as_ptr();
-    let config_len = config.len() as u32;
+
+    // initialize before the new version takes any session
+    let initialize = symbol::<FfiInitializeFn>(&lib, ffi_symbols::INITIALIZE)?;
     unsafe {
-        initialize(config_ptr, config_len);
+        initialize(config.as_ptr(), config.len() as u32);
     }
+    Ok(ffi_item)
 }
@@ -0,0 +1,136 @@
+//! Metrics emitted by plugins
+//!
+//! Plugins share one counter and one histogram family in the default
+//! registry, labelled by plugin and metric name, so they appear on the
+//! proxy's own Prometheus endpoint. Each plugin may use a bounded number of
+//! names, so a plugin cannot grow the endpoint without limit.
+
+use dashmap::DashMap;
+use nylon_error::NylonError;
+use once_cell::sync::Lazy;
+use prometheus::{CounterVec, HistogramVec, register_counter_vec, register_histogram_vec};
+use std::collections::HashSet;
+
+/// Longest metric name a plugin may use
+const MAX_NAME_LEN: usize = 128;
+
+/// Distinct counter and histogram names one plugin may use
+const MAX_NAMES_PER_PLUGIN: usize = 100;
+
+/// Names each plugin has used, as `(kind, name)`
+static NAMES: Lazy<DashMap<String, HashSet<(&'static str, String)>>> = Lazy::new(DashMap::new);
+
+static COUNTERS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "nylon_plugin_counter_total",
+        "Counters incremented by plugins",
+        &["plugin", "metric"]
+    )
+    .expect("register nylon_plugin_counter_total")
+});
+
+static HISTOGRAMS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nylon_plugin_histogram",
+        "Values observed by plugins",
+        &["plugin", "metric"]
+    )
+    .expect("register nylon_plugin_histogram")
+});
+
+fn check_name(name: &str) -> Result<(), NylonError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if !valid {
+        return Err(NylonError::ConfigError(format!(
+            "Invalid plugin metric name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Allow `name` unless the plugin already uses `MAX_NAMES_PER_PLUGIN` others
+fn check_cardinality(plugin: &str, kind: &'static str, name: &str) -> Result<(), NylonError> {
+    let mut names = NAMES.entry(plugin.to_string()).or_default();
+    let key = (kind, name.to_string());
+    if names.contains(&key) {
+        return Ok(());
+    }
+    if names.len() >= MAX_NAMES_PER_PLUGIN {
+        return Err(NylonError::ConfigError(format!(
+            "Plugin {} already uses {} metric names, dropping {}",
+            plugin, MAX_NAMES_PER_PLUGIN, name
+        )));
+    }
+    names.insert(key);
+    Ok(())
+}
+
+/// Add `value` to a plugin counter
+pub fn incr(plugin: &str, name: &str, value: f64) -> Result<(), NylonError> {
+    check_name(name)?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(NylonError::ConfigError(format!(
+            "Counter {} can only grow, got {}",
+            name, value
+        )));
+    }
+    check_cardinality(plugin, "counter", name)?;
+    COUNTERS.with_label_values(&[plugin, name]).inc_by(value);
+    Ok(())
+}
+
+/// Record `value` in a plugin histogram
+pub fn observe(plugin: &str, name: &str, value: f64) -> Result<(), NylonError> {
+    check_name(name)?;
+    if !value.is_finite() {
+        return Err(NylonError::ConfigError(format!(
+            "Histogram {} got {}",
+            name, value
+        )));
+    }
+    check_cardinality(plugin, "histogram", name)?;
+    HISTOGRAMS.with_label_values(&[plugin, name]).observe(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("requests:denied_1").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("has-dash").is_err());
+        assert!(check_name("label{x=\"1\"}").is_err());
+        assert!(check_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_bad_values() {
+        assert!(incr("test-values", "ok", 2.0).is_ok());
+        assert!(incr("test-values", "ok", -1.0).is_err());
+        assert!(incr("test-values", "ok", f64::NAN).is_err());
+        assert!(observe("test-values", "latency", -0.5).is_ok());
+        assert!(observe("test-values", "latency", f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn test_cardinality_cap() {
+        let plugin = "test-cardinality";
+        for i in 0..MAX_NAMES_PER_PLUGIN {
+            incr(plugin, &format!("m{}", i), 1.0).unwrap();
+        }
+        // Known names keep working, new ones are dropped
+        assert!(incr(plugin, "m0", 1.0).is_ok());
+        assert!(incr(plugin, "new", 1.0).is_err());
+        assert!(observe(plugin, "new", 1.0).is_err());
+        // The cap is per plugin
+        assert!(incr("test-cardinality-other", "new", 1.0).is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+//! JSON body transforms
+//!
+//! The mapping is rendered when the middleware runs, so templates see the
+//! request as it was then. The proxy buffers the body and applies it.
+
+use nylon_error::NylonError;
+use nylon_types::{
+    body_transform::{BodyTransform, JsonMapping},
+    context::NylonContext,
+    template::{Expr, apply_payload_ast},
+};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Payload structure for body transforms
+#[derive(Debug, Deserialize, Clone)]
+struct Payload {
+    request: Option<JsonMapping>,
+    response: Option<JsonMapping>,
+}
+
+fn parse(
+    ctx: &NylonContext,
+    session: &Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<Payload, NylonError> {
+    let Some(payload) = payload else {
+        return Err(NylonError::ConfigError(
+            "BodyTransform requires a payload with a request or response mapping".to_string(),
+        ));
+    };
+    let mut payload = payload.clone();
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    serde_json::from_value::<Payload>(payload).map_err(|e| NylonError::ConfigError(e.to_string()))
+}
+
+/// Prepare the transform of a JSON request body
+pub fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<(), NylonError> {
+    let Some(mapping) = parse(ctx, session, payload, payload_ast)?.request else {
+        return Ok(());
+    };
+    let json = session
+        .req_header()
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(BodyTransform::applies_to);
+    if !json || session.is_body_empty() {
+        return Ok(());
+    }
+    ctx.request_body_transform = Some(BodyTransform::new(mapping));
+    Ok(())
+}
+
+/// Prepare the transform of the response body
+///
+/// The proxy drops it again unless the response is uncompressed JSON.
+pub fn response(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<(), NylonError> {
+    let Some(mapping) = parse(ctx, session, payload, payload_ast)?.response else {
+        return Ok(());
+    };
+    ctx.response_body_transform = Some(BodyTransform::new(mapping));
+    Ok(())
+}
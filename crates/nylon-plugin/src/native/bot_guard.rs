@@ -0,0 +1,522 @@
+//! Bot and scanner detection
+//!
+//! A request is suspicious when its `User-Agent` names a known scanner or one
+//! of the configured agents, when a required header is missing, or when its
+//! client IP goes over the rate limit. Suspicious requests are blocked,
+//! tarpitted (blocked after a delay), or challenged. A challenge sets a
+//! cookie signed for the client IP and `User-Agent`, either from a small
+//! JavaScript page or with a redirect. Requests that carry a valid cookie or
+//! come from an allowed agent skip the other checks, but not the rate limit.
+
+use super::waf::take;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use nylon_error::NylonError;
+use nylon_types::{
+    context::NylonContext,
+    template::{Expr, apply_payload_ast},
+    waf::RateLimit,
+};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Longest a tarpit holds a request
+const MAX_TARPIT_MS: u64 = 30_000;
+
+/// Requests held in a tarpit at once; more are blocked right away
+const MAX_TARPITTED: usize = 1024;
+
+static TARPITTED: AtomicUsize = AtomicUsize::new(0);
+
+/// `User-Agent` substrings of common vulnerability scanners and fuzzers
+const SCANNERS: &[&str] = &[
+    "sqlmap",
+    "nikto",
+    "nmap",
+    "masscan",
+    "zgrab",
+    "nuclei",
+    "wpscan",
+    "dirbuster",
+    "gobuster",
+    "feroxbuster",
+    "fuzz faster u fool",
+    "acunetix",
+    "netsparker",
+    "openvas",
+    "nessus",
+    "w3af",
+    "whatweb",
+    "zmeu",
+    "jorgee",
+    "hydra",
+];
+
+fn default_status() -> u16 {
+    403
+}
+
+fn default_tarpit_ms() -> u64 {
+    5000
+}
+
+fn default_cookie_name() -> String {
+    "nylon_bot".to_string()
+}
+
+fn default_cookie_ttl() -> u64 {
+    86400
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BotAction {
+    #[default]
+    Block,
+    Tarpit,
+    Challenge,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ChallengeKind {
+    #[default]
+    Js,
+    Cookie,
+}
+
+/// Payload structure for bot detection
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Payload {
+    #[serde(default)]
+    action: BotAction,
+    /// Status of blocked requests and challenge pages
+    #[serde(default = "default_status")]
+    status: u16,
+    /// Delay of `tarpit`, at most 30 seconds
+    #[serde(default = "default_tarpit_ms")]
+    tarpit_ms: u64,
+    #[serde(default)]
+    challenge: ChallengeKind,
+    /// Signs challenge cookies; required for `challenge`
+    secret: Option<String>,
+    #[serde(default = "default_cookie_name")]
+    cookie_name: String,
+    #[serde(default = "default_cookie_ttl")]
+    cookie_ttl_seconds: u64,
+    /// Match the built-in list of scanners
+    #[serde(default = "default_true")]
+    scanners: bool,
+    /// More `User-Agent` substrings, ignoring case
+    #[serde(default)]
+    user_agents: Vec<String>,
+    /// `User-Agent` substrings that skip the agent and header checks
+    #[serde(default)]
+    allow_user_agents: Vec<String>,
+    /// Headers every real client sends, e.g. `user-agent`, `accept`
+    #[serde(default)]
+    require_headers: Vec<String>,
+    /// Requests per client IP
+    rate_limit: Option<RateLimit>,
+}
+
+/// Check the request and answer suspicious ones
+///
+/// Returns `true` when the request was answered.
+pub async fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<bool, NylonError> {
+    let mut payload = payload
+        .clone()
+        .unwrap_or_else(|| Value::Object(Default::default()));
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+    let secret = match (&payload.secret, payload.action) {
+        (Some(secret), _) => secret.as_str(),
+        (None, BotAction::Challenge) => {
+            return Err(NylonError::ConfigError(
+                "BotGuard challenge requires a secret".to_string(),
+            ));
+        }
+        (None, _) => "",
+    };
+
+    let req = session.req_header();
+    let header = |name: &str| {
+        req.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    };
+    let user_agent = header("user-agent").to_string();
+    let client_ip = ctx.client_ip.clone();
+    let route = ctx
+        .route
+        .as_ref()
+        .map(|r| r.name.as_str())
+        .unwrap_or_default();
+    let verdict = inspect(
+        &payload,
+        secret,
+        route,
+        &client_ip,
+        &user_agent,
+        header("cookie"),
+        |name| !header(name).is_empty(),
+    );
+    let reason = match verdict {
+        Verdict::Pass => return Ok(false),
+        Verdict::RateLimited(retry_after) => {
+            tracing::debug!("BotGuard: rate from {} ({})", client_ip, user_agent);
+            if payload.action == BotAction::Challenge {
+                // Another challenge would not slow the client down
+                rate_limited(ctx, retry_after);
+            } else {
+                answer(ctx, &payload).await;
+            }
+            return Ok(true);
+        }
+        Verdict::Suspicious(reason) => reason,
+    };
+    tracing::debug!(
+        "BotGuard: {} from {} ({}), {:?}",
+        reason,
+        client_ip,
+        user_agent,
+        payload.action
+    );
+
+    match payload.action {
+        BotAction::Block | BotAction::Tarpit => answer(ctx, &payload).await,
+        BotAction::Challenge => {
+            let token = sign(secret, &client_ip, &user_agent, payload.cookie_ttl_seconds);
+            let target = req
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/")
+                .to_string();
+            let set_cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                payload.cookie_name, token, payload.cookie_ttl_seconds
+            );
+            challenge(ctx, &payload, &token, set_cookie, target);
+        }
+    }
+    Ok(true)
+}
+
+/// Outcome of the checks
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Pass,
+    /// Over the rate limit, retry after this many seconds
+    RateLimited(u64),
+    Suspicious(&'static str),
+}
+
+/// Run the checks on a request
+///
+/// Every request counts against the rate limit, so neither an allowed agent
+/// (the client picks its `User-Agent`) nor a challenge cookie lifts it.
+fn inspect(
+    payload: &Payload,
+    secret: &str,
+    route: &str,
+    client_ip: &str,
+    user_agent: &str,
+    cookies: &str,
+    has_header: impl Fn(&str) -> bool,
+) -> Verdict {
+    if let Some(limit) = payload.rate_limit
+        && let Err(retry_after) = take(&format!("BotGuard/{}", route), client_ip, limit)
+    {
+        return Verdict::RateLimited(retry_after);
+    }
+
+    let ua = user_agent.to_lowercase();
+    let contains = |list: &[String]| list.iter().any(|s| ua.contains(&s.to_lowercase()));
+    if contains(&payload.allow_user_agents) {
+        return Verdict::Pass;
+    }
+    if payload.action == BotAction::Challenge
+        && let Some(cookie) = cookie(cookies, &payload.cookie_name)
+        && verify(secret, client_ip, user_agent, cookie)
+    {
+        return Verdict::Pass;
+    }
+
+    if (payload.scanners && SCANNERS.iter().any(|s| ua.contains(s)))
+        || contains(&payload.user_agents)
+    {
+        Verdict::Suspicious("scanner")
+    } else if payload
+        .require_headers
+        .iter()
+        .any(|name| !has_header(&name.to_ascii_lowercase()))
+    {
+        Verdict::Suspicious("missing_header")
+    } else {
+        Verdict::Pass
+    }
+}
+
+/// Block the request, after the delay of a tarpit
+async fn answer(ctx: &mut NylonContext, payload: &Payload) {
+    if payload.action == BotAction::Tarpit {
+        if let Some(_held) = Tarpitted::enter() {
+            let ms = payload.tarpit_ms.min(MAX_TARPIT_MS);
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+        }
+    }
+    block(ctx, payload.status);
+}
+
+/// A request in the tarpit, counted against `MAX_TARPITTED`
+struct Tarpitted;
+
+impl Tarpitted {
+    fn enter() -> Option<Self> {
+        if TARPITTED.fetch_add(1, Ordering::Relaxed) >= MAX_TARPITTED {
+            TARPITTED.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Self)
+    }
+}
+
+impl Drop for Tarpitted {
+    fn drop(&mut self) {
+        TARPITTED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn cookie<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|part| part.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn mac(secret: &str, client_ip: &str, user_agent: &str, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(format!("{}|{}|{}", client_ip, user_agent, expires).as_bytes());
+    mac
+}
+
+/// `expiry.base64url(hmac_sha256(secret, "ip|user agent|expiry"))`
+fn sign(secret: &str, client_ip: &str, user_agent: &str, ttl: u64) -> String {
+    let expires = now() + ttl;
+    let signature = mac(secret, client_ip, user_agent, expires)
+        .finalize()
+        .into_bytes();
+    format!("{}.{}", expires, URL_SAFE_NO_PAD.encode(signature))
+}
+
+fn verify(secret: &str, client_ip: &str, user_agent: &str, token: &str) -> bool {
+    let Some((expires, signature)) = token.split_once('.') else {
+        return false;
+    };
+    let (Ok(expires), Ok(signature)) = (expires.parse::<u64>(), URL_SAFE_NO_PAD.decode(signature))
+    else {
+        return false;
+    };
+    expires > now()
+        && mac(secret, client_ip, user_agent, expires)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+fn block(ctx: &mut NylonContext, status: u16) {
+    ctx.set_response_status.store(status, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": "FORBIDDEN",
+        "message": "Request blocked",
+    })
+    .to_string()
+    .into_bytes();
+}
+
+fn rate_limited(ctx: &mut NylonContext, retry_after: u64) {
+    ctx.set_response_status.store(429, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.add_response_header
+        .insert("Retry-After".to_string(), retry_after.to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": "RATE_LIMITED",
+        "message": "Too many requests",
+    })
+    .to_string()
+    .into_bytes();
+}
+
+/// Answer with the cookie: a redirect back for `cookie`, a page setting it for `js`
+fn challenge(
+    ctx: &mut NylonContext,
+    payload: &Payload,
+    token: &str,
+    set_cookie: String,
+    target: String,
+) {
+    ctx.add_response_header
+        .insert("Cache-Control".to_string(), "no-store".to_string());
+    match payload.challenge {
+        ChallengeKind::Cookie => {
+            ctx.set_response_status.store(307, Ordering::Relaxed);
+            ctx.add_response_header
+                .insert("Set-Cookie".to_string(), set_cookie);
+            ctx.add_response_header
+                .insert("Location".to_string(), target);
+        }
+        ChallengeKind::Js => {
+            ctx.set_response_status
+                .store(payload.status, Ordering::Relaxed);
+            ctx.add_response_header.insert(
+                "Content-Type".to_string(),
+                "text/html; charset=utf-8".to_string(),
+            );
+            // The cookie is split so it is only whole once the script runs
+            let (head, tail) = token.split_at(token.len() / 2);
+            let body = format!(
+                "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Checking your browser</title></head>\
+<body><noscript>Please enable JavaScript and reload the page.</noscript>\
+<script>document.cookie=\"{}=\"+[\"{}\",\"{}\"].join(\"\")+\"; Path=/; Max-Age={}; SameSite=Lax\";location.reload();</script>\
+</body></html>",
+                payload.cookie_name, head, tail, payload.cookie_ttl_seconds
+            );
+            ctx.set_response_body = body.into_bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let token = sign("secret", "10.0.0.1", "curl/8", 60);
+        assert!(verify("secret", "10.0.0.1", "curl/8", &token));
+        assert!(!verify("other", "10.0.0.1", "curl/8", &token));
+        assert!(!verify("secret", "10.0.0.2", "curl/8", &token));
+        assert!(!verify("secret", "10.0.0.1", "curl/9", &token));
+
+        // The expiry is signed, so it cannot be pushed out
+        let (expires, signature) = token.split_once('.').unwrap();
+        let later = expires.parse::<u64>().unwrap() + 3600;
+        assert!(!verify(
+            "secret",
+            "10.0.0.1",
+            "curl/8",
+            &format!("{}.{}", later, signature)
+        ));
+
+        let expired = sign("secret", "10.0.0.1", "curl/8", 0);
+        assert!(!verify("secret", "10.0.0.1", "curl/8", &expired));
+        for token in ["", "123", "abc.def", "123.!!!"] {
+            assert!(!verify("secret", "10.0.0.1", "curl/8", token));
+        }
+    }
+
+    #[test]
+    fn test_cookie() {
+        let header = "a=1; nylon_bot=123.abc;b=2 ; nylon_bot_x=3";
+        assert_eq!(cookie(header, "nylon_bot"), Some("123.abc"));
+        assert_eq!(cookie(header, "b"), Some("2"));
+        assert_eq!(cookie(header, "nylon_bot_x"), Some("3"));
+        assert_eq!(cookie(header, "nylon"), None);
+        assert_eq!(cookie("", "nylon_bot"), None);
+        assert_eq!(cookie("nylon_bot", "nylon_bot"), None);
+    }
+
+    fn payload(value: Value) -> Payload {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_allowed_agents_are_rate_limited() {
+        let p = payload(serde_json::json!({
+            "allow_user_agents": ["googlebot"],
+            "rate_limit": {"requests": 2, "window_seconds": 60},
+        }));
+        let ua = "Mozilla/5.0 (compatible; Googlebot/2.1) sqlmap";
+        let check = || inspect(&p, "", "allowed", "10.1.0.1", ua, "", |_| true);
+        assert_eq!(check(), Verdict::Pass);
+        assert_eq!(check(), Verdict::Pass);
+        assert!(matches!(check(), Verdict::RateLimited(_)));
+        // Other clients keep their own window
+        assert_eq!(
+            inspect(&p, "", "allowed", "10.1.0.2", ua, "", |_| true),
+            Verdict::Pass
+        );
+    }
+
+    #[test]
+    fn test_inspect() {
+        let p = payload(serde_json::json!({
+            "allow_user_agents": ["goodbot"],
+            "require_headers": ["Accept"],
+        }));
+        let check =
+            |ua: &str, accept: bool| inspect(&p, "", "inspect", "10.2.0.1", ua, "", |_| accept);
+        assert_eq!(check("curl/8", true), Verdict::Pass);
+        assert_eq!(check("sqlmap/1.7", true), Verdict::Suspicious("scanner"));
+        assert_eq!(
+            check("curl/8", false),
+            Verdict::Suspicious("missing_header")
+        );
+        assert_eq!(check("GoodBot/1 sqlmap", false), Verdict::Pass);
+
+        let p = payload(serde_json::json!({"action": "challenge", "secret": "s"}));
+        let token = sign("s", "10.2.0.1", "nikto", 60);
+        let cookies = format!("nylon_bot={}", token);
+        let check =
+            |cookies: &str| inspect(&p, "s", "inspect", "10.2.0.1", "nikto", cookies, |_| true);
+        assert_eq!(check(&cookies), Verdict::Pass);
+        assert_eq!(check(""), Verdict::Suspicious("scanner"));
+    }
+
+    #[test]
+    fn test_tarpit_is_capped() {
+        TARPITTED.store(MAX_TARPITTED, Ordering::Relaxed);
+        assert!(Tarpitted::enter().is_none());
+        assert_eq!(TARPITTED.load(Ordering::Relaxed), MAX_TARPITTED);
+        TARPITTED.store(0, Ordering::Relaxed);
+        {
+            let _held = Tarpitted::enter().unwrap();
+            assert_eq!(TARPITTED.load(Ordering::Relaxed), 1);
+        }
+        assert_eq!(TARPITTED.load(Ordering::Relaxed), 0);
+    }
+}
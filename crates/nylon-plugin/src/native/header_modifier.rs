@@ -1,6 +1,6 @@
 use nylon_error::NylonError;
 use nylon_types::{
-    context::NylonContext,
+    context::{HeaderMode, NylonContext},
     template::{Expr, apply_payload_ast},
 };
 use pingora::proxy::Session;
@@ -9,8 +9,10 @@ use serde_json::Value;
 use std::collections::HashMap;
 
 /// Payload structure for header modification
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 struct Payload {
+    /// Rendered template; the modifier does nothing when it is empty
+    when: Option<String>,
     remove: Option<Vec<String>>,
     set: Option<Vec<Header>>,
 }
@@ -20,6 +22,36 @@ struct Payload {
 struct Header {
     name: String,
     value: String,
+    #[serde(default)]
+    mode: HeaderMode,
+    /// Rendered template; the header is skipped when it is empty
+    when: Option<String>,
+}
+
+/// Template conditions are true unless they render to an empty string, as in `if_cond`
+fn holds(when: &Option<String>) -> bool {
+    when.as_ref().is_none_or(|when| !when.is_empty())
+}
+
+fn parse(
+    ctx: &NylonContext,
+    session: &Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<Payload, NylonError> {
+    let Some(payload) = payload.as_ref() else {
+        return Ok(Payload::default());
+    };
+    let mut payload = payload.clone();
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+    if !holds(&payload.when) {
+        return Ok(Payload::default());
+    }
+    Ok(payload)
 }
 
 pub fn request(
@@ -28,27 +60,19 @@ pub fn request(
     payload: &Option<Value>,
     payload_ast: &Option<HashMap<String, Vec<Expr>>>,
 ) -> Result<(), NylonError> {
+    let payload = parse(ctx, session, payload, payload_ast)?;
     let headers = session.req_header_mut();
-    let payload = match payload.as_ref() {
-        Some(payload) => {
-            let mut payload = payload.clone();
-            if let Some(payload_ast) = payload_ast {
-                apply_payload_ast(&mut payload, payload_ast, headers, ctx);
-            }
-            serde_json::from_value::<Payload>(payload.clone())
-                .map_err(|e| NylonError::ConfigError(e.to_string()))?
-        }
-        None => Payload {
-            remove: None,
-            set: None,
-        },
-    };
-    // println!("payload: {:#?}", payload);
     if let Some(set) = payload.set {
-        for header in set {
-            let _ = headers.remove_header(&header.name);
+        for header in set.into_iter().filter(|h| holds(&h.when)) {
             let name = header.name.to_ascii_lowercase();
-            let _ = headers.append_header(name.clone(), &header.value);
+            match header.mode {
+                HeaderMode::Set => {
+                    let _ = headers.remove_header(&name);
+                }
+                HeaderMode::SetIfAbsent if headers.headers.contains_key(&name) => continue,
+                _ => {}
+            }
+            let _ = headers.append_header(name, &header.value);
         }
     }
     if let Some(remove) = payload.remove {
@@ -65,32 +89,15 @@ pub fn response(
     payload: &Option<Value>,
     payload_ast: &Option<HashMap<String, Vec<Expr>>>,
 ) -> Result<(), NylonError> {
-    let headers = session.req_header();
-    let payload = match payload.as_ref() {
-        Some(payload) => {
-            let mut payload = payload.clone();
-            if let Some(payload_ast) = payload_ast {
-                apply_payload_ast(&mut payload, payload_ast, headers, ctx);
-            }
-            serde_json::from_value::<Payload>(payload.clone())
-                .map_err(|e| NylonError::ConfigError(e.to_string()))?
-        }
-        None => Payload {
-            remove: None,
-            set: None,
-        },
-    };
+    let payload = parse(ctx, session, payload, payload_ast)?;
     if let Some(set) = payload.set {
-        let mut map = ctx.add_response_header.write().expect("lock");
-        for header in set {
-            let _ = map.insert(header.name, header.value);
+        for header in set.into_iter().filter(|h| holds(&h.when)) {
+            ctx.response_header_ops
+                .push((header.name, header.value, header.mode));
         }
     }
     if let Some(remove) = payload.remove {
-        let mut vec = ctx.remove_response_header.write().expect("lock");
-        for header in remove {
-            vec.push(header);
-        }
+        ctx.remove_response_header.extend(remove);
     }
     Ok(())
 }
@@ -0,0 +1,432 @@
+//! Inline Lua scripts
+//!
+//! Scripts come from the middleware payload and run on a sandboxed Lua state
+//! kept per worker thread, where each script is compiled once. Every run gets
+//! a fresh global environment, so nothing a script stores outlives the run,
+//! and the libraries it sees are read-only. A script sees a snapshot of the
+//! request in `nylon.req` and acts through the `nylon.*` functions; the
+//! actions are applied once it returns.
+
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value as LuaValue, VmState,
+};
+use nylon_error::NylonError;
+use nylon_types::context::NylonContext;
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+/// Memory a thread's Lua state may use
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Compiled scripts a thread keeps; the cache starts over when it is full
+const MAX_SCRIPTS: usize = 256;
+
+/// Registry name of the function building the environment of a run
+const ENV_FACTORY: &str = "nylon_env";
+
+/// Takes the `nylon.*` functions and returns a function that builds the
+/// environment of one run from `nylon.req` and `nylon.res`
+const SANDBOX: &str = r#"
+local functions = ...
+local setmetatable, error = setmetatable, error
+local function read_only(t)
+  return setmetatable({}, {
+    __index = t,
+    __newindex = function() error("read-only table", 2) end,
+    __metatable = false,
+  })
+end
+local base = {
+  assert = assert, error = error, ipairs = ipairs, next = next, pairs = pairs,
+  pcall = pcall, select = select, tonumber = tonumber, tostring = tostring,
+  type = type, xpcall = xpcall,
+  string = read_only(string), table = read_only(table),
+  math = read_only(math), utf8 = read_only(utf8),
+}
+local env_meta = { __index = base, __metatable = false }
+local nylon_meta = { __index = functions, __metatable = false }
+return function(req, res)
+  local nylon = setmetatable({ req = req, res = res }, nylon_meta)
+  return setmetatable({ nylon = nylon }, env_meta)
+end
+"#;
+
+fn default_timeout_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Payload {
+    /// Script run in the request filter
+    request: Option<String>,
+    /// Script run in the response filter
+    response: Option<String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+/// What a script asked for
+enum Action {
+    SetRequestHeader(String, Option<String>),
+    SetResponseHeader(String, Option<String>),
+    SetStatus(u16),
+    Respond(u16, String),
+}
+
+thread_local! {
+    static LUA: Result<Lua, mlua::Error> = new_state();
+    static SCRIPTS: RefCell<HashMap<u64, RegistryKey>> = RefCell::new(HashMap::new());
+    static ACTIONS: RefCell<Vec<Action>> = const { RefCell::new(Vec::new()) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+fn push(action: Action) {
+    ACTIONS.with(|actions| actions.borrow_mut().push(action));
+}
+
+/// A state without `io`, `os` or module loading, stopped at the deadline
+///
+/// Scripts only reach the globals listed in `SANDBOX`.
+fn new_state() -> Result<Lua, mlua::Error> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(1000),
+        |_, _| match DEADLINE.get() {
+            Some(deadline) if Instant::now() > deadline => {
+                Err(mlua::Error::runtime("script timed out"))
+            }
+            _ => Ok(VmState::Continue),
+        },
+    );
+
+    let nylon = lua.create_table()?;
+    nylon.set(
+        "set_request_header",
+        lua.create_function(|_, (name, value): (String, Option<String>)| {
+            push(Action::SetRequestHeader(name, value));
+            Ok(())
+        })?,
+    )?;
+    nylon.set(
+        "set_response_header",
+        lua.create_function(|_, (name, value): (String, Option<String>)| {
+            push(Action::SetResponseHeader(name, value));
+            Ok(())
+        })?,
+    )?;
+    nylon.set(
+        "set_status",
+        lua.create_function(|_, status: u16| {
+            push(Action::SetStatus(status));
+            Ok(())
+        })?,
+    )?;
+    nylon.set(
+        "respond",
+        lua.create_function(|_, (status, body): (u16, Option<String>)| {
+            push(Action::Respond(status, body.unwrap_or_default()));
+            Ok(())
+        })?,
+    )?;
+    nylon.set(
+        "log",
+        lua.create_function(|_, message: String| {
+            tracing::info!(target: "nylon::lua", "{}", message);
+            Ok(())
+        })?,
+    )?;
+    let factory: Function = lua.load(SANDBOX).set_name("sandbox").call(nylon)?;
+    lua.set_named_registry_value(ENV_FACTORY, factory)?;
+    Ok(lua)
+}
+
+/// Compiled function of `script`, cached per thread
+///
+/// The chunk takes its global environment as the first argument.
+fn compile(lua: &Lua, script: &str) -> Result<Function, mlua::Error> {
+    let mut hasher = DefaultHasher::new();
+    script.hash(&mut hasher);
+    let key = hasher.finish();
+    SCRIPTS.with(|scripts| {
+        if let Some(function) = scripts.borrow().get(&key) {
+            return lua.registry_value::<Function>(function);
+        }
+        // Same line, so error positions still match the script
+        let function = lua
+            .load(format!("local _ENV = ...; {}", script))
+            .set_name("middleware")
+            .into_function()?;
+        let mut scripts = scripts.borrow_mut();
+        if scripts.len() >= MAX_SCRIPTS {
+            scripts.clear();
+            lua.expire_registry_values();
+        }
+        scripts.insert(key, lua.create_registry_value(function.clone())?);
+        Ok(function)
+    })
+}
+
+/// Snapshot of the request, exposed as `nylon.req`
+fn request_table(lua: &Lua, ctx: &NylonContext, session: &Session) -> Result<Table, mlua::Error> {
+    let header = session.req_header();
+    let req = lua.create_table()?;
+    req.set("method", header.method.as_str())?;
+    req.set("path", header.uri.path())?;
+    req.set("query", header.uri.query().unwrap_or_default())?;
+    req.set("host", ctx.host.as_str())?;
+    req.set("client_ip", ctx.client_ip.as_str())?;
+    let headers = lua.create_table()?;
+    for (name, value) in header.headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        let value = match headers.get::<Option<String>>(name.as_str())? {
+            Some(previous) => format!("{}, {}", previous, value),
+            None => value.to_string(),
+        };
+        headers.set(name.as_str(), value)?;
+    }
+    req.set("headers", headers)?;
+    let params = lua.create_table()?;
+    if let Some(map) = &ctx.params {
+        for (name, value) in map {
+            params.set(name.as_str(), value.as_str())?;
+        }
+    }
+    req.set("params", params)?;
+    Ok(req)
+}
+
+fn run_script(
+    script: &str,
+    timeout: Duration,
+    ctx: &NylonContext,
+    session: &Session,
+) -> Result<Vec<Action>, NylonError> {
+    run(
+        script,
+        timeout,
+        ctx.set_response_status.load(Ordering::Relaxed),
+        |lua| request_table(lua, ctx, session),
+    )
+}
+
+/// Run `script` in a fresh environment and collect what it asked for
+fn run(
+    script: &str,
+    timeout: Duration,
+    status: u16,
+    req: impl FnOnce(&Lua) -> Result<Table, mlua::Error>,
+) -> Result<Vec<Action>, NylonError> {
+    let lua_error = |e: mlua::Error| NylonError::ConfigError(format!("Lua: {}", e));
+    LUA.with(|lua| {
+        let lua = lua.as_ref().map_err(|e| lua_error(e.clone()))?;
+        let function = compile(lua, script).map_err(lua_error)?;
+        let res = lua.create_table().map_err(lua_error)?;
+        res.set("status", status).map_err(lua_error)?;
+        let env: Table = lua
+            .named_registry_value::<Function>(ENV_FACTORY)
+            .and_then(|factory| factory.call((req(lua)?, res)))
+            .map_err(lua_error)?;
+
+        ACTIONS.with(|actions| actions.borrow_mut().clear());
+        DEADLINE.set(Some(Instant::now() + timeout));
+        let result = function.call::<LuaValue>(env);
+        DEADLINE.set(None);
+        let actions = ACTIONS.with(|actions| std::mem::take(&mut *actions.borrow_mut()));
+        if result.is_err() {
+            // A script stopped at the memory limit leaves its garbage behind
+            let _ = lua.gc_collect();
+        }
+        result.map_err(lua_error)?;
+        Ok(actions)
+    })
+}
+
+fn payload(payload: &Option<Value>) -> Result<Payload, NylonError> {
+    let Some(payload) = payload else {
+        return Err(NylonError::ConfigError(
+            "Lua requires a payload with a request or response script".to_string(),
+        ));
+    };
+    serde_json::from_value::<Payload>(payload.clone())
+        .map_err(|e| NylonError::ConfigError(e.to_string()))
+}
+
+fn apply_response(ctx: &mut NylonContext, action: Action) {
+    match action {
+        Action::SetResponseHeader(name, Some(value)) if !value.is_empty() => {
+            ctx.add_response_header.insert(name, value);
+        }
+        Action::SetResponseHeader(name, _) => {
+            ctx.remove_response_header.push(name);
+        }
+        Action::SetStatus(status) => ctx.set_response_status.store(status, Ordering::Relaxed),
+        _ => {}
+    }
+}
+
+/// Run the request script; returns true when it answered the request itself
+pub fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload_value: &Option<Value>,
+) -> Result<bool, NylonError> {
+    let payload = payload(payload_value)?;
+    let Some(script) = &payload.request else {
+        return Ok(false);
+    };
+    let actions = run_script(
+        script,
+        Duration::from_millis(payload.timeout_ms),
+        ctx,
+        session,
+    )?;
+
+    let mut responded = false;
+    for action in actions {
+        match action {
+            Action::SetRequestHeader(name, value) => {
+                let name = name.to_ascii_lowercase();
+                let headers = session.req_header_mut();
+                let _ = headers.remove_header(&name);
+                if let Some(value) = value.filter(|v| !v.is_empty()) {
+                    headers
+                        .insert_header(name, value)
+                        .map_err(|e| NylonError::ConfigError(format!("Lua: {}", e)))?;
+                }
+            }
+            Action::Respond(status, body) => {
+                ctx.set_response_status.store(status, Ordering::Relaxed);
+                ctx.set_response_body = body.into_bytes();
+                responded = true;
+            }
+            action => apply_response(ctx, action),
+        }
+    }
+    Ok(responded)
+}
+
+/// Run the response script
+pub fn response(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload_value: &Option<Value>,
+) -> Result<(), NylonError> {
+    let payload = payload(payload_value)?;
+    let Some(script) = &payload.response else {
+        return Ok(());
+    };
+    let actions = run_script(
+        script,
+        Duration::from_millis(payload.timeout_ms),
+        ctx,
+        session,
+    )?;
+    for action in actions {
+        apply_response(ctx, action);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(script: &str) -> Result<Vec<Action>, NylonError> {
+        run(script, Duration::from_secs(5), 200, |lua| {
+            lua.create_table()
+        })
+    }
+
+    fn status(actions: &[Action]) -> Option<u16> {
+        actions.iter().find_map(|action| match action {
+            Action::SetStatus(status) => Some(*status),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_globals_do_not_outlive_a_run() {
+        let script = "counter = (counter or 0) + 1; nylon.set_status(200 + counter)";
+        assert_eq!(status(&run_test(script).unwrap()), Some(201));
+        assert_eq!(status(&run_test(script).unwrap()), Some(201));
+    }
+
+    #[test]
+    fn test_libraries_are_read_only() {
+        assert!(run_test("string.upper = nil").is_err());
+        run_test("nylon.set_status = function() end").unwrap();
+        assert_eq!(
+            status(&run_test("nylon.set_status(418)").unwrap()),
+            Some(418)
+        );
+        let actions =
+            run_test("nylon.set_status(string.upper('a') == 'A' and 204 or 500)").unwrap();
+        assert_eq!(status(&actions), Some(204));
+    }
+
+    #[test]
+    fn test_unsafe_globals_are_hidden() {
+        for name in [
+            "os",
+            "io",
+            "require",
+            "load",
+            "dofile",
+            "getmetatable",
+            "rawset",
+            "_G",
+        ] {
+            let script = format!("if {} ~= nil then nylon.set_status(500) end", name);
+            assert_eq!(status(&run_test(&script).unwrap()), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let result = run("while true do end", Duration::from_millis(10), 200, |lua| {
+            lua.create_table()
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        assert!(run_test("local t = {} for i = 1, 1e8 do t[i] = i end").is_err());
+        assert_eq!(
+            status(&run_test("nylon.set_status(201)").unwrap()),
+            Some(201)
+        );
+    }
+
+    #[test]
+    fn test_script_cache_is_bounded() {
+        for i in 0..MAX_SCRIPTS + 10 {
+            run_test(&format!("local _ = {}", i)).unwrap();
+        }
+        assert!(SCRIPTS.with(|scripts| scripts.borrow().len()) <= MAX_SCRIPTS);
+    }
+
+    #[test]
+    fn test_respond_and_headers() {
+        let actions = run_test(
+            "nylon.set_request_header('x-a', '1'); nylon.respond(nylon.res.status + 1, 'body')",
+        )
+        .unwrap();
+        assert!(
+            matches!(&actions[0], Action::SetRequestHeader(name, Some(value)) if name == "x-a" && value == "1")
+        );
+        assert!(matches!(&actions[1], Action::Respond(201, body) if body == "body"));
+    }
+}
@@ -1 +1,10 @@
+pub mod body_transform;
+pub mod bot_guard;
 pub mod header_modifier;
+pub mod lua;
+pub mod oidc;
+pub mod query_token;
+pub mod replay_protection;
+pub mod request_decompression;
+pub mod waf;
+pub mod watermark;
@@ -0,0 +1,1639 @@
+//! OAuth2 / OpenID Connect authentication
+//!
+//! In `code` mode, browsers without a session are sent to the identity
+//! provider with PKCE. The callback exchanges the code, checks the ID token
+//! and sets a session cookie encrypted with AES-256-GCM, so any node with the
+//! same `cookie_secret` accepts it. Expired access tokens are refreshed when a
+//! refresh token was issued, once for all requests carrying the same one. A
+//! session too large for one cookie is split over several. In
+//! `introspection` mode, bearer tokens are checked at the introspection
+//! endpoint (RFC 7662) and the answers cached.
+//!
+//! The ID token comes straight from the token endpoint over TLS, so its
+//! issuer is trusted from the connection and its signature is not checked
+//! (OpenID Connect Core 3.1.3.7).
+
+use super::bot_guard::cookie;
+use aes_gcm::{
+    Aes256Gcm, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore},
+};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use dashmap::DashMap;
+use lru::LruCache;
+use nylon_error::NylonError;
+use nylon_types::{
+    context::{HeaderMode, NylonContext},
+    template::{Expr, apply_payload_ast, url_decode, url_encode},
+};
+use once_cell::sync::Lazy;
+use pingora::{http::RequestHeader, proxy::Session};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Timeout of each call to the identity provider
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a discovery document is used before it is fetched again
+const DISCOVERY_TTL_SECS: u64 = 3600;
+
+/// How long a failed discovery is answered from memory before it is tried again
+const DISCOVERY_RETRY_SECS: u64 = 30;
+
+/// How long a login may take from the redirect to the callback
+const LOGIN_TTL_SECS: u64 = 600;
+
+/// Access tokens this close to expiry are refreshed
+const REFRESH_LEEWAY_SECS: u64 = 30;
+
+/// Active introspection answers kept, least recently used dropped first
+const MAX_INTROSPECTED: usize = 100_000;
+
+/// Inactive introspection answers kept, apart so unknown tokens cannot push out active ones
+const MAX_REJECTED: usize = 10_000;
+
+/// How long the result of a refresh is shared with requests carrying the same refresh token
+const REFRESH_REUSE_SECS: u64 = 60;
+
+/// Refreshes remembered
+const MAX_REFRESHES: usize = 10_000;
+
+/// Longest cookie, name and attributes included, every browser keeps
+const MAX_COOKIE_BYTES: usize = 4096;
+
+/// Cookies a session may be split over
+const MAX_COOKIE_CHUNKS: usize = 4;
+
+/// Longest path a login returns to; longer ones return to `/`
+const MAX_RETURN_TO: usize = 2048;
+
+static AGENT: Lazy<ureq::Agent> = Lazy::new(|| {
+    ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .http_status_as_error(false)
+        .build()
+        .into()
+});
+
+/// A result one request fetches while the others wait and share it, with when it was fetched
+type Shared<T> = Arc<tokio::sync::Mutex<Option<(u64, Result<T, String>)>>>;
+
+/// Discovery documents by issuer
+static DISCOVERY: Lazy<DashMap<String, Shared<Arc<Provider>>>> = Lazy::new(DashMap::new);
+
+/// Claims of active tokens by token hash, with when they expire
+static INTROSPECTED: Lazy<Mutex<LruCache<String, (u64, Arc<Map<String, Value>>)>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(MAX_INTROSPECTED).unwrap())));
+
+/// Hashes of inactive tokens, with when they expire
+static REJECTED: Lazy<Mutex<LruCache<String, u64>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(MAX_REJECTED).unwrap())));
+
+/// Token refreshes by refresh token hash
+static REFRESHES: Lazy<Mutex<LruCache<String, Shared<Arc<TokenResponse>>>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(MAX_REFRESHES).unwrap())));
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "email".to_string(),
+        "profile".to_string(),
+    ]
+}
+
+fn default_callback_path() -> String {
+    "/oauth2/callback".to_string()
+}
+
+fn default_logout_path() -> String {
+    "/oauth2/logout".to_string()
+}
+
+fn default_cookie_name() -> String {
+    "nylon_oidc".to_string()
+}
+
+fn default_session_ttl() -> u64 {
+    86400
+}
+
+fn default_claim_headers() -> HashMap<String, String> {
+    HashMap::from([
+        ("x-auth-subject".to_string(), "sub".to_string()),
+        ("x-auth-email".to_string(), "email".to_string()),
+    ])
+}
+
+fn default_introspection_cache() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    #[default]
+    Code,
+    Introspection,
+}
+
+/// How the client authenticates to the token and introspection endpoints
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ClientAuth {
+    #[default]
+    Basic,
+    Post,
+}
+
+/// Payload structure for OIDC authentication
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Payload {
+    #[serde(default)]
+    mode: Mode,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    #[serde(default)]
+    client_auth: ClientAuth,
+    #[serde(default = "default_scopes")]
+    scopes: Vec<String>,
+    /// Absolute callback URL; built from the request host and `callback_path` when unset
+    redirect_uri: Option<String>,
+    #[serde(default = "default_callback_path")]
+    callback_path: String,
+    #[serde(default = "default_logout_path")]
+    logout_path: String,
+    /// Where the provider sends the browser after logout
+    post_logout_redirect_uri: Option<String>,
+    /// Encrypts session cookies; required for `code`
+    cookie_secret: Option<String>,
+    #[serde(default = "default_cookie_name")]
+    cookie_name: String,
+    #[serde(default = "default_session_ttl")]
+    session_ttl_seconds: u64,
+    /// Request header to the claim it carries
+    #[serde(default = "default_claim_headers")]
+    claim_headers: HashMap<String, String>,
+    /// Send the access token upstream as `Authorization: Bearer`
+    #[serde(default)]
+    forward_access_token: bool,
+    #[serde(default = "default_introspection_cache")]
+    introspection_cache_seconds: u64,
+    /// Endpoints, overriding the discovery document
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    introspection_endpoint: Option<String>,
+    end_session_endpoint: Option<String>,
+}
+
+impl Payload {
+    /// Mistakes that would fail every request
+    fn check(&self) -> Result<(), String> {
+        // Templated values are only known per request
+        if !self.issuer.contains("${")
+            && !(self.issuer.starts_with("https://") || self.issuer.starts_with("http://"))
+        {
+            return Err(format!("issuer {:?} must be an http(s) URL", self.issuer));
+        }
+        if self.client_id.is_empty() {
+            return Err("client_id must not be empty".to_string());
+        }
+        if self.mode == Mode::Code && self.cookie_secret.as_deref().is_none_or(str::is_empty) {
+            return Err("code mode requires a cookie_secret".to_string());
+        }
+        for (name, path) in [
+            ("callback_path", &self.callback_path),
+            ("logout_path", &self.logout_path),
+        ] {
+            if !path.starts_with('/') {
+                return Err(format!("{} {:?} must start with /", name, path));
+            }
+        }
+        if self.callback_path == self.logout_path {
+            return Err("callback_path and logout_path must differ".to_string());
+        }
+        if self.session_ttl_seconds == 0 {
+            return Err("session_ttl_seconds must be more than 0".to_string());
+        }
+        // `.` is left out for the names of split cookies
+        if self.cookie_name.is_empty()
+            || !self
+                .cookie_name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            return Err(format!(
+                "cookie_name {:?} may only use letters, digits, _ and -",
+                self.cookie_name
+            ));
+        }
+        for header in self.claim_headers.keys() {
+            http::HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("claim header {:?} is not a valid header name", header))?;
+        }
+        Ok(())
+    }
+}
+
+/// Check an `Oidc` payload when the config loads
+pub(crate) fn check_payload(payload: Option<&Value>) -> Result<(), String> {
+    let payload = payload.ok_or("a payload with an issuer and a client_id is required")?;
+    serde_json::from_value::<Payload>(payload.clone())
+        .map_err(|e| e.to_string())?
+        .check()
+}
+
+/// Endpoints from `/.well-known/openid-configuration`
+#[derive(Debug, Deserialize, Clone, Default)]
+struct Provider {
+    issuer: Option<String>,
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    introspection_endpoint: Option<String>,
+    end_session_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+/// What the cookie holds, encrypted
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "t", rename_all = "snake_case")]
+enum CookieState {
+    /// A login on its way through the provider
+    Login {
+        state: String,
+        nonce: String,
+        verifier: String,
+        return_to: String,
+        expires: u64,
+    },
+    Session {
+        claims: Map<String, Value>,
+        access_token: String,
+        refresh_token: Option<String>,
+        /// When the access token expires
+        token_expires: u64,
+        /// When the session ends, refreshed or not
+        expires: u64,
+    },
+}
+
+/// Authenticate the request
+///
+/// Returns `true` when the request was answered (a redirect or a rejection).
+pub async fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<bool, NylonError> {
+    let Some(payload) = payload.as_ref() else {
+        return Err(NylonError::ConfigError(
+            "Oidc requires a payload with an issuer and a client_id".to_string(),
+        ));
+    };
+    let mut payload = payload.clone();
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+
+    // Identity headers are only trusted when we set them ourselves
+    let headers = session.req_header_mut();
+    for name in payload.claim_headers.keys() {
+        let _ = headers.remove_header(name.as_str());
+    }
+
+    let provider = match provider(&payload).await {
+        Ok(provider) => provider,
+        Err(e) => {
+            warn!("Oidc: discovery for {} failed: {}", payload.issuer, e);
+            respond(ctx, 502, "BAD_GATEWAY", "Identity provider unavailable");
+            return Ok(true);
+        }
+    };
+    match payload.mode {
+        Mode::Code => code_flow(ctx, session, &payload, &provider).await,
+        Mode::Introspection => introspect(ctx, session, &payload, &provider).await,
+    }
+}
+
+async fn code_flow(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Payload,
+    provider: &Provider,
+) -> Result<bool, NylonError> {
+    let Some(secret) = payload.cookie_secret.as_deref() else {
+        return Err(NylonError::ConfigError(
+            "Oidc code mode requires a cookie_secret".to_string(),
+        ));
+    };
+    let req = session.req_header();
+    let path = req.uri.path().to_string();
+    let query = query(req.uri.query());
+    let redirect_uri = redirect_uri(ctx, session, payload);
+    let held = held_chunks(req, &payload.cookie_name);
+    let state = session_cookie(req, &payload.cookie_name).and_then(|value| open(secret, &value));
+
+    if path == payload.logout_path {
+        return Ok(logout(ctx, payload, provider, held));
+    }
+    if path == payload.callback_path {
+        let cookie = Cookie { secret, held };
+        return callback(ctx, payload, provider, cookie, state, &query, &redirect_uri).await;
+    }
+
+    let state = match state {
+        Some(CookieState::Session {
+            claims,
+            access_token,
+            refresh_token,
+            token_expires,
+            expires,
+        }) if expires > now() => {
+            if token_expires > now() + REFRESH_LEEWAY_SECS {
+                Some((claims, access_token))
+            } else if let Some(refresh_token) = refresh_token {
+                match refresh(payload, provider, &refresh_token).await {
+                    Ok(tokens) => {
+                        let claims = match &tokens.id_token {
+                            Some(id_token) => id_token_claims(payload, id_token, None)
+                                .map(|c| keep_claims(payload, c))
+                                .unwrap_or(claims),
+                            None => claims,
+                        };
+                        let renewed = CookieState::Session {
+                            claims: claims.clone(),
+                            access_token: tokens.access_token.clone(),
+                            refresh_token: tokens.refresh_token.clone().or(Some(refresh_token)),
+                            token_expires: now() + tokens.expires_in.unwrap_or(300),
+                            expires,
+                        };
+                        match set_cookies(ctx, payload, &seal(secret, &renewed), expires, held) {
+                            Ok(cookies) => add_cookies(ctx, cookies),
+                            // The old cookie still refreshes on the next request
+                            Err(e) => warn!("Oidc: renewed session not saved: {}", e),
+                        }
+                        Some((claims, tokens.access_token.clone()))
+                    }
+                    Err(e) => {
+                        tracing::debug!("Oidc: refresh failed: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    let Some((claims, access_token)) = state else {
+        let cookie = Cookie { secret, held };
+        return Ok(login(
+            ctx,
+            session,
+            payload,
+            provider,
+            cookie,
+            &redirect_uri,
+        ));
+    };
+    let headers = session.req_header_mut();
+    strip_cookie(headers, &payload.cookie_name);
+    set_claim_headers(headers, payload, &claims);
+    if payload.forward_access_token {
+        let _ = headers.insert_header(
+            http::header::AUTHORIZATION,
+            format!("Bearer {}", access_token),
+        );
+    }
+    Ok(false)
+}
+
+/// What a new session cookie is sealed with, and how many cookies the browser holds now
+struct Cookie<'a> {
+    secret: &'a str,
+    held: usize,
+}
+
+/// Send the browser to the provider, remembering where it was going
+fn login(
+    ctx: &mut NylonContext,
+    session: &Session,
+    payload: &Payload,
+    provider: &Provider,
+    cookie: Cookie<'_>,
+    redirect_uri: &str,
+) -> bool {
+    let req = session.req_header();
+    if !matches!(req.method, http::Method::GET | http::Method::HEAD) {
+        respond(ctx, 401, "UNAUTHORIZED", "Login required");
+        return true;
+    }
+    let Some(endpoint) = &provider.authorization_endpoint else {
+        respond(
+            ctx,
+            502,
+            "BAD_GATEWAY",
+            "Identity provider has no authorization endpoint",
+        );
+        return true;
+    };
+    let return_to = req
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .filter(|p| p.len() <= MAX_RETURN_TO)
+        .unwrap_or("/")
+        .to_string();
+    let (state, nonce, verifier) = (random(), random(), random());
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let location = format!(
+        "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        endpoint,
+        if endpoint.contains('?') { '&' } else { '?' },
+        url_encode(&payload.client_id),
+        url_encode(redirect_uri),
+        url_encode(&payload.scopes.join(" ")),
+        state,
+        nonce,
+        challenge
+    );
+    let expires = now() + LOGIN_TTL_SECS;
+    let login = CookieState::Login {
+        state,
+        nonce,
+        verifier,
+        return_to,
+        expires,
+    };
+    // A login state is a few hundred bytes and always fits
+    let cookies = set_cookies(
+        ctx,
+        payload,
+        &seal(cookie.secret, &login),
+        expires,
+        cookie.held,
+    )
+    .unwrap_or_default();
+    redirect(ctx, location, cookies);
+    true
+}
+
+/// Exchange the code from the provider for a session
+async fn callback(
+    ctx: &mut NylonContext,
+    payload: &Payload,
+    provider: &Provider,
+    cookie: Cookie<'_>,
+    state: Option<CookieState>,
+    query: &HashMap<String, String>,
+    redirect_uri: &str,
+) -> Result<bool, NylonError> {
+    if let Some(error) = query.get("error") {
+        let message = query.get("error_description").unwrap_or(error);
+        tracing::debug!("Oidc: provider answered with {}", message);
+        respond(ctx, 401, "UNAUTHORIZED", "Login failed");
+        return Ok(true);
+    }
+    let (nonce, verifier, return_to) = match state {
+        Some(CookieState::Login {
+            state,
+            nonce,
+            verifier,
+            return_to,
+            expires,
+        }) if expires > now() && query.get("state") == Some(&state) => (nonce, verifier, return_to),
+        // Another tab finished the login first
+        Some(CookieState::Session { expires, .. }) if expires > now() => {
+            redirect(ctx, "/".to_string(), vec![]);
+            return Ok(true);
+        }
+        _ => {
+            respond(ctx, 400, "BAD_REQUEST", "Login state is missing or expired");
+            return Ok(true);
+        }
+    };
+    let Some(code) = query.get("code") else {
+        respond(ctx, 400, "BAD_REQUEST", "Missing authorization code");
+        return Ok(true);
+    };
+
+    let form = vec![
+        ("grant_type".to_string(), "authorization_code".to_string()),
+        ("code".to_string(), code.clone()),
+        ("redirect_uri".to_string(), redirect_uri.to_string()),
+        ("code_verifier".to_string(), verifier),
+    ];
+    let tokens = match token_request(payload, provider, form).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            warn!("Oidc: code exchange failed: {}", e);
+            respond(ctx, 502, "BAD_GATEWAY", "Code exchange failed");
+            return Ok(true);
+        }
+    };
+    let claims = match tokens
+        .id_token
+        .as_deref()
+        .ok_or_else(|| "no id_token".to_string())
+        .and_then(|id_token| id_token_claims(payload, id_token, Some(nonce.as_str())))
+    {
+        Ok(claims) => keep_claims(payload, claims),
+        Err(e) => {
+            warn!("Oidc: rejected ID token: {}", e);
+            respond(ctx, 401, "UNAUTHORIZED", "Invalid ID token");
+            return Ok(true);
+        }
+    };
+
+    let expires = now() + payload.session_ttl_seconds;
+    let session = CookieState::Session {
+        claims,
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        token_expires: now() + tokens.expires_in.unwrap_or(300),
+        expires,
+    };
+    let sealed = seal(cookie.secret, &session);
+    let cookies = match set_cookies(ctx, payload, &sealed, expires, cookie.held) {
+        Ok(cookies) => cookies,
+        Err(e) => {
+            // The browser would drop the cookie and come straight back to the provider
+            warn!("Oidc: session not saved: {}", e);
+            respond(
+                ctx,
+                500,
+                "INTERNAL_SERVER_ERROR",
+                "Session is too large for cookies",
+            );
+            return Ok(true);
+        }
+    };
+    redirect(ctx, local_path(return_to), cookies);
+    Ok(true)
+}
+
+/// `return_to` when it is a path on this host, so the callback cannot be used as an open redirect
+fn local_path(return_to: String) -> String {
+    let local = return_to.starts_with('/')
+        && !return_to.starts_with("//")
+        // Browsers read `/\` as `//`, and skip tabs and newlines
+        && !return_to.contains('\\')
+        && !return_to.chars().any(|c| c.is_ascii_control());
+    if local { return_to } else { "/".to_string() }
+}
+
+fn logout(ctx: &mut NylonContext, payload: &Payload, provider: &Provider, held: usize) -> bool {
+    let cookies = (0..held.max(1))
+        .map(|i| clear_cookie(&chunk_name(&payload.cookie_name, i)))
+        .collect();
+    let after = payload.post_logout_redirect_uri.as_deref();
+    let location = match &provider.end_session_endpoint {
+        Some(endpoint) => {
+            let mut location = format!(
+                "{}{}client_id={}",
+                endpoint,
+                if endpoint.contains('?') { '&' } else { '?' },
+                url_encode(&payload.client_id)
+            );
+            if let Some(after) = after {
+                location.push_str("&post_logout_redirect_uri=");
+                location.push_str(&url_encode(after));
+            }
+            location
+        }
+        None => after.unwrap_or("/").to_string(),
+    };
+    redirect(ctx, location, cookies);
+    true
+}
+
+/// Check the bearer token at the introspection endpoint
+async fn introspect(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Payload,
+    provider: &Provider,
+) -> Result<bool, NylonError> {
+    let token = session
+        .req_header()
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.strip_prefix("Bearer ")
+                .or_else(|| v.strip_prefix("bearer "))
+        })
+        .map(|v| v.trim().to_string());
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        unauthorized(ctx, "Bearer", "Missing bearer token");
+        return Ok(true);
+    };
+    let Some(endpoint) = provider.introspection_endpoint.clone() else {
+        return Err(NylonError::ConfigError(
+            "Oidc introspection requires an introspection_endpoint".to_string(),
+        ));
+    };
+
+    let key = format!(
+        "{:x}",
+        Sha256::digest(format!("{}|{}", payload.issuer, token).as_bytes())
+    );
+    let now = now();
+    let claims = match cached_answer(&key, now) {
+        Some(claims) => claims,
+        None => {
+            let form = vec![
+                ("token".to_string(), token),
+                ("token_type_hint".to_string(), "access_token".to_string()),
+            ];
+            let answer = match post_form(payload, endpoint, form).await {
+                Ok(answer) => answer,
+                Err(e) => {
+                    warn!("Oidc: introspection failed: {}", e);
+                    respond(ctx, 502, "BAD_GATEWAY", "Token introspection failed");
+                    return Ok(true);
+                }
+            };
+            let claims = match answer {
+                Value::Object(claims)
+                    if claims.get("active").and_then(Value::as_bool) == Some(true) =>
+                {
+                    Some(Arc::new(claims))
+                }
+                _ => None,
+            };
+            cache_answer(
+                key,
+                claims.clone(),
+                now + payload.introspection_cache_seconds,
+            );
+            claims
+        }
+    };
+    let Some(claims) = claims else {
+        unauthorized(
+            ctx,
+            "Bearer error=\"invalid_token\"",
+            "Invalid or expired token",
+        );
+        return Ok(true);
+    };
+    set_claim_headers(session.req_header_mut(), payload, &claims);
+    Ok(false)
+}
+
+/// A cached introspection answer; `Some(None)` for an inactive token
+fn cached_answer(key: &str, now: u64) -> Option<Option<Arc<Map<String, Value>>>> {
+    let active = INTROSPECTED
+        .lock()
+        .ok()
+        .and_then(|mut active| active.get(key).filter(|e| e.0 > now).map(|e| e.1.clone()));
+    if active.is_some() {
+        return Some(active);
+    }
+    let rejected = REJECTED
+        .lock()
+        .ok()
+        .is_some_and(|mut rejected| rejected.get(key).is_some_and(|expires| *expires > now));
+    rejected.then_some(None)
+}
+
+/// Remember an introspection answer until `expires`, never past the token's own expiry
+fn cache_answer(key: String, claims: Option<Arc<Map<String, Value>>>, expires: u64) {
+    match claims {
+        Some(claims) => {
+            let exp = claims
+                .get("exp")
+                .and_then(Value::as_u64)
+                .unwrap_or(u64::MAX);
+            if let Ok(mut active) = INTROSPECTED.lock() {
+                active.put(key, (expires.min(exp), claims));
+            }
+        }
+        None => {
+            if let Ok(mut rejected) = REJECTED.lock() {
+                rejected.put(key, expires);
+            }
+        }
+    }
+}
+
+/// Endpoints of the provider, discovered once an hour unless all are configured
+///
+/// One request fetches the document while the others for the same issuer
+/// wait for it; a failure is answered from memory for a while, so a provider
+/// that is down does not tie up a blocking thread per request.
+async fn provider(payload: &Payload) -> Result<Provider, String> {
+    let configured = Provider {
+        issuer: None,
+        authorization_endpoint: payload.authorization_endpoint.clone(),
+        token_endpoint: payload.token_endpoint.clone(),
+        introspection_endpoint: payload.introspection_endpoint.clone(),
+        end_session_endpoint: payload.end_session_endpoint.clone(),
+    };
+    let complete = match payload.mode {
+        Mode::Code => {
+            configured.authorization_endpoint.is_some() && configured.token_endpoint.is_some()
+        }
+        Mode::Introspection => configured.introspection_endpoint.is_some(),
+    };
+    if complete {
+        return Ok(configured);
+    }
+
+    let issuer = payload.issuer.trim_end_matches('/').to_string();
+    let shared = DISCOVERY.entry(issuer.clone()).or_default().clone();
+    let discovered = {
+        let mut entry = shared.lock().await;
+        match &*entry {
+            Some((at, Ok(discovered))) if at + DISCOVERY_TTL_SECS > now() => discovered.clone(),
+            Some((at, Err(e))) if at + DISCOVERY_RETRY_SECS > now() => return Err(e.clone()),
+            _ => {
+                let result = discover(issuer).await.map(Arc::new);
+                *entry = Some((now(), result.clone()));
+                result?
+            }
+        }
+    };
+    Ok(Provider {
+        issuer: discovered.issuer.clone(),
+        authorization_endpoint: configured
+            .authorization_endpoint
+            .or_else(|| discovered.authorization_endpoint.clone()),
+        token_endpoint: configured
+            .token_endpoint
+            .or_else(|| discovered.token_endpoint.clone()),
+        introspection_endpoint: configured
+            .introspection_endpoint
+            .or_else(|| discovered.introspection_endpoint.clone()),
+        end_session_endpoint: configured
+            .end_session_endpoint
+            .or_else(|| discovered.end_session_endpoint.clone()),
+    })
+}
+
+/// Fetch the discovery document of `issuer` (without a trailing `/`)
+async fn discover(issuer: String) -> Result<Provider, String> {
+    let url = format!("{}/.well-known/openid-configuration", issuer);
+    let document = blocking(move || {
+        let mut response = AGENT
+            .get(url.as_str())
+            .header("Accept", "application/json")
+            .call()
+            .map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| e.to_string())?;
+        if status != 200 {
+            return Err(format!("{} answered HTTP {}", url, status));
+        }
+        serde_json::from_str::<Provider>(&body).map_err(|e| e.to_string())
+    })
+    .await?;
+    // OpenID Connect Discovery 4.3: the document must be for this issuer
+    let found = document.issuer.as_deref().unwrap_or_default();
+    if found.trim_end_matches('/') != issuer {
+        return Err(format!(
+            "discovery document is for issuer {:?}, not {}",
+            found, issuer
+        ));
+    }
+    Ok(document)
+}
+
+/// Refresh the tokens once for all requests that carry the same refresh token
+///
+/// Providers that rotate refresh tokens accept each one only once, so the
+/// other requests wait for the first refresh and share its result.
+async fn refresh(
+    payload: &Payload,
+    provider: &Provider,
+    refresh_token: &str,
+) -> Result<Arc<TokenResponse>, String> {
+    let key = format!(
+        "{:x}",
+        Sha256::digest(format!("{}|{}", payload.issuer, refresh_token).as_bytes())
+    );
+    let shared = match REFRESHES.lock() {
+        Ok(mut refreshes) => refreshes.get_or_insert(key, Default::default).clone(),
+        Err(_) => Default::default(),
+    };
+    let mut entry = shared.lock().await;
+    if let Some((at, result)) = &*entry
+        && at + REFRESH_REUSE_SECS > now()
+    {
+        return result.clone();
+    }
+    let form = vec![
+        ("grant_type".to_string(), "refresh_token".to_string()),
+        ("refresh_token".to_string(), refresh_token.to_string()),
+    ];
+    let result = token_request(payload, provider, form).await.map(Arc::new);
+    *entry = Some((now(), result.clone()));
+    result
+}
+
+async fn token_request(
+    payload: &Payload,
+    provider: &Provider,
+    form: Vec<(String, String)>,
+) -> Result<TokenResponse, String> {
+    let endpoint = provider
+        .token_endpoint
+        .clone()
+        .ok_or_else(|| "provider has no token endpoint".to_string())?;
+    let answer = post_form(payload, endpoint, form).await?;
+    serde_json::from_value::<TokenResponse>(answer).map_err(|e| e.to_string())
+}
+
+/// POST a form with the client credentials and return the JSON answer
+async fn post_form(
+    payload: &Payload,
+    url: String,
+    mut form: Vec<(String, String)>,
+) -> Result<Value, String> {
+    let secret = payload.client_secret.clone().unwrap_or_default();
+    let basic = match payload.client_auth {
+        ClientAuth::Basic => Some(format!(
+            "Basic {}",
+            STANDARD.encode(format!(
+                "{}:{}",
+                url_encode(&payload.client_id),
+                url_encode(&secret)
+            ))
+        )),
+        ClientAuth::Post => {
+            form.push(("client_id".to_string(), payload.client_id.clone()));
+            if !secret.is_empty() {
+                form.push(("client_secret".to_string(), secret));
+            }
+            None
+        }
+    };
+    blocking(move || {
+        let mut request = AGENT
+            .post(url.as_str())
+            .header("Accept", "application/json");
+        if let Some(basic) = basic {
+            request = request.header("Authorization", basic);
+        }
+        let mut response = request.send_form(form).map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| e.to_string())?;
+        if status != 200 {
+            return Err(format!("{} answered HTTP {}: {}", url, status, body));
+        }
+        serde_json::from_str::<Value>(&body).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// Run a blocking call to the provider off the proxy threads
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Claims of the ID token, after checking issuer, audience, expiry and nonce
+fn id_token_claims(
+    payload: &Payload,
+    id_token: &str,
+    nonce: Option<&str>,
+) -> Result<Map<String, Value>, String> {
+    let claims = id_token
+        .split('.')
+        .nth(1)
+        .and_then(|part| URL_SAFE_NO_PAD.decode(part.trim_end_matches('=')).ok())
+        .and_then(|json| serde_json::from_slice::<Map<String, Value>>(&json).ok())
+        .ok_or_else(|| "malformed ID token".to_string())?;
+    let issuer = claims
+        .get("iss")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if issuer.trim_end_matches('/') != payload.issuer.trim_end_matches('/') {
+        return Err(format!("issuer {} does not match", issuer));
+    }
+    let audience = match claims.get("aud") {
+        Some(Value::String(aud)) => aud == &payload.client_id,
+        Some(Value::Array(aud)) => aud.iter().any(|a| a.as_str() == Some(&payload.client_id)),
+        _ => false,
+    };
+    if !audience {
+        return Err("audience does not include the client".to_string());
+    }
+    if claims.get("exp").and_then(Value::as_u64).unwrap_or(0) <= now() {
+        return Err("token expired".to_string());
+    }
+    if let Some(nonce) = nonce
+        && claims.get("nonce").and_then(Value::as_str) != Some(nonce)
+    {
+        return Err("nonce does not match".to_string());
+    }
+    Ok(claims)
+}
+
+/// Keep `sub` and the claims sent upstream, so the cookie stays small
+fn keep_claims(payload: &Payload, claims: Map<String, Value>) -> Map<String, Value> {
+    claims
+        .into_iter()
+        .filter(|(name, _)| name == "sub" || payload.claim_headers.values().any(|c| c == name))
+        .collect()
+}
+
+fn set_claim_headers(headers: &mut RequestHeader, payload: &Payload, claims: &Map<String, Value>) {
+    for (header, claim) in &payload.claim_headers {
+        let value = match claims.get(claim) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Number(n)) => n.to_string(),
+            Some(Value::Bool(b)) => b.to_string(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => continue,
+        };
+        let _ = headers.insert_header(header.clone(), value);
+    }
+}
+
+/// Keep the session cookies, and the tokens in them, from the upstream
+fn strip_cookie(headers: &mut RequestHeader, name: &str) {
+    let cookies = headers
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|c| {
+            !c.is_empty()
+                && !c
+                    .split_once('=')
+                    .is_some_and(|(n, _)| chunk_index(n, name).is_some())
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    let _ = headers.remove_header(&http::header::COOKIE);
+    if !cookies.is_empty() {
+        let _ = headers.insert_header(http::header::COOKIE, cookies);
+    }
+}
+
+/// The callback URL registered with the provider
+fn redirect_uri(ctx: &NylonContext, session: &Session, payload: &Payload) -> String {
+    if let Some(uri) = &payload.redirect_uri {
+        return uri.clone();
+    }
+    let req = session.req_header();
+    let host = req
+        .headers
+        .get(http::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri.authority().map(|a| a.as_str()))
+        .unwrap_or(ctx.host.as_str());
+    let scheme = if ctx.tls.load(Ordering::Relaxed) {
+        "https"
+    } else {
+        "http"
+    };
+    format!("{}://{}{}", scheme, host, payload.callback_path)
+}
+
+fn query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| {
+            (
+                url_decode(&name.replace('+', " ")),
+                url_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn random() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn cipher(secret: &str) -> Aes256Gcm {
+    Aes256Gcm::new_from_slice(&Sha256::digest(secret.as_bytes()))
+        .expect("SHA-256 gives an AES-256 key")
+}
+
+/// `base64url(nonce || AES-256-GCM(sha256(secret), json))`
+fn seal(secret: &str, state: &CookieState) -> String {
+    let json = serde_json::to_vec(state).unwrap_or_default();
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher(secret)
+            .encrypt(&nonce, json.as_slice())
+            .unwrap_or_default(),
+    );
+    URL_SAFE_NO_PAD.encode(sealed)
+}
+
+fn open(secret: &str, value: &str) -> Option<CookieState> {
+    let sealed = URL_SAFE_NO_PAD.decode(value).ok()?;
+    if sealed.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let json = cipher(secret)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// Name of chunk `index` of a session cookie; the first is the cookie itself
+fn chunk_name(name: &str, index: usize) -> String {
+    match index {
+        0 => name.to_string(),
+        i => format!("{}.{}", name, i),
+    }
+}
+
+/// Which chunk of session cookie `name` the cookie `cookie` is
+fn chunk_index(cookie: &str, name: &str) -> Option<usize> {
+    match cookie.strip_prefix(name)? {
+        "" => Some(0),
+        rest => rest.strip_prefix('.')?.parse().ok().filter(|i| *i > 0),
+    }
+}
+
+/// Session cookies the browser sent, chunks included
+fn held_chunks(req: &RequestHeader, name: &str) -> usize {
+    req.headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .filter_map(|(n, _)| chunk_index(n, name))
+        .filter(|i| *i < MAX_COOKIE_CHUNKS)
+        .map(|i| i + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+/// The sealed session, put back together when it was split
+///
+/// A split session starts with the number of chunks, e.g. `3.<part>`; the
+/// base64url alphabet has no `.`.
+fn session_cookie(req: &RequestHeader, name: &str) -> Option<String> {
+    let values = req
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>();
+    let find = |name: &str| values.iter().find_map(|v| cookie(v, name));
+    let first = find(name)?;
+    let Some((count, first)) = first.split_once('.') else {
+        return Some(first.to_string());
+    };
+    let count = count
+        .parse::<usize>()
+        .ok()
+        .filter(|c| (2..=MAX_COOKIE_CHUNKS).contains(c))?;
+    let mut value = first.to_string();
+    for i in 1..count {
+        value.push_str(find(&chunk_name(name, i))?);
+    }
+    Some(value)
+}
+
+/// `Set-Cookie` values for a sealed state, split over several cookies when
+/// one would pass the browsers' limit
+///
+/// Chunks left over from a larger session the browser `held` are cleared.
+fn set_cookies(
+    ctx: &NylonContext,
+    payload: &Payload,
+    value: &str,
+    expires: u64,
+    held: usize,
+) -> Result<Vec<String>, String> {
+    let name = &payload.cookie_name;
+    let attributes = format!(
+        "; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        expires.saturating_sub(now()),
+        if ctx.tls.load(Ordering::Relaxed) {
+            "; Secure"
+        } else {
+            ""
+        }
+    );
+    // The name, `=`, a `.N` suffix or `N.` prefix, and the attributes
+    let room = MAX_COOKIE_BYTES.saturating_sub(name.len() + 3 + attributes.len());
+    let parts = if value.len() <= room + 2 {
+        vec![value]
+    } else {
+        // base64url is ASCII, so any byte is a char boundary
+        value
+            .as_bytes()
+            .chunks(room.max(1))
+            .map(|part| std::str::from_utf8(part).unwrap_or_default())
+            .collect()
+    };
+    if parts.len() > MAX_COOKIE_CHUNKS {
+        return Err(format!(
+            "{} bytes do not fit in {} cookies",
+            value.len(),
+            MAX_COOKIE_CHUNKS
+        ));
+    }
+    let mut cookies = parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| match (i, parts.len()) {
+            (0, 1) => format!("{}={}{}", name, part, attributes),
+            (0, count) => format!("{}={}.{}{}", name, count, part, attributes),
+            (i, _) => format!("{}={}{}", chunk_name(name, i), part, attributes),
+        })
+        .collect::<Vec<_>>();
+    cookies.extend((parts.len()..held).map(|i| clear_cookie(&chunk_name(name, i))));
+    Ok(cookies)
+}
+
+fn clear_cookie(name: &str) -> String {
+    format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax", name)
+}
+
+/// Each cookie goes in a `Set-Cookie` header of its own
+fn add_cookies(ctx: &mut NylonContext, cookies: Vec<String>) {
+    for cookie in cookies {
+        ctx.response_header_ops
+            .push(("Set-Cookie".to_string(), cookie, HeaderMode::Append));
+    }
+}
+
+fn redirect(ctx: &mut NylonContext, location: String, cookies: Vec<String>) {
+    ctx.set_response_status.store(302, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Cache-Control".to_string(), "no-store".to_string());
+    ctx.add_response_header
+        .insert("Location".to_string(), location);
+    add_cookies(ctx, cookies);
+}
+
+fn unauthorized(ctx: &mut NylonContext, challenge: &str, message: &str) {
+    ctx.add_response_header
+        .insert("WWW-Authenticate".to_string(), challenge.to_string());
+    respond(ctx, 401, "UNAUTHORIZED", message);
+}
+
+fn respond(ctx: &mut NylonContext, status: u16, code: &str, message: &str) {
+    ctx.set_response_status.store(status, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": code,
+        "message": message,
+    })
+    .to_string()
+    .into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    fn payload(extra: Value) -> Payload {
+        let mut value = json!({
+            "issuer": "https://id.example.com",
+            "client_id": "app",
+            "cookie_secret": "secret",
+        });
+        if let (Value::Object(base), Value::Object(extra)) = (&mut value, extra) {
+            base.extend(extra);
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn id_token(claims: &Value) -> String {
+        format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.signature",
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    fn request(cookies: &[&str]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for cookie in cookies {
+            req.append_header(http::header::COOKIE, *cookie).unwrap();
+        }
+        req
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    /// Answer every request with `status` and `body`, counting them
+    fn serve(listener: TcpListener, status: u16, body: String) -> Arc<AtomicUsize> {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = vec![];
+                let mut chunk = [0u8; 4096];
+                while let Ok(n) = stream.read(&mut chunk) {
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length:"))
+                            .and_then(|v| v.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break;
+                        }
+                    }
+                }
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        hits
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let login = CookieState::Login {
+            state: "state".to_string(),
+            nonce: "nonce".to_string(),
+            verifier: "verifier".to_string(),
+            return_to: "/app?x=1".to_string(),
+            expires: 42,
+        };
+        let sealed = seal("secret", &login);
+        assert_ne!(sealed, seal("secret", &login));
+        match open("secret", &sealed) {
+            Some(CookieState::Login {
+                state,
+                return_to,
+                expires,
+                ..
+            }) => {
+                assert_eq!(state, "state");
+                assert_eq!(return_to, "/app?x=1");
+                assert_eq!(expires, 42);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(open("other secret", &sealed).is_none());
+        let mut tampered = sealed.into_bytes();
+        let i = tampered.len() - 5;
+        tampered[i] = if tampered[i] == b'A' { b'B' } else { b'A' };
+        assert!(open("secret", &String::from_utf8(tampered).unwrap()).is_none());
+        assert!(open("secret", "c2hvcnQ").is_none());
+        assert!(open("secret", "not base64!").is_none());
+    }
+
+    #[test]
+    fn test_id_token_claims() {
+        let p = payload(json!({}));
+        let good = json!({
+            "iss": "https://id.example.com/",
+            "aud": "app",
+            "exp": now() + 300,
+            "nonce": "n",
+            "sub": "user",
+        });
+        let token = |change: Value| {
+            let mut claims = good.clone();
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(change.as_object().unwrap().clone());
+            id_token(&claims)
+        };
+
+        let claims = id_token_claims(&p, &id_token(&good), Some("n")).unwrap();
+        assert_eq!(claims["sub"], "user");
+        assert!(id_token_claims(&p, &token(json!({"aud": ["other", "app"]})), Some("n")).is_ok());
+        // After a refresh there is no nonce to check
+        assert!(id_token_claims(&p, &token(json!({"nonce": null})), None).is_ok());
+
+        for (change, error) in [
+            (json!({"iss": "https://evil.example.com"}), "issuer"),
+            (json!({"iss": null}), "issuer"),
+            (json!({"aud": "other"}), "audience"),
+            (json!({"aud": ["other"]}), "audience"),
+            (json!({"exp": now() - 1}), "expired"),
+            (json!({"exp": null}), "expired"),
+            (json!({"nonce": "m"}), "nonce"),
+            (json!({"nonce": null}), "nonce"),
+        ] {
+            let err = id_token_claims(&p, &token(change.clone()), Some("n")).unwrap_err();
+            assert!(err.contains(error), "{}: {}", change, err);
+        }
+        assert_eq!(
+            id_token_claims(&p, "not-a-jwt", None).unwrap_err(),
+            "malformed ID token"
+        );
+    }
+
+    #[test]
+    fn test_keep_claims() {
+        let p = payload(json!({"claim_headers": {"x-auth-groups": "groups"}}));
+        let claims = json!({"sub": "user", "groups": ["a"], "email": "u@example.com", "iat": 1});
+        let kept = keep_claims(&p, claims.as_object().unwrap().clone());
+        let mut names = kept.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["groups", "sub"]);
+    }
+
+    #[test]
+    fn test_strip_cookie() {
+        let mut req = request(&[
+            "a=1; nylon_oidc=2.xx; b=2",
+            "nylon_oidc.1=yy; nylon_oidcx=3; nylon_oidc.z=4",
+        ]);
+        strip_cookie(&mut req, "nylon_oidc");
+        let cookies = req
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(cookies, vec!["a=1; b=2; nylon_oidcx=3; nylon_oidc.z=4"]);
+
+        let mut req = request(&["nylon_oidc=xx"]);
+        strip_cookie(&mut req, "nylon_oidc");
+        assert!(req.headers.get(http::header::COOKIE).is_none());
+    }
+
+    #[test]
+    fn test_query() {
+        let q = query(Some("code=abc&state=x%2Fy&message=a+b&flag"));
+        assert_eq!(q.len(), 3);
+        assert_eq!(q["code"], "abc");
+        assert_eq!(q["state"], "x/y");
+        assert_eq!(q["message"], "a b");
+        assert!(query(None).is_empty());
+    }
+
+    #[test]
+    fn test_callback_only_returns_to_local_paths() {
+        assert_eq!(local_path("/app?x=1".to_string()), "/app?x=1");
+        for target in [
+            "https://evil.example.com/",
+            "//evil.example.com/",
+            "/\\evil.example.com/",
+            "/\t/evil.example.com/",
+            "\n/evil.example.com",
+            "",
+        ] {
+            assert_eq!(local_path(target.to_string()), "/", "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn test_check_payload() {
+        let check = |value: Value| check_payload(Some(&value));
+        let base = json!({
+            "issuer": "https://id.example.com",
+            "client_id": "app",
+            "cookie_secret": "secret",
+        });
+        let with = |change: Value| {
+            let mut value = base.clone();
+            value
+                .as_object_mut()
+                .unwrap()
+                .extend(change.as_object().unwrap().clone());
+            value
+        };
+
+        assert_eq!(check(base.clone()), Ok(()));
+        assert_eq!(
+            check(
+                json!({"mode": "introspection", "issuer": "https://id.example.com", "client_id": "api"})
+            ),
+            Ok(())
+        );
+        assert_eq!(check(with(json!({"issuer": "${env(ISSUER)}"}))), Ok(()));
+        assert!(check_payload(None).is_err());
+
+        for (change, error) in [
+            (json!({"cookie_secret": null}), "cookie_secret"),
+            (json!({"cookie_secret": ""}), "cookie_secret"),
+            (json!({"issuer": "id.example.com"}), "issuer"),
+            (json!({"client_id": ""}), "client_id"),
+            (json!({"callback_path": "callback"}), "callback_path"),
+            (json!({"logout_path": "/oauth2/callback"}), "must differ"),
+            (json!({"session_ttl_seconds": 0}), "session_ttl_seconds"),
+            (json!({"cookie_name": "session.id"}), "cookie_name"),
+            (json!({"claim_headers": {"x bad": "sub"}}), "claim header"),
+            (json!({"scope": ["openid"]}), "unknown field"),
+        ] {
+            let err = check(with(change.clone())).unwrap_err();
+            assert!(err.contains(error), "{}: {}", change, err);
+        }
+    }
+
+    #[test]
+    fn test_large_sessions_are_split_over_cookies() {
+        let ctx = NylonContext::default();
+        let p = payload(json!({}));
+        let expires = now() + 3600;
+        let as_request = |cookies: &[String]| {
+            let pairs = cookies
+                .iter()
+                .map(|c| c.split(';').next().unwrap())
+                .collect::<Vec<_>>()
+                .join("; ");
+            request(&[pairs.as_str()])
+        };
+
+        let cookies = set_cookies(&ctx, &p, "c2Vzc2lvbg", expires, 0).unwrap();
+        assert_eq!(cookies.len(), 1);
+        assert!(cookies[0].starts_with("nylon_oidc=c2Vzc2lvbg; Path=/; Max-Age="));
+        assert_eq!(
+            session_cookie(&as_request(&cookies), "nylon_oidc").as_deref(),
+            Some("c2Vzc2lvbg")
+        );
+
+        let large = "A".repeat(9000);
+        let cookies = set_cookies(&ctx, &p, &large, expires, 0).unwrap();
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies[0].starts_with("nylon_oidc=3."));
+        assert!(cookies[2].starts_with("nylon_oidc.2="));
+        assert!(cookies.iter().all(|c| c.len() <= MAX_COOKIE_BYTES));
+        let req = as_request(&cookies);
+        assert_eq!(session_cookie(&req, "nylon_oidc"), Some(large.clone()));
+        assert_eq!(held_chunks(&req, "nylon_oidc"), 3);
+
+        // Chunks of the larger session are cleared
+        let cookies = set_cookies(&ctx, &p, "c2Vzc2lvbg", expires, 3).unwrap();
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies[1].starts_with("nylon_oidc.1=; Path=/; Max-Age=0"));
+        assert!(cookies[2].starts_with("nylon_oidc.2=; Path=/; Max-Age=0"));
+
+        assert!(set_cookies(&ctx, &p, &"A".repeat(20000), expires, 0).is_err());
+        // A missing chunk is no session
+        let req = request(&["nylon_oidc=3.xx; nylon_oidc.1=yy"]);
+        assert_eq!(session_cookie(&req, "nylon_oidc"), None);
+        assert_eq!(held_chunks(&request(&[]), "nylon_oidc"), 0);
+    }
+
+    #[test]
+    fn test_introspection_cache() {
+        let claims = json!({"active": true, "sub": "user", "exp": 100});
+        let claims = Arc::new(claims.as_object().unwrap().clone());
+        cache_answer("test-active".to_string(), Some(claims), 1000);
+        assert!(matches!(cached_answer("test-active", 50), Some(Some(_))));
+        // Never past the token's own exp
+        assert_eq!(cached_answer("test-active", 100), None);
+
+        cache_answer("test-inactive".to_string(), None, 1000);
+        assert_eq!(cached_answer("test-inactive", 50), Some(None));
+        assert_eq!(cached_answer("test-inactive", 1000), None);
+        assert!(INTROSPECTED.lock().unwrap().peek("test-inactive").is_none());
+        assert_eq!(cached_answer("test-unknown", 50), None);
+    }
+
+    #[test]
+    fn test_concurrent_requests_refresh_once() {
+        let (listener, url) = listen();
+        let hits = serve(
+            listener,
+            200,
+            r#"{"access_token":"new","refresh_token":"rotated","expires_in":300}"#.to_string(),
+        );
+        let p = payload(json!({
+            "authorization_endpoint": format!("{}/authorize", url),
+            "token_endpoint": format!("{}/token", url),
+        }));
+        let tokens = block_on(async {
+            let provider = provider(&p).await.unwrap();
+            let tasks = (0..3)
+                .map(|_| {
+                    let (p, provider) = (p.clone(), provider.clone());
+                    tokio::spawn(async move { refresh(&p, &provider, "refresh-once").await })
+                })
+                .collect::<Vec<_>>();
+            let mut tokens = vec![];
+            for task in tasks {
+                tokens.push(task.await.unwrap().unwrap());
+            }
+            tokens
+        });
+        assert!(tokens.iter().all(|t| t.access_token == "new"));
+        assert!(
+            tokens
+                .iter()
+                .all(|t| t.refresh_token.as_deref() == Some("rotated"))
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_discovery() {
+        let (listener, url) = listen();
+        let document = json!({
+            "issuer": format!("{}/", url),
+            "authorization_endpoint": format!("{}/authorize", url),
+            "token_endpoint": format!("{}/token", url),
+        });
+        let hits = serve(listener, 200, document.to_string());
+        let p = payload(json!({"issuer": url}));
+        let found = block_on(provider(&p)).unwrap();
+        assert_eq!(found.token_endpoint, Some(format!("{}/token", p.issuer)));
+        block_on(provider(&p)).unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_discovery_checks_the_issuer() {
+        let (listener, url) = listen();
+        let document = json!({
+            "issuer": "https://other.example.com",
+            "authorization_endpoint": "https://other.example.com/authorize",
+            "token_endpoint": "https://other.example.com/token",
+        });
+        serve(listener, 200, document.to_string());
+        let err = block_on(provider(&payload(json!({"issuer": url})))).unwrap_err();
+        assert!(err.contains("other.example.com"), "{}", err);
+    }
+
+    #[test]
+    fn test_failed_discovery_is_remembered() {
+        let (listener, url) = listen();
+        let hits = serve(listener, 500, "{}".to_string());
+        let p = payload(json!({"issuer": url}));
+        let first = block_on(provider(&p)).unwrap_err();
+        assert!(first.contains("HTTP 500"), "{}", first);
+        let second = block_on(provider(&p)).unwrap_err();
+        assert_eq!(first, second);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
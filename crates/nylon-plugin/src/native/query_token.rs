@@ -0,0 +1,197 @@
+//! Signed query token authentication
+//!
+//! Browsers cannot attach headers to WebSocket or EventSource requests, so the
+//! token travels in the query string instead. The token has the form
+//! `base64url(claims_json).base64url(hmac_sha256(secret, base64url(claims_json)))`
+//! and must carry an `exp` claim (unix seconds).
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use nylon_error::NylonError;
+use nylon_types::{
+    context::NylonContext,
+    template::{Expr, apply_payload_ast},
+};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn default_param() -> String {
+    "token".to_string()
+}
+
+fn default_header_prefix() -> String {
+    "x-auth-".to_string()
+}
+
+fn default_leeway() -> u64 {
+    30
+}
+
+/// Payload structure for query token validation
+#[derive(Debug, Deserialize, Clone)]
+struct Payload {
+    secret: String,
+    #[serde(default = "default_param")]
+    param: String,
+    #[serde(default = "default_header_prefix")]
+    header_prefix: String,
+    #[serde(default = "default_leeway")]
+    leeway_seconds: u64,
+    /// Reject requests without a token (default: true)
+    required: Option<bool>,
+}
+
+/// Validate the query token and turn its claims into identity headers
+///
+/// Returns `true` when the request was rejected and the response is ready.
+pub fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<bool, NylonError> {
+    let Some(payload) = payload.as_ref() else {
+        return Err(NylonError::ConfigError(
+            "QueryTokenAuth requires a payload with a secret".to_string(),
+        ));
+    };
+    let mut payload = payload.clone();
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+    let header_prefix = payload.header_prefix.to_ascii_lowercase();
+
+    // Identity headers are only trusted when we set them ourselves
+    let headers = session.req_header_mut();
+    let spoofed: Vec<String> = headers
+        .headers
+        .keys()
+        .map(|k| k.as_str().to_string())
+        .filter(|k| k.starts_with(&header_prefix))
+        .collect();
+    for name in spoofed {
+        let _ = headers.remove_header(&name);
+    }
+
+    let (token, remaining_query) = split_token(headers.uri.query(), &payload.param);
+
+    // Strip the token so it never reaches upstreams, plugins, or access logs
+    if token.is_some() {
+        let path = headers.uri.path().to_string();
+        let path_and_query = match remaining_query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = headers.uri.clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = http::Uri::from_parts(parts) {
+            headers.set_uri(uri);
+        }
+        ctx.cached_query.take();
+    }
+
+    let claims = match token {
+        Some(token) => verify(&token, payload.secret.as_bytes(), payload.leeway_seconds),
+        None if payload.required.unwrap_or(true) => Err("missing token"),
+        None => return Ok(false),
+    };
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(reason) => {
+            tracing::debug!("QueryTokenAuth rejected request: {}", reason);
+            reject(ctx, reason);
+            return Ok(true);
+        }
+    };
+
+    for (name, value) in claims {
+        let value = match value {
+            Value::String(s) => s,
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => continue,
+        };
+        let name = format!("{}{}", header_prefix, name.to_ascii_lowercase());
+        let _ = headers.insert_header(name, value);
+    }
+    Ok(false)
+}
+
+/// Pull the token parameter out of the query string
+fn split_token(query: Option<&str>, param: &str) -> (Option<String>, Option<String>) {
+    let Some(query) = query else {
+        return (None, None);
+    };
+    let mut token = None;
+    let mut rest = vec![];
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let mut it = pair.splitn(2, '=');
+        if it.next() == Some(param) {
+            token.get_or_insert_with(|| it.next().unwrap_or_default().to_string());
+        } else {
+            rest.push(pair);
+        }
+    }
+    let rest = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.join("&"))
+    };
+    (token, rest)
+}
+
+/// Check signature and expiry, returning the claims
+fn verify(
+    token: &str,
+    secret: &[u8],
+    leeway_seconds: u64,
+) -> Result<serde_json::Map<String, Value>, &'static str> {
+    let (claims_b64, signature_b64) = token.split_once('.').ok_or("malformed token")?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature_b64)
+        .map_err(|_| "malformed signature")?;
+
+    let mut mac = HmacSha256::new_from_slice(secret).map_err(|_| "invalid secret")?;
+    mac.update(claims_b64.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| "invalid signature")?;
+
+    let claims_json = URL_SAFE_NO_PAD
+        .decode(claims_b64)
+        .map_err(|_| "malformed claims")?;
+    let claims: serde_json::Map<String, Value> =
+        serde_json::from_slice(&claims_json).map_err(|_| "malformed claims")?;
+
+    let exp = claims
+        .get("exp")
+        .and_then(Value::as_u64)
+        .ok_or("missing exp claim")?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if exp.saturating_add(leeway_seconds) < now {
+        return Err("token expired");
+    }
+    Ok(claims)
+}
+
+fn reject(ctx: &mut NylonContext, reason: &str) {
+    ctx.set_response_status.store(401, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": "UNAUTHORIZED",
+        "message": reason,
+    })
+    .to_string()
+    .into_bytes();
+}
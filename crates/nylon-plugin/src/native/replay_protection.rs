@@ -0,0 +1,127 @@
+//! Replay protection
+//!
+//! Signed requests carry a unique nonce and the time they were created.
+//! Requests outside the time window or reusing a nonce are rejected, so a
+//! captured request cannot be sent again.
+
+use nylon_error::NylonError;
+use nylon_types::{
+    context::NylonContext,
+    template::{Expr, apply_payload_ast},
+};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+fn default_nonce_header() -> String {
+    "x-nonce".to_string()
+}
+
+fn default_timestamp_header() -> String {
+    "x-timestamp".to_string()
+}
+
+fn default_window() -> u64 {
+    300
+}
+
+fn default_key_prefix() -> String {
+    "nylon:nonce:".to_string()
+}
+
+/// Payload structure for replay protection
+#[derive(Debug, Deserialize, Clone)]
+struct Payload {
+    #[serde(default = "default_nonce_header")]
+    nonce_header: String,
+    #[serde(default = "default_timestamp_header")]
+    timestamp_header: String,
+    /// Accepted clock difference in either direction
+    #[serde(default = "default_window")]
+    window_seconds: u64,
+    /// Share nonces between nodes through Redis (e.g. `redis://127.0.0.1:6379/0`)
+    redis_url: Option<String>,
+    #[serde(default = "default_key_prefix")]
+    key_prefix: String,
+}
+
+/// Reject requests with a stale timestamp or an already used nonce
+///
+/// Returns `true` when the request was rejected and the response is ready.
+pub async fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<bool, NylonError> {
+    let mut payload = payload
+        .clone()
+        .unwrap_or_else(|| Value::Object(Default::default()));
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+
+    let headers = &session.req_header().headers;
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    let (Some(nonce), Some(timestamp)) = (
+        header(&payload.nonce_header),
+        header(&payload.timestamp_header),
+    ) else {
+        reject(ctx, "missing", "missing nonce or timestamp");
+        return Ok(true);
+    };
+
+    let Ok(timestamp) = timestamp.parse::<u64>() else {
+        reject(ctx, "invalid", "invalid timestamp");
+        return Ok(true);
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now.abs_diff(timestamp) > payload.window_seconds {
+        reject(
+            ctx,
+            "expired",
+            "request timestamp outside the allowed window",
+        );
+        return Ok(true);
+    }
+
+    // A nonce has to be remembered as long as its timestamp is acceptable
+    let ttl = Duration::from_secs(payload.window_seconds.saturating_mul(2));
+    let key = format!("{}{}", payload.key_prefix, nonce);
+    let fresh =
+        nylon_store::nonces::insert_if_absent(&key, ttl, payload.redis_url.as_deref()).await?;
+    if !fresh {
+        reject(ctx, "replayed", "nonce already used");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn reject(ctx: &mut NylonContext, reason: &str, message: &str) {
+    tracing::debug!("ReplayProtection rejected request: {}", message);
+    nylon_store::nonces::record_rejection(reason);
+    ctx.set_response_status.store(401, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": "UNAUTHORIZED",
+        "message": message,
+    })
+    .to_string()
+    .into_bytes();
+}
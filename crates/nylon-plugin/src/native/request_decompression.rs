@@ -0,0 +1,311 @@
+//! Request body decompression
+//!
+//! A body sent with `Content-Encoding` gzip, deflate, or br is read whole and
+//! decompressed on a blocking thread, so later plugins and the upstream get
+//! it as plain bytes. The compressed body and the output are capped by size,
+//! and the output also by its ratio to the compressed body, so a small zip
+//! bomb cannot fill memory. At most two codings are undone, and the output
+//! of every one of them counts against `max_bytes`.
+
+use super::waf::read_body;
+use nylon_error::NylonError;
+use nylon_types::{buffer_pool, context::NylonContext};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{io::Read, sync::atomic::Ordering};
+
+/// Codings undone for one body; more are refused
+const MAX_CODINGS: usize = 2;
+
+fn default_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_ratio() -> usize {
+    100
+}
+
+/// Payload structure for request decompression
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Payload {
+    /// Largest decompressed body
+    #[serde(default = "default_max_bytes")]
+    max_bytes: usize,
+    /// Largest decompressed size as a multiple of the compressed one
+    #[serde(default = "default_max_ratio")]
+    max_ratio: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    TooLarge,
+    Invalid(String),
+}
+
+/// Decompress the request body
+///
+/// Returns `true` when the request was rejected and the response is ready.
+pub async fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+) -> Result<bool, NylonError> {
+    let payload = serde_json::from_value::<Payload>(
+        payload
+            .clone()
+            .unwrap_or_else(|| Value::Object(Default::default())),
+    )
+    .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+
+    // Codings in the order they were applied
+    let encodings = session
+        .req_header()
+        .headers
+        .get_all(http::header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+        .collect::<Vec<_>>();
+    if encodings.is_empty() || ctx.request_body_decoded.load(Ordering::Relaxed) {
+        return Ok(false);
+    }
+    if let Err(message) = check_codings(&encodings) {
+        respond(ctx, 415, "UNSUPPORTED_MEDIA_TYPE", &message);
+        return Ok(true);
+    }
+
+    read_body(ctx, session, payload.max_bytes.saturating_add(1)).await?;
+    if ctx.request_body.len() > payload.max_bytes {
+        respond(ctx, 413, "BODY_TOO_LARGE", "Request body is too large");
+        return Ok(true);
+    }
+    let limit = payload.max_bytes.min(
+        ctx.request_body
+            .len()
+            .max(1)
+            .saturating_mul(payload.max_ratio),
+    );
+    let body = std::mem::take(&mut ctx.request_body);
+    let max_bytes = payload.max_bytes;
+    let decoded =
+        tokio::task::spawn_blocking(move || decode_all(&encodings, body, limit, max_bytes))
+            .await
+            .map_err(|e| NylonError::InternalServerError(format!("Decompression failed: {}", e)))?;
+    let decoded = match decoded {
+        Ok(decoded) => decoded,
+        Err(Failure::TooLarge) => {
+            respond(
+                ctx,
+                413,
+                "BODY_TOO_LARGE",
+                "Decompressed request body is too large",
+            );
+            return Ok(true);
+        }
+        Err(Failure::Invalid(message)) => {
+            respond(ctx, 400, "BAD_REQUEST", &message);
+            return Ok(true);
+        }
+    };
+    let length = decoded.len();
+    ctx.request_body = decoded;
+    ctx.request_body_decoded.store(true, Ordering::Relaxed);
+
+    let req = session.req_header_mut();
+    let _ = req.remove_header(&http::header::CONTENT_ENCODING);
+    let _ = req.remove_header(&http::header::TRANSFER_ENCODING);
+    let _ = req.insert_header(http::header::CONTENT_LENGTH, length.to_string());
+    Ok(false)
+}
+
+/// Refuse unknown codings and more than `MAX_CODINGS` of them
+fn check_codings(encodings: &[String]) -> Result<(), String> {
+    if let Some(unknown) = encodings
+        .iter()
+        .find(|e| !matches!(e.as_str(), "gzip" | "x-gzip" | "deflate" | "br"))
+    {
+        return Err(format!("Content-Encoding {} is not supported", unknown));
+    }
+    if encodings.len() > MAX_CODINGS {
+        return Err(format!(
+            "Content-Encoding lists {} codings, at most {} are supported",
+            encodings.len(),
+            MAX_CODINGS
+        ));
+    }
+    Ok(())
+}
+
+/// Undo `encodings`, listed in the order they were applied
+///
+/// Each output is at most `limit` bytes, and all of them together at most
+/// `total` bytes.
+fn decode_all(
+    encodings: &[String],
+    body: Vec<u8>,
+    limit: usize,
+    total: usize,
+) -> Result<Vec<u8>, Failure> {
+    let mut decoded = body;
+    let mut budget = total;
+    for encoding in encodings.iter().rev() {
+        let out = decode(encoding, &decoded, limit.min(budget)).map_err(|failure| match failure {
+            Failure::Invalid(e) => {
+                Failure::Invalid(format!("Request body is not valid {}: {}", encoding, e))
+            }
+            failure => failure,
+        });
+        buffer_pool::put(decoded);
+        decoded = out?;
+        budget -= decoded.len();
+    }
+    Ok(decoded)
+}
+
+fn decode(encoding: &str, data: &[u8], limit: usize) -> Result<Vec<u8>, Failure> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        "br" => Box::new(brotli::Decompressor::new(data, 4096)),
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(data)),
+        _ => Box::new(flate2::read::MultiGzDecoder::new(data)),
+    };
+    let mut out = buffer_pool::take();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| Failure::Invalid(e.to_string()))?;
+    if out.len() > limit {
+        return Err(Failure::TooLarge);
+    }
+    Ok(out)
+}
+
+fn respond(ctx: &mut NylonContext, status: u16, code: &str, message: &str) {
+    ctx.set_response_status.store(status, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ctx.set_response_body = serde_json::json!({
+        "error": code,
+        "message": message,
+    })
+    .to_string()
+    .into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// gzip without compression, so the output is as large as the input
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::none());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn encodings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let body = b"name=nylon&kind=proxy".to_vec();
+        assert_eq!(
+            decode_all(&encodings(&["gzip"]), gzip(&body), 1024, 1024),
+            Ok(body.clone())
+        );
+        // Applied deflate first, then gzip
+        let stacked = gzip(&deflate(&body));
+        assert_eq!(
+            decode_all(&encodings(&["deflate", "gzip"]), stacked, 1024, 1024),
+            Ok(body.clone())
+        );
+        assert_eq!(decode_all(&[], body.clone(), 1024, 1024), Ok(body));
+    }
+
+    #[test]
+    fn test_zip_bomb_is_refused() {
+        let bomb = gzip(&vec![0; 10 * 1024 * 1024]);
+        assert!(bomb.len() < 64 * 1024);
+        assert_eq!(
+            decode_all(
+                &encodings(&["gzip"]),
+                bomb.clone(),
+                1024 * 1024,
+                1024 * 1024
+            ),
+            Err(Failure::TooLarge)
+        );
+        // Exactly at the limit is fine, one byte over is not
+        let body = vec![7; 1000];
+        assert_eq!(
+            decode_all(&encodings(&["gzip"]), gzip(&body), 1000, 1000).map(|b| b.len()),
+            Ok(1000)
+        );
+        assert_eq!(
+            decode_all(&encodings(&["gzip"]), gzip(&body), 999, 999),
+            Err(Failure::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_invalid_body_names_the_encoding() {
+        let Err(Failure::Invalid(message)) = decode_all(
+            &encodings(&["deflate"]),
+            b"not compressed".to_vec(),
+            1024,
+            1024,
+        ) else {
+            panic!("expected an invalid body");
+        };
+        assert!(
+            message.starts_with("Request body is not valid deflate: "),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_check_codings() {
+        assert!(check_codings(&encodings(&["gzip"])).is_ok());
+        assert!(check_codings(&encodings(&["deflate", "br"])).is_ok());
+        let err = check_codings(&encodings(&["zstd"])).unwrap_err();
+        assert!(err.contains("zstd"), "{}", err);
+        let err = check_codings(&encodings(&["gzip", "gzip", "gzip"])).unwrap_err();
+        assert!(err.contains("at most 2"), "{}", err);
+        let many = std::iter::repeat_n("gzip".to_string(), 5000).collect::<Vec<_>>();
+        assert!(check_codings(&many).is_err());
+    }
+
+    #[test]
+    fn test_every_layer_counts_against_the_total() {
+        let body = (0..600).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        // The inner layer decodes to 600 bytes, the outer one to a few more
+        let nested = gzip(&gzip_stored(&body));
+        let codings = encodings(&["gzip", "gzip"]);
+        assert_eq!(
+            decode_all(&codings, nested.clone(), 1000, 2000),
+            Ok(body.clone())
+        );
+        assert_eq!(
+            decode_all(&codings, nested, 1000, 1000),
+            Err(Failure::TooLarge)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Web application firewall
+//!
+//! Rules are compiled when routes are stored (the payload is not templated).
+//! `block` answers with the configured status and `rate_limit` with `429`
+//! once a client exceeds the rule's limit; `log` and `tag` let the request
+//! through. The body is only read when a rule looks at it, and no further
+//! than `inspect_body_bytes`.
+
+use dashmap::DashMap;
+use nylon_error::NylonError;
+use nylon_types::{
+    buffer_pool,
+    context::NylonContext,
+    waf::{Action, RateLimit, Rule, Waf, WafRequest},
+};
+use once_cell::sync::Lazy;
+use pingora::proxy::Session;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+/// End of the current window and requests in it, by scope and client
+static WINDOWS: Lazy<DashMap<(String, String), (u64, u32)>> = Lazy::new(DashMap::new);
+
+/// Windows kept before expired ones are swept
+const MAX_WINDOWS: usize = 100_000;
+
+/// Second of the last sweep, so a full map is swept at most once a second
+static LAST_SWEEP: AtomicU64 = AtomicU64::new(0);
+
+/// Check the request against the rules
+///
+/// Returns `true` when the request was rejected and the response is ready.
+pub async fn request(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    waf: Option<&Arc<Waf>>,
+) -> Result<bool, NylonError> {
+    let Some(waf) = waf else {
+        return Err(NylonError::ShouldNeverHappen(
+            "Waf middleware was stored without its rules".to_string(),
+        ));
+    };
+    let (config, rules) = (&waf.config, &waf.rules);
+
+    // Only the WAF sets the tag header; never pass on a client's
+    let _ = session.req_header_mut().remove_header(&config.tag_header);
+
+    let is_form = session
+        .req_header()
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.starts_with("application/x-www-form-urlencoded"));
+    let mut body = vec![];
+    if rules.needs_body || (rules.needs_form && is_form) {
+        read_body(ctx, session, config.inspect_body_bytes).await?;
+        let full = &ctx.request_body;
+        body = full[..full.len().min(config.inspect_body_bytes)].to_vec();
+    }
+    let req = session.req_header();
+    let waf_req = WafRequest::new(
+        req.method.as_str(),
+        req.uri.path(),
+        req.uri.query(),
+        req.headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+        &body,
+    );
+    let client_ip = ctx.client_ip.clone();
+    let route = ctx
+        .route
+        .as_ref()
+        .map(|r| r.name.clone())
+        .unwrap_or_default();
+
+    let mut tags = vec![];
+    for rule in rules.matching(&waf_req) {
+        match rule.action {
+            Action::Log => log(rule, "matched", &waf_req, &client_ip),
+            Action::Tag => tags.extend(rule.tag.clone()),
+            Action::Block if config.detect_only => log(rule, "would block", &waf_req, &client_ip),
+            Action::Block => {
+                log(rule, "blocked", &waf_req, &client_ip);
+                let message = rule.message.as_deref().unwrap_or("Request blocked");
+                respond(ctx, config.status, "FORBIDDEN", message, None);
+                return Ok(true);
+            }
+            Action::RateLimit => {
+                let Some(limit) = rule.rate_limit else {
+                    continue;
+                };
+                let scope = format!("Waf/{}/{}", route, rule.id);
+                let Err(retry_after) = take(&scope, &client_ip, limit) else {
+                    continue;
+                };
+                if config.detect_only {
+                    log(rule, "would rate limit", &waf_req, &client_ip);
+                    continue;
+                }
+                log(rule, "rate limited", &waf_req, &client_ip);
+                let message = rule.message.as_deref().unwrap_or("Too many requests");
+                respond(ctx, 429, "RATE_LIMITED", message, Some(retry_after));
+                return Ok(true);
+            }
+        }
+    }
+
+    if !tags.is_empty() {
+        let value = tags.join(",");
+        ctx.waf_tags.extend(tags);
+        let _ = session
+            .req_header_mut()
+            .insert_header(config.tag_header.clone(), value);
+    }
+    Ok(false)
+}
+
+/// Read the request body into `ctx.request_body` until it holds `limit` bytes
+///
+/// A later call goes on where the last one stopped. What was read is sent
+/// upstream in place of pingora's retry buffer (see `request_body_held`).
+pub(crate) async fn read_body(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    limit: usize,
+) -> Result<(), NylonError> {
+    if session.is_body_empty() || session.is_body_done() || ctx.request_body.len() >= limit {
+        return Ok(());
+    }
+    if !ctx.read_body.swap(true, Ordering::Relaxed) {
+        session.enable_retry_buffering();
+    }
+    if ctx.request_body.capacity() == 0 {
+        ctx.request_body = buffer_pool::take();
+    }
+    while ctx.request_body.len() < limit {
+        match session.read_request_body().await {
+            Ok(Some(data)) => ctx.request_body.extend_from_slice(&data),
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Failed to read the request body: {}", e);
+                return Err(NylonError::HttpException(
+                    400,
+                    "BAD_REQUEST",
+                    "Failed to read the request body",
+                ));
+            }
+        }
+    }
+    if !ctx.request_body.is_empty() {
+        ctx.request_body_held.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Count a request against a limit; `Err` holds the seconds until the window ends
+///
+/// `scope` names the limit, e.g. the middleware, route and rule, so limits of
+/// different routes or instances never share a window.
+pub(crate) fn take(scope: &str, client: &str, limit: RateLimit) -> Result<(), u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if WINDOWS.len() > MAX_WINDOWS && LAST_SWEEP.swap(now, Ordering::Relaxed) != now {
+        WINDOWS.retain(|_, (end, _)| *end > now);
+    }
+    let mut window = WINDOWS
+        .entry((scope.to_string(), client.to_string()))
+        .or_insert((now + limit.window_seconds, 0));
+    if window.0 <= now {
+        *window = (now + limit.window_seconds, 0);
+    }
+    window.1 += 1;
+    if window.1 > limit.requests {
+        return Err(window.0 - now);
+    }
+    Ok(())
+}
+
+fn log(rule: &Rule, outcome: &str, req: &WafRequest, client_ip: &str) {
+    warn!(
+        "WAF rule {} {} {} {} from {}{}",
+        rule.id,
+        outcome,
+        req.method,
+        req.path,
+        client_ip,
+        rule.message
+            .as_deref()
+            .map(|m| format!(": {}", m))
+            .unwrap_or_default()
+    );
+}
+
+fn respond(
+    ctx: &mut NylonContext,
+    status: u16,
+    code: &str,
+    message: &str,
+    retry_after: Option<u64>,
+) {
+    ctx.set_response_status.store(status, Ordering::Relaxed);
+    ctx.add_response_header
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    if let Some(retry_after) = retry_after {
+        ctx.add_response_header
+            .insert("Retry-After".to_string(), retry_after.to_string());
+    }
+    ctx.set_response_body = serde_json::json!({
+        "error": code,
+        "message": message,
+    })
+    .to_string()
+    .into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_counts_per_scope_and_client() {
+        let limit = RateLimit {
+            requests: 2,
+            window_seconds: 60,
+        };
+        assert!(take("Waf/a/login", "10.0.0.1", limit).is_ok());
+        assert!(take("Waf/a/login", "10.0.0.1", limit).is_ok());
+        let retry_after = take("Waf/a/login", "10.0.0.1", limit).unwrap_err();
+        assert!(retry_after > 0 && retry_after <= 60);
+
+        // Another route, rule or client has a window of its own
+        assert!(take("Waf/b/login", "10.0.0.1", limit).is_ok());
+        assert!(take("Waf/a/search", "10.0.0.1", limit).is_ok());
+        assert!(take("Waf/a/login", "10.0.0.2", limit).is_ok());
+    }
+}
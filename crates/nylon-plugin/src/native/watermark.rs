@@ -0,0 +1,77 @@
+//! Response watermarking
+//!
+//! Stamps a per-request identifier into HTML and JSON responses so leaked
+//! content can be traced back to the user or request it was served to.
+
+use nylon_error::NylonError;
+use nylon_types::{
+    context::NylonContext,
+    template::{Expr, apply_payload_ast},
+    watermark::Watermark,
+};
+use pingora::proxy::Session;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+fn default_html() -> bool {
+    true
+}
+
+fn default_json_field() -> Option<String> {
+    Some("_watermark".to_string())
+}
+
+/// Payload structure for response watermarking
+#[derive(Debug, Deserialize, Clone)]
+struct Payload {
+    /// Identifier to stamp, usually a template such as `${header(x-user-id)}`
+    value: String,
+    /// Stamp a hash of the value instead of the value itself
+    #[serde(default)]
+    hash: bool,
+    #[serde(default = "default_html")]
+    html: bool,
+    /// `null` disables JSON watermarks
+    #[serde(default = "default_json_field")]
+    json_field: Option<String>,
+}
+
+/// Prepare the watermark of the current response
+///
+/// The body is stamped by the proxy once the content type is known.
+pub fn response(
+    ctx: &mut NylonContext,
+    session: &mut Session,
+    payload: &Option<Value>,
+    payload_ast: &Option<HashMap<String, Vec<Expr>>>,
+) -> Result<(), NylonError> {
+    let Some(payload) = payload else {
+        return Err(NylonError::ConfigError(
+            "ResponseWatermark requires a payload with a value".to_string(),
+        ));
+    };
+    let mut payload = payload.clone();
+    if let Some(payload_ast) = payload_ast {
+        apply_payload_ast(&mut payload, payload_ast, session.req_header(), ctx);
+    }
+    let payload = serde_json::from_value::<Payload>(payload)
+        .map_err(|e| NylonError::ConfigError(e.to_string()))?;
+    if payload.value.is_empty() {
+        return Ok(());
+    }
+
+    let value = if payload.hash {
+        let digest = Sha256::digest(payload.value.as_bytes());
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        payload.value
+    };
+    ctx.response_watermark = Some(Watermark {
+        value,
+        html: payload.html,
+        json_field: payload.json_field,
+    });
+    Ok(())
+}
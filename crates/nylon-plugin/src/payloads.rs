@@ -0,0 +1,319 @@
+//! Payloads of ABI version 2
+//!
+//! Handlers work on the version 1 layout. For a session on version 2,
+//! `decode_call` turns the table a plugin sent into that layout before the
+//! method is handled, and `encode_reply` wraps every reply in its table.
+//! Methods whose payload already is a table pass through unchanged.
+
+use crate::constants::methods;
+use nylon_error::NylonError;
+use nylon_sdk::fbs::plugin_generated::nylon_plugin::{
+    BodyChunk, BodyChunkArgs, Bytes, BytesArgs, CloseFrame, Number, NumberArgs, Text, TextArgs,
+};
+use std::borrow::Cow;
+
+/// Table a plugin sends a method's payload in
+#[derive(Debug, PartialEq)]
+enum Call {
+    Raw,
+    /// `Text`, e.g. a header name, a path or JSON
+    Text,
+    /// `Bytes`, a body or a binary frame
+    Bytes,
+    /// `CloseFrame`, version 1 sends `[code BE][reason]`
+    Close,
+}
+
+/// Table nylon answers a method in
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Raw,
+    Text,
+    Bytes,
+    /// `Number`, version 1 sends the value as a decimal string
+    Number,
+    /// `BodyChunk`, version 1 sends a flag byte (1 on the last chunk) and the chunk
+    BodyChunk,
+}
+
+fn call(method: u32) -> Call {
+    match method {
+        methods::READ_REQUEST_HEADER
+        | methods::SET_REQUEST_PATH
+        | methods::SET_REQUEST_METHOD
+        | methods::SET_REQUEST_QUERY
+        | methods::SET_UPSTREAM
+        | methods::HTTP_FETCH
+        | methods::WEBSOCKET_SEND_TEXT
+        | methods::WEBSOCKET_SELECT_PROTOCOL
+        | methods::WEBSOCKET_SET_CONNECTION_METADATA
+        | methods::WEBSOCKET_READ_CONNECTION_METADATA
+        | methods::WEBSOCKET_JOIN_ROOM
+        | methods::WEBSOCKET_LEAVE_ROOM
+        | methods::WEBSOCKET_ROOM_SIZE
+        | methods::WEBSOCKET_ROOM_MEMBERS => Call::Text,
+        methods::SET_RESPONSE_FULL_BODY
+        | methods::SET_RESPONSE_STREAM_DATA
+        | methods::SET_REQUEST_BODY_CHUNK
+        | methods::WEBSOCKET_SEND_BINARY => Call::Bytes,
+        methods::WEBSOCKET_CLOSE => Call::Close,
+        _ => Call::Raw,
+    }
+}
+
+fn reply(method: u32) -> Reply {
+    match method {
+        methods::GET_PAYLOAD
+        | methods::READ_REQUEST_HEADER
+        | methods::READ_REQUEST_URL..=methods::READ_REQUEST_METHOD
+        | methods::READ_ROUTE_INFO
+        | methods::HTTP_FETCH
+        | methods::READ_UPSTREAM_CONNECTION
+        | methods::READ_RESPONSE_ERROR
+        | methods::WEBSOCKET_READ_CONNECTION_METADATA
+        | methods::WEBSOCKET_ROOM_MEMBERS
+        | methods::WEBSOCKET_ON_OPEN
+        | methods::WEBSOCKET_ON_MESSAGE_TEXT
+        | methods::WEBSOCKET_ON_CLOSE..=methods::WEBSOCKET_ON_ROOM_LEAVE => Reply::Text,
+        methods::READ_REQUEST_FULL_BODY
+        | methods::READ_RESPONSE_FULL_BODY
+        | methods::WEBSOCKET_ON_MESSAGE_BINARY => Reply::Bytes,
+        methods::READ_REQUEST_BYTES
+        | methods::READ_RESPONSE_BYTES
+        | methods::READ_REQUEST_TIMESTAMP
+        | methods::READ_RESPONSE_DURATION
+        | methods::WEBSOCKET_ROOM_SIZE => Reply::Number,
+        methods::READ_REQUEST_BODY_CHUNK => Reply::BodyChunk,
+        _ => Reply::Raw,
+    }
+}
+
+/// Version 1 layout of a call a plugin sent; an empty payload stays empty
+pub fn decode_call(method: u32, data: Vec<u8>) -> Result<Vec<u8>, NylonError> {
+    if data.is_empty() {
+        return Ok(data);
+    }
+    let invalid = |e: flatbuffers::InvalidFlatbuffer| {
+        NylonError::ConfigError(format!("Invalid payload for method {}: {}", method, e))
+    };
+    match call(method) {
+        Call::Raw => Ok(data),
+        Call::Text => Ok(flatbuffers::root::<Text>(&data)
+            .map_err(invalid)?
+            .value()
+            .as_bytes()
+            .to_vec()),
+        Call::Bytes => Ok(flatbuffers::root::<Bytes>(&data)
+            .map_err(invalid)?
+            .data()
+            .map(|d| d.bytes().to_vec())
+            .unwrap_or_default()),
+        Call::Close => {
+            let frame = flatbuffers::root::<CloseFrame>(&data).map_err(invalid)?;
+            // No code, no close body
+            if frame.code() == 0 {
+                return Ok(vec![]);
+            }
+            let reason = frame.reason().unwrap_or_default();
+            let mut body = Vec::with_capacity(2 + reason.len());
+            body.extend_from_slice(&frame.code().to_be_bytes());
+            body.extend_from_slice(reason.as_bytes());
+            Ok(body)
+        }
+    }
+}
+
+/// Version 2 layout of a reply handlers built in the version 1 layout
+pub fn encode_reply(method: u32, data: &[u8]) -> Result<Cow<'_, [u8]>, NylonError> {
+    let mut fbb = flatbuffers::FlatBufferBuilder::with_capacity(data.len() + 32);
+    match reply(method) {
+        Reply::Raw => return Ok(Cow::Borrowed(data)),
+        Reply::Text => {
+            let value = fbb.create_string(&String::from_utf8_lossy(data));
+            let text = Text::create(&mut fbb, &TextArgs { value: Some(value) });
+            fbb.finish(text, None);
+        }
+        Reply::Bytes => {
+            let data = fbb.create_vector(data);
+            let bytes = Bytes::create(&mut fbb, &BytesArgs { data: Some(data) });
+            fbb.finish(bytes, None);
+        }
+        Reply::Number => {
+            let value = std::str::from_utf8(data)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or_else(|| {
+                    NylonError::RuntimeError(format!(
+                        "reply to method {} is not a number: {:?}",
+                        method,
+                        String::from_utf8_lossy(data)
+                    ))
+                })?;
+            let number = Number::create(&mut fbb, &NumberArgs { value });
+            fbb.finish(number, None);
+        }
+        Reply::BodyChunk => {
+            let Some((flag, chunk)) = data.split_first() else {
+                return Err(NylonError::RuntimeError(format!(
+                    "reply to method {} has no last chunk flag",
+                    method
+                )));
+            };
+            let chunk = fbb.create_vector(chunk);
+            let body = BodyChunk::create(
+                &mut fbb,
+                &BodyChunkArgs {
+                    data: Some(chunk),
+                    last: *flag == 1,
+                },
+            );
+            fbb.finish(body, None);
+        }
+    }
+    Ok(Cow::Owned(fbb.finished_data().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nylon_sdk::fbs::plugin_generated::nylon_plugin::{
+        CloseFrameArgs, ResponseStatus, ResponseStatusArgs,
+    };
+
+    fn text(value: &str) -> Vec<u8> {
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let value = fbb.create_string(value);
+        let text = Text::create(&mut fbb, &TextArgs { value: Some(value) });
+        fbb.finish(text, None);
+        fbb.finished_data().to_vec()
+    }
+
+    fn close(code: u16, reason: Option<&str>) -> Vec<u8> {
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let reason = reason.map(|r| fbb.create_string(r));
+        let frame = CloseFrame::create(&mut fbb, &CloseFrameArgs { code, reason });
+        fbb.finish(frame, None);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn test_decode_text() {
+        let data = decode_call(methods::SET_REQUEST_PATH, text("/v2/users")).unwrap();
+        assert_eq!(data, b"/v2/users");
+        let data = decode_call(methods::READ_REQUEST_HEADER, text("x-user")).unwrap();
+        assert_eq!(data, b"x-user");
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let data = fbb.create_vector(&[0u8, 1, 2, 255]);
+        let bytes = Bytes::create(&mut fbb, &BytesArgs { data: Some(data) });
+        fbb.finish(bytes, None);
+        let data = decode_call(
+            methods::SET_RESPONSE_FULL_BODY,
+            fbb.finished_data().to_vec(),
+        );
+        assert_eq!(data.unwrap(), vec![0u8, 1, 2, 255]);
+
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let bytes = Bytes::create(&mut fbb, &BytesArgs::default());
+        fbb.finish(bytes, None);
+        let data = decode_call(methods::WEBSOCKET_SEND_BINARY, fbb.finished_data().to_vec());
+        assert!(data.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_close() {
+        let data = decode_call(methods::WEBSOCKET_CLOSE, close(1000, Some("bye"))).unwrap();
+        assert_eq!(data, b"\x03\xe8bye");
+        let data = decode_call(methods::WEBSOCKET_CLOSE, close(4001, None)).unwrap();
+        assert_eq!(data, 4001u16.to_be_bytes());
+        let data = decode_call(methods::WEBSOCKET_CLOSE, close(0, Some("ignored"))).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_decode_empty_and_raw() {
+        assert!(
+            decode_call(methods::READ_REQUEST_PATH, vec![])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            decode_call(methods::WEBSOCKET_CLOSE, vec![])
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let status = ResponseStatus::create(&mut fbb, &ResponseStatusArgs { status: 404 });
+        fbb.finish(status, None);
+        let data = fbb.finished_data().to_vec();
+        assert_eq!(
+            decode_call(methods::SET_RESPONSE_STATUS, data.clone()).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode_call(methods::SET_REQUEST_PATH, b"/raw/path".to_vec()).is_err());
+        assert!(decode_call(methods::WEBSOCKET_CLOSE, vec![0x03, 0xe8]).is_err());
+    }
+
+    #[test]
+    fn test_encode_text() {
+        let reply = encode_reply(methods::READ_REQUEST_PATH, b"/users").unwrap();
+        assert_eq!(flatbuffers::root::<Text>(&reply).unwrap().value(), "/users");
+        let reply = encode_reply(methods::READ_REQUEST_HEADER, b"").unwrap();
+        assert_eq!(flatbuffers::root::<Text>(&reply).unwrap().value(), "");
+        let reply = encode_reply(methods::WEBSOCKET_ON_CLOSE, br#"{"code":1000}"#).unwrap();
+        assert_eq!(
+            flatbuffers::root::<Text>(&reply).unwrap().value(),
+            r#"{"code":1000}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_bytes() {
+        let reply = encode_reply(methods::READ_REQUEST_FULL_BODY, &[0, 159, 146, 150]).unwrap();
+        let bytes = flatbuffers::root::<Bytes>(&reply).unwrap();
+        assert_eq!(bytes.data().unwrap().bytes(), &[0, 159, 146, 150]);
+    }
+
+    #[test]
+    fn test_encode_number() {
+        let reply = encode_reply(methods::READ_REQUEST_TIMESTAMP, b"1760000000000").unwrap();
+        assert_eq!(
+            flatbuffers::root::<Number>(&reply).unwrap().value(),
+            1_760_000_000_000
+        );
+        let reply = encode_reply(methods::READ_REQUEST_BYTES, b"-1").unwrap();
+        assert_eq!(flatbuffers::root::<Number>(&reply).unwrap().value(), -1);
+        assert!(encode_reply(methods::WEBSOCKET_ROOM_SIZE, b"many").is_err());
+    }
+
+    #[test]
+    fn test_encode_body_chunk() {
+        let reply = encode_reply(methods::READ_REQUEST_BODY_CHUNK, b"\x01tail").unwrap();
+        let chunk = flatbuffers::root::<BodyChunk>(&reply).unwrap();
+        assert!(chunk.last());
+        assert_eq!(chunk.data().unwrap().bytes(), b"tail");
+
+        let reply = encode_reply(methods::READ_REQUEST_BODY_CHUNK, b"\x00").unwrap();
+        let chunk = flatbuffers::root::<BodyChunk>(&reply).unwrap();
+        assert!(!chunk.last());
+        assert!(chunk.data().unwrap().bytes().is_empty());
+
+        assert!(encode_reply(methods::READ_REQUEST_BODY_CHUNK, b"").is_err());
+    }
+
+    #[test]
+    fn test_encode_raw() {
+        let reply = encode_reply(methods::READ_RESPONSE_HEADERS, b"table").unwrap();
+        assert!(matches!(reply, Cow::Borrowed(b"table")));
+        let reply = encode_reply(methods::NEGOTIATE_ABI, b"table").unwrap();
+        assert!(matches!(reply, Cow::Borrowed(_)));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{constants::builtin_plugins, types::BuiltinPlugin};
 use dashmap::DashMap;
 use nylon_error::NylonError;
-use nylon_types::plugins::FfiPlugin;
+use nylon_types::plugins::PluginBackend;
 use std::sync::Arc;
 
 pub struct PluginManager;
@@ -13,21 +13,50 @@ impl PluginManager {
             builtin_plugins::RESPONSE_HEADER_MODIFIER => {
                 Some(BuiltinPlugin::ResponseHeaderModifier)
             }
+            builtin_plugins::QUERY_TOKEN_AUTH => Some(BuiltinPlugin::QueryTokenAuth),
+            builtin_plugins::REPLAY_PROTECTION => Some(BuiltinPlugin::ReplayProtection),
+            builtin_plugins::RESPONSE_WATERMARK => Some(BuiltinPlugin::ResponseWatermark),
+            builtin_plugins::LUA => Some(BuiltinPlugin::Lua),
+            builtin_plugins::BODY_TRANSFORM => Some(BuiltinPlugin::BodyTransform),
+            builtin_plugins::WAF => Some(BuiltinPlugin::Waf),
+            builtin_plugins::BOT_GUARD => Some(BuiltinPlugin::BotGuard),
+            builtin_plugins::REQUEST_DECOMPRESSION => Some(BuiltinPlugin::RequestDecompression),
+            builtin_plugins::OIDC => Some(BuiltinPlugin::Oidc),
             _ => None,
         }
     }
 
+    /// Check the payload of a builtin plugin when the config loads
+    pub fn check_payload(name: &str, payload: Option<&serde_json::Value>) -> Result<(), String> {
+        match Self::try_builtin(name) {
+            Some(BuiltinPlugin::Oidc) => crate::native::oidc::check_payload(payload),
+            _ => Ok(()),
+        }
+    }
+
     pub fn is_request_filter(name: &str) -> bool {
-        matches!(name, builtin_plugins::REQUEST_HEADER_MODIFIER)
+        matches!(
+            name,
+            builtin_plugins::REQUEST_HEADER_MODIFIER
+                | builtin_plugins::QUERY_TOKEN_AUTH
+                | builtin_plugins::REPLAY_PROTECTION
+                | builtin_plugins::WAF
+                | builtin_plugins::BOT_GUARD
+                | builtin_plugins::REQUEST_DECOMPRESSION
+                | builtin_plugins::OIDC
+        )
     }
 
     pub fn is_response_filter(name: &str) -> bool {
-        matches!(name, builtin_plugins::RESPONSE_HEADER_MODIFIER)
+        matches!(
+            name,
+            builtin_plugins::RESPONSE_HEADER_MODIFIER | builtin_plugins::RESPONSE_WATERMARK
+        )
     }
 
-    pub fn get_plugin(name: &str) -> Result<Arc<FfiPlugin>, NylonError> {
+    pub fn get_plugin(name: &str) -> Result<Arc<dyn PluginBackend>, NylonError> {
         let Some(plugins) =
-            &nylon_store::get::<DashMap<String, Arc<FfiPlugin>>>(nylon_store::KEY_PLUGINS)
+            &nylon_store::get::<DashMap<String, Arc<dyn PluginBackend>>>(nylon_store::KEY_PLUGINS)
         else {
             return Err(NylonError::ConfigError("Plugins not found".to_string()));
         };
@@ -1,14 +1,22 @@
-use crate::{constants::methods, stream::PluginSessionStream, types::PluginResult};
+use crate::{
+    constants::{abi, methods},
+    fetch::{self, FetchRequest, FetchResponse},
+    payloads,
+    stream::{self, PluginSessionStream},
+    types::PluginResult,
+};
 use base64::Engine;
 use bytes::Bytes;
 use http::{HeaderMap, HeaderValue};
 use nylon_error::NylonError;
 use nylon_sdk::fbs::plugin_generated::nylon_plugin::{
-    HeaderKeyValue, HeaderKeyValueArgs, NylonHttpHeaders, NylonHttpHeadersArgs,
+    AbiVersion, AbiVersionArgs, HeaderKeyValue, HeaderKeyValueArgs, Metric, NylonHttpHeaders,
+    NylonHttpHeadersArgs, RemoveResponseHeader, ResponseStatus, ResponseStatusArgs, RoomMessage,
 };
-use nylon_types::plugins::PluginPhase;
+use nylon_types::plugins::{PluginPermission, PluginPhase};
 use nylon_types::websocket::WebSocketMessage;
 use nylon_types::{
+    buffer_pool,
     context::NylonContext,
     plugins::SessionStream,
     template::{Expr, apply_payload_ast},
@@ -20,11 +28,68 @@ use pingora::{
 };
 use sha1::{Digest, Sha1};
 use std::collections::HashMap;
-use tokio::sync::mpsc;
 
 /// Handles session stream operations for plugins
 pub struct SessionHandler;
 
+/// What a plugin with a `permissions` list needs to call a method
+#[derive(Debug, PartialEq)]
+enum Access {
+    /// Reads of request metadata, metrics and session control
+    Open,
+    Requires(PluginPermission),
+    /// Not a method a plugin calls
+    Denied,
+}
+
+/// Access needed for `method`; anything not listed is denied
+fn access(method: u32) -> Access {
+    match method {
+        methods::NEXT
+        | methods::GET_PAYLOAD
+        | methods::NEGOTIATE_ABI
+        | methods::READ_RESPONSE_STATUS..=methods::READ_RESPONSE_ERROR
+        | methods::READ_REQUEST_HEADER..=methods::READ_ROUTE_INFO
+        | methods::SUBSCRIBE_UPSTREAM_PHASES
+        | methods::READ_UPSTREAM_CONNECTION
+        | methods::METRIC_INCR
+        | methods::METRIC_OBSERVE => Access::Open,
+        methods::READ_REQUEST_FULL_BODY
+        | methods::READ_REQUEST_BODY_STREAM
+        | methods::READ_REQUEST_BODY_CHUNK
+        | methods::READ_RESPONSE_FULL_BODY => Access::Requires(PluginPermission::ReadBody),
+        methods::SET_REQUEST_BODY_CHUNK
+        | methods::SET_REQUEST_PATH
+        | methods::SET_REQUEST_METHOD
+        | methods::SET_REQUEST_QUERY
+        | methods::SET_REQUEST_HEADER => Access::Requires(PluginPermission::WriteRequest),
+        // Ending the request answers it with the response the plugin set
+        methods::END | methods::SET_RESPONSE_HEADER..=methods::SET_RESPONSE_STREAM_HEADER => {
+            Access::Requires(PluginPermission::WriteResponse)
+        }
+        methods::SET_UPSTREAM => Access::Requires(PluginPermission::Upstream),
+        methods::HTTP_FETCH => Access::Requires(PluginPermission::Fetch),
+        methods::WEBSOCKET_UPGRADE..=methods::WEBSOCKET_BROADCAST_ALL_BINARY => {
+            Access::Requires(PluginPermission::Websocket)
+        }
+        _ => Access::Denied,
+    }
+}
+
+/// Bytes a FlatBuffers builder starts with when the message is small
+const MIN_FBS_BUFFER: usize = 256;
+
+/// A FlatBuffers builder writing into a pooled buffer; put the buffer back once sent
+///
+/// The builder writes from the end of its buffer and expects it zeroed, so
+/// only `size_hint` bytes are zeroed up front rather than the whole pooled
+/// capacity; the builder doubles them if the message needs more.
+fn fbs_builder(size_hint: usize) -> flatbuffers::FlatBufferBuilder<'static> {
+    let mut buf = buffer_pool::take();
+    buf.resize(size_hint.max(MIN_FBS_BUFFER), 0);
+    flatbuffers::FlatBufferBuilder::from_vec(buf)
+}
+
 impl SessionHandler {
     fn build_ws_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
         let mut frame = Vec::with_capacity(2 + payload.len() + 8);
@@ -52,6 +117,8 @@ impl SessionHandler {
         ctx: &mut NylonContext,
         session: &mut Session,
         session_stream: &SessionStream,
+        plugin_name: &str,
+        permissions: Option<&[PluginPermission]>,
         payload: &Option<serde_json::Value>,
         payload_ast: &Option<HashMap<String, Vec<Expr>>>,
         response_body: &Option<Bytes>,
@@ -61,6 +128,31 @@ impl SessionHandler {
         <T as ProxyHttp>::CTX: Send + Sync + From<NylonContext>,
     {
         // println!("method: {}, sid: {}", method, session_stream.session_id);
+        if let Some(granted) = permissions {
+            match access(method) {
+                Access::Open => {}
+                Access::Requires(permission) if granted.contains(&permission) => {}
+                Access::Requires(permission) => {
+                    return Err(NylonError::ConfigError(format!(
+                        "plugin session {} called method {} without the {} permission",
+                        session_stream.session_id,
+                        method,
+                        permission.name()
+                    )));
+                }
+                Access::Denied => {
+                    return Err(NylonError::ConfigError(format!(
+                        "plugin session {} called unknown method {}",
+                        session_stream.session_id, method
+                    )));
+                }
+            }
+        }
+        let data = if stream::abi_version(session_stream.session_id) >= abi::V2 {
+            payloads::decode_call(method, data)?
+        } else {
+            data
+        };
         match method {
             // Control methods
             methods::GET_PAYLOAD => {
@@ -70,6 +162,10 @@ impl SessionHandler {
             }
             methods::NEXT => Ok(Some(PluginResult::default())),
             methods::END => Ok(Some(PluginResult::new(true, false))),
+            methods::NEGOTIATE_ABI => {
+                Self::handle_negotiate_abi(&data, session_stream).await?;
+                Ok(None)
+            }
 
             // Response methods
             methods::SET_RESPONSE_HEADER => {
@@ -77,11 +173,11 @@ impl SessionHandler {
                 Ok(None)
             }
             methods::REMOVE_RESPONSE_HEADER => {
-                Self::handle_remove_response_header(&data, ctx).await?;
+                Self::handle_remove_response_header(&data, ctx, session_stream).await?;
                 Ok(None)
             }
             methods::SET_RESPONSE_STATUS => {
-                Self::handle_set_response_status(&data, ctx).await?;
+                Self::handle_set_response_status(&data, ctx, session_stream).await?;
                 Ok(None)
             }
             methods::SET_RESPONSE_FULL_BODY => {
@@ -146,6 +242,54 @@ impl SessionHandler {
                 Self::handle_read_request_method(session_stream, session).await?;
                 Ok(None)
             }
+            methods::READ_ROUTE_INFO => {
+                Self::handle_read_route_info(session_stream, ctx).await?;
+                Ok(None)
+            }
+            methods::READ_REQUEST_BODY_STREAM => {
+                Self::handle_read_request_body_stream(session_stream, ctx).await?;
+                Ok(None)
+            }
+            methods::READ_REQUEST_BODY_CHUNK => {
+                Self::handle_read_request_body_chunk(session_stream, ctx).await?;
+                Ok(None)
+            }
+            methods::SET_REQUEST_BODY_CHUNK => {
+                Self::handle_set_request_body_chunk(data, ctx).await?;
+                Ok(None)
+            }
+            methods::SET_REQUEST_PATH => {
+                Self::handle_set_request_path(&data, session).await?;
+                Ok(None)
+            }
+            methods::SET_REQUEST_METHOD => {
+                Self::handle_set_request_method(&data, session).await?;
+                Ok(None)
+            }
+            methods::SET_REQUEST_QUERY => {
+                Self::handle_set_request_query(&data, ctx, session).await?;
+                Ok(None)
+            }
+            methods::SET_REQUEST_HEADER => {
+                Self::handle_set_request_header(&data, session).await?;
+                Ok(None)
+            }
+            methods::SET_UPSTREAM => {
+                Self::handle_set_upstream(&data, ctx).await?;
+                Ok(None)
+            }
+            methods::HTTP_FETCH => {
+                Self::handle_http_fetch(&data, session_stream).await?;
+                Ok(None)
+            }
+            methods::SUBSCRIBE_UPSTREAM_PHASES => {
+                Self::handle_subscribe_upstream_phases(session_stream, ctx).await?;
+                Ok(None)
+            }
+            methods::READ_UPSTREAM_CONNECTION => {
+                Self::handle_read_upstream_connection(session_stream, ctx).await?;
+                Ok(None)
+            }
             methods::READ_RESPONSE_STATUS => {
                 Self::handle_read_response_status(session_stream, ctx).await?;
                 Ok(None)
@@ -186,21 +330,35 @@ impl SessionHandler {
                     .unwrap_or("");
                 if key.is_empty() {
                     // Fallback text response if no key
-                    let mut headers = ResponseHeader::build(400u16, None)
-                        .map_err(|e| NylonError::ConfigError(format!("Invalid headers: {}", e)))?;
-                    let _ = headers.append_header("content-type", "text/plain");
-                    let tasks = vec![
-                        HttpTask::Header(Box::new(headers), false),
-                        HttpTask::Body(
-                            Some(Bytes::from_static(b"Missing Sec-WebSocket-Key")),
-                            false,
-                        ),
-                        HttpTask::Done,
-                    ];
-                    session.response_duplex_vec(tasks).await.map_err(|e| {
-                        NylonError::ConfigError(format!("Error sending response: {}", e))
-                    })?;
-                    return Ok(Some(PluginResult::new(true, false)));
+                    return Self::reject_upgrade(session, 400, "Missing Sec-WebSocket-Key").await;
+                }
+
+                // Connection limits of this node
+                let route = ctx.route.clone();
+                let (route_name, route_limit) = route
+                    .map(|route| (route.name.clone(), route.websocket_max_connections))
+                    .unwrap_or_default();
+                let client_ip = ctx.client_ip.clone();
+                match nylon_store::websockets::acquire_connection_slot(
+                    &route_name,
+                    route_limit,
+                    &client_ip,
+                ) {
+                    Ok(slot) => stream::hold_ws_slot(session_stream.session_id, slot),
+                    Err(limit) => {
+                        tracing::warn!(
+                            "WebSocket upgrade on route {} from {} rejected: {} connection limit reached",
+                            route_name,
+                            client_ip,
+                            limit
+                        );
+                        return Self::reject_upgrade(
+                            session,
+                            503,
+                            "Too many WebSocket connections",
+                        )
+                        .await;
+                    }
                 }
 
                 // Compute Sec-WebSocket-Accept
@@ -215,6 +373,11 @@ impl SessionHandler {
                 let _ = resp.append_header("upgrade", "websocket");
                 let _ = resp.append_header("connection", "Upgrade");
                 let _ = resp.append_header("sec-websocket-accept", &accept_key);
+                let protocol = ctx.websocket_protocol.clone();
+                if let Some(protocol) = &protocol {
+                    let _ = resp.append_header("sec-websocket-protocol", protocol);
+                }
+                let protocols = Self::offered_protocols(session);
 
                 session
                     .response_duplex_vec(vec![HttpTask::Header(Box::new(resp), false)])
@@ -231,6 +394,11 @@ impl SessionHandler {
                         .unwrap_or_else(|_| "node".into()),
                     session_stream.session_id
                 );
+                let open = serde_json::json!({
+                    "connection_id": connection_id,
+                    "protocols": protocols,
+                    "protocol": protocol,
+                });
                 let connection = nylon_types::websocket::WebSocketConnection {
                     id: connection_id.clone(),
                     session_id: session_stream.session_id,
@@ -243,18 +411,18 @@ impl SessionHandler {
                 };
                 let _ = nylon_store::websockets::add_connection(connection).await;
 
-                // local rx for cluster events
-                let (tx, rx): (
-                    mpsc::UnboundedSender<nylon_types::websocket::WebSocketMessage>,
-                    mpsc::UnboundedReceiver<nylon_types::websocket::WebSocketMessage>,
-                ) = mpsc::unbounded_channel();
-                nylon_store::websockets::register_local_sender(connection_id.clone(), tx);
-                // store ws rx per session for use in outer event loop if needed
-                let _ = crate::stream::set_ws_rx(session_stream.session_id, rx).await;
+                // bounded local queue for cluster events
+                let queue = nylon_store::websockets::register_local_sender(connection_id.clone());
+                // store the queue per session for the outer event loop
+                let _ = crate::stream::set_ws_rx(session_stream.session_id, queue).await;
 
                 // Notify plugin side that WebSocket connection is established immediately
                 let _ = session_stream
-                    .event_stream(PluginPhase::Zero, methods::WEBSOCKET_ON_OPEN, &[])
+                    .event_stream(
+                        PluginPhase::Zero,
+                        methods::WEBSOCKET_ON_OPEN,
+                        open.to_string().as_bytes(),
+                    )
                     .await;
 
                 // Spawn task to forward cluster messages to client frames
@@ -263,6 +431,81 @@ impl SessionHandler {
                 // Keep session open (wait for future events)
                 Ok(None)
             }
+            methods::WEBSOCKET_SELECT_PROTOCOL => {
+                let protocol = String::from_utf8_lossy(&data).trim().to_string();
+                if !Self::offered_protocols(session).contains(&protocol) {
+                    return Err(NylonError::ConfigError(format!(
+                        "plugin selected WebSocket subprotocol {:?} the client did not offer",
+                        protocol
+                    )));
+                }
+                ctx.websocket_protocol = Some(protocol);
+                Ok(None)
+            }
+            methods::WEBSOCKET_SET_CONNECTION_METADATA => {
+                let metadata: HashMap<String, String> = serde_json::from_slice(&data)
+                    .map_err(|e| NylonError::ConfigError(format!("Invalid metadata: {}", e)))?;
+                let conn_id = Self::connection_id(session_stream).await;
+                nylon_store::websockets::set_connection_metadata(&conn_id, metadata).await?;
+                Ok(None)
+            }
+            methods::WEBSOCKET_READ_CONNECTION_METADATA => {
+                // Empty payload reads the metadata of this connection
+                let conn_id = match String::from_utf8_lossy(&data).trim() {
+                    "" => Self::connection_id(session_stream).await,
+                    id => id.to_string(),
+                };
+                let metadata = nylon_store::websockets::get_connection(&conn_id)
+                    .await?
+                    .map(|connection| connection.metadata);
+                let reply = serde_json::to_vec(&metadata).unwrap_or_default();
+                session_stream
+                    .event_stream(
+                        PluginPhase::Zero,
+                        methods::WEBSOCKET_READ_CONNECTION_METADATA,
+                        &reply,
+                    )
+                    .await?;
+                Ok(None)
+            }
+            methods::WEBSOCKET_ROOM_SIZE => {
+                let room = String::from_utf8_lossy(&data).to_string();
+                let size = nylon_store::websockets::room_size(&room).await?;
+                session_stream
+                    .event_stream(
+                        PluginPhase::Zero,
+                        methods::WEBSOCKET_ROOM_SIZE,
+                        size.to_string().as_bytes(),
+                    )
+                    .await?;
+                Ok(None)
+            }
+            methods::WEBSOCKET_ROOM_MEMBERS => {
+                let room = String::from_utf8_lossy(&data).to_string();
+                let members: Vec<_> = nylon_store::websockets::room_members(&room)
+                    .await?
+                    .into_iter()
+                    .map(|member| {
+                        serde_json::json!({
+                            "id": member.id,
+                            "node_id": member.node_id,
+                            "connected_at": member.connected_at,
+                            "metadata": member.metadata,
+                        })
+                    })
+                    .collect();
+                let reply = serde_json::to_vec(&members).unwrap_or_default();
+                session_stream
+                    .event_stream(PluginPhase::Zero, methods::WEBSOCKET_ROOM_MEMBERS, &reply)
+                    .await?;
+                Ok(None)
+            }
+            methods::WEBSOCKET_WATCH_PRESENCE => {
+                if let Ok(queue) = crate::stream::get_ws_rx(session_stream.session_id) {
+                    queue.watch_presence();
+                }
+                Ok(None)
+            }
             methods::WEBSOCKET_SEND_TEXT => {
                 // Send a text frame to client
                 let frame = Self::build_ws_frame(0x1, &data);
@@ -282,8 +525,15 @@ impl SessionHandler {
                 Ok(None)
             }
             methods::WEBSOCKET_CLOSE => {
+                // Optional body: status code and reason, as in a close frame
+                if data.len() > 125 || !crate::valid_close_payload(&data) {
+                    return Err(NylonError::ConfigError(format!(
+                        "plugin sent an invalid WebSocket close body of {} bytes",
+                        data.len()
+                    )));
+                }
                 // Send close frame to client
-                let frame = Self::build_ws_frame(0x8, &[]);
+                let frame = Self::build_ws_frame(0x8, &data);
                 let tasks = vec![
                     HttpTask::Body(Some(Bytes::from(frame)), false),
                     HttpTask::Done,
@@ -292,30 +542,16 @@ impl SessionHandler {
                     NylonError::ConfigError(format!("Error sending WS close: {}", e))
                 })?;
 
-                // Notify plugin that connection is closing
+                // Notify plugin that connection is closing and cleanup adapter registration
                 // Spawn task to ensure event is sent before connection cleanup
+                let (code, reason) = crate::parse_close_payload(&data);
                 tokio::spawn({
                     let session_stream = session_stream.clone();
                     async move {
-                        let _ = session_stream
-                            .event_stream(PluginPhase::Zero, methods::WEBSOCKET_ON_CLOSE, &[])
-                            .await;
+                        let _ = crate::websocket_closed(&session_stream, code, &reason).await;
                     }
                 });
 
-                // Cleanup adapter registration
-                let conn_id = format!(
-                    "{}:{}",
-                    nylon_store::websockets::get_node_id()
-                        .await
-                        .unwrap_or_default(),
-                    session_stream.session_id
-                );
-                nylon_store::websockets::unregister_local_sender(&conn_id);
-                tokio::spawn(async move {
-                    let _ = nylon_store::websockets::remove_connection(&conn_id).await;
-                });
-
                 // End the session
                 Ok(Some(PluginResult::new(false, true)))
             }
@@ -350,7 +586,7 @@ impl SessionHandler {
                 Ok(None)
             }
             methods::WEBSOCKET_BROADCAST_ROOM_TEXT => {
-                if let Some((room, payload)) = Self::split_room_payload(&data) {
+                if let Some((room, payload)) = Self::split_room_payload(&data, session_stream)? {
                     let message = String::from_utf8_lossy(&payload).to_string();
                     let _ = nylon_store::websockets::broadcast_to_room(
                         &room,
@@ -362,7 +598,7 @@ impl SessionHandler {
                 Ok(None)
             }
             methods::WEBSOCKET_BROADCAST_ROOM_BINARY => {
-                if let Some((room, payload)) = Self::split_room_payload(&data) {
+                if let Some((room, payload)) = Self::split_room_payload(&data, session_stream)? {
                     let _ = nylon_store::websockets::broadcast_to_room(
                         &room,
                         WebSocketMessage::Binary(payload),
@@ -372,6 +608,39 @@ impl SessionHandler {
                 }
                 Ok(None)
             }
+            methods::WEBSOCKET_BROADCAST_ALL_TEXT | methods::WEBSOCKET_BROADCAST_ALL_BINARY => {
+                // Same layout as room broadcasts, with the connection to skip (or nothing) in place of the room
+                let Some((exclude, payload)) = Self::split_target_payload(&data, session_stream)?
+                else {
+                    return Ok(None);
+                };
+                let exclude = Some(exclude).filter(|id| !id.is_empty());
+                let message = if method == methods::WEBSOCKET_BROADCAST_ALL_TEXT {
+                    WebSocketMessage::Text(String::from_utf8_lossy(&payload).to_string())
+                } else {
+                    WebSocketMessage::Binary(payload)
+                };
+                let _ = nylon_store::websockets::broadcast_all(message, exclude.as_deref()).await;
+                Ok(None)
+            }
+
+            // Metric methods
+            methods::METRIC_INCR | methods::METRIC_OBSERVE => {
+                // A bad metric is dropped, it never fails the request
+                let recorded = flatbuffers::root::<Metric>(&data)
+                    .map_err(|e| NylonError::ConfigError(format!("Invalid metric: {}", e)))
+                    .and_then(|metric| {
+                        if method == methods::METRIC_INCR {
+                            crate::metrics::incr(plugin_name, metric.name(), metric.value())
+                        } else {
+                            crate::metrics::observe(plugin_name, metric.name(), metric.value())
+                        }
+                    });
+                if let Err(e) = recorded {
+                    tracing::warn!("plugin {}: {}", plugin_name, e);
+                }
+                Ok(None)
+            }
 
             // Unknown method
             _ => Err(NylonError::ConfigError(format!(
@@ -381,16 +650,108 @@ impl SessionHandler {
         }
     }
 
-    /// Split room and payload using a NUL (0x00) delimiter: [room_bytes, 0x00, payload_bytes]
-    fn split_room_payload(data: &[u8]) -> Option<(String, Vec<u8>)> {
-        if let Some(pos) = data.iter().position(|b| *b == 0) {
-            let room = String::from_utf8_lossy(&data[..pos]).to_string();
-            let payload = data[pos + 1..].to_vec();
-            if !room.is_empty() {
-                return Some((room, payload));
-            }
+    /// Answer a WebSocket upgrade request with a plain text error
+    async fn reject_upgrade(
+        session: &mut Session,
+        status: u16,
+        message: &'static str,
+    ) -> Result<Option<PluginResult>, NylonError> {
+        let mut headers = ResponseHeader::build(status, None)
+            .map_err(|e| NylonError::ConfigError(format!("Invalid headers: {}", e)))?;
+        let _ = headers.append_header("content-type", "text/plain");
+        let tasks = vec![
+            HttpTask::Header(Box::new(headers), false),
+            HttpTask::Body(Some(Bytes::from_static(message.as_bytes())), false),
+            HttpTask::Done,
+        ];
+        session
+            .response_duplex_vec(tasks)
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Error sending response: {}", e)))?;
+        Ok(Some(PluginResult::new(true, false)))
+    }
+
+    /// Adapter id of the WebSocket connection of this session
+    async fn connection_id(session_stream: &SessionStream) -> String {
+        format!(
+            "{}:{}",
+            nylon_store::websockets::get_node_id()
+                .await
+                .unwrap_or_default(),
+            session_stream.session_id
+        )
+    }
+
+    /// Subprotocols from the client's `Sec-WebSocket-Protocol` headers, in order
+    fn offered_protocols(session: &Session) -> Vec<String> {
+        session
+            .req_header()
+            .headers
+            .get_all("sec-websocket-protocol")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect()
+    }
+
+    /// Split room and payload
+    ///
+    /// v1 uses a NUL (0x00) delimiter: [room_bytes, 0x00, payload_bytes],
+    /// v2 a `RoomMessage` table.
+    fn split_room_payload(
+        data: &[u8],
+        session_stream: &SessionStream,
+    ) -> Result<Option<(String, Vec<u8>)>, NylonError> {
+        Ok(Self::split_target_payload(data, session_stream)?.filter(|(room, _)| !room.is_empty()))
+    }
+
+    /// Split a `RoomMessage` (v2) or `target NUL payload` (v1); the target may be empty
+    fn split_target_payload(
+        data: &[u8],
+        session_stream: &SessionStream,
+    ) -> Result<Option<(String, Vec<u8>)>, NylonError> {
+        if stream::abi_version(session_stream.session_id) >= abi::V2 {
+            let message = flatbuffers::root::<RoomMessage>(data)
+                .map_err(|e| NylonError::ConfigError(format!("Invalid room message: {}", e)))?;
+            let payload = message
+                .data()
+                .map(|d| d.bytes().to_vec())
+                .unwrap_or_default();
+            return Ok(Some((message.room().to_string(), payload)));
         }
-        None
+        Ok(data.iter().position(|b| *b == 0).map(|pos| {
+            (
+                String::from_utf8_lossy(&data[..pos]).to_string(),
+                data[pos + 1..].to_vec(),
+            )
+        }))
+    }
+
+    /// Agree on the highest ABI version both sides support and reply with it
+    async fn handle_negotiate_abi(
+        data: &[u8],
+        session_stream: &SessionStream,
+    ) -> Result<(), NylonError> {
+        let requested = flatbuffers::root::<AbiVersion>(data)
+            .map_err(|e| NylonError::ConfigError(format!("Invalid ABI version: {}", e)))?
+            .version();
+        let version = requested.clamp(abi::V1, abi::LATEST);
+        stream::set_abi_version(session_stream.session_id, version);
+
+        let mut fbs = fbs_builder(0);
+        let reply = AbiVersion::create(&mut fbs, &AbiVersionArgs { version });
+        fbs.finish(reply, None);
+        let sent = session_stream
+            .event_stream(
+                PluginPhase::Zero,
+                methods::NEGOTIATE_ABI,
+                fbs.finished_data(),
+            )
+            .await;
+        buffer_pool::put(fbs.collapse().0);
+        sent
     }
 
     async fn handle_get_payload(
@@ -424,8 +785,6 @@ impl SessionHandler {
         let headers = flatbuffers::root::<HeaderKeyValue>(data)
             .map_err(|e| NylonError::ConfigError(format!("Invalid headers: {}", e)))?;
         ctx.add_response_header
-            .write()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
             .insert(headers.key().to_string(), headers.value().to_string());
         Ok(())
     }
@@ -433,20 +792,31 @@ impl SessionHandler {
     async fn handle_remove_response_header(
         data: &[u8],
         ctx: &mut NylonContext,
+        session_stream: &SessionStream,
     ) -> Result<(), NylonError> {
-        let header_key = String::from_utf8_lossy(data).to_string();
-        ctx.remove_response_header
-            .write()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-            .push(header_key);
+        let header_key = if stream::abi_version(session_stream.session_id) >= abi::V2 {
+            let header = flatbuffers::root::<RemoveResponseHeader>(data)
+                .map_err(|e| NylonError::ConfigError(format!("Invalid header: {}", e)))?;
+            header.key().unwrap_or_default().to_string()
+        } else {
+            String::from_utf8_lossy(data).to_string()
+        };
+        ctx.remove_response_header.push(header_key);
         Ok(())
     }
 
     async fn handle_set_response_status(
         data: &[u8],
         ctx: &mut NylonContext,
+        session_stream: &SessionStream,
     ) -> Result<(), NylonError> {
-        if data.len() >= 2 {
+        if stream::abi_version(session_stream.session_id) >= abi::V2 {
+            let status = flatbuffers::root::<ResponseStatus>(data)
+                .map_err(|e| NylonError::ConfigError(format!("Invalid status: {}", e)))?
+                .status();
+            ctx.set_response_status
+                .store(status, std::sync::atomic::Ordering::Relaxed);
+        } else if data.len() >= 2 {
             let status = u16::from_be_bytes([data[0], data[1]]);
             ctx.set_response_status
                 .store(status, std::sync::atomic::Ordering::Relaxed);
@@ -458,9 +828,7 @@ impl SessionHandler {
         data: Vec<u8>,
         ctx: &mut NylonContext,
     ) -> Result<(), NylonError> {
-        *ctx.set_response_body
-            .write()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))? = data;
+        ctx.set_response_body = data;
         Ok(())
     }
 
@@ -520,12 +888,7 @@ impl SessionHandler {
         ctx: &mut NylonContext,
         response_body: &Option<Bytes>,
     ) -> Result<(), NylonError> {
-        let mut body = {
-            ctx.set_response_body
-                .read()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-                .clone()
-        };
+        let mut body = ctx.set_response_body.clone();
         if let Some(response_body) = response_body {
             body.extend_from_slice(response_body.as_ref());
         }
@@ -539,28 +902,12 @@ impl SessionHandler {
         ctx: &mut NylonContext,
         session: &mut Session,
     ) -> Result<(), NylonError> {
-        if !session.is_body_empty() && !ctx.read_body.load(std::sync::atomic::Ordering::Relaxed) {
-            ctx.read_body
-                .store(true, std::sync::atomic::Ordering::Relaxed);
-            session.enable_retry_buffering();
-            while let Ok(Some(data)) = session.read_request_body().await {
-                ctx.request_body
-                    .write()
-                    .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-                    .extend_from_slice(&data);
-            }
-        }
-        let req_body = {
-            ctx.request_body
-                .read()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-                .clone()
-        };
+        crate::native::waf::read_body(ctx, session, usize::MAX).await?;
         session_stream
             .event_stream(
                 PluginPhase::Zero,
                 methods::READ_REQUEST_FULL_BODY,
-                &req_body,
+                &ctx.request_body,
             )
             .await
     }
@@ -597,11 +944,16 @@ impl SessionHandler {
         session_stream: &SessionStream,
         session: &mut Session,
     ) -> Result<(), NylonError> {
-        let mut fbs = flatbuffers::FlatBufferBuilder::new();
         let headers: &HeaderMap<HeaderValue> = match session.as_http2() {
             Some(h2) => &h2.req_header().headers,
             None => &session.req_header().headers,
         };
+        // Names and values, plus room for the table of each header
+        let size_hint = headers
+            .iter()
+            .map(|(k, v)| k.as_str().len() + v.len() + 32)
+            .sum::<usize>();
+        let mut fbs = fbs_builder(size_hint);
 
         let headers_vec = headers
             .iter()
@@ -626,10 +978,15 @@ impl SessionHandler {
             },
         );
         fbs.finish(headers, None);
-        let headers = fbs.finished_data();
-        session_stream
-            .event_stream(PluginPhase::Zero, methods::READ_REQUEST_HEADERS, headers)
-            .await
+        let sent = session_stream
+            .event_stream(
+                PluginPhase::Zero,
+                methods::READ_REQUEST_HEADERS,
+                fbs.finished_data(),
+            )
+            .await;
+        buffer_pool::put(fbs.collapse().0);
+        sent
     }
 
     async fn handle_read_request_url(
@@ -640,16 +997,8 @@ impl SessionHandler {
         // Build full URL: scheme://host[:port]/path?query
         let is_tls = ctx.tls.load(std::sync::atomic::Ordering::Relaxed);
         let scheme = if is_tls { "https" } else { "http" };
-        let port = ctx
-            .port
-            .read()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-            .clone();
-        let host = ctx
-            .host
-            .read()
-            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-            .clone();
+        let port = ctx.port.clone();
+        let host = ctx.host.clone();
 
         let host_part = if !port.is_empty() && !["80", "443"].contains(&port.as_str()) {
             format!("{}:{}", host, port)
@@ -716,14 +1065,8 @@ impl SessionHandler {
         session_stream: &SessionStream,
         ctx: &NylonContext,
     ) -> Result<(), NylonError> {
-        let params_json = {
-            let params = ctx
-                .params
-                .read()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-            serde_json::to_vec(&*params)
-                .map_err(|e| NylonError::InternalServerError(format!("serialize error: {}", e)))?
-        };
+        let params_json = serde_json::to_vec(&ctx.params)
+            .map_err(|e| NylonError::InternalServerError(format!("serialize error: {}", e)))?;
         session_stream
             .event_stream(
                 PluginPhase::Zero,
@@ -737,12 +1080,7 @@ impl SessionHandler {
         session_stream: &SessionStream,
         ctx: &NylonContext,
     ) -> Result<(), NylonError> {
-        let host = {
-            ctx.host
-                .read()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-                .clone()
-        };
+        let host = ctx.host.clone();
         session_stream
             .event_stream(
                 PluginPhase::Zero,
@@ -756,12 +1094,7 @@ impl SessionHandler {
         session_stream: &SessionStream,
         ctx: &NylonContext,
     ) -> Result<(), NylonError> {
-        let client_ip = {
-            ctx.client_ip
-                .read()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?
-                .clone()
-        };
+        let client_ip = ctx.client_ip.clone();
         session_stream
             .event_stream(
                 PluginPhase::Zero,
@@ -788,6 +1121,190 @@ impl SessionHandler {
             .await
     }
 
+    /// Deliver the request body to this session chunk by chunk in the
+    /// `RequestBodyFilter` phase, as it is forwarded upstream
+    async fn handle_read_request_body_stream(
+        session_stream: &SessionStream,
+        ctx: &mut NylonContext,
+    ) -> Result<(), NylonError> {
+        ctx.request_body_streams.insert(session_stream.session_id);
+        Ok(())
+    }
+
+    /// Current chunk, prefixed with one byte that is 1 on the last chunk
+    async fn handle_read_request_body_chunk(
+        session_stream: &SessionStream,
+        ctx: &mut NylonContext,
+    ) -> Result<(), NylonError> {
+        let chunk = ctx.request_body_chunk.clone();
+        let mut data = Vec::with_capacity(chunk.len() + 1);
+        data.push(
+            ctx.request_body_end
+                .load(std::sync::atomic::Ordering::Relaxed) as u8,
+        );
+        data.extend_from_slice(&chunk);
+        session_stream
+            .event_stream(PluginPhase::Zero, methods::READ_REQUEST_BODY_CHUNK, &data)
+            .await
+    }
+
+    async fn handle_set_request_body_chunk(
+        data: Vec<u8>,
+        ctx: &mut NylonContext,
+    ) -> Result<(), NylonError> {
+        ctx.request_body_chunk = Bytes::from(data);
+        Ok(())
+    }
+
+    /// Replace the path and query of the request sent upstream
+    fn rewrite_uri(
+        session: &mut Session,
+        path: &str,
+        query: Option<&str>,
+    ) -> Result<(), NylonError> {
+        let req = session.req_header_mut();
+        let path_and_query = match query {
+            Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+            _ => path.to_string(),
+        };
+        let mut parts = req.uri.clone().into_parts();
+        parts.path_and_query = Some(
+            http::uri::PathAndQuery::try_from(path_and_query.as_str())
+                .map_err(|e| NylonError::ConfigError(format!("Invalid request path: {}", e)))?,
+        );
+        let uri = http::Uri::from_parts(parts)
+            .map_err(|e| NylonError::ConfigError(format!("Invalid request uri: {}", e)))?;
+        req.set_uri(uri);
+        Ok(())
+    }
+
+    async fn handle_set_request_path(data: &[u8], session: &mut Session) -> Result<(), NylonError> {
+        let path = String::from_utf8_lossy(data).to_string();
+        if !path.starts_with('/') {
+            return Err(NylonError::ConfigError(format!(
+                "Invalid request path from plugin: {}",
+                path
+            )));
+        }
+        let query = session.req_header().uri.query().map(str::to_string);
+        Self::rewrite_uri(session, &path, query.as_deref())
+    }
+
+    async fn handle_set_request_query(
+        data: &[u8],
+        ctx: &mut NylonContext,
+        session: &mut Session,
+    ) -> Result<(), NylonError> {
+        let query = String::from_utf8_lossy(data).to_string();
+        let path = session.req_header().uri.path().to_string();
+        Self::rewrite_uri(session, &path, Some(query.trim_start_matches('?')))?;
+        // templates evaluated later must see the new query
+        ctx.cached_query.take();
+        Ok(())
+    }
+
+    async fn handle_set_request_method(
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), NylonError> {
+        let method = http::Method::from_bytes(data).map_err(|_| {
+            NylonError::ConfigError(format!(
+                "Invalid request method from plugin: {}",
+                String::from_utf8_lossy(data)
+            ))
+        })?;
+        session.req_header_mut().set_method(method);
+        Ok(())
+    }
+
+    /// Set a header of the request sent upstream; an empty value removes it
+    async fn handle_set_request_header(
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), NylonError> {
+        let header = flatbuffers::root::<HeaderKeyValue>(data)
+            .map_err(|e| NylonError::ConfigError(format!("Invalid headers: {}", e)))?;
+        let name = header.key().to_ascii_lowercase();
+        let req = session.req_header_mut();
+        let _ = req.remove_header(&name);
+        if !header.value().is_empty() {
+            req.insert_header(name, header.value())
+                .map_err(|e| NylonError::ConfigError(format!("Invalid header: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Pick the upstream of this request; resolved after the request filter
+    async fn handle_set_upstream(data: &[u8], ctx: &mut NylonContext) -> Result<(), NylonError> {
+        let target = String::from_utf8_lossy(data).trim().to_string();
+        ctx.upstream_override = (!target.is_empty()).then_some(target);
+        Ok(())
+    }
+
+    /// Opt in to the upstream_request_filter and connected_to_upstream phases
+    async fn handle_subscribe_upstream_phases(
+        session_stream: &SessionStream,
+        ctx: &mut NylonContext,
+    ) -> Result<(), NylonError> {
+        ctx.upstream_subscribers.insert(session_stream.session_id);
+        Ok(())
+    }
+
+    async fn handle_read_upstream_connection(
+        session_stream: &SessionStream,
+        ctx: &NylonContext,
+    ) -> Result<(), NylonError> {
+        let connection = serde_json::to_vec(&ctx.upstream_connection)
+            .map_err(|e| NylonError::InternalServerError(format!("serialize error: {}", e)))?;
+        session_stream
+            .event_stream(
+                PluginPhase::Zero,
+                methods::READ_UPSTREAM_CONNECTION,
+                &connection,
+            )
+            .await
+    }
+
+    /// Run an outbound request; failures are reported to the plugin, not the client
+    async fn handle_http_fetch(
+        data: &[u8],
+        session_stream: &SessionStream,
+    ) -> Result<(), NylonError> {
+        let res = match serde_json::from_slice::<FetchRequest>(data) {
+            Ok(req) => fetch::fetch(req)
+                .await
+                .unwrap_or_else(|e| FetchResponse::error(e.to_string())),
+            Err(e) => FetchResponse::error(format!("Invalid fetch request: {}", e)),
+        };
+        let res = serde_json::to_vec(&res)
+            .map_err(|e| NylonError::InternalServerError(format!("serialize error: {}", e)))?;
+        session_stream
+            .event_stream(PluginPhase::Zero, methods::HTTP_FETCH, &res)
+            .await
+    }
+
+    async fn handle_read_route_info(
+        session_stream: &SessionStream,
+        ctx: &NylonContext,
+    ) -> Result<(), NylonError> {
+        let route_json = {
+            let info = ctx.route.as_ref().map(|route| {
+                serde_json::json!({
+                    "route": route.name,
+                    "path": route.path,
+                    "service": route.service.name,
+                    "service_type": route.service.service_type,
+                    "rewrite": route.rewrite,
+                })
+            });
+            serde_json::to_vec(&info)
+                .map_err(|e| NylonError::InternalServerError(format!("serialize error: {}", e)))?
+        };
+        session_stream
+            .event_stream(PluginPhase::Zero, methods::READ_ROUTE_INFO, &route_json)
+            .await
+    }
+
     async fn handle_read_response_status(
         session_stream: &SessionStream,
         ctx: &NylonContext,
@@ -795,6 +1312,18 @@ impl SessionHandler {
         let status = ctx
             .set_response_status
             .load(std::sync::atomic::Ordering::Relaxed);
+        if stream::abi_version(session_stream.session_id) >= abi::V2 {
+            let mut fbs = flatbuffers::FlatBufferBuilder::new();
+            let reply = ResponseStatus::create(&mut fbs, &ResponseStatusArgs { status });
+            fbs.finish(reply, None);
+            return session_stream
+                .event_stream(
+                    PluginPhase::Zero,
+                    methods::READ_RESPONSE_STATUS,
+                    fbs.finished_data(),
+                )
+                .await;
+        }
         let status_str = status.to_string();
         session_stream
             .event_stream(
@@ -833,11 +1362,7 @@ impl SessionHandler {
         response_body: &Option<Bytes>,
     ) -> Result<(), NylonError> {
         // Try to get response body length from context
-        let mut bytes: i64 = ctx
-            .set_response_body
-            .read()
-            .map(|body| body.len() as i64)
-            .unwrap_or(0);
+        let mut bytes = ctx.set_response_body.len() as i64;
 
         if let Some(response_body) = response_body {
             bytes += response_body.len() as i64;
@@ -879,13 +1404,7 @@ impl SessionHandler {
         let mut headers_vec = vec![];
 
         // Get response headers from context
-        let headers_map = ctx
-            .add_response_header
-            .read()
-            .map(|h| h.clone())
-            .unwrap_or_default();
-
-        for (key, value) in headers_map.iter() {
+        for (key, value) in ctx.add_response_header.iter() {
             let key_str = builder.create_string(key);
             let value_str = builder.create_string(value);
 
@@ -943,11 +1462,7 @@ impl SessionHandler {
         session_stream: &SessionStream,
         ctx: &NylonContext,
     ) -> Result<(), NylonError> {
-        let error_msg = ctx
-            .error_message
-            .read()
-            .map(|e| e.clone().unwrap_or_default())
-            .unwrap_or_default();
+        let error_msg = ctx.error_message.clone().unwrap_or_default();
         session_stream
             .event_stream(
                 PluginPhase::Zero,
@@ -957,3 +1472,38 @@ impl SessionHandler {
             .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access() {
+        assert_eq!(access(methods::NEXT), Access::Open);
+        assert_eq!(access(methods::READ_REQUEST_HEADERS), Access::Open);
+        assert_eq!(access(methods::READ_RESPONSE_STATUS), Access::Open);
+        assert_eq!(access(methods::METRIC_INCR), Access::Open);
+        assert_eq!(
+            access(methods::END),
+            Access::Requires(PluginPermission::WriteResponse)
+        );
+        assert_eq!(
+            access(methods::READ_REQUEST_FULL_BODY),
+            Access::Requires(PluginPermission::ReadBody)
+        );
+        assert_eq!(
+            access(methods::READ_RESPONSE_FULL_BODY),
+            Access::Requires(PluginPermission::ReadBody)
+        );
+        assert_eq!(
+            access(methods::SET_REQUEST_HEADER),
+            Access::Requires(PluginPermission::WriteRequest)
+        );
+        assert_eq!(
+            access(methods::WEBSOCKET_ROOM_MEMBERS),
+            Access::Requires(PluginPermission::Websocket)
+        );
+        assert_eq!(access(methods::WEBSOCKET_ON_OPEN), Access::Denied);
+        assert_eq!(access(999), Access::Denied);
+    }
+}
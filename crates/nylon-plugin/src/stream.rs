@@ -1,36 +1,62 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::{
+    constants::{abi, methods},
+    payloads,
+};
 use async_trait::async_trait;
+use dashmap::DashMap;
 use nylon_error::NylonError;
-use nylon_types::plugins::{FfiBuffer, FfiPlugin, PluginPhase, SessionStream};
-use nylon_types::websocket::WebSocketMessage;
+use nylon_store::websockets::{ConnectionSlot, SendQueue};
+use nylon_types::plugins::{
+    DEFAULT_EVENT_QUEUE_CAPACITY, FfiBuffer, PluginBackend, PluginItem, PluginPhase,
+    PluginQueueFull, SessionStream,
+};
 use once_cell::sync::Lazy;
 use std::{
+    borrow::Cow,
     collections::HashMap,
     sync::{
         Arc, RwLock,
         atomic::{AtomicU32, Ordering},
     },
 };
-use tokio::sync::Mutex;
-use tokio::sync::mpsc::UnboundedReceiver as UnboundedWsReceiver;
-use tokio::sync::mpsc::{self, UnboundedReceiver};
-use tracing::{debug, trace};
+use tokio::sync::mpsc::{self, Receiver, error::TrySendError};
+use tokio::sync::{Mutex, OwnedSemaphorePermit};
+use tracing::{debug, trace, warn};
 
 // Active sessions
-type SessionSender = mpsc::UnboundedSender<(u32, Vec<u8>)>;
+#[derive(Clone)]
+struct SessionSender {
+    tx: mpsc::Sender<(u32, Vec<u8>)>,
+    plugin: Arc<str>,
+    on_full: PluginQueueFull,
+}
 
 static ACTIVE_SESSIONS: Lazy<RwLock<HashMap<u32, SessionSender>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
-// static SESSION_RX: Lazy<Arc<Mutex<HashMap<u32, Arc<Mutex<UnboundedReceiver<(u32, Vec<u8>)>>>>>>> =
-//     Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
-static SESSION_RX: Lazy<Mutex<HashMap<u32, Arc<Mutex<UnboundedReceiver<(u32, Vec<u8>)>>>>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+// Event receivers of open sessions, dropped when the session closes
+static SESSION_RX: Lazy<RwLock<HashMap<u32, Arc<Mutex<Receiver<(u32, Vec<u8>)>>>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
 
-// WS message receivers per session for cluster/local adapter dispatch
-static SESSION_WS_RX: Lazy<Mutex<HashMap<u32, Arc<Mutex<UnboundedWsReceiver<WebSocketMessage>>>>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+// Events dropped or sessions closed because an event queue was full, by plugin
+static QUEUE_FULL: Lazy<DashMap<String, u64>> = Lazy::new(DashMap::new);
+
+// Plugin session slots, given back when the session closes
+static SESSION_PERMITS: Lazy<RwLock<HashMap<u32, OwnedSemaphorePermit>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// WebSocket connection limit slots, given back when the session closes
+static SESSION_WS_SLOTS: Lazy<RwLock<HashMap<u32, ConnectionSlot>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// ABI version negotiated per session; missing means v1
+static SESSION_ABI: Lazy<RwLock<HashMap<u32, u16>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// WS send queues per session for cluster/local adapter dispatch
+static SESSION_WS_RX: Lazy<RwLock<HashMap<u32, Arc<SendQueue>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
 
 #[unsafe(no_mangle)]
 pub extern "C" fn handle_ffi_event(data: *const FfiBuffer) {
@@ -55,7 +81,7 @@ pub extern "C" fn handle_ffi_event(data: *const FfiBuffer) {
         if ptr.is_null() {
             trace!("handle_ffi_event: null payload");
             // println!("handle_ffi_event: null payload");
-            let _ = sender.send((method, Vec::new()));
+            deliver(&sender, session_id, (method, Vec::new()));
             return;
         }
 
@@ -67,20 +93,69 @@ pub extern "C" fn handle_ffi_event(data: *const FfiBuffer) {
             // println!("handle_ffi_event: buf={:?}", buf);
             // println!("handle_ffi_event: buf len={}", buf.len());
             // println!("handle_ffi_event: buf as string={}", String::from_utf8_lossy(&buf));
-            if let Err(_e) = sender.send((method, buf)) {
-                debug!("send error: {:?}", session_id);
-            }
+            deliver(&sender, session_id, (method, buf));
         }
     } else {
         trace!("handle_ffi_event: no active session for sid={}", session_id);
     }
 }
 
+/// Queue an event for the session, applying the plugin's `on_event_queue_full`
+fn deliver(sender: &SessionSender, session_id: u32, event: (u32, Vec<u8>)) {
+    match sender.tx.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Closed(_)) => debug!("send error: {:?}", session_id),
+        Err(TrySendError::Full((method, _))) => {
+            *QUEUE_FULL.entry(sender.plugin.to_string()).or_insert(0) += 1;
+            if sender.on_full == PluginQueueFull::Drop && droppable(method) {
+                debug!(
+                    "Plugin {} session {}: event queue full, event {} dropped",
+                    sender.plugin, session_id, method
+                );
+            } else {
+                warn!(
+                    "Plugin {} session {}: event queue full, session closed",
+                    sender.plugin, session_id
+                );
+                abort_session(session_id);
+            }
+        }
+    }
+}
+
+/// Events that can be lost without leaving the request waiting or missing its changes
+fn droppable(method: u32) -> bool {
+    matches!(
+        method,
+        methods::METRIC_INCR
+            | methods::METRIC_OBSERVE
+            | methods::WEBSOCKET_SEND_TEXT
+            | methods::WEBSOCKET_SEND_BINARY
+            | methods::WEBSOCKET_BROADCAST_ROOM_TEXT
+            | methods::WEBSOCKET_BROADCAST_ROOM_BINARY
+            | methods::WEBSOCKET_BROADCAST_ALL_TEXT
+            | methods::WEBSOCKET_BROADCAST_ALL_BINARY
+    )
+}
+
+/// Events dropped or sessions closed since start because an event queue was full, by plugin
+pub fn queue_full_events() -> Vec<(String, u64)> {
+    QUEUE_FULL
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
 // === SessionStream trait ===
 #[async_trait]
 pub trait PluginSessionStream {
-    fn new(plugin: Arc<FfiPlugin>, session_id: u32) -> Self;
-    async fn open(&self, entry: &str) -> Result<u32, NylonError>;
+    fn new(plugin: Arc<dyn PluginBackend>, session_id: u32) -> Self;
+    async fn open(
+        &self,
+        entry: &str,
+        plugin_name: &str,
+        item: Option<&PluginItem>,
+    ) -> Result<u32, NylonError>;
     async fn event_stream(
         &self,
         phase: PluginPhase,
@@ -92,7 +167,7 @@ pub trait PluginSessionStream {
 
 #[async_trait]
 impl PluginSessionStream for SessionStream {
-    fn new(plugin: Arc<FfiPlugin>, session_id: u32) -> Self {
+    fn new(plugin: Arc<dyn PluginBackend>, session_id: u32) -> Self {
         if session_id == 0 {
             let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
             Self { plugin, session_id }
@@ -101,39 +176,47 @@ impl PluginSessionStream for SessionStream {
         }
     }
 
-    async fn open(&self, entry: &str) -> Result<u32, NylonError> {
-        let (tx, rx) = mpsc::unbounded_channel();
+    async fn open(
+        &self,
+        entry: &str,
+        plugin_name: &str,
+        item: Option<&PluginItem>,
+    ) -> Result<u32, NylonError> {
+        let capacity = item.map_or(DEFAULT_EVENT_QUEUE_CAPACITY, |item| {
+            item.event_queue_capacity()
+        });
+        let (tx, rx) = mpsc::channel(capacity);
+        let sender = SessionSender {
+            tx,
+            plugin: Arc::from(plugin_name),
+            on_full: item
+                .map(|item| item.on_event_queue_full)
+                .unwrap_or_default(),
+        };
 
         {
             let mut sessions = ACTIVE_SESSIONS.write().map_err(|e| {
                 NylonError::ConfigError(format!("Failed to lock ACTIVE_SESSIONS: {:?}", e))
             })?;
-            sessions.insert(self.session_id, tx);
+            sessions.insert(self.session_id, sender);
         }
 
-        unsafe {
-            let ok = (*self.plugin.register_session)(
-                self.session_id,
-                entry.as_ptr(),
-                entry.len() as u32,
-                handle_ffi_event,
-            );
-            if !ok {
-                if let Ok(mut sessions) = ACTIVE_SESSIONS.write() {
-                    sessions.remove(&self.session_id);
-                }
-                return Err(NylonError::ConfigError(
-                    "Failed to register session".to_string(),
-                ));
+        if !self
+            .plugin
+            .register_session(self.session_id, entry, handle_ffi_event)
+        {
+            if let Ok(mut sessions) = ACTIVE_SESSIONS.write() {
+                sessions.remove(&self.session_id);
             }
+            return Err(NylonError::ConfigError(
+                "Failed to register session".to_string(),
+            ));
         }
         {
-            // let mut sessions = SESSION_RX.lock().await;
-            // sessions.insert(self.session_id, Arc::new(Mutex::new(rx)));
-            {
-                let mut sessions = SESSION_RX.lock().await;
-                sessions.insert(self.session_id, Arc::new(Mutex::new(rx)));
-            }
+            let mut sessions = SESSION_RX.write().map_err(|e| {
+                NylonError::ConfigError(format!("Failed to lock SESSION_RX: {:?}", e))
+            })?;
+            sessions.insert(self.session_id, Arc::new(Mutex::new(rx)));
         }
         Ok(self.session_id)
     }
@@ -144,6 +227,13 @@ impl PluginSessionStream for SessionStream {
         method: u32,
         data: &[u8],
     ) -> Result<(), NylonError> {
+        // Replies and WebSocket events come in the version 1 layout
+        let data = if matches!(phase, PluginPhase::Zero) && abi_version(self.session_id) >= abi::V2
+        {
+            payloads::encode_reply(method, data)?
+        } else {
+            Cow::Borrowed(data)
+        };
         let ffi_buffer = &FfiBuffer {
             sid: self.session_id,
             phase: phase.to_u8(),
@@ -151,9 +241,7 @@ impl PluginSessionStream for SessionStream {
             ptr: data.as_ptr(),
             len: data.len() as u64,
         };
-        unsafe {
-            (*self.plugin.event_stream)(ffi_buffer);
-        }
+        self.plugin.event_stream(ffi_buffer);
         Ok(())
     }
 
@@ -163,50 +251,88 @@ impl PluginSessionStream for SessionStream {
     }
 }
 
-pub async fn close_session(plugin: Arc<FfiPlugin>, session_id: u32) -> Result<(), NylonError> {
-    unsafe {
-        (*plugin.close_session)(session_id);
-    }
+pub async fn close_session(
+    plugin: Arc<dyn PluginBackend>,
+    session_id: u32,
+) -> Result<(), NylonError> {
+    plugin.close_session(session_id);
+    forget_session(session_id);
+    Ok(())
+}
+
+/// Forget a session whose plugin went away, so nobody waits on it forever
+pub(crate) fn abort_session(session_id: u32) {
+    forget_session(session_id);
+}
+
+/// Drop everything held for the session
+fn forget_session(session_id: u32) {
     if let Ok(mut sessions) = ACTIVE_SESSIONS.write() {
         sessions.remove(&session_id);
     }
-    Ok(())
+    if let Ok(mut sessions) = SESSION_RX.write() {
+        sessions.remove(&session_id);
+    }
+    if let Ok(mut permits) = SESSION_PERMITS.write() {
+        permits.remove(&session_id);
+    }
+    if let Ok(mut abi) = SESSION_ABI.write() {
+        abi.remove(&session_id);
+    }
+    if let Ok(mut slots) = SESSION_WS_SLOTS.write() {
+        slots.remove(&session_id);
+    }
+    if let Ok(mut queues) = SESSION_WS_RX.write() {
+        queues.remove(&session_id);
+    }
 }
 
-pub fn get_rx(
-    session_id: u32,
-) -> Result<Arc<Mutex<UnboundedReceiver<(u32, Vec<u8>)>>>, NylonError> {
-    let sessions = SESSION_RX.try_lock();
-    // sessions
-    //     .get(&session_id)
-    //     .cloned()
-    //     .ok_or_else(|| NylonError::ConfigError(format!("Session {} not found", session_id)))
-    //     .map(|arc| arc.clone())
-    match sessions {
-        Ok(sessions) => sessions
-            .get(&session_id)
-            .cloned()
-            .ok_or_else(|| NylonError::ConfigError(format!("Session {} not found", session_id))),
-        Err(_) => Err(NylonError::ConfigError(
-            "Failed to lock SESSION_RX".to_string(),
-        )),
+/// Keep a session slot of the plugin until the session closes
+pub(crate) fn hold_permit(session_id: u32, permit: OwnedSemaphorePermit) {
+    if let Ok(mut permits) = SESSION_PERMITS.write() {
+        permits.insert(session_id, permit);
     }
 }
 
-pub async fn set_ws_rx(
-    session_id: u32,
-    rx: UnboundedWsReceiver<WebSocketMessage>,
-) -> Result<(), NylonError> {
-    let mut sessions = SESSION_WS_RX.lock().await;
-    sessions.insert(session_id, Arc::new(Mutex::new(rx)));
+/// Keep a WebSocket connection slot until the session closes
+pub(crate) fn hold_ws_slot(session_id: u32, slot: ConnectionSlot) {
+    if let Ok(mut slots) = SESSION_WS_SLOTS.write() {
+        slots.insert(session_id, slot);
+    }
+}
+
+pub(crate) fn set_abi_version(session_id: u32, version: u16) {
+    if let Ok(mut abi) = SESSION_ABI.write() {
+        abi.insert(session_id, version);
+    }
+}
+
+pub(crate) fn abi_version(session_id: u32) -> u16 {
+    SESSION_ABI
+        .read()
+        .ok()
+        .and_then(|abi| abi.get(&session_id).copied())
+        .unwrap_or(abi::V1)
+}
+
+/// Receiver of an open session's events
+pub async fn session_rx(session_id: u32) -> Option<Arc<Mutex<Receiver<(u32, Vec<u8>)>>>> {
+    SESSION_RX
+        .read()
+        .ok()
+        .and_then(|sessions| sessions.get(&session_id).cloned())
+}
+
+pub async fn set_ws_rx(session_id: u32, queue: Arc<SendQueue>) -> Result<(), NylonError> {
+    let mut sessions = SESSION_WS_RX
+        .write()
+        .map_err(|e| NylonError::ConfigError(format!("Failed to lock SESSION_WS_RX: {:?}", e)))?;
+    sessions.insert(session_id, queue);
     Ok(())
 }
 
-pub fn get_ws_rx(
-    session_id: u32,
-) -> Result<Arc<Mutex<UnboundedWsReceiver<WebSocketMessage>>>, NylonError> {
-    let sessions = SESSION_WS_RX.try_lock();
-    match sessions {
+pub fn get_ws_rx(session_id: u32) -> Result<Arc<SendQueue>, NylonError> {
+    match SESSION_WS_RX.read() {
         Ok(sessions) => sessions
             .get(&session_id)
             .cloned()
@@ -216,3 +342,158 @@ pub fn get_ws_rx(
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_session(
+        session_id: u32,
+        plugin: &str,
+        on_full: PluginQueueFull,
+    ) -> (SessionSender, Receiver<(u32, Vec<u8>)>) {
+        let (tx, rx) = mpsc::channel(1);
+        let sender = SessionSender {
+            tx,
+            plugin: Arc::from(plugin),
+            on_full,
+        };
+        ACTIVE_SESSIONS
+            .write()
+            .unwrap()
+            .insert(session_id, sender.clone());
+        (sender, rx)
+    }
+
+    fn is_open(session_id: u32) -> bool {
+        ACTIVE_SESSIONS.read().unwrap().contains_key(&session_id)
+    }
+
+    #[derive(Debug)]
+    struct Backend;
+
+    impl PluginBackend for Backend {
+        fn register_session(
+            &self,
+            _: u32,
+            _: &str,
+            _: nylon_types::plugins::PluginEventCallback,
+        ) -> bool {
+            true
+        }
+        fn event_stream(&self, _: &FfiBuffer) {}
+        fn close_session(&self, _: u32) {}
+        fn shutdown(&self) {}
+    }
+
+    fn is_held(session_id: u32) -> bool {
+        SESSION_RX.read().unwrap().contains_key(&session_id)
+            || SESSION_PERMITS.read().unwrap().contains_key(&session_id)
+            || SESSION_ABI.read().unwrap().contains_key(&session_id)
+            || SESSION_WS_RX.read().unwrap().contains_key(&session_id)
+    }
+
+    /// Open a session through the stream, holding a slot of `slots`
+    fn open_stream(slots: &Arc<tokio::sync::Semaphore>) -> SessionStream {
+        let stream = SessionStream::new(Arc::new(Backend), 0);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(stream.open("entry", "session-cleanup", None))
+            .unwrap();
+        hold_permit(
+            stream.session_id,
+            slots.clone().try_acquire_owned().unwrap(),
+        );
+        set_abi_version(stream.session_id, abi::V2);
+        assert!(is_open(stream.session_id));
+        assert!(is_held(stream.session_id));
+        stream
+    }
+
+    #[test]
+    fn test_close_forgets_the_session() {
+        let slots = Arc::new(tokio::sync::Semaphore::new(1));
+        let stream = open_stream(&slots);
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(stream.close())
+            .unwrap();
+        assert!(!is_open(stream.session_id));
+        assert!(!is_held(stream.session_id));
+        assert_eq!(slots.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_abort_forgets_the_session() {
+        let slots = Arc::new(tokio::sync::Semaphore::new(1));
+        let stream = open_stream(&slots);
+        abort_session(stream.session_id);
+        assert!(!is_open(stream.session_id));
+        assert!(!is_held(stream.session_id));
+        assert_eq!(slots.available_permits(), 1);
+    }
+
+    fn full_count(plugin: &str) -> u64 {
+        QUEUE_FULL.get(plugin).map(|count| *count).unwrap_or(0)
+    }
+
+    #[test]
+    fn test_queue_full_defaults_to_close() {
+        assert_eq!(PluginQueueFull::default(), PluginQueueFull::Close);
+    }
+
+    #[test]
+    fn test_close_ends_the_session() {
+        let (sender, mut rx) = open_session(u32::MAX - 1, "queue-close", PluginQueueFull::Close);
+        deliver(&sender, u32::MAX - 1, (methods::METRIC_INCR, Vec::new()));
+        assert!(is_open(u32::MAX - 1));
+        deliver(&sender, u32::MAX - 1, (methods::METRIC_INCR, Vec::new()));
+        assert!(!is_open(u32::MAX - 1));
+        assert_eq!(full_count("queue-close"), 1);
+        assert_eq!(rx.try_recv().unwrap().0, methods::METRIC_INCR);
+    }
+
+    #[test]
+    fn test_drop_discards_droppable_events() {
+        let (sender, mut rx) = open_session(u32::MAX - 2, "queue-drop", PluginQueueFull::Drop);
+        deliver(&sender, u32::MAX - 2, (methods::NEXT, Vec::new()));
+        deliver(
+            &sender,
+            u32::MAX - 2,
+            (methods::WEBSOCKET_SEND_TEXT, b"hi".to_vec()),
+        );
+        deliver(&sender, u32::MAX - 2, (methods::METRIC_OBSERVE, Vec::new()));
+        assert!(is_open(u32::MAX - 2));
+        assert_eq!(full_count("queue-drop"), 2);
+        assert_eq!(rx.try_recv().unwrap().0, methods::NEXT);
+        assert!(rx.try_recv().is_err());
+        abort_session(u32::MAX - 2);
+    }
+
+    #[test]
+    fn test_drop_still_closes_on_control_events() {
+        for (offset, method) in [methods::END, methods::NEXT, methods::SET_RESPONSE_HEADER]
+            .into_iter()
+            .enumerate()
+        {
+            let session_id = u32::MAX - 10 - offset as u32;
+            let (sender, _rx) = open_session(session_id, "queue-control", PluginQueueFull::Drop);
+            deliver(&sender, session_id, (methods::METRIC_INCR, Vec::new()));
+            deliver(&sender, session_id, (method, Vec::new()));
+            assert!(!is_open(session_id), "method {method}");
+        }
+        assert_eq!(full_count("queue-control"), 3);
+    }
+
+    #[test]
+    fn test_droppable() {
+        assert!(droppable(methods::METRIC_INCR));
+        assert!(droppable(methods::WEBSOCKET_BROADCAST_ALL_BINARY));
+        assert!(!droppable(methods::END));
+        assert!(!droppable(methods::SET_REQUEST_BODY_CHUNK));
+        assert!(!droppable(methods::WEBSOCKET_CLOSE));
+    }
+}
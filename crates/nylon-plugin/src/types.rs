@@ -9,6 +9,15 @@ use std::collections::HashMap;
 pub enum BuiltinPlugin {
     RequestHeaderModifier,
     ResponseHeaderModifier,
+    QueryTokenAuth,
+    ReplayProtection,
+    ResponseWatermark,
+    Lua,
+    BodyTransform,
+    Waf,
+    BotGuard,
+    RequestDecompression,
+    Oidc,
 }
 
 /// Context for middleware execution
@@ -0,0 +1,484 @@
+//! WASM plugin runtime
+//!
+//! Runs plugins compiled to `wasm32-wasip1` inside wasmtime. The module speaks
+//! the same session/method protocol as shared library plugins, with pointers
+//! into its own linear memory instead of raw host pointers.
+//!
+//! Exports expected from the module:
+//! - `memory`
+//! - `nylon_alloc(len) -> ptr` - buffer the host copies payloads into
+//! - `register_session_stream(sid, entry_ptr, entry_len) -> i32` (non-zero on success)
+//! - `event_stream(sid, phase, method, ptr, len)`
+//! - `close_session_stream(sid)`
+//! - optional: `initialize(ptr, len)`, `shutdown()`, `nylon_free(ptr, len)`
+//!
+//! The host provides `nylon.emit(sid, phase, method, ptr, len)` for the
+//! plugin to send events back.
+//!
+//! Each module runs on a thread of its own that takes calls from a bounded
+//! queue, so a slow module never holds up the proxy's workers. A call that
+//! runs past the plugin's `timeout_ms` traps, and linear memory cannot grow
+//! past `max_memory_mb`.
+
+use nylon_error::NylonError;
+use nylon_types::plugins::{FfiBuffer, PluginBackend, PluginEventCallback, PluginItem};
+use once_cell::sync::Lazy;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::time::Duration;
+use tracing::error;
+use wasmtime::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+use wasmtime_wasi::{WasiCtxBuilder, p1::WasiP1Ctx};
+
+/// Calls waiting for a module before new ones are turned away
+const CALL_QUEUE_CAPACITY: usize = 1024;
+
+/// Resolution of call deadlines
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Longest a call may run when the plugin sets no `timeout_ms`
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Linear memory a module may grow to when `max_memory_mb` is unset
+const DEFAULT_MAX_MEMORY_MB: u64 = 64;
+
+/// Largest event a module may emit
+const MAX_EVENT_BYTES: usize = 16 * 1024 * 1024;
+
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config).expect("create wasmtime engine");
+    let ticker = engine.clone();
+    std::thread::Builder::new()
+        .name("wasm-epoch".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        })
+        .expect("spawn wasm epoch ticker");
+    engine
+});
+
+struct HostState {
+    wasi: WasiP1Ctx,
+    callback: Option<PluginEventCallback>,
+    limits: StoreLimits,
+}
+
+/// Work handed to a module's thread
+enum Call {
+    Register {
+        sid: u32,
+        entry: String,
+        callback: PluginEventCallback,
+    },
+    Event {
+        sid: u32,
+        phase: u8,
+        method: u32,
+        data: Vec<u8>,
+    },
+    Close(u32),
+    Shutdown,
+}
+
+struct WasmInstance {
+    store: Store<HostState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    free: Option<TypedFunc<(i32, i32), ()>>,
+    register_session: TypedFunc<(i32, i32, i32), i32>,
+    event_stream: TypedFunc<(i32, i32, i32, i32, i32), ()>,
+    close_session: TypedFunc<i32, ()>,
+    shutdown: Option<TypedFunc<(), ()>>,
+    /// Epoch ticks each call may run for
+    deadline: u64,
+}
+
+impl WasmInstance {
+    /// Run `f` with a fresh deadline, logging a trap
+    fn call<R>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut WasmInstance) -> wasmtime::Result<R>,
+    ) -> Option<R> {
+        self.store.set_epoch_deadline(self.deadline);
+        match f(self) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                error!("WASM plugin {} trapped: {}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Copy `data` into guest memory, returning its pointer and length
+    fn write(&mut self, data: &[u8]) -> wasmtime::Result<(i32, i32)> {
+        if data.is_empty() {
+            return Ok((0, 0));
+        }
+        let len = data.len() as i32;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as usize, data)?;
+        Ok((ptr, len))
+    }
+
+    fn release(&mut self, (ptr, len): (i32, i32)) {
+        if len == 0 {
+            return;
+        }
+        if let Some(free) = &self.free
+            && let Err(e) = free.call(&mut self.store, (ptr, len))
+        {
+            error!("WASM plugin nylon_free failed: {}", e);
+        }
+    }
+}
+
+/// A plugin running in a wasmtime sandbox
+///
+/// A module instance is single threaded, so calls are queued for its thread.
+pub struct WasmPlugin {
+    name: String,
+    calls: SyncSender<Call>,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+fn load_error(plugin: &PluginItem, e: impl std::fmt::Display) -> NylonError {
+    NylonError::ConfigError(format!("WASM plugin {}: {}", plugin.name, e))
+}
+
+impl WasmPlugin {
+    /// Compile, instantiate and initialize the module of `plugin`
+    pub fn load(plugin: &PluginItem) -> Result<Self, NylonError> {
+        let module = Module::from_file(&ENGINE, &plugin.file).map_err(|e| load_error(plugin, e))?;
+
+        let mut linker = Linker::<HostState>::new(&ENGINE);
+        wasmtime_wasi::p1::add_to_linker_sync(&mut linker, |state| &mut state.wasi)
+            .map_err(|e| load_error(plugin, e))?;
+        linker
+            .func_wrap("nylon", "emit", emit)
+            .map_err(|e| load_error(plugin, e))?;
+
+        let wasi = WasiCtxBuilder::new().inherit_stdio().build_p1();
+        let max_memory_mb = plugin.max_memory_mb.unwrap_or(DEFAULT_MAX_MEMORY_MB);
+        let limits = StoreLimitsBuilder::new()
+            .memory_size((max_memory_mb as usize).saturating_mul(1024 * 1024))
+            .build();
+        let mut store = Store::new(
+            &ENGINE,
+            HostState {
+                wasi,
+                callback: None,
+                limits,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        let timeout = plugin
+            .timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_CALL_TIMEOUT);
+        let deadline = (timeout.as_millis() / EPOCH_TICK.as_millis()).max(1) as u64;
+        store.set_epoch_deadline(deadline);
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| load_error(plugin, e))?;
+
+        // Reactor modules set up their runtime in `_initialize`
+        if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            init.call(&mut store, ())
+                .map_err(|e| load_error(plugin, e))?;
+        }
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| load_error(plugin, "missing memory export"))?;
+        let mut wasm = WasmInstance {
+            memory,
+            alloc: instance
+                .get_typed_func(&mut store, "nylon_alloc")
+                .map_err(|e| load_error(plugin, e))?,
+            free: instance.get_typed_func(&mut store, "nylon_free").ok(),
+            register_session: instance
+                .get_typed_func(&mut store, "register_session_stream")
+                .map_err(|e| load_error(plugin, e))?,
+            event_stream: instance
+                .get_typed_func(&mut store, "event_stream")
+                .map_err(|e| load_error(plugin, e))?,
+            close_session: instance
+                .get_typed_func(&mut store, "close_session_stream")
+                .map_err(|e| load_error(plugin, e))?,
+            shutdown: instance.get_typed_func(&mut store, "shutdown").ok(),
+            deadline,
+            store,
+        };
+
+        if let Ok(initialize) =
+            instance.get_typed_func::<(i32, i32), ()>(&mut wasm.store, "initialize")
+        {
+            let config = match &plugin.config {
+                Some(config) => serde_json::to_string(config).unwrap_or_default(),
+                None => "".to_string(),
+            };
+            wasm.store.set_epoch_deadline(deadline);
+            let buf = wasm
+                .write(config.as_bytes())
+                .map_err(|e| load_error(plugin, e))?;
+            initialize
+                .call(&mut wasm.store, buf)
+                .map_err(|e| load_error(plugin, e))?;
+            wasm.release(buf);
+        }
+
+        let (calls, queue) = sync_channel(CALL_QUEUE_CAPACITY);
+        let name = plugin.name.clone();
+        std::thread::Builder::new()
+            .name(format!("wasm-{}", plugin.name))
+            .spawn(move || run(name, wasm, queue))
+            .map_err(|e| load_error(plugin, e))?;
+        Ok(Self {
+            name: plugin.name.clone(),
+            calls,
+        })
+    }
+
+    fn send(&self, call: Call) -> bool {
+        match self.calls.try_send(call) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                error!(
+                    "WASM plugin {} has {} calls waiting, turning one away",
+                    self.name, CALL_QUEUE_CAPACITY
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("WASM plugin {} is no longer running", self.name);
+                false
+            }
+        }
+    }
+}
+
+/// Thread of a module: run its calls one at a time
+fn run(name: String, mut wasm: WasmInstance, calls: Receiver<Call>) {
+    while let Ok(call) = calls.recv() {
+        match call {
+            Call::Register {
+                sid,
+                entry,
+                callback,
+            } => {
+                let registered = wasm.call(&name, |wasm| {
+                    wasm.store.data_mut().callback = Some(callback);
+                    let buf = wasm.write(entry.as_bytes())?;
+                    let ok = wasm
+                        .register_session
+                        .call(&mut wasm.store, (sid as i32, buf.0, buf.1))?;
+                    wasm.release(buf);
+                    Ok(ok != 0)
+                });
+                if registered != Some(true) {
+                    error!("WASM plugin {} has no entry {}", name, entry);
+                    crate::stream::abort_session(sid);
+                }
+            }
+            Call::Event {
+                sid,
+                phase,
+                method,
+                data,
+            } => {
+                let delivered = wasm.call(&name, |wasm| {
+                    let buf = wasm.write(&data)?;
+                    wasm.event_stream.call(
+                        &mut wasm.store,
+                        (sid as i32, phase as i32, method as i32, buf.0, buf.1),
+                    )?;
+                    wasm.release(buf);
+                    Ok(())
+                });
+                // A trapped call never answers; end the session instead of hanging it
+                if delivered.is_none() {
+                    crate::stream::abort_session(sid);
+                }
+            }
+            Call::Close(sid) => {
+                wasm.call(&name, |wasm| {
+                    wasm.close_session.call(&mut wasm.store, sid as i32)
+                });
+            }
+            Call::Shutdown => {
+                wasm.call(&name, |wasm| match &wasm.shutdown {
+                    Some(shutdown) => shutdown.call(&mut wasm.store, ()),
+                    None => Ok(()),
+                });
+                break;
+            }
+        }
+    }
+}
+
+/// `nylon.emit`: forward an event from guest memory to the host callback
+fn emit(
+    mut caller: Caller<'_, HostState>,
+    sid: i32,
+    phase: i32,
+    method: i32,
+    ptr: i32,
+    len: i32,
+) -> wasmtime::Result<()> {
+    let Some(memory) = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+    else {
+        return Err(wasmtime::Error::msg("missing memory export"));
+    };
+    let (start, len) = event_range(ptr, len, memory.data_size(&caller))?;
+    let mut data = vec![0u8; len];
+    memory.read(&caller, start, &mut data)?;
+
+    if let Some(callback) = caller.data().callback {
+        let buffer = FfiBuffer {
+            sid: sid as u32,
+            phase: phase as u8,
+            method: method as u32,
+            ptr: data.as_ptr(),
+            len: data.len() as u64,
+        };
+        callback(&buffer);
+    }
+    Ok(())
+}
+
+/// Check an event's place in guest memory before anything is allocated for it
+fn event_range(ptr: i32, len: i32, memory_size: usize) -> wasmtime::Result<(usize, usize)> {
+    let (Ok(start), Ok(len)) = (usize::try_from(ptr), usize::try_from(len)) else {
+        return Err(wasmtime::Error::msg(format!(
+            "emit: negative pointer {} or length {}",
+            ptr, len
+        )));
+    };
+    if len > MAX_EVENT_BYTES {
+        return Err(wasmtime::Error::msg(format!(
+            "emit: event of {} bytes is over the limit of {}",
+            len, MAX_EVENT_BYTES
+        )));
+    }
+    if start as u64 + len as u64 > memory_size as u64 {
+        return Err(wasmtime::Error::msg(format!(
+            "emit: {} bytes at {} are outside the {} bytes of memory",
+            len, start, memory_size
+        )));
+    }
+    Ok((start, len))
+}
+
+impl PluginBackend for WasmPlugin {
+    /// Registration finishes on the module's thread; an unknown entry ends
+    /// the session there
+    fn register_session(
+        &self,
+        session_id: u32,
+        entry: &str,
+        callback: PluginEventCallback,
+    ) -> bool {
+        self.send(Call::Register {
+            sid: session_id,
+            entry: entry.to_string(),
+            callback,
+        })
+    }
+
+    fn event_stream(&self, buffer: &FfiBuffer) {
+        let data = if buffer.ptr.is_null() {
+            vec![]
+        } else {
+            unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len as usize) }.to_vec()
+        };
+        let queued = self.send(Call::Event {
+            sid: buffer.sid,
+            phase: buffer.phase,
+            method: buffer.method,
+            data,
+        });
+        if !queued {
+            crate::stream::abort_session(buffer.sid);
+        }
+    }
+
+    fn close_session(&self, session_id: u32) {
+        self.send(Call::Close(session_id));
+    }
+
+    fn shutdown(&self) {
+        self.send(Call::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module whose `initialize` emits `len` bytes from address 0
+    fn module(len: i32) -> String {
+        format!(
+            r#"(module
+  (import "nylon" "emit" (func $emit (param i32 i32 i32 i32 i32)))
+  (memory (export "memory") 1)
+  (func (export "nylon_alloc") (param i32) (result i32) i32.const 1024)
+  (func (export "register_session_stream") (param i32 i32 i32) (result i32) i32.const 1)
+  (func (export "event_stream") (param i32 i32 i32 i32 i32))
+  (func (export "close_session_stream") (param i32))
+  (func (export "initialize") (param i32 i32)
+    (call $emit (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const {len}))))"#
+        )
+    }
+
+    fn load(name: &str, len: i32) -> Result<WasmPlugin, NylonError> {
+        let file = std::env::temp_dir().join(format!("nylon-{}-{}.wat", name, std::process::id()));
+        std::fs::write(&file, module(len)).unwrap();
+        let plugin: PluginItem = serde_json::from_value(serde_json::json!({
+            "name": name,
+            "file": file.to_string_lossy(),
+            "type": "wasm",
+        }))
+        .unwrap();
+        let result = WasmPlugin::load(&plugin);
+        let _ = std::fs::remove_file(&file);
+        result
+    }
+
+    #[test]
+    fn test_event_range() {
+        let page = 65536;
+        assert_eq!(event_range(0, 0, page).unwrap(), (0, 0));
+        assert_eq!(event_range(100, 200, page).unwrap(), (100, 200));
+        assert_eq!(event_range(0, page as i32, page).unwrap(), (0, page));
+        assert!(event_range(1, page as i32, page).is_err());
+        assert!(event_range(-1, 10, page).is_err());
+        assert!(event_range(0, -1, page).is_err());
+        assert!(event_range(i32::MAX, i32::MAX, usize::MAX).is_err());
+        assert!(event_range(0, MAX_EVENT_BYTES as i32 + 1, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_emit_rejects_out_of_range_len() {
+        assert!(load("emit-in-range", 16).is_ok());
+        // A trap instead of a 2 GiB allocation
+        assert!(load("emit-out-of-range", i32::MAX).is_err());
+        assert!(load("emit-negative", -1).is_err());
+    }
+}
@@ -1,6 +1,5 @@
-use crate as store;
+use arc_swap::ArcSwap;
 use fnv::FnvHasher;
-use lru::LruCache;
 use nylon_error::NylonError;
 use nylon_types::services::{Algorithm, HealthCheck, ServiceItem, ServiceType};
 use once_cell::sync::Lazy;
@@ -18,17 +17,14 @@ use pingora::{
     prelude::HttpPeer,
     protocols::l4::socket::SocketAddr,
 };
-use std::num::NonZeroUsize;
-use std::sync::Mutex;
 use std::time::Duration;
 use std::{
     collections::{BTreeSet, HashMap},
     sync::Arc,
 };
 
-// LRU cache for backend service lookups - cache up to 500 services
-static BACKEND_SERVICE_CACHE: Lazy<Mutex<LruCache<String, HttpService>>> =
-    Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(500).unwrap())));
+/// Services by name, replaced whole on reload
+static SERVICES: Lazy<ArcSwap<HashMap<String, HttpService>>> = Lazy::new(Default::default);
 
 #[derive(Clone)]
 pub enum BackendType {
@@ -249,60 +245,17 @@ pub async fn store(services: &Vec<&ServiceItem>) -> Result<(), NylonError> {
             },
         );
     }
-    store::insert(store::KEY_LB_BACKENDS, store_backends);
-
-    // Clear backend service cache when backends are reloaded
-    clear_backend_service_cache();
+    SERVICES.store(Arc::new(store_backends));
 
     Ok(())
 }
 
 pub async fn get(service_name: &str) -> Result<HttpService, NylonError> {
-    // Check cache first
-    if let Ok(mut cache) = BACKEND_SERVICE_CACHE.lock()
-        && let Some(cached) = cache.get(service_name)
-    {
-        tracing::debug!("Backend service cache hit: {}", service_name);
-        return Ok(cached.clone());
-    }
-
-    tracing::debug!("Backend service cache miss: {}", service_name);
-
-    // Cache miss - lookup from store
-    let Some(services) = store::get::<HashMap<String, HttpService>>(store::KEY_LB_BACKENDS) else {
-        return Err(NylonError::ConfigError(format!(
-            "Services not found: {}",
-            service_name
-        )));
-    };
-    let service = services
+    SERVICES
+        .load()
         .get(service_name)
-        .ok_or_else(|| NylonError::ConfigError(format!("Service not found: {}", service_name)))?
-        .clone();
-
-    // Store in cache
-    if let Ok(mut cache) = BACKEND_SERVICE_CACHE.lock() {
-        cache.put(service_name.to_string(), service.clone());
-    }
-
-    Ok(service)
-}
-
-/// Clear backend service cache - useful when services are reloaded
-pub fn clear_backend_service_cache() {
-    if let Ok(mut cache) = BACKEND_SERVICE_CACHE.lock() {
-        cache.clear();
-        tracing::info!("Backend service cache cleared");
-    }
-}
-
-/// Get backend service cache statistics
-pub fn get_backend_service_cache_stats() -> (usize, usize) {
-    if let Ok(cache) = BACKEND_SERVICE_CACHE.lock() {
-        (cache.len(), cache.cap().get())
-    } else {
-        (0, 0)
-    }
+        .cloned()
+        .ok_or_else(|| NylonError::ConfigError(format!("Service not found: {}", service_name)))
 }
 
 /// Parse a duration string like "5s" into seconds
@@ -316,11 +269,9 @@ fn parse_seconds(input: &str) -> Option<u64> {
 
 /// Run health checks for all stored HTTP services
 pub async fn run_health_checks_for_all() {
-    let Some(services) = store::get::<HashMap<String, HttpService>>(store::KEY_LB_BACKENDS) else {
-        return;
-    };
-    for (_name, svc) in services.into_iter() {
-        match svc.backend_type {
+    let services = SERVICES.load_full();
+    for svc in services.values() {
+        match &svc.backend_type {
             BackendType::RoundRobin(lb) => {
                 lb.backends().run_health_check(true).await;
             }
@@ -336,3 +287,25 @@ pub async fn run_health_checks_for_all() {
         }
     }
 }
+
+/// Health state of every backend as `(service, backend address, healthy)`
+pub fn backend_health() -> Vec<(String, String, bool)> {
+    let services = SERVICES.load();
+    let mut result = vec![];
+    for (name, svc) in services.iter() {
+        let backends = match &svc.backend_type {
+            BackendType::RoundRobin(lb) => lb.backends(),
+            BackendType::Weighted(lb) => lb.backends(),
+            BackendType::Consistent(lb) => lb.backends(),
+            BackendType::Random(lb) => lb.backends(),
+        };
+        for backend in backends.get_backend().iter() {
+            result.push((
+                name.clone(),
+                backend.addr.to_string(),
+                backends.ready(backend),
+            ));
+        }
+    }
+    result
+}
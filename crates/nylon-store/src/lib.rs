@@ -1,4 +1,5 @@
 pub mod lb_backends;
+pub mod nonces;
 pub mod redis_adapter;
 pub mod routes;
 pub mod tls;
@@ -15,20 +16,16 @@ pub const DEFAULT_HEADER_SELECTOR: &str = "x-nylon-proxy";
 // constants
 pub const KEY_RUNTIME_CONFIG: &str = "runtime_config";
 pub const KEY_CONFIG_PATH: &str = "config_path";
-pub const KEY_COMMAND_SOCKET_PATH: &str = "/tmp/_nylon.sock";
-pub const KEY_LB_BACKENDS: &str = "lb_backends";
-pub const KEY_ROUTES: &str = "routes";
-pub const KEY_TLS_ROUTES: &str = "tls_routes";
-pub const KEY_ROUTES_MATCHIT: &str = "routes_matchit";
-pub const KEY_HEADER_SELECTOR: &str = "header_selector";
-pub const KEY_LIBRARY_FILE: &str = "library_file";
+pub const KEY_COMMAND_SOCKET_PATH: &str = nylon_types::paths::COMMAND_SOCKET_PATH;
+pub const KEY_PROXY_CONFIG: &str = "proxy_config";
 pub const KEY_PLUGINS: &str = "plugins";
 pub const KEY_TLS: &str = "tls";
 pub const KEY_ACME_CERTS: &str = "acme_certs";
 pub const KEY_ACME_CONFIG: &str = "acme_config";
 pub const KEY_ACME_METRICS: &str = "acme_metrics";
 
-// storage for global variables
+// storage for global variables; routes and backends, read on every
+// request, are kept in typed snapshots in their own modules instead
 static GLOBAL_STORE: Lazy<DashMap<String, Box<dyn Any + Send + Sync>>> = Lazy::new(DashMap::new);
 
 pub fn insert<T: Any + Send + Sync + 'static>(key: &str, value: T) {
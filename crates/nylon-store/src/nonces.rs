@@ -0,0 +1,123 @@
+//! Nonce store for replay protection
+//!
+//! Nonces are kept in process memory by default, or in Redis when a URL is
+//! given so that every node of a cluster sees the same set.
+
+use dashmap::DashMap;
+use nylon_error::NylonError;
+use once_cell::sync::Lazy;
+use redis::aio::MultiplexedConnection;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Purge expired in-memory nonces once the map grows past this size
+const MEMORY_PURGE_THRESHOLD: usize = 100_000;
+
+/// Nonces kept in memory at most; requests beyond it are turned away
+const MEMORY_MAX_NONCES: usize = 1_000_000;
+
+/// Shortest time between two purges of a large map
+const MEMORY_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest a nonce is remembered in memory
+const MEMORY_MAX_TTL: Duration = Duration::from_secs(7 * 86400);
+
+static MEMORY_NONCES: Lazy<DashMap<String, Instant>> = Lazy::new(DashMap::new);
+static LAST_PURGE: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+static REDIS_CONNECTIONS: Lazy<DashMap<String, MultiplexedConnection>> = Lazy::new(DashMap::new);
+static REJECTIONS: Lazy<DashMap<String, AtomicU64>> = Lazy::new(DashMap::new);
+
+/// Remember `key` for `ttl`, returning `false` if it was already seen
+pub async fn insert_if_absent(
+    key: &str,
+    ttl: Duration,
+    redis_url: Option<&str>,
+) -> Result<bool, NylonError> {
+    match redis_url {
+        Some(url) => insert_redis(url, key, ttl).await,
+        None => insert_memory(key, ttl),
+    }
+}
+
+fn insert_memory(key: &str, ttl: Duration) -> Result<bool, NylonError> {
+    let now = Instant::now();
+    if MEMORY_NONCES.len() > MEMORY_PURGE_THRESHOLD
+        && let Ok(mut last_purge) = LAST_PURGE.try_lock()
+        && now.duration_since(*last_purge) >= MEMORY_PURGE_INTERVAL
+    {
+        *last_purge = now;
+        MEMORY_NONCES.retain(|_, expires_at| *expires_at > now);
+    }
+    if MEMORY_NONCES.len() >= MEMORY_MAX_NONCES && !MEMORY_NONCES.contains_key(key) {
+        return Err(NylonError::HttpException(
+            503,
+            "SERVICE_UNAVAILABLE",
+            "Too many nonces in flight",
+        ));
+    }
+
+    let expires = now + ttl.min(MEMORY_MAX_TTL);
+    let mut fresh = false;
+    MEMORY_NONCES
+        .entry(key.to_string())
+        .and_modify(|expires_at| {
+            if *expires_at <= now {
+                *expires_at = expires;
+                fresh = true;
+            }
+        })
+        .or_insert_with(|| {
+            fresh = true;
+            expires
+        });
+    Ok(fresh)
+}
+
+async fn insert_redis(url: &str, key: &str, ttl: Duration) -> Result<bool, NylonError> {
+    let mut conn = redis_connection(url).await?;
+    let result: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl.as_secs().max(1))
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| NylonError::RuntimeError(format!("Redis nonce check failed: {}", e)))?;
+    Ok(result.is_some())
+}
+
+async fn redis_connection(url: &str) -> Result<MultiplexedConnection, NylonError> {
+    if let Some(conn) = REDIS_CONNECTIONS.get(url) {
+        return Ok(conn.clone());
+    }
+    let client = redis::Client::open(url)
+        .map_err(|e| NylonError::ConfigError(format!("Redis connection error: {}", e)))?;
+    let conn = client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| NylonError::RuntimeError(format!("Redis connection error: {}", e)))?;
+    REDIS_CONNECTIONS.insert(url.to_string(), conn.clone());
+    Ok(conn)
+}
+
+/// Count a rejected request by reason
+pub fn record_rejection(reason: &str) {
+    if let Some(counter) = REJECTIONS.get(reason) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    REJECTIONS
+        .entry(reason.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Rejections since start by reason, for the rejections counter
+pub fn rejections() -> Vec<(String, u64)> {
+    REJECTIONS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+        .collect()
+}
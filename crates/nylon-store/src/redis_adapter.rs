@@ -13,6 +13,9 @@ use tokio::sync::{RwLock, mpsc};
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
+/// Times a metadata update is retried when another node changed the connection first
+const METADATA_UPDATE_ATTEMPTS: usize = 5;
+
 /// Redis-based WebSocket adapter for cluster support
 pub struct RedisAdapter {
     client: Arc<Client>,
@@ -324,9 +327,11 @@ impl WebSocketAdapter for RedisAdapter {
         // Get connection rooms first
         let rooms = self.get_connection_rooms(connection_id).await?;
 
-        // Remove from all rooms
+        // Remove from all rooms; one failing room must not keep the connection around
         for room in rooms {
-            self.leave_room(connection_id, &room).await?;
+            if let Err(e) = self.leave_room(connection_id, &room).await {
+                tracing::warn!("Removing {} from room {}: {}", connection_id, room, e);
+            }
         }
 
         // Remove connection
@@ -467,6 +472,22 @@ impl WebSocketAdapter for RedisAdapter {
         Ok(connections)
     }
 
+    async fn room_size(&self, room: &str) -> Result<usize, NylonError> {
+        let mut conn = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Redis connection error: {}", e)))?;
+
+        let room_key = format!("{}:rooms:{}", self.get_key_prefix(), room);
+        let size: usize = conn
+            .scard(&room_key)
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Redis scard error: {}", e)))?;
+
+        Ok(size)
+    }
+
     async fn get_connection_rooms(&self, connection_id: &str) -> Result<Vec<String>, NylonError> {
         let mut conn = self
             .client
@@ -502,6 +523,19 @@ impl WebSocketAdapter for RedisAdapter {
         .await
     }
 
+    async fn broadcast_all(
+        &self,
+        message: WebSocketMessage,
+        exclude_connection: Option<&str>,
+    ) -> Result<(), NylonError> {
+        self.publish_event(WebSocketEvent::BroadcastAll {
+            message,
+            exclude_connection: exclude_connection.map(|s| s.to_string()),
+            sender_node_id: self.node_id.clone(),
+        })
+        .await
+    }
+
     async fn send_to_connection(
         &self,
         connection_id: &str,
@@ -515,6 +549,70 @@ impl WebSocketAdapter for RedisAdapter {
         .await
     }
 
+    /// Merge `metadata` into the stored connection
+    ///
+    /// The read and write run in a WATCH/MULTI transaction, so two updates
+    /// from different nodes never overwrite each other's keys.
+    async fn set_connection_metadata(
+        &self,
+        connection_id: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), NylonError> {
+        // A fresh connection, WATCH must not be shared with other commands
+        let mut conn = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Redis connection error: {}", e)))?;
+
+        let key = format!("{}:connections:{}", self.get_key_prefix(), connection_id);
+        for _ in 0..METADATA_UPDATE_ATTEMPTS {
+            let _: () = cmd("WATCH")
+                .arg(&key)
+                .query_async(&mut conn)
+                .await
+                .map_err(|e| NylonError::ConfigError(format!("Redis watch error: {}", e)))?;
+            let value: Option<String> = conn
+                .get(&key)
+                .await
+                .map_err(|e| NylonError::ConfigError(format!("Redis get error: {}", e)))?;
+            let Some(value) = value else {
+                let _: () = cmd("UNWATCH")
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|e| NylonError::ConfigError(format!("Redis unwatch error: {}", e)))?;
+                return Ok(());
+            };
+            let mut connection: WebSocketConnection =
+                serde_json::from_str(&value).map_err(|e| {
+                    NylonError::ConfigError(format!("Connection deserialization error: {}", e))
+                })?;
+            connection.metadata.extend(metadata.clone());
+            let value = serde_json::to_string(&connection).map_err(|e| {
+                NylonError::ConfigError(format!("Connection serialization error: {}", e))
+            })?;
+
+            // EXEC returns nil when the key changed since WATCH
+            let written: Option<((),)> = redis::pipe()
+                .atomic()
+                .set(&key, value)
+                .query_async(&mut conn)
+                .await
+                .map_err(|e| NylonError::ConfigError(format!("Redis set error: {}", e)))?;
+            if written.is_some() {
+                let mut local_connections = self.local_connections.write().await;
+                if let Some(local) = local_connections.get_mut(connection_id) {
+                    *local = connection;
+                }
+                return Ok(());
+            }
+        }
+        Err(NylonError::RuntimeError(format!(
+            "Metadata of connection {} kept changing, gave up after {} attempts",
+            connection_id, METADATA_UPDATE_ATTEMPTS
+        )))
+    }
+
     async fn get_connection(
         &self,
         connection_id: &str,
@@ -550,6 +648,41 @@ impl WebSocketAdapter for RedisAdapter {
         }
     }
 
+    /// One MGET for every connection instead of a GET each
+    async fn get_connections(
+        &self,
+        connection_ids: &[String],
+    ) -> Result<Vec<WebSocketConnection>, NylonError> {
+        if connection_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut conn = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Redis connection error: {}", e)))?;
+
+        let keys: Vec<String> = connection_ids
+            .iter()
+            .map(|id| format!("{}:connections:{}", self.get_key_prefix(), id))
+            .collect();
+        let values: Vec<Option<String>> = cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| NylonError::ConfigError(format!("Redis mget error: {}", e)))?;
+
+        values
+            .into_iter()
+            .flatten()
+            .map(|value| {
+                serde_json::from_str(&value).map_err(|e| {
+                    NylonError::ConfigError(format!("Connection deserialization error: {}", e))
+                })
+            })
+            .collect()
+    }
+
     async fn get_room(&self, room: &str) -> Result<Option<WebSocketRoom>, NylonError> {
         let connections = self.get_room_connections(room).await?;
 
@@ -1,29 +1,160 @@
 #![allow(clippy::type_complexity)]
 use crate as store;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytes::Bytes;
+use fnv::FnvHasher;
 use lru::LruCache;
 use nylon_error::NylonError;
 use nylon_types::{
-    context::Route,
+    context::{ErrorPages, LoadedErrorPage, ParsedTemplate, Route, RouteSnapshot},
     route::{HTTP_METHODS, MiddlewareItem, PathConfig, RouteConfig},
-    services::ServiceItem,
+    services::{ServiceItem, ServiceType},
     template::{Expr, extract_and_parse_templates, walk_json},
+    waf::Waf,
 };
 use once_cell::sync::Lazy;
-use pingora::proxy::Session;
+use pingora::{http::RequestHeader, proxy::Session};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Route lookups cached when `route_cache_capacity` is not set
+pub const DEFAULT_ROUTE_CACHE_CAPACITY: usize = 10_000;
+
+/// The route cache is split so a lookup only locks the shard its key hashes to
+const ROUTE_CACHE_SHARDS: usize = 16;
+
+/// A cached match, with its key to tell hash collisions apart
+struct CachedRoute {
+    route_name: String,
+    method: String,
+    path: String,
+    route: RouteSnapshot,
+    params: HashMap<String, String>,
+}
+
+// LRU cache for route matching, by hash of route name, method and path
+static ROUTE_CACHE: Lazy<Vec<Mutex<LruCache<u64, CachedRoute>>>> = Lazy::new(|| {
+    (0..ROUTE_CACHE_SHARDS)
+        .map(|_| Mutex::new(LruCache::new(shard_capacity(DEFAULT_ROUTE_CACHE_CAPACITY))))
+        .collect()
+});
+
+/// `0` turns the cache off
+static ROUTE_CACHE_CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_ROUTE_CACHE_CAPACITY);
+
+static ROUTE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static ROUTE_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static ROUTE_CACHE_EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Route cache counters since start, for metrics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RouteCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// Everything a request is matched against, replaced whole on reload
+struct RouteTable {
+    /// Path routers by route name
+    matchit: HashMap<String, matchit::Router<RouteSnapshot>>,
+    /// Route names by `host-<host>` and `header-<value>`
+    routes: HashMap<String, String>,
+    /// TLS redirect by host
+    tls_routes: HashMap<String, Option<String>>,
+    /// Error pages by route name, for requests that matched no path
+    error_pages: HashMap<String, Arc<ErrorPages>>,
+}
+
+static ROUTE_TABLE: ArcSwapOption<RouteTable> = ArcSwapOption::const_empty();
+
+static HEADER_SELECTOR: Lazy<ArcSwap<String>> =
+    Lazy::new(|| ArcSwap::from_pointee(store::DEFAULT_HEADER_SELECTOR.to_string()));
+
+/// Snapshots handed out by the current configuration
+static LIVE_SNAPSHOTS: Lazy<Mutex<Vec<Weak<Route>>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Snapshots of replaced configurations, per generation, still referenced
+/// by in-flight requests or WebSocket sessions
+static RETIRED_SNAPSHOTS: Lazy<Mutex<Vec<(u64, Vec<Weak<Route>>)>>> =
+    Lazy::new(|| Mutex::new(vec![]));
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Maximum number of middleware a single request may run (route + path, groups expanded)
+pub const MAX_MIDDLEWARE_CHAIN: usize = 64;
+
+/// Maximum nesting of middleware groups
+pub const MAX_GROUP_DEPTH: usize = 8;
+
+/// Expand middleware groups (which may contain other groups) into plugin items
+///
+/// Unknown groups, cycles, nesting deeper than [`MAX_GROUP_DEPTH`] and chains
+/// longer than [`MAX_MIDDLEWARE_CHAIN`] are rejected.
+pub fn expand_middleware(
+    middleware: &[MiddlewareItem],
+    middleware_groups: &HashMap<String, Vec<MiddlewareItem>>,
+) -> Result<Vec<MiddlewareItem>, NylonError> {
+    let mut expanded = vec![];
+    expand_into(middleware, middleware_groups, &mut vec![], &mut expanded)?;
+    Ok(expanded)
+}
 
-// LRU cache for route matching - cache up to 10,000 route lookups
-static ROUTE_CACHE: Lazy<Mutex<LruCache<String, (Route, HashMap<String, String>)>>> =
-    Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(10_000).unwrap())));
+fn expand_into(
+    middleware: &[MiddlewareItem],
+    middleware_groups: &HashMap<String, Vec<MiddlewareItem>>,
+    stack: &mut Vec<String>,
+    to: &mut Vec<MiddlewareItem>,
+) -> Result<(), NylonError> {
+    for m in middleware {
+        let Some(group) = &m.group else {
+            if to.len() >= MAX_MIDDLEWARE_CHAIN {
+                return Err(NylonError::ConfigError(format!(
+                    "Middleware chain exceeds {} entries",
+                    MAX_MIDDLEWARE_CHAIN
+                )));
+            }
+            to.push(m.clone());
+            continue;
+        };
+        if stack.contains(group) {
+            return Err(NylonError::ConfigError(format!(
+                "Middleware group cycle: {} -> {}",
+                stack.join(" -> "),
+                group
+            )));
+        }
+        if stack.len() >= MAX_GROUP_DEPTH {
+            return Err(NylonError::ConfigError(format!(
+                "Middleware group {} is nested deeper than {} levels",
+                group, MAX_GROUP_DEPTH
+            )));
+        }
+        let items = middleware_groups.get(group).ok_or_else(|| {
+            NylonError::ConfigError(format!("Middleware group {} does not exist", group))
+        })?;
+        stack.push(group.clone());
+        expand_into(items, middleware_groups, stack, to)?;
+        stack.pop();
+    }
+    Ok(())
+}
 
 fn parsed_middleware(
     middleware: Vec<MiddlewareItem>,
     to: &mut Vec<(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)>,
-) {
-    for m in middleware {
+) -> Result<(), NylonError> {
+    for mut m in middleware {
+        // Waf rules are compiled, and their files read, once here
+        if m.plugin.as_deref() == Some("Waf") {
+            m.waf = Some(Arc::new(Waf::from_payload(m.payload.as_ref())?));
+        }
         let mut payload_ast = HashMap::<String, Vec<Expr>>::new();
         if let Some(payload) = &m.payload {
             walk_json(payload, "".to_string(), &mut |path, val| {
@@ -37,6 +168,7 @@ fn parsed_middleware(
         }
         to.push((m, Some(payload_ast)));
     }
+    Ok(())
 }
 
 pub fn store(
@@ -48,6 +180,8 @@ pub fn store(
     let mut store_route = HashMap::new();
     let mut globa_routes_matchit = HashMap::new();
     let mut tls_routes = HashMap::new();
+    let mut error_pages = HashMap::new();
+    let mut snapshots = vec![];
     for route in routes {
         if let Some(tls) = &route.tls
             && tls.enabled
@@ -58,25 +192,98 @@ pub fn store(
         }
         process_route_matcher(route, &mut store_route)?;
         let route_middleware = process_route_middleware(route, &middleware_groups)?;
-        let matchit_route =
-            create_matchit_router(route, services, &route_middleware, &middleware_groups)?;
+        let pages = load_error_pages(route)?;
+        if let Some(pages) = &pages {
+            error_pages.insert(route.name.clone(), pages.clone());
+        }
+        let matchit_route = create_matchit_router(
+            route,
+            services,
+            &route_middleware,
+            &middleware_groups,
+            &pages,
+            &mut snapshots,
+        )?;
         globa_routes_matchit.insert(route.name.clone(), matchit_route);
     }
 
-    store::insert(store::KEY_ROUTES_MATCHIT, globa_routes_matchit);
-    store::insert(store::KEY_ROUTES, store_route);
-    store::insert(store::KEY_TLS_ROUTES, tls_routes);
+    ROUTE_TABLE.store(Some(Arc::new(RouteTable {
+        matchit: globa_routes_matchit,
+        routes: store_route,
+        tls_routes,
+        error_pages,
+    })));
 
     // Clear route cache when routes are reloaded
     clear_route_cache();
+    retire_snapshots(snapshots);
 
     Ok(())
 }
 
+/// Move the snapshots of the previous configuration to the retired list
+///
+/// New requests only see the freshly stored routes. Requests that already
+/// matched keep their [`RouteSnapshot`] until they finish, so a route removed
+/// by a reload stays alive exactly as long as someone is still using it.
+fn retire_snapshots(snapshots: Vec<Weak<Route>>) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    let Ok(mut live) = LIVE_SNAPSHOTS.lock() else {
+        return;
+    };
+    let previous = std::mem::replace(&mut *live, snapshots);
+    let in_use: Vec<Weak<Route>> = previous
+        .into_iter()
+        .filter(|route| route.strong_count() > 0)
+        .collect();
+    if !in_use.is_empty()
+        && let Ok(mut retired) = RETIRED_SNAPSHOTS.lock()
+    {
+        retired.push((generation, in_use));
+    }
+}
+
+/// Drop retired snapshots nobody holds anymore
+///
+/// Returns the configuration generations that finished draining.
+pub fn reap_retired_routes() -> Vec<u64> {
+    let Ok(mut retired) = RETIRED_SNAPSHOTS.lock() else {
+        return vec![];
+    };
+    let mut drained = vec![];
+    retired.retain_mut(|(generation, routes)| {
+        routes.retain(|route| route.strong_count() > 0);
+        if routes.is_empty() {
+            drained.push(*generation);
+            false
+        } else {
+            true
+        }
+    });
+    drained
+}
+
+/// Number of retired route snapshots still in use
+pub fn retired_routes_in_use() -> usize {
+    RETIRED_SNAPSHOTS
+        .lock()
+        .map(|retired| {
+            retired
+                .iter()
+                .map(|(_, routes)| routes.iter().filter(|r| r.strong_count() > 0).count())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Set the header that selects a `header` route
+pub fn set_header_selector(selector: String) {
+    HEADER_SELECTOR.store(Arc::new(selector));
+}
+
 pub fn get_tls_route(host: &str) -> Result<Option<String>, NylonError> {
-    let tls_routes = store::get::<HashMap<String, Option<String>>>(store::KEY_TLS_ROUTES)
-        .ok_or_else(|| NylonError::ShouldNeverHappen("TLS routes not found in store".into()))?;
-    let tls_route = tls_routes.get(host).ok_or_else(|| {
+    let table = get_route_table()?;
+    let tls_route = table.tls_routes.get(host).ok_or_else(|| {
         NylonError::RouteNotFound(format!("TLS route not found for host: {}", host))
     })?;
     Ok(tls_route.clone())
@@ -84,21 +291,53 @@ pub fn get_tls_route(host: &str) -> Result<Option<String>, NylonError> {
 
 /// Clear the route cache - useful when routes are reloaded
 pub fn clear_route_cache() {
-    if let Ok(mut cache) = ROUTE_CACHE.lock() {
-        cache.clear();
-        tracing::info!("Route cache cleared");
+    for shard in ROUTE_CACHE.iter() {
+        if let Ok(mut cache) = shard.lock() {
+            cache.clear();
+        }
+    }
+    tracing::info!("Route cache cleared");
+}
+
+/// Set how many route lookups are cached; `0` turns the cache off
+pub fn set_route_cache_capacity(capacity: usize) {
+    if ROUTE_CACHE_CAPACITY.swap(capacity, Ordering::Relaxed) == capacity {
+        return;
+    }
+    for shard in ROUTE_CACHE.iter() {
+        if let Ok(mut cache) = shard.lock() {
+            cache.resize(shard_capacity(capacity));
+            if capacity == 0 {
+                cache.clear();
+            }
+        }
     }
 }
 
 /// Get route cache statistics
-pub fn get_route_cache_stats() -> (usize, usize) {
-    if let Ok(cache) = ROUTE_CACHE.lock() {
-        (cache.len(), cache.cap().get())
-    } else {
-        (0, 0)
+pub fn get_route_cache_stats() -> RouteCacheStats {
+    RouteCacheStats {
+        entries: ROUTE_CACHE
+            .iter()
+            .filter_map(|shard| shard.lock().ok().map(|cache| cache.len()))
+            .sum(),
+        capacity: ROUTE_CACHE_CAPACITY.load(Ordering::Relaxed),
+        hits: ROUTE_CACHE_HITS.load(Ordering::Relaxed),
+        misses: ROUTE_CACHE_MISSES.load(Ordering::Relaxed),
+        evictions: ROUTE_CACHE_EVICTIONS.load(Ordering::Relaxed),
     }
 }
 
+fn shard_capacity(capacity: usize) -> NonZeroUsize {
+    NonZeroUsize::new(capacity.div_ceil(ROUTE_CACHE_SHARDS)).unwrap_or(NonZeroUsize::MIN)
+}
+
+fn route_cache_key(route_name: &str, method: &str, path: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    (route_name, method, path).hash(&mut hasher);
+    hasher.finish()
+}
+
 fn process_route_matcher(
     route: &RouteConfig,
     store_route: &mut HashMap<String, String>,
@@ -130,17 +369,52 @@ fn process_route_middleware(
 ) -> Result<Vec<(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)>, NylonError> {
     let mut route_middleware = vec![];
     if let Some(middleware) = &route.middleware {
-        for m in middleware {
-            if let Some(group) = &m.group {
-                if let Some(plugins) = middleware_groups.get(group) {
-                    parsed_middleware(plugins.clone(), &mut route_middleware);
+        parsed_middleware(
+            expand_middleware(middleware, middleware_groups)?,
+            &mut route_middleware,
+        )?;
+    }
+    Ok(route_middleware)
+}
+
+/// Read the file pages and parse the templates of a route's `error_pages`
+fn load_error_pages(route: &RouteConfig) -> Result<Option<Arc<ErrorPages>>, NylonError> {
+    let Some(pages) = &route.error_pages else {
+        return Ok(None);
+    };
+    let mut loaded = ErrorPages::new();
+    for (key, page) in pages {
+        let page = match &page.file {
+            Some(file) => {
+                let body = std::fs::read(file).map_err(|e| {
+                    NylonError::ConfigError(format!(
+                        "Error page {} of route {}: {}: {}",
+                        key, route.name, file, e
+                    ))
+                })?;
+                LoadedErrorPage::File {
+                    content_type: mime_guess::from_path(file)
+                        .first_or_octet_stream()
+                        .to_string(),
+                    body: Bytes::from(body),
                 }
-                continue;
             }
-            parsed_middleware(vec![m.clone()], &mut route_middleware);
-        }
+            None => LoadedErrorPage::Template {
+                html: page
+                    .html
+                    .as_deref()
+                    .map(extract_and_parse_templates)
+                    .transpose()?,
+                json: page
+                    .json
+                    .as_deref()
+                    .map(extract_and_parse_templates)
+                    .transpose()?,
+            },
+        };
+        loaded.insert(key.clone(), page);
     }
-    Ok(route_middleware)
+    Ok(Some(Arc::new(loaded)))
 }
 
 fn create_matchit_router(
@@ -148,19 +422,47 @@ fn create_matchit_router(
     services: &Vec<&ServiceItem>,
     route_middleware: &[(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)],
     middleware_groups: &HashMap<String, Vec<MiddlewareItem>>,
-) -> Result<matchit::Router<Route>, NylonError> {
-    let mut matchit_route = matchit::Router::<Route>::new();
+    error_pages: &Option<Arc<ErrorPages>>,
+    snapshots: &mut Vec<Weak<Route>>,
+) -> Result<matchit::Router<RouteSnapshot>, NylonError> {
+    let mut matchit_route = matchit::Router::<RouteSnapshot>::new();
 
     for path in &route.paths {
         let match_path = extract_match_path(path)?;
         let methods = path.methods.clone();
-        let service = create_route_service(path, services, route_middleware, middleware_groups)?;
+        let mut service = create_route_service(
+            &route.name,
+            path,
+            services,
+            route_middleware,
+            middleware_groups,
+        )?;
+        service.accept_ch = route
+            .accept_ch
+            .as_ref()
+            .filter(|hints| !hints.is_empty())
+            .map(|hints| hints.join(", "));
+        service.websocket_max_connections = route.websocket_max_connections;
+        service.max_in_flight = route.max_in_flight;
+        service.error_pages = error_pages.clone();
+        // One snapshot per pattern, so a request knows which pattern it matched
+        let patterns = match_path
+            .iter()
+            .map(|p| {
+                let snapshot = Arc::new(Route {
+                    path: p.to_string(),
+                    ..service.clone()
+                });
+                snapshots.push(Arc::downgrade(&snapshot));
+                (*p, snapshot)
+            })
+            .collect::<Vec<_>>();
 
         if let Some(methods) = methods {
             for method in methods {
-                for p in &match_path {
+                for (p, snapshot) in &patterns {
                     matchit_route
-                        .insert(format!("/{method}{p}"), service.clone())
+                        .insert(format!("/{method}{p}"), snapshot.clone())
                         .map_err(|e| {
                             NylonError::ConfigError(format!("Failed to register route: {e}"))
                         })?;
@@ -168,13 +470,13 @@ fn create_matchit_router(
                 }
             }
         } else {
-            for p in match_path {
+            for (p, snapshot) in &patterns {
                 // matchit_route.insert(p, service.clone()).map_err(|e| {
                 //     NylonError::ConfigError(format!("Failed to register route: {e}"))
                 // })?;
                 for method in HTTP_METHODS {
                     matchit_route
-                        .insert(format!("/{method}{p}"), service.clone())
+                        .insert(format!("/{method}{p}"), snapshot.clone())
                         .map_err(|e| {
                             NylonError::ConfigError(format!("Failed to register route: {e}"))
                         })?;
@@ -208,6 +510,7 @@ fn extract_match_path(path: &PathConfig) -> Result<Vec<&str>, NylonError> {
 }
 
 fn create_route_service(
+    route_name: &str,
     path: &PathConfig,
     services: &Vec<&ServiceItem>,
     route_middleware: &[(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)],
@@ -220,6 +523,41 @@ fn create_route_service(
             NylonError::ConfigError(format!("Service {} not found", path.service.name))
         })?;
 
+    let fallback = match &path.fallback {
+        Some(name) => Some(
+            services
+                .iter()
+                .find(|s| &s.name == name)
+                .map(|s| (*s).clone())
+                .ok_or_else(|| {
+                    NylonError::ConfigError(format!("Fallback service {} not found", name))
+                })?,
+        ),
+        None => None,
+    };
+
+    let devices = match &path.devices {
+        Some(devices) => Some(
+            devices
+                .iter()
+                .map(|(class, name)| {
+                    services
+                        .iter()
+                        .find(|s| &s.name == name)
+                        .map(|s| (*class, (*s).clone()))
+                        .ok_or_else(|| {
+                            NylonError::ConfigError(format!(
+                                "Service {} for {} devices not found",
+                                name,
+                                class.as_str()
+                            ))
+                        })
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?,
+        ),
+        None => None,
+    };
+
     let mut payload_ast = HashMap::<String, Vec<Expr>>::new();
     if let Some(plugin) = &service.plugin
         && let Some(payload) = &plugin.payload
@@ -233,8 +571,33 @@ fn create_route_service(
             }
         });
     }
+    let mut service = service.to_owned().clone();
+    if service.service_type == ServiceType::Template && path.response.is_some() {
+        // The path overrides the response of the template service
+        service.template_conf = path.response.clone();
+    }
+    let template = match &service.template_conf {
+        Some(conf) => Some(ParsedTemplate {
+            body: extract_and_parse_templates(&conf.body)?,
+            headers: conf
+                .headers
+                .iter()
+                .flatten()
+                .map(|(name, value)| Ok((name.clone(), extract_and_parse_templates(value)?)))
+                .collect::<Result<Vec<_>, NylonError>>()?,
+        }),
+        None => None,
+    };
     let mut route = Route {
-        service: service.to_owned().clone(),
+        name: route_name.to_string(),
+        path: String::new(),
+        service,
+        fallback,
+        devices,
+        accept_ch: None,
+        websocket_max_connections: None,
+        max_in_flight: None,
+        error_pages: None,
         rewrite: path.service.rewrite.clone(),
         route_middleware: Some(route_middleware.to_vec()),
         path_middleware: None,
@@ -243,42 +606,69 @@ fn create_route_service(
         } else {
             Some(payload_ast)
         },
+        template,
+        response_body_middleware: false,
     };
 
     if let Some(middleware) = &path.middleware {
         let mut middleware_items = vec![];
-        for m in middleware {
-            if let Some(group) = &m.group {
-                if let Some(plugins) = middleware_groups.get(group) {
-                    parsed_middleware(plugins.clone(), &mut middleware_items);
-                }
-                continue;
-            }
-            parsed_middleware(vec![m.clone()], &mut middleware_items);
+        parsed_middleware(
+            expand_middleware(middleware, middleware_groups)?,
+            &mut middleware_items,
+        )?;
+        if route_middleware.len() + middleware_items.len() > MAX_MIDDLEWARE_CHAIN {
+            return Err(NylonError::ConfigError(format!(
+                "Route {} path {} runs more than {} middleware",
+                route_name, path.path, MAX_MIDDLEWARE_CHAIN
+            )));
         }
         route.path_middleware = Some(middleware_items);
     }
+    route.response_body_middleware = route
+        .route_middleware
+        .iter()
+        .chain(route.path_middleware.iter())
+        .flatten()
+        .any(|(middleware, _)| middleware.entry.is_some());
 
     Ok(route)
 }
 
-pub fn find_route(session: &Session) -> Result<(Route, HashMap<String, String>), NylonError> {
+pub fn find_route(
+    session: &Session,
+) -> Result<(RouteSnapshot, HashMap<String, String>), NylonError> {
     let (path, host, method) = get_request_info(session)?;
-    let routes_matchit = get_routes_matchit()?;
-    let header_selector = get_header_selector()?;
-    let store_route = get_store_route()?;
+    let header_selector = HEADER_SELECTOR.load();
+    let selector = session
+        .req_header()
+        .headers
+        .get(header_selector.as_str())
+        .map(|value| value.to_str().unwrap_or_default());
+    match_route(&host, selector, &method, &path)
+}
+
+/// Match a request by host, `header_selector` header value, method and path
+///
+/// This is the lookup [`find_route`] does for a session, for tools that
+/// have no session.
+pub fn match_route(
+    host: &str,
+    selector: Option<&str>,
+    method: &str,
+    path: &str,
+) -> Result<(RouteSnapshot, HashMap<String, String>), NylonError> {
+    let table = get_route_table()?;
 
     // Check header match
-    if let Some(header_value) = session.req_header().headers.get(&header_selector) {
-        let value = header_value.to_str().unwrap_or_default();
-        if let Some(route_name) = store_route.get(&format!("header-{value}")) {
-            return find_matching_route(&routes_matchit, route_name, &path, &method);
-        }
+    if let Some(value) = selector
+        && let Some(route_name) = table.routes.get(&format!("header-{value}"))
+    {
+        return find_matching_route(&table.matchit, route_name, path, method);
     }
 
     // Fallback to host match
-    if let Some(route_name) = store_route.get(&format!("host-{host}")) {
-        return find_matching_route(&routes_matchit, route_name, &path, &method);
+    if let Some(route_name) = table.routes.get(&format!("host-{host}")) {
+        return find_matching_route(&table.matchit, route_name, path, method);
     }
 
     Err(NylonError::RouteNotFound(format!(
@@ -286,19 +676,34 @@ pub fn find_route(session: &Session) -> Result<(Route, HashMap<String, String>),
     )))
 }
 
-fn get_routes_matchit() -> Result<HashMap<String, matchit::Router<Route>>, NylonError> {
-    store::get::<HashMap<String, matchit::Router<Route>>>(store::KEY_ROUTES_MATCHIT)
-        .ok_or_else(|| NylonError::ShouldNeverHappen("Route matcher not found in store".into()))
-}
-
-fn get_header_selector() -> Result<String, NylonError> {
-    store::get::<String>(store::KEY_HEADER_SELECTOR)
-        .ok_or_else(|| NylonError::ShouldNeverHappen("Header selector not configured".into()))
+/// Error pages of the route a request's host or `header_selector` header
+/// picks, for requests that matched none of its paths
+pub fn find_error_pages(header: &RequestHeader) -> Option<Arc<ErrorPages>> {
+    let table = ROUTE_TABLE.load_full()?;
+    let selector = header
+        .headers
+        .get(HEADER_SELECTOR.load().as_str())
+        .and_then(|value| value.to_str().ok());
+    let host = match header.uri.host() {
+        Some(host) => host,
+        None => host_name(
+            header
+                .headers
+                .get("host")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or_default(),
+        ),
+    };
+    let route_name = selector
+        .and_then(|value| table.routes.get(&format!("header-{value}")))
+        .or_else(|| table.routes.get(&format!("host-{host}")))?;
+    table.error_pages.get(route_name).cloned()
 }
 
-fn get_store_route() -> Result<HashMap<String, String>, NylonError> {
-    store::get::<HashMap<String, String>>(store::KEY_ROUTES)
-        .ok_or_else(|| NylonError::ShouldNeverHappen("Route map not found in store".into()))
+fn get_route_table() -> Result<Arc<RouteTable>, NylonError> {
+    ROUTE_TABLE
+        .load_full()
+        .ok_or_else(|| NylonError::ShouldNeverHappen("Route matcher not found in store".into()))
 }
 
 fn get_request_info(session: &Session) -> Result<(String, String, String), NylonError> {
@@ -323,39 +728,61 @@ fn get_http2_request_info(session: &Session) -> Result<(String, String, String),
 
 fn get_http1_request_info(session: &Session) -> Result<(String, String, String), NylonError> {
     let path = session.req_header().uri.path().to_string();
-    let host = session
-        .get_header("host")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or_default()
-        .split(':')
-        .next()
-        .unwrap_or("")
-        .to_string();
+    let host = host_name(
+        session
+            .get_header("host")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default(),
+    )
+    .to_string();
     let method = session.req_header().method.to_string();
 
     Ok((path, host, method))
 }
 
+/// Host of a `Host` header without its port
+///
+/// IPv6 literals keep their brackets (`[::1]:8080` is `[::1]`), as in the
+/// `:authority` of HTTP/2 requests. A bare IPv6 address is left whole.
+pub fn host_name(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        return match authority.find(']') {
+            Some(end) => &authority[..=end],
+            None => authority,
+        };
+    }
+    match authority.split_once(':') {
+        Some((host, port)) if !port.contains(':') => host,
+        _ => authority,
+    }
+}
+
 fn find_matching_route(
-    routes_matchit: &HashMap<String, matchit::Router<Route>>,
+    routes_matchit: &HashMap<String, matchit::Router<RouteSnapshot>>,
     route_name: &str,
     path: &str,
     method: &str,
-) -> Result<(Route, HashMap<String, String>), NylonError> {
-    // let now = std::time::Instant::now();
-    // Create cache key from route_name, method, and path
-    let cache_key = format!("{}:{}:{}", route_name, method, path);
+) -> Result<(RouteSnapshot, HashMap<String, String>), NylonError> {
+    let caching = ROUTE_CACHE_CAPACITY.load(Ordering::Relaxed) > 0;
+    let key = route_cache_key(route_name, method, path);
+    let shard = &ROUTE_CACHE[key as usize % ROUTE_CACHE_SHARDS];
 
     // Check cache first
-    if let Ok(mut cache) = ROUTE_CACHE.lock()
-        && let Some(cached) = cache.get(&cache_key)
+    if caching
+        && let Ok(mut cache) = shard.lock()
+        && let Some(cached) = cache.get(&key)
+        && cached.route_name == route_name
+        && cached.method == method
+        && cached.path == path
     {
-        // println!("Time taken to find matching route: {:?}", now.elapsed());
-        tracing::debug!("Route cache hit: {}:{}:{}", route_name, method, path);
-        return Ok(cached.clone());
+        ROUTE_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok((cached.route.clone(), cached.params.clone()));
     }
 
     // Cache miss - perform actual route matching
+    if caching {
+        ROUTE_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
     tracing::debug!("Route cache miss: {}:{}:{}", route_name, method, path);
 
     let router = routes_matchit
@@ -382,11 +809,188 @@ fn find_matching_route(
         .map(|(k, v)| (k.to_string(), v.to_string()))
         .collect();
 
-    // println!("Time taken to find matching route: {:?}", now.elapsed());
     // Store in cache
-    if let Ok(mut cache) = ROUTE_CACHE.lock() {
-        cache.put(cache_key, (route.clone(), params.clone()));
+    if caching && let Ok(mut cache) = shard.lock() {
+        let entry = CachedRoute {
+            route_name: route_name.to_string(),
+            method: method.to_string(),
+            path: path.to_string(),
+            route: route.clone(),
+            params: params.clone(),
+        };
+        if let Some((evicted, _)) = cache.push(key, entry)
+            && evicted != key
+        {
+            ROUTE_CACHE_EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     Ok((route, params))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The route cache is global; tests that use it take turns
+    static CACHE_TESTS: Mutex<()> = Mutex::new(());
+
+    fn route(name: &str) -> RouteSnapshot {
+        let service = serde_json::from_value::<ServiceItem>(serde_json::json!({
+            "name": "svc",
+            "service_type": "http",
+        }))
+        .unwrap();
+        Arc::new(Route {
+            name: name.to_string(),
+            path: String::new(),
+            service,
+            fallback: None,
+            devices: None,
+            accept_ch: None,
+            websocket_max_connections: None,
+            max_in_flight: None,
+            error_pages: None,
+            rewrite: None,
+            route_middleware: None,
+            path_middleware: None,
+            payload_ast: None,
+            template: None,
+            response_body_middleware: false,
+        })
+    }
+
+    fn routers() -> HashMap<String, matchit::Router<RouteSnapshot>> {
+        let mut router = matchit::Router::new();
+        router.insert("/users/{id}", route("users")).unwrap();
+        HashMap::from([("main".to_string(), router)])
+    }
+
+    fn cached(key: u64) -> bool {
+        ROUTE_CACHE[key as usize % ROUTE_CACHE_SHARDS]
+            .lock()
+            .unwrap()
+            .contains(&key)
+    }
+
+    #[test]
+    fn test_route_cache_shards() {
+        let _turn = CACHE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(shard_capacity(0).get(), 1);
+        assert_eq!(shard_capacity(1).get(), 1);
+        assert_eq!(shard_capacity(ROUTE_CACHE_SHARDS).get(), 1);
+        assert_eq!(shard_capacity(ROUTE_CACHE_SHARDS + 1).get(), 2);
+
+        set_route_cache_capacity(ROUTE_CACHE_SHARDS * 2);
+        clear_route_cache();
+        let routers = routers();
+
+        // A lookup lands in the shard of its key only, and hits from there
+        let key = route_cache_key("main", "GET", "/users/1");
+        let hits = ROUTE_CACHE_HITS.load(Ordering::Relaxed);
+        find_matching_route(&routers, "main", "/users/1", "GET").unwrap();
+        assert!(cached(key));
+        for (i, shard) in ROUTE_CACHE.iter().enumerate() {
+            let len = shard.lock().unwrap().len();
+            assert_eq!(len, usize::from(i == key as usize % ROUTE_CACHE_SHARDS));
+        }
+        let (found, params) = find_matching_route(&routers, "main", "/users/1", "GET").unwrap();
+        assert_eq!(found.name, "users");
+        assert_eq!(params.get("id").map(String::as_str), Some("1"));
+        assert_eq!(ROUTE_CACHE_HITS.load(Ordering::Relaxed), hits + 1);
+
+        // A full shard evicts its oldest lookup, the others keep theirs
+        let shard = key as usize % ROUTE_CACHE_SHARDS;
+        let same_shard: Vec<String> = (2..)
+            .map(|i| format!("/users/{}", i))
+            .filter(|path| {
+                route_cache_key("main", "GET", path) as usize % ROUTE_CACHE_SHARDS == shard
+            })
+            .take(2)
+            .collect();
+        let evictions = ROUTE_CACHE_EVICTIONS.load(Ordering::Relaxed);
+        for path in &same_shard {
+            find_matching_route(&routers, "main", path, "GET").unwrap();
+        }
+        assert!(!cached(key));
+        assert_eq!(ROUTE_CACHE_EVICTIONS.load(Ordering::Relaxed), evictions + 1);
+        assert_eq!(get_route_cache_stats().entries, 2);
+
+        set_route_cache_capacity(DEFAULT_ROUTE_CACHE_CAPACITY);
+        clear_route_cache();
+    }
+
+    #[test]
+    fn test_route_cache_tells_collisions_apart() {
+        let _turn = CACHE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        set_route_cache_capacity(DEFAULT_ROUTE_CACHE_CAPACITY);
+        clear_route_cache();
+        let routers = routers();
+
+        // Another lookup cached under the same hash
+        let key = route_cache_key("main", "GET", "/users/1");
+        let _ = ROUTE_CACHE[key as usize % ROUTE_CACHE_SHARDS]
+            .lock()
+            .unwrap()
+            .push(
+                key,
+                CachedRoute {
+                    route_name: "main".to_string(),
+                    method: "GET".to_string(),
+                    path: "/other".to_string(),
+                    route: route("other"),
+                    params: HashMap::new(),
+                },
+            );
+        let misses = ROUTE_CACHE_MISSES.load(Ordering::Relaxed);
+        let (found, _) = find_matching_route(&routers, "main", "/users/1", "GET").unwrap();
+        assert_eq!(found.name, "users");
+        assert_eq!(ROUTE_CACHE_MISSES.load(Ordering::Relaxed), misses + 1);
+
+        // The real lookup replaced it
+        let cache = ROUTE_CACHE[key as usize % ROUTE_CACHE_SHARDS]
+            .lock()
+            .unwrap();
+        assert_eq!(cache.peek(&key).map(|c| c.path.as_str()), Some("/users/1"));
+        drop(cache);
+        clear_route_cache();
+    }
+
+    #[test]
+    fn test_route_cache_capacity_zero() {
+        let _turn = CACHE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        set_route_cache_capacity(DEFAULT_ROUTE_CACHE_CAPACITY);
+        let routers = routers();
+        find_matching_route(&routers, "main", "/users/1", "GET").unwrap();
+        assert!(get_route_cache_stats().entries > 0);
+
+        // Turning the cache off empties it and stops counting
+        set_route_cache_capacity(0);
+        assert_eq!(get_route_cache_stats().entries, 0);
+        let before = get_route_cache_stats();
+        for _ in 0..3 {
+            let (found, _) = find_matching_route(&routers, "main", "/users/1", "GET").unwrap();
+            assert_eq!(found.name, "users");
+        }
+        let after = get_route_cache_stats();
+        assert_eq!(after.entries, 0);
+        assert_eq!(after.capacity, 0);
+        assert_eq!((after.hits, after.misses), (before.hits, before.misses));
+        assert!(find_matching_route(&routers, "main", "/missing", "GET").is_err());
+
+        set_route_cache_capacity(DEFAULT_ROUTE_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(host_name("example.com"), "example.com");
+        assert_eq!(host_name("example.com:8080"), "example.com");
+        assert_eq!(host_name("10.0.0.1:443"), "10.0.0.1");
+        assert_eq!(host_name("[::1]"), "[::1]");
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+        assert_eq!(host_name("[2001:db8::1]:443"), "[2001:db8::1]");
+        assert_eq!(host_name("2001:db8::1"), "2001:db8::1");
+        assert_eq!(host_name("[::1"), "[::1");
+        assert_eq!(host_name(""), "");
+    }
+}
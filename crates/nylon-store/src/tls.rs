@@ -2,7 +2,7 @@ use crate::{KEY_ACME_CERTS, KEY_TLS, get, insert};
 use lru::LruCache;
 use nylon_error::NylonError;
 use nylon_tls::CertificateInfo;
-use nylon_types::tls::{TlsConfig, TlsKind};
+use nylon_types::tls::{AcmeConfig, TlsConfig, TlsKind};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
@@ -22,6 +22,7 @@ pub struct TlsStore {
 pub fn store(tls: Vec<&TlsConfig>, acme_dir: Option<String>) -> Result<(), NylonError> {
     let mut tls_store = HashMap::new();
     let mut acme_configs = HashMap::new();
+    let mut acme_accounts = HashMap::new();
 
     for t in tls {
         match t.kind {
@@ -69,6 +70,7 @@ pub fn store(tls: Vec<&TlsConfig>, acme_dir: Option<String>) -> Result<(), Nylon
                     if acme_config.acme_dir.is_none() {
                         acme_config.acme_dir = acme_dir.clone();
                     }
+                    check_acme_account(&mut acme_accounts, &acme_config)?;
                     for domain in &t.domains {
                         acme_configs.insert(domain.clone(), acme_config.clone());
                     }
@@ -92,6 +94,40 @@ pub fn store(tls: Vec<&TlsConfig>, acme_dir: Option<String>) -> Result<(), Nylon
     Ok(())
 }
 
+/// Every config using the same account must describe the same account
+fn check_acme_account(
+    accounts: &mut HashMap<(Option<String>, Option<String>), AcmeConfig>,
+    config: &AcmeConfig,
+) -> Result<(), NylonError> {
+    if let Some(name) = &config.account
+        && !nylon_tls::acme::is_valid_account_name(name)
+    {
+        return Err(NylonError::ConfigError(format!(
+            "Invalid ACME account name {}: use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    let key = (config.acme_dir.clone(), config.account.clone());
+    let Some(existing) = accounts.get(&key) else {
+        accounts.insert(key, config.clone());
+        return Ok(());
+    };
+    let same = existing.provider.eq_ignore_ascii_case(&config.provider)
+        && existing.email == config.email
+        && existing.staging == config.staging
+        && existing.directory_url == config.directory_url
+        && existing.eab_kid == config.eab_kid;
+    if same {
+        return Ok(());
+    }
+    Err(NylonError::ConfigError(format!(
+        "ACME account {} is configured with different providers or emails ({} and {}); give each account its own `account` name",
+        config.account.as_deref().unwrap_or("default"),
+        existing.email,
+        config.email
+    )))
+}
+
 pub fn get_certs(domain: &str) -> Result<TlsStore, NylonError> {
     // Check cache first
     if let Ok(mut cache) = TLS_CERT_CACHE.lock()
@@ -158,6 +194,21 @@ pub fn store_acme_cert(cert_info: CertificateInfo) -> Result<(), NylonError> {
     Ok(())
 }
 
+/// Remove an ACME certificate from the store
+pub fn remove_acme_cert(domain: &str) -> Option<CertificateInfo> {
+    let mut acme_certs =
+        get::<HashMap<String, CertificateInfo>>(KEY_ACME_CERTS).unwrap_or_default();
+
+    let removed = acme_certs.remove(domain);
+    insert::<HashMap<String, CertificateInfo>>(KEY_ACME_CERTS, acme_certs);
+
+    if let Ok(mut cache) = TLS_CERT_CACHE.lock() {
+        cache.pop(domain);
+    }
+
+    removed
+}
+
 /// Clear TLS certificate cache - useful when certificates are reloaded
 pub fn clear_tls_cert_cache() {
     if let Ok(mut cache) = TLS_CERT_CACHE.lock() {
@@ -32,6 +32,11 @@ pub trait WebSocketAdapter: Send + Sync {
     /// Get all rooms for a connection
     async fn get_connection_rooms(&self, connection_id: &str) -> Result<Vec<String>, NylonError>;
 
+    /// Number of connections in a room
+    async fn room_size(&self, room: &str) -> Result<usize, NylonError> {
+        Ok(self.get_room_connections(room).await?.len())
+    }
+
     /// Broadcast message to all connections in a room
     async fn broadcast_to_room(
         &self,
@@ -40,6 +45,13 @@ pub trait WebSocketAdapter: Send + Sync {
         exclude_connection: Option<&str>,
     ) -> Result<(), NylonError>;
 
+    /// Broadcast message to all connections of the cluster
+    async fn broadcast_all(
+        &self,
+        message: WebSocketMessage,
+        exclude_connection: Option<&str>,
+    ) -> Result<(), NylonError>;
+
     /// Send message to a specific connection
     async fn send_to_connection(
         &self,
@@ -47,12 +59,33 @@ pub trait WebSocketAdapter: Send + Sync {
         message: WebSocketMessage,
     ) -> Result<(), NylonError>;
 
+    /// Merge `metadata` into the metadata of a connection
+    async fn set_connection_metadata(
+        &self,
+        connection_id: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), NylonError>;
+
     /// Get connection info
     async fn get_connection(
         &self,
         connection_id: &str,
     ) -> Result<Option<WebSocketConnection>, NylonError>;
 
+    /// Connections of `connection_ids` that still exist, in one round trip where the adapter can
+    async fn get_connections(
+        &self,
+        connection_ids: &[String],
+    ) -> Result<Vec<WebSocketConnection>, NylonError> {
+        let mut connections = Vec::with_capacity(connection_ids.len());
+        for connection_id in connection_ids {
+            if let Some(connection) = self.get_connection(connection_id).await? {
+                connections.push(connection);
+            }
+        }
+        Ok(connections)
+    }
+
     /// Get room info
     async fn get_room(&self, room: &str) -> Result<Option<WebSocketRoom>, NylonError>;
 
@@ -172,6 +205,11 @@ impl WebSocketAdapter for MemoryAdapter {
             .unwrap_or_default())
     }
 
+    async fn room_size(&self, room: &str) -> Result<usize, NylonError> {
+        let rooms = self.rooms.read().await;
+        Ok(rooms.get(room).map_or(0, |conns| conns.len()))
+    }
+
     async fn get_connection_rooms(&self, connection_id: &str) -> Result<Vec<String>, NylonError> {
         let connection_rooms = self.connection_rooms.read().await;
         Ok(connection_rooms
@@ -208,6 +246,21 @@ impl WebSocketAdapter for MemoryAdapter {
         Ok(())
     }
 
+    async fn broadcast_all(
+        &self,
+        message: WebSocketMessage,
+        exclude_connection: Option<&str>,
+    ) -> Result<(), NylonError> {
+        if let Some(sender) = &self.event_sender {
+            let _ = sender.send(WebSocketEvent::BroadcastAll {
+                message,
+                exclude_connection: exclude_connection.map(|s| s.to_string()),
+                sender_node_id: self.node_id.clone(),
+            });
+        }
+        Ok(())
+    }
+
     async fn send_to_connection(
         &self,
         connection_id: &str,
@@ -223,6 +276,18 @@ impl WebSocketAdapter for MemoryAdapter {
         Ok(())
     }
 
+    async fn set_connection_metadata(
+        &self,
+        connection_id: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), NylonError> {
+        let mut connections = self.connections.write().await;
+        if let Some(connection) = connections.get_mut(connection_id) {
+            connection.metadata.extend(metadata);
+        }
+        Ok(())
+    }
+
     async fn get_connection(
         &self,
         connection_id: &str,
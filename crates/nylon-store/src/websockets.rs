@@ -2,12 +2,16 @@ use crate::websocket_adapter::{MemoryAdapter, WebSocketAdapter};
 use dashmap::DashMap;
 use nylon_error::NylonError;
 use nylon_types::websocket::{
-    AdapterType, WebSocketAdapterConfig, WebSocketConnection, WebSocketEvent, WebSocketMessage,
+    AdapterType, RoomPresence, SendQueueOverflow, WebSocketAdapterConfig, WebSocketConnection,
+    WebSocketEvent, WebSocketMessage, default_idle_timeout_secs, default_max_message_bytes,
+    default_ping_interval_secs, default_send_queue_size,
 };
 use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::sync::mpsc::UnboundedSender;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
 
 // WebSocket related constants
 
@@ -18,12 +22,242 @@ pub const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 static WEBSOCKET_ADAPTER: Lazy<RwLock<Option<Arc<dyn WebSocketAdapter>>>> =
     Lazy::new(|| RwLock::new(None));
 
-// Local connection senders to push messages to active sessions
-static LOCAL_SENDERS: Lazy<DashMap<String, UnboundedSender<WebSocketMessage>>> =
-    Lazy::new(DashMap::new);
+// Local connection queues to push messages to active sessions
+static LOCAL_SENDERS: Lazy<DashMap<String, Arc<SendQueue>>> = Lazy::new(DashMap::new);
+
+// Limits of new connections
+static SETTINGS: Lazy<std::sync::RwLock<ConnectionSettings>> =
+    Lazy::new(|| std::sync::RwLock::new(ConnectionSettings::default()));
+
+/// Per-connection limits from the `websocket` config
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    pub send_queue_size: usize,
+    pub on_send_queue_full: SendQueueOverflow,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub max_frame_bytes: usize,
+    pub max_message_bytes: usize,
+    pub max_messages_per_sec: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            send_queue_size: default_send_queue_size(),
+            on_send_queue_full: SendQueueOverflow::default(),
+            ping_interval: Duration::from_secs(default_ping_interval_secs()),
+            idle_timeout: Duration::from_secs(default_idle_timeout_secs()),
+            max_frame_bytes: default_max_message_bytes(),
+            max_message_bytes: default_max_message_bytes(),
+            max_messages_per_sec: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+        }
+    }
+}
+
+// Totals since start, across all connections
+static MESSAGES_DROPPED: AtomicU64 = AtomicU64::new(0);
+static OVERFLOW_CLOSES: AtomicU64 = AtomicU64::new(0);
+
+// Open connections on this node, in total, per route and per client IP
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static OPEN_BY_ROUTE: Lazy<DashMap<String, usize>> = Lazy::new(DashMap::new);
+static OPEN_BY_IP: Lazy<DashMap<String, usize>> = Lazy::new(DashMap::new);
+
+// Upgrades turned away since start, by the limit that was hit
+static REJECTED_UPGRADES: Lazy<DashMap<&'static str, u64>> = Lazy::new(DashMap::new);
+
+/// Place of an open connection in the connection limits, given back on drop
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    route: String,
+    ip: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        for (open, key) in [(&*OPEN_BY_ROUTE, &self.route), (&*OPEN_BY_IP, &self.ip)] {
+            open.remove_if_mut(key, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
+        }
+    }
+}
+
+/// Take a connection slot, or name the limit (`global`, `route` or `ip`) that is reached
+pub fn acquire_connection_slot(
+    route: &str,
+    route_limit: Option<usize>,
+    ip: &str,
+) -> Result<ConnectionSlot, &'static str> {
+    // Count first and check after, so concurrent upgrades cannot both slip under a limit
+    let total = OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    let by_route = {
+        let mut count = OPEN_BY_ROUTE.entry(route.to_string()).or_default();
+        *count += 1;
+        *count
+    };
+    let by_ip = {
+        let mut count = OPEN_BY_IP.entry(ip.to_string()).or_default();
+        *count += 1;
+        *count
+    };
+    let slot = ConnectionSlot {
+        route: route.to_string(),
+        ip: ip.to_string(),
+    };
+
+    let settings = connection_settings();
+    let exceeded = |limit: Option<usize>, open: usize| limit.is_some_and(|limit| open > limit);
+    let rejected = if exceeded(settings.max_connections, total) {
+        Some("global")
+    } else if exceeded(route_limit, by_route) {
+        Some("route")
+    } else if exceeded(settings.max_connections_per_ip, by_ip) {
+        Some("ip")
+    } else {
+        None
+    };
+    match rejected {
+        Some(limit) => {
+            *REJECTED_UPGRADES.entry(limit).or_default() += 1;
+            Err(limit)
+        }
+        None => Ok(slot),
+    }
+}
+
+/// Open connections on this node per route
+pub fn open_connections() -> Vec<(String, usize)> {
+    OPEN_BY_ROUTE
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
+/// Upgrades turned away since start, by limit
+pub fn rejected_upgrades() -> Vec<(&'static str, u64)> {
+    REJECTED_UPGRADES
+        .iter()
+        .map(|entry| (*entry.key(), *entry.value()))
+        .collect()
+}
+
+/// Bounded queue of messages waiting to be written to one client
+#[derive(Debug)]
+pub struct SendQueue {
+    messages: std::sync::Mutex<VecDeque<WebSocketMessage>>,
+    notify: Notify,
+    capacity: usize,
+    overflow: SendQueueOverflow,
+    overflowed: AtomicBool,
+    presence: AtomicBool,
+}
+
+impl SendQueue {
+    fn new(capacity: usize, overflow: SendQueueOverflow) -> Self {
+        Self {
+            messages: std::sync::Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            overflow,
+            overflowed: AtomicBool::new(false),
+            presence: AtomicBool::new(false),
+        }
+    }
+
+    /// Also queue join/leave notifications of the rooms this connection is in
+    pub fn watch_presence(&self) {
+        self.presence.store(true, Ordering::Relaxed);
+    }
+
+    /// Queue a message, applying the overflow policy when full
+    pub fn push(&self, message: WebSocketMessage) {
+        if matches!(message, WebSocketMessage::Presence(_))
+            && !self.presence.load(Ordering::Relaxed)
+        {
+            return;
+        }
+        let Ok(mut messages) = self.messages.lock() else {
+            return;
+        };
+        if messages.len() >= self.capacity {
+            match self.overflow {
+                SendQueueOverflow::DropOldest => {
+                    messages.pop_front();
+                    MESSAGES_DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                SendQueueOverflow::Close => {
+                    if !self.overflowed.swap(true, Ordering::Relaxed) {
+                        OVERFLOW_CLOSES.fetch_add(1, Ordering::Relaxed);
+                    }
+                    messages.clear();
+                    drop(messages);
+                    self.notify.notify_one();
+                    return;
+                }
+            }
+        }
+        messages.push_back(message);
+        drop(messages);
+        self.notify.notify_one();
+    }
+
+    /// Next message for the client; a close once the queue overflowed with `close`
+    pub async fn recv(&self) -> Option<WebSocketMessage> {
+        loop {
+            if self.overflowed.load(Ordering::Relaxed) {
+                return Some(WebSocketMessage::Close {
+                    code: 1008,
+                    reason: "send queue full".to_string(),
+                });
+            }
+            if let Some(message) = self.messages.lock().ok()?.pop_front() {
+                return Some(message);
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Messages waiting right now
+    pub fn depth(&self) -> usize {
+        self.messages.lock().map(|m| m.len()).unwrap_or_default()
+    }
+}
+
+/// Send queue totals for metrics
+#[derive(Debug, Default)]
+pub struct SendQueueStats {
+    pub queued: usize,
+    pub max_depth: usize,
+    pub dropped: u64,
+    pub overflow_closes: u64,
+}
 
 /// Initialize WebSocket adapter with configuration
 pub async fn initialize_adapter(config: Option<WebSocketAdapterConfig>) -> Result<(), NylonError> {
+    if let Some(config) = &config
+        && let Ok(mut settings) = SETTINGS.write()
+    {
+        *settings = ConnectionSettings {
+            send_queue_size: config.send_queue_size,
+            on_send_queue_full: config.on_send_queue_full,
+            // A zero interval would make the ping timer panic
+            ping_interval: Duration::from_secs(config.ping_interval_secs.max(1)),
+            idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+            max_frame_bytes: config.max_frame_bytes,
+            max_message_bytes: config.max_message_bytes,
+            max_messages_per_sec: config.max_messages_per_sec,
+            max_connections: config.max_connections,
+            max_connections_per_ip: config.max_connections_per_ip,
+        };
+    }
     let adapter: Arc<dyn WebSocketAdapter> = match config {
         Some(config) => match config.adapter_type {
             AdapterType::Memory => Arc::new(MemoryAdapter::new()) as Arc<dyn WebSocketAdapter>,
@@ -63,8 +297,8 @@ pub async fn initialize_adapter(config: Option<WebSocketAdapterConfig>) -> Resul
                         message,
                         ..
                     } => {
-                        if let Some(sender) = LOCAL_SENDERS.get(&connection_id) {
-                            let _ = sender.send(message);
+                        if let Some(queue) = LOCAL_SENDERS.get(&connection_id) {
+                            queue.push(message);
                         }
                     }
                     WebSocketEvent::BroadcastToRoom {
@@ -80,12 +314,24 @@ pub async fn initialize_adapter(config: Option<WebSocketAdapterConfig>) -> Resul
                                 {
                                     continue;
                                 }
-                                if let Some(sender) = LOCAL_SENDERS.get(&cid) {
-                                    let _ = sender.send(message.clone());
+                                if let Some(queue) = LOCAL_SENDERS.get(&cid) {
+                                    queue.push(message.clone());
                                 }
                             }
                         }
                     }
+                    WebSocketEvent::BroadcastAll {
+                        message,
+                        exclude_connection,
+                        ..
+                    } => {
+                        for queue in LOCAL_SENDERS.iter() {
+                            if exclude_connection.as_deref() == Some(queue.key().as_str()) {
+                                continue;
+                            }
+                            queue.push(message.clone());
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -111,22 +357,86 @@ pub async fn add_connection(connection: WebSocketConnection) -> Result<(), Nylon
     adapter.add_connection(connection).await
 }
 
-/// Remove a WebSocket connection
+/// Remove a WebSocket connection, announcing it leaves its rooms
+///
+/// A failed step is logged and the cleanup goes on, so one unreachable room
+/// never leaves the connection behind.
 pub async fn remove_connection(connection_id: &str) -> Result<(), NylonError> {
     let adapter = get_adapter().await?;
-    adapter.remove_connection(connection_id).await
+    let rooms = adapter
+        .get_connection_rooms(connection_id)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Rooms of WebSocket connection {}: {}", connection_id, e);
+            vec![]
+        });
+    let metadata = connection_metadata(&adapter, connection_id)
+        .await
+        .unwrap_or_default();
+    let removed = adapter.remove_connection(connection_id).await;
+    for room in rooms {
+        if let Err(e) =
+            announce_presence(&adapter, connection_id, &room, false, metadata.clone()).await
+        {
+            tracing::warn!(
+                "Announcing that {} left room {}: {}",
+                connection_id,
+                room,
+                e
+            );
+        }
+    }
+    removed
 }
 
 /// Join a connection to a room
 pub async fn join_room(connection_id: &str, room: &str) -> Result<(), NylonError> {
     let adapter = get_adapter().await?;
-    adapter.join_room(connection_id, room).await
+    adapter.join_room(connection_id, room).await?;
+    let metadata = connection_metadata(&adapter, connection_id).await?;
+    announce_presence(&adapter, connection_id, room, true, metadata).await
 }
 
 /// Leave a connection from a room
 pub async fn leave_room(connection_id: &str, room: &str) -> Result<(), NylonError> {
     let adapter = get_adapter().await?;
-    adapter.leave_room(connection_id, room).await
+    adapter.leave_room(connection_id, room).await?;
+    let metadata = connection_metadata(&adapter, connection_id).await?;
+    announce_presence(&adapter, connection_id, room, false, metadata).await
+}
+
+async fn connection_metadata(
+    adapter: &Arc<dyn WebSocketAdapter>,
+    connection_id: &str,
+) -> Result<HashMap<String, String>, NylonError> {
+    Ok(adapter
+        .get_connection(connection_id)
+        .await?
+        .map(|connection| connection.metadata)
+        .unwrap_or_default())
+}
+
+/// Tell the other members of a room that a connection joined or left it
+async fn announce_presence(
+    adapter: &Arc<dyn WebSocketAdapter>,
+    connection_id: &str,
+    room: &str,
+    joined: bool,
+    metadata: HashMap<String, String>,
+) -> Result<(), NylonError> {
+    let presence = RoomPresence {
+        room: room.to_string(),
+        connection_id: connection_id.to_string(),
+        joined,
+        metadata,
+    };
+    adapter
+        .broadcast_to_room(
+            room,
+            WebSocketMessage::Presence(presence),
+            Some(connection_id),
+        )
+        .await
 }
 
 /// Broadcast message to all connections in a room
@@ -141,6 +451,15 @@ pub async fn broadcast_to_room(
         .await
 }
 
+/// Broadcast message to every connection in the cluster
+pub async fn broadcast_all(
+    message: WebSocketMessage,
+    exclude_connection: Option<&str>,
+) -> Result<(), NylonError> {
+    let adapter = get_adapter().await?;
+    adapter.broadcast_all(message, exclude_connection).await
+}
+
 /// Send message to a specific connection
 pub async fn send_to_connection(
     connection_id: &str,
@@ -156,15 +475,53 @@ pub async fn get_room_connections(room: &str) -> Result<Vec<String>, NylonError>
     adapter.get_room_connections(room).await
 }
 
+/// Number of connections in a room across all nodes
+pub async fn room_size(room: &str) -> Result<usize, NylonError> {
+    let adapter = get_adapter().await?;
+    adapter.room_size(room).await
+}
+
+/// Connections in a room with their metadata; ones that closed meanwhile are skipped
+pub async fn room_members(room: &str) -> Result<Vec<WebSocketConnection>, NylonError> {
+    let adapter = get_adapter().await?;
+    let connection_ids = adapter.get_room_connections(room).await?;
+    adapter.get_connections(&connection_ids).await
+}
+
 /// Get all rooms for a connection
 pub async fn get_connection_rooms(connection_id: &str) -> Result<Vec<String>, NylonError> {
     let adapter = get_adapter().await?;
     adapter.get_connection_rooms(connection_id).await
 }
 
-/// Register a local sender for a connection to receive cluster messages
-pub fn register_local_sender(connection_id: String, sender: UnboundedSender<WebSocketMessage>) {
-    LOCAL_SENDERS.insert(connection_id, sender);
+/// Merge metadata into a connection, visible to every node
+pub async fn set_connection_metadata(
+    connection_id: &str,
+    metadata: HashMap<String, String>,
+) -> Result<(), NylonError> {
+    let adapter = get_adapter().await?;
+    adapter
+        .set_connection_metadata(connection_id, metadata)
+        .await
+}
+
+/// Get a connection, which may live on another node
+pub async fn get_connection(
+    connection_id: &str,
+) -> Result<Option<WebSocketConnection>, NylonError> {
+    let adapter = get_adapter().await?;
+    adapter.get_connection(connection_id).await
+}
+
+/// Register a local send queue for a connection to receive cluster messages
+pub fn register_local_sender(connection_id: String) -> Arc<SendQueue> {
+    let settings = connection_settings();
+    let queue = Arc::new(SendQueue::new(
+        settings.send_queue_size,
+        settings.on_send_queue_full,
+    ));
+    LOCAL_SENDERS.insert(connection_id, queue.clone());
+    queue
 }
 
 /// Unregister a local sender when a connection closes
@@ -172,6 +529,29 @@ pub fn unregister_local_sender(connection_id: &str) {
     LOCAL_SENDERS.remove(connection_id);
 }
 
+/// Limits of new connections
+pub fn connection_settings() -> ConnectionSettings {
+    SETTINGS
+        .read()
+        .map(|settings| *settings)
+        .unwrap_or_default()
+}
+
+/// Depth of the local send queues
+pub fn send_queue_stats() -> SendQueueStats {
+    let mut stats = SendQueueStats {
+        dropped: MESSAGES_DROPPED.load(Ordering::Relaxed),
+        overflow_closes: OVERFLOW_CLOSES.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    for queue in LOCAL_SENDERS.iter() {
+        let depth = queue.depth();
+        stats.queued += depth;
+        stats.max_depth = stats.max_depth.max(depth);
+    }
+    stats
+}
+
 /// Get current node id from adapter
 pub async fn get_node_id() -> Result<String, NylonError> {
     let adapter = get_adapter().await?;
@@ -1,12 +1,18 @@
+use dashmap::DashMap;
 use instant_acme::{
     Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
-    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+    NewAccount, NewOrder, OrderStatus, RetryPolicy, RevocationRequest,
 };
 use nylon_error::NylonError;
 use nylon_types::tls::AcmeConfig;
+use once_cell::sync::Lazy;
+use openssl::x509::X509;
+use rustls_pki_types::CertificateDer;
 use std::fs::OpenOptions;
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tracing::{error, info, warn};
 
@@ -26,19 +32,16 @@ impl RateLimiter {
         }
     }
 
-    /// รอ delay ตาม rate limit
-    async fn wait_if_needed(&mut self) {
-        if let Some(last) = self.last_request {
-            let elapsed = last.elapsed();
-            let required_wait = self.backoff_duration.max(self.min_interval);
-
-            if elapsed < required_wait {
-                let wait_time = required_wait - elapsed;
-                info!("Rate limiting: waiting {:?}", wait_time);
-                tokio::time::sleep(wait_time).await;
-            }
-        }
-        self.last_request = Some(Instant::now());
+    /// Delay before the next request may go out; reserves that slot
+    fn next_delay(&mut self) -> Duration {
+        let now = Instant::now();
+        let required_wait = self.backoff_duration.max(self.min_interval);
+        let delay = match self.last_request {
+            Some(last) => (last + required_wait).saturating_duration_since(now),
+            None => Duration::ZERO,
+        };
+        self.last_request = Some(now + delay);
+        delay
     }
 
     /// เพิ่ม backoff หลังจากเกิด error
@@ -53,11 +56,22 @@ impl RateLimiter {
     }
 }
 
+/// One rate limiter per ACME account, shared by every client using it
+static RATE_LIMITERS: Lazy<DashMap<PathBuf, Arc<Mutex<RateLimiter>>>> = Lazy::new(DashMap::new);
+
+/// Account names become file names, so keep them to `[A-Za-z0-9_-]`
+pub fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 /// ACME Client สำหรับจัดการ certificate ด้วย Let's Encrypt
 pub struct AcmeClient {
     account: Account,
     acme_dir: String,
-    rate_limiter: RateLimiter,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 impl AcmeClient {
@@ -84,10 +98,12 @@ impl AcmeClient {
 
         info!("Using ACME directory: {}", acme_dir);
 
+        let credentials_path = Self::credentials_path(&acme_dir, config.account.as_deref())?;
+
         // สร้าง account ใหม่หรือใช้ account ที่มีอยู่
-        let account = match Self::load_account_credentials(&acme_dir) {
+        let account = match Self::load_account_credentials(&credentials_path) {
             Ok(credentials) => {
-                info!("Using existing ACME account");
+                info!("Using existing ACME account {}", credentials_path.display());
                 Account::builder()
                     .map_err(|e| {
                         NylonError::ConfigError(format!("Failed to build account: {}", e))
@@ -101,15 +117,20 @@ impl AcmeClient {
             Err(_) => {
                 info!("Creating new ACME account");
                 let (account, credentials) = Self::create_new_account(config).await?;
-                Self::save_account_credentials(&credentials, &acme_dir)?;
+                Self::save_account_credentials(&credentials, &credentials_path)?;
                 account
             }
         };
 
+        let rate_limiter = RATE_LIMITERS
+            .entry(credentials_path)
+            .or_insert_with(|| Arc::new(Mutex::new(RateLimiter::new())))
+            .clone();
+
         Ok(Self {
             account,
             acme_dir,
-            rate_limiter: RateLimiter::new(),
+            rate_limiter,
         })
     }
 
@@ -174,9 +195,8 @@ impl AcmeClient {
     }
 
     /// โหลด account credentials จาก file
-    fn load_account_credentials(acme_dir: &str) -> Result<AccountCredentials, NylonError> {
-        let path = Self::credentials_path(acme_dir);
-        let data = std::fs::read_to_string(&path).map_err(|e| {
+    fn load_account_credentials(path: &Path) -> Result<AccountCredentials, NylonError> {
+        let data = std::fs::read_to_string(path).map_err(|e| {
             NylonError::ConfigError(format!("Failed to read credentials file: {}", e))
         })?;
 
@@ -189,10 +209,8 @@ impl AcmeClient {
     /// บันทึก account credentials ลง file
     fn save_account_credentials(
         credentials: &AccountCredentials,
-        acme_dir: &str,
+        path: &Path,
     ) -> Result<(), NylonError> {
-        let path = Self::credentials_path(acme_dir);
-
         // สร้างโฟลเดอร์ถ้ายังไม่มี
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent).map_err(|e| {
@@ -213,8 +231,21 @@ impl AcmeClient {
     }
 
     /// ได้ path สำหรับเก็บ credentials
-    fn credentials_path(acme_dir: &str) -> std::path::PathBuf {
-        std::path::PathBuf::from(format!("{}/account.json", acme_dir))
+    ///
+    /// Unnamed accounts keep using `account.json`; named accounts live in
+    /// `accounts/<name>.json`.
+    fn credentials_path(acme_dir: &str, account: Option<&str>) -> Result<PathBuf, NylonError> {
+        match account {
+            None => Ok(PathBuf::from(format!("{}/account.json", acme_dir))),
+            Some(name) if is_valid_account_name(name) => Ok(PathBuf::from(format!(
+                "{}/accounts/{}.json",
+                acme_dir, name
+            ))),
+            Some(name) => Err(NylonError::ConfigError(format!(
+                "Invalid ACME account name: {}",
+                name
+            ))),
+        }
     }
 
     /// ได้ path สำหรับเก็บ certificate
@@ -242,6 +273,25 @@ impl AcmeClient {
         std::path::PathBuf::from(format!("{}/challenges/{}/{}", acme_dir, domain, token))
     }
 
+    /// Wait for this account's turn to talk to the ACME server
+    async fn throttle(&self) {
+        let delay = self
+            .rate_limiter
+            .lock()
+            .map(|mut limiter| limiter.next_delay())
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            info!("Rate limiting: waiting {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn update_rate_limiter(&self, f: fn(&mut RateLimiter)) {
+        if let Ok(mut limiter) = self.rate_limiter.lock() {
+            f(&mut limiter);
+        }
+    }
+
     /// ออก certificate ใหม่สำหรับ domain
     pub async fn issue_certificate(
         &mut self,
@@ -250,7 +300,7 @@ impl AcmeClient {
         info!("Issuing certificate for domain: {}", domain);
 
         // Apply rate limiting
-        self.rate_limiter.wait_if_needed().await;
+        self.throttle().await;
 
         // Track challenge tokens for cleanup
         let mut challenge_tokens: Vec<String> = Vec::new();
@@ -373,14 +423,14 @@ impl AcmeClient {
         // Update rate limiter based on result
         match &result {
             Ok(_) => {
-                self.rate_limiter.reset_backoff();
+                self.update_rate_limiter(RateLimiter::reset_backoff);
                 info!(
                     "Certificate issuance completed successfully for: {}",
                     domain
                 );
             }
             Err(e) => {
-                self.rate_limiter.increase_backoff();
+                self.update_rate_limiter(RateLimiter::increase_backoff);
                 error!("Failed to issue certificate for {}: {}", domain, e);
             }
         }
@@ -424,6 +474,53 @@ impl AcmeClient {
         Ok((cert, chain))
     }
 
+    /// Revoke a certificate that was issued by this account
+    pub async fn revoke_certificate(&mut self, cert_pem: &[u8]) -> Result<(), NylonError> {
+        let der = X509::from_pem(cert_pem)
+            .and_then(|x509| x509.to_der())
+            .map_err(|e| NylonError::AcmeClientError(format!("Invalid certificate: {}", e)))?;
+        let certificate = CertificateDer::from(der);
+
+        self.throttle().await;
+        self.account
+            .revoke(&RevocationRequest {
+                certificate: &certificate,
+                reason: None,
+            })
+            .await
+            .map_err(|e| {
+                self.update_rate_limiter(RateLimiter::increase_backoff);
+                NylonError::AcmeClientError(format!("Failed to revoke certificate: {}", e))
+            })?;
+        self.update_rate_limiter(RateLimiter::reset_backoff);
+
+        info!("Certificate revoked (acme dir: {})", self.acme_dir);
+        Ok(())
+    }
+
+    /// Store an externally issued certificate in the ACME directory layout
+    pub fn import_certificate(
+        acme_dir: &str,
+        domain: &str,
+        cert: &[u8],
+        chain: &[Vec<u8>],
+        key: &[u8],
+    ) -> Result<(), NylonError> {
+        Self::save_certificate_bundle(acme_dir, domain, cert, chain, key)
+    }
+
+    /// Remove the stored certificate files of a domain
+    pub fn remove_certificate(acme_dir: &str, domain: &str) -> Result<(), NylonError> {
+        let cert_dir = std::path::PathBuf::from(format!("{}/certs/{}", acme_dir, domain));
+        if cert_dir.exists() {
+            std::fs::remove_dir_all(&cert_dir).map_err(|e| {
+                NylonError::ConfigError(format!("Failed to remove certificate files: {}", e))
+            })?;
+            info!("Removed certificate files for domain: {}", domain);
+        }
+        Ok(())
+    }
+
     /// บันทึก certificate bundle (leaf + chain + fullchain) และ key ลง file
     fn save_certificate_bundle(
         acme_dir: &str,
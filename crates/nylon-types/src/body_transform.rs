@@ -0,0 +1,155 @@
+//! JSON body transforms
+//!
+//! A mapping rewrites a JSON body as a whole, so the body is buffered until
+//! the end of the stream. Field paths are dotted, e.g. `user.name`.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Largest body that is buffered for a transform; bigger ones pass unchanged
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Changes to a JSON body, applied in field order
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct JsonMapping {
+    /// Replace the body with this field
+    pub unwrap: Option<String>,
+    /// Old path to new path
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Path to value, overwriting what is there
+    #[serde(default)]
+    pub add: BTreeMap<String, Value>,
+    /// Move the body into an object under this field
+    pub wrap: Option<String>,
+}
+
+/// A pending transform of the current request or response body
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyTransform {
+    pub mapping: JsonMapping,
+    /// Body received so far
+    pub buffer: Vec<u8>,
+}
+
+impl BodyTransform {
+    pub fn new(mapping: JsonMapping) -> Self {
+        Self {
+            mapping,
+            buffer: vec![],
+        }
+    }
+
+    /// Whether bodies of `content_type` are transformed
+    pub fn applies_to(content_type: &str) -> bool {
+        content_type.to_ascii_lowercase().contains("json")
+    }
+}
+
+impl JsonMapping {
+    /// Transformed body, or `None` when `body` is not JSON
+    pub fn apply(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let mut value: Value = serde_json::from_slice(body).ok()?;
+        if let Some(path) = &self.unwrap {
+            value = take(&mut value, path).unwrap_or(Value::Null);
+        }
+        for (from, to) in &self.rename {
+            if let Some(field) = take(&mut value, from) {
+                put(&mut value, to, field);
+            }
+        }
+        for path in &self.remove {
+            take(&mut value, path);
+        }
+        for (path, field) in &self.add {
+            put(&mut value, path, field.clone());
+        }
+        if let Some(path) = &self.wrap {
+            let mut wrapped = Value::Object(Map::new());
+            put(&mut wrapped, path, value);
+            value = wrapped;
+        }
+        serde_json::to_vec(&value).ok()
+    }
+}
+
+/// Remove the field at `path`
+fn take(value: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (
+            parent
+                .split('.')
+                .try_fold(value, |value, key| value.get_mut(key))?,
+            key,
+        ),
+        None => (value, path),
+    };
+    parent.as_object_mut()?.remove(key)
+}
+
+/// Set the field at `path`, creating objects on the way
+fn put(value: &mut Value, path: &str, field: Value) {
+    let mut current = value;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        let Some(object) = current.as_object_mut() else {
+            return;
+        };
+        if keys.peek().is_none() {
+            object.insert(key.to_string(), field);
+            return;
+        }
+        current = object
+            .entry(key)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(mapping: Value, body: Value) -> Value {
+        let mapping: JsonMapping = serde_json::from_value(mapping).unwrap();
+        let out = mapping.apply(body.to_string().as_bytes()).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn test_rename_remove_add() {
+        let out = apply(
+            json!({
+                "rename": {"user_name": "user.name"},
+                "remove": ["password", "meta.internal"],
+                "add": {"source": "nylon", "meta.version": 2}
+            }),
+            json!({"user_name": "ann", "password": "x", "meta": {"internal": true}}),
+        );
+        assert_eq!(
+            out,
+            json!({"user": {"name": "ann"}, "source": "nylon", "meta": {"version": 2}})
+        );
+    }
+
+    #[test]
+    fn test_unwrap_and_wrap() {
+        let out = apply(
+            json!({"unwrap": "data.items"}),
+            json!({"data": {"items": [1, 2]}, "status": "ok"}),
+        );
+        assert_eq!(out, json!([1, 2]));
+        let out = apply(json!({"wrap": "payload"}), json!([1, 2]));
+        assert_eq!(out, json!({"payload": [1, 2]}));
+    }
+
+    #[test]
+    fn test_not_json() {
+        assert!(JsonMapping::default().apply(b"<html>").is_none());
+        assert!(BodyTransform::applies_to("application/vnd.api+json"));
+        assert!(!BodyTransform::applies_to("text/plain"));
+    }
+}
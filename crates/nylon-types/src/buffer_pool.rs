@@ -0,0 +1,158 @@
+//! Byte buffers reused across requests
+//!
+//! Request bodies, WebSocket reads and plugin messages each need a scratch
+//! buffer; taking one from here keeps its allocation for the next request.
+//! Buffers that grew past [`MAX_CAPACITY`] are freed instead of kept, so one
+//! large upload does not pin its memory.
+
+use crossbeam_queue::ArrayQueue;
+use once_cell::sync::Lazy;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Buffers kept for reuse
+const MAX_POOLED: usize = 1024;
+
+/// Largest buffer kept for reuse
+pub const MAX_CAPACITY: usize = 1024 * 1024;
+
+/// Capacity of a buffer allocated when the pool is empty
+const INITIAL_CAPACITY: usize = 4096;
+
+static POOL: Lazy<ArrayQueue<Vec<u8>>> = Lazy::new(|| ArrayQueue::new(MAX_POOLED));
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static DISCARDED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters since start, for metrics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    /// Buffers waiting in the pool
+    pub pooled: usize,
+    /// Takes served from the pool
+    pub hits: u64,
+    /// Takes that allocated a new buffer
+    pub misses: u64,
+    /// Returned buffers freed for being too large or the pool being full
+    pub discarded: u64,
+}
+
+/// An empty buffer, reused when one is pooled
+pub fn take() -> Vec<u8> {
+    match POOL.pop() {
+        Some(buf) => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(INITIAL_CAPACITY)
+        }
+    }
+}
+
+/// Hand a buffer back for reuse
+pub fn put(mut buf: Vec<u8>) {
+    if buf.capacity() == 0 {
+        return;
+    }
+    if buf.capacity() > MAX_CAPACITY {
+        DISCARDED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    buf.clear();
+    if POOL.push(buf).is_err() {
+        DISCARDED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats() -> PoolStats {
+    PoolStats {
+        pooled: POOL.len(),
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        discarded: DISCARDED.load(Ordering::Relaxed),
+    }
+}
+
+/// A pooled buffer that goes back to the pool when dropped
+pub struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    pub fn new() -> Self {
+        Self(take())
+    }
+}
+
+impl Default for PooledBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        put(std::mem::take(&mut self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_and_put() {
+        let before = stats();
+        let mut buf = take();
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"secret");
+        put(buf);
+        // Pooled buffers come back cleared
+        let buf = take();
+        assert!(buf.is_empty());
+        put(buf);
+        let after = stats();
+        assert!(after.hits + after.misses >= before.hits + before.misses + 2);
+
+        // Buffers without memory are ignored, too large ones freed
+        put(Vec::new());
+        let discarded = stats().discarded;
+        put(Vec::with_capacity(MAX_CAPACITY + 1));
+        assert!(stats().discarded > discarded);
+
+        // So are buffers beyond what the pool keeps
+        let discarded = stats().discarded;
+        for _ in 0..MAX_POOLED + 2 {
+            put(Vec::with_capacity(16));
+        }
+        assert!(stats().discarded > discarded);
+        assert!(stats().pooled <= MAX_POOLED);
+    }
+
+    #[test]
+    fn test_pooled_buffer() {
+        let mut buf = PooledBuffer::new();
+        buf.extend_from_slice(b"abc");
+        assert_eq!(&buf[..], b"abc");
+        drop(buf);
+        let buf = PooledBuffer::default();
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+//! Client hints and device classes
+//!
+//! The device class of a request comes from the `Sec-CH-UA-Mobile` client
+//! hint when the browser sends it, otherwise from the `User-Agent`. Crawlers
+//! are recognized by their user agent only, since they rarely send hints.
+
+use crate::context::NylonContext;
+use pingora::http::RequestHeader;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Request headers the device class depends on, for `Vary`
+pub const DEVICE_VARY: &str = "Sec-CH-UA-Mobile, User-Agent";
+
+const BOT_MARKERS: [&str; 6] = [
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "headless",
+    "lighthouse",
+];
+const MOBILE_MARKERS: [&str; 6] = ["mobi", "android", "iphone", "ipod", "ipad", "windows phone"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceClass {
+    Mobile,
+    Desktop,
+    Bot,
+}
+
+impl DeviceClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Bot => "bot",
+        }
+    }
+}
+
+/// Classify a request from its hints and user agent
+pub fn detect(headers: &RequestHeader) -> DeviceClass {
+    let header = |name: &str| {
+        headers
+            .headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    };
+    let user_agent = header("user-agent").to_ascii_lowercase();
+    if BOT_MARKERS.iter().any(|m| user_agent.contains(m)) {
+        return DeviceClass::Bot;
+    }
+    match header("sec-ch-ua-mobile").trim() {
+        "?1" => DeviceClass::Mobile,
+        "?0" => DeviceClass::Desktop,
+        _ if MOBILE_MARKERS.iter().any(|m| user_agent.contains(m)) => DeviceClass::Mobile,
+        _ => DeviceClass::Desktop,
+    }
+}
+
+/// Device class of the request, detected once per request
+pub fn device_class(headers: &RequestHeader, ctx: &NylonContext) -> DeviceClass {
+    *ctx.device_class.get_or_init(|| detect(headers))
+}
@@ -1,82 +1,223 @@
 #![allow(clippy::type_complexity)]
 
-use crate::{plugins::SessionStream, route::MiddlewareItem, services::ServiceItem, template::Expr};
+use crate::{
+    body_transform::BodyTransform, client_hints::DeviceClass, plugins::SessionStream,
+    route::MiddlewareItem, services::ServiceItem, template::Expr, watermark::Watermark,
+};
+use bytes::Bytes;
 use pingora::lb::Backend;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
-        RwLock,
+        Arc, OnceLock,
         atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
     },
 };
 
+/// Connection the request is sent over, as seen by `connected_to_upstream`
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamConnection {
+    pub address: String,
+    /// Taken from the connection pool rather than newly opened
+    pub reused: bool,
+    pub tls: bool,
+    pub sni: String,
+}
+
+/// How a middleware writes a header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderMode {
+    /// Replace any value already there
+    #[default]
+    Set,
+    /// Add a value next to the ones already there
+    Append,
+    /// Only write the header when it is missing
+    SetIfAbsent,
+}
+
 #[derive(Debug, Clone)]
 pub struct Route {
+    pub name: String,
+    /// Path pattern that matched, e.g. `/users/{id}`
+    pub path: String,
     pub service: ServiceItem,
+    pub fallback: Option<ServiceItem>,
+    pub devices: Option<HashMap<DeviceClass, ServiceItem>>,
+    /// `Accept-CH` response header value
+    pub accept_ch: Option<String>,
+    pub websocket_max_connections: Option<usize>,
+    pub max_in_flight: Option<usize>,
+    pub error_pages: Option<Arc<ErrorPages>>,
     pub rewrite: Option<String>,
     pub route_middleware: Option<Vec<(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)>>,
     pub path_middleware: Option<Vec<(MiddlewareItem, Option<HashMap<String, Vec<Expr>>>)>>,
     pub payload_ast: Option<HashMap<String, Vec<Expr>>>,
+    /// `template` service response, parsed when the config loads
+    pub template: Option<ParsedTemplate>,
+    /// A plugin with an entry may see the response body; builtins never do,
+    /// so without one the body passes through untouched
+    pub response_body_middleware: bool,
 }
 
+/// Body and header values of a `template` service
+#[derive(Debug, Clone)]
+pub struct ParsedTemplate {
+    pub body: Vec<Expr>,
+    pub headers: Vec<(String, Vec<Expr>)>,
+}
+
+/// Error pages of a route by status, class (`5xx`) or `default`
+pub type ErrorPages = HashMap<String, LoadedErrorPage>;
+
+/// An error page, read or parsed when the config loads
+#[derive(Debug, Clone)]
+pub enum LoadedErrorPage {
+    /// A `file` page, with the content type of its extension
+    File { content_type: String, body: Bytes },
+    /// `html` and `json` templates
+    Template {
+        html: Option<Vec<Expr>>,
+        json: Option<Vec<Expr>>,
+    },
+}
+
+/// A route as matched by a request
+///
+/// Requests and WebSocket sessions hold on to the snapshot they matched, so a
+/// reload that changes or removes the route does not affect them; new
+/// requests match against the new configuration.
+pub type RouteSnapshot = Arc<Route>;
+
+/// State of one request
+///
+/// The proxy owns the context and hands it out by reference, so fields are
+/// plain values changed through `&mut`. Flags read by code that only gets
+/// `&NylonContext` are atomics, and caches filled on first use are
+/// `OnceLock`s; nothing here locks or can be poisoned.
 #[derive(Debug)]
 pub struct NylonContext {
-    pub backend: RwLock<Backend>,
-    pub client_ip: RwLock<String>,
-    pub route: RwLock<Option<Route>>,
-    pub params: RwLock<Option<HashMap<String, String>>>,
-    pub host: RwLock<String>,
-    pub port: RwLock<String>,
+    pub backend: Backend,
+    /// Service name or `host:port` a plugin chose for this request
+    pub upstream_override: Option<String>,
+    pub client_ip: String,
+    pub route: Option<RouteSnapshot>,
+    pub params: Option<HashMap<String, String>>,
+    pub host: String,
+    pub port: String,
     pub tls: AtomicBool,
-    pub session_ids: RwLock<HashMap<String, u32>>,
-    pub session_stream: RwLock<HashMap<String, SessionStream>>,
-    pub add_response_header: RwLock<HashMap<String, String>>,
-    pub remove_response_header: RwLock<Vec<String>>,
+    /// Negotiated TLS version, e.g. `TLSv1.3`; empty for plain HTTP
+    pub tls_version: String,
+    /// Server name the client sent in the TLS handshake (HTTP/1 only)
+    pub sni: String,
+    pub session_ids: HashMap<String, u32>,
+    pub session_stream: HashMap<String, SessionStream>,
+    pub add_response_header: HashMap<String, String>,
+    pub remove_response_header: Vec<String>,
+    /// Headers written with a mode, after `add_response_header`
+    pub response_header_ops: Vec<(String, String, HeaderMode)>,
     pub set_response_status: AtomicU16,
-    pub set_response_body: RwLock<Vec<u8>>,
+    pub set_response_body: Vec<u8>,
+    pub response_watermark: Option<Watermark>,
+    pub request_body_transform: Option<BodyTransform>,
+    pub response_body_transform: Option<BodyTransform>,
     pub read_body: AtomicBool,
-    pub request_body: RwLock<Vec<u8>>,
+    pub request_body: Vec<u8>,
+    // Request body streaming: subscribed plugin sessions and the current chunk
+    pub request_body_streams: HashSet<u32>,
+    pub request_body_chunk: Bytes,
+    pub request_body_end: AtomicBool,
+    // Plugin sessions that asked for the upstream phases
+    pub upstream_subscribers: HashSet<u32>,
+    pub upstream_connection: Option<UpstreamConnection>,
+    /// Failed upstream connects that were retried
+    pub connect_retries: u8,
+    /// WebSocket subprotocol a plugin picked for the handshake
+    pub websocket_protocol: Option<String>,
     // Caches per request to avoid repeated parsing
-    pub cached_query: RwLock<Option<HashMap<String, String>>>,
-    pub cached_cookies: RwLock<Option<HashMap<String, String>>>,
+    pub cached_query: OnceLock<HashMap<String, String>>,
+    pub cached_cookies: OnceLock<HashMap<String, String>>,
+    pub device_class: OnceLock<DeviceClass>,
+    /// Tags added by `Waf` rules
+    pub waf_tags: Vec<String>,
+    /// Client IP counted against `max_idle_requests_per_ip` until the body is read
+    pub awaiting_body: Option<String>,
+    /// `request_body` was decompressed and replaces the body sent upstream
+    pub request_body_decoded: AtomicBool,
+    /// `request_body` was read ahead of the proxy and is sent in place of
+    /// its retry buffer, which only keeps the first 64 KiB
+    pub request_body_held: AtomicBool,
+    /// Counted against `max_in_flight` until the request is logged
+    pub in_flight: AtomicBool,
     // Logging information
     pub request_timestamp: AtomicU64,
-    pub error_message: RwLock<Option<String>>,
+    pub upstream_timestamp: AtomicU64,
+    pub upstream_response_ms: AtomicU64,
+    pub error_message: Option<String>,
+    // Tracing spans
+    pub trace_span: tracing::Span,
+    pub upstream_span: tracing::Span,
 }
 
 impl Default for NylonContext {
     fn default() -> Self {
         Self {
             // Backend and routing
-            backend: RwLock::new(
-                Backend::new("127.0.0.1:80").expect("Unable to create default backend"),
-            ),
-            client_ip: RwLock::new("127.0.0.1".to_string()),
-            route: RwLock::new(None),
-            params: RwLock::new(None),
-            host: RwLock::new("".to_string()),
-            port: RwLock::new("".to_string()),
+            backend: Backend::new("127.0.0.1:80").expect("Unable to create default backend"),
+            upstream_override: None,
+            client_ip: "127.0.0.1".to_string(),
+            route: None,
+            params: None,
+            host: String::new(),
+            port: String::new(),
             tls: AtomicBool::new(false),
-            session_ids: RwLock::new(HashMap::new()),
-            session_stream: RwLock::new(HashMap::new()),
+            tls_version: String::new(),
+            sni: String::new(),
+            session_ids: HashMap::new(),
+            session_stream: HashMap::new(),
 
             // Response modifications
-            add_response_header: RwLock::new(HashMap::new()),
-            remove_response_header: RwLock::new(Vec::new()),
+            add_response_header: HashMap::new(),
+            remove_response_header: Vec::new(),
+            response_header_ops: Vec::new(),
             set_response_status: AtomicU16::new(200),
-            set_response_body: RwLock::new(Vec::new()),
+            set_response_body: Vec::new(),
+            response_watermark: None,
+            request_body_transform: None,
+            response_body_transform: None,
 
             // Request modifications
             read_body: AtomicBool::new(false),
-            request_body: RwLock::new(Vec::new()),
+            request_body: Vec::new(),
+            request_body_streams: HashSet::new(),
+            request_body_chunk: Bytes::new(),
+            request_body_end: AtomicBool::new(false),
+            upstream_subscribers: HashSet::new(),
+            upstream_connection: None,
+            connect_retries: 0,
+            websocket_protocol: None,
 
             // Request caches
-            cached_query: RwLock::new(None),
-            cached_cookies: RwLock::new(None),
+            cached_query: OnceLock::new(),
+            cached_cookies: OnceLock::new(),
+            device_class: OnceLock::new(),
+            waf_tags: Vec::new(),
+            awaiting_body: None,
+            request_body_decoded: AtomicBool::new(false),
+            request_body_held: AtomicBool::new(false),
+            in_flight: AtomicBool::new(false),
 
             // Logging information
             request_timestamp: AtomicU64::new(0),
-            error_message: RwLock::new(None),
+            upstream_timestamp: AtomicU64::new(0),
+            upstream_response_ms: AtomicU64::new(0),
+            error_message: None,
+
+            // Tracing spans
+            trace_span: tracing::Span::none(),
+            upstream_span: tracing::Span::none(),
         }
     }
 }
@@ -84,29 +225,52 @@ impl Default for NylonContext {
 impl Clone for NylonContext {
     fn clone(&self) -> Self {
         Self {
-            backend: RwLock::new(self.backend.read().expect("lock").clone()),
-            client_ip: RwLock::new(self.client_ip.read().expect("lock").clone()),
-            route: RwLock::new(self.route.read().expect("lock").clone()),
-            params: RwLock::new(self.params.read().expect("lock").clone()),
-            host: RwLock::new(self.host.read().expect("lock").clone()),
-            port: RwLock::new(self.port.read().expect("lock").clone()),
+            backend: self.backend.clone(),
+            upstream_override: self.upstream_override.clone(),
+            client_ip: self.client_ip.clone(),
+            route: self.route.clone(),
+            params: self.params.clone(),
+            host: self.host.clone(),
+            port: self.port.clone(),
             tls: AtomicBool::new(self.tls.load(Ordering::Relaxed)),
-            session_ids: RwLock::new(self.session_ids.read().expect("lock").clone()),
-            session_stream: RwLock::new(self.session_stream.read().expect("lock").clone()),
-            add_response_header: RwLock::new(
-                self.add_response_header.read().expect("lock").clone(),
-            ),
-            remove_response_header: RwLock::new(
-                self.remove_response_header.read().expect("lock").clone(),
-            ),
+            tls_version: self.tls_version.clone(),
+            sni: self.sni.clone(),
+            session_ids: self.session_ids.clone(),
+            session_stream: self.session_stream.clone(),
+            add_response_header: self.add_response_header.clone(),
+            remove_response_header: self.remove_response_header.clone(),
+            response_header_ops: self.response_header_ops.clone(),
             set_response_status: AtomicU16::new(self.set_response_status.load(Ordering::Relaxed)),
-            set_response_body: RwLock::new(self.set_response_body.read().expect("lock").clone()),
+            set_response_body: self.set_response_body.clone(),
+            response_watermark: self.response_watermark.clone(),
+            request_body_transform: self.request_body_transform.clone(),
+            response_body_transform: self.response_body_transform.clone(),
             read_body: AtomicBool::new(self.read_body.load(Ordering::Relaxed)),
-            request_body: RwLock::new(self.request_body.read().expect("lock").clone()),
-            cached_query: RwLock::new(self.cached_query.read().expect("lock").clone()),
-            cached_cookies: RwLock::new(self.cached_cookies.read().expect("lock").clone()),
+            request_body: self.request_body.clone(),
+            request_body_streams: self.request_body_streams.clone(),
+            request_body_chunk: self.request_body_chunk.clone(),
+            request_body_end: AtomicBool::new(self.request_body_end.load(Ordering::Relaxed)),
+            upstream_subscribers: self.upstream_subscribers.clone(),
+            upstream_connection: self.upstream_connection.clone(),
+            connect_retries: self.connect_retries,
+            websocket_protocol: self.websocket_protocol.clone(),
+            cached_query: self.cached_query.clone(),
+            cached_cookies: self.cached_cookies.clone(),
+            device_class: self.device_class.clone(),
+            waf_tags: self.waf_tags.clone(),
+            // The slot is released by the original
+            awaiting_body: None,
+            request_body_decoded: AtomicBool::new(
+                self.request_body_decoded.load(Ordering::Relaxed),
+            ),
+            request_body_held: AtomicBool::new(self.request_body_held.load(Ordering::Relaxed)),
+            in_flight: AtomicBool::new(false),
             request_timestamp: AtomicU64::new(self.request_timestamp.load(Ordering::Relaxed)),
-            error_message: RwLock::new(self.error_message.read().expect("lock").clone()),
+            upstream_timestamp: AtomicU64::new(self.upstream_timestamp.load(Ordering::Relaxed)),
+            upstream_response_ms: AtomicU64::new(self.upstream_response_ms.load(Ordering::Relaxed)),
+            error_message: self.error_message.clone(),
+            trace_span: self.trace_span.clone(),
+            upstream_span: self.upstream_span.clone(),
         }
     }
 }
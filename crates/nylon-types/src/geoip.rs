@@ -0,0 +1,126 @@
+//! GeoIP lookups for the `geo(field)` template function
+//!
+//! Databases are MaxMind DB files (GeoLite2/GeoIP2 City or Country, and ASN),
+//! loaded from the runtime config at startup and on reload.
+
+use maxminddb::{Reader, geoip2};
+use nylon_error::NylonError;
+use once_cell::sync::Lazy;
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Default)]
+struct Databases {
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+static DATABASES: Lazy<RwLock<Arc<Databases>>> = Lazy::new(Default::default);
+
+fn open(path: &Path) -> Result<Reader<Vec<u8>>, NylonError> {
+    Reader::open_readfile(path).map_err(|e| {
+        NylonError::ConfigError(format!(
+            "Failed to open GeoIP database {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Replace the loaded databases; `None` unloads one
+pub fn load(city: Option<&Path>, asn: Option<&Path>) -> Result<(), NylonError> {
+    let databases = Databases {
+        city: city.map(open).transpose()?,
+        asn: asn.map(open).transpose()?,
+    };
+    if let Ok(mut current) = DATABASES.write() {
+        *current = Arc::new(databases);
+    }
+    Ok(())
+}
+
+/// A field of the location of `ip`, empty when unknown
+///
+/// Fields: `country` (ISO code), `country_name`, `continent`, `region`,
+/// `city`, `latitude`, `longitude`, `time_zone`, `asn` and `as_org`.
+pub fn lookup(ip: &str, field: &str) -> String {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return String::new();
+    };
+    let Some(databases) = DATABASES.read().ok().map(|d| d.clone()) else {
+        return String::new();
+    };
+    match field {
+        "asn" | "as_org" => {
+            let Some(Ok(Some(asn))) = databases.asn.as_ref().map(|r| r.lookup::<geoip2::Asn>(ip))
+            else {
+                return String::new();
+            };
+            match field {
+                "asn" => asn
+                    .autonomous_system_number
+                    .map(|n| n.to_string())
+                    .unwrap_or_default(),
+                _ => asn
+                    .autonomous_system_organization
+                    .unwrap_or_default()
+                    .to_string(),
+            }
+        }
+        _ => {
+            let Some(Ok(Some(city))) = databases
+                .city
+                .as_ref()
+                .map(|r| r.lookup::<geoip2::City>(ip))
+            else {
+                return String::new();
+            };
+            let english = |names: &Option<std::collections::BTreeMap<&str, &str>>| {
+                names
+                    .as_ref()
+                    .and_then(|n| n.get("en"))
+                    .map(|n| n.to_string())
+                    .unwrap_or_default()
+            };
+            match field {
+                "country" => city
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .unwrap_or_default()
+                    .to_string(),
+                "country_name" => city.country.map(|c| english(&c.names)).unwrap_or_default(),
+                "continent" => city
+                    .continent
+                    .and_then(|c| c.code)
+                    .unwrap_or_default()
+                    .to_string(),
+                "region" => city
+                    .subdivisions
+                    .and_then(|s| s.into_iter().next())
+                    .and_then(|s| s.iso_code)
+                    .unwrap_or_default()
+                    .to_string(),
+                "city" => city.city.map(|c| english(&c.names)).unwrap_or_default(),
+                "latitude" => city
+                    .location
+                    .and_then(|l| l.latitude)
+                    .map(|l| l.to_string())
+                    .unwrap_or_default(),
+                "longitude" => city
+                    .location
+                    .and_then(|l| l.longitude)
+                    .map(|l| l.to_string())
+                    .unwrap_or_default(),
+                "time_zone" => city
+                    .location
+                    .and_then(|l| l.time_zone)
+                    .unwrap_or_default()
+                    .to_string(),
+                _ => String::new(),
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
+pub mod body_transform;
+pub mod buffer_pool;
+pub mod client_hints;
 pub mod context;
+pub mod geoip;
+pub mod paths;
 pub mod plugins;
 pub mod proxy;
 pub mod route;
 pub mod services;
 pub mod template;
 pub mod tls;
+pub mod waf;
+pub mod watermark;
 pub mod websocket;
 
 /// Nylon runtime server instance
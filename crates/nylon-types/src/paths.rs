@@ -0,0 +1,35 @@
+//! Default locations of the installed service
+//!
+//! Windows paths use forward slashes so they can be put in YAML as they are.
+//! `nylon-command` includes this file as its own `paths` module, so it may
+//! only hold constants.
+
+#[cfg(not(windows))]
+pub const NYLON_DIR: &str = "/etc/nylon";
+#[cfg(windows)]
+pub const NYLON_DIR: &str = "C:/ProgramData/nylon";
+
+#[cfg(not(windows))]
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/nylon/config.yaml";
+#[cfg(windows)]
+pub const DEFAULT_CONFIG_PATH: &str = "C:/ProgramData/nylon/config.yaml";
+
+#[cfg(not(windows))]
+pub const DEFAULT_PROXY_CONFIG_DIR: &str = "/etc/nylon/proxy";
+#[cfg(windows)]
+pub const DEFAULT_PROXY_CONFIG_DIR: &str = "C:/ProgramData/nylon/proxy";
+
+#[cfg(not(windows))]
+pub const DEFAULT_ACME_DIR: &str = "/etc/nylon/acme";
+#[cfg(windows)]
+pub const DEFAULT_ACME_DIR: &str = "C:/ProgramData/nylon/acme";
+
+#[cfg(not(windows))]
+pub const DEFAULT_STATIC_DIR: &str = "/etc/nylon/static";
+#[cfg(windows)]
+pub const DEFAULT_STATIC_DIR: &str = "C:/ProgramData/nylon/static";
+
+#[cfg(not(windows))]
+pub const COMMAND_SOCKET_PATH: &str = "/tmp/_nylon.sock";
+#[cfg(windows)]
+pub const COMMAND_SOCKET_PATH: &str = r"\\.\pipe\nylon";
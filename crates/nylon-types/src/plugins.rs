@@ -1,5 +1,7 @@
 use libloading::{Library, Symbol};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
@@ -9,6 +11,9 @@ pub enum PluginPhase {
     ResponseFilter,
     ResponseBodyFilter,
     Logging,
+    RequestBodyFilter,
+    UpstreamRequestFilter,
+    ConnectedToUpstream,
 }
 
 impl PluginPhase {
@@ -19,6 +24,22 @@ impl PluginPhase {
             PluginPhase::ResponseFilter => 2,
             PluginPhase::ResponseBodyFilter => 3,
             PluginPhase::Logging => 4,
+            PluginPhase::RequestBodyFilter => 5,
+            PluginPhase::UpstreamRequestFilter => 6,
+            PluginPhase::ConnectedToUpstream => 7,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PluginPhase::Zero => "zero",
+            PluginPhase::RequestFilter => "request_filter",
+            PluginPhase::ResponseFilter => "response_filter",
+            PluginPhase::ResponseBodyFilter => "response_body_filter",
+            PluginPhase::Logging => "logging",
+            PluginPhase::RequestBodyFilter => "request_body_filter",
+            PluginPhase::UpstreamRequestFilter => "upstream_request_filter",
+            PluginPhase::ConnectedToUpstream => "connected_to_upstream",
         }
     }
 }
@@ -32,12 +53,16 @@ pub struct FfiBuffer {
     pub len: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone)]
 pub enum PluginType {
     #[serde(rename = "wasm")]
     Wasm,
     #[serde(rename = "ffi")]
     Ffi,
+    #[serde(rename = "grpc")]
+    Grpc,
+    #[serde(rename = "js")]
+    Js,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -46,14 +71,145 @@ pub struct LifeCycle {
     pub shutdown: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PluginItem {
     pub name: String,
+    /// Library or module path; for gRPC plugins the server address
     pub file: String,
     #[serde(rename = "type")]
     pub plugin_type: PluginType,
     pub entry: Option<Vec<String>>,
     pub config: Option<serde_json::Value>,
+    /// Longest time to wait for the plugin within one phase
+    pub timeout_ms: Option<u64>,
+    /// Per phase overrides of `timeout_ms`, keyed by phase name
+    pub phase_timeout_ms: Option<HashMap<String, u64>>,
+    #[serde(default)]
+    pub on_timeout: PluginErrorAction,
+    /// What to do when the plugin fails or is unhealthy
+    #[serde(default)]
+    pub on_failure: PluginErrorAction,
+    /// Sessions the plugin may have open at once (1 to 1,000,000); unlimited when unset
+    pub max_concurrent_sessions: Option<usize>,
+    #[serde(default)]
+    pub on_overflow: PluginOverflow,
+    /// Longest a request waits for a free session with `on_overflow: queue`
+    pub queue_timeout_ms: Option<u64>,
+    /// Events a session may have waiting to be handled; 1024 unless set
+    pub event_queue_capacity: Option<usize>,
+    /// What happens to an event that finds the queue full
+    #[serde(default)]
+    pub on_event_queue_full: PluginQueueFull,
+    /// Methods the plugin may call beyond reading request metadata; all when unset
+    pub permissions: Option<Vec<PluginPermission>>,
+    /// Linear memory a WASM module may grow to, 64 MiB unless set
+    pub max_memory_mb: Option<u64>,
+}
+
+/// Groups of plugin methods that have to be granted
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginPermission {
+    /// Read the request and response bodies
+    ReadBody,
+    /// Change the request sent upstream
+    WriteRequest,
+    /// Change the response status, headers and body
+    WriteResponse,
+    /// Choose the upstream of a request
+    Upstream,
+    /// Send HTTP requests of its own
+    Fetch,
+    /// Upgrade to WebSocket and use rooms
+    Websocket,
+}
+
+impl PluginPermission {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PluginPermission::ReadBody => "read_body",
+            PluginPermission::WriteRequest => "write_request",
+            PluginPermission::WriteResponse => "write_response",
+            PluginPermission::Upstream => "upstream",
+            PluginPermission::Fetch => "fetch",
+            PluginPermission::Websocket => "websocket",
+        }
+    }
+}
+
+/// What happens to a request when a plugin has `max_concurrent_sessions` open
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginOverflow {
+    /// Handle it like a plugin failure, see `on_failure`
+    #[default]
+    Reject,
+    /// Wait for a session to finish, up to `queue_timeout_ms`
+    Queue,
+}
+
+/// Highest `max_concurrent_sessions` a plugin may set
+pub const MAX_CONCURRENT_SESSIONS: usize = 1_000_000;
+
+/// Events a session may have waiting when `event_queue_capacity` is not set
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// What happens when a plugin sends events faster than they are handled
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginQueueFull {
+    /// Drop metric and WebSocket send events; any other event still ends the session
+    Drop,
+    /// End the session; the request is handled like a plugin failure
+    #[default]
+    Close,
+}
+
+/// What happens to a request when a plugin times out or fails
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginErrorAction {
+    /// Answer with an error (`504` on timeout, `502` on failure)
+    #[default]
+    Error,
+    /// Skip the plugin and go on with the request
+    Continue,
+}
+
+impl PluginItem {
+    /// Timeout for `phase`, if any
+    pub fn timeout(&self, phase: &PluginPhase) -> Option<std::time::Duration> {
+        self.phase_timeout_ms
+            .as_ref()
+            .and_then(|phases| phases.get(phase.name()))
+            .or(self.timeout_ms.as_ref())
+            .map(|ms| std::time::Duration::from_millis(*ms))
+    }
+
+    /// How long to wait for a free session, 1s unless configured
+    pub fn queue_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.queue_timeout_ms.unwrap_or(1_000))
+    }
+
+    /// Capacity of a session's event queue, 1024 unless configured
+    pub fn event_queue_capacity(&self) -> usize {
+        self.event_queue_capacity
+            .unwrap_or(DEFAULT_EVENT_QUEUE_CAPACITY)
+            .max(1)
+    }
+}
+
+/// Host callback receiving the events a plugin emits for a session
+pub type PluginEventCallback = extern "C" fn(*const FfiBuffer);
+
+/// A loaded plugin: a shared library, a WASM module or a gRPC server
+pub trait PluginBackend: Send + Sync + std::fmt::Debug {
+    fn register_session(&self, session_id: u32, entry: &str, callback: PluginEventCallback)
+    -> bool;
+    fn event_stream(&self, buffer: &FfiBuffer);
+    fn close_session(&self, session_id: u32);
+    fn shutdown(&self);
 }
 
 // FFI Plugin
@@ -75,9 +231,32 @@ pub struct FfiPlugin {
     pub shutdown: Symbol<'static, FfiShutdownFn>,
 }
 
+impl PluginBackend for FfiPlugin {
+    fn register_session(
+        &self,
+        session_id: u32,
+        entry: &str,
+        callback: PluginEventCallback,
+    ) -> bool {
+        unsafe { (self.register_session)(session_id, entry.as_ptr(), entry.len() as u32, callback) }
+    }
+
+    fn event_stream(&self, buffer: &FfiBuffer) {
+        unsafe { (self.event_stream)(buffer) }
+    }
+
+    fn close_session(&self, session_id: u32) {
+        unsafe { (self.close_session)(session_id) }
+    }
+
+    fn shutdown(&self) {
+        unsafe { (self.shutdown)() }
+    }
+}
+
 // Plugin Session Stream
 #[derive(Debug, Clone)]
 pub struct SessionStream {
-    pub plugin: Arc<FfiPlugin>,
+    pub plugin: Arc<dyn PluginBackend>,
     pub session_id: u32,
 }
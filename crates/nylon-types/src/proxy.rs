@@ -4,11 +4,16 @@ use crate::{
     services::ServiceItem,
     tls::TlsConfig,
 };
+use schemars::JsonSchema;
 use serde::Deserialize;
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ProxyConfig {
+    /// Files with a higher priority override names defined in other files
+    #[serde(default)]
+    pub priority: i32,
     pub services: Option<Vec<ServiceItem>>,
     pub tls: Option<Vec<TlsConfig>>,
     pub header_selector: Option<String>,
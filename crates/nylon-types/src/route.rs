@@ -1,35 +1,67 @@
+use crate::{client_hints::DeviceClass, services::TemplateConfig, waf::Waf};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
 
 pub const HTTP_METHODS: [&str; 9] = [
     "GET", "POST", "PUT", "DELETE", "OPTIONS", "HEAD", "CONNECT", "TRACE", "PATCH",
 ];
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MiddlewareItem {
     pub group: Option<String>,
     pub plugin: Option<String>,
     pub entry: Option<String>,
     pub payload: Option<serde_json::Value>,
+    /// Rules of a `Waf` middleware, compiled when routes are stored
+    #[serde(skip)]
+    pub waf: Option<Arc<Waf>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct RouteConfig {
     pub route: RouteMatcher,
     pub name: String,
     pub tls: Option<TlsRoute>,
     pub middleware: Option<Vec<MiddlewareItem>>,
+    /// Client hints to request from browsers with `Accept-CH`
+    pub accept_ch: Option<Vec<String>>,
+    /// Open WebSocket connections allowed on this node for the route
+    pub websocket_max_connections: Option<usize>,
+    /// Requests in flight allowed on this node for the route; more get `503`
+    pub max_in_flight: Option<usize>,
+    /// Error responses by status (`404`), class (`5xx`) or `default`
+    pub error_pages: Option<HashMap<String, ErrorPage>>,
     pub paths: Vec<PathConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// An error response: a file, or templates chosen by the `Accept` header
+///
+/// Templates may use `${error(status)}`, `${error(code)}` and `${error(message)}`.
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPage {
+    /// File sent as is, with a content type from its extension
+    pub file: Option<String>,
+    /// Template for clients that accept `text/html`
+    pub html: Option<String>,
+    /// Template for other clients
+    pub json: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct RouteMatcher {
     #[serde(rename = "type")]
     pub kind: String,
     pub value: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct TlsRoute {
     pub enabled: bool,
     pub redirect: Option<String>,
@@ -41,15 +73,22 @@ pub struct Header {
     pub value: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PathConfig {
     pub path: Value,
     pub service: ServiceRef,
+    pub fallback: Option<String>,
+    /// Service to use instead for a device class
+    pub devices: Option<HashMap<DeviceClass, String>>,
     pub middleware: Option<Vec<MiddlewareItem>>,
     pub methods: Option<Vec<String>>,
+    /// Replaces the `template` of a template service for this path
+    pub response: Option<TemplateConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceRef {
     pub name: String,
     pub rewrite: Option<String>,
@@ -1,6 +1,9 @@
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct HealthCheck {
     pub enabled: bool,
     pub path: String,
@@ -10,14 +13,15 @@ pub struct HealthCheck {
     pub unhealthy_threshold: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Endpoint {
     pub ip: String,
     pub port: u16,
     pub weight: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize, Clone, PartialEq)]
 pub enum ServiceType {
     #[serde(rename = "http")]
     Http,
@@ -25,9 +29,11 @@ pub enum ServiceType {
     Plugin,
     #[serde(rename = "static")]
     Static,
+    #[serde(rename = "template")]
+    Template,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 pub enum Algorithm {
     #[serde(rename = "round_robin")]
     RoundRobin,
@@ -39,14 +45,16 @@ pub enum Algorithm {
     Weighted,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Plugin {
     pub name: String,
     pub entry: String,
     pub payload: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct StaticConfig {
     /// Root directory to serve files from
     pub root: String,
@@ -54,9 +62,51 @@ pub struct StaticConfig {
     pub index: Option<String>,
     /// Enable SPA fallback: on 404, serve index file instead
     pub spa: Option<bool>,
+    /// List directories without an index file
+    pub autoindex: Option<bool>,
+    /// HTML page of `autoindex` listings; `${listing(title)}` is the directory
+    /// and `${listing(rows)}` its table rows
+    pub autoindex_template: Option<String>,
+    /// File under the root sent with status 404 for missing files
+    pub not_found: Option<String>,
+    /// `Cache-Control` by request path; the first matching rule applies
+    pub cache_control: Option<Vec<CacheControlRule>>,
+    /// Send `file.br` / `file.gz` to clients that accept them (default: false)
+    pub precompressed: Option<bool>,
+    /// Gzip text-like files of at least this many bytes on the fly
+    pub compress_min_bytes: Option<u64>,
+    /// Serve names starting with a dot (default: false)
+    pub dotfiles: Option<bool>,
+    /// Paths never served: `*` within a name, `**` across directories; a
+    /// pattern without `/` is matched against each name in the path
+    pub deny: Option<Vec<String>>,
+    /// Paths served even when `deny` or the dotfile rule matches them
+    pub allow: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheControlRule {
+    /// Path prefix (`/assets/`), or `*` and a suffix (`*.html`)
+    pub path: String,
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateConfig {
+    /// Response status code (default: 200)
+    pub status: Option<u16>,
+    /// Content-Type header (default: text/plain; charset=utf-8)
+    pub content_type: Option<String>,
+    /// Extra response headers, values may contain `${...}` expressions
+    pub headers: Option<HashMap<String, String>>,
+    /// Response body, rendered per request
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceItem {
     pub name: String,
     pub service_type: ServiceType,
@@ -66,4 +116,6 @@ pub struct ServiceItem {
     pub plugin: Option<Plugin>,
     #[serde(rename = "static")]
     pub static_conf: Option<StaticConfig>,
+    #[serde(rename = "template")]
+    pub template_conf: Option<TemplateConfig>,
 }
@@ -1,20 +1,225 @@
-use crate::context::NylonContext;
-use chrono::Utc;
+use crate::{client_hints, context::NylonContext};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::{
+    Utc,
+    format::{Item, StrftimeItems},
+};
+use hmac::{Hmac, Mac};
 use lru::LruCache;
 use nylon_error::NylonError;
 use once_cell::sync::Lazy;
 use pingora::http::RequestHeader;
 use regex::Regex;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
 // LRU cache for parsed template expressions - cache up to 5,000 unique template strings
 static PARSED_TEMPLATE_CACHE: Lazy<Mutex<LruCache<String, Vec<Expr>>>> =
     Lazy::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(5_000).unwrap())));
 
+// Patterns of match() and capture(), compiled when their template is parsed.
+// They are literals of the config, so this only grows with the config.
+static PATTERNS: Lazy<RwLock<HashMap<String, Regex>>> = Lazy::new(Default::default);
+
+/// The compiled pattern of a parsed template
+fn compiled_regex(pattern: &str) -> Option<Regex> {
+    PATTERNS.read().ok()?.get(pattern).cloned()
+}
+
+/// Functions a template may call
+///
+/// `error()` is rendered by error pages, `response()` by the access log and
+/// `listing()` by `autoindex` pages; `eval_expr` leaves them empty.
+const FUNCTIONS: &[&str] = &[
+    "error",
+    "response",
+    "listing",
+    "header",
+    "query",
+    "cookie",
+    "param",
+    "request",
+    "path",
+    "method",
+    "var",
+    "geo",
+    "env",
+    "or",
+    "eq",
+    "neq",
+    "concat",
+    "upper",
+    "lower",
+    "len",
+    "if_cond",
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "gt",
+    "lt",
+    "gte",
+    "lte",
+    "rand",
+    "trim",
+    "substr",
+    "replace",
+    "split",
+    "match",
+    "capture",
+    "base64",
+    "base64url",
+    "url_encode",
+    "url_decode",
+    "sha256",
+    "md5",
+    "hmac",
+    "timestamp",
+    "now",
+    "unix_ms",
+    "uuid",
+];
+
+/// Check the function names of `expr` and compile the patterns of its
+/// `match()` and `capture()` calls
+///
+/// A pattern has to be a literal, so requests can not make the proxy
+/// compile expressions of their choosing.
+fn check_expr(expr: &Expr) -> Result<(), String> {
+    let Expr::Func { name, args } = expr else {
+        return Ok(());
+    };
+    if !FUNCTIONS.contains(&name.as_str()) {
+        return Err(format!("unknown function {}()", name));
+    }
+    if matches!(name.as_str(), "match" | "capture")
+        && let Some(pattern) = args.get(1)
+    {
+        let Expr::Literal(pattern) = pattern else {
+            return Err(format!(
+                "the pattern of {}() must be a quoted literal",
+                name
+            ));
+        };
+        if compiled_regex(pattern).is_none() {
+            let regex =
+                Regex::new(pattern).map_err(|e| format!("invalid pattern of {}(): {}", name, e))?;
+            if let Ok(mut patterns) = PATTERNS.write() {
+                patterns.insert(pattern.clone(), regex);
+            }
+        }
+    }
+    args.iter().try_for_each(check_expr)
+}
+
+/// Integer arithmetic when both sides are integers, floating point otherwise;
+/// empty when an operand is not a number or the result is not finite
+fn arithmetic(
+    a: &str,
+    b: &str,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> String {
+    let (a, b) = (a.trim(), b.trim());
+    if let (Ok(x), Ok(y)) = (a.parse::<i64>(), b.parse::<i64>())
+        && let Some(n) = int_op(x, y)
+    {
+        return n.to_string();
+    }
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => format_number(float_op(x, y)),
+        _ => String::new(),
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if !n.is_finite() {
+        String::new()
+    } else if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        (n as i64).to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// Numeric comparison of the first two arguments; like `eq`, a true result
+/// is the third argument (or the first) and a false one is empty
+fn compare(
+    args: &[Expr],
+    headers: &RequestHeader,
+    ctx: &NylonContext,
+    holds: fn(f64, f64) -> bool,
+) -> String {
+    if args.len() < 2 {
+        return String::new();
+    }
+    let a = eval_expr(&args[0], headers, ctx);
+    let b = eval_expr(&args[1], headers, ctx);
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(x), Ok(y)) if holds(x, y) => match args.get(2) {
+            Some(value) => eval_expr(value, headers, ctx),
+            None => a,
+        },
+        _ => String::new(),
+    }
+}
+
+/// Percent-encode everything but the RFC 3986 unreserved characters
+pub fn url_encode(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Decode `%XX` sequences; `+` is kept as is
+pub fn url_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                result.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                result.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// A digest as lowercase hex, or in the encoding named by the optional argument
+fn encode_digest(digest: &[u8], encoding: Option<&Expr>) -> String {
+    match encoding {
+        Some(Expr::Request(e)) | Some(Expr::Literal(e)) if e == "base64" => STANDARD.encode(digest),
+        Some(Expr::Request(e)) | Some(Expr::Literal(e)) if e == "base64url" => {
+            URL_SAFE_NO_PAD.encode(digest)
+        }
+        _ => digest.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
 fn percent_decode_plus(input: &str, plus_as_space: bool) -> String {
     let mut result = String::with_capacity(input.len());
     let mut bytes = input.as_bytes().iter().copied();
@@ -50,15 +255,14 @@ fn percent_decode_plus(input: &str, plus_as_space: bool) -> String {
     result
 }
 
-fn get_or_build_query_cache(
+fn get_or_build_query_cache<'a>(
     headers: &RequestHeader,
-    ctx: &NylonContext,
-) -> HashMap<String, String> {
-    if let Ok(cache) = ctx.cached_query.read()
-        && let Some(map) = &*cache
-    {
-        return map.clone();
-    }
+    ctx: &'a NylonContext,
+) -> &'a HashMap<String, String> {
+    ctx.cached_query.get_or_init(|| build_query_cache(headers))
+}
+
+fn build_query_cache(headers: &RequestHeader) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     if let Some(q) = headers.uri.query() {
         for pair in q.split('&') {
@@ -73,21 +277,18 @@ fn get_or_build_query_cache(
                 .or_insert_with(|| percent_decode_plus(v, true));
         }
     }
-    if let Ok(mut w) = ctx.cached_query.write() {
-        *w = Some(map.clone());
-    }
     map
 }
 
-fn get_or_build_cookie_cache(
+fn get_or_build_cookie_cache<'a>(
     headers: &RequestHeader,
-    ctx: &NylonContext,
-) -> HashMap<String, String> {
-    if let Ok(cache) = ctx.cached_cookies.read()
-        && let Some(map) = &*cache
-    {
-        return map.clone();
-    }
+    ctx: &'a NylonContext,
+) -> &'a HashMap<String, String> {
+    ctx.cached_cookies
+        .get_or_init(|| build_cookie_cache(headers))
+}
+
+fn build_cookie_cache(headers: &RequestHeader) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     let cookie_val_opt = headers
         .headers
@@ -108,9 +309,6 @@ fn get_or_build_cookie_cache(
                 .or_insert_with(|| percent_decode_plus(v.trim(), false));
         }
     }
-    if let Ok(mut w) = ctx.cached_cookies.write() {
-        *w = Some(map.clone());
-    }
     map
 }
 /// Represents a part of a JSON path
@@ -154,6 +352,7 @@ fn parse_expr<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> O
     if let Some(c) = chars.peek() {
         match c {
             '\'' | '"' => parse_literal(chars),
+            '0'..='9' => parse_number(chars),
             'a'..='z' | 'A'..='Z' | '_' | '-' => parse_func_or_var(chars),
             _ => None,
         }
@@ -175,6 +374,19 @@ fn parse_literal<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -
     Some(Expr::Literal(val))
 }
 
+/// Unquoted numbers are literals, e.g. `substr(value, 0, 8)`
+fn parse_number<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> Option<Expr> {
+    let mut val = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        val.push(c);
+        chars.next();
+    }
+    Some(Expr::Literal(val))
+}
+
 fn parse_func_or_var<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> Option<Expr> {
     let mut name = String::new();
     while let Some(&c) = chars.peek() {
@@ -271,8 +483,19 @@ fn parse_template_uncached(input: &str) -> Result<Vec<Expr>, NylonError> {
         }
 
         // Parse expression
-        if let Some(expr) = parse_expression(expr_str) {
-            result.push(expr);
+        match parse_expression(expr_str) {
+            Some(expr) => {
+                check_expr(&expr).map_err(|e| {
+                    NylonError::ConfigError(format!("{}: {}", whole_match.as_str(), e))
+                })?;
+                result.push(expr)
+            }
+            None => {
+                return Err(NylonError::ConfigError(format!(
+                    "invalid template expression {}",
+                    whole_match.as_str()
+                )));
+            }
         }
 
         last = whole_match.end();
@@ -307,14 +530,8 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
     match expr {
         Expr::Literal(s) => s.clone(),
         Expr::Request(name) => match name.as_str() {
-            "client_ip" => match ctx.client_ip.read() {
-                Ok(ip) => ip.clone(),
-                Err(_) => String::new(),
-            },
-            "host" => match ctx.host.read() {
-                Ok(h) => h.clone(),
-                Err(_) => String::new(),
-            },
+            "client_ip" => ctx.client_ip.clone(),
+            "host" => ctx.host.clone(),
             "tls" => {
                 if ctx.tls.load(std::sync::atomic::Ordering::Relaxed) {
                     "true".to_string()
@@ -322,6 +539,9 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
                     "false".to_string()
                 }
             }
+            "device_class" => client_hints::device_class(headers, ctx)
+                .as_str()
+                .to_string(),
             _ => String::new(), // fallback
         },
         Expr::Func { name, args } => match name.as_str() {
@@ -367,12 +587,9 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
             }
             "param" => {
                 if let Some(Expr::Request(name)) = args.first() {
-                    let got = match ctx.params.read() {
-                        Ok(map_opt) => match &*map_opt {
-                            Some(map) => map.get(name).cloned().unwrap_or_default(),
-                            None => String::new(),
-                        },
-                        Err(_) => String::new(),
+                    let got = match &ctx.params {
+                        Some(map) => map.get(name).cloned().unwrap_or_default(),
+                        None => String::new(),
                     };
                     if got.is_empty() && args.len() >= 2 {
                         eval_expr(&args[1], headers, ctx)
@@ -386,14 +603,8 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
             "request" => {
                 if let Some(Expr::Request(v)) = args.first() {
                     match v.as_str() {
-                        "client_ip" => match ctx.client_ip.read() {
-                            Ok(ip) => ip.clone(),
-                            Err(_) => String::new(),
-                        },
-                        "host" => match ctx.host.read() {
-                            Ok(h) => h.clone(),
-                            Err(_) => String::new(),
-                        },
+                        "client_ip" => ctx.client_ip.clone(),
+                        "host" => ctx.host.clone(),
                         "tls" => {
                             if ctx.tls.load(std::sync::atomic::Ordering::Relaxed) {
                                 "true".to_string()
@@ -401,6 +612,9 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
                                 "false".to_string()
                             }
                         }
+                        "device_class" => client_hints::device_class(headers, ctx)
+                            .as_str()
+                            .to_string(),
                         "method" => headers.method.as_str().to_string(),
                         "path" => headers.uri.path().to_string(),
                         "scheme" => {
@@ -416,6 +630,16 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
                     String::new()
                 }
             }
+            "path" => headers.uri.path().to_string(),
+            "method" => headers.method.as_str().to_string(),
+            "var" => match args.first() {
+                Some(Expr::Request(v)) => eval_var(v, ctx),
+                _ => String::new(),
+            },
+            "geo" => match args.first() {
+                Some(Expr::Request(field)) => crate::geoip::lookup(&ctx.client_ip, field),
+                _ => String::new(),
+            },
             "env" => {
                 if let Some(Expr::Request(v)) = args.first() {
                     std::env::var(v).unwrap_or_default()
@@ -524,7 +748,176 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
                     String::new() // Incorrect number of arguments
                 }
             }
+            "add" | "sub" | "mul" | "div" => {
+                if args.len() == 2 {
+                    let a = eval_expr(&args[0], headers, ctx);
+                    let b = eval_expr(&args[1], headers, ctx);
+                    match name.as_str() {
+                        "add" => arithmetic(&a, &b, i64::checked_add, |x, y| x + y),
+                        "sub" => arithmetic(&a, &b, i64::checked_sub, |x, y| x - y),
+                        "mul" => arithmetic(&a, &b, i64::checked_mul, |x, y| x * y),
+                        // Integer division only when it is exact
+                        _ => arithmetic(
+                            &a,
+                            &b,
+                            |x, y| (x.checked_rem(y) == Some(0)).then(|| x / y),
+                            |x, y| x / y,
+                        ),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            "gt" => compare(args, headers, ctx, |x, y| x > y),
+            "lt" => compare(args, headers, ctx, |x, y| x < y),
+            "gte" => compare(args, headers, ctx, |x, y| x >= y),
+            "lte" => compare(args, headers, ctx, |x, y| x <= y),
+            "rand" => {
+                // rand(min, max): an integer in [min, max]
+                if args.len() == 2 {
+                    let min = eval_expr(&args[0], headers, ctx).trim().parse::<i64>();
+                    let max = eval_expr(&args[1], headers, ctx).trim().parse::<i64>();
+                    match (min, max) {
+                        (Ok(min), Ok(max)) if min <= max => fastrand::i64(min..=max).to_string(),
+                        _ => String::new(),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            "trim" => match args.first() {
+                Some(arg) => eval_expr(arg, headers, ctx).trim().to_string(),
+                None => String::new(),
+            },
+            "substr" => {
+                // substr(value, start[, length]), counted in characters
+                if args.len() >= 2 {
+                    let value = eval_expr(&args[0], headers, ctx);
+                    let start = eval_expr(&args[1], headers, ctx).parse().unwrap_or(0);
+                    let chars = value.chars().skip(start);
+                    match args
+                        .get(2)
+                        .map(|a| eval_expr(a, headers, ctx).parse::<usize>())
+                    {
+                        Some(Ok(len)) => chars.take(len).collect(),
+                        _ => chars.collect(),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            "replace" => {
+                // replace(value, from, to): every occurrence
+                if args.len() == 3 {
+                    let value = eval_expr(&args[0], headers, ctx);
+                    let from = eval_expr(&args[1], headers, ctx);
+                    if from.is_empty() {
+                        value
+                    } else {
+                        value.replace(&from, &eval_expr(&args[2], headers, ctx))
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            "split" => {
+                // split(value, separator, index)
+                if args.len() == 3 {
+                    let value = eval_expr(&args[0], headers, ctx);
+                    let sep = eval_expr(&args[1], headers, ctx);
+                    let index = eval_expr(&args[2], headers, ctx).parse::<usize>();
+                    match index {
+                        Ok(index) if !sep.is_empty() => {
+                            value.split(&sep).nth(index).unwrap_or_default().to_string()
+                        }
+                        _ => String::new(),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            "match" => {
+                // match(value, pattern): the matched text, empty if none
+                if args.len() >= 2 {
+                    let value = eval_expr(&args[0], headers, ctx);
+                    let pattern = eval_expr(&args[1], headers, ctx);
+                    compiled_regex(&pattern)
+                        .and_then(|re| re.find(&value).map(|m| m.as_str().to_string()))
+                        .unwrap_or_default()
+                } else {
+                    String::new()
+                }
+            }
+            "capture" => {
+                // capture(value, pattern, group): a group by number or name
+                if args.len() >= 3 {
+                    let value = eval_expr(&args[0], headers, ctx);
+                    let pattern = eval_expr(&args[1], headers, ctx);
+                    let group = eval_expr(&args[2], headers, ctx);
+                    compiled_regex(&pattern)
+                        .and_then(|re| {
+                            let caps = re.captures(&value)?;
+                            let m = match group.parse::<usize>() {
+                                Ok(i) => caps.get(i),
+                                Err(_) => caps.name(&group),
+                            };
+                            m.map(|m| m.as_str().to_string())
+                        })
+                        .unwrap_or_default()
+                } else {
+                    String::new()
+                }
+            }
+            "base64" => match args.first() {
+                Some(arg) => STANDARD.encode(eval_expr(arg, headers, ctx)),
+                None => String::new(),
+            },
+            "base64url" => match args.first() {
+                Some(arg) => URL_SAFE_NO_PAD.encode(eval_expr(arg, headers, ctx)),
+                None => String::new(),
+            },
+            "url_encode" => match args.first() {
+                Some(arg) => url_encode(&eval_expr(arg, headers, ctx)),
+                None => String::new(),
+            },
+            "url_decode" => match args.first() {
+                Some(arg) => url_decode(&eval_expr(arg, headers, ctx)),
+                None => String::new(),
+            },
+            "sha256" => match args.first() {
+                Some(arg) => {
+                    encode_digest(&Sha256::digest(eval_expr(arg, headers, ctx)), args.get(1))
+                }
+                None => String::new(),
+            },
+            "md5" => match args.first() {
+                Some(arg) => {
+                    encode_digest(&md5::Md5::digest(eval_expr(arg, headers, ctx)), args.get(1))
+                }
+                None => String::new(),
+            },
+            "hmac" => {
+                // HMAC-SHA256: hmac(key, message[, base64|base64url])
+                if args.len() >= 2 {
+                    let key = eval_expr(&args[0], headers, ctx);
+                    let message = eval_expr(&args[1], headers, ctx);
+                    match Hmac::<Sha256>::new_from_slice(key.as_bytes()) {
+                        Ok(mut mac) => {
+                            mac.update(message.as_bytes());
+                            encode_digest(&mac.finalize().into_bytes(), args.get(2))
+                        }
+                        Err(_) => String::new(),
+                    }
+                } else {
+                    String::new()
+                }
+            }
             "timestamp" => Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "now" => match args.first() {
+                Some(arg) => format_now(&eval_expr(arg, headers, ctx)),
+                None => Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            },
+            "unix_ms" => Utc::now().timestamp_millis().to_string(),
             "uuid" => {
                 // uuid(v4), uuid(v7)
                 if let Some(Expr::Request(v)) = args.first() {
@@ -544,6 +937,43 @@ pub fn eval_expr(expr: &Expr, headers: &RequestHeader, ctx: &NylonContext) -> St
     }
 }
 
+/// Current UTC time in a chrono `strftime` format, empty if the format is invalid
+fn format_now(format: &str) -> String {
+    use std::fmt::Write;
+
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return String::new();
+    }
+    let mut out = String::new();
+    match write!(out, "{}", Utc::now().format_with_items(items.into_iter())) {
+        Ok(()) => out,
+        Err(_) => String::new(),
+    }
+}
+
+/// Routing and connection facts for `var(name)`
+fn eval_var(name: &str, ctx: &NylonContext) -> String {
+    let route =
+        |f: fn(&crate::context::Route) -> String| ctx.route.as_deref().map(f).unwrap_or_default();
+    match name {
+        "route_name" => route(|r| r.name.clone()),
+        "route_path" => route(|r| r.path.clone()),
+        "service_name" => route(|r| r.service.name.clone()),
+        "tls_version" => ctx.tls_version.clone(),
+        "sni" => ctx.sni.clone(),
+        "waf_tags" => ctx.waf_tags.join(","),
+        "scheme" => {
+            if ctx.tls.load(std::sync::atomic::Ordering::Relaxed) {
+                "https".to_string()
+            } else {
+                "http".to_string()
+            }
+        }
+        _ => String::new(),
+    }
+}
+
 /// Render a template string by evaluating all expressions in the given context
 pub fn render_template_string(
     expr: &[Expr],
@@ -708,6 +1138,7 @@ mod tests {
     fn eval_str(expr_str: &str, headers: &RequestHeader, ctx: &NylonContext) -> String {
         let expr = parse_expression(expr_str)
             .unwrap_or_else(|| panic!("Failed to parse test expression: {}", expr_str));
+        check_expr(&expr).unwrap();
         eval_expr(&expr, headers, ctx)
     }
 
@@ -745,6 +1176,44 @@ mod tests {
         assert_eq!(eval_str("request(something_else)", &headers, &ctx), "");
     }
 
+    #[test]
+    fn test_eval_func_request_accessors() {
+        let mut headers =
+            RequestHeader::build(Method::POST, b"/users/42?tab=posts&q=a%20b", None).unwrap();
+        let _ = headers.append_header("cookie", "session=abc; theme=dark");
+        let mut ctx = NylonContext::default();
+        ctx.params = Some(HashMap::from([("id".to_string(), "42".to_string())]));
+
+        assert_eq!(eval_str("path()", &headers, &ctx), "/users/42");
+        assert_eq!(eval_str("method()", &headers, &ctx), "POST");
+        assert_eq!(eval_str("query(tab)", &headers, &ctx), "posts");
+        assert_eq!(eval_str("query(q)", &headers, &ctx), "a b");
+        assert_eq!(eval_str("query(page, '1')", &headers, &ctx), "1");
+        assert_eq!(eval_str("cookie(theme)", &headers, &ctx), "dark");
+        assert_eq!(eval_str("param(id)", &headers, &ctx), "42");
+        assert_eq!(eval_str("param(slug, 'none')", &headers, &ctx), "none");
+    }
+
+    #[test]
+    fn test_eval_device_class() {
+        let (headers, ctx) = mock_ctx();
+        assert_eq!(eval_str("device_class", &headers, &ctx), "desktop");
+
+        let (mut headers, ctx) = mock_ctx();
+        let _ = headers.append_header("user-agent", "Mozilla/5.0 (Linux; Android 14)");
+        let _ = headers.append_header("sec-ch-ua-mobile", "?0");
+        assert_eq!(eval_str("request(device_class)", &headers, &ctx), "desktop");
+
+        let (mut headers, ctx) = mock_ctx();
+        let _ = headers.append_header("user-agent", "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0)");
+        assert_eq!(eval_str("device_class", &headers, &ctx), "mobile");
+
+        let (mut headers, ctx) = mock_ctx();
+        let _ = headers.append_header("user-agent", "Googlebot/2.1");
+        let _ = headers.append_header("sec-ch-ua-mobile", "?1");
+        assert_eq!(eval_str("device_class", &headers, &ctx), "bot");
+    }
+
     #[test]
     fn test_eval_func_header() {
         let (headers, ctx) = mock_ctx();
@@ -867,6 +1336,156 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_eval_func_encoding_and_hashing() {
+        let (headers, ctx) = mock_ctx();
+        assert_eq!(
+            eval_str("base64('nylon:proxy')", &headers, &ctx),
+            "bnlsb246cHJveHk="
+        );
+        assert_eq!(eval_str("base64url('??>')", &headers, &ctx), "Pz8-");
+        assert_eq!(
+            eval_str("url_encode('a b/ü')", &headers, &ctx),
+            "a%20b%2F%C3%BC"
+        );
+        assert_eq!(
+            eval_str("url_decode('a%20b%2F%C3%BC+')", &headers, &ctx),
+            "a b/ü+"
+        );
+        assert_eq!(
+            eval_str("sha256('abc')", &headers, &ctx),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            eval_str("md5('abc')", &headers, &ctx),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        assert_eq!(
+            eval_str(
+                "hmac('key', 'The quick brown fox jumps over the lazy dog')",
+                &headers,
+                &ctx
+            ),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert_eq!(
+            eval_str("sha256('abc', base64)", &headers, &ctx),
+            "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+        );
+    }
+
+    #[test]
+    fn test_eval_func_string_manipulation() {
+        let (headers, ctx) = mock_ctx();
+        assert_eq!(eval_str("trim('  a b ')", &headers, &ctx), "a b");
+        assert_eq!(
+            eval_str("substr('nylon-proxy', 6)", &headers, &ctx),
+            "proxy"
+        );
+        assert_eq!(
+            eval_str("substr('nylon-proxy', 0, 5)", &headers, &ctx),
+            "nylon"
+        );
+        assert_eq!(
+            eval_str("replace('a.b.c', '.', '/')", &headers, &ctx),
+            "a/b/c"
+        );
+        assert_eq!(eval_str("split('a, b, c', ', ', 1)", &headers, &ctx), "b");
+        assert_eq!(eval_str("split('a,b', ',', 5)", &headers, &ctx), "");
+        assert_eq!(
+            eval_str("match(header(host), '[a-z]+')", &headers, &ctx),
+            "example"
+        );
+        assert_eq!(
+            eval_str(
+                "capture('/v2/users', '^/v(?P<version>[0-9]+)/', 'version')",
+                &headers,
+                &ctx
+            ),
+            "2"
+        );
+        assert_eq!(
+            eval_str("capture('/v2/users', '^/v([0-9]+)/', 1)", &headers, &ctx),
+            "2"
+        );
+        assert_eq!(eval_str("match('abc', 'x+')", &headers, &ctx), "");
+    }
+
+    #[test]
+    fn test_patterns_are_checked_when_parsed() {
+        assert!(extract_and_parse_templates("${match(path(), '^/api/[0-9]+')}").is_ok());
+        assert!(compiled_regex("^/api/[0-9]+").is_some());
+
+        let err = extract_and_parse_templates("${match('abc', '(')}").unwrap_err();
+        assert!(err.to_string().contains("invalid pattern"), "{}", err);
+
+        let err = extract_and_parse_templates("${match(path(), header(x-pattern))}").unwrap_err();
+        assert!(err.to_string().contains("quoted literal"), "{}", err);
+
+        // nested calls are checked too
+        let err =
+            extract_and_parse_templates("${upper(capture(path(), query(re), 1))}").unwrap_err();
+        assert!(err.to_string().contains("capture()"), "{}", err);
+    }
+
+    #[test]
+    fn test_unknown_functions_are_refused() {
+        let err = extract_and_parse_templates("${uper(path())}").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown function uper()"),
+            "{}",
+            err
+        );
+
+        let err = extract_and_parse_templates("${concat('a', lenght(path()))}").unwrap_err();
+        assert!(err.to_string().contains("lenght()"), "{}", err);
+
+        assert!(extract_and_parse_templates("${upper(uuid(v7))}").is_ok());
+        assert!(extract_and_parse_templates("${error(status)} ${response(bytes)}").is_ok());
+    }
+
+    #[test]
+    fn test_eval_func_numeric() {
+        let (headers, ctx) = mock_ctx();
+        assert_eq!(eval_str("add(2, 3)", &headers, &ctx), "5");
+        assert_eq!(eval_str("sub(2, '3.5')", &headers, &ctx), "-1.5");
+        assert_eq!(eval_str("mul(len('abcd'), 25)", &headers, &ctx), "100");
+        assert_eq!(eval_str("div(10, 4)", &headers, &ctx), "2.5");
+        assert_eq!(eval_str("div(10, 5)", &headers, &ctx), "2");
+        assert_eq!(eval_str("div(1, 0)", &headers, &ctx), "");
+        assert_eq!(eval_str("add('x', 1)", &headers, &ctx), "");
+
+        assert_eq!(eval_str("gt(10, 9)", &headers, &ctx), "10");
+        assert_eq!(eval_str("gt(9, 10)", &headers, &ctx), "");
+        assert_eq!(eval_str("lt('1.5', 2, 'small')", &headers, &ctx), "small");
+        assert_eq!(eval_str("gte(2, 2, 'yes')", &headers, &ctx), "yes");
+        assert_eq!(eval_str("lte(3, 2, 'yes')", &headers, &ctx), "");
+        assert_eq!(eval_str("gt('abc', 1)", &headers, &ctx), "");
+
+        for _ in 0..20 {
+            let n: i64 = eval_str("rand(1, 3)", &headers, &ctx).parse().unwrap();
+            assert!((1..=3).contains(&n));
+        }
+        assert_eq!(eval_str("rand(3, 1)", &headers, &ctx), "");
+    }
+
+    #[test]
+    fn test_eval_func_var_and_geo() {
+        let (headers, mut ctx) = mock_ctx();
+        assert_eq!(eval_str("var(scheme)", &headers, &ctx), "http");
+        assert_eq!(eval_str("var(route_name)", &headers, &ctx), "");
+
+        ctx.tls.store(true, std::sync::atomic::Ordering::Relaxed);
+        ctx.tls_version = "TLSv1.3".to_string();
+        ctx.sni = "api.example.com".to_string();
+        assert_eq!(eval_str("var(scheme)", &headers, &ctx), "https");
+        assert_eq!(eval_str("var(tls_version)", &headers, &ctx), "TLSv1.3");
+        assert_eq!(eval_str("var(sni)", &headers, &ctx), "api.example.com");
+
+        // No database loaded
+        assert_eq!(eval_str("geo(country)", &headers, &ctx), "");
+    }
+
     #[test]
     fn test_eval_func_timestamp() {
         let (headers, ctx) = mock_ctx();
@@ -881,6 +1500,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_eval_func_now_and_unix_ms() {
+        let (headers, ctx) = mock_ctx();
+        assert_eq!(
+            eval_str("now('%Y-%m-%d')", &headers, &ctx),
+            Utc::now().format("%Y-%m-%d").to_string()
+        );
+        assert!(eval_str("now()", &headers, &ctx).ends_with('Z'));
+        assert_eq!(eval_str("now('%Q')", &headers, &ctx), "");
+
+        let before = Utc::now().timestamp_millis();
+        let ms: i64 = eval_str("unix_ms()", &headers, &ctx).parse().unwrap();
+        assert!(ms >= before && ms <= Utc::now().timestamp_millis());
+    }
+
     #[test]
     fn test_eval_func_uuid() {
         let (headers, ctx) = mock_ctx();
@@ -1002,6 +1636,10 @@ mod tests {
 
         let res5 = extract_and_parse_templates("${func(arg)}").unwrap();
         assert_eq!(res5.len(), 1);
+
+        let err = extract_and_parse_templates("id: ${upper(header(x-id)}").unwrap_err();
+        assert!(err.to_string().contains("${upper(header(x-id)}"));
+        assert!(extract_and_parse_templates("${a.b}").is_err());
     }
 
     #[test]
@@ -1,6 +1,7 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub enum TlsKind {
     #[serde(rename = "custom")]
     Custom,
@@ -8,7 +9,7 @@ pub enum TlsKind {
     Acme,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct TlsConfig {
     #[serde(rename = "type")]
     pub kind: TlsKind, // "custom" or "acme"
@@ -22,10 +23,13 @@ pub struct TlsConfig {
     pub domains: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 pub struct AcmeConfig {
     pub provider: String,
     pub email: String,
+    /// Account name; domains sharing an account share its credentials and
+    /// rate limit. Unnamed configs use the default account.
+    pub account: Option<String>,
     /// Path to ACME directory (will use runtime config if not specified)
     #[serde(skip)]
     pub acme_dir: Option<String>,
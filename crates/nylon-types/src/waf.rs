@@ -0,0 +1,1051 @@
+//! Web application firewall rules
+//!
+//! Rules come from the `Waf` middleware payload, and from rule files in the
+//! `SecRule` syntax of ModSecurity and the OWASP Core Rule Set. A rule
+//! matches when all of its conditions hold; the middleware then applies its
+//! action.
+//!
+//! Of the `SecRule` syntax, request variables with name or `/regex/` keys
+//! and exclusions, the `@rx`, `@contains`, `@streq`, `@beginsWith`,
+//! `@endsWith`, `@pm` and `@within` operators, the `lowercase`, `urlDecode`,
+//! `compressWhitespace`, `removeWhitespace` and `removeNulls`
+//! transformations, `chain`, and the disruptive and metadata actions are
+//! understood. Rules using anything else (e.g. `TX` variables, `setvar`,
+//! `ctl` or `@detectSQLi`) would not behave as written, so they are skipped.
+
+use crate::template::url_decode;
+use nylon_error::NylonError;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::BTreeMap, path::Path};
+
+fn default_status() -> u16 {
+    403
+}
+
+fn default_inspect_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_tag_header() -> String {
+    "x-waf-tags".to_string()
+}
+
+/// Payload of the `Waf` middleware
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WafConfig {
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Files of `SecRule` lines; a directory stands for its `*.conf` files
+    #[serde(default)]
+    pub rule_files: Vec<String>,
+    /// Status of blocked requests
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// Bytes of the request body that body conditions see
+    #[serde(default = "default_inspect_body_bytes")]
+    pub inspect_body_bytes: usize,
+    /// Log requests that `block` rules match instead of blocking them
+    #[serde(default)]
+    pub detect_only: bool,
+    /// Request header that carries the tags to the upstream
+    #[serde(default = "default_tag_header")]
+    pub tag_header: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub id: String,
+    #[serde(rename = "match")]
+    pub conditions: MatchConfig,
+    #[serde(default)]
+    pub action: Action,
+    /// Tag added by the `tag` action
+    pub tag: Option<String>,
+    pub message: Option<String>,
+    /// Limit of the `rate_limit` action
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Conditions of a rule; regexes unless noted, and all of them have to hold
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Any of these methods
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Decoded path
+    pub path: Option<String>,
+    /// Decoded query string
+    pub query: Option<String>,
+    /// Name or value of any query or form argument
+    pub args: Option<String>,
+    /// Header name to regex
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Start of the body, see `inspect_body_bytes`
+    pub body: Option<String>,
+    /// More query and form arguments than this
+    pub args_over: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Answer with the configured status
+    #[default]
+    Block,
+    /// Only log the match
+    Log,
+    /// Add the rule's tag and let the request through
+    Tag,
+    /// Answer with 429 once a client exceeds the rule's limit
+    RateLimit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window_seconds: u64,
+}
+
+/// A compiled rule
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub id: String,
+    pub action: Action,
+    pub tag: Option<String>,
+    pub message: Option<String>,
+    pub rate_limit: Option<RateLimit>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Methods(Vec<String>),
+    ArgsOver(usize),
+    Match {
+        targets: Vec<Target>,
+        transforms: Vec<Transform>,
+        operator: Operator,
+        negated: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Method,
+    Path,
+    /// Last segment of the path
+    Basename,
+    Uri,
+    Query,
+    RequestLine,
+    /// Values, or names, of the entries of a collection; only those named by
+    /// `key` when it is set, and never the excluded ones
+    Entries {
+        collection: Collection,
+        names: bool,
+        key: Option<Key>,
+        except: Vec<Key>,
+    },
+    Body,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Collection {
+    /// Query, then form arguments
+    Args,
+    ArgsGet,
+    ArgsPost,
+    Headers,
+    Cookies,
+}
+
+/// An entry name, or a pattern of names; both ignore case
+#[derive(Debug, Clone)]
+enum Key {
+    Name(String),
+    Pattern(Regex),
+}
+
+impl Key {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Key::Name(key) => key.eq_ignore_ascii_case(name),
+            Key::Pattern(pattern) => pattern.is_match(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transform {
+    Lowercase,
+    UrlDecode,
+    CompressWhitespace,
+    RemoveWhitespace,
+    RemoveNulls,
+}
+
+#[derive(Debug, Clone)]
+enum Operator {
+    Regex(Regex),
+    Contains(String),
+    Equals(String),
+    BeginsWith(String),
+    EndsWith(String),
+    /// Any of these phrases, ignoring case
+    Phrases(Vec<String>),
+    /// The value is one of these words
+    Within(Vec<String>),
+}
+
+/// The parts of a request rules look at
+#[derive(Debug, Clone, Default)]
+pub struct WafRequest {
+    pub method: String,
+    /// Decoded path
+    pub path: String,
+    /// Decoded query string
+    pub query: String,
+    /// Header names in lowercase
+    pub headers: Vec<(String, String)>,
+    /// Query arguments, then form arguments of the body
+    pub args: Vec<(String, String)>,
+    /// How many of `args` came from the query
+    pub query_args: usize,
+    pub cookies: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl WafRequest {
+    pub fn new<'a>(
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: impl Iterator<Item = (&'a str, &'a str)>,
+        body: &[u8],
+    ) -> Self {
+        let headers: Vec<(String, String)> = headers
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+            .collect();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let mut args = form_args(query.unwrap_or_default());
+        let query_args = args.len();
+        if header("content-type")
+            .is_some_and(|t| t.starts_with("application/x-www-form-urlencoded"))
+        {
+            args.extend(form_args(&String::from_utf8_lossy(body)));
+        }
+        let cookies = header("cookie")
+            .map(|cookie| {
+                cookie
+                    .split(';')
+                    .filter_map(|part| part.trim().split_once('='))
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            method: method.to_string(),
+            path: url_decode(path),
+            query: url_decode(query.unwrap_or_default()),
+            args,
+            query_args,
+            cookies,
+            body: String::from_utf8_lossy(body).into_owned(),
+            headers,
+        }
+    }
+
+    fn values(&self, target: &Target) -> Vec<String> {
+        match target {
+            Target::Method => vec![self.method.clone()],
+            Target::Path => vec![self.path.clone()],
+            Target::Basename => vec![self.path.rsplit('/').next().unwrap_or_default().to_string()],
+            Target::Uri if self.query.is_empty() => vec![self.path.clone()],
+            Target::Uri => vec![format!("{}?{}", self.path, self.query)],
+            Target::Query => vec![self.query.clone()],
+            Target::RequestLine => vec![format!("{} {}", self.method, self.path)],
+            Target::Entries {
+                collection,
+                names,
+                key,
+                except,
+            } => self
+                .entries(*collection)
+                .iter()
+                .filter(|(n, _)| key.as_ref().is_none_or(|k| k.matches(n)))
+                .filter(|(n, _)| !except.iter().any(|e| e.matches(n)))
+                .map(|(n, v)| if *names { n.clone() } else { v.clone() })
+                .collect(),
+            Target::Body => vec![self.body.clone()],
+        }
+    }
+
+    fn entries(&self, collection: Collection) -> &[(String, String)] {
+        let query_args = self.query_args.min(self.args.len());
+        match collection {
+            Collection::Args => &self.args,
+            Collection::ArgsGet => &self.args[..query_args],
+            Collection::ArgsPost => &self.args[query_args..],
+            Collection::Headers => &self.headers,
+            Collection::Cookies => &self.cookies,
+        }
+    }
+}
+
+fn form_args(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                url_decode(&name.replace('+', " ")),
+                url_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+impl Rule {
+    pub fn matches(&self, req: &WafRequest) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Methods(methods) => {
+                methods.iter().any(|m| m.eq_ignore_ascii_case(&req.method))
+            }
+            Condition::ArgsOver(max) => req.args.len() > *max,
+            Condition::Match {
+                targets,
+                transforms,
+                operator,
+                negated,
+            } => {
+                let found = targets.iter().any(|target| {
+                    req.values(target)
+                        .into_iter()
+                        .any(|value| operator.test(&transform(value, transforms)))
+                });
+                found != *negated
+            }
+        })
+    }
+
+    fn uses_body(&self) -> bool {
+        self.conditions.iter().any(|c| match c {
+            Condition::Match { targets, .. } => targets.iter().any(|t| matches!(t, Target::Body)),
+            _ => false,
+        })
+    }
+
+    /// Whether the rule looks at form arguments of the body
+    fn uses_form(&self) -> bool {
+        self.conditions.iter().any(|c| match c {
+            Condition::ArgsOver(_) => true,
+            Condition::Match { targets, .. } => targets.iter().any(|t| {
+                matches!(
+                    t,
+                    Target::Entries {
+                        collection: Collection::Args | Collection::ArgsPost,
+                        ..
+                    }
+                )
+            }),
+            Condition::Methods(_) => false,
+        })
+    }
+}
+
+impl Operator {
+    fn test(&self, value: &str) -> bool {
+        match self {
+            Operator::Regex(regex) => regex.is_match(value),
+            Operator::Contains(s) => value.contains(s.as_str()),
+            Operator::Equals(s) => value == s,
+            Operator::BeginsWith(s) => value.starts_with(s.as_str()),
+            Operator::EndsWith(s) => value.ends_with(s.as_str()),
+            Operator::Phrases(phrases) => {
+                let value = value.to_lowercase();
+                phrases.iter().any(|p| value.contains(p.as_str()))
+            }
+            Operator::Within(words) => words.iter().any(|w| w == value),
+        }
+    }
+}
+
+fn transform(mut value: String, transforms: &[Transform]) -> String {
+    for t in transforms {
+        value = match t {
+            Transform::Lowercase => value.to_lowercase(),
+            Transform::UrlDecode => url_decode(&value),
+            Transform::CompressWhitespace => value.split_whitespace().collect::<Vec<_>>().join(" "),
+            Transform::RemoveWhitespace => value.split_whitespace().collect(),
+            Transform::RemoveNulls => value.replace('\0', ""),
+        };
+    }
+    value
+}
+
+fn regex(pattern: &str, rule: &str) -> Result<Regex, NylonError> {
+    Regex::new(pattern).map_err(|e| {
+        NylonError::ConfigError(format!(
+            "WAF rule {}: invalid regex {}: {}",
+            rule, pattern, e
+        ))
+    })
+}
+
+/// A `Waf` payload and its rules, compiled when the config loads
+#[derive(Debug)]
+pub struct Waf {
+    pub config: WafConfig,
+    pub rules: WafRules,
+}
+
+impl Waf {
+    pub fn from_payload(payload: Option<&Value>) -> Result<Self, NylonError> {
+        let Some(payload) = payload else {
+            return Err(NylonError::ConfigError(
+                "Waf requires a payload with rules or rule_files".to_string(),
+            ));
+        };
+        let config = serde_json::from_value::<WafConfig>(payload.clone())
+            .map_err(|e| NylonError::ConfigError(format!("Waf payload: {}", e)))?;
+        let rules = WafRules::compile(&config)?;
+        Ok(Self { config, rules })
+    }
+}
+
+/// Compiled rules of a `Waf` middleware
+#[derive(Debug, Clone, Default)]
+pub struct WafRules {
+    pub rules: Vec<Rule>,
+    /// Whether any rule looks at the body, so it has to be read
+    pub needs_body: bool,
+    /// Whether any rule looks at arguments, so a form body has to be read
+    pub needs_form: bool,
+}
+
+impl WafRules {
+    pub fn compile(config: &WafConfig) -> Result<Self, NylonError> {
+        let mut rules = vec![];
+        for rule in &config.rules {
+            rules.push(compile_rule(rule)?);
+        }
+        for file in &config.rule_files {
+            for path in rule_file_paths(Path::new(file))? {
+                let text = std::fs::read_to_string(&path).map_err(|e| {
+                    NylonError::ConfigError(format!(
+                        "Failed to read WAF rules {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                rules.extend(parse_sec_rules(&text, &path.display().to_string()));
+            }
+        }
+        let needs_body = rules.iter().any(Rule::uses_body);
+        let needs_form = rules.iter().any(Rule::uses_form);
+        Ok(Self {
+            rules,
+            needs_body,
+            needs_form,
+        })
+    }
+
+    /// Rules matching the request, in order
+    pub fn matching<'a>(&'a self, req: &'a WafRequest) -> impl Iterator<Item = &'a Rule> + 'a {
+        self.rules.iter().filter(move |rule| rule.matches(req))
+    }
+}
+
+fn compile_rule(rule: &RuleConfig) -> Result<Rule, NylonError> {
+    let id = &rule.id;
+    let c = &rule.conditions;
+    let mut conditions = vec![];
+    if !c.methods.is_empty() {
+        conditions.push(Condition::Methods(c.methods.clone()));
+    }
+    if let Some(max) = c.args_over {
+        conditions.push(Condition::ArgsOver(max));
+    }
+    let mut add = |targets: Vec<Target>, pattern: &str| -> Result<(), NylonError> {
+        conditions.push(Condition::Match {
+            targets,
+            transforms: vec![],
+            operator: Operator::Regex(regex(pattern, id)?),
+            negated: false,
+        });
+        Ok(())
+    };
+    if let Some(path) = &c.path {
+        add(vec![Target::Path], path)?;
+    }
+    if let Some(query) = &c.query {
+        add(vec![Target::Query], query)?;
+    }
+    if let Some(args) = &c.args {
+        let all = |names| Target::Entries {
+            collection: Collection::Args,
+            names,
+            key: None,
+            except: vec![],
+        };
+        add(vec![all(true), all(false)], args)?;
+    }
+    for (name, pattern) in &c.headers {
+        let header = Target::Entries {
+            collection: Collection::Headers,
+            names: false,
+            key: Some(Key::Name(name.clone())),
+            except: vec![],
+        };
+        add(vec![header], pattern)?;
+    }
+    if let Some(body) = &c.body {
+        add(vec![Target::Body], body)?;
+    }
+    if conditions.is_empty() {
+        return Err(NylonError::ConfigError(format!(
+            "WAF rule {} has no conditions",
+            id
+        )));
+    }
+    if rule.action == Action::RateLimit && rule.rate_limit.is_none() {
+        return Err(NylonError::ConfigError(format!(
+            "WAF rule {}: rate_limit action requires rate_limit",
+            id
+        )));
+    }
+    if rule.action == Action::Tag && rule.tag.is_none() {
+        return Err(NylonError::ConfigError(format!(
+            "WAF rule {}: tag action requires tag",
+            id
+        )));
+    }
+    Ok(Rule {
+        id: id.clone(),
+        action: rule.action,
+        tag: rule.tag.clone(),
+        message: rule.message.clone(),
+        rate_limit: rule.rate_limit,
+        conditions,
+    })
+}
+
+fn rule_file_paths(path: &Path) -> Result<Vec<std::path::PathBuf>, NylonError> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let entries = std::fs::read_dir(path).map_err(|e| {
+        NylonError::ConfigError(format!(
+            "Failed to read WAF rules {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "conf"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Rules of a `SecRule` file; `origin` names it in logs
+pub fn parse_sec_rules(text: &str, origin: &str) -> Vec<Rule> {
+    let mut rules = vec![];
+    // A rule with `chain` takes the conditions of the rules that follow it
+    let mut chained: Option<Rule> = None;
+    let mut skipping_chain = false;
+    let mut skipped = 0;
+    let mut line = String::new();
+    for raw in text.lines() {
+        let raw = raw.trim();
+        if let Some(part) = raw.strip_suffix('\\') {
+            line.push_str(part);
+            line.push(' ');
+            continue;
+        }
+        line.push_str(raw);
+        let statement = std::mem::take(&mut line);
+        let statement = statement.trim();
+        if statement.is_empty() || statement.starts_with('#') {
+            continue;
+        }
+        let args = split_args(statement);
+        if args.first().map(String::as_str) != Some("SecRule") {
+            continue;
+        }
+        let chains = args
+            .get(3)
+            .is_some_and(|a| split_actions(a).iter().any(|(name, _)| name == "chain"));
+        let continues_chain = chained.is_some() || skipping_chain;
+        match (sec_rule(&args), chained.take()) {
+            (Ok(rule), Some(mut head)) if !skipping_chain => {
+                head.conditions.extend(rule.conditions);
+                chained = Some(head);
+            }
+            (Ok(rule), None) if !continues_chain => chained = Some(rule),
+            (Ok(_), _) => {}
+            (Err(reason), head) => {
+                if head.is_some() || !continues_chain {
+                    skipped += 1;
+                }
+                let id = head.map(|h| h.id).unwrap_or_else(|| rule_id(&args));
+                tracing::debug!("{}: WAF rule {} skipped: {}", origin, id, reason);
+                skipping_chain = chains;
+                continue;
+            }
+        }
+        if !chains {
+            if let Some(rule) = chained.take()
+                && !skipping_chain
+            {
+                rules.push(rule);
+            }
+            skipping_chain = false;
+        }
+    }
+    if skipped > 0 {
+        tracing::warn!(
+            "{}: {} WAF rules skipped, they use syntax that is not supported",
+            origin,
+            skipped
+        );
+    }
+    rules
+}
+
+fn rule_id(args: &[String]) -> String {
+    args.get(3)
+        .and_then(|a| {
+            split_actions(a)
+                .into_iter()
+                .find(|(name, _)| name == "id")
+                .and_then(|(_, value)| value)
+        })
+        .unwrap_or_default()
+}
+
+/// A rule from the arguments of one `SecRule`
+fn sec_rule(args: &[String]) -> Result<Rule, String> {
+    let (Some(variables), Some(operator)) = (args.get(1), args.get(2)) else {
+        return Err("missing variables or operator".to_string());
+    };
+    let mut rule = Rule {
+        id: rule_id(args),
+        action: Action::Log,
+        tag: None,
+        message: None,
+        rate_limit: None,
+        conditions: vec![],
+    };
+    let mut transforms = vec![];
+    for (name, value) in split_actions(args.get(3).map(String::as_str).unwrap_or_default()) {
+        match (name.as_str(), value) {
+            ("deny" | "block" | "drop", None) => rule.action = Action::Block,
+            ("pass" | "chain" | "log" | "nolog" | "auditlog" | "noauditlog" | "capture", None) => {}
+            ("id" | "rev" | "ver" | "severity" | "maturity" | "accuracy" | "logdata", Some(_)) => {}
+            ("msg", Some(msg)) => rule.message = Some(msg),
+            ("tag", Some(tag)) => {
+                if rule.tag.is_none() {
+                    rule.tag = Some(tag);
+                }
+            }
+            ("phase", Some(phase)) => {
+                if !matches!(phase.as_str(), "1" | "2" | "request") {
+                    return Err(format!("phase {} is not a request phase", phase));
+                }
+            }
+            ("t", Some(t)) => match t.as_str() {
+                "none" => transforms.clear(),
+                "lowercase" => transforms.push(Transform::Lowercase),
+                "urlDecode" => transforms.push(Transform::UrlDecode),
+                "compressWhitespace" => transforms.push(Transform::CompressWhitespace),
+                "removeWhitespace" => transforms.push(Transform::RemoveWhitespace),
+                "removeNulls" => transforms.push(Transform::RemoveNulls),
+                t => return Err(format!("unsupported transformation {}", t)),
+            },
+            (name, _) => return Err(format!("unsupported action {}", name)),
+        }
+    }
+
+    let (negated, operator) = match operator.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, operator.as_str()),
+    };
+    let (name, argument) = match operator.strip_prefix('@') {
+        Some(rest) => rest.split_once(' ').unwrap_or((rest, "")),
+        None => ("rx", operator),
+    };
+
+    // `&ARGS "@gt N"` counts arguments
+    if variables == "&ARGS" {
+        return match (name, argument.trim().parse::<usize>()) {
+            ("gt", Ok(max)) if !negated => {
+                rule.conditions.push(Condition::ArgsOver(max));
+                Ok(rule)
+            }
+            _ => Err(format!("unsupported count operator @{}", name)),
+        };
+    }
+
+    let operator = match name {
+        "rx" => Operator::Regex(Regex::new(argument).map_err(|e| e.to_string())?),
+        "contains" => Operator::Contains(argument.to_string()),
+        "streq" => Operator::Equals(argument.to_string()),
+        "beginsWith" => Operator::BeginsWith(argument.to_string()),
+        "endsWith" => Operator::EndsWith(argument.to_string()),
+        "pm" => Operator::Phrases(argument.split_whitespace().map(str::to_lowercase).collect()),
+        "within" => Operator::Within(
+            argument
+                .split([' ', ','])
+                .filter(|w| !w.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ),
+        _ => return Err(format!("unsupported operator @{}", name)),
+    };
+    rule.conditions.push(Condition::Match {
+        targets: targets(variables)?,
+        transforms,
+        operator,
+        negated,
+    });
+    Ok(rule)
+}
+
+/// Targets of a `|` separated variable list
+fn targets(variables: &str) -> Result<Vec<Target>, String> {
+    let mut targets = vec![];
+    let mut exclusions: Vec<(&str, Key)> = vec![];
+    for variable in variables.split('|').map(str::trim) {
+        let (variable, key) = match variable.split_once(':') {
+            Some((v, k)) => (v, Some(key(k)?)),
+            None => (variable, None),
+        };
+        if let Some(variable) = variable.strip_prefix('!') {
+            let key = key.ok_or_else(|| format!("exclusion of {} has no key", variable))?;
+            exclusions.push((variable, key));
+            continue;
+        }
+        let entries = |collection, names| Target::Entries {
+            collection,
+            names,
+            key: None,
+            except: vec![],
+        };
+        let target = match variable {
+            "REQUEST_METHOD" => Target::Method,
+            "REQUEST_FILENAME" => Target::Path,
+            "REQUEST_BASENAME" => Target::Basename,
+            "REQUEST_URI" => Target::Uri,
+            "QUERY_STRING" => Target::Query,
+            "REQUEST_LINE" => Target::RequestLine,
+            "REQUEST_BODY" => Target::Body,
+            "ARGS" => entries(Collection::Args, false),
+            "ARGS_GET" => entries(Collection::ArgsGet, false),
+            "ARGS_POST" => entries(Collection::ArgsPost, false),
+            "ARGS_NAMES" => entries(Collection::Args, true),
+            "ARGS_GET_NAMES" => entries(Collection::ArgsGet, true),
+            "ARGS_POST_NAMES" => entries(Collection::ArgsPost, true),
+            "REQUEST_HEADERS" => entries(Collection::Headers, false),
+            "REQUEST_HEADERS_NAMES" => entries(Collection::Headers, true),
+            "REQUEST_COOKIES" => entries(Collection::Cookies, false),
+            "REQUEST_COOKIES_NAMES" => entries(Collection::Cookies, true),
+            variable => return Err(format!("unsupported variable {}", variable)),
+        };
+        let target = match (target, key) {
+            (
+                Target::Entries {
+                    collection, names, ..
+                },
+                key,
+            ) => Target::Entries {
+                collection,
+                names,
+                key,
+                except: vec![],
+            },
+            (target, None) => target,
+            (_, Some(_)) => return Err(format!("variable {} takes no key", variable)),
+        };
+        targets.push((variable, target));
+    }
+    Ok(targets
+        .into_iter()
+        .map(|(variable, mut target)| {
+            // An exclusion applies to the variable it names, e.g. `!ARGS:x` to `ARGS`
+            if let Target::Entries { except, .. } = &mut target {
+                except.extend(
+                    exclusions
+                        .iter()
+                        .filter(|(v, _)| *v == variable)
+                        .map(|(_, k)| k.clone()),
+                );
+            }
+            target
+        })
+        .collect())
+}
+
+/// A `name` or `/pattern/` key, optionally in `'`
+fn key(key: &str) -> Result<Key, String> {
+    let key = key.trim_matches('\'');
+    if !key.starts_with('/') {
+        return Ok(Key::Name(key.to_string()));
+    }
+    let pattern = key
+        .strip_prefix('/')
+        .and_then(|k| k.strip_suffix('/'))
+        .filter(|k| !k.is_empty())
+        .ok_or_else(|| format!("unterminated key pattern {}", key))?;
+    Regex::new(&format!("(?i){}", pattern))
+        .map(Key::Pattern)
+        .map_err(|e| e.to_string())
+}
+
+/// Whitespace separated arguments, with `"` quoting and `\` escapes
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_arg = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => match chars.next() {
+                Some('"') => current.push('"'),
+                Some(next) => {
+                    current.push('\\');
+                    current.push(next);
+                }
+                None => current.push('\\'),
+            },
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// `name:value` actions separated by commas, values optionally in `'`
+fn split_actions(actions: &str) -> Vec<(String, Option<String>)> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in actions.chars() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' if !quoted => parts.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+        .into_iter()
+        .map(|part| {
+            let part = part.trim();
+            match part.split_once(':') {
+                Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+                None => (part.to_string(), None),
+            }
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(method: &str, path: &str, query: Option<&str>, body: &str) -> WafRequest {
+        let headers = [
+            ("User-Agent", "sqlmap/1.7"),
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Cookie", "session=abc; theme=dark"),
+        ];
+        WafRequest::new(method, path, query, headers.into_iter(), body.as_bytes())
+    }
+
+    #[test]
+    fn test_dsl_rules() {
+        let config: WafConfig = serde_json::from_value(json!({
+            "rules": [
+                {"id": "scanner", "match": {"headers": {"user-agent": "(?i)sqlmap|nikto"}}, "action": "tag", "tag": "scanner"},
+                {"id": "admin-post", "match": {"methods": ["POST"], "path": "^/admin"}},
+                {"id": "xss", "match": {"body": "(?i)<script"}, "action": "log"},
+                {"id": "many-args", "match": {"args_over": 2}}
+            ]
+        }))
+        .unwrap();
+        let rules = WafRules::compile(&config).unwrap();
+        assert!(rules.needs_body);
+        assert_eq!(config.status, 403);
+
+        let req = request("POST", "/admin/users", Some("a=1"), "name=%3Cscript%3E");
+        let ids: Vec<&str> = rules.matching(&req).map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["scanner", "admin-post"]);
+
+        let req = request("GET", "/", Some("a=1&b=2"), "c=<SCRIPT>");
+        let ids: Vec<&str> = rules.matching(&req).map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["scanner", "xss", "many-args"]);
+    }
+
+    #[test]
+    fn test_invalid_dsl_rules() {
+        let compile = |rule: serde_json::Value| {
+            let config: WafConfig = serde_json::from_value(json!({ "rules": [rule] })).unwrap();
+            WafRules::compile(&config)
+        };
+        assert!(compile(json!({"id": "empty", "match": {}})).is_err());
+        assert!(compile(json!({"id": "bad", "match": {"path": "("}})).is_err());
+        assert!(
+            compile(json!({"id": "rl", "match": {"path": "/"}, "action": "rate_limit"})).is_err()
+        );
+    }
+
+    #[test]
+    fn test_sec_rules() {
+        let text = r#"
+# comment
+SecRule ARGS|!ARGS:token "@rx (?i)union\s+select" \
+    "id:942100,phase:2,deny,t:none,t:urlDecode,msg:'SQL injection'"
+SecRule REQUEST_HEADERS:User-Agent "@pm sqlmap nikto" "id:913100,phase:1,block,tag:'scanner'"
+SecRule REQUEST_METHOD "@streq POST" "id:1,phase:1,deny,chain"
+    SecRule REQUEST_FILENAME "@beginsWith /login" "t:lowercase"
+SecRule &ARGS "@gt 100" "id:2,phase:2,deny"
+SecRule TX:ANOMALY_SCORE "@ge 5" "id:3,phase:2,deny,chain"
+    SecRule ARGS "@rx x" ""
+SecRule RESPONSE_BODY "@rx secret" "id:4,phase:4,deny"
+"#;
+        let rules = parse_sec_rules(text, "test.conf");
+        let ids: Vec<&str> = rules.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["942100", "913100", "1", "2"]);
+        assert_eq!(rules[0].message.as_deref(), Some("SQL injection"));
+        assert_eq!(rules[1].tag.as_deref(), Some("scanner"));
+
+        let req = request("GET", "/", Some("q=1%20UNION%20SELECT"), "");
+        assert!(rules[0].matches(&req));
+        assert!(rules[1].matches(&req));
+        let req = request("GET", "/", Some("token=union+select"), "");
+        assert!(!rules[0].matches(&req));
+
+        assert!(rules[2].matches(&request("POST", "/LOGIN", None, "")));
+        assert!(!rules[2].matches(&request("GET", "/login", None, "")));
+    }
+
+    #[test]
+    fn test_untranslatable_sec_rules_are_skipped() {
+        let text = r#"
+SecRule ARGS "@rx x" "id:1,phase:2,block,setvar:'tx.anomaly_score=+5'"
+SecRule ARGS "@rx x" "id:2,phase:2,deny,ctl:ruleRemoveById=3"
+SecRule ARGS "@rx x" "id:3,phase:2,deny,t:htmlEntityDecode"
+SecRule ARGS "@rx x" "id:4,phase:2,deny,t:urlDecodeUni"
+SecRule ARGS "@rx x" "id:5,phase:2,deny,skipAfter:END"
+SecRule PATH_INFO "@rx x" "id:6,phase:2,deny"
+SecRule REQUEST_METHOD:x "@rx x" "id:7,phase:2,deny"
+SecRule ARGS:/unterminated "@rx x" "id:8,phase:2,deny"
+SecRule ARGS "@rx x" "id:9,phase:2,deny,chain"
+    SecRule ARGS "@rx y" "setvar:tx.x=1"
+SecRule ARGS "@rx x" "id:10,phase:2,deny,severity:'CRITICAL',tag:'a',tag:'b',nolog"
+"#;
+        let rules = parse_sec_rules(text, "test.conf");
+        let ids: Vec<&str> = rules.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["10"]);
+        assert_eq!(rules[0].tag.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_sec_rule_variables() {
+        let text = r#"
+SecRule ARGS_GET "@streq evil" "id:get,phase:2,deny"
+SecRule ARGS_POST "@streq evil" "id:post,phase:2,deny"
+SecRule REQUEST_BASENAME "@endsWith .php" "id:basename,phase:1,deny"
+SecRule ARGS:/^user_/|!ARGS:/_ok$/ "@streq evil" "id:keys,phase:2,deny"
+SecRule ARGS_NAMES|!ARGS:skip "@streq skip" "id:names,phase:2,deny"
+"#;
+        let rules = parse_sec_rules(text, "test.conf");
+        let ids: Vec<&str> = rules.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["get", "post", "basename", "keys", "names"]);
+        let matching = |req: &WafRequest| -> Vec<&str> {
+            rules
+                .iter()
+                .filter(|r| r.matches(req))
+                .map(|r| r.id.as_str())
+                .collect()
+        };
+
+        assert_eq!(
+            matching(&request("GET", "/", Some("a=evil"), "")),
+            vec!["get"]
+        );
+        assert_eq!(
+            matching(&request("POST", "/", None, "a=evil")),
+            vec!["post"]
+        );
+        assert_eq!(
+            matching(&request("GET", "/dir/index.php", None, "")),
+            vec!["basename"]
+        );
+        assert!(matching(&request("GET", "/index.php/x", None, "")).is_empty());
+        assert_eq!(
+            matching(&request("GET", "/", Some("USER_name=evil"), "")),
+            vec!["get", "keys"]
+        );
+        assert_eq!(
+            matching(&request("GET", "/", Some("user_ok=evil"), "")),
+            vec!["get"]
+        );
+        // `!ARGS:skip` only narrows `ARGS`, not `ARGS_NAMES`
+        assert_eq!(
+            matching(&request("GET", "/", Some("skip=1"), "")),
+            vec!["names"]
+        );
+
+        let rules = WafRules::compile(&serde_json::from_value(json!({})).unwrap()).unwrap();
+        assert!(!rules.needs_form);
+        let parsed = parse_sec_rules(text, "test.conf");
+        assert!(parsed.iter().any(Rule::uses_form));
+        assert!(!parsed.iter().any(Rule::uses_body));
+    }
+
+    #[test]
+    fn test_waf_from_payload() {
+        assert!(Waf::from_payload(None).is_err());
+        assert!(Waf::from_payload(Some(&json!({"rules": [], "unknown": 1}))).is_err());
+        let waf = Waf::from_payload(Some(&json!({
+            "rules": [{"id": "admin", "match": {"path": "^/admin"}}]
+        })))
+        .unwrap();
+        assert_eq!(waf.rules.rules.len(), 1);
+        assert_eq!(waf.config.tag_header, "x-waf-tags");
+    }
+}
@@ -0,0 +1,111 @@
+//! Response watermarks
+//!
+//! A watermark is stamped into the first body chunk of HTML and JSON
+//! responses, so it works on streamed bodies without buffering them.
+
+/// Identifier to stamp into the current response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+    pub value: String,
+    /// Add `<!-- value -->` to HTML responses
+    pub html: bool,
+    /// Top level field added to JSON objects, if any
+    pub json_field: Option<String>,
+}
+
+impl Watermark {
+    /// Whether responses of `content_type` can carry the watermark
+    pub fn applies_to(&self, content_type: &str) -> bool {
+        let content_type = content_type.to_ascii_lowercase();
+        (self.html && content_type.starts_with("text/html"))
+            || (self.json_field.is_some() && content_type.contains("json"))
+    }
+
+    /// Stamp the first body chunk, or `None` if it cannot carry the mark
+    pub fn apply(&self, chunk: &[u8]) -> Option<Vec<u8>> {
+        let start = chunk.iter().position(|b| !b.is_ascii_whitespace())?;
+        match chunk[start] {
+            b'{' => self.apply_json(chunk, start),
+            b'<' if self.html => self.apply_html(chunk, start),
+            _ => None,
+        }
+    }
+
+    fn apply_html(&self, chunk: &[u8], start: usize) -> Option<Vec<u8>> {
+        // Nothing may precede the doctype, so the comment goes right after it
+        let at = if chunk[start..]
+            .get(..9)
+            .is_some_and(|p| p.eq_ignore_ascii_case(b"<!doctype"))
+        {
+            start + chunk[start..].iter().position(|b| *b == b'>')? + 1
+        } else {
+            start
+        };
+        // Keep the value from closing the comment
+        let value = self.value.replace("--", "- -").replace('>', "");
+        let mut out = Vec::with_capacity(chunk.len() + value.len() + 9);
+        out.extend_from_slice(&chunk[..at]);
+        out.extend_from_slice(format!("<!-- {} -->", value).as_bytes());
+        out.extend_from_slice(&chunk[at..]);
+        Some(out)
+    }
+
+    fn apply_json(&self, chunk: &[u8], start: usize) -> Option<Vec<u8>> {
+        let field = self.json_field.as_ref()?;
+        let rest = start + 1;
+        // An object split right after `{` cannot be told empty or not
+        let next = chunk[rest..].iter().find(|b| !b.is_ascii_whitespace())?;
+        let mut member = format!(
+            "{}:{}",
+            serde_json::to_string(field).ok()?,
+            serde_json::to_string(&self.value).ok()?
+        );
+        if *next != b'}' {
+            member.push(',');
+        }
+        let mut out = Vec::with_capacity(chunk.len() + member.len());
+        out.extend_from_slice(&chunk[..rest]);
+        out.extend_from_slice(member.as_bytes());
+        out.extend_from_slice(&chunk[rest..]);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watermark() -> Watermark {
+        Watermark {
+            value: "u-42".to_string(),
+            html: true,
+            json_field: Some("_wm".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_html_after_doctype() {
+        let out = watermark().apply(b"<!DOCTYPE html><html></html>").unwrap();
+        assert_eq!(out, b"<!DOCTYPE html><!-- u-42 --><html></html>");
+        let out = watermark().apply(b"<html></html>").unwrap();
+        assert_eq!(out, b"<!-- u-42 --><html></html>");
+    }
+
+    #[test]
+    fn test_json_object() {
+        let out = watermark().apply(b"{\"a\":1}").unwrap();
+        assert_eq!(out, b"{\"_wm\":\"u-42\",\"a\":1}");
+        let out = watermark().apply(b" { }").unwrap();
+        assert_eq!(out, b" {\"_wm\":\"u-42\" }");
+        assert!(watermark().apply(b"[1,2]").is_none());
+        assert!(watermark().apply(b"{").is_none());
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let wm = watermark();
+        assert!(wm.applies_to("text/html; charset=utf-8"));
+        assert!(wm.applies_to("application/problem+json"));
+        assert!(!wm.applies_to("image/png"));
+    }
+}
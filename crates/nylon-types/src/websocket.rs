@@ -1,16 +1,68 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::sync::mpsc;
 
 /// WebSocket adapter configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WebSocketAdapterConfig {
     pub adapter_type: AdapterType,
     pub redis: Option<RedisAdapterConfig>,
     pub cluster: Option<ClusterAdapterConfig>,
+    /// Messages waiting to be written to one client
+    #[serde(default = "default_send_queue_size")]
+    pub send_queue_size: usize,
+    #[serde(default)]
+    pub on_send_queue_full: SendQueueOverflow,
+    /// Seconds between server pings
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Seconds without any frame from the client (pongs included) before closing
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Largest single frame accepted from a client
+    #[serde(default = "default_max_message_bytes")]
+    pub max_frame_bytes: usize,
+    /// Largest message accepted from a client, fragments included
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// Text and binary messages a client may send per second
+    pub max_messages_per_sec: Option<u32>,
+    /// Open connections allowed on this node
+    pub max_connections: Option<usize>,
+    /// Open connections allowed on this node per client IP
+    pub max_connections_per_ip: Option<usize>,
+}
+
+pub fn default_send_queue_size() -> usize {
+    1024
+}
+
+pub fn default_ping_interval_secs() -> u64 {
+    20
+}
+
+pub fn default_idle_timeout_secs() -> u64 {
+    60
+}
+
+pub fn default_max_message_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+/// What happens when a client reads slower than messages arrive for it
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SendQueueOverflow {
+    /// Drop the oldest queued message
+    #[default]
+    DropOldest,
+    /// Close the connection with 1008 (policy violation)
+    Close,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub enum AdapterType {
     #[serde(rename = "memory")]
     Memory,
@@ -20,7 +72,8 @@ pub enum AdapterType {
     Cluster,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RedisAdapterConfig {
     pub host: String,
     pub port: u16,
@@ -29,7 +82,8 @@ pub struct RedisAdapterConfig {
     pub key_prefix: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ClusterAdapterConfig {
     pub nodes: Vec<String>,
     pub key_prefix: Option<String>,
@@ -82,6 +136,12 @@ pub enum WebSocketEvent {
         exclude_connection: Option<String>,
         sender_node_id: String,
     },
+    /// Broadcast message to every connection on every node
+    BroadcastAll {
+        message: WebSocketMessage,
+        exclude_connection: Option<String>,
+        sender_node_id: String,
+    },
     /// Send message to specific connection
     SendToConnection {
         connection_id: String,
@@ -95,9 +155,23 @@ pub enum WebSocketEvent {
 pub enum WebSocketMessage {
     Text(String),
     Binary(Vec<u8>),
-    Close { code: u16, reason: String },
+    Close {
+        code: u16,
+        reason: String,
+    },
     Ping(Vec<u8>),
     Pong(Vec<u8>),
+    /// A connection joined or left a room; delivered to the plugin, not the client
+    Presence(RoomPresence),
+}
+
+/// Join or leave of a room member, as sent to the plugins of the other members
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomPresence {
+    pub room: String,
+    pub connection_id: String,
+    pub joined: bool,
+    pub metadata: HashMap<String, String>,
 }
 
 /// Adapter event sender type
@@ -0,0 +1,460 @@
+//! Access Log
+//!
+//! Renders one record per request in the logging phase and hands it to a
+//! writer thread, so a slow disk or syslog daemon never holds up a response.
+//! The thread is started on the first record rather than in [`init`], because
+//! threads do not survive pingora's daemonize fork.
+//!
+//! Besides the regular template functions, access log templates can use
+//! `${response(field)}` with `status`, `bytes`, `duration_ms`, `upstream_ms`,
+//! `route`, `service` and `backend`.
+//!
+//! Independently of the access log, requests crossing the `slow_request_ms`
+//! or `large_response_bytes` thresholds are reported as warnings.
+
+use chrono::Local;
+use nylon_config::runtime::{AccessLogConfig, AccessLogFormat, AccessLogSink};
+use nylon_error::NylonError;
+use nylon_types::{
+    context::NylonContext,
+    template::{Expr, eval_expr, extract_and_parse_templates},
+};
+use once_cell::sync::OnceCell;
+use pingora::http::RequestHeader;
+use serde_json::{Map, Value, json};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use tracing::{error, warn};
+
+/// Records buffered before new ones are dropped
+const QUEUE_SIZE: usize = 8192;
+
+static ACCESS_LOG: OnceCell<AccessLog> = OnceCell::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static SLOW_REQUEST_MS: AtomicU64 = AtomicU64::new(0);
+static LARGE_RESPONSE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Values only known once the response has been sent
+pub struct AccessRecord<'a> {
+    pub route: &'a str,
+    pub service: &'a str,
+    pub backend: &'a str,
+    pub status: u16,
+    pub bytes: usize,
+    pub duration_ms: u64,
+    /// Time until the upstream response headers arrived
+    pub upstream_ms: Option<u64>,
+}
+
+enum Format {
+    Json(Option<Vec<(String, Vec<Expr>)>>),
+    Combined,
+    Template(Vec<Expr>),
+}
+
+struct AccessLog {
+    format: Format,
+    sinks: Mutex<Vec<Sink>>,
+    tx: OnceCell<SyncSender<String>>,
+}
+
+impl AccessLog {
+    fn sender(&self) -> &SyncSender<String> {
+        self.tx.get_or_init(|| {
+            let sinks = match self.sinks.lock() {
+                Ok(mut sinks) => std::mem::take(&mut *sinks),
+                Err(_) => vec![],
+            };
+            let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+            if let Err(e) = std::thread::Builder::new()
+                .name("access-log".to_string())
+                .spawn(move || run_writer(rx, sinks))
+            {
+                error!("Failed to start access log writer: {}", e);
+            }
+            tx
+        })
+    }
+}
+
+/// Parse the access log format and open its sinks
+pub fn init(config: Option<&AccessLogConfig>) -> Result<(), NylonError> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+
+    let format = match config.format {
+        AccessLogFormat::Json => {
+            let fields = match &config.fields {
+                Some(fields) => Some(
+                    fields
+                        .iter()
+                        .map(|(name, template)| {
+                            Ok((name.clone(), extract_and_parse_templates(template)?))
+                        })
+                        .collect::<Result<Vec<_>, NylonError>>()?,
+                ),
+                None => None,
+            };
+            Format::Json(fields)
+        }
+        AccessLogFormat::Combined => Format::Combined,
+        AccessLogFormat::Template => {
+            let template = config.template.as_deref().ok_or_else(|| {
+                NylonError::ConfigError(
+                    "access_log.template is required when format is template".to_string(),
+                )
+            })?;
+            Format::Template(extract_and_parse_templates(template)?)
+        }
+    };
+
+    let sinks = config
+        .sinks
+        .iter()
+        .map(Sink::open)
+        .collect::<Result<Vec<_>, NylonError>>()?;
+    if sinks.is_empty() {
+        return Err(NylonError::ConfigError(
+            "access_log.sinks must not be empty".to_string(),
+        ));
+    }
+
+    let _ = ACCESS_LOG.set(AccessLog {
+        format,
+        sinks: Mutex::new(sinks),
+        tx: OnceCell::new(),
+    });
+    Ok(())
+}
+
+/// Set the slow request and large response warning thresholds
+pub fn set_thresholds(slow_request_ms: Option<u64>, large_response_bytes: Option<u64>) {
+    SLOW_REQUEST_MS.store(slow_request_ms.unwrap_or(0), Ordering::Relaxed);
+    LARGE_RESPONSE_BYTES.store(large_response_bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Warn about requests that crossed a configured threshold
+pub fn check_thresholds(headers: &RequestHeader, record: &AccessRecord) {
+    let slow_request_ms = SLOW_REQUEST_MS.load(Ordering::Relaxed);
+    let large_response_bytes = LARGE_RESPONSE_BYTES.load(Ordering::Relaxed);
+    let slow = slow_request_ms > 0 && record.duration_ms >= slow_request_ms;
+    let large = large_response_bytes > 0 && record.bytes as u64 >= large_response_bytes;
+    if !slow && !large {
+        return;
+    }
+
+    let upstream_ms = record
+        .upstream_ms
+        .map(|ms| ms.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let reason = match (slow, large) {
+        (true, true) => "slow request, large response",
+        (true, false) => "slow request",
+        _ => "large response",
+    };
+    warn!(
+        route = record.route,
+        service = record.service,
+        backend = record.backend,
+        method = headers.method.as_str(),
+        path = headers.uri.path(),
+        status = record.status,
+        duration_ms = record.duration_ms,
+        upstream_ms = %upstream_ms,
+        bytes = record.bytes,
+        "{}",
+        reason
+    );
+}
+
+/// Queue the record of a finished request
+pub fn log(headers: &RequestHeader, ctx: &NylonContext, record: &AccessRecord) {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return;
+    };
+
+    let line = match &access_log.format {
+        Format::Json(Some(fields)) => {
+            let record: Map<String, Value> = fields
+                .iter()
+                .map(|(name, exprs)| {
+                    (
+                        name.clone(),
+                        Value::String(render(exprs, headers, ctx, record)),
+                    )
+                })
+                .collect();
+            Value::Object(record).to_string()
+        }
+        Format::Json(None) => default_json(headers, ctx, record).to_string(),
+        Format::Combined => combined(headers, ctx, record),
+        Format::Template(exprs) => render(exprs, headers, ctx, record),
+    };
+
+    if let Err(TrySendError::Full(_)) = access_log.sender().try_send(line) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn render(
+    exprs: &[Expr],
+    headers: &RequestHeader,
+    ctx: &NylonContext,
+    record: &AccessRecord,
+) -> String {
+    let mut out = String::new();
+    for expr in exprs {
+        match expr {
+            Expr::Func { name, args } if name == "response" => {
+                if let Some(Expr::Request(field)) = args.first() {
+                    out.push_str(&response_field(field, record));
+                }
+            }
+            _ => out.push_str(&eval_expr(expr, headers, ctx)),
+        }
+    }
+    out
+}
+
+fn response_field(field: &str, record: &AccessRecord) -> String {
+    match field {
+        "status" => record.status.to_string(),
+        "bytes" => record.bytes.to_string(),
+        "duration_ms" => record.duration_ms.to_string(),
+        "upstream_ms" => record
+            .upstream_ms
+            .map(|ms| ms.to_string())
+            .unwrap_or_default(),
+        "route" => record.route.to_string(),
+        "service" => record.service.to_string(),
+        "backend" => record.backend.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn header<'a>(headers: &'a RequestHeader, name: &str) -> &'a str {
+    headers
+        .headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn client_ip(ctx: &NylonContext) -> String {
+    ctx.client_ip.clone()
+}
+
+fn default_json(headers: &RequestHeader, ctx: &NylonContext, record: &AccessRecord) -> Value {
+    json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "client_ip": client_ip(ctx),
+        "method": headers.method.as_str(),
+        "host": ctx.host.clone(),
+        "path": headers.uri.path(),
+        "query": headers.uri.query().unwrap_or_default(),
+        "status": record.status,
+        "bytes": record.bytes,
+        "duration_ms": record.duration_ms,
+        "upstream_ms": record.upstream_ms,
+        "route": record.route,
+        "service": record.service,
+        "backend": record.backend,
+        "referer": header(headers, "referer"),
+        "user_agent": header(headers, "user-agent"),
+    })
+}
+
+/// Apache/NGINX combined log format
+fn combined(headers: &RequestHeader, ctx: &NylonContext, record: &AccessRecord) -> String {
+    let ip = client_ip(ctx);
+    let bytes = if record.bytes == 0 {
+        "-".to_string()
+    } else {
+        record.bytes.to_string()
+    };
+    let request_line = format!(
+        "{} {} {:?}",
+        headers.method,
+        headers
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/"),
+        headers.version
+    );
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+        if ip.is_empty() { "-" } else { ip.as_str() },
+        Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        request_line,
+        record.status,
+        bytes,
+        header(headers, "referer"),
+        header(headers, "user-agent"),
+    )
+}
+
+fn run_writer(rx: Receiver<String>, mut sinks: Vec<Sink>) {
+    while let Ok(line) = rx.recv() {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Access log queue full, dropped {} record(s)", dropped);
+        }
+        for sink in sinks.iter_mut() {
+            if let Err(e) = sink.write(&line) {
+                error!("Failed to write access log: {}", e);
+            }
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    SyslogUnix {
+        socket: UnixDatagram,
+        tag: String,
+    },
+    SyslogUdp {
+        socket: UdpSocket,
+        tag: String,
+    },
+}
+
+impl Sink {
+    fn open(config: &AccessLogSink) -> Result<Self, NylonError> {
+        match config {
+            AccessLogSink::Stdout => Ok(Sink::Stdout),
+            AccessLogSink::File {
+                path,
+                max_size_mb,
+                max_files,
+            } => Ok(Sink::File(RotatingFile::open(
+                path.clone(),
+                max_size_mb * 1024 * 1024,
+                *max_files,
+            )?)),
+            AccessLogSink::Syslog { address, tag } => {
+                let err = |e: std::io::Error| {
+                    NylonError::ConfigError(format!(
+                        "Failed to connect to syslog {}: {}",
+                        address, e
+                    ))
+                };
+                #[cfg(unix)]
+                if address.starts_with('/') {
+                    let socket = UnixDatagram::unbound().map_err(err)?;
+                    socket.connect(address).map_err(err)?;
+                    return Ok(Sink::SyslogUnix {
+                        socket,
+                        tag: tag.clone(),
+                    });
+                }
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(err)?;
+                socket.connect(address).map_err(err)?;
+                Ok(Sink::SyslogUdp {
+                    socket,
+                    tag: tag.clone(),
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{}", line)
+            }
+            Sink::File(file) => file.write_line(line),
+            #[cfg(unix)]
+            Sink::SyslogUnix { socket, tag } => socket
+                .send(syslog_message(tag, line).as_bytes())
+                .map(|_| ()),
+            Sink::SyslogUdp { socket, tag } => socket
+                .send(syslog_message(tag, line).as_bytes())
+                .map(|_| ()),
+        }
+    }
+}
+
+/// RFC 3164 message with facility local0 and severity info
+fn syslog_message(tag: &str, line: &str) -> String {
+    format!(
+        "<134>{} {}: {}",
+        Local::now().format("%b %e %H:%M:%S"),
+        tag,
+        line
+    )
+}
+
+/// Append-only file rotated by size (`access.log` -> `access.log.1` -> ...)
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self, NylonError> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                NylonError::ConfigError(format!("Failed to create {}: {}", parent.display(), e))
+            })?;
+        }
+        let file = Self::open_file(&path).map_err(|e| {
+            NylonError::ConfigError(format!("Failed to open {}: {}", path.display(), e))
+        })?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn open_file(path: &PathBuf) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = std::fs::rename(rotated(n), rotated(n + 1));
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
@@ -1,19 +1,51 @@
+use crate::config_history;
 use async_trait::async_trait;
 use dashmap::DashMap;
 use nylon_config::{proxy::ProxyConfigExt, runtime::RuntimeConfig};
-use nylon_types::{plugins::FfiPlugin, proxy::ProxyConfig, tls::AcmeConfig};
+use nylon_types::{
+    plugins::PluginBackend,
+    proxy::ProxyConfig,
+    services::{ServiceItem, ServiceType},
+    tls::{AcmeConfig, TlsConfig, TlsKind},
+};
 use pingora::{server::ShutdownWatch, services::background::BackgroundService};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time::{interval, sleep};
 use tracing::{error, info, warn};
 
 pub struct NylonBackgroundService;
+/// Windows has no SIGHUP; reloads go through the command pipe
+#[cfg(not(unix))]
+struct NoHangup;
+
+#[cfg(not(unix))]
+impl NoHangup {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
+}
+
 #[async_trait]
 impl BackgroundService for NylonBackgroundService {
     async fn start(&self, mut shutdown: ShutdownWatch) {
+        crate::systemd::ready();
         let mut period_1d = interval(Duration::from_secs(86400));
         let mut hc_interval = interval(Duration::from_secs(5));
+        let poll_secs = RuntimeConfig::get()
+            .ok()
+            .and_then(|c| c.remote_config)
+            .unwrap_or_default()
+            .poll_interval_secs;
+        let mut remote_poll = interval(Duration::from_secs(poll_secs.max(1)));
+        #[cfg(unix)]
         let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup());
+        #[cfg(not(unix))]
+        let signal = Ok::<_, std::io::Error>(NoHangup);
         let mut signal = match signal {
             Ok(signal) => signal,
             Err(e) => {
@@ -27,34 +59,49 @@ impl BackgroundService for NylonBackgroundService {
                     info!("Received SIGHUP signal - reloading configuration...");
                     if let Err(e) = reload_configuration().await {
                         error!("Failed to reload configuration: {}", e);
-                    } else {
-                        info!("✓ Configuration reloaded successfully");
                     }
                 },
                 _ = shutdown.changed() => {
-                    // shutdown
-                    info!("Shutting down background service");
-
-                    // Shutting down plugins
-                    let plugins =
-                    match nylon_store::get::<DashMap<String, Arc<FfiPlugin>>>(nylon_store::KEY_PLUGINS) {
-                        Some(plugins) => plugins,
-                        None => {
-                            let new_plugins = DashMap::new();
-                            nylon_store::insert(nylon_store::KEY_PLUGINS, new_plugins.clone());
-                            new_plugins
-                        }
-                    };
-                    for plugin in plugins.iter() {
-                        unsafe {
-                            (plugin.value().shutdown)();
-                        }
-                    }
+                    shut_down();
+                    break;
+                },
+                _ = service_stop() => {
+                    shut_down();
+                    #[cfg(windows)]
+                    crate::win_service::stopped();
+                    #[cfg(not(windows))]
                     break;
                 },
                 _ = hc_interval.tick() => {
                     // periodic health checks for all services
                     nylon_store::lb_backends::run_health_checks_for_all().await;
+                    crate::metrics::refresh();
+                    for generation in nylon_store::routes::reap_retired_routes() {
+                        info!("Routes from config generation {} drained", generation);
+                    }
+                    for (name, result) in nylon_plugin::loaders::restart_unhealthy() {
+                        match result {
+                            Ok(version) => info!("Plugin {} restarted at version {}", name, version.version),
+                            Err(e) => warn!("Plugin {} restart failed: {}", name, e),
+                        }
+                    }
+                    for plugin in nylon_plugin::loaders::reap_retired() {
+                        info!("Plugin {} version {} drained and unloaded", plugin.name, plugin.version);
+                    }
+                },
+                _ = remote_poll.tick(), if poll_secs > 0 => {
+                    match tokio::task::spawn_blocking(nylon_config::remote::poll).await {
+                        Ok(Ok(true)) => {
+                            info!("Remote configuration changed - reloading configuration...");
+                            // the poll has just fetched it
+                            if let Err(e) = reload(false).await {
+                                error!("Failed to reload configuration: {}", e);
+                            }
+                        }
+                        Ok(Ok(false)) => {}
+                        Ok(Err(e)) => warn!("Failed to poll remote configuration: {}", e),
+                        Err(e) => error!("Remote configuration poll panicked: {}", e),
+                    }
                 },
                 _ = period_1d.tick() => {
                     info!("Running daily certificate expiration check");
@@ -136,7 +183,7 @@ async fn check_and_renew_certificates() -> Result<(), nylon_error::NylonError> {
 }
 
 /// Renew certificate สำหรับ domain
-async fn renew_certificate(domain: &str) -> Result<(), nylon_error::NylonError> {
+pub(crate) async fn renew_certificate(domain: &str) -> Result<(), nylon_error::NylonError> {
     info!("Renewing certificate for domain: {}", domain);
 
     let result = async {
@@ -196,8 +243,190 @@ async fn renew_certificate(domain: &str) -> Result<(), nylon_error::NylonError>
     result
 }
 
-/// Reload configuration from file
-async fn reload_configuration() -> Result<(), nylon_error::NylonError> {
+/// Outcome of a configuration reload, meant for automation to verify a deploy
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ReloadReport {
+    /// Version of the applied config in `nylon config history`
+    pub version: u64,
+    pub routes_added: Vec<String>,
+    pub routes_removed: Vec<String>,
+    pub services_rebuilt: Vec<String>,
+    pub certs_touched: Vec<String>,
+    pub plugins_reloaded: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ReloadReport {
+    /// Compare the previously applied proxy config with the new one
+    fn diff(previous: &ProxyConfig, current: &ProxyConfig) -> Self {
+        let route_names = |config: &ProxyConfig| -> BTreeSet<String> {
+            config
+                .routes
+                .iter()
+                .flatten()
+                .map(|r| r.name.clone())
+                .collect()
+        };
+        let old_routes = route_names(previous);
+        let new_routes = route_names(current);
+
+        let old_services: HashMap<&str, &ServiceItem> = previous
+            .services
+            .iter()
+            .flatten()
+            .map(|s| (s.name.as_str(), s))
+            .collect();
+        let mut services_rebuilt: Vec<String> = current
+            .services
+            .iter()
+            .flatten()
+            .filter(|s| s.service_type == ServiceType::Http)
+            .filter(|s| old_services.get(s.name.as_str()) != Some(s))
+            .map(|s| s.name.clone())
+            .collect();
+        services_rebuilt.sort();
+
+        let old_tls: Vec<&TlsConfig> = previous.tls.iter().flatten().collect();
+        let mut certs_touched: Vec<String> = current
+            .tls
+            .iter()
+            .flatten()
+            .filter(|t| t.kind == TlsKind::Custom && !old_tls.contains(t))
+            .flat_map(|t| t.domains.clone())
+            .collect();
+        certs_touched.sort();
+
+        let old_plugins: HashMap<&str, serde_json::Value> = previous
+            .plugins
+            .iter()
+            .flatten()
+            .map(|p| (p.name.as_str(), serde_json::to_value(p).unwrap_or_default()))
+            .collect();
+        let mut plugins_reloaded: Vec<String> = current
+            .plugins
+            .iter()
+            .flatten()
+            .filter(|p| {
+                old_plugins.get(p.name.as_str())
+                    != Some(&serde_json::to_value(p).unwrap_or_default())
+            })
+            .map(|p| p.name.clone())
+            .collect();
+        plugins_reloaded.sort();
+
+        Self {
+            version: 0,
+            routes_added: new_routes.difference(&old_routes).cloned().collect(),
+            routes_removed: old_routes.difference(&new_routes).cloned().collect(),
+            services_rebuilt,
+            certs_touched,
+            plugins_reloaded,
+            warnings: vec![],
+        }
+    }
+}
+
+/// Stop plugins and flush telemetry before the process exits
+fn shut_down() {
+    info!("Shutting down background service");
+    // After a handover the unit lives on in the new process
+    if !crate::upgrade::handed_over() {
+        crate::systemd::stopping();
+    }
+
+    // Shutting down plugins
+    let plugins =
+        match nylon_store::get::<DashMap<String, Arc<dyn PluginBackend>>>(nylon_store::KEY_PLUGINS)
+        {
+            Some(plugins) => plugins,
+            None => {
+                let new_plugins = DashMap::new();
+                nylon_store::insert(nylon_store::KEY_PLUGINS, new_plugins.clone());
+                new_plugins
+            }
+        };
+    for plugin in plugins.iter() {
+        plugin.value().shutdown();
+    }
+
+    // Flush pending trace spans
+    crate::telemetry::shutdown();
+}
+
+/// Resolves when the Windows service control manager stops the service
+#[cfg(windows)]
+async fn service_stop() {
+    crate::win_service::stop_requested().await
+}
+
+/// Only the Windows service is stopped from outside of pingora
+#[cfg(not(windows))]
+async fn service_stop() {
+    std::future::pending().await
+}
+
+/// Reload configuration from file and log the resulting report
+pub(crate) async fn reload_configuration() -> Result<ReloadReport, nylon_error::NylonError> {
+    reload(true).await
+}
+
+/// Reload, fetching a remote `config_dir` first when `fetch_remote` is set
+async fn reload(fetch_remote: bool) -> Result<ReloadReport, nylon_error::NylonError> {
+    crate::systemd::reloading();
+    let applied = apply_configuration(fetch_remote).await;
+    crate::systemd::ready();
+    let report = applied?;
+    match serde_json::to_string(&report) {
+        Ok(json) => info!("✓ Configuration reloaded: {}", json),
+        Err(_) => info!("✓ Configuration reloaded: {:?}", report),
+    }
+    for warning in &report.warnings {
+        warn!("Reload warning: {}", warning);
+    }
+    Ok(report)
+}
+
+/// Swap a runtime config in, with the GeoIP databases and settings taken from it
+///
+/// The databases are read on a blocking thread; when they fail to open,
+/// nothing changes.
+async fn apply_runtime(runtime: &RuntimeConfig) -> Result<(), nylon_error::NylonError> {
+    let geoip = runtime.geoip.as_ref();
+    let city = geoip.and_then(|g| g.database.clone());
+    let asn = geoip.and_then(|g| g.asn_database.clone());
+    tokio::task::spawn_blocking(move || nylon_types::geoip::load(city.as_deref(), asn.as_deref()))
+        .await
+        .map_err(|e| {
+            nylon_error::NylonError::RuntimeError(format!(
+                "Loading the GeoIP databases panicked: {}",
+                e
+            ))
+        })??;
+    runtime.store()?;
+    crate::error_page::set_detail(runtime.error_detail);
+    crate::load_shedding::configure(runtime);
+    Ok(())
+}
+
+/// Fetch (if asked) and parse the proxy config on a blocking thread
+async fn load_proxy_config(
+    dir: String,
+    fetch_remote: bool,
+) -> Result<ProxyConfig, nylon_error::NylonError> {
+    tokio::task::spawn_blocking(move || {
+        if fetch_remote {
+            nylon_config::remote::fetch(&dir)?;
+        }
+        ProxyConfig::from_dir(&dir)
+    })
+    .await
+    .map_err(|e| {
+        nylon_error::NylonError::RuntimeError(format!("Loading the proxy config panicked: {}", e))
+    })?
+}
+
+/// Load the configuration files and swap them in
+async fn apply_configuration(fetch_remote: bool) -> Result<ReloadReport, nylon_error::NylonError> {
     info!("Starting configuration reload...");
 
     // Get stored config path
@@ -206,36 +435,122 @@ async fn reload_configuration() -> Result<(), nylon_error::NylonError> {
 
     info!("Loading runtime configuration from: {}", config_path);
 
-    // Load and validate runtime configuration
-    let runtime_config = RuntimeConfig::from_file(&config_path)?;
+    // Load and validate runtime configuration; secrets may come from Vault
+    let runtime_config =
+        tokio::task::spawn_blocking(move || RuntimeConfig::from_file(&config_path))
+            .await
+            .map_err(|e| {
+                nylon_error::NylonError::RuntimeError(format!(
+                    "Loading the runtime config panicked: {}",
+                    e
+                ))
+            })??;
 
     // Store new runtime config
-    runtime_config.store()?;
+    apply_runtime(&runtime_config).await?;
     info!("✓ Runtime configuration updated");
 
     // Load proxy configuration from config_dir
-    let proxy_config = ProxyConfig::from_dir(
-        runtime_config
-            .config_dir
-            .to_string_lossy()
-            .to_string()
-            .as_str(),
-    )?;
+    let config_dir = runtime_config.config_dir.to_string_lossy().to_string();
+    let proxy_config = match load_proxy_config(config_dir, fetch_remote).await {
+        Ok(proxy_config) => proxy_config,
+        Err(e) => {
+            // Nothing of the proxy config changed yet
+            if let Some(snapshot) = config_history::current() {
+                apply_runtime(&snapshot.runtime).await?;
+            }
+            return Err(e);
+        }
+    };
 
     // Store new proxy config
-    proxy_config.store().await?;
-    info!("✓ Proxy configuration updated");
+    let previous =
+        nylon_store::get::<ProxyConfig>(nylon_store::KEY_PROXY_CONFIG).unwrap_or_default();
+    if let Err(e) = proxy_config.store().await {
+        return Err(restore_last_applied(e).await);
+    }
+    let mut report = ReloadReport::diff(&previous, &proxy_config);
+    report.version = config_history::record(&runtime_config, &proxy_config, "reload");
 
     // Reload ACME certificates if needed
-    if let Err(e) = reload_acme_certificates().await {
-        warn!("Failed to reload ACME certificates: {}", e);
+    if let Err(e) = reload_acme_certificates(&mut report).await {
+        report
+            .warnings
+            .push(format!("Failed to reload ACME certificates: {}", e));
     }
+    report.certs_touched.sort();
+    report.certs_touched.dedup();
 
-    Ok(())
+    Ok(report)
+}
+
+/// Put the last applied configuration back after a reload failed half way
+async fn restore_last_applied(error: nylon_error::NylonError) -> nylon_error::NylonError {
+    let Some(snapshot) = config_history::current() else {
+        return error;
+    };
+    let restored = match apply_runtime(&snapshot.runtime).await {
+        Ok(()) => snapshot.proxy.store().await,
+        Err(e) => Err(e),
+    };
+    match restored {
+        Ok(()) => {
+            warn!(
+                "Reload failed, rolled back to config version {}: {}",
+                snapshot.version, error
+            );
+            nylon_error::NylonError::ConfigError(format!(
+                "{} (rolled back to config version {})",
+                error, snapshot.version
+            ))
+        }
+        Err(e) => {
+            error!(
+                "Rolling back to config version {} failed: {}",
+                snapshot.version, e
+            );
+            error
+        }
+    }
+}
+
+/// Apply a configuration from the history again
+pub(crate) async fn rollback_configuration(
+    version: u64,
+) -> Result<ReloadReport, nylon_error::NylonError> {
+    let snapshot = config_history::get(version).ok_or_else(|| {
+        nylon_error::NylonError::ConfigError(format!(
+            "Config version {} is not in the history",
+            version
+        ))
+    })?;
+    info!("Rolling back to config version {}...", version);
+
+    let previous =
+        nylon_store::get::<ProxyConfig>(nylon_store::KEY_PROXY_CONFIG).unwrap_or_default();
+    apply_runtime(&snapshot.runtime).await?;
+    if let Err(e) = snapshot.proxy.store().await {
+        return Err(restore_last_applied(e).await);
+    }
+    let mut report = ReloadReport::diff(&previous, &snapshot.proxy);
+    report.version = config_history::record(
+        &snapshot.runtime,
+        &snapshot.proxy,
+        format!("rollback to {}", version),
+    );
+    if let Err(e) = reload_acme_certificates(&mut report).await {
+        report
+            .warnings
+            .push(format!("Failed to reload ACME certificates: {}", e));
+    }
+    info!("✓ Rolled back to config version {}", version);
+    Ok(report)
 }
 
 /// Reload ACME certificates configuration
-async fn reload_acme_certificates() -> Result<(), nylon_error::NylonError> {
+async fn reload_acme_certificates(
+    report: &mut ReloadReport,
+) -> Result<(), nylon_error::NylonError> {
     use nylon_types::tls::AcmeConfig;
 
     info!("Reloading ACME certificates...");
@@ -272,6 +587,7 @@ async fn reload_acme_certificates() -> Result<(), nylon_error::NylonError> {
                                 domain
                             );
                             renew_certificate(domain).await?;
+                            report.certs_touched.push(domain.clone());
                         } else {
                             info!(
                                 "Certificate for {} is still valid, expires in {} days",
@@ -286,6 +602,7 @@ async fn reload_acme_certificates() -> Result<(), nylon_error::NylonError> {
                         warn!("Failed to parse certificate for {}: {}", domain, e);
                         info!("Issuing new certificate for {}...", domain);
                         renew_certificate(domain).await?;
+                        report.certs_touched.push(domain.clone());
                     }
                 }
             }
@@ -295,9 +612,15 @@ async fn reload_acme_certificates() -> Result<(), nylon_error::NylonError> {
                     "No certificate found for {} after reload, issuing new certificate...",
                     domain
                 );
-                if let Err(e) = renew_certificate(domain).await {
-                    error!("Failed to issue certificate for {}: {}", domain, e);
-                    // Don't return error, continue with other domains
+                match renew_certificate(domain).await {
+                    Ok(()) => report.certs_touched.push(domain.clone()),
+                    Err(e) => {
+                        error!("Failed to issue certificate for {}: {}", domain, e);
+                        report
+                            .warnings
+                            .push(format!("Failed to issue certificate for {}: {}", domain, e));
+                        // Don't return error, continue with other domains
+                    }
                 }
             }
         }
@@ -0,0 +1,649 @@
+//! Command Socket Service
+//!
+//! Listens on a local unix socket (a named pipe on Windows) so that
+//! `nylon cert ...`, `nylon plugin ...`, `nylon proxy ...`,
+//! `nylon service reload` and `nylon service upgrade` can manage the running
+//! daemon without a restart.
+
+use crate::background_service::{reload_configuration, renew_certificate, rollback_configuration};
+use async_trait::async_trait;
+use nylon_command::socket::{CommandRequest, CommandResponse};
+use nylon_config::runtime::RuntimeConfig;
+use nylon_error::NylonError;
+use nylon_store::routes::expand_middleware;
+use nylon_store::tls::TlsStore;
+use nylon_tls::{AcmeClient, CertificateInfo};
+use nylon_types::{proxy::ProxyConfig, route::MiddlewareItem, tls::AcmeConfig};
+use openssl::{pkey::PKey, x509::X509};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{error, info, warn};
+
+pub struct CommandSocketService;
+
+#[async_trait]
+impl BackgroundService for CommandSocketService {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        serve(nylon_store::KEY_COMMAND_SOCKET_PATH, shutdown).await;
+    }
+}
+
+#[cfg(unix)]
+async fn serve(path: &str, mut shutdown: ShutdownWatch) {
+    // A stale socket file from a previous run would make bind fail
+    let _ = std::fs::remove_file(path);
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind command socket {}: {}", path, e);
+            return;
+        }
+    };
+
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        warn!("Failed to restrict command socket permissions: {}", e);
+    }
+
+    info!("Command socket listening on {}", path);
+
+    // After an upgrade the path belongs to the new process; leave it alone
+    let bound = std::fs::metadata(path).map(|m| m.ino()).ok();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                if bound.is_some() && std::fs::metadata(path).map(|m| m.ino()).ok() == bound {
+                    let _ = std::fs::remove_file(path);
+                }
+                break;
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream));
+                }
+                Err(e) => warn!("Failed to accept command connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Windows has no unix sockets; a named pipe serves the same protocol
+///
+/// The default pipe security only lets administrators and the service account
+/// write to it, and remote clients are rejected.
+#[cfg(windows)]
+async fn serve(path: &str, mut shutdown: ShutdownWatch) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(path) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to create command pipe {}: {}", path, e);
+            return;
+        }
+    };
+
+    info!("Command pipe listening on {}", path);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            connected = server.connect() => {
+                if let Err(e) = connected {
+                    warn!("Failed to accept command connection: {}", e);
+                    continue;
+                }
+                // Each client gets its own pipe instance; open the next one first
+                let next = match ServerOptions::new().create(path) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        error!("Failed to create command pipe {}: {}", path, e);
+                        break;
+                    }
+                };
+                tokio::spawn(handle_connection(std::mem::replace(&mut server, next)));
+            }
+        }
+    }
+}
+
+/// Read a single request from the connection and write back the response
+async fn handle_connection<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    if let Err(e) = reader.read_line(&mut line).await {
+        warn!("Failed to read command: {}", e);
+        return;
+    }
+
+    let response = match serde_json::from_str::<CommandRequest>(line.trim()) {
+        Ok(request) => {
+            info!("Command received: {:?}", request_name(&request));
+            handle_request(request)
+                .await
+                .unwrap_or_else(|e| CommandResponse::error(e.to_string()))
+        }
+        Err(e) => CommandResponse::error(format!("Invalid command: {}", e)),
+    };
+
+    let mut payload = match serde_json::to_vec(&response) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Failed to serialize command response: {}", e);
+            return;
+        }
+    };
+    payload.push(b'\n');
+    if let Err(e) = writer.write_all(&payload).await {
+        warn!("Failed to write command response: {}", e);
+    }
+}
+
+/// Short name of the request for logging (the import payload holds a private key)
+fn request_name(request: &CommandRequest) -> String {
+    match request {
+        CommandRequest::Reload => "reload".to_string(),
+        CommandRequest::CertList => "cert list".to_string(),
+        CommandRequest::CertRenew { domain } => format!("cert renew {}", domain),
+        CommandRequest::CertRevoke { domain } => format!("cert revoke {}", domain),
+        CommandRequest::CertImport { domain, .. } => format!("cert import {}", domain),
+        CommandRequest::ConfigHistory => "config history".to_string(),
+        CommandRequest::ConfigRollback { version } => format!("config rollback {}", version),
+        CommandRequest::PluginList => "plugin list".to_string(),
+        CommandRequest::PluginReload { name } => format!("plugin reload {}", name),
+        CommandRequest::ProxyRoutes => "proxy routes".to_string(),
+        CommandRequest::ProxyServices => "proxy services".to_string(),
+        CommandRequest::ProxyTestRoute {
+            host, method, path, ..
+        } => format!("proxy test-route {} {} {}", method, host, path),
+        CommandRequest::Upgrade { binary } => format!("upgrade {}", binary),
+    }
+}
+
+async fn handle_request(request: CommandRequest) -> Result<CommandResponse, NylonError> {
+    match request {
+        CommandRequest::Reload => {
+            let report = reload_configuration().await?;
+            let data = serde_json::to_value(&report)
+                .map_err(|e| NylonError::InternalServerError(e.to_string()))?;
+            Ok(CommandResponse::ok("Configuration reloaded").with_data(data))
+        }
+        CommandRequest::ConfigHistory => {
+            let history = crate::config_history::summaries();
+            let data = serde_json::to_value(&history)
+                .map_err(|e| NylonError::InternalServerError(e.to_string()))?;
+            Ok(
+                CommandResponse::ok(format!("{} config version(s) kept", history.len()))
+                    .with_data(data),
+            )
+        }
+        CommandRequest::ConfigRollback { version } => {
+            let report = rollback_configuration(version).await?;
+            let data = serde_json::to_value(&report)
+                .map_err(|e| NylonError::InternalServerError(e.to_string()))?;
+            Ok(CommandResponse::ok(format!(
+                "Rolled back to config version {}, applied as version {}",
+                version, report.version
+            ))
+            .with_data(data))
+        }
+        CommandRequest::CertList => list_certificates(),
+        CommandRequest::CertRenew { domain } => {
+            acme_config_for(&domain)?;
+            renew_certificate(&domain).await?;
+            Ok(CommandResponse::ok(format!(
+                "Certificate renewed for {}",
+                domain
+            )))
+        }
+        CommandRequest::CertRevoke { domain } => revoke_certificate(&domain).await,
+        CommandRequest::CertImport {
+            domain,
+            cert,
+            key,
+            chain,
+        } => import_certificate(domain, cert, key, chain),
+        CommandRequest::PluginList => list_plugins(),
+        CommandRequest::PluginReload { name } => reload_plugin(&name),
+        CommandRequest::ProxyRoutes => list_routes(),
+        CommandRequest::ProxyServices => list_services(),
+        CommandRequest::ProxyTestRoute {
+            host,
+            path,
+            method,
+            selector,
+        } => test_route(&host, selector.as_deref(), &method, &path),
+        CommandRequest::Upgrade { binary } => {
+            let pid = crate::upgrade::start(&binary).await?;
+            Ok(CommandResponse::ok(format!(
+                "Handing the listen sockets from pid {} to pid {}",
+                std::process::id(),
+                pid
+            ))
+            .with_data(json!({ "old_pid": std::process::id(), "new_pid": pid })))
+        }
+    }
+}
+
+/// Routes with their paths and the middleware each path runs, groups expanded
+fn list_routes() -> Result<CommandResponse, NylonError> {
+    let config = nylon_store::get::<ProxyConfig>(nylon_store::KEY_PROXY_CONFIG).unwrap_or_default();
+    let groups = config.middleware_groups.clone().unwrap_or_default();
+    let chain = |middleware: &Option<Vec<MiddlewareItem>>| -> Result<Vec<String>, NylonError> {
+        let items = expand_middleware(middleware.as_deref().unwrap_or_default(), &groups)?;
+        Ok(items.iter().map(middleware_name).collect())
+    };
+
+    let mut data = vec![];
+    for route in config.routes.iter().flatten() {
+        let route_chain = chain(&route.middleware)?;
+        let mut paths = vec![];
+        for path in &route.paths {
+            let mut middleware = route_chain.clone();
+            middleware.extend(chain(&path.middleware)?);
+            paths.push(json!({
+                "path": path.path,
+                "methods": path.methods,
+                "service": path.service.name,
+                "rewrite": path.service.rewrite,
+                "fallback": path.fallback,
+                "middleware": middleware,
+            }));
+        }
+        data.push(json!({
+            "name": route.name,
+            "match": { "type": route.route.kind, "value": route.route.value },
+            "tls": route.tls.as_ref().is_some_and(|tls| tls.enabled),
+            "paths": paths,
+        }));
+    }
+    Ok(
+        CommandResponse::ok(format!("{} route(s) loaded", data.len()))
+            .with_data(Value::Array(data)),
+    )
+}
+
+/// The route, service and middleware that would handle a request
+pub(crate) fn test_route(
+    host: &str,
+    selector: Option<&str>,
+    method: &str,
+    path: &str,
+) -> Result<CommandResponse, NylonError> {
+    let host = nylon_store::routes::host_name(host);
+    let (route, params) = nylon_store::routes::match_route(host, selector, method, path)?;
+    let middleware: Vec<String> = route
+        .route_middleware
+        .iter()
+        .chain(route.path_middleware.iter())
+        .flatten()
+        .map(|(item, _)| middleware_name(item))
+        .collect();
+    let data = json!({
+        "route": route.name,
+        "pattern": route.path,
+        "service": route.service.name,
+        "rewrite": route.rewrite,
+        "fallback": route.fallback.as_ref().map(|service| &service.name),
+        "middleware": middleware,
+        "params": params,
+    });
+    Ok(CommandResponse::ok(format!(
+        "{} {}{} matches route {}",
+        method, host, path, route.name
+    ))
+    .with_data(data))
+}
+
+fn middleware_name(item: &MiddlewareItem) -> String {
+    match (&item.plugin, &item.entry) {
+        (Some(plugin), Some(entry)) => format!("{}:{}", plugin, entry),
+        (Some(plugin), None) => plugin.clone(),
+        _ => "-".to_string(),
+    }
+}
+
+/// Services with the health of each backend as seen by the last health check
+fn list_services() -> Result<CommandResponse, NylonError> {
+    let config = nylon_store::get::<ProxyConfig>(nylon_store::KEY_PROXY_CONFIG).unwrap_or_default();
+    let health = nylon_store::lb_backends::backend_health();
+
+    let mut data = vec![];
+    for service in config.services.iter().flatten() {
+        let endpoints: Vec<Value> = service
+            .endpoints
+            .iter()
+            .flatten()
+            .map(|endpoint| {
+                let address = format!("{}:{}", endpoint.ip, endpoint.port);
+                let healthy = health
+                    .iter()
+                    .find(|(name, backend, _)| *name == service.name && *backend == address)
+                    .map(|(_, _, healthy)| *healthy);
+                json!({ "address": address, "weight": endpoint.weight, "healthy": healthy })
+            })
+            .collect();
+        data.push(json!({
+            "name": service.name,
+            "type": service.service_type,
+            "algorithm": service.algorithm,
+            "health_check": service.health_check.as_ref().is_some_and(|hc| hc.enabled),
+            "endpoints": endpoints,
+        }));
+    }
+    Ok(
+        CommandResponse::ok(format!("{} service(s) loaded", data.len()))
+            .with_data(Value::Array(data)),
+    )
+}
+
+fn list_plugins() -> Result<CommandResponse, NylonError> {
+    let versions = nylon_plugin::loaders::versions();
+    let data = serde_json::to_value(&versions)
+        .map_err(|e| NylonError::InternalServerError(e.to_string()))?;
+    Ok(CommandResponse::ok(format!("{} plugin(s) loaded", versions.len())).with_data(data))
+}
+
+fn reload_plugin(name: &str) -> Result<CommandResponse, NylonError> {
+    let plugin = nylon_store::get::<ProxyConfig>(nylon_store::KEY_PROXY_CONFIG)
+        .and_then(|config| config.plugins)
+        .and_then(|plugins| plugins.into_iter().find(|p| p.name == name))
+        .ok_or_else(|| NylonError::ConfigError(format!("Plugin {} is not configured", name)))?;
+    let version = nylon_plugin::loaders::reload(&plugin)?;
+    let data = serde_json::to_value(&version)
+        .map_err(|e| NylonError::InternalServerError(e.to_string()))?;
+    Ok(CommandResponse::ok(format!(
+        "Plugin {} reloaded, now at version {}",
+        name, version.version
+    ))
+    .with_data(data))
+}
+
+/// Get the ACME config of a domain
+fn acme_config_for(domain: &str) -> Result<AcmeConfig, NylonError> {
+    nylon_store::get::<HashMap<String, AcmeConfig>>(nylon_store::KEY_ACME_CONFIG)
+        .and_then(|configs| configs.get(domain).cloned())
+        .ok_or_else(|| {
+            NylonError::ConfigError(format!("Domain {} is not configured for ACME", domain))
+        })
+}
+
+fn certificate_json(cert_info: &CertificateInfo, source: &str) -> Value {
+    json!({
+        "domain": cert_info.domain,
+        "source": source,
+        "issued_at": cert_info.issued_at.to_rfc3339(),
+        "expires_at": cert_info.expires_at.to_rfc3339(),
+        "days_until_expiry": cert_info.days_until_expiry(),
+        "needs_renewal": cert_info.needs_renewal(),
+    })
+}
+
+fn list_certificates() -> Result<CommandResponse, NylonError> {
+    let acme_configs =
+        nylon_store::get::<HashMap<String, AcmeConfig>>(nylon_store::KEY_ACME_CONFIG)
+            .unwrap_or_default();
+
+    let mut certs = nylon_store::tls::get_all_certificates();
+    certs.sort_by(|a, b| a.domain.cmp(&b.domain));
+    let mut data: Vec<Value> = certs
+        .iter()
+        .map(|c| {
+            let source = if acme_configs.contains_key(&c.domain) {
+                "acme"
+            } else {
+                "imported"
+            };
+            certificate_json(c, source)
+        })
+        .collect();
+
+    // Certificates configured with `kind: custom`
+    let custom =
+        nylon_store::get::<HashMap<String, TlsStore>>(nylon_store::KEY_TLS).unwrap_or_default();
+    let mut custom_domains: Vec<&String> = custom.keys().collect();
+    custom_domains.sort();
+    for domain in custom_domains {
+        let store = &custom[domain];
+        match CertificateInfo::new(
+            domain.clone(),
+            store.cert.clone(),
+            store.key.clone(),
+            store.chain.clone(),
+        ) {
+            Ok(cert_info) => data.push(certificate_json(&cert_info, "custom")),
+            Err(e) => data.push(json!({
+                "domain": domain,
+                "source": "custom",
+                "error": e.to_string(),
+            })),
+        }
+    }
+
+    Ok(
+        CommandResponse::ok(format!("{} certificate(s) loaded", data.len()))
+            .with_data(Value::Array(data)),
+    )
+}
+
+async fn revoke_certificate(domain: &str) -> Result<CommandResponse, NylonError> {
+    check_domain(domain)?;
+    let acme_config = acme_config_for(domain)?;
+    let cert_info = nylon_store::tls::get_all_certificates()
+        .into_iter()
+        .find(|c| c.domain == domain)
+        .ok_or_else(|| NylonError::ConfigError(format!("No certificate loaded for {}", domain)))?;
+
+    let mut client = AcmeClient::new(&acme_config).await?;
+    client.revoke_certificate(&cert_info.cert).await?;
+
+    nylon_store::tls::remove_acme_cert(domain);
+    let acme_dir = acme_config.acme_dir.as_deref().unwrap_or(".acme");
+    AcmeClient::remove_certificate(acme_dir, domain)?;
+
+    info!("Certificate revoked for {}", domain);
+    Ok(CommandResponse::ok(format!(
+        "Certificate revoked for {}; run `nylon cert renew {}` to issue a new one",
+        domain, domain
+    )))
+}
+
+fn import_certificate(
+    domain: String,
+    cert: String,
+    key: String,
+    chain: Vec<String>,
+) -> Result<CommandResponse, NylonError> {
+    check_domain(&domain)?;
+    // Make sure the key actually belongs to the certificate before serving it
+    let x509 = X509::from_pem(cert.as_bytes())
+        .map_err(|e| NylonError::ConfigError(format!("Invalid certificate: {}", e)))?;
+    if !names_domain(&x509, &domain) {
+        return Err(NylonError::ConfigError(format!(
+            "Certificate does not cover {}",
+            domain
+        )));
+    }
+    let pkey = PKey::private_key_from_pem(key.as_bytes())
+        .map_err(|e| NylonError::ConfigError(format!("Invalid private key: {}", e)))?;
+    let matches = x509
+        .public_key()
+        .map(|public| public.public_eq(&pkey))
+        .unwrap_or(false);
+    if !matches {
+        return Err(NylonError::ConfigError(
+            "Private key does not match the certificate".to_string(),
+        ));
+    }
+
+    let chain: Vec<Vec<u8>> = chain.into_iter().map(String::into_bytes).collect();
+    let cert_info =
+        CertificateInfo::new(domain.clone(), cert.into_bytes(), key.into_bytes(), chain)?;
+
+    let acme_dir = match acme_config_for(&domain) {
+        Ok(config) => config.acme_dir.unwrap_or_else(|| ".acme".to_string()),
+        Err(_) => RuntimeConfig::get()?.acme.to_string_lossy().to_string(),
+    };
+    AcmeClient::import_certificate(
+        &acme_dir,
+        &domain,
+        &cert_info.cert,
+        &cert_info.chain,
+        &cert_info.key,
+    )?;
+
+    if let Some(metrics) = nylon_store::get::<nylon_tls::AcmeMetrics>(nylon_store::KEY_ACME_METRICS)
+    {
+        metrics.update_days_until_expiry(&domain, cert_info.days_until_expiry());
+    }
+
+    let expires_at = cert_info.expires_at;
+    nylon_store::tls::store_acme_cert(cert_info)?;
+
+    Ok(CommandResponse::ok(format!(
+        "Certificate imported for {}, expires at {}",
+        domain, expires_at
+    )))
+}
+
+/// Refuse a domain that could name a path outside the certificate directory
+fn check_domain(domain: &str) -> Result<(), NylonError> {
+    if domain.is_empty() || domain.contains(['/', '\\', '\0']) || domain.contains("..") {
+        return Err(NylonError::ConfigError(format!(
+            "Invalid domain {:?}",
+            domain
+        )));
+    }
+    Ok(())
+}
+
+/// Whether a DNS name in the certificate's SANs is `domain`, or a wildcard over it
+fn names_domain(x509: &X509, domain: &str) -> bool {
+    let Some(names) = x509.subject_alt_names() else {
+        return false;
+    };
+    let domain = domain.to_ascii_lowercase();
+    names
+        .iter()
+        .filter_map(|name| name.dnsname())
+        .map(|name| name.to_ascii_lowercase())
+        .any(|name| {
+            name == domain
+                || name.strip_prefix("*.").is_some_and(|parent| {
+                    domain
+                        .split_once('.')
+                        .is_some_and(|(label, rest)| !label.is_empty() && rest == parent)
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nylon_types::{route::RouteConfig, services::ServiceItem};
+
+    #[test]
+    fn test_route_strips_ports_from_ipv6_hosts() {
+        let service: ServiceItem = serde_json::from_value(json!({
+            "name": "api",
+            "service_type": "http",
+            "endpoints": [{"ip": "127.0.0.1", "port": 8080}],
+        }))
+        .unwrap();
+        let route: RouteConfig = serde_json::from_value(json!({
+            "name": "local",
+            "route": {"type": "host", "value": "[::1]|example.com"},
+            "paths": [{"path": "/", "service": {"name": "api"}}],
+        }))
+        .unwrap();
+        nylon_store::routes::store(vec![&route], &vec![&service], &None).unwrap();
+
+        for host in ["[::1]", "[::1]:8080", "example.com", "example.com:8443"] {
+            let response = test_route(host, None, "GET", "/").unwrap();
+            assert_eq!(response.data.unwrap()["route"], "local", "{}", host);
+        }
+        assert!(test_route("[::2]:8080", None, "GET", "/").is_err());
+        assert!(test_route("[", None, "GET", "/").is_err());
+    }
+
+    /// A self-signed certificate for `names`, and its key
+    fn certificate(names: &[&str]) -> (X509, PKey<openssl::pkey::Private>) {
+        use openssl::{
+            asn1::Asn1Time, bn::BigNum, ec::EcGroup, ec::EcKey, hash::MessageDigest, nid::Nid,
+            x509::extension::SubjectAlternativeName,
+        };
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        if !names.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for name in names {
+                san.dns(name);
+            }
+            let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn test_check_domain() {
+        assert!(check_domain("example.com").is_ok());
+        assert!(check_domain("*.example.com").is_ok());
+        for domain in [
+            "",
+            "../../etc/x",
+            "a/b",
+            "a\\b",
+            "..",
+            "example..com",
+            "a\0b",
+        ] {
+            assert!(check_domain(domain).is_err(), "{:?}", domain);
+        }
+    }
+
+    #[test]
+    fn test_names_domain() {
+        let (cert, _) = certificate(&["example.com", "*.apps.example.com"]);
+        assert!(names_domain(&cert, "example.com"));
+        assert!(names_domain(&cert, "EXAMPLE.com"));
+        assert!(names_domain(&cert, "api.apps.example.com"));
+        assert!(names_domain(&cert, "*.apps.example.com"));
+        assert!(!names_domain(&cert, "apps.example.com"));
+        assert!(!names_domain(&cert, "a.b.apps.example.com"));
+        assert!(!names_domain(&cert, "other.com"));
+        let (cert, _) = certificate(&[]);
+        assert!(!names_domain(&cert, "example.com"));
+    }
+
+    #[test]
+    fn test_import_checks_the_domain() {
+        let (cert, key) = certificate(&["example.com"]);
+        let cert = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        let key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let err = import_certificate("../../etc/x".to_string(), cert.clone(), key.clone(), vec![])
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid domain"), "{}", err);
+        let err = import_certificate("other.com".to_string(), cert, key, vec![]).unwrap_err();
+        assert!(err.to_string().contains("does not cover"), "{}", err);
+    }
+}
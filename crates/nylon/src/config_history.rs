@@ -0,0 +1,163 @@
+//! Applied configurations, for `nylon config history` and `nylon config rollback`
+//!
+//! Every configuration that was applied successfully is kept in memory under
+//! a version number, up to `config_history` of them. A reload that fails while
+//! it is being applied puts the latest one back.
+
+use nylon_config::runtime::RuntimeConfig;
+use nylon_types::proxy::ProxyConfig;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+
+#[derive(Clone)]
+pub struct Snapshot {
+    pub version: u64,
+    pub applied_at: String,
+    /// `startup`, `reload` or `rollback to N`
+    pub source: String,
+    pub runtime: RuntimeConfig,
+    pub proxy: ProxyConfig,
+}
+
+/// What `nylon config history` shows of a snapshot
+#[derive(Debug, Serialize)]
+pub struct SnapshotSummary {
+    pub version: u64,
+    pub applied_at: String,
+    pub source: String,
+    pub routes: usize,
+    pub services: usize,
+    pub plugins: usize,
+    pub current: bool,
+}
+
+#[derive(Default)]
+struct History {
+    last_version: u64,
+    snapshots: VecDeque<Snapshot>,
+}
+
+static HISTORY: Lazy<Mutex<History>> = Lazy::new(Default::default);
+
+impl History {
+    fn record(&mut self, runtime: &RuntimeConfig, proxy: &ProxyConfig, source: String) -> u64 {
+        self.last_version += 1;
+        let version = self.last_version;
+        self.snapshots.push_back(Snapshot {
+            version,
+            applied_at: chrono::Utc::now().to_rfc3339(),
+            source,
+            runtime: runtime.clone(),
+            proxy: proxy.clone(),
+        });
+        while self.snapshots.len() > runtime.config_history.max(1) {
+            self.snapshots.pop_front();
+        }
+        version
+    }
+
+    fn get(&self, version: u64) -> Option<Snapshot> {
+        self.snapshots
+            .iter()
+            .find(|s| s.version == version)
+            .cloned()
+    }
+
+    fn summaries(&self) -> Vec<SnapshotSummary> {
+        self.snapshots
+            .iter()
+            .rev()
+            .map(|s| SnapshotSummary {
+                version: s.version,
+                applied_at: s.applied_at.clone(),
+                source: s.source.clone(),
+                routes: s.proxy.routes.iter().flatten().count(),
+                services: s.proxy.services.iter().flatten().count(),
+                plugins: s.proxy.plugins.iter().flatten().count(),
+                current: s.version == self.last_version,
+            })
+            .collect()
+    }
+}
+
+/// Remember a configuration that was just applied and return its version
+pub fn record(runtime: &RuntimeConfig, proxy: &ProxyConfig, source: impl Into<String>) -> u64 {
+    match HISTORY.lock() {
+        Ok(mut history) => history.record(runtime, proxy, source.into()),
+        Err(_) => 0,
+    }
+}
+
+/// The configuration that is applied now
+pub fn current() -> Option<Snapshot> {
+    HISTORY.lock().ok()?.snapshots.back().cloned()
+}
+
+pub fn get(version: u64) -> Option<Snapshot> {
+    HISTORY.lock().ok()?.get(version)
+}
+
+/// Newest first
+pub fn summaries() -> Vec<SnapshotSummary> {
+    match HISTORY.lock() {
+        Ok(history) => history.summaries(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime(config_history: usize) -> RuntimeConfig {
+        RuntimeConfig {
+            config_history,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_versions_count_up() {
+        let mut history = History::default();
+        let runtime = runtime(10);
+        let proxy = ProxyConfig::default();
+        assert_eq!(history.record(&runtime, &proxy, "startup".into()), 1);
+        assert_eq!(history.record(&runtime, &proxy, "reload".into()), 2);
+        assert_eq!(history.get(1).unwrap().source, "startup");
+        assert_eq!(history.get(2).unwrap().source, "reload");
+        assert!(history.get(3).is_none());
+    }
+
+    #[test]
+    fn test_oldest_snapshots_are_trimmed() {
+        let mut history = History::default();
+        let proxy = ProxyConfig::default();
+        for _ in 0..5 {
+            history.record(&runtime(3), &proxy, "reload".into());
+        }
+        let versions: Vec<u64> = history.summaries().iter().map(|s| s.version).collect();
+        assert_eq!(versions, vec![5, 4, 3]);
+        assert!(history.get(2).is_none());
+
+        // at least the applied configuration is kept
+        history.record(&runtime(0), &proxy, "reload".into());
+        assert_eq!(history.snapshots.len(), 1);
+        assert_eq!(history.snapshots[0].version, 6);
+    }
+
+    #[test]
+    fn test_only_the_newest_is_current() {
+        let mut history = History::default();
+        let runtime = runtime(10);
+        let proxy = ProxyConfig::default();
+        history.record(&runtime, &proxy, "startup".into());
+        history.record(&runtime, &proxy, "reload".into());
+        history.record(&runtime, &proxy, "rollback to 1".into());
+        let summaries = history.summaries();
+        assert_eq!(summaries[0].version, 3);
+        assert_eq!(summaries[0].source, "rollback to 1");
+        assert!(summaries[0].current);
+        assert!(summaries[1..].iter().all(|s| !s.current));
+    }
+}
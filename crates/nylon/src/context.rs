@@ -1,33 +1,21 @@
 use async_trait::async_trait;
 use nylon_error::NylonError;
 use nylon_types::context::NylonContext;
-use pingora::proxy::Session;
+use openssl::ssl::NameType;
+use pingora::{protocols::Ssl, proxy::Session};
 use std::sync::atomic::Ordering;
 
 #[async_trait]
 pub trait NylonContextExt {
-    async fn parse_request(&self, session: &mut Session) -> Result<(), NylonError>;
+    async fn parse_request(&mut self, session: &mut Session) -> Result<(), NylonError>;
 }
 
 #[async_trait]
 impl NylonContextExt for NylonContext {
-    async fn parse_request(&self, session: &mut Session) -> Result<(), NylonError> {
-        {
-            let mut client_ip = self
-                .client_ip
-                .write()
-                .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-            *client_ip = match session.client_addr() {
-                Some(ip) => match ip.as_inet() {
-                    Some(ip) => ip.ip().to_string(),
-                    None => {
-                        return Err(NylonError::HttpException(
-                            400,
-                            "BAD_REQUEST",
-                            "Unable to get client IP",
-                        ));
-                    }
-                },
+    async fn parse_request(&mut self, session: &mut Session) -> Result<(), NylonError> {
+        self.client_ip = match session.client_addr() {
+            Some(ip) => match ip.as_inet() {
+                Some(ip) => ip.ip().to_string(),
                 None => {
                     return Err(NylonError::HttpException(
                         400,
@@ -35,35 +23,37 @@ impl NylonContextExt for NylonContext {
                         "Unable to get client IP",
                     ));
                 }
-            };
-        }
-        let is_tls = match session.digest() {
-            Some(d) => d.ssl_digest.is_some(),
-            None => false,
+            },
+            None => {
+                return Err(NylonError::HttpException(
+                    400,
+                    "BAD_REQUEST",
+                    "Unable to get client IP",
+                ));
+            }
         };
-        self.tls.store(is_tls, Ordering::Relaxed);
+        let tls_version = session
+            .digest()
+            .and_then(|d| d.ssl_digest.as_ref())
+            .map(|ssl| ssl.version);
+        self.tls.store(tls_version.is_some(), Ordering::Relaxed);
+        self.tls_version = tls_version.unwrap_or_default().to_string();
+        // pingora only exposes the TLS session of HTTP/1 connections
+        self.sni = session
+            .stream()
+            .and_then(|stream| stream.get_ssl())
+            .and_then(|ssl| ssl.servername(NameType::HOST_NAME))
+            .unwrap_or_default()
+            .to_string();
         // reset per-request caches
-        {
-            if let Ok(mut q) = self.cached_query.write() {
-                *q = None;
-            }
-            if let Ok(mut c) = self.cached_cookies.write() {
-                *c = None;
-            }
-        }
+        self.cached_query.take();
+        self.cached_cookies.take();
+        self.device_class.take();
         match session.as_http2() {
             Some(session) => {
                 let host = session.req_header().uri.host().unwrap_or("");
-                let mut h = self
-                    .host
-                    .write()
-                    .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-                *h = host.to_string();
-                let mut p = self
-                    .port
-                    .write()
-                    .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-                *p = "".to_string();
+                self.host = host.to_string();
+                self.port = "".to_string();
             }
             None => {
                 let host = match session.req_header().headers.get("Host") {
@@ -71,20 +61,12 @@ impl NylonContextExt for NylonContext {
                         // h.to_str().unwrap_or("").split(':').next().unwrap_or("")
                         let host = h.to_str().unwrap_or("").split(':').next().unwrap_or("");
                         let port = h.to_str().unwrap_or("").split(':').nth(1).unwrap_or("");
-                        let mut p = self
-                            .port
-                            .write()
-                            .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-                        *p = port.to_string();
+                        self.port = port.to_string();
                         host
                     }
                     None => "",
                 };
-                let mut h = self
-                    .host
-                    .write()
-                    .map_err(|_| NylonError::InternalServerError("lock poisoned".into()))?;
-                *h = host.to_string();
+                self.host = host.to_string();
             }
         }
         Ok(())
@@ -7,7 +7,7 @@ use openssl::{
     x509::X509,
 };
 use pingora::{
-    listeners::{TlsAccept, tls::TlsSettings},
+    listeners::{ALPN, TlsAccept, tls::TlsSettings},
     tls::ext,
 };
 use tracing::error;
@@ -21,10 +21,15 @@ impl DynamicCertificate {
     }
 }
 
-pub fn new_tls_settings() -> Result<TlsSettings, NylonError> {
+/// TLS settings of an https listener; `h2` offers HTTP/2 over ALPN
+pub fn new_tls_settings(h2: bool) -> Result<TlsSettings, NylonError> {
     let mut tls = TlsSettings::with_callbacks(Box::new(DynamicCertificate::new()))
         .map_err(|e| NylonError::PingoraError(e.to_string()))?;
-    tls.enable_h2();
+    if h2 {
+        tls.enable_h2();
+    } else {
+        tls.set_alpn(ALPN::H1);
+    }
     Ok(tls)
 }
 
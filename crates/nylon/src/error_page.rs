@@ -0,0 +1,322 @@
+//! Error responses: pages of the matched route (`error_pages`) and the default body
+//!
+//! Pages are read and parsed when the config loads. A page is looked up by
+//! exact status, then by class (`5xx`), then `default`. Without one, the error is sent as JSON, or as HTML or plain
+//! text to clients that do not accept JSON, with the detail `error_detail`
+//! allows.
+
+use bytes::Bytes;
+use nylon_config::runtime::ErrorDetail;
+use nylon_types::{
+    context::{ErrorPages, LoadedErrorPage, NylonContext},
+    template::{Expr, eval_expr},
+};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+use serde_json::json;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+static DETAIL: AtomicU8 = AtomicU8::new(0);
+
+/// Set how much of an error clients see
+pub fn set_detail(detail: ErrorDetail) {
+    let value = match detail {
+        ErrorDetail::Full => 0,
+        ErrorDetail::CodeOnly => 1,
+        ErrorDetail::Redacted => 2,
+    };
+    DETAIL.store(value, Ordering::Relaxed);
+}
+
+/// Error code and message as shown to clients; `None` when hidden
+pub fn public(status: u16, code: String, message: String) -> (Option<String>, Option<String>) {
+    match DETAIL.load(Ordering::Relaxed) {
+        0 => (Some(code), Some(message)),
+        1 => (Some(code), None),
+        _ => (None, Some(reason(status).to_string())),
+    }
+}
+
+/// Reason phrase of a status, `Error` when it has none
+pub fn reason(status: u16) -> &'static str {
+    http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("Error")
+}
+
+/// Content type and body of an error without a page, in a format the client accepts
+pub fn default_body(
+    headers: &RequestHeader,
+    status: u16,
+    code: Option<&str>,
+    message: Option<&str>,
+) -> (String, Bytes) {
+    let accept = headers
+        .headers
+        .get("accept")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let accepts_json = accept.is_empty()
+        || accept.contains("json")
+        || accept.contains("*/*")
+        || accept.contains("application/*");
+
+    if accepts_json {
+        let mut body = json!({ "status": status });
+        if let Some(code) = code {
+            body["error"] = code.into();
+        }
+        if let Some(message) = message {
+            body["message"] = message.into();
+        }
+        return (
+            "application/json".to_string(),
+            Bytes::from(body.to_string()),
+        );
+    }
+
+    let title = format!("{} {}", status, reason(status));
+    let detail: Vec<&str> = [code, message].into_iter().flatten().collect();
+    if accept.contains("text/html") {
+        let detail = detail
+            .iter()
+            .map(|d| format!("<p>{}</p>", escape_html(d)))
+            .collect::<String>();
+        let body = format!(
+            "<!DOCTYPE html><html><head><title>{title}</title></head><body><h1>{title}</h1>{detail}</body></html>"
+        );
+        ("text/html; charset=utf-8".to_string(), Bytes::from(body))
+    } else {
+        let mut body = title;
+        for line in detail {
+            body.push('\n');
+            body.push_str(line);
+        }
+        body.push('\n');
+        ("text/plain; charset=utf-8".to_string(), Bytes::from(body))
+    }
+}
+
+/// Text safe to put in HTML content and attribute values
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Text safe to put inside a JSON string
+fn escape_json(text: &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or_default();
+    quoted
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Content type and body of the route's page for `status`, if it has one
+///
+/// Requests that matched no path get the pages of the route their host
+/// picks. Values a template renders are escaped for its content type.
+pub fn render(
+    headers: &RequestHeader,
+    ctx: &NylonContext,
+    status: u16,
+    code: &str,
+    message: &str,
+) -> Option<(String, Bytes)> {
+    let pages = match &ctx.route {
+        Some(route) => route.error_pages.clone()?,
+        None => nylon_store::routes::find_error_pages(headers)?,
+    };
+    let (html, json) = match find(&pages, status)? {
+        LoadedErrorPage::File { content_type, body } => {
+            return Some((content_type.clone(), body.clone()));
+        }
+        LoadedErrorPage::Template { html, json } => (html, json),
+    };
+
+    let wants_html = headers
+        .headers
+        .get("accept")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let (exprs, content_type, escape): (_, _, fn(&str) -> String) = match (html, json) {
+        (Some(html), _) if wants_html || json.is_none() => {
+            (html, "text/html; charset=utf-8", escape_html)
+        }
+        (_, Some(json)) => (json, "application/json", escape_json),
+        _ => return None,
+    };
+    let error = |field: &str| match field {
+        "status" => status.to_string(),
+        "code" => code.to_string(),
+        "message" => message.to_string(),
+        _ => String::new(),
+    };
+    let body = render_template(exprs, escape, headers, ctx, error);
+    Some((content_type.to_string(), Bytes::from(body)))
+}
+
+/// Render a page template, passing every value it computes through `escape`
+fn render_template(
+    exprs: &[Expr],
+    escape: fn(&str) -> String,
+    headers: &RequestHeader,
+    ctx: &NylonContext,
+    error: impl Fn(&str) -> String,
+) -> String {
+    let mut body = String::new();
+    for expr in exprs {
+        match expr {
+            Expr::Literal(text) => body.push_str(text),
+            Expr::Func { name, args } if name == "error" => {
+                if let Some(Expr::Request(field)) = args.first() {
+                    body.push_str(&escape(&error(field)));
+                }
+            }
+            _ => body.push_str(&escape(&eval_expr(expr, headers, ctx))),
+        }
+    }
+    body
+}
+
+fn find(pages: &ErrorPages, status: u16) -> Option<&LoadedErrorPage> {
+    pages
+        .get(&status.to_string())
+        .or_else(|| pages.get(&format!("{}xx", status / 100)))
+        .or_else(|| pages.get("default"))
+}
+
+/// Write an error page straight to the client, for errors outside of request_filter
+pub async fn send(
+    session: &mut Session,
+    status: u16,
+    content_type: String,
+    body: Bytes,
+) -> pingora::Result<()> {
+    let mut header = ResponseHeader::build(status, Some(2))?;
+    header.insert_header("Content-Type", content_type)?;
+    header.insert_header("Content-Length", body.len().to_string())?;
+    session.set_keepalive(None);
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session.write_response_body(Some(body), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nylon_types::template::extract_and_parse_templates;
+
+    fn render_with(template: &str, escape: fn(&str) -> String, query: &str) -> String {
+        let exprs = extract_and_parse_templates(template).unwrap();
+        let headers = RequestHeader::build("GET", format!("/?{}", query).as_bytes(), None).unwrap();
+        let error = |field: &str| match field {
+            "message" => "<b>\"bad\"</b>".to_string(),
+            _ => String::new(),
+        };
+        render_template(&exprs, escape, &headers, &NylonContext::default(), error)
+    }
+
+    fn accepting(accept: &str) -> RequestHeader {
+        let mut headers = RequestHeader::build("GET", b"/", None).unwrap();
+        if !accept.is_empty() {
+            headers.insert_header("accept", accept).unwrap();
+        }
+        headers
+    }
+
+    #[test]
+    fn test_public_follows_error_detail() {
+        let public_of = |detail| {
+            set_detail(detail);
+            public(502, "upstream_error".into(), "10.0.0.1:8080 refused".into())
+        };
+        assert_eq!(
+            public_of(ErrorDetail::Full),
+            (
+                Some("upstream_error".to_string()),
+                Some("10.0.0.1:8080 refused".to_string())
+            )
+        );
+        assert_eq!(
+            public_of(ErrorDetail::CodeOnly),
+            (Some("upstream_error".to_string()), None)
+        );
+        assert_eq!(
+            public_of(ErrorDetail::Redacted),
+            (None, Some("Bad Gateway".to_string()))
+        );
+        set_detail(ErrorDetail::Full);
+    }
+
+    #[test]
+    fn test_default_body_follows_accept() {
+        for accept in ["", "application/json", "*/*", "text/html, */*;q=0.8"] {
+            let (content_type, body) =
+                default_body(&accepting(accept), 404, Some("not_found"), Some("gone"));
+            assert_eq!(content_type, "application/json", "{}", accept);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                body,
+                json!({"status": 404, "error": "not_found", "message": "gone"})
+            );
+        }
+
+        let (content_type, body) = default_body(&accepting("text/html"), 503, None, Some("<x>"));
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("<h1>503 Service Unavailable</h1>"),
+            "{}",
+            body
+        );
+        assert!(body.contains("<p>&lt;x&gt;</p>"), "{}", body);
+
+        let (content_type, body) = default_body(&accepting("text/plain"), 500, Some("e"), None);
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "500 Internal Server Error\ne\n");
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x" title='y'>&</a>"#),
+            "&lt;a href=&quot;x&quot; title=&#39;y&#39;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain"), "plain");
+    }
+
+    #[test]
+    fn test_html_pages_escape_values() {
+        let body = render_with(
+            "<p>${error(message)}</p><a title='${query(q)}'>",
+            escape_html,
+            "q=x%27onmouseover%3D1",
+        );
+        assert_eq!(
+            body,
+            "<p>&lt;b&gt;&quot;bad&quot;&lt;/b&gt;</p><a title='x&#39;onmouseover=1'>"
+        );
+    }
+
+    #[test]
+    fn test_json_pages_escape_values() {
+        let body = render_with(
+            r#"{"message": "${error(message)}", "q": "${query(q)}"}"#,
+            escape_json,
+            "q=a%22%2C%22admin%22%3Atrue",
+        );
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["message"], "<b>\"bad\"</b>");
+        assert_eq!(value["q"], "a\",\"admin\":true");
+        assert!(value.get("admin").is_none());
+    }
+}
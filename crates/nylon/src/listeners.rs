@@ -0,0 +1,211 @@
+//! Listen sockets of the http and https services
+//!
+//! TCP keepalive and SO_REUSEPORT are options pingora sets itself. An
+//! interface from `bind_device` has to be set before the socket is bound, so
+//! those listeners are bound here and handed to pingora together with the
+//! sockets from systemd socket activation.
+//!
+//! There is no `backlog` or `proxy_protocol` option: pingora binds with a
+//! fixed backlog and has no hook to read a PROXY header before TLS or HTTP,
+//! so neither could be honored on every listener.
+
+use nylon_config::runtime::{Listener, ListenerOptions, RuntimeConfig};
+use nylon_error::NylonError;
+use pingora::{listeners::TcpSocketOptions, protocols::TcpKeepalive, server::Server};
+use std::{net::SocketAddr, time::Duration};
+
+/// Backlog of the sockets bound here, the one pingora uses for its own
+const LISTEN_BACKLOG: i32 = 65535;
+
+/// The listeners to bind
+///
+/// A wildcard address (`0.0.0.0` or `[::]`) covers the addresses of its
+/// family on the same port, which could not be bound next to it.
+pub fn to_bind(listeners: &[Listener]) -> Vec<ListenerOptions> {
+    let all: Vec<ListenerOptions> = listeners.iter().map(Listener::options).collect();
+    all.iter()
+        .filter(|listener| {
+            match all
+                .iter()
+                .find(|other| covers(&other.address, &listener.address))
+            {
+                Some(wildcard) => {
+                    tracing::warn!(
+                        "Listener {} is covered by {} and not bound",
+                        listener.address,
+                        wildcard.address
+                    );
+                    false
+                }
+                None => true,
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether binding `wildcard` takes the port `address` needs
+fn covers(wildcard: &str, address: &str) -> bool {
+    let (Ok(wildcard), Ok(address)) = (
+        wildcard.parse::<SocketAddr>(),
+        address.parse::<SocketAddr>(),
+    ) else {
+        return false;
+    };
+    wildcard.ip().is_unspecified()
+        && !address.ip().is_unspecified()
+        && wildcard.port() == address.port()
+        && wildcard.is_ipv4() == address.is_ipv4()
+}
+
+/// Options pingora applies to the listen socket and accepted connections
+pub fn socket_options(options: &ListenerOptions) -> Option<TcpSocketOptions> {
+    if options.tcp_keepalive.is_none() && !options.reuseport {
+        return None;
+    }
+    let mut sock_opt = TcpSocketOptions::default();
+    sock_opt.tcp_keepalive = options.tcp_keepalive.as_ref().map(|k| TcpKeepalive {
+        idle: Duration::from_secs(k.idle_secs),
+        interval: Duration::from_secs(k.interval_secs),
+        count: k.count,
+        #[cfg(target_os = "linux")]
+        user_timeout: Duration::ZERO,
+    });
+    sock_opt.so_reuseport = options.reuseport.then_some(true);
+    Some(sock_opt)
+}
+
+/// Bind the listeners that have a `bind_device`, except addresses in `taken`
+#[cfg(target_os = "linux")]
+pub fn prebind(
+    config: &RuntimeConfig,
+    taken: &[(String, i32)],
+) -> Result<Vec<(String, i32)>, NylonError> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::os::fd::IntoRawFd;
+
+    let mut sockets = vec![];
+    for options in to_bind(&config.http)
+        .into_iter()
+        .chain(to_bind(&config.https))
+    {
+        let Some(device) = &options.bind_device else {
+            continue;
+        };
+        if taken.iter().any(|(address, _)| *address == options.address) {
+            continue;
+        }
+        let err = |e: std::io::Error| {
+            NylonError::ConfigError(format!(
+                "Failed to bind {} on {}: {}",
+                options.address, device, e
+            ))
+        };
+        let addr: SocketAddr = options.address.parse().map_err(|e| {
+            NylonError::ConfigError(format!("Invalid listen address {}: {}", options.address, e))
+        })?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+            .map_err(err)?;
+        socket.set_reuse_address(true).map_err(err)?;
+        socket.set_reuse_port(options.reuseport).map_err(err)?;
+        socket.bind_device(Some(device.as_bytes())).map_err(err)?;
+        socket.bind(&addr.into()).map_err(err)?;
+        // pingora adopts the socket as it is, without listening again
+        socket.listen(LISTEN_BACKLOG).map_err(err)?;
+        // and hands it to tokio, which needs it non-blocking
+        socket.set_nonblocking(true).map_err(err)?;
+        sockets.push((options.address.clone(), socket.into_raw_fd()));
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn prebind(
+    config: &RuntimeConfig,
+    _taken: &[(String, i32)],
+) -> Result<Vec<(String, i32)>, NylonError> {
+    let with_device = config
+        .http
+        .iter()
+        .chain(&config.https)
+        .find(|l| l.options().bind_device.is_some());
+    match with_device {
+        Some(listener) => Err(NylonError::ConfigError(format!(
+            "{}: bind_device is only supported on Linux",
+            listener.address()
+        ))),
+        None => Ok(vec![]),
+    }
+}
+
+/// Give sockets bound outside of pingora to the server
+///
+/// pingora only adopts listen sockets while it bootstraps from a graceful
+/// upgrade, so they are sent over the upgrade socket the way an old process
+/// would send them. The server must have been created with `upgrade` set.
+#[cfg(target_os = "linux")]
+pub fn hand_over(server: &mut Server, sockets: Vec<(String, i32)>) {
+    let mut fds = pingora::server::Fds::new();
+    for (addr, fd) in sockets {
+        fds.add(addr, fd);
+    }
+    let path = server.configuration.upgrade_sock.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = fds.send_to_sock(path.as_str()) {
+            tracing::error!("Failed to pass listen sockets to the server: {}", e);
+        }
+    });
+    server.bootstrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn hand_over(_server: &mut Server, _sockets: Vec<(String, i32)>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(listeners: &[&str]) -> Vec<String> {
+        let listeners: Vec<Listener> = listeners
+            .iter()
+            .map(|a| Listener::Address(a.to_string()))
+            .collect();
+        to_bind(&listeners).into_iter().map(|l| l.address).collect()
+    }
+
+    #[test]
+    fn test_wildcards_cover_the_same_port() {
+        assert_eq!(
+            addresses(&["0.0.0.0:80", "10.0.0.5:80", "127.0.0.1:80"]),
+            vec!["0.0.0.0:80"]
+        );
+        assert_eq!(addresses(&["[::]:443", "[::1]:443"]), vec!["[::]:443"]);
+    }
+
+    #[test]
+    fn test_other_ports_and_families_are_kept() {
+        assert_eq!(
+            addresses(&["0.0.0.0:8088", "10.0.0.5:8080"]),
+            vec!["0.0.0.0:8088", "10.0.0.5:8080"]
+        );
+        assert_eq!(
+            addresses(&["0.0.0.0:80", "[::1]:80", "[::]:81", "10.0.0.5:81"]),
+            vec!["0.0.0.0:80", "[::1]:80", "[::]:81", "10.0.0.5:81"]
+        );
+    }
+
+    #[test]
+    fn test_the_documented_h2c_listener_is_bound() {
+        let listeners = vec![
+            Listener::Address("0.0.0.0:8088".to_string()),
+            Listener::Options(ListenerOptions {
+                address: "10.0.0.5:8080".to_string(),
+                h2: Some(true),
+                ..Default::default()
+            }),
+        ];
+        let bound = to_bind(&listeners);
+        assert_eq!(bound.len(), 2);
+        assert_eq!(bound[1].h2, Some(true));
+    }
+}
@@ -0,0 +1,234 @@
+//! Load shedding (`max_in_flight` and `load_shedding`)
+//!
+//! Requests in flight are counted on this node and per route; once a limit
+//! is reached new requests get `503` with `Retry-After`. WebSocket upgrades
+//! are not counted, since they stay open; `websocket_max_connections` limits
+//! them. With `load_shedding`, CPU and memory use are sampled by a background
+//! service and a share of requests is shed that grows from none at the
+//! watermark to all at full use. Usage is read from `/proc` and only sampled
+//! on Linux.
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use nylon_config::runtime::{LoadSheddingConfig, RuntimeConfig};
+use nylon_error::NylonError;
+use nylon_types::context::{NylonContext, Route};
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use std::{
+    sync::{
+        RwLock,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+/// Limit on this node; `usize::MAX` when unlimited
+static MAX_IN_FLIGHT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Requests in flight, by route with a limit
+static ROUTES: Lazy<DashMap<String, usize>> = Lazy::new(DashMap::new);
+
+static SHEDDING: Lazy<RwLock<Option<LoadSheddingConfig>>> = Lazy::new(Default::default);
+
+/// Share of requests to shed, in thousandths
+static SHED_PERMILLE: AtomicU32 = AtomicU32::new(0);
+
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Take the limits; the sampler picks up `load_shedding` on its next tick
+pub fn configure(config: &RuntimeConfig) {
+    MAX_IN_FLIGHT.store(
+        config.max_in_flight.unwrap_or(usize::MAX),
+        Ordering::Relaxed,
+    );
+    if let Ok(mut shedding) = SHEDDING.write() {
+        *shedding = config.load_shedding.clone();
+    }
+    if config.load_shedding.is_none() {
+        SHED_PERMILLE.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Count the request against the limits of the node and its route
+///
+/// An `upgrade` may be shed but is not counted.
+pub fn acquire(ctx: &mut NylonContext, route: &Route, upgrade: bool) -> Result<(), NylonError> {
+    let shed = SHED_PERMILLE.load(Ordering::Relaxed);
+    if shed > 0 && fastrand::u32(0..1000) < shed {
+        return Err(overloaded(ctx, "Shedding load"));
+    }
+    if upgrade {
+        return Ok(());
+    }
+    if IN_FLIGHT.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT.load(Ordering::Relaxed) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        return Err(overloaded(ctx, "Too many requests in flight"));
+    }
+    if let Some(max) = route.max_in_flight {
+        let mut in_flight = ROUTES.entry(route.name.clone()).or_insert(0);
+        if *in_flight >= max {
+            drop(in_flight);
+            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+            return Err(overloaded(ctx, "Too many requests in flight for the route"));
+        }
+        *in_flight += 1;
+    }
+    ctx.in_flight.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop counting the request
+pub fn release(ctx: &NylonContext) {
+    if !ctx.in_flight.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    if let Some(route) = &ctx.route
+        && route.max_in_flight.is_some()
+    {
+        ROUTES.remove_if_mut(&route.name, |_, in_flight| {
+            *in_flight = in_flight.saturating_sub(1);
+            *in_flight == 0
+        });
+    }
+}
+
+fn overloaded(ctx: &mut NylonContext, message: &'static str) -> NylonError {
+    ctx.add_response_header
+        .insert("Retry-After".to_string(), RETRY_AFTER_SECS.to_string());
+    NylonError::HttpException(503, "OVERLOADED", message)
+}
+
+/// Samples usage and updates the share to shed while `load_shedding` is set
+pub struct LoadSamplerService;
+
+#[async_trait]
+impl BackgroundService for LoadSamplerService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_cpu = None;
+        loop {
+            let config = SHEDDING.read().ok().and_then(|c| c.clone());
+            let interval = config.as_ref().map_or(1000, |c| c.interval_ms.max(100));
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(Duration::from_millis(interval)) => {}
+            }
+            let Some(config) = config else {
+                SHED_PERMILLE.store(0, Ordering::Relaxed);
+                last_cpu = None;
+                continue;
+            };
+            let cpu = cpu_times();
+            let cpu_percent = match (last_cpu, cpu) {
+                (Some((idle, total)), Some((idle_now, total_now))) if total_now > total => Some(
+                    100.0
+                        - idle_now.saturating_sub(idle) as f64 * 100.0 / (total_now - total) as f64,
+                ),
+                _ => None,
+            };
+            last_cpu = cpu;
+            let share = over(cpu_percent, config.cpu_percent)
+                .max(over(memory_percent(), config.memory_percent));
+            let permille = (share * 1000.0) as u32;
+            if permille > 0 && SHED_PERMILLE.load(Ordering::Relaxed) == 0 {
+                warn!("Shedding {:.1}% of requests", permille as f64 / 10.0);
+            }
+            SHED_PERMILLE.store(permille, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How far usage is between the watermark and 100%, from 0 to 1
+fn over(usage: Option<f64>, watermark: Option<f64>) -> f64 {
+    let (Some(usage), Some(watermark)) = (usage, watermark) else {
+        return 0.0;
+    };
+    if usage <= watermark || watermark >= 100.0 {
+        return 0.0;
+    }
+    ((usage - watermark) / (100.0 - watermark)).min(1.0)
+}
+
+fn cpu_times() -> Option<(u64, u64)> {
+    parse_cpu_times(&std::fs::read_to_string("/proc/stat").ok()?)
+}
+
+/// Idle and total CPU time from `/proc/stat`
+fn parse_cpu_times(stat: &str) -> Option<(u64, u64)> {
+    let times = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .take(8)
+        .map(|t| t.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    // user nice system idle iowait irq softirq steal
+    let idle = times.get(3)? + times.get(4).copied().unwrap_or_default();
+    Some((idle, times.iter().sum()))
+}
+
+fn memory_percent() -> Option<f64> {
+    parse_memory_percent(&std::fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Memory in use from `/proc/meminfo`
+fn parse_memory_percent(meminfo: &str) -> Option<f64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))?
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse::<f64>()
+            .ok()
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    (total > 0.0).then(|| 100.0 - available * 100.0 / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_over() {
+        assert_eq!(over(None, Some(80.0)), 0.0);
+        assert_eq!(over(Some(90.0), None), 0.0);
+        assert_eq!(over(Some(70.0), Some(80.0)), 0.0);
+        assert_eq!(over(Some(80.0), Some(80.0)), 0.0);
+        assert!((over(Some(90.0), Some(80.0)) - 0.5).abs() < 1e-9);
+        assert_eq!(over(Some(100.0), Some(80.0)), 1.0);
+        assert_eq!(over(Some(120.0), Some(80.0)), 1.0);
+        assert_eq!(over(Some(100.0), Some(100.0)), 0.0);
+    }
+
+    #[test]
+    fn test_parse_cpu_times() {
+        let stat = "cpu  100 5 50 800 20 3 2 10 7 0\ncpu0 50 2 25 400 10 1 1 5 0 0\n";
+        // Idle is idle plus iowait; guest time is already part of user
+        assert_eq!(parse_cpu_times(stat), Some((820, 990)));
+        assert_eq!(parse_cpu_times("cpu  100 5 50 800\n"), Some((800, 955)));
+        assert_eq!(parse_cpu_times("cpu0 1 2 3 4\n"), None);
+        assert_eq!(parse_cpu_times("cpu  1 2 x 4\n"), None);
+        assert_eq!(parse_cpu_times("cpu  1 2\n"), None);
+        assert_eq!(parse_cpu_times(""), None);
+    }
+
+    #[test]
+    fn test_parse_memory_percent() {
+        let meminfo = "MemTotal:       16000000 kB\nMemFree:         1000000 kB\nMemAvailable:    4000000 kB\n";
+        assert_eq!(parse_memory_percent(meminfo), Some(75.0));
+        assert_eq!(parse_memory_percent("MemTotal: 1000 kB\n"), None);
+        assert_eq!(
+            parse_memory_percent("MemTotal: 0 kB\nMemAvailable: 0 kB\n"),
+            None
+        );
+    }
+}
@@ -3,15 +3,30 @@
 //! This is the main entry point for the Nylon proxy server application.
 //! It handles command-line argument parsing and initializes the server runtime.
 
+mod access_log;
 mod backend;
 mod background_service;
+mod command_socket;
+mod config_history;
 mod context;
 mod dynamic_certificate;
+mod error_page;
+mod listeners;
+mod load_shedding;
+mod metrics;
 mod proxy;
 mod response;
 mod runtime;
-
-use nylon_command::Commands;
+mod slow_clients;
+mod static_files;
+mod systemd;
+mod telemetry;
+mod upgrade;
+#[cfg(windows)]
+mod win_service;
+
+use nylon_command::socket::{CommandRequest, CommandResponse};
+use nylon_command::{Commands, ConfigCommands, ConfigKind, OutputFormat, ProxyCommands};
 use nylon_config::{proxy::ProxyConfigExt, runtime::RuntimeConfig};
 use nylon_error::NylonError;
 use nylon_types::proxy::ProxyConfig;
@@ -20,14 +35,20 @@ use tracing::{error, info, warn};
 
 /// Main entry point for the Nylon proxy server
 fn main() {
-    // Initialize logging with appropriate level
-    tracing_subscriber::fmt::init();
-
-    info!("Starting Nylon proxy server...");
-
     // Parse command line arguments
     let args = nylon_command::parse();
 
+    // Initialize logging, exporting traces when the run config enables it
+    let tracing_config = match &args.command {
+        Commands::Run { config, .. } => RuntimeConfig::from_file(config)
+            .ok()
+            .and_then(|c| c.tracing),
+        _ => None,
+    };
+    telemetry::init(tracing_config.as_ref());
+
+    info!("Starting Nylon proxy server...");
+
     // Handle different commands
     if let Err(e) = handle_commands(args.command) {
         error!("Application error: {}", e);
@@ -48,12 +69,187 @@ fn handle_commands(args: Commands) -> Result<(), NylonError> {
     match args {
         Commands::Service(service) => {
             info!("Service command received: {:?}", service);
-            nylon_command::handle_service_command(service)
+            nylon_command::handle_service_command(service, nylon_store::KEY_COMMAND_SOCKET_PATH)
                 .map_err(|e| NylonError::RuntimeError(format!("Service command failed: {}", e)))?;
             Ok(())
         }
-        Commands::Run { config } => handle_run_command(config),
+        Commands::Cert(cert) => {
+            nylon_command::handle_cert_command(cert, nylon_store::KEY_COMMAND_SOCKET_PATH)
+                .map_err(|e| NylonError::RuntimeError(format!("Cert command failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Plugin(plugin) => {
+            nylon_command::handle_plugin_command(
+                plugin,
+                nylon_store::KEY_COMMAND_SOCKET_PATH,
+                env!("CARGO_PKG_VERSION"),
+            )
+            .map_err(|e| NylonError::RuntimeError(format!("Plugin command failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Proxy(ProxyCommands::TestRoute {
+            host,
+            path,
+            method,
+            selector,
+            config: Some(config),
+            output,
+        }) => handle_test_route_command(config, host, path, method, selector, output),
+        Commands::Proxy(proxy) => {
+            nylon_command::handle_proxy_command(proxy, nylon_store::KEY_COMMAND_SOCKET_PATH)
+                .map_err(|e| NylonError::RuntimeError(format!("Proxy command failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Smoke { spec, base_url } => {
+            nylon_command::handle_smoke_command(&spec, base_url)
+                .map_err(|e| NylonError::RuntimeError(format!("Smoke test failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Bench {
+            duration,
+            connections,
+            scenario,
+            output,
+            baseline,
+            tolerance,
+        } => {
+            let options = nylon_command::BenchOptions {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                duration_secs: duration,
+                connections,
+                scenarios: scenario,
+                output,
+                baseline,
+                tolerance,
+            };
+            nylon_command::handle_bench_command(options, nylon_store::KEY_COMMAND_SOCKET_PATH)
+                .map_err(|e| NylonError::RuntimeError(format!("Benchmark failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Config(ConfigCommands::Schema { kind }) => handle_schema_command(kind),
+        Commands::Config(config) => {
+            nylon_command::handle_config_command(config, nylon_store::KEY_COMMAND_SOCKET_PATH)
+                .map_err(|e| NylonError::RuntimeError(format!("Config command failed: {}", e)))?;
+            Ok(())
+        }
+        Commands::Validate { config } => handle_validate_command(config),
+        #[cfg(windows)]
+        Commands::Run {
+            config,
+            service: true,
+        } => win_service::run(config),
+        Commands::Run {
+            config, upgrade, ..
+        } => handle_run_command(config, upgrade),
+    }
+}
+
+/// Handle the config schema command
+///
+/// # Arguments
+///
+/// * `kind` - Which config file to print the JSON Schema of
+///
+/// # Returns
+///
+/// * `Result<(), NylonError>` - The result of the operation
+fn handle_schema_command(kind: ConfigKind) -> Result<(), NylonError> {
+    let schema = match kind {
+        ConfigKind::Runtime => nylon_config::schema::runtime(),
+        ConfigKind::Proxy => nylon_config::schema::proxy(),
+    };
+    let json = serde_json::to_string_pretty(&schema)
+        .map_err(|e| NylonError::RuntimeError(format!("Failed to print schema: {}", e)))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Handle `proxy test-route` against a config file instead of the daemon
+///
+/// Only the routes are built, so plugins are not loaded.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file
+/// * `host`, `path`, `method`, `selector` - The request to match
+/// * `output` - Table or JSON
+///
+/// # Returns
+///
+/// * `Result<(), NylonError>` - Fails when no route matches
+fn handle_test_route_command(
+    config_path: String,
+    host: String,
+    path: String,
+    method: String,
+    selector: Option<String>,
+    output: OutputFormat,
+) -> Result<(), NylonError> {
+    let config = RuntimeConfig::from_file(&config_path)?;
+    config.store()?;
+    let config_dir = config.config_dir.to_string_lossy().to_string();
+    nylon_config::remote::fetch(&config_dir)?;
+    let proxy_config = ProxyConfig::from_dir(&config_dir)?;
+    nylon_store::routes::store(
+        proxy_config.routes.iter().flatten().collect(),
+        &proxy_config.services.iter().flatten().collect(),
+        &proxy_config.middleware_groups,
+    )?;
+
+    let response = command_socket::test_route(&host, selector.as_deref(), &method, &path)
+        .unwrap_or_else(|e| CommandResponse::error(e.to_string()));
+    let request = CommandRequest::ProxyTestRoute {
+        host,
+        path,
+        method,
+        selector,
+    };
+    nylon_command::print_proxy_response(&request, response, output)
+        .map_err(|e| NylonError::RuntimeError(e.to_string()))
+}
+
+/// Handle the validate command
+///
+/// Loads the runtime config and the proxy config directory and builds TLS,
+/// backends, routes and plugins like `run` does, then reports every error.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file
+///
+/// # Returns
+///
+/// * `Result<(), NylonError>` - Fails when any error was found
+fn handle_validate_command(config_path: String) -> Result<(), NylonError> {
+    let config = RuntimeConfig::from_file(&config_path)?;
+    config.store()?;
+    let config_dir = config.config_dir.to_string_lossy().to_string();
+    nylon_config::remote::fetch(&config_dir)?;
+    let (proxy_config, mut errors) = nylon_config::proxy::parse_dir(&config_dir)?;
+
+    let rt = tokio::runtime::Runtime::new()
+        .map_err(|e| NylonError::RuntimeError(format!("Failed to create Tokio runtime: {}", e)))?;
+    errors.extend(rt.block_on(proxy_config.check()));
+
+    if !errors.is_empty() {
+        for e in &errors {
+            eprintln!("error: {}", e);
+        }
+        return Err(NylonError::ConfigError(format!(
+            "{} error(s) in {} and {}",
+            errors.len(),
+            config_path,
+            config_dir
+        )));
     }
+    println!(
+        "{} is valid: {} routes, {} services, {} plugins",
+        config_path,
+        proxy_config.routes.iter().flatten().count(),
+        proxy_config.services.iter().flatten().count(),
+        proxy_config.plugins.iter().flatten().count()
+    );
+    Ok(())
 }
 
 /// Handle the run command
@@ -65,7 +261,7 @@ fn handle_commands(args: Commands) -> Result<(), NylonError> {
 /// # Returns
 ///
 /// * `Result<(), NylonError>` - The result of the operation
-fn handle_run_command(config_path: String) -> Result<(), NylonError> {
+fn handle_run_command(config_path: String, upgrade: bool) -> Result<(), NylonError> {
     info!("Loading configuration from: {}", config_path);
 
     // Store config path for reload functionality
@@ -74,13 +270,22 @@ fn handle_run_command(config_path: String) -> Result<(), NylonError> {
     // Load and validate runtime configuration
     let config = RuntimeConfig::from_file(&config_path)?;
     config.store()?;
+    access_log::init(config.access_log.as_ref())?;
+    access_log::set_thresholds(config.slow_request_ms, config.large_response_bytes);
+    error_page::set_detail(config.error_detail);
+    slow_clients::configure(&config);
+    load_shedding::configure(&config);
+    if let Some(geoip) = &config.geoip {
+        nylon_types::geoip::load(geoip.database.as_deref(), geoip.asn_database.as_deref())?;
+    }
 
     info!("Runtime configuration loaded successfully");
     tracing::debug!("Runtime config: {:#?}", RuntimeConfig::get()?);
 
     // Load proxy configuration
-    let proxy_config =
-        ProxyConfig::from_dir(config.config_dir.to_string_lossy().to_string().as_str())?;
+    let config_dir = config.config_dir.to_string_lossy().to_string();
+    nylon_config::remote::fetch(&config_dir)?;
+    let proxy_config = ProxyConfig::from_dir(&config_dir)?;
     tracing::debug!("Proxy config: {:#?}", proxy_config);
 
     // Create and run the server
@@ -89,6 +294,7 @@ fn handle_run_command(config_path: String) -> Result<(), NylonError> {
 
     rt.block_on(async {
         proxy_config.store().await?;
+        config_history::record(&config, &proxy_config, "startup");
 
         // Initialize WebSocket adapter
         let runtime_config = RuntimeConfig::get()?;
@@ -107,7 +313,7 @@ fn handle_run_command(config_path: String) -> Result<(), NylonError> {
     })?;
 
     info!("Starting Nylon runtime server...");
-    NylonRuntime::new_server()
+    NylonRuntime::new_server(upgrade)
         .map_err(|e| NylonError::RuntimeError(format!("Failed to create server: {}", e)))?
         .run_forever();
 }
@@ -0,0 +1,453 @@
+//! Prometheus Metrics
+//!
+//! Metrics are registered in the default prometheus registry and served by
+//! the pingora prometheus service on every configured `metrics` address.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+};
+use std::sync::atomic::Ordering;
+
+static REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_requests_total",
+        "Total number of requests handled per route",
+        &["route", "method", "status"]
+    )
+    .expect("register nylon_requests_total")
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nylon_request_duration_seconds",
+        "Request latency per route",
+        &["route"],
+        vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0
+        ]
+    )
+    .expect("register nylon_request_duration_seconds")
+});
+
+static SERVICE_REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_service_requests_total",
+        "Requests per route, service and backend by status class",
+        &["route", "service", "backend", "status_class"]
+    )
+    .expect("register nylon_service_requests_total")
+});
+
+static SERVICE_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nylon_service_request_duration_seconds",
+        "Request latency per route, service and backend",
+        &["route", "service", "backend"],
+        vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0
+        ]
+    )
+    .expect("register nylon_service_request_duration_seconds")
+});
+
+static REQUEST_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_request_bytes_total",
+        "Request body bytes received from clients",
+        &["route", "service", "backend"]
+    )
+    .expect("register nylon_request_bytes_total")
+});
+
+static RESPONSE_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_response_bytes_total",
+        "Response body bytes sent to clients",
+        &["route", "service", "backend"]
+    )
+    .expect("register nylon_response_bytes_total")
+});
+
+static UPSTREAM_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_upstream_healthy",
+        "Whether an upstream backend is healthy (1) or not (0)",
+        &["service", "backend"]
+    )
+    .expect("register nylon_upstream_healthy")
+});
+
+static CERT_EXPIRY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_tls_certificate_expiry_timestamp_seconds",
+        "Expiry time of ACME managed certificates as unix timestamp",
+        &["domain"]
+    )
+    .expect("register nylon_tls_certificate_expiry_timestamp_seconds")
+});
+
+static ACME_OPERATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_acme_operations_total",
+        "ACME operations by kind and result",
+        &["operation", "result"]
+    )
+    .expect("register nylon_acme_operations_total")
+});
+
+static ACME_DOMAIN_FAILURES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_acme_domain_consecutive_failures",
+        "Consecutive ACME failures per domain",
+        &["domain"]
+    )
+    .expect("register nylon_acme_domain_consecutive_failures")
+});
+
+static FALLBACK_ACTIVATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_fallback_activations_total",
+        "Requests served by a fallback service because the primary had no healthy backend",
+        &["route", "fallback"]
+    )
+    .expect("register nylon_fallback_activations_total")
+});
+
+/// Measurements of a finished request
+pub struct RequestSample<'a> {
+    pub route: &'a str,
+    pub service: &'a str,
+    /// Upstream address, `-` when the service has no backends (static, plugin, ...)
+    pub backend: &'a str,
+    pub method: &'a str,
+    pub status: u16,
+    pub duration_secs: f64,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+}
+
+/// `2xx`, `4xx`, ... (`0xx` when no response was written)
+fn status_class(status: u16) -> &'static str {
+    match status / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        5 => "5xx",
+        _ => "0xx",
+    }
+}
+
+static REPLAY_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_replay_rejections_total",
+        "Requests rejected by ReplayProtection by reason",
+        &["reason"]
+    )
+    .expect("register nylon_replay_rejections_total")
+});
+
+static PLUGIN_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_plugin_healthy",
+        "Whether a plugin takes new sessions (1) or is waiting for a restart (0)",
+        &["plugin"]
+    )
+    .expect("register nylon_plugin_healthy")
+});
+
+static PLUGIN_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_plugin_failures_total",
+        "Plugin sessions that failed",
+        &["plugin"]
+    )
+    .expect("register nylon_plugin_failures_total")
+});
+
+static PLUGIN_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_plugin_sessions_in_flight",
+        "Plugin sessions currently open",
+        &["plugin"]
+    )
+    .expect("register nylon_plugin_sessions_in_flight")
+});
+
+static PLUGIN_EVENT_QUEUE_FULL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_plugin_event_queue_full_total",
+        "Plugin events that found their session's queue full",
+        &["plugin"]
+    )
+    .expect("register nylon_plugin_event_queue_full_total")
+});
+
+static PLUGIN_SESSIONS_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_plugin_sessions_rejected_total",
+        "Requests turned away because a plugin had no free session",
+        &["plugin"]
+    )
+    .expect("register nylon_plugin_sessions_rejected_total")
+});
+
+static RETIRED_ROUTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "nylon_retired_route_snapshots",
+        "Routes from replaced configurations still used by in-flight requests"
+    )
+    .expect("register nylon_retired_route_snapshots")
+});
+
+static WS_QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "nylon_websocket_send_queue_depth",
+        "Messages waiting in WebSocket send queues"
+    )
+    .expect("register nylon_websocket_send_queue_depth")
+});
+
+static WS_QUEUE_MAX_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "nylon_websocket_send_queue_max_depth",
+        "Messages waiting in the fullest WebSocket send queue"
+    )
+    .expect("register nylon_websocket_send_queue_max_depth")
+});
+
+static WS_MESSAGES_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "nylon_websocket_messages_dropped_total",
+        "WebSocket messages dropped from full send queues"
+    )
+    .expect("register nylon_websocket_messages_dropped_total")
+});
+
+static WS_OVERFLOW_CLOSES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "nylon_websocket_queue_overflow_closes_total",
+        "WebSocket connections closed because their send queue was full"
+    )
+    .expect("register nylon_websocket_queue_overflow_closes_total")
+});
+
+static WS_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nylon_websocket_connections",
+        "Open WebSocket connections on this node",
+        &["route"]
+    )
+    .expect("register nylon_websocket_connections")
+});
+
+static WS_UPGRADES_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_websocket_upgrades_rejected_total",
+        "WebSocket upgrades turned away by a connection limit",
+        &["limit"]
+    )
+    .expect("register nylon_websocket_upgrades_rejected_total")
+});
+
+static ROUTE_CACHE_ENTRIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "nylon_route_cache_entries",
+        "Route lookups held in the route cache"
+    )
+    .expect("register nylon_route_cache_entries")
+});
+
+static ROUTE_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_route_cache_lookups_total",
+        "Route cache lookups by result (hit, miss)",
+        &["result"]
+    )
+    .expect("register nylon_route_cache_lookups_total")
+});
+
+static ROUTE_CACHE_EVICTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "nylon_route_cache_evictions_total",
+        "Route lookups evicted from the full route cache"
+    )
+    .expect("register nylon_route_cache_evictions_total")
+});
+
+static BUFFER_POOL_BUFFERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "nylon_buffer_pool_buffers",
+        "Byte buffers waiting in the pool for reuse"
+    )
+    .expect("register nylon_buffer_pool_buffers")
+});
+
+static BUFFER_POOL_TAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nylon_buffer_pool_takes_total",
+        "Byte buffers taken, reused (hit) or newly allocated (miss)",
+        &["result"]
+    )
+    .expect("register nylon_buffer_pool_takes_total")
+});
+
+static BUFFER_POOL_DISCARDED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "nylon_buffer_pool_discarded_total",
+        "Byte buffers freed because they were too large or the pool was full"
+    )
+    .expect("register nylon_buffer_pool_discarded_total")
+});
+
+/// Record a finished request
+pub fn record_request(sample: &RequestSample) {
+    REQUESTS_TOTAL
+        .with_label_values(&[sample.route, sample.method, &sample.status.to_string()])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[sample.route])
+        .observe(sample.duration_secs);
+
+    let labels = [sample.route, sample.service, sample.backend];
+    SERVICE_REQUESTS_TOTAL
+        .with_label_values(&[
+            sample.route,
+            sample.service,
+            sample.backend,
+            status_class(sample.status),
+        ])
+        .inc();
+    SERVICE_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(sample.duration_secs);
+    REQUEST_BYTES
+        .with_label_values(&labels)
+        .inc_by(sample.bytes_in as u64);
+    RESPONSE_BYTES
+        .with_label_values(&labels)
+        .inc_by(sample.bytes_out as u64);
+}
+
+/// Record a switch to the fallback service of a route
+pub fn record_fallback(route: &str, fallback: &str) {
+    FALLBACK_ACTIVATIONS
+        .with_label_values(&[route, fallback])
+        .inc();
+}
+
+/// Bring a counter up to a total kept elsewhere
+fn mirror(counter: &IntCounter, total: u64) {
+    let current = counter.get();
+    if total > current {
+        counter.inc_by(total - current);
+    }
+}
+
+/// Refresh gauges that mirror state kept elsewhere (ACME metrics, certificates, upstream health, replay rejections, plugin sessions, WebSocket queues, route cache, buffer pool)
+pub fn refresh() {
+    UPSTREAM_HEALTHY.reset();
+    for (service, backend, healthy) in nylon_store::lb_backends::backend_health() {
+        UPSTREAM_HEALTHY
+            .with_label_values(&[&service, &backend])
+            .set(healthy as i64);
+    }
+
+    for (reason, count) in nylon_store::nonces::rejections() {
+        mirror(&REPLAY_REJECTIONS.with_label_values(&[&reason]), count);
+    }
+
+    PLUGIN_HEALTHY.reset();
+    for (plugin, health) in nylon_plugin::loaders::health() {
+        PLUGIN_HEALTHY
+            .with_label_values(&[&plugin])
+            .set(health.healthy as i64);
+        mirror(
+            &PLUGIN_FAILURES.with_label_values(&[&plugin]),
+            health.failures,
+        );
+    }
+
+    for (plugin, open, rejected) in nylon_plugin::loaders::sessions_in_flight() {
+        PLUGIN_SESSIONS
+            .with_label_values(&[&plugin])
+            .set(open as i64);
+        mirror(
+            &PLUGIN_SESSIONS_REJECTED.with_label_values(&[&plugin]),
+            rejected,
+        );
+    }
+
+    for (plugin, full) in nylon_plugin::stream::queue_full_events() {
+        mirror(&PLUGIN_EVENT_QUEUE_FULL.with_label_values(&[&plugin]), full);
+    }
+
+    RETIRED_ROUTES.set(nylon_store::routes::retired_routes_in_use() as i64);
+
+    let route_cache = nylon_store::routes::get_route_cache_stats();
+    ROUTE_CACHE_ENTRIES.set(route_cache.entries as i64);
+    mirror(
+        &ROUTE_CACHE_LOOKUPS.with_label_values(&["hit"]),
+        route_cache.hits,
+    );
+    mirror(
+        &ROUTE_CACHE_LOOKUPS.with_label_values(&["miss"]),
+        route_cache.misses,
+    );
+    mirror(&ROUTE_CACHE_EVICTIONS, route_cache.evictions);
+
+    let pool = nylon_types::buffer_pool::stats();
+    BUFFER_POOL_BUFFERS.set(pool.pooled as i64);
+    mirror(&BUFFER_POOL_TAKES.with_label_values(&["hit"]), pool.hits);
+    mirror(&BUFFER_POOL_TAKES.with_label_values(&["miss"]), pool.misses);
+    mirror(&BUFFER_POOL_DISCARDED, pool.discarded);
+
+    let queues = nylon_store::websockets::send_queue_stats();
+    WS_QUEUE_DEPTH.set(queues.queued as i64);
+    WS_QUEUE_MAX_DEPTH.set(queues.max_depth as i64);
+    mirror(&WS_MESSAGES_DROPPED, queues.dropped);
+    mirror(&WS_OVERFLOW_CLOSES, queues.overflow_closes);
+
+    WS_CONNECTIONS.reset();
+    for (route, open) in nylon_store::websockets::open_connections() {
+        WS_CONNECTIONS.with_label_values(&[&route]).set(open as i64);
+    }
+    for (limit, rejected) in nylon_store::websockets::rejected_upgrades() {
+        mirror(&WS_UPGRADES_REJECTED.with_label_values(&[limit]), rejected);
+    }
+
+    CERT_EXPIRY.reset();
+    for cert in nylon_store::tls::get_all_certificates() {
+        CERT_EXPIRY
+            .with_label_values(&[&cert.domain])
+            .set(cert.expires_at.timestamp());
+    }
+
+    let Some(acme) = nylon_store::get::<nylon_tls::AcmeMetrics>(nylon_store::KEY_ACME_METRICS)
+    else {
+        return;
+    };
+    let counters = [
+        ("issuance", "success", &acme.issuance_success),
+        ("issuance", "failure", &acme.issuance_failure),
+        ("renewal", "success", &acme.renewal_success),
+        ("renewal", "failure", &acme.renewal_failure),
+        ("challenge", "success", &acme.challenge_success),
+        ("challenge", "failure", &acme.challenge_failure),
+    ];
+    for (operation, result, value) in counters {
+        mirror(
+            &ACME_OPERATIONS.with_label_values(&[operation, result]),
+            value.load(Ordering::Relaxed),
+        );
+    }
+
+    ACME_DOMAIN_FAILURES.reset();
+    for entry in acme.domain_metrics.iter() {
+        ACME_DOMAIN_FAILURES
+            .with_label_values(&[entry.key()])
+            .set(entry.failure_count as i64);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{backend, context::NylonContextExt, response::Response, runtime::NylonRuntime};
+use crate::{
+    backend, context::NylonContextExt, error_page, load_shedding, response::Response,
+    runtime::NylonRuntime, slow_clients, static_files, telemetry,
+};
 use async_trait::async_trait;
 use bytes::Bytes;
 use nylon_error::NylonError;
@@ -7,19 +10,27 @@ use nylon_plugin::{
     stream::PluginSessionStream,
     types::{MiddlewareContext, PluginResult},
 };
-use nylon_types::{context::NylonContext, plugins::PluginPhase, services::ServiceType};
+use nylon_types::{
+    body_transform::{self, BodyTransform},
+    buffer_pool, client_hints,
+    context::{HeaderMode, NylonContext, UpstreamConnection},
+    plugins::PluginPhase,
+    services::ServiceType,
+    template::render_template_string,
+};
 use pingora::{
-    ErrorType,
-    http::ResponseHeader,
+    ErrorSource, ErrorType,
+    http::{RequestHeader, ResponseHeader},
     prelude::HttpPeer,
-    proxy::{ProxyHttp, Session},
+    protocols::Digest,
+    proxy::{FailToProxy, ProxyHttp, Session},
 };
 use std::collections::HashMap;
-use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
-use tracing::{debug, error, info};
+use tracing::{Instrument, debug, error, info, warn};
 
 async fn handle_error_response<'a>(
     res: &'a mut Response<'a>,
@@ -29,10 +40,55 @@ async fn handle_error_response<'a>(
     let error = error.into();
     error!("Request error: {}", error);
 
-    res.status(error.http_status())
-        .body_json(error.exception_json())?
-        .send(session)
-        .await
+    let status = error.http_status();
+    let (code, message) = error_page::public(status, error.error_code(), error.message());
+    let (content_type, body) = match error_page::render(
+        session.req_header(),
+        res.ctx,
+        status,
+        code.as_deref().unwrap_or_default(),
+        message.as_deref().unwrap_or_default(),
+    ) {
+        Some(page) => page,
+        None => error_page::default_body(
+            session.req_header(),
+            status,
+            code.as_deref(),
+            message.as_deref(),
+        ),
+    };
+    res.ctx
+        .add_response_header
+        .insert("Content-Type".to_string(), content_type);
+    res.status(status).body(body).send(session).await
+}
+
+/// Times a request may retry a failed upstream connect, on another backend when the service has one
+const MAX_CONNECT_RETRIES: u8 = 2;
+
+/// The typed error for a pingora failure, when there is one
+///
+/// `upstream` tells whether the failure was on the upstream side.
+fn proxy_error(e: &pingora::Error, upstream: bool) -> Option<NylonError> {
+    let message = e.to_string();
+    match e.etype() {
+        ErrorType::HTTPStatus(413) => Some(NylonError::BodyTooLarge(message)),
+        ErrorType::HTTPStatus(429) => Some(NylonError::RateLimited(message)),
+        ErrorType::ConnectTimedout => Some(NylonError::UpstreamTimeout(message)),
+        ErrorType::ReadTimedout | ErrorType::WriteTimedout if upstream => {
+            Some(NylonError::UpstreamTimeout(message))
+        }
+        ErrorType::ConnectRefused
+        | ErrorType::ConnectNoRoute
+        | ErrorType::ConnectError
+        | ErrorType::ConnectProxyFailure
+        | ErrorType::TLSHandshakeFailure
+            if upstream =>
+        {
+            Some(NylonError::UpstreamConnectError(message))
+        }
+        _ => None,
+    }
 }
 
 /// Handle ACME HTTP-01 challenge requests
@@ -83,10 +139,9 @@ async fn handle_acme_challenge<'a>(
         Ok(key_auth) => {
             debug!("ACME challenge response for {}: {}", host_name, token);
             res.status(200);
-            {
-                let mut headers = res.ctx.add_response_header.write().expect("lock");
-                headers.insert("Content-Type".to_string(), "text/plain".to_string());
-            }
+            res.ctx
+                .add_response_header
+                .insert("Content-Type".to_string(), "text/plain".to_string());
             res.body(Bytes::from(key_auth.as_bytes().to_vec()));
             res.send(session).await
         }
@@ -105,6 +160,55 @@ async fn handle_acme_challenge<'a>(
     }
 }
 
+/// Look up an HTTP service and pick one of its healthy backends
+async fn select_http_backend(
+    service_name: &str,
+    session: &mut Session,
+    ctx: &mut NylonContext,
+) -> Result<pingora::lb::Backend, NylonError> {
+    let http_service = nylon_store::lb_backends::get(service_name).await?;
+    backend::selection(&http_service, session, ctx)
+}
+
+/// Backend for an upstream chosen by a plugin
+///
+/// `target` is an HTTP service name or an address such as `10.0.0.5:8080`
+/// or `https://api.internal:443`.
+async fn override_backend(
+    target: &str,
+    session: &mut Session,
+    ctx: &mut NylonContext,
+) -> Result<pingora::lb::Backend, NylonError> {
+    let (tls, address) = match target.strip_prefix("https://") {
+        Some(address) => (true, address),
+        None => (false, target.strip_prefix("http://").unwrap_or(target)),
+    };
+    let is_address = address
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if !is_address {
+        return select_http_backend(target, session, ctx).await;
+    }
+
+    let addr = tokio::net::lookup_host(address)
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| NylonError::ConfigError(format!("Unable to resolve upstream {}", target)))?;
+    let mut backend = pingora::lb::Backend::new(&addr.to_string())
+        .map_err(|e| NylonError::ConfigError(format!("Invalid upstream {}: {}", target, e)))?;
+    let sni = address
+        .rsplit_once(':')
+        .map(|(host, _)| host.trim_matches(['[', ']']).to_string())
+        .unwrap_or_default();
+    backend.ext.insert::<HttpPeer>(HttpPeer::new(
+        addr,
+        tls,
+        if tls { sni } else { String::new() },
+    ));
+    Ok(backend)
+}
+
 fn process_tls_redirect(host: &str, tls: bool) -> Option<String> {
     if tls {
         return None;
@@ -137,36 +241,45 @@ where
     <T as ProxyHttp>::CTX: Send + Sync + From<NylonContext>,
 {
     // Store error message if present
-    if let Some(err) = error
-        && let Ok(mut error_msg) = ctx.error_message.write()
-    {
-        *error_msg = Some(err.to_string());
+    if let Some(err) = error {
+        ctx.error_message = Some(err.to_string());
     }
     // Collect all middleware items from route and path levels
-    let route_opt = ctx
-        .route
-        .read()
-        .map_err(|_| {
-            pingora::Error::because(
-                ErrorType::InternalError,
-                "[middleware]",
-                NylonError::InternalServerError("lock poisoned".into()),
-            )
-        })?
-        .clone();
-    let Some(route) = &route_opt else {
+    let Some(route) = ctx.route.clone() else {
         return Ok(PluginResult::default());
     };
-    let path_middleware = &route.path_middleware;
     let middleware_items = route
         .route_middleware
         .iter()
         .flatten()
-        .chain(path_middleware.iter().flatten());
+        .chain(route.path_middleware.iter().flatten())
+        .collect::<Vec<_>>();
+    // Config loading already caps the chain; never run away if that is bypassed
+    if middleware_items.len() > nylon_store::routes::MAX_MIDDLEWARE_CHAIN {
+        return Err(pingora::Error::because(
+            ErrorType::InternalError,
+            "[middleware]",
+            NylonError::InternalServerError(format!(
+                "route {} has {} middleware, limit is {}",
+                route.name,
+                middleware_items.len(),
+                nylon_store::routes::MAX_MIDDLEWARE_CHAIN
+            )),
+        ));
+    }
+
+    let parent_span = ctx.trace_span.clone();
 
     // Process each middleware item
-    for middleware in middleware_items.cloned().collect::<Vec<_>>() {
+    for middleware in middleware_items {
         // debug!("Processing middleware: {:?}", middleware.0.plugin);
+        let middleware_span = tracing::info_span!(
+            parent: &parent_span,
+            "middleware",
+            plugin = middleware.0.plugin.as_deref().unwrap_or_default(),
+            entry = middleware.0.entry.as_deref().unwrap_or_default(),
+            phase = ?phase,
+        );
 
         match run_middleware(
             proxy,
@@ -180,6 +293,7 @@ where
             session,
             response_body,
         )
+        .instrument(middleware_span)
         .await
         {
             Ok((http_end, _)) if http_end => {
@@ -205,6 +319,94 @@ where
     Ok(PluginResult::default())
 }
 
+/// Error for a request a plugin ended after the request filter
+fn plugin_rejection(
+    ctx: &NylonContext,
+    default_status: u16,
+    reason: &'static str,
+) -> Box<pingora::Error> {
+    let status = match ctx.set_response_status.load(Ordering::Relaxed) {
+        status if status >= 400 => status,
+        _ => default_status,
+    };
+    pingora::Error::explain(ErrorType::HTTPStatus(status), reason)
+}
+
+/// Whether a plugin subscribed to the upstream phases
+fn has_upstream_subscribers(ctx: &NylonContext) -> bool {
+    !ctx.upstream_subscribers.is_empty()
+}
+
+/// Carry the changes plugins made to the session's request header over to the
+/// upstream request, leaving whatever pingora set on it alone
+fn apply_request_changes(
+    before: &RequestHeader,
+    after: &RequestHeader,
+    upstream: &mut RequestHeader,
+) {
+    if after.method != before.method {
+        upstream.set_method(after.method.clone());
+    }
+    if after.uri != before.uri {
+        upstream.set_uri(after.uri.clone());
+    }
+    for name in before.headers.keys() {
+        if !after.headers.contains_key(name) {
+            let _ = upstream.remove_header(name);
+        }
+    }
+    for name in after.headers.keys() {
+        let values: Vec<_> = after.headers.get_all(name).iter().collect();
+        if before
+            .headers
+            .get_all(name)
+            .iter()
+            .eq(values.iter().copied())
+        {
+            continue;
+        }
+        let _ = upstream.remove_header(name);
+        for value in values {
+            let _ = upstream.append_header(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Hold back body chunks for a pending transform and release the result at
+/// the end of the stream
+///
+/// A body that outgrows the buffer is released as it is.
+fn transform_body(pending: &mut Option<BodyTransform>, body: &mut Option<Bytes>, end: bool) {
+    let Some(transform) = pending.as_mut() else {
+        return;
+    };
+    if let Some(chunk) = body.take() {
+        transform.buffer.extend_from_slice(&chunk);
+    }
+    if !end && transform.buffer.len() <= body_transform::MAX_BODY_SIZE {
+        return;
+    }
+    let Some(transform) = pending.take() else {
+        return;
+    };
+    let out = if end {
+        transform
+            .mapping
+            .apply(&transform.buffer)
+            .unwrap_or(transform.buffer)
+    } else {
+        transform.buffer
+    };
+    *body = Some(Bytes::from(out));
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 #[async_trait]
 impl ProxyHttp for NylonRuntime {
     type CTX = NylonContext;
@@ -212,12 +414,8 @@ impl ProxyHttp for NylonRuntime {
     fn new_ctx(&self) -> Self::CTX {
         let ctx = NylonContext::default();
         // Set request timestamp
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
         ctx.request_timestamp
-            .store(timestamp, std::sync::atomic::Ordering::Relaxed);
+            .store(unix_millis(), std::sync::atomic::Ordering::Relaxed);
         ctx
     }
 
@@ -226,12 +424,20 @@ impl ProxyHttp for NylonRuntime {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        // Start the request span, continuing the caller's trace if any
+        let span = telemetry::request_span(session.req_header());
+        telemetry::inject(&span, session.req_header_mut());
+        ctx.trace_span = span.clone();
+
         let mut res = Response::new(self, ctx).await?;
 
         // Parse request and handle errors
         if let Err(e) = res.ctx.parse_request(session).await {
             return handle_error_response(&mut res, session, e).await;
         }
+        if let Err(e) = slow_clients::check_request(session, res.ctx) {
+            return handle_error_response(&mut res, session, e).await;
+        }
 
         // Handle ACME HTTP-01 challenge requests BEFORE route matching
         let req_path = session.req_header().uri.path().to_string();
@@ -241,51 +447,47 @@ impl ProxyHttp for NylonRuntime {
         }
 
         // Find matching route
-        let (route, params) = match nylon_store::routes::find_route(session) {
+        let (mut route, params) = match nylon_store::routes::find_route(session) {
             Ok(route) => route,
             Err(e) => return handle_error_response(&mut res, session, e).await,
         };
 
         // Check for TLS redirect
-        let host_owned = res
-            .ctx
-            .host
-            .read()
-            .map_err(|_| {
-                pingora::Error::because(
-                    ErrorType::InternalError,
-                    "[proxy]",
-                    "host lock".to_string(),
-                )
-            })?
-            .clone();
         let tls = res.ctx.tls.load(Ordering::Relaxed);
-        if let Some(redirect_url) = process_tls_redirect(&host_owned, tls) {
+        if let Some(redirect_url) = process_tls_redirect(&res.ctx.host, tls) {
             info!("Redirecting to TLS: {}", redirect_url);
             res.redirect(redirect_url);
             return res.send(session).await;
         }
 
-        // Store route and params in context
-        {
-            let mut r = res.ctx.route.write().map_err(|_| {
-                pingora::Error::because(
-                    ErrorType::InternalError,
-                    "[proxy]",
-                    "route lock".to_string(),
-                )
-            })?;
-            *r = Some(route.clone());
+        span.record("http.route", route.name.as_str());
+
+        // Client hints and device class routing
+        if let Some(accept_ch) = &route.accept_ch {
+            res.ctx
+                .add_response_header
+                .insert("Accept-CH".to_string(), accept_ch.clone());
         }
-        {
-            let mut p = res.ctx.params.write().map_err(|_| {
-                pingora::Error::because(
-                    ErrorType::InternalError,
-                    "[proxy]",
-                    "params lock".to_string(),
-                )
-            })?;
-            *p = Some(params.clone());
+        if route.devices.is_some() {
+            res.ctx
+                .add_response_header
+                .insert("Vary".to_string(), client_hints::DEVICE_VARY.to_string());
+        }
+        if let Some(devices) = &route.devices {
+            let class = client_hints::device_class(session.req_header(), res.ctx);
+            if let Some(service) = devices.get(&class).cloned() {
+                // Only this request switches; the shared snapshot stays untouched
+                let detached = Arc::make_mut(&mut route);
+                detached.service = service;
+                detached.devices = None;
+            }
+        }
+
+        // Store route and params in context
+        res.ctx.route = Some(route.clone());
+        res.ctx.params = Some(params.clone());
+        if let Err(e) = load_shedding::acquire(res.ctx, &route, session.is_upgrade_req()) {
+            return handle_error_response(&mut res, session, e).await;
         }
 
         // Process middleware
@@ -312,6 +514,17 @@ impl ProxyHttp for NylonRuntime {
             }
         }
 
+        // A plugin picked the upstream, which replaces the route's service
+        if let Some(target) = res.ctx.upstream_override.clone() {
+            debug!("[{}] upstream overridden by plugin: {}", route.name, target);
+            let selected = match override_backend(&target, session, res.ctx).await {
+                Ok(backend) => backend,
+                Err(e) => return handle_error_response(&mut res, session, e).await,
+            };
+            res.ctx.backend = selected;
+            return Ok(false);
+        }
+
         // Handle plugin service type
         if route.service.service_type == ServiceType::Plugin {
             if let Some(plugin) = &route.service.plugin {
@@ -326,6 +539,12 @@ impl ProxyHttp for NylonRuntime {
                     &None,
                     &None,
                 )
+                .instrument(tracing::info_span!(
+                    parent: &span,
+                    "plugin",
+                    plugin = plugin.name.as_str(),
+                    entry = plugin.entry.as_str(),
+                ))
                 .await
                 {
                     Ok(result) => {
@@ -349,27 +568,66 @@ impl ProxyHttp for NylonRuntime {
 
         // Handle regular HTTP service type only
         if route.service.service_type == ServiceType::Http {
-            let http_service = match nylon_store::lb_backends::get(&route.service.name).await {
-                Ok(backend) => backend,
-                Err(e) => return handle_error_response(&mut res, session, e).await,
-            };
+            let mut selected = select_http_backend(&route.service.name, session, res.ctx).await;
 
-            // Get backend selection
-            let selected_backend = match backend::selection(&http_service, session, res.ctx) {
-                Ok(b) => b,
-                Err(e) => return handle_error_response(&mut res, session, e).await,
+            // Switch to the fallback service when the primary one cannot serve
+            if let Err(e) = &selected
+                && let Some(fallback) = route.fallback.clone()
+            {
+                warn!(
+                    "[{}] service {} unavailable ({}), falling back to {}",
+                    route.name, route.service.name, e, fallback.name
+                );
+                crate::metrics::record_fallback(&route.name, &fallback.name);
+                // Only this request switches; the shared snapshot stays untouched
+                let detached = Arc::make_mut(&mut route);
+                detached.fallback = None;
+                detached.service = fallback;
+                res.ctx.route = Some(route.clone());
+                if route.service.service_type == ServiceType::Http {
+                    selected = select_http_backend(&route.service.name, session, res.ctx).await;
+                }
+            }
+
+            if route.service.service_type == ServiceType::Http {
+                let selected_backend = match selected {
+                    Ok(b) => b,
+                    Err(e) => return handle_error_response(&mut res, session, e).await,
+                };
+
+                res.ctx.backend = selected_backend;
+            }
+        }
+
+        // Handle template service type (synthetic response rendered per request)
+        if route.service.service_type == ServiceType::Template {
+            let (Some(conf), Some(template)) = (&route.service.template_conf, &route.template)
+            else {
+                let err = NylonError::ConfigError(
+                    "Template service missing 'template' config".to_string(),
+                );
+                return handle_error_response(&mut res, session, err).await;
             };
 
-            {
-                let mut b = res.ctx.backend.write().map_err(|_| {
-                    pingora::Error::because(
-                        ErrorType::InternalError,
-                        "[proxy]",
-                        "backend lock".to_string(),
-                    )
-                })?;
-                *b = selected_backend;
+            let body = render_template_string(&template.body, session.req_header(), res.ctx);
+            let mut headers = HashMap::new();
+            headers.insert(
+                "Content-Type".to_string(),
+                conf.content_type
+                    .clone()
+                    .unwrap_or_else(|| "text/plain; charset=utf-8".to_string()),
+            );
+            for (name, value) in &template.headers {
+                headers.insert(
+                    name.clone(),
+                    render_template_string(value, session.req_header(), res.ctx),
+                );
             }
+
+            res.ctx.add_response_header.extend(headers);
+            res.status(conf.status.unwrap_or(200))
+                .body(Bytes::from(body.into_bytes()));
+            return res.send(session).await;
         }
 
         // Handle static file service type (serve from disk, optional SPA fallback)
@@ -402,6 +660,8 @@ impl ProxyHttp for NylonRuntime {
                 let err = NylonError::HttpException(403, "FORBIDDEN", "Invalid path");
                 return handle_error_response(&mut res, session, err).await;
             }
+            // Deny patterns are checked before the disk is touched
+            let permitted = static_files::permitted(conf, &rel_path);
 
             let root = PathBuf::from(&conf.root);
             let mut file_path = root.join(rel_path.trim_start_matches('/'));
@@ -411,52 +671,74 @@ impl ProxyHttp for NylonRuntime {
                 .index
                 .clone()
                 .unwrap_or_else(|| "index.html".to_string());
-            if uri_path.ends_with('/')
-                || fs::metadata(&file_path)
-                    .map(|m| m.is_dir())
-                    .unwrap_or(false)
-            {
+            let dir = (uri_path.ends_with('/')
+                || (permitted
+                    && tokio::fs::metadata(&file_path)
+                        .await
+                        .map(|m| m.is_dir())
+                        .unwrap_or(false)))
+            .then(|| file_path.clone());
+            if dir.is_some() {
                 file_path = file_path.join(&index_name);
             }
 
-            // Try to read file
+            // Try to read file, or index.html from root if SPA is enabled
             debug!("[static] file_path: {}", file_path.display());
-            match fs::read(&file_path) {
-                Ok(bytes) => {
-                    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
-                    {
-                        let mut headers = res.ctx.add_response_header.write().expect("lock");
-                        headers.insert("Content-Type".to_string(), mime.to_string());
+            let req = session.req_header();
+            let mut served = if permitted {
+                static_files::serve(req, &file_path, conf, &uri_path).await
+            } else {
+                debug!("[static] denied: {}", rel_path);
+                Err(std::io::ErrorKind::PermissionDenied.into())
+            };
+            if served.is_err()
+                && permitted
+                && conf.autoindex.unwrap_or(false)
+                && let Some(dir) = &dir
+                && let Ok(listing) = static_files::listing(dir, conf, &rel_path, req, res.ctx).await
+            {
+                res.ctx.add_response_header.insert(
+                    "Content-Type".to_string(),
+                    "text/html; charset=utf-8".to_string(),
+                );
+                res.status(200).body(Bytes::from(listing));
+                return res.send(session).await;
+            }
+            if served.is_err() && conf.spa.unwrap_or(false) {
+                let spa_index = root.join(&index_name);
+                served =
+                    static_files::serve(req, &spa_index, conf, &format!("/{index_name}")).await;
+            }
+            if served.is_err()
+                && let Some(not_found) = &conf.not_found
+            {
+                let page = root.join(not_found);
+                match tokio::fs::read(&page).await {
+                    Ok(body) => {
+                        let mime = mime_guess::from_path(&page).first_or_octet_stream();
+                        res.ctx
+                            .add_response_header
+                            .insert("Content-Type".to_string(), mime.to_string());
+                        res.status(404).body(Bytes::from(body));
+                        return res.send(session).await;
                     }
-                    res.status(200).body(Bytes::from(bytes));
-                    return res.send(session).await;
+                    Err(e) => warn!("Failed to read not_found page {}: {}", page.display(), e),
                 }
-                Err(_) => {
-                    // If SPA enabled, serve index.html from root
-                    if conf.spa.unwrap_or(false) {
-                        let spa_index = root.join(&index_name);
-                        match fs::read(&spa_index) {
-                            Ok(bytes) => {
-                                let mime =
-                                    mime_guess::from_path(&spa_index).first_or_octet_stream();
-                                {
-                                    let mut headers =
-                                        res.ctx.add_response_header.write().expect("lock");
-                                    headers.insert("Content-Type".to_string(), mime.to_string());
-                                }
-                                res.status(200).body(Bytes::from(bytes));
-                                return res.send(session).await;
-                            }
-                            Err(_) => {
-                                let err =
-                                    NylonError::HttpException(404, "NOT_FOUND", "File not found");
-                                return handle_error_response(&mut res, session, err).await;
-                            }
-                        }
-                    } else {
-                        let err = NylonError::HttpException(404, "NOT_FOUND", "File not found");
-                        return handle_error_response(&mut res, session, err).await;
+            }
+            match served {
+                Ok(served) => {
+                    for (name, value) in served.headers {
+                        res.ctx.add_response_header.insert(name.to_string(), value);
                     }
+                    res.status(served.status);
+                    return match served.body {
+                        Some((file, len)) => res.send_file(session, file, len, served.gzip).await,
+                        None => res.send(session).await,
+                    };
+                }
+                Err(_) => {
+                    let err = NylonError::HttpException(404, "NOT_FOUND", "File not found");
+                    return handle_error_response(&mut res, session, err).await;
                 }
             }
         }
@@ -466,24 +748,192 @@ impl ProxyHttp for NylonRuntime {
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let backend_guard = ctx.backend.read().map_err(|_| {
-            pingora::Error::because(
-                ErrorType::InternalError,
-                "[upstream_peer]",
-                "backend lock".to_string(),
-            )
-        })?;
-        let peer = backend_guard.ext.get::<HttpPeer>().ok_or_else(|| {
+        // A retried connect picks a backend again, unless a plugin chose the upstream
+        if ctx.connect_retries > 0
+            && ctx.upstream_override.is_none()
+            && let Some(route) = ctx.route.clone()
+            && route.service.service_type == ServiceType::Http
+        {
+            match select_http_backend(&route.service.name, session, ctx).await {
+                Ok(backend) => ctx.backend = backend,
+                Err(e) => debug!("Keeping the backend for the retry: {}", e),
+            }
+        }
+        let peer = ctx.backend.ext.get::<HttpPeer>().cloned().ok_or_else(|| {
             pingora::Error::because(
                 ErrorType::InternalError,
                 "[upstream_peer]",
                 NylonError::ConfigError("[backend] no peer found".to_string()),
             )
         })?;
-        Ok(Box::new(peer.clone()))
+        ctx.upstream_span = tracing::info_span!(
+            parent: &ctx.trace_span,
+            "upstream",
+            otel.kind = "client",
+            server.address = %peer._address,
+            http.response.status_code = tracing::field::Empty,
+        );
+        ctx.upstream_timestamp
+            .store(unix_millis(), Ordering::Relaxed);
+        Ok(Box::new(peer))
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        // Nothing has been sent yet, so a retryable failure is safe to try again
+        if ctx.connect_retries < MAX_CONNECT_RETRIES
+            && proxy_error(&e, true).is_some_and(|error| error.is_retryable())
+        {
+            ctx.connect_retries += 1;
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if has_upstream_subscribers(ctx) {
+            // Plugins edit the session's header; only their changes are copied over
+            let before = session.req_header().clone();
+            let result = process_middleware(
+                self,
+                PluginPhase::UpstreamRequestFilter,
+                ctx,
+                session,
+                &None,
+                None,
+            )
+            .await?;
+            if result.http_end {
+                return Err(plugin_rejection(
+                    ctx,
+                    403,
+                    "upstream request rejected by plugin",
+                ));
+            }
+            apply_request_changes(&before, session.req_header(), upstream_request);
+        }
+        // A transformed body, or one plugins may rewrite chunk by chunk, has a length of its own
+        if ctx.request_body_transform.is_some() || !ctx.request_body_streams.is_empty() {
+            let _ = upstream_request.remove_header(&http::header::CONTENT_LENGTH);
+            let _ = upstream_request.insert_header(http::header::TRANSFER_ENCODING, "chunked");
+        }
+        telemetry::inject(&ctx.upstream_span, upstream_request);
+        Ok(())
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if !has_upstream_subscribers(ctx) {
+            return Ok(());
+        }
+        ctx.upstream_connection = Some(UpstreamConnection {
+            address: peer._address.to_string(),
+            reused,
+            tls: peer.is_tls(),
+            sni: peer.sni.clone(),
+        });
+        let result = process_middleware(
+            self,
+            PluginPhase::ConnectedToUpstream,
+            ctx,
+            session,
+            &None,
+            None,
+        )
+        .await?;
+        if result.http_end {
+            return Err(plugin_rejection(
+                ctx,
+                502,
+                "upstream connection rejected by plugin",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Err(e) = slow_clients::check_body(session, ctx, end_of_stream) {
+            return Err(pingora::Error::because(
+                ErrorType::HTTPStatus(e.http_status()),
+                "[request_body_filter]",
+                e,
+            ));
+        }
+
+        // A decompressed body was read whole; send it in place of the original
+        if ctx.request_body_decoded.load(Ordering::Relaxed) {
+            *body = end_of_stream.then(|| Bytes::from(ctx.request_body.clone()));
+        } else if ctx.request_body_held.swap(false, Ordering::Relaxed) {
+            // The first chunk is the retry buffer, which may have cut off what
+            // was read ahead; send all of it instead
+            *body = Some(Bytes::from(ctx.request_body.clone()));
+        }
+
+        // Plugins that asked for the body in chunks see it before a transform
+        if !ctx.request_body_streams.is_empty() {
+            ctx.request_body_chunk = body.take().unwrap_or_default();
+            ctx.request_body_end.store(end_of_stream, Ordering::Relaxed);
+
+            let result = process_middleware(
+                self,
+                PluginPhase::RequestBodyFilter,
+                ctx,
+                session,
+                &None,
+                None,
+            )
+            .await?;
+            if result.http_end {
+                return Err(plugin_rejection(
+                    ctx,
+                    400,
+                    "request body rejected by plugin",
+                ));
+            }
+
+            let chunk = std::mem::take(&mut ctx.request_body_chunk);
+            if !chunk.is_empty() {
+                *body = Some(chunk);
+            }
+        }
+        transform_body(&mut ctx.request_body_transform, body, end_of_stream);
+        Ok(())
     }
 
     async fn response_filter(
@@ -495,39 +945,81 @@ impl ProxyHttp for NylonRuntime {
     where
         Self::CTX: Send + Sync,
     {
+        // Upstream answered, close its span
+        let upstream_started = ctx.upstream_timestamp.load(Ordering::Relaxed);
+        if upstream_started > 0 {
+            ctx.upstream_response_ms.store(
+                unix_millis().saturating_sub(upstream_started),
+                Ordering::Relaxed,
+            );
+        }
+        ctx.upstream_span.record(
+            "http.response.status_code",
+            upstream_response.status.as_u16(),
+        );
+        ctx.upstream_span = tracing::Span::none();
+
         // Process middleware
         let _ =
             process_middleware(self, PluginPhase::ResponseFilter, ctx, session, &None, None).await;
 
+        // A watermark changes the body length; encoded bodies cannot carry one
+        if let Some(mark) = &ctx.response_watermark {
+            let content_type = upstream_response
+                .headers
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            let encoded = upstream_response
+                .headers
+                .get(http::header::CONTENT_ENCODING)
+                .is_some_and(|v| v.as_bytes() != b"identity");
+            if mark.applies_to(content_type) && !encoded {
+                let _ = upstream_response.remove_header(&http::header::CONTENT_LENGTH);
+            } else {
+                ctx.response_watermark = None;
+            }
+        }
+
+        if ctx.response_body_transform.is_some() {
+            let json = upstream_response
+                .headers
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(BodyTransform::applies_to);
+            let encoded = upstream_response
+                .headers
+                .get(http::header::CONTENT_ENCODING)
+                .is_some_and(|v| v.as_bytes() != b"identity");
+            if json && !encoded {
+                let _ = upstream_response.remove_header(&http::header::CONTENT_LENGTH);
+            } else {
+                ctx.response_body_transform = None;
+            }
+        }
+
         // Add response headers
-        for (key, value) in ctx
-            .add_response_header
-            .read()
-            .map_err(|_| {
-                pingora::Error::because(
-                    ErrorType::InternalError,
-                    "[response_filter]",
-                    "add_header lock".to_string(),
-                )
-            })?
-            .iter()
-        {
+        for (key, value) in ctx.add_response_header.iter() {
             let _ = upstream_response.append_header(key.to_ascii_lowercase(), value);
         }
 
+        // Headers with a write mode
+        for (key, value, mode) in ctx.response_header_ops.iter() {
+            let key = key.to_ascii_lowercase();
+            match mode {
+                HeaderMode::Set => {
+                    let _ = upstream_response.remove_header(&key);
+                }
+                HeaderMode::SetIfAbsent if upstream_response.headers.contains_key(&key) => {
+                    continue;
+                }
+                _ => {}
+            }
+            let _ = upstream_response.append_header(key, value);
+        }
+
         // Remove response headers
-        for key in ctx
-            .remove_response_header
-            .read()
-            .map_err(|_| {
-                pingora::Error::because(
-                    ErrorType::InternalError,
-                    "[response_filter]",
-                    "remove_header lock".to_string(),
-                )
-            })?
-            .iter()
-        {
+        for key in ctx.remove_response_header.iter() {
             let key = key.to_ascii_lowercase();
             let _ = upstream_response.remove_header(&key);
         }
@@ -542,55 +1034,258 @@ impl ProxyHttp for NylonRuntime {
         &self,
         session: &mut Session,
         body: &mut Option<Bytes>,
-        _end_of_stream: bool,
+        end_of_stream: bool,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Option<Duration>>
     where
         Self::CTX: Send + Sync,
     {
+        let body_middleware = ctx
+            .route
+            .as_ref()
+            .is_some_and(|route| route.response_body_middleware);
+        if !body_middleware
+            && ctx.set_response_body.is_empty()
+            && ctx.response_body_transform.is_none()
+            && ctx.response_watermark.is_none()
+        {
+            // Nothing reads or rewrites the body
+            return Ok(None);
+        }
+
         // Process middleware for response_body_filter phase
-        let _ = tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async {
-                process_middleware(
-                    self,
-                    PluginPhase::ResponseBodyFilter,
-                    ctx,
-                    session,
-                    body,
-                    None,
-                )
-                .await
-            })
-        });
+        if body_middleware {
+            let _ = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(async {
+                    process_middleware(
+                        self,
+                        PluginPhase::ResponseBodyFilter,
+                        ctx,
+                        session,
+                        body,
+                        None,
+                    )
+                    .await
+                })
+            });
+        }
 
-        let buf = ctx.set_response_body.write().map_err(|_| {
-            pingora::Error::because(
-                ErrorType::InternalError,
-                "[body_filter]",
-                "set_response_body lock".to_string(),
-            )
-        })?;
+        if !ctx.set_response_body.is_empty() {
+            *body = Some(Bytes::from(ctx.set_response_body.clone()));
+        }
+        transform_body(&mut ctx.response_body_transform, body, end_of_stream);
 
-        if !buf.is_empty() {
-            *body = Some(Bytes::from(buf.clone()));
+        // Stamp the first chunk with data
+        if let Some(chunk) = body.as_ref().filter(|chunk| !chunk.is_empty())
+            && let Some(mark) = ctx.response_watermark.take()
+            && let Some(marked) = mark.apply(chunk)
+        {
+            *body = Some(Bytes::from(marked));
         }
         Ok(None)
     }
 
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Same status as pingora's default unless the error has a type of its own,
+        // with the route's error page or the default body
+        let typed = proxy_error(e, matches!(e.esource(), ErrorSource::Upstream));
+        let code = match (&typed, e.etype()) {
+            (Some(error), _) => error.http_status(),
+            (None, ErrorType::HTTPStatus(code)) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            // pingora's error text names upstream addresses, so clients only get the reason
+            let error_code = match &typed {
+                Some(error) => error.error_code(),
+                None => e.etype().as_str().to_string(),
+            };
+            let (error_code, message) =
+                error_page::public(code, error_code, error_page::reason(code).to_string());
+            let (content_type, body) = match error_page::render(
+                session.req_header(),
+                ctx,
+                code,
+                error_code.as_deref().unwrap_or_default(),
+                message.as_deref().unwrap_or_default(),
+            ) {
+                Some(page) => page,
+                None => error_page::default_body(
+                    session.req_header(),
+                    code,
+                    error_code.as_deref(),
+                    message.as_deref(),
+                ),
+            };
+            if let Err(e) = error_page::send(session, code, content_type, body).await {
+                error!("Failed to send error response: {}", e);
+            }
+        }
+        if let Some(error) = &typed {
+            warn!(
+                "Proxy failed: {} (retryable: {})",
+                error,
+                error.is_retryable()
+            );
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     async fn logging(&self, session: &mut Session, e: Option<&pingora::Error>, ctx: &mut Self::CTX)
     where
         Self::CTX: Send + Sync,
     {
+        slow_clients::release(ctx);
+        load_shedding::release(ctx);
+
         // Process middleware for logging phase
         let _ = process_middleware(self, PluginPhase::Logging, ctx, session, &None, e).await;
 
-        let streams = ctx
-            .session_stream
-            .read()
-            .map(|m| m.values().cloned().collect::<Vec<_>>())
-            .unwrap_or_default();
-        for stream in streams {
+        // Record request metrics
+        let (route_name, service_name, is_http) = ctx
+            .route
+            .as_ref()
+            .map(|r| {
+                (
+                    r.name.clone(),
+                    r.service.name.clone(),
+                    r.service.service_type == ServiceType::Http,
+                )
+            })
+            .unwrap_or_else(|| ("unmatched".to_string(), "-".to_string(), false));
+        let backend_addr = if is_http {
+            ctx.backend.addr.to_string()
+        } else {
+            "-".to_string()
+        };
+        let status = session
+            .response_written()
+            .map(|r| r.status.as_u16())
+            .unwrap_or(0);
+        let now_ms = unix_millis();
+        let started_ms = ctx.request_timestamp.load(Ordering::Relaxed);
+        ctx.trace_span.record("http.response.status_code", status);
+        let duration_ms = now_ms.saturating_sub(started_ms);
+        crate::metrics::record_request(&crate::metrics::RequestSample {
+            route: &route_name,
+            service: &service_name,
+            backend: &backend_addr,
+            method: session.req_header().method.as_str(),
+            status,
+            duration_secs: duration_ms as f64 / 1000.0,
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+        });
+        let record = crate::access_log::AccessRecord {
+            route: &route_name,
+            service: &service_name,
+            backend: &backend_addr,
+            status,
+            bytes: session.body_bytes_sent(),
+            duration_ms,
+            upstream_ms: match ctx.upstream_timestamp.load(Ordering::Relaxed) {
+                0 => None,
+                _ => Some(ctx.upstream_response_ms.load(Ordering::Relaxed)),
+            },
+        };
+        crate::access_log::check_thresholds(session.req_header(), &record);
+        crate::access_log::log(session.req_header(), ctx, &record);
+
+        for stream in ctx.session_stream.values() {
             let _ = stream.close().await;
         }
+        buffer_pool::put(std::mem::take(&mut ctx.request_body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/a", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_apply_request_changes() {
+        let before = request(&[("x-keep", "1"), ("x-drop", "1"), ("x-multi", "a")]);
+        let mut after = before.clone();
+        after.set_method(http::Method::POST);
+        after.set_uri(http::Uri::from_static("/b?q=1"));
+        after.remove_header("x-drop");
+        after.append_header("x-multi", "b").unwrap();
+        after.insert_header("x-new", "1").unwrap();
+
+        // pingora's own changes to the upstream request survive
+        let mut upstream = before.clone();
+        upstream.insert_header("x-upstream", "1").unwrap();
+        apply_request_changes(&before, &after, &mut upstream);
+
+        assert_eq!(upstream.method, http::Method::POST);
+        assert_eq!(upstream.uri, "/b?q=1");
+        assert!(upstream.headers.get("x-drop").is_none());
+        assert_eq!(upstream.headers.get("x-keep").unwrap(), "1");
+        assert_eq!(upstream.headers.get("x-new").unwrap(), "1");
+        assert_eq!(upstream.headers.get("x-upstream").unwrap(), "1");
+        let multi: Vec<_> = upstream.headers.get_all("x-multi").iter().collect();
+        assert_eq!(multi, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_apply_request_changes_untouched() {
+        let before = request(&[("x-keep", "1")]);
+        let mut upstream = before.clone();
+        upstream.set_uri(http::Uri::from_static("/rewritten"));
+        apply_request_changes(&before, &before.clone(), &mut upstream);
+        assert_eq!(upstream.uri, "/rewritten");
+        assert_eq!(upstream.headers.get("x-keep").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_proxy_errors_map_to_statuses() {
+        let typed = |etype, upstream| {
+            proxy_error(&pingora::Error::new(etype), upstream)
+                .map(|e| (e.http_status(), e.error_code(), e.is_retryable()))
+        };
+        let connect = Some((502, "UPSTREAM_CONNECT_ERROR".to_string(), true));
+        let timeout = Some((504, "UPSTREAM_TIMEOUT".to_string(), true));
+        assert_eq!(typed(ErrorType::ConnectRefused, true), connect);
+        assert_eq!(typed(ErrorType::TLSHandshakeFailure, true), connect);
+        assert_eq!(typed(ErrorType::ConnectTimedout, false), timeout);
+        assert_eq!(typed(ErrorType::ReadTimedout, true), timeout);
+        assert_eq!(
+            typed(ErrorType::HTTPStatus(413), false),
+            Some((413, "BODY_TOO_LARGE".to_string(), false))
+        );
+        assert_eq!(
+            typed(ErrorType::HTTPStatus(429), false),
+            Some((429, "RATE_LIMITED".to_string(), true))
+        );
+        // client side failures have no type of their own
+        assert_eq!(typed(ErrorType::ReadTimedout, false), None);
+        assert_eq!(typed(ErrorType::ConnectRefused, false), None);
+        assert_eq!(typed(ErrorType::InternalError, true), None);
     }
 }
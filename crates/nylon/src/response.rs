@@ -1,5 +1,6 @@
 use crate::runtime::NylonRuntime;
 use bytes::Bytes;
+use flate2::write::GzEncoder;
 use nylon_types::context::NylonContext;
 use pingora::{
     ErrorType,
@@ -8,6 +9,11 @@ use pingora::{
     proxy::{ProxyHttp, Session},
 };
 use serde_json::Value;
+use std::io::Write;
+use tokio::io::AsyncReadExt;
+
+/// Bytes read from a file per body chunk
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct Response<'a> {
     pub body: Option<Bytes>,
@@ -26,11 +32,9 @@ impl<'a> Response<'a> {
 
     pub fn redirect(&mut self, redirect: String) -> &mut Self {
         self.status(301);
-        {
-            let mut headers = self.ctx.add_response_header.write().expect("lock");
-            headers.insert("Location".to_string(), redirect);
-            headers.insert("Content-Length".to_string(), "0".to_string());
-        }
+        let headers = &mut self.ctx.add_response_header;
+        headers.insert("Location".to_string(), redirect);
+        headers.insert("Content-Length".to_string(), "0".to_string());
         self
     }
 
@@ -44,10 +48,9 @@ impl<'a> Response<'a> {
     pub fn body(&mut self, body: Bytes) -> &mut Self {
         let body_len = body.len();
         self.body = Some(body);
-        {
-            let mut headers = self.ctx.add_response_header.write().expect("lock");
-            headers.insert("Content-Length".to_string(), body_len.to_string());
-        }
+        self.ctx
+            .add_response_header
+            .insert("Content-Length".to_string(), body_len.to_string());
         self
     }
 
@@ -79,7 +82,7 @@ impl<'a> Response<'a> {
         let mut tasks = vec![HttpTask::Header(Box::new(headers), false)];
         let _ = self
             .proxy
-            .response_body_filter(session, &mut self.body, false, self.ctx)
+            .response_body_filter(session, &mut self.body, true, self.ctx)
             .is_ok();
         if let Some(body) = self.body.clone() {
             tasks.push(HttpTask::Body(Some(body), false));
@@ -98,4 +101,96 @@ impl<'a> Response<'a> {
         }
         Ok(true)
     }
+
+    /// Send `len` bytes of `file` in chunks, without holding the file in memory
+    ///
+    /// With `gzip`, each chunk is compressed and the length is left open.
+    pub async fn send_file(
+        &mut self,
+        session: &mut Session,
+        mut file: tokio::fs::File,
+        len: u64,
+        gzip: bool,
+    ) -> pingora::Result<bool> {
+        let send_error = |e: Box<pingora::Error>| {
+            tracing::error!("Error sending response: {:?}", e);
+            pingora::Error::because(
+                ErrorType::InternalError,
+                "[Response]".to_string(),
+                e.to_string(),
+            )
+        };
+        let mut encoder = gzip.then(|| GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        let status = self
+            .ctx
+            .set_response_status
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let mut headers = ResponseHeader::build(status, None)?;
+        // Set like an upstream would, so response_filter can drop it when the
+        // body is transformed; a gzipped body has no length and pingora frames it
+        if !gzip {
+            headers.insert_header("Content-Length", len.to_string())?;
+        }
+        self.proxy
+            .response_filter(session, &mut headers, self.ctx)
+            .await?;
+        session
+            .response_duplex_vec(vec![HttpTask::Header(Box::new(headers), false)])
+            .await
+            .map_err(send_error)?;
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        let mut remaining = len;
+        loop {
+            let want = remaining.min(FILE_CHUNK_SIZE as u64) as usize;
+            let read = match want {
+                0 => 0,
+                _ => file.read(&mut buf[..want]).await.map_err(|e| {
+                    pingora::Error::because(
+                        ErrorType::ReadError,
+                        "[Response]".to_string(),
+                        e.to_string(),
+                    )
+                })?,
+            };
+            remaining -= read as u64;
+            // A file that shrank while being sent ends the body early
+            let end = read == 0 || remaining == 0;
+            let mut body = match encoder.as_mut() {
+                Some(encoder) => {
+                    encoder
+                        .write_all(&buf[..read])
+                        .and_then(|_| if end { encoder.try_finish() } else { Ok(()) })
+                        .map_err(|e| {
+                            pingora::Error::because(
+                                ErrorType::InternalError,
+                                "[Response]".to_string(),
+                                e.to_string(),
+                            )
+                        })?;
+                    let compressed = std::mem::take(encoder.get_mut());
+                    (!compressed.is_empty()).then(|| Bytes::from(compressed))
+                }
+                None => (read > 0).then(|| Bytes::copy_from_slice(&buf[..read])),
+            };
+            let _ = self
+                .proxy
+                .response_body_filter(session, &mut body, end, self.ctx)
+                .is_ok();
+            let mut tasks = vec![];
+            if let Some(body) = body {
+                tasks.push(HttpTask::Body(Some(body), false));
+            }
+            if end {
+                tasks.push(HttpTask::Done);
+            }
+            session
+                .response_duplex_vec(tasks)
+                .await
+                .map_err(send_error)?;
+            if end {
+                return Ok(true);
+            }
+        }
+    }
 }
@@ -3,13 +3,19 @@
 //! This module contains the core runtime functionality for the Nylon proxy server,
 //! including server initialization, configuration, and service management.
 
-use crate::{background_service::NylonBackgroundService, dynamic_certificate::new_tls_settings};
+use crate::{
+    background_service::NylonBackgroundService, command_socket::CommandSocketService,
+    dynamic_certificate::new_tls_settings, listeners, load_shedding::LoadSamplerService,
+    slow_clients::HeaderDeadline, systemd,
+};
 use nylon_config::runtime::RuntimeConfig;
 use nylon_error::NylonError;
 use pingora::{
+    apps::HttpServerOptions,
     prelude::{Opt, background_service},
     proxy,
     server::{Server, configuration::ServerConf},
+    services::listening::Service,
 };
 use tracing::info;
 
@@ -22,17 +28,30 @@ impl NylonRuntime {
     ///
     /// This method initializes the Pingora server with Nylon-specific configuration
     /// including HTTP/HTTPS listeners, TLS settings, and background services.
+    /// With `upgrade`, the listen sockets are taken over from the running
+    /// daemon instead of being bound.
     ///
     /// # Returns
     ///
     /// * `Result<Server, NylonError>` - The configured server instance or an error
-    pub fn new_server() -> Result<Server, NylonError> {
+    pub fn new_server(upgrade: bool) -> Result<Server, NylonError> {
         let config = RuntimeConfig::get()?;
         info!("Initializing Nylon server with configuration");
 
+        // Sockets from systemd socket activation, and listeners bound to an
+        // interface, are taken over like in an upgrade. In an upgrade the old
+        // process sends all of them.
+        let mut activated = vec![];
+        if !upgrade {
+            activated = systemd::activated_sockets(&config);
+            let prebound = listeners::prebind(&config, &activated)?;
+            activated.extend(prebound);
+        }
+
         // Create Pingora server with basic options
         let opt = Opt {
             daemon: config.pingora.daemon,
+            upgrade: upgrade || !activated.is_empty(),
             ..Default::default()
         };
 
@@ -42,6 +61,12 @@ impl NylonRuntime {
         // Configure server settings
         let conf = create_server_config(&config)?;
         pingora_server.configuration = conf.into();
+        if !activated.is_empty() {
+            listeners::hand_over(&mut pingora_server, activated);
+        } else {
+            // Keeps the listen sockets, so a later upgrade can pass them on
+            pingora_server.bootstrap();
+        }
 
         let runtime = NylonRuntime {};
 
@@ -57,6 +82,22 @@ impl NylonRuntime {
         let bg_service = background_service("NylonBackgroundService", NylonBackgroundService {});
         pingora_server.add_service(bg_service);
 
+        // Sample CPU and memory use for `load_shedding`
+        let sampler_service = background_service("LoadSamplerService", LoadSamplerService);
+        pingora_server.add_service(sampler_service);
+
+        // Add Prometheus metrics listeners
+        for addr in &config.metrics {
+            let mut metrics_service = Service::prometheus_http_service();
+            metrics_service.add_tcp(addr);
+            pingora_server.add_service(metrics_service);
+            info!("Metrics endpoint listening on: {}", addr);
+        }
+
+        // Add command socket service used by `nylon cert ...` and `nylon service reload`
+        let command_service = background_service("CommandSocketService", CommandSocketService);
+        pingora_server.add_service(command_service);
+
         info!("Nylon server initialization completed successfully");
         Ok(pingora_server)
     }
@@ -114,7 +155,10 @@ fn create_server_config(config: &RuntimeConfig) -> Result<ServerConf, NylonError
     Ok(conf)
 }
 
-/// Add HTTP service to the server
+/// Add HTTP services to the server
+///
+/// h2c is a setting of the whole service, so listeners with `h2: true` get
+/// their own.
 ///
 /// # Arguments
 ///
@@ -130,24 +174,41 @@ fn add_http_service(
     config: &RuntimeConfig,
     runtime: &NylonRuntime,
 ) -> Result<(), NylonError> {
-    let mut pingora_svc = proxy::http_proxy_service(&server.configuration, runtime.clone());
-
-    // Find and add zero address first (for binding to all interfaces)
-    if let Some(http_zero_addr) = config.http.iter().find(|a| a.contains("0.0.0.0")) {
-        pingora_svc.add_tcp(http_zero_addr);
-        info!("HTTP proxy server started on http://{}", http_zero_addr);
-    } else {
-        // Add all configured HTTP addresses
-        for addr in &config.http {
-            pingora_svc.add_tcp(addr);
-            info!("HTTP proxy server started on http://{}", addr);
+    let (h2c, h1): (Vec<_>, Vec<_>) = listeners::to_bind(&config.http)
+        .into_iter()
+        .partition(|l| l.h2 == Some(true));
+
+    for (group, h2c) in [(h1, false), (h2c, true)] {
+        if group.is_empty() {
+            continue;
+        }
+        let mut app = proxy::http_proxy(&server.configuration, runtime.clone());
+        if h2c {
+            app.server_options = Some(HttpServerOptions { h2c: true });
         }
+        let mut pingora_svc = proxy_service(app);
+        for listener in &group {
+            match listeners::socket_options(listener) {
+                Some(sock_opt) => pingora_svc.add_tcp_with_settings(&listener.address, sock_opt),
+                None => pingora_svc.add_tcp(&listener.address),
+            }
+            info!("HTTP proxy server started on http://{}", listener.address);
+        }
+        server.add_service(pingora_svc);
     }
-
-    server.add_service(pingora_svc);
     Ok(())
 }
 
+/// A proxy service that keeps the header deadline of `slow_clients`
+fn proxy_service(
+    app: proxy::HttpProxy<NylonRuntime>,
+) -> Service<HeaderDeadline<proxy::HttpProxy<NylonRuntime>>> {
+    Service::new(
+        "Pingora HTTP Proxy Service".to_string(),
+        HeaderDeadline::new(app),
+    )
+}
+
 /// Add HTTPS service to the server
 ///
 /// # Arguments
@@ -164,22 +225,16 @@ fn add_https_service(
     config: &RuntimeConfig,
     runtime: &NylonRuntime,
 ) -> Result<(), NylonError> {
-    let mut pingora_svc = proxy::http_proxy_service(&server.configuration, runtime.clone());
-
-    // Create TLS settings
-    let tls_settings = new_tls_settings()?;
-
-    // Find and add zero address first (for binding to all interfaces)
-    if let Some(https_zero_addr) = config.https.iter().find(|a| a.contains("0.0.0.0")) {
-        pingora_svc.add_tls_with_settings(https_zero_addr, None, tls_settings);
-        info!("HTTPS proxy server started on https://{}", https_zero_addr);
-    } else {
-        // Add all configured HTTPS addresses
-        for addr in &config.https {
-            let tls_settings = new_tls_settings()?;
-            pingora_svc.add_tls_with_settings(addr, None, tls_settings);
-            info!("HTTPS proxy server started on https://{}", addr);
-        }
+    let mut pingora_svc = proxy_service(proxy::http_proxy(&server.configuration, runtime.clone()));
+
+    for listener in listeners::to_bind(&config.https) {
+        let tls_settings = new_tls_settings(listener.h2.unwrap_or(true))?;
+        pingora_svc.add_tls_with_settings(
+            &listener.address,
+            listeners::socket_options(&listener),
+            tls_settings,
+        );
+        info!("HTTPS proxy server started on https://{}", listener.address);
     }
 
     server.add_service(pingora_svc);
@@ -0,0 +1,476 @@
+//! Limits against slow clients (`slow_clients` of a listener)
+//!
+//! pingora reads a request header before any hook runs, with its own
+//! timeout of 60 seconds per read. The header deadline is therefore kept by
+//! [`HeaderDeadline`], which wraps each accepted stream before pingora reads
+//! from it. The other limits are on bodies: they apply while the body is read
+//! for the upstream.
+
+use crate::listeners;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use nylon_config::runtime::{RuntimeConfig, SlowClientLimits};
+use nylon_error::NylonError;
+use nylon_types::context::NylonContext;
+use once_cell::sync::Lazy;
+use pingora::{
+    apps::ServerApp,
+    protocols::{
+        GetProxyDigest, GetSocketDigest, GetTimingDigest, IO, Peek, Shutdown, SocketDigest, Ssl,
+        Stream, TimingDigest, UniqueID, UniqueIDType, raw_connect::ProxyDigest, tls::TlsRef,
+    },
+    proxy::Session,
+    server::ShutdownWatch,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, RwLock, atomic::Ordering},
+    task::{Context, Poll, ready},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// Limits by listen port
+static LIMITS: Lazy<RwLock<HashMap<u16, SlowClientLimits>>> = Lazy::new(Default::default);
+
+/// Requests waiting for their body, by client IP
+static AWAITING_BODY: Lazy<DashMap<String, usize>> = Lazy::new(DashMap::new);
+
+/// Take the limits of the listeners; they are bound at startup
+pub fn configure(config: &RuntimeConfig) {
+    let mut limits = HashMap::new();
+    for options in listeners::to_bind(&config.http)
+        .into_iter()
+        .chain(listeners::to_bind(&config.https))
+    {
+        if let Some(slow_clients) = options.slow_clients
+            && let Some(port) = options
+                .address
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse::<u16>().ok())
+        {
+            limits.insert(port, slow_clients);
+        }
+    }
+    if let Ok(mut current) = LIMITS.write() {
+        *current = limits;
+    }
+}
+
+fn limits(session: &Session) -> Option<SlowClientLimits> {
+    let port = session.server_addr()?.as_inet()?.port();
+    LIMITS.read().ok()?.get(&port).cloned()
+}
+
+/// Header deadline of connections accepted on the stream's listen port
+fn header_timeout(stream: &dyn IO) -> Option<Duration> {
+    let digest = stream.get_socket_digest()?;
+    let port = digest.local_addr()?.as_inet()?.port();
+    let secs = LIMITS.read().ok()?.get(&port)?.header_timeout_secs?;
+    Some(Duration::from_secs(secs))
+}
+
+/// A server app that gives every request header `header_timeout_secs`
+///
+/// The clock of the first request starts once the connection is accepted,
+/// and that of a later one with its first byte. HTTP/2 connections are held
+/// to it until their preface is complete.
+pub struct HeaderDeadline<A> {
+    inner: Arc<A>,
+}
+
+impl<A> HeaderDeadline<A> {
+    pub fn new(inner: A) -> Self {
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for HeaderDeadline<A> {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let stream: Stream = if stream.as_any().is::<DeadlineStream>() {
+            // A kept-alive connection, back for its next request
+            let mut stream = stream.into_any().downcast::<DeadlineStream>().ok()?;
+            stream.next_request();
+            stream
+        } else if let Some(timeout) = header_timeout(&*stream) {
+            Box::new(DeadlineStream::new(stream, timeout))
+        } else {
+            stream
+        };
+        self.inner.process_new(stream, shutdown).await
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await
+    }
+}
+
+/// Where a connection is in reading its request header
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeaderState {
+    /// Between requests; the next byte starts a header
+    Idle,
+    /// In a header; `line_start` after a line feed
+    Reading { line_start: bool },
+    /// The header is complete
+    Done,
+}
+
+/// A stream that fails reads once its header deadline has passed
+#[derive(Debug)]
+struct DeadlineStream {
+    inner: Stream,
+    timeout: Duration,
+    state: HeaderState,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl DeadlineStream {
+    fn new(inner: Stream, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            state: HeaderState::Reading { line_start: false },
+            deadline: Some(Box::pin(tokio::time::sleep(timeout))),
+        }
+    }
+
+    fn next_request(&mut self) {
+        self.state = HeaderState::Idle;
+        self.deadline = None;
+    }
+
+    /// Follow the header through the bytes read, up to its empty line
+    fn saw(&mut self, read: &[u8]) {
+        if read.is_empty() {
+            return;
+        }
+        if self.state == HeaderState::Idle {
+            self.state = HeaderState::Reading { line_start: false };
+            self.deadline = Some(Box::pin(tokio::time::sleep(self.timeout)));
+        }
+        let HeaderState::Reading { mut line_start } = self.state else {
+            return;
+        };
+        for byte in read {
+            match byte {
+                b'\n' if line_start => {
+                    self.state = HeaderState::Done;
+                    self.deadline = None;
+                    return;
+                }
+                b'\n' => line_start = true,
+                b'\r' => {}
+                _ => line_start = false,
+            }
+        }
+        self.state = HeaderState::Reading { line_start };
+    }
+}
+
+impl AsyncRead for DeadlineStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deadline) = &mut this.deadline
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request header took too long",
+            )));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.saw(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DeadlineStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl Shutdown for DeadlineStream {
+    async fn shutdown(&mut self) {
+        self.inner.shutdown().await
+    }
+}
+
+impl UniqueID for DeadlineStream {
+    fn id(&self) -> UniqueIDType {
+        self.inner.id()
+    }
+}
+
+impl Ssl for DeadlineStream {
+    fn get_ssl(&self) -> Option<&TlsRef> {
+        self.inner.get_ssl()
+    }
+
+    fn get_ssl_digest(&self) -> Option<Arc<pingora::protocols::tls::SslDigest>> {
+        self.inner.get_ssl_digest()
+    }
+
+    fn selected_alpn_proto(&self) -> Option<pingora::protocols::ALPN> {
+        self.inner.selected_alpn_proto()
+    }
+}
+
+impl GetTimingDigest for DeadlineStream {
+    fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+        self.inner.get_timing_digest()
+    }
+
+    fn get_read_pending_time(&self) -> Duration {
+        self.inner.get_read_pending_time()
+    }
+
+    fn get_write_pending_time(&self) -> Duration {
+        self.inner.get_write_pending_time()
+    }
+}
+
+impl GetProxyDigest for DeadlineStream {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.inner.get_proxy_digest()
+    }
+
+    fn set_proxy_digest(&mut self, digest: ProxyDigest) {
+        self.inner.set_proxy_digest(digest)
+    }
+}
+
+impl GetSocketDigest for DeadlineStream {
+    fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+        self.inner.get_socket_digest()
+    }
+
+    fn set_socket_digest(&mut self, digest: SocketDigest) {
+        self.inner.set_socket_digest(digest)
+    }
+}
+
+#[async_trait]
+impl Peek for DeadlineStream {
+    async fn try_peek(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        self.inner.try_peek(buf).await
+    }
+}
+
+/// Check a request once its header is read
+pub fn check_request(session: &mut Session, ctx: &mut NylonContext) -> Result<(), NylonError> {
+    let Some(limits) = limits(session) else {
+        return Ok(());
+    };
+    if let Some(secs) = limits.body_timeout_secs {
+        session.set_read_timeout(Some(Duration::from_secs(secs)));
+    }
+    if let Some(max) = limits.max_idle_requests_per_ip
+        && !session.is_body_empty()
+    {
+        let mut waiting = AWAITING_BODY.entry(ctx.client_ip.clone()).or_insert(0);
+        if *waiting >= max {
+            drop(waiting);
+            session.set_keepalive(None);
+            return Err(NylonError::RateLimited(
+                "Too many requests waiting for their body".to_string(),
+            ));
+        }
+        *waiting += 1;
+        drop(waiting);
+        // Released under the same key even if the client IP is changed later
+        ctx.awaiting_body = Some(ctx.client_ip.clone());
+    }
+    Ok(())
+}
+
+/// Check the upload rate while the body is read
+pub fn check_body(
+    session: &mut Session,
+    ctx: &mut NylonContext,
+    end_of_stream: bool,
+) -> Result<(), NylonError> {
+    if end_of_stream {
+        release(ctx);
+        return Ok(());
+    }
+    let Some(limits) = limits(session) else {
+        return Ok(());
+    };
+    let Some(min_rate) = limits.min_body_rate else {
+        return Ok(());
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let elapsed_ms = now_ms.saturating_sub(ctx.request_timestamp.load(Ordering::Relaxed));
+    if elapsed_ms < limits.min_body_rate_grace_secs * 1000 {
+        return Ok(());
+    }
+    let rate = session.body_bytes_read() as u64 * 1000 / elapsed_ms.max(1);
+    if rate < min_rate {
+        session.set_keepalive(None);
+        return Err(NylonError::HttpException(
+            408,
+            "REQUEST_TIMEOUT",
+            "Request body is sent too slowly",
+        ));
+    }
+    Ok(())
+}
+
+/// Stop counting the request against its client IP
+pub fn release(ctx: &mut NylonContext) {
+    let Some(client_ip) = ctx.awaiting_body.take() else {
+        return;
+    };
+    AWAITING_BODY.remove_if_mut(&client_ip, |_, waiting| {
+        *waiting = waiting.saturating_sub(1);
+        *waiting == 0
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn deadline_stream(timeout_ms: u64) -> (DuplexStream, DeadlineStream) {
+        let (client, server) = duplex(1024);
+        let server = DeadlineStream::new(Box::new(server), Duration::from_millis(timeout_ms));
+        (client, server)
+    }
+
+    #[test]
+    fn test_header_deadline_cuts_off_a_trickled_header() {
+        block_on(async {
+            let (mut client, mut server) = deadline_stream(200);
+            let mut buf = [0u8; 64];
+            // Each byte comes well within any per-read timeout
+            let err = loop {
+                client.write_all(b"G").await.unwrap();
+                match server.read(&mut buf).await {
+                    Ok(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        });
+    }
+
+    #[test]
+    fn test_header_deadline_ends_with_the_header() {
+        block_on(async {
+            let (mut client, mut server) = deadline_stream(100);
+            let mut buf = [0u8; 64];
+            client
+                .write_all(b"POST / HTTP/1.1\r\nHost: a\r\n\r\n")
+                .await
+                .unwrap();
+            server.read(&mut buf).await.unwrap();
+            assert_eq!(server.state, HeaderState::Done);
+            assert!(server.deadline.is_none());
+
+            // A slow body is left to the body limits
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            client.write_all(b"body").await.unwrap();
+            assert_eq!(server.read(&mut buf).await.unwrap(), 4);
+
+            // The next request on the connection gets a new deadline from its first byte
+            server.next_request();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            client.write_all(b"GET / HTTP/1.1\n").await.unwrap();
+            server.read(&mut buf).await.unwrap();
+            assert_eq!(server.state, HeaderState::Reading { line_start: true });
+            client.write_all(b"\n").await.unwrap();
+            server.read(&mut buf).await.unwrap();
+            assert_eq!(server.state, HeaderState::Done);
+        });
+    }
+
+    #[test]
+    fn test_header_end_split_over_reads() {
+        block_on(async {
+            let (_client, mut server) = deadline_stream(1000);
+            for part in [&b"GET / HTTP/1.1\r"[..], b"\n", b"\r", b"\n"] {
+                assert_ne!(server.state, HeaderState::Done);
+                server.saw(part);
+            }
+            assert_eq!(server.state, HeaderState::Done);
+        });
+    }
+
+    #[test]
+    fn test_release_uses_the_counted_client_ip() {
+        AWAITING_BODY.insert("192.0.2.1".to_string(), 2);
+        let mut ctx = NylonContext {
+            awaiting_body: Some("192.0.2.1".to_string()),
+            client_ip: "198.51.100.7".to_string(),
+            ..Default::default()
+        };
+        release(&mut ctx);
+        assert_eq!(AWAITING_BODY.get("192.0.2.1").map(|w| *w), Some(1));
+        assert!(ctx.awaiting_body.is_none());
+
+        // Released once only
+        release(&mut ctx);
+        assert_eq!(AWAITING_BODY.get("192.0.2.1").map(|w| *w), Some(1));
+
+        ctx.awaiting_body = Some("192.0.2.1".to_string());
+        release(&mut ctx);
+        assert!(!AWAITING_BODY.contains_key("192.0.2.1"));
+    }
+}
@@ -0,0 +1,636 @@
+//! Files of a static service
+//!
+//! Responses carry `ETag` and `Last-Modified`, answer `If-None-Match` and
+//! `If-Modified-Since` with `304`, and a single `Range` (honoring `If-Range`)
+//! with `206`. Bodies are streamed from disk by
+//! [`Response::send_file`](crate::response::Response::send_file).
+//!
+//! With `precompressed`, a `.br` or `.gz` file next to the requested one is
+//! sent instead to clients that accept it; with `compress_min_bytes`, other
+//! text-like files of at least that size are gzipped on the fly.
+//! `autoindex` lists directories that have no index file, on a page from
+//! `autoindex_template` or a plain default.
+//!
+//! Paths matching `deny`, and names starting with a dot unless `dotfiles` is
+//! set, are answered as missing before the disk is touched; `allow` exempts
+//! paths from both.
+
+use crate::error_page::escape_html;
+use chrono::{DateTime, Utc};
+use nylon_types::{
+    context::NylonContext,
+    services::StaticConfig,
+    template::{Expr, eval_expr, extract_and_parse_templates, url_decode, url_encode},
+};
+use pingora::http::RequestHeader;
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::Path,
+};
+use tokio::{fs::File, io::AsyncSeekExt};
+
+pub struct StaticResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    /// The file, positioned at the first byte to send, and how many bytes to send
+    pub body: Option<(File, u64)>,
+    /// Gzip the body while sending it
+    pub gzip: bool,
+}
+
+/// Answer a request for `path`, a file under the service root, for `request_path`
+pub async fn serve(
+    req: &RequestHeader,
+    path: &Path,
+    conf: &StaticConfig,
+    request_path: &str,
+) -> io::Result<StaticResponse> {
+    let (mut file, meta, precompressed) = open(req, path, conf).await?;
+    let len = meta.len();
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    // Ranges are of the bytes on disk, so a gzipped body has none
+    let gzip = precompressed.is_none()
+        && header(req, "range").is_none()
+        && conf.compress_min_bytes.is_some_and(|min| len >= min)
+        && accepts(req, "gzip")
+        && compressible(mime.essence_str());
+    let encoding = precompressed.or(gzip.then_some("gzip"));
+
+    let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+    let etag = format!(
+        "\"{:x}-{:x}{}\"",
+        modified.map(|m| m.timestamp()).unwrap_or_default(),
+        len,
+        encoding.map(|e| format!("-{}", e)).unwrap_or_default()
+    );
+    let last_modified = modified.map(|m| m.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+
+    let mut headers = vec![("ETag", etag.clone())];
+    if !gzip {
+        headers.push(("Accept-Ranges", "bytes".to_string()));
+    }
+    if let Some(last_modified) = &last_modified {
+        headers.push(("Last-Modified", last_modified.clone()));
+    }
+    if let Some(cache_control) = cache_control(conf, request_path) {
+        headers.push(("Cache-Control", cache_control.to_string()));
+    }
+    if conf.precompressed.unwrap_or(false) || conf.compress_min_bytes.is_some() {
+        headers.push(("Vary", "Accept-Encoding".to_string()));
+    }
+    if let Some(encoding) = encoding {
+        headers.push(("Content-Encoding", encoding.to_string()));
+    }
+    if not_modified(req, &etag, modified) {
+        return Ok(StaticResponse {
+            status: 304,
+            headers,
+            body: None,
+            gzip: false,
+        });
+    }
+
+    headers.push(("Content-Type", mime.to_string()));
+    let range = header(req, "range")
+        .filter(|_| if_range_holds(req, &etag, last_modified.as_deref()))
+        .and_then(|range| parse_range(range, len));
+    match range {
+        Some(Some((start, end))) => {
+            file.seek(SeekFrom::Start(start)).await?;
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, len)));
+            Ok(StaticResponse {
+                status: 206,
+                headers,
+                body: Some((file, end - start + 1)),
+                gzip: false,
+            })
+        }
+        Some(None) => {
+            headers.push(("Content-Range", format!("bytes */{}", len)));
+            headers.push(("Content-Length", "0".to_string()));
+            Ok(StaticResponse {
+                status: 416,
+                headers,
+                body: None,
+                gzip: false,
+            })
+        }
+        None => Ok(StaticResponse {
+            status: 200,
+            headers,
+            body: Some((file, len)),
+            gzip,
+        }),
+    }
+}
+
+/// Whether `path`, relative to the service root, may be served
+pub fn permitted(conf: &StaticConfig, path: &str) -> bool {
+    let path = format!("/{}", path.trim_start_matches('/'));
+    let matches = |patterns: &Option<Vec<String>>| {
+        patterns
+            .iter()
+            .flatten()
+            .any(|pattern| pattern_matches(pattern, &path))
+    };
+    if matches(&conf.allow) {
+        return true;
+    }
+    let dotfile = path
+        .split('/')
+        .any(|name| name.starts_with('.') && name != "." && name != "..");
+    (!dotfile || conf.dotfiles.unwrap_or(false)) && !matches(&conf.deny)
+}
+
+/// A pattern with a `/` matches the whole path, one without matches any name in it
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        let pattern = format!("/{}", pattern.trim_start_matches('/'));
+        glob(pattern.as_bytes(), path.as_bytes())
+    } else {
+        path.split('/')
+            .any(|name| glob(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+/// `*` and `?` stay within a name, `**` spans directories
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        // `**/` is any number of whole directories, including none
+        [b'*', b'*', rest @ ..] => match rest.strip_prefix(b"/") {
+            Some(after) => {
+                glob(after, text)
+                    || (0..text.len()).any(|i| text[i] == b'/' && glob(after, &text[i + 1..]))
+            }
+            None => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        },
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob(rest, &text[i..])),
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob(rest, tail)),
+    }
+}
+
+/// Page of `autoindex` listings without an `autoindex_template`
+const LISTING_TEMPLATE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of ${listing(title)}</title></head>\n<body><h1>Index of ${listing(title)}</h1>\n<table>\n<tr><th>Name</th><th>Last modified</th><th>Size</th></tr>\n${listing(rows)}</table></body></html>\n";
+
+/// An HTML listing of `dir` for `autoindex`, without entries that are not served
+pub async fn listing(
+    dir: &Path,
+    conf: &StaticConfig,
+    path: &str,
+    req: &RequestHeader,
+    ctx: &NylonContext,
+) -> io::Result<String> {
+    let mut entries = vec![];
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !permitted(conf, &format!("{}/{}", path.trim_end_matches('/'), name)) {
+            continue;
+        }
+        let Ok(meta) = entry.metadata().await else {
+            continue;
+        };
+        entries.push((meta.is_dir(), name, meta));
+    }
+    // Directories first, then by name
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    // The request path is still percent-encoded, so it only needs HTML escaping
+    let base = req.uri.path().trim_end_matches('/');
+    let mut rows = String::new();
+    if !base.is_empty() {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (is_dir, name, meta) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        let size = if is_dir {
+            "-".to_string()
+        } else {
+            meta.len().to_string()
+        };
+        let modified = meta
+            .modified()
+            .ok()
+            .map(|m| {
+                DateTime::<Utc>::from(m)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(base),
+            url_encode(&name),
+            slash,
+            escape_html(&name),
+            slash,
+            modified,
+            size
+        ));
+    }
+
+    let template = conf
+        .autoindex_template
+        .as_deref()
+        .unwrap_or(LISTING_TEMPLATE);
+    let exprs = extract_and_parse_templates(template).map_err(io::Error::other)?;
+    let title = format!("{}/", url_decode(base));
+    let mut page = String::new();
+    for expr in &exprs {
+        match expr {
+            Expr::Literal(text) => page.push_str(text),
+            Expr::Func { name, args } if name == "listing" => match args.first() {
+                Some(Expr::Request(field)) if field == "title" => {
+                    page.push_str(&escape_html(&title))
+                }
+                Some(Expr::Request(field)) if field == "rows" => page.push_str(&rows),
+                _ => {}
+            },
+            _ => page.push_str(&escape_html(&eval_expr(expr, req, ctx))),
+        }
+    }
+    Ok(page)
+}
+
+/// The file to send: a precompressed sibling the client accepts, or the file itself
+async fn open(
+    req: &RequestHeader,
+    path: &Path,
+    conf: &StaticConfig,
+) -> io::Result<(File, Metadata, Option<&'static str>)> {
+    if conf.precompressed.unwrap_or(false) {
+        for (encoding, extension) in [("br", "br"), ("gzip", "gz")] {
+            if !accepts(req, encoding) {
+                continue;
+            }
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(".");
+            sibling.push(extension);
+            if let Ok(file) = File::open(&sibling).await
+                && let Ok(meta) = file.metadata().await
+                && meta.is_file()
+            {
+                return Ok((file, meta, Some(encoding)));
+            }
+        }
+    }
+    let file = File::open(path).await?;
+    let meta = file.metadata().await?;
+    if !meta.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok((file, meta, None))
+}
+
+/// Whether `Accept-Encoding` lists `coding` with a non-zero quality
+fn accepts(req: &RequestHeader, coding: &str) -> bool {
+    header(req, "accept-encoding").is_some_and(|value| {
+        value.split(',').any(|part| {
+            let mut params = part.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(coding) && quality > 0.0
+        })
+    })
+}
+
+fn compressible(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || ["json", "javascript", "xml", "svg", "wasm"]
+            .iter()
+            .any(|kind| mime.contains(kind))
+}
+
+/// The `Cache-Control` rule of the service for a request path
+fn cache_control<'a>(conf: &'a StaticConfig, path: &str) -> Option<&'a str> {
+    conf.cache_control.iter().flatten().find_map(|rule| {
+        let matched = match rule.path.strip_prefix('*') {
+            Some(suffix) => path.ends_with(suffix),
+            None => path.starts_with(&rule.path),
+        };
+        matched.then_some(rule.value.as_str())
+    })
+}
+
+fn header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn not_modified(req: &RequestHeader, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    // If-Modified-Since only counts without If-None-Match
+    if let Some(tags) = header(req, "if-none-match") {
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = header(req, "if-modified-since").and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+/// A range only applies while the file still matches `If-Range`
+fn if_range_holds(req: &RequestHeader, etag: &str, last_modified: Option<&str>) -> bool {
+    match header(req, "if-range") {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => Some(value) == last_modified,
+    }
+}
+
+/// A single `bytes=` range as inclusive offsets
+///
+/// `None` ignores the header (malformed, or several ranges), `Some(None)` is
+/// a range outside of the file.
+fn parse_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(None);
+        }
+        return Some(Some((len.saturating_sub(suffix), len - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse().ok()?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= len {
+        return Some(None);
+    }
+    Some(Some((start, end.min(len - 1))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/file", None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn config(value: serde_json::Value) -> StaticConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accepts_gzip = |value: &str| accepts(&request(&[("accept-encoding", value)]), "gzip");
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("br, GZIP;q=0.5"));
+        assert!(accepts_gzip("deflate, gzip ; q=1"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("br, deflate"));
+        assert!(!accepts_gzip("x-gzip"));
+        assert!(!accepts(&request(&[]), "gzip"));
+    }
+
+    #[test]
+    fn test_compressible() {
+        for mime in [
+            "text/html",
+            "text/css",
+            "application/json",
+            "application/javascript",
+            "image/svg+xml",
+            "application/wasm",
+        ] {
+            assert!(compressible(mime), "{}", mime);
+        }
+        for mime in ["image/png", "video/mp4", "application/zip", "font/woff2"] {
+            assert!(!compressible(mime), "{}", mime);
+        }
+    }
+
+    #[test]
+    fn test_open_prefers_accepted_siblings() {
+        let dir = std::env::temp_dir().join(format!("nylon-static-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let path = dir.join("app.js");
+        std::fs::write(&path, "plain").unwrap();
+        std::fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        std::fs::write(dir.join("app.js.br"), "brotli").unwrap();
+
+        let encoding = |conf: &StaticConfig, accept: &str| {
+            let req = request(&[("accept-encoding", accept)]);
+            block_on(open(&req, &path, conf)).map(|(_, meta, encoding)| (meta.len(), encoding))
+        };
+        let on = config(serde_json::json!({"root": "/", "precompressed": true}));
+        let off = config(serde_json::json!({"root": "/"}));
+        assert_eq!(encoding(&on, "gzip, br").unwrap(), (6, Some("br")));
+        assert_eq!(encoding(&on, "gzip").unwrap(), (7, Some("gzip")));
+        assert_eq!(encoding(&on, "deflate").unwrap(), (5, None));
+        assert_eq!(encoding(&off, "gzip, br").unwrap(), (5, None));
+
+        // a directory is not a file
+        let req = request(&[]);
+        let err = block_on(open(&req, &dir.join("sub"), &off)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_listing_escapes_names_and_uses_the_template() {
+        let dir = std::env::temp_dir().join(format!("nylon-listing-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub dir")).unwrap();
+        std::fs::write(dir.join("<b>&.txt"), "x").unwrap();
+        std::fs::write(dir.join(".env"), "secret").unwrap();
+
+        let req = RequestHeader::build("GET", b"/files%20old/", None).unwrap();
+        let ctx = NylonContext::default();
+        let conf = config(serde_json::json!({"root": "/", "autoindex": true}));
+        let page = block_on(listing(&dir, &conf, "/", &req, &ctx)).unwrap();
+        assert!(
+            page.contains("<title>Index of /files old/</title>"),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains("<a href=\"/files%20old/sub%20dir/\">sub dir/</a>"),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains("<a href=\"/files%20old/%3Cb%3E%26.txt\">&lt;b&gt;&amp;.txt</a>"),
+            "{}",
+            page
+        );
+        assert!(!page.contains(".env"), "{}", page);
+        // directories come first
+        assert!(page.find("sub dir").unwrap() < page.find("&lt;b&gt;").unwrap());
+
+        let conf = config(serde_json::json!({
+            "root": "/",
+            "autoindex": true,
+            "autoindex_template": "<h1>${listing(title)} ${path()}</h1><table>${listing(rows)}</table>",
+        }));
+        let req = RequestHeader::build("GET", b"/%3Cx%3E/", None).unwrap();
+        let page = block_on(listing(&dir, &conf, "/", &req, &ctx)).unwrap();
+        assert!(
+            page.starts_with("<h1>/&lt;x&gt;/ /%3Cx%3E/</h1><table><tr><td><a href=\"../\">"),
+            "{}",
+            page
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dotfiles_are_refused() {
+        let conf = config(serde_json::json!({"root": "/"}));
+        assert!(!permitted(&conf, "/.git/config"));
+        assert!(!permitted(&conf, "/app/.env"));
+        assert!(!permitted(&conf, ".htpasswd"));
+        assert!(permitted(&conf, "/app/index.html"));
+        assert!(permitted(&conf, "/app/./index.html"));
+
+        let conf = config(serde_json::json!({"root": "/", "dotfiles": true}));
+        assert!(permitted(&conf, "/.git/config"));
+    }
+
+    #[test]
+    fn test_allow_exempts_from_deny_and_dotfiles() {
+        let conf = config(serde_json::json!({
+            "root": "/",
+            "deny": ["/.git/**", "*.php"],
+            "allow": ["/.well-known/**"],
+        }));
+        assert!(permitted(&conf, "/.well-known/acme-challenge/token"));
+        assert!(!permitted(&conf, "/.well-known"));
+        assert!(!permitted(&conf, "/.git/HEAD"));
+        assert!(!permitted(&conf, "/a/.well-known/token"));
+    }
+
+    #[test]
+    fn test_patterns_without_a_slash_match_any_name() {
+        let conf = config(serde_json::json!({"root": "/", "deny": ["*.php", "backup?"]}));
+        assert!(!permitted(&conf, "/index.php"));
+        assert!(!permitted(&conf, "/a/b/c/index.php"));
+        assert!(!permitted(&conf, "/backup1/file"));
+        assert!(permitted(&conf, "/index.php.txt"));
+        assert!(permitted(&conf, "/php/index.html"));
+        assert!(permitted(&conf, "/backup12/file"));
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |pattern: &str, text: &str| glob(pattern.as_bytes(), text.as_bytes());
+        // `**/` matches no directory as well as several
+        assert!(glob("/a/**/b.txt", "/a/b.txt"));
+        assert!(glob("/a/**/b.txt", "/a/x/b.txt"));
+        assert!(glob("/a/**/b.txt", "/a/x/y/b.txt"));
+        assert!(!glob("/a/**/b.txt", "/ab.txt"));
+        assert!(glob("/backup/**", "/backup/"));
+        assert!(glob("/backup/**", "/backup/x/y"));
+        assert!(!glob("/backup/**", "/backups/x"));
+        // `*` and `?` stay within a name
+        assert!(glob("/*.php", "/index.php"));
+        assert!(!glob("/*.php", "/a/index.php"));
+        assert!(glob("/a?c", "/abc"));
+        assert!(!glob("/a?c", "/a/c"));
+        assert!(glob("**", ""));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some((900, 999))));
+        // past the end is clamped, a larger suffix is the whole file
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(Some((990, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Some((0, 999))));
+        // unsatisfiable
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), Some(None));
+        // ignored
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=9-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = "\"5f5e100-3e8\"";
+        let modified = DateTime::parse_from_rfc2822("Tue, 01 Sep 2020 10:00:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let check =
+            |headers: &[(&str, &str)]| not_modified(&request(headers), etag, Some(modified));
+
+        assert!(!check(&[]));
+        assert!(check(&[("if-none-match", etag)]));
+        assert!(check(&[("if-none-match", "\"other\", W/\"5f5e100-3e8\"")]));
+        assert!(check(&[("if-none-match", "*")]));
+        assert!(!check(&[("if-none-match", "\"other\"")]));
+
+        assert!(check(&[(
+            "if-modified-since",
+            "Tue, 01 Sep 2020 10:00:00 GMT"
+        )]));
+        assert!(check(&[(
+            "if-modified-since",
+            "Wed, 02 Sep 2020 10:00:00 GMT"
+        )]));
+        assert!(!check(&[(
+            "if-modified-since",
+            "Mon, 31 Aug 2020 10:00:00 GMT"
+        )]));
+        assert!(!check(&[("if-modified-since", "yesterday")]));
+        // If-None-Match wins over If-Modified-Since
+        assert!(!check(&[
+            ("if-none-match", "\"other\""),
+            ("if-modified-since", "Wed, 02 Sep 2020 10:00:00 GMT"),
+        ]));
+    }
+
+    #[test]
+    fn test_if_range_holds() {
+        let etag = "\"5f5e100-3e8\"";
+        let last_modified = Some("Tue, 01 Sep 2020 10:00:00 GMT");
+        let holds = |value: &str| {
+            let req = request(&[("if-range", value)]);
+            if_range_holds(&req, etag, last_modified)
+        };
+        assert!(if_range_holds(&request(&[]), etag, last_modified));
+        assert!(holds(etag));
+        assert!(!holds("\"changed\""));
+        assert!(holds("Tue, 01 Sep 2020 10:00:00 GMT"));
+        assert!(!holds("Mon, 31 Aug 2020 10:00:00 GMT"));
+        assert!(!if_range_holds(
+            &request(&[("if-range", "Tue, 01 Sep 2020 10:00:00 GMT")]),
+            etag,
+            None
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! systemd integration
+//!
+//! - Listen sockets passed by socket activation (`LISTEN_FDS`) are used for
+//!   the `http`, `https` and `metrics` addresses they are bound to, instead
+//!   of binding those addresses again (see [`crate::listeners::hand_over`]).
+//! - `READY=1`, `RELOADING=1` and `STOPPING=1` are sent to `NOTIFY_SOCKET`,
+//!   so `Type=notify` and `Type=notify-reload` units follow the proxy.
+//!
+//! Both are no-ops outside of Linux or when systemd did not set the variables.
+
+use nylon_config::runtime::RuntimeConfig;
+use std::ops::Range;
+use tracing::warn;
+
+/// Tell systemd about a state change, e.g. `READY=1`
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        warn!("Failed to notify systemd ({}): {}", state, e);
+    }
+}
+
+/// The server started, or finished a reload
+pub fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// A reload started; `notify-reload` units wait for the next `READY=1`
+pub fn reloading() {
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::{
+        ffi::OsStrExt,
+        net::{SocketAddr, UnixDatagram},
+    };
+
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn monotonic_usec() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec to write to
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+#[cfg(not(target_os = "linux"))]
+fn monotonic_usec() -> u64 {
+    0
+}
+
+/// Sockets passed by socket activation, keyed by the configured address they are bound to
+#[cfg(target_os = "linux")]
+pub fn activated_sockets(config: &RuntimeConfig) -> Vec<(String, i32)> {
+    use std::net::{SocketAddr, TcpListener};
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let fds = passed_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    if fds.is_empty() {
+        return vec![];
+    }
+
+    let configured: Vec<&str> = config
+        .http
+        .iter()
+        .chain(&config.https)
+        .map(|l| l.address())
+        .chain(config.metrics.iter().map(String::as_str))
+        .collect();
+    let mut sockets = vec![];
+    for fd in fds {
+        // SAFETY: systemd passes the descriptor to this process; it is given
+        // back with into_raw_fd before the listener is dropped
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // pingora hands the socket to tokio, which needs it non-blocking
+        let local = listener
+            .set_nonblocking(true)
+            .and_then(|_| listener.local_addr());
+        let fd = listener.into_raw_fd();
+        let Ok(local) = local else {
+            warn!(
+                "Socket activation: fd {} is not a TCP listener, ignored",
+                fd
+            );
+            continue;
+        };
+        match configured
+            .iter()
+            .find(|addr| addr.parse::<SocketAddr>().ok() == Some(local))
+        {
+            Some(addr) => sockets.push((addr.to_string(), fd)),
+            None => warn!(
+                "Socket activation: {} is not in http, https or metrics, ignored",
+                local
+            ),
+        }
+    }
+    sockets
+}
+
+/// Descriptors systemd passed, from `LISTEN_PID` and `LISTEN_FDS`
+///
+/// Empty unless the variables are meant for process `pid`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<i32> {
+    // The first passed descriptor is always 3
+    const LISTEN_FDS_START: i32 = 3;
+
+    let for_us = listen_pid
+        .and_then(|p| p.trim().parse::<u32>().ok())
+        .is_some_and(|p| p == pid);
+    let count = listen_fds
+        .and_then(|n| n.trim().parse::<i32>().ok())
+        .filter(|n| *n > 0);
+    match (for_us, count) {
+        (true, Some(count)) => LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count),
+        _ => 0..0,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn activated_sockets(_config: &RuntimeConfig) -> Vec<(String, i32)> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passed_fds() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42), 3..5);
+        assert_eq!(passed_fds(Some("42"), Some("1"), 42), 3..4);
+        assert_eq!(passed_fds(Some(" 42\n"), Some("1"), 42), 3..4);
+    }
+
+    #[test]
+    fn test_fds_for_another_process_are_ignored() {
+        assert!(passed_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(passed_fds(None, Some("2"), 42).is_empty());
+        assert!(passed_fds(Some("pid"), Some("2"), 42).is_empty());
+    }
+
+    #[test]
+    fn test_bad_counts_pass_nothing() {
+        assert!(passed_fds(Some("42"), None, 42).is_empty());
+        assert!(passed_fds(Some("42"), Some("0"), 42).is_empty());
+        assert!(passed_fds(Some("42"), Some("-1"), 42).is_empty());
+        assert!(passed_fds(Some("42"), Some("two"), 42).is_empty());
+        assert_eq!(passed_fds(Some("42"), Some("2147483647"), 42).end, i32::MAX);
+    }
+}
@@ -0,0 +1,119 @@
+//! OpenTelemetry Tracing
+//!
+//! Sets up the tracing subscriber (optionally with an OTLP exporter) and
+//! provides helpers to continue incoming W3C `traceparent` contexts and to
+//! hand the proxy's own span context to upstreams and plugins.
+
+use nylon_config::runtime::TracingConfig;
+use once_cell::sync::OnceCell;
+use opentelemetry::{global, trace::TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+};
+use pingora::http::RequestHeader;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+static TRACER_PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Initialize logging, plus OTLP trace export when configured
+pub fn init(config: Option<&TracingConfig>) {
+    let fmt_layer = tracing_subscriber::fmt::layer();
+    let registry = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(fmt_layer);
+
+    let Some(config) = config else {
+        registry.init();
+        return;
+    };
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            registry.init();
+            tracing::error!("Failed to create OTLP exporter: {}", e);
+            return;
+        }
+    };
+
+    let provider = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio.clamp(0.0, 1.0),
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .with_batch_exporter(exporter)
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer("nylon");
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+
+    let _ = TRACER_PROVIDER.set(provider);
+    tracing::info!("OpenTelemetry tracing enabled ({})", config.otlp_endpoint);
+}
+
+/// Flush pending spans before exit
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        eprintln!("Failed to shutdown tracer provider: {}", e);
+    }
+}
+
+/// Whether spans are exported
+pub fn enabled() -> bool {
+    TRACER_PROVIDER.get().is_some()
+}
+
+/// Create the root span of a request, continuing an incoming `traceparent`
+pub fn request_span(req: &RequestHeader) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method, req.uri.path()),
+        otel.kind = "server",
+        http.request.method = %req.method,
+        url.path = %req.uri.path(),
+        http.route = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+    );
+
+    if enabled() {
+        let carrier: HashMap<String, String> = req
+            .headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_str().to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let parent = global::get_text_map_propagator(|p| p.extract(&carrier));
+        let _ = span.set_parent(parent);
+    }
+    span
+}
+
+/// Overwrite the W3C trace headers so the next hop becomes a child of `span`
+pub fn inject(span: &Span, req: &mut RequestHeader) {
+    if !enabled() {
+        return;
+    }
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|p| p.inject_context(&span.context(), &mut carrier));
+    for (key, value) in carrier {
+        let _ = req.insert_header(key, value);
+    }
+}
@@ -0,0 +1,221 @@
+//! Graceful binary upgrade (`nylon service upgrade`)
+//!
+//! The daemon starts the new binary with `run --upgrade` on its own config
+//! file and waits until it listens on `pingora.upgrade_sock`. It then raises
+//! `SIGQUIT` on itself: pingora sends the listen sockets over and the old
+//! process finishes its connections for `grace_period_seconds` before it
+//! exits, so no connection is refused while the binaries change places.
+
+use nylon_error::NylonError;
+
+/// How long the new binary gets to load its config and ask for the sockets
+#[cfg(unix)]
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[cfg(unix)]
+static UPGRADING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Set once the sockets were handed over; the new process now owns the service
+#[cfg(unix)]
+static HANDED_OVER: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Whether this process is shutting down because a new binary took over
+///
+/// systemd must not hear `STOPPING=1` then: with `NotifyAccess=all` it would
+/// stop the unit and kill the new main process.
+#[cfg(unix)]
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(std::sync::atomic::Ordering::SeqCst)
+}
+
+#[cfg(not(unix))]
+pub fn handed_over() -> bool {
+    false
+}
+
+/// Hand the listen sockets to `binary`; returns the pid of the new process
+#[cfg(unix)]
+pub async fn start(binary: &str) -> Result<u32, NylonError> {
+    use std::sync::atomic::Ordering;
+
+    if UPGRADING.swap(true, Ordering::SeqCst) {
+        return Err(NylonError::RuntimeError(
+            "An upgrade is already in progress".to_string(),
+        ));
+    }
+    let result = hand_over(binary).await;
+    if result.is_err() {
+        UPGRADING.store(false, Ordering::SeqCst);
+    }
+    result
+}
+
+#[cfg(unix)]
+async fn hand_over(binary: &str) -> Result<u32, NylonError> {
+    use nylon_config::runtime::RuntimeConfig;
+    use pingora::server::configuration::ServerConf;
+    use std::{process::Command, sync::atomic::Ordering};
+    use tracing::info;
+
+    let config_path = nylon_store::get::<String>(nylon_store::KEY_CONFIG_PATH)
+        .ok_or_else(|| NylonError::RuntimeError("Config path not found".to_string()))?;
+    let upgrade_sock = RuntimeConfig::get()?
+        .pingora
+        .upgrade_sock
+        .and_then(|p| p.to_str().filter(|s| !s.is_empty()).map(String::from))
+        .unwrap_or_else(|| ServerConf::default().upgrade_sock);
+
+    // The new process binds the socket once it is ready for the handover
+    let _ = std::fs::remove_file(&upgrade_sock);
+    let mut child = Command::new(binary)
+        .arg("run")
+        .arg("-c")
+        .arg(&config_path)
+        .arg("--upgrade")
+        .spawn()
+        .map_err(|e| NylonError::RuntimeError(format!("Failed to start {}: {}", binary, e)))?;
+    let pid = child.id();
+    info!("Upgrade: started {} as pid {}", binary, pid);
+
+    wait_for_listener(&mut child, &upgrade_sock, STARTUP_TIMEOUT).await?;
+
+    info!("Upgrade: handing the listen sockets to pid {}", pid);
+    HANDED_OVER.store(true, Ordering::SeqCst);
+    // SAFETY: kill(2) with our own pid and a signal pingora handles
+    if unsafe { libc::kill(std::process::id() as libc::pid_t, libc::SIGQUIT) } != 0 {
+        HANDED_OVER.store(false, Ordering::SeqCst);
+        return Err(NylonError::RuntimeError(format!(
+            "Failed to signal the handover: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(pid)
+}
+
+/// Wait until the new process listens on `upgrade_sock`; it is killed if it takes too long
+#[cfg(unix)]
+async fn wait_for_listener(
+    child: &mut std::process::Child,
+    upgrade_sock: &str,
+    timeout: std::time::Duration,
+) -> Result<(), NylonError> {
+    use std::{path::Path, time::Instant};
+
+    let started = Instant::now();
+    while !Path::new(upgrade_sock).exists() {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(NylonError::RuntimeError(format!(
+                "New binary exited before taking over: {}",
+                status
+            )));
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(NylonError::RuntimeError(format!(
+                "New binary did not listen on {} in time",
+                upgrade_sock
+            )));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn start(_binary: &str) -> Result<u32, NylonError> {
+    Err(NylonError::RuntimeError(
+        "Graceful upgrade is only supported on Unix".to_string(),
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn sh(script: &str) -> std::process::Child {
+        Command::new("sh").arg("-c").arg(script).spawn().unwrap()
+    }
+
+    #[test]
+    fn test_wait_for_listener() {
+        let sock = std::env::temp_dir().join(format!("nylon-upgrade-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&sock);
+        let sock = sock.to_string_lossy().to_string();
+        let mut child = sh(&format!("sleep 0.2; touch '{}'; sleep 5", sock));
+        block_on(wait_for_listener(
+            &mut child,
+            &sock,
+            Duration::from_secs(10),
+        ))
+        .unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = std::fs::remove_file(&sock);
+    }
+
+    #[test]
+    fn test_wait_for_listener_fails_when_the_binary_exits() {
+        let mut child = sh("exit 3");
+        let err = block_on(wait_for_listener(
+            &mut child,
+            "/nonexistent/nylon-upgrade.sock",
+            Duration::from_secs(10),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("exited before taking over"));
+    }
+
+    #[test]
+    fn test_wait_for_listener_kills_a_slow_binary() {
+        let mut child = sh("sleep 30");
+        let err = block_on(wait_for_listener(
+            &mut child,
+            "/nonexistent/nylon-upgrade.sock",
+            Duration::from_millis(300),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("in time"));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_failed_upgrade_is_not_a_handover() {
+        let sock =
+            std::env::temp_dir().join(format!("nylon-upgrade-failed-{}.sock", std::process::id()));
+        let config: nylon_config::runtime::RuntimeConfig =
+            format!("pingora:\n  upgrade_sock: {}\n", sock.display())
+                .parse()
+                .unwrap();
+        config.store().unwrap();
+        nylon_store::insert(
+            nylon_store::KEY_CONFIG_PATH,
+            "/nonexistent/nylon.yaml".to_string(),
+        );
+
+        // The config is found, so it is the spawn that fails
+        let first = block_on(start("/nonexistent/nylon")).unwrap_err();
+        assert!(
+            first
+                .to_string()
+                .contains("Failed to start /nonexistent/nylon"),
+            "{first}"
+        );
+        assert!(!handed_over());
+        // The failed attempt does not block the next one
+        let second = block_on(start("/nonexistent/nylon")).unwrap_err();
+        assert!(!second.to_string().contains("already in progress"));
+        assert_eq!(first.to_string(), second.to_string());
+        assert!(!handed_over());
+    }
+}
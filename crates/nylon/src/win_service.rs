@@ -0,0 +1,103 @@
+//! Windows service entry point
+//!
+//! `nylon service install` registers `nylon run --service`. The service
+//! control manager stops a process that does not report its state, so the
+//! server is started from the service dispatcher instead of directly. A stop
+//! request goes through the background service, which shuts plugins and
+//! telemetry down before the process exits.
+
+use nylon_error::NylonError;
+use once_cell::sync::Lazy;
+use std::{ffi::OsString, sync::OnceLock, time::Duration};
+use tokio::sync::watch;
+use tracing::error;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+
+const SERVICE_NAME: &str = "nylon";
+
+static CONFIG_PATH: OnceLock<String> = OnceLock::new();
+static STATUS_HANDLE: OnceLock<ServiceStatusHandle> = OnceLock::new();
+/// Set when the service control manager asks the service to stop
+static STOP: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// How long the service control manager should wait for a stop
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hand the process to the service control manager and run the server
+pub fn run(config_path: String) -> Result<(), NylonError> {
+    let _ = CONFIG_PATH.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(|e| NylonError::RuntimeError(format!("Failed to start Windows service: {}", e)))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let handle = match service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            set_state(ServiceState::StopPending, ServiceExitCode::Win32(0));
+            STOP.send_replace(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    }) {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to register the service control handler: {}", e);
+            return;
+        }
+    };
+    let _ = STATUS_HANDLE.set(handle);
+    set_state(ServiceState::Running, ServiceExitCode::Win32(0));
+
+    let config_path = CONFIG_PATH.get().cloned().unwrap_or_default();
+    if let Err(e) = crate::handle_run_command(config_path, false) {
+        error!("Application error: {}", e);
+        set_state(ServiceState::Stopped, ServiceExitCode::ServiceSpecific(1));
+    }
+}
+
+/// Resolves once the service control manager asked the service to stop
+pub async fn stop_requested() {
+    let mut stop = STOP.subscribe();
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+/// Report the service stopped and exit; called once shutdown work is done
+pub fn stopped() -> ! {
+    set_state(ServiceState::Stopped, ServiceExitCode::Win32(0));
+    std::process::exit(0)
+}
+
+fn set_state(state: ServiceState, exit_code: ServiceExitCode) {
+    let Some(handle) = STATUS_HANDLE.get() else {
+        return;
+    };
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    if let Err(e) = handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StopPending => STOP_WAIT_HINT,
+            _ => Duration::default(),
+        },
+        process_id: None,
+    }) {
+        error!("Failed to report the service state: {}", e);
+    }
+}
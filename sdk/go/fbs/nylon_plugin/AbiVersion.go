@@ -0,0 +1,64 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type AbiVersion struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsAbiVersion(buf []byte, offset flatbuffers.UOffsetT) *AbiVersion {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &AbiVersion{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishAbiVersionBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsAbiVersion(buf []byte, offset flatbuffers.UOffsetT) *AbiVersion {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &AbiVersion{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedAbiVersionBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *AbiVersion) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *AbiVersion) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *AbiVersion) Version() uint16 {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.GetUint16(o + rcv._tab.Pos)
+	}
+	return 0
+}
+
+func (rcv *AbiVersion) MutateVersion(n uint16) bool {
+	return rcv._tab.MutateUint16Slot(4, n)
+}
+
+func AbiVersionStart(builder *flatbuffers.Builder) {
+	builder.StartObject(1)
+}
+func AbiVersionAddVersion(builder *flatbuffers.Builder, version uint16) {
+	builder.PrependUint16Slot(0, version, 0)
+}
+func AbiVersionEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
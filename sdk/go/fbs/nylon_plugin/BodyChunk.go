@@ -0,0 +1,104 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type BodyChunk struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsBodyChunk(buf []byte, offset flatbuffers.UOffsetT) *BodyChunk {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &BodyChunk{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishBodyChunkBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsBodyChunk(buf []byte, offset flatbuffers.UOffsetT) *BodyChunk {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &BodyChunk{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedBodyChunkBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *BodyChunk) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *BodyChunk) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *BodyChunk) Data(j int) byte {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		a := rcv._tab.Vector(o)
+		return rcv._tab.GetByte(a + flatbuffers.UOffsetT(j*1))
+	}
+	return 0
+}
+
+func (rcv *BodyChunk) DataLength() int {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.VectorLen(o)
+	}
+	return 0
+}
+
+func (rcv *BodyChunk) DataBytes() []byte {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.ByteVector(o + rcv._tab.Pos)
+	}
+	return nil
+}
+
+func (rcv *BodyChunk) MutateData(j int, n byte) bool {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		a := rcv._tab.Vector(o)
+		return rcv._tab.MutateByte(a+flatbuffers.UOffsetT(j*1), n)
+	}
+	return false
+}
+
+func (rcv *BodyChunk) Last() bool {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(6))
+	if o != 0 {
+		return rcv._tab.GetBool(o + rcv._tab.Pos)
+	}
+	return false
+}
+
+func (rcv *BodyChunk) MutateLast(n bool) bool {
+	return rcv._tab.MutateBoolSlot(6, n)
+}
+
+func BodyChunkStart(builder *flatbuffers.Builder) {
+	builder.StartObject(2)
+}
+func BodyChunkAddData(builder *flatbuffers.Builder, data flatbuffers.UOffsetT) {
+	builder.PrependUOffsetTSlot(0, flatbuffers.UOffsetT(data), 0)
+}
+func BodyChunkStartDataVector(builder *flatbuffers.Builder, numElems int) flatbuffers.UOffsetT {
+	return builder.StartVector(1, numElems, 1)
+}
+func BodyChunkAddLast(builder *flatbuffers.Builder, last bool) {
+	builder.PrependBoolSlot(1, last, false)
+}
+func BodyChunkEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
@@ -0,0 +1,75 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type CloseFrame struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsCloseFrame(buf []byte, offset flatbuffers.UOffsetT) *CloseFrame {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &CloseFrame{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishCloseFrameBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsCloseFrame(buf []byte, offset flatbuffers.UOffsetT) *CloseFrame {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &CloseFrame{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedCloseFrameBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *CloseFrame) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *CloseFrame) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *CloseFrame) Code() uint16 {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.GetUint16(o + rcv._tab.Pos)
+	}
+	return 0
+}
+
+func (rcv *CloseFrame) MutateCode(n uint16) bool {
+	return rcv._tab.MutateUint16Slot(4, n)
+}
+
+func (rcv *CloseFrame) Reason() []byte {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(6))
+	if o != 0 {
+		return rcv._tab.ByteVector(o + rcv._tab.Pos)
+	}
+	return nil
+}
+
+func CloseFrameStart(builder *flatbuffers.Builder) {
+	builder.StartObject(2)
+}
+func CloseFrameAddCode(builder *flatbuffers.Builder, code uint16) {
+	builder.PrependUint16Slot(0, code, 0)
+}
+func CloseFrameAddReason(builder *flatbuffers.Builder, reason flatbuffers.UOffsetT) {
+	builder.PrependUOffsetTSlot(1, flatbuffers.UOffsetT(reason), 0)
+}
+func CloseFrameEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
@@ -0,0 +1,75 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type Metric struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsMetric(buf []byte, offset flatbuffers.UOffsetT) *Metric {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &Metric{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishMetricBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsMetric(buf []byte, offset flatbuffers.UOffsetT) *Metric {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &Metric{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedMetricBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *Metric) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *Metric) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *Metric) Name() []byte {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.ByteVector(o + rcv._tab.Pos)
+	}
+	return nil
+}
+
+func (rcv *Metric) Value() float64 {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(6))
+	if o != 0 {
+		return rcv._tab.GetFloat64(o + rcv._tab.Pos)
+	}
+	return 1.0
+}
+
+func (rcv *Metric) MutateValue(n float64) bool {
+	return rcv._tab.MutateFloat64Slot(6, n)
+}
+
+func MetricStart(builder *flatbuffers.Builder) {
+	builder.StartObject(2)
+}
+func MetricAddName(builder *flatbuffers.Builder, name flatbuffers.UOffsetT) {
+	builder.PrependUOffsetTSlot(0, flatbuffers.UOffsetT(name), 0)
+}
+func MetricAddValue(builder *flatbuffers.Builder, value float64) {
+	builder.PrependFloat64Slot(1, value, 1.0)
+}
+func MetricEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
@@ -0,0 +1,64 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type Number struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsNumber(buf []byte, offset flatbuffers.UOffsetT) *Number {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &Number{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishNumberBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsNumber(buf []byte, offset flatbuffers.UOffsetT) *Number {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &Number{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedNumberBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *Number) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *Number) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *Number) Value() int64 {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.GetInt64(o + rcv._tab.Pos)
+	}
+	return 0
+}
+
+func (rcv *Number) MutateValue(n int64) bool {
+	return rcv._tab.MutateInt64Slot(4, n)
+}
+
+func NumberStart(builder *flatbuffers.Builder) {
+	builder.StartObject(1)
+}
+func NumberAddValue(builder *flatbuffers.Builder, value int64) {
+	builder.PrependInt64Slot(0, value, 0)
+}
+func NumberEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
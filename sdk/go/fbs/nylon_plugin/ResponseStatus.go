@@ -0,0 +1,64 @@
+// Code generated by the FlatBuffers compiler. DO NOT EDIT.
+
+package nylon_plugin
+
+import (
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+type ResponseStatus struct {
+	_tab flatbuffers.Table
+}
+
+func GetRootAsResponseStatus(buf []byte, offset flatbuffers.UOffsetT) *ResponseStatus {
+	n := flatbuffers.GetUOffsetT(buf[offset:])
+	x := &ResponseStatus{}
+	x.Init(buf, n+offset)
+	return x
+}
+
+func FinishResponseStatusBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.Finish(offset)
+}
+
+func GetSizePrefixedRootAsResponseStatus(buf []byte, offset flatbuffers.UOffsetT) *ResponseStatus {
+	n := flatbuffers.GetUOffsetT(buf[offset+flatbuffers.SizeUint32:])
+	x := &ResponseStatus{}
+	x.Init(buf, n+offset+flatbuffers.SizeUint32)
+	return x
+}
+
+func FinishSizePrefixedResponseStatusBuffer(builder *flatbuffers.Builder, offset flatbuffers.UOffsetT) {
+	builder.FinishSizePrefixed(offset)
+}
+
+func (rcv *ResponseStatus) Init(buf []byte, i flatbuffers.UOffsetT) {
+	rcv._tab.Bytes = buf
+	rcv._tab.Pos = i
+}
+
+func (rcv *ResponseStatus) Table() flatbuffers.Table {
+	return rcv._tab
+}
+
+func (rcv *ResponseStatus) Status() uint16 {
+	o := flatbuffers.UOffsetT(rcv._tab.Offset(4))
+	if o != 0 {
+		return rcv._tab.GetUint16(o + rcv._tab.Pos)
+	}
+	return 0
+}
+
+func (rcv *ResponseStatus) MutateStatus(n uint16) bool {
+	return rcv._tab.MutateUint16Slot(4, n)
+}
+
+func ResponseStatusStart(builder *flatbuffers.Builder) {
+	builder.StartObject(1)
+}
+func ResponseStatusAddStatus(builder *flatbuffers.Builder, status uint16) {
+	builder.PrependUint16Slot(0, status, 0)
+}
+func ResponseStatusEnd(builder *flatbuffers.Builder) flatbuffers.UOffsetT {
+	return builder.EndObject()
+}
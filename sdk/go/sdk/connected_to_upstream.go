@@ -0,0 +1,41 @@
+package sdk
+
+import "encoding/json"
+
+func (p *PhaseConnectedToUpstream) Request() *Request {
+	return &Request{
+		ctx: p.ctx,
+	}
+}
+
+func (p *PhaseConnectedToUpstream) GetPayload() map[string]any {
+	return p.ctx.GetPayload()
+}
+
+// Connection returns the backend address and whether the connection was reused.
+func (p *PhaseConnectedToUpstream) Connection() *UpstreamConnection {
+	ctx := p.ctx
+	methodID := MethodIDMapping[NylonMethodReadUpstreamConnection]
+
+	ctx.mu.Lock()
+	defer ctx.mu.Unlock()
+
+	go func() {
+		RequestMethod(ctx.sessionID, 0, NylonMethodReadUpstreamConnection, nil)
+	}()
+
+	ctx.cond.Wait()
+
+	var conn *UpstreamConnection
+	json.Unmarshal(ctx.dataMap[methodID], &conn)
+	return conn
+}
+
+func (p *PhaseConnectedToUpstream) Next() {
+	p.ctx.Next()
+}
+
+// End fails the request with the status set on the response (502 if none).
+func (p *PhaseConnectedToUpstream) End() {
+	p.ctx.End()
+}
@@ -20,23 +20,35 @@ const (
 )
 
 const (
-	NylonMethodReadRequestFullBody  NylonMethods = "read_request_full_body"
-	NylonMethodReadRequestHeader    NylonMethods = "read_request_header"
-	NylonMethodReadRequestHeaders   NylonMethods = "read_request_headers"
-	NylonMethodReadRequestURL       NylonMethods = "read_request_url"
-	NylonMethodReadRequestPath      NylonMethods = "read_request_path"
-	NylonMethodReadRequestQuery     NylonMethods = "read_request_query"
-	NylonMethodReadRequestParams    NylonMethods = "read_request_params"
-	NylonMethodReadRequestHost      NylonMethods = "read_request_host"
-	NylonMethodReadRequestClientIP  NylonMethods = "read_request_client_ip"
-	NylonMethodReadRequestMethod    NylonMethods = "read_request_method"
-	NylonMethodReadRequestBytes     NylonMethods = "read_request_bytes"
-	NylonMethodReadRequestTimestamp NylonMethods = "read_request_timestamp"
-	NylonMethodReadResponseStatus   NylonMethods = "read_response_status"
-	NylonMethodReadResponseBytes    NylonMethods = "read_response_bytes"
-	NylonMethodReadResponseHeaders  NylonMethods = "read_response_headers"
-	NylonMethodReadResponseDuration NylonMethods = "read_response_duration"
-	NylonMethodReadResponseError    NylonMethods = "read_response_error"
+	NylonMethodReadRequestFullBody     NylonMethods = "read_request_full_body"
+	NylonMethodReadRequestHeader       NylonMethods = "read_request_header"
+	NylonMethodReadRequestHeaders      NylonMethods = "read_request_headers"
+	NylonMethodReadRequestURL          NylonMethods = "read_request_url"
+	NylonMethodReadRequestPath         NylonMethods = "read_request_path"
+	NylonMethodReadRequestQuery        NylonMethods = "read_request_query"
+	NylonMethodReadRequestParams       NylonMethods = "read_request_params"
+	NylonMethodReadRequestHost         NylonMethods = "read_request_host"
+	NylonMethodReadRequestClientIP     NylonMethods = "read_request_client_ip"
+	NylonMethodReadRequestMethod       NylonMethods = "read_request_method"
+	NylonMethodReadRequestBytes        NylonMethods = "read_request_bytes"
+	NylonMethodReadRequestTimestamp    NylonMethods = "read_request_timestamp"
+	NylonMethodReadRouteInfo           NylonMethods = "read_route_info"
+	NylonMethodReadRequestBodyStream   NylonMethods = "read_request_body_stream"
+	NylonMethodReadRequestBodyChunk    NylonMethods = "read_request_body_chunk"
+	NylonMethodSetRequestBodyChunk     NylonMethods = "set_request_body_chunk"
+	NylonMethodSetRequestPath          NylonMethods = "set_request_path"
+	NylonMethodSetRequestMethod        NylonMethods = "set_request_method"
+	NylonMethodSetRequestQuery         NylonMethods = "set_request_query"
+	NylonMethodSetRequestHeader        NylonMethods = "set_request_header"
+	NylonMethodSetUpstream             NylonMethods = "set_upstream"
+	NylonMethodHttpFetch               NylonMethods = "http_fetch"
+	NylonMethodSubscribeUpstreamPhases NylonMethods = "subscribe_upstream_phases"
+	NylonMethodReadUpstreamConnection  NylonMethods = "read_upstream_connection"
+	NylonMethodReadResponseStatus      NylonMethods = "read_response_status"
+	NylonMethodReadResponseBytes       NylonMethods = "read_response_bytes"
+	NylonMethodReadResponseHeaders     NylonMethods = "read_response_headers"
+	NylonMethodReadResponseDuration    NylonMethods = "read_response_duration"
+	NylonMethodReadResponseError       NylonMethods = "read_response_error"
 )
 
 // WebSocket methods
@@ -47,6 +59,10 @@ const (
 	NylonMethodWebSocketSendBinary NylonMethods = "websocket_send_binary"
 	NylonMethodWebSocketClose      NylonMethods = "websocket_close"
 
+	NylonMethodWebSocketSelectProtocol         NylonMethods = "websocket_select_protocol"
+	NylonMethodWebSocketSetConnectionMetadata  NylonMethods = "websocket_set_connection_metadata"
+	NylonMethodWebSocketReadConnectionMetadata NylonMethods = "websocket_read_connection_metadata"
+
 	// WebSocket room methods (Plugin -> Rust)
 	NylonMethodWebSocketJoinRoom            NylonMethods = "websocket_join_room"
 	NylonMethodWebSocketLeaveRoom           NylonMethods = "websocket_leave_room"
@@ -59,6 +75,23 @@ const (
 	NylonMethodWebSocketOnMessageBinary NylonMethods = "websocket_on_message_binary"
 	NylonMethodWebSocketOnClose         NylonMethods = "websocket_on_close"
 	NylonMethodWebSocketOnError         NylonMethods = "websocket_on_error"
+
+	// Room presence
+	NylonMethodWebSocketRoomSize      NylonMethods = "websocket_room_size"
+	NylonMethodWebSocketRoomMembers   NylonMethods = "websocket_room_members"
+	NylonMethodWebSocketWatchPresence NylonMethods = "websocket_watch_presence"
+	NylonMethodWebSocketOnRoomJoin    NylonMethods = "websocket_on_room_join"
+	NylonMethodWebSocketOnRoomLeave   NylonMethods = "websocket_on_room_leave"
+
+	// Cluster-wide broadcast
+	NylonMethodWebSocketBroadcastAllText   NylonMethods = "websocket_broadcast_all_text"
+	NylonMethodWebSocketBroadcastAllBinary NylonMethods = "websocket_broadcast_all_binary"
+)
+
+// Metric methods
+const (
+	NylonMethodMetricIncr    NylonMethods = "metric_incr"
+	NylonMethodMetricObserve NylonMethods = "metric_observe"
 )
 
 var MethodIDMapping = map[NylonMethods]uint32{
@@ -77,29 +110,42 @@ var MethodIDMapping = map[NylonMethods]uint32{
 	NylonMethodReadResponseFullBody:    107,
 
 	// Request methods
-	NylonMethodReadRequestFullBody:  200,
-	NylonMethodReadRequestHeader:    201,
-	NylonMethodReadRequestHeaders:   202,
-	NylonMethodReadRequestURL:       203,
-	NylonMethodReadRequestPath:      204,
-	NylonMethodReadRequestQuery:     205,
-	NylonMethodReadRequestParams:    206,
-	NylonMethodReadRequestHost:      207,
-	NylonMethodReadRequestClientIP:  208,
-	NylonMethodReadRequestMethod:    209,
-	NylonMethodReadRequestBytes:     210,
-	NylonMethodReadRequestTimestamp: 211,
-	NylonMethodReadResponseStatus:   108,
-	NylonMethodReadResponseBytes:    109,
-	NylonMethodReadResponseHeaders:  110,
-	NylonMethodReadResponseDuration: 111,
-	NylonMethodReadResponseError:    112,
+	NylonMethodReadRequestFullBody:     200,
+	NylonMethodReadRequestHeader:       201,
+	NylonMethodReadRequestHeaders:      202,
+	NylonMethodReadRequestURL:          203,
+	NylonMethodReadRequestPath:         204,
+	NylonMethodReadRequestQuery:        205,
+	NylonMethodReadRequestParams:       206,
+	NylonMethodReadRequestHost:         207,
+	NylonMethodReadRequestClientIP:     208,
+	NylonMethodReadRequestMethod:       209,
+	NylonMethodReadRequestBytes:        210,
+	NylonMethodReadRequestTimestamp:    211,
+	NylonMethodReadRouteInfo:           212,
+	NylonMethodReadRequestBodyStream:   213,
+	NylonMethodReadRequestBodyChunk:    214,
+	NylonMethodSetRequestBodyChunk:     215,
+	NylonMethodSetRequestPath:          216,
+	NylonMethodSetRequestMethod:        217,
+	NylonMethodSetRequestQuery:         218,
+	NylonMethodSetRequestHeader:        219,
+	NylonMethodSetUpstream:             220,
+	NylonMethodHttpFetch:               221,
+	NylonMethodSubscribeUpstreamPhases: 222,
+	NylonMethodReadUpstreamConnection:  223,
+	NylonMethodReadResponseStatus:      108,
+	NylonMethodReadResponseBytes:       109,
+	NylonMethodReadResponseHeaders:     110,
+	NylonMethodReadResponseDuration:    111,
+	NylonMethodReadResponseError:       112,
 
 	// WebSocket methods
 	NylonMethodWebSocketUpgrade:             300,
 	NylonMethodWebSocketSendText:            301,
 	NylonMethodWebSocketSendBinary:          302,
 	NylonMethodWebSocketClose:               303,
+	NylonMethodWebSocketSelectProtocol:      304,
 	NylonMethodWebSocketJoinRoom:            310,
 	NylonMethodWebSocketLeaveRoom:           311,
 	NylonMethodWebSocketBroadcastRoomText:   312,
@@ -109,6 +155,25 @@ var MethodIDMapping = map[NylonMethods]uint32{
 	NylonMethodWebSocketOnMessageBinary:     352,
 	NylonMethodWebSocketOnClose:             353,
 	NylonMethodWebSocketOnError:             354,
+
+	// WebSocket connection metadata
+	NylonMethodWebSocketSetConnectionMetadata:  305,
+	NylonMethodWebSocketReadConnectionMetadata: 306,
+
+	// WebSocket room presence
+	NylonMethodWebSocketRoomSize:      314,
+	NylonMethodWebSocketRoomMembers:   315,
+	NylonMethodWebSocketWatchPresence: 316,
+	NylonMethodWebSocketOnRoomJoin:    355,
+	NylonMethodWebSocketOnRoomLeave:   356,
+
+	// WebSocket cluster-wide broadcast
+	NylonMethodWebSocketBroadcastAllText:   317,
+	NylonMethodWebSocketBroadcastAllBinary: 318,
+
+	// Metric methods
+	NylonMethodMetricIncr:    400,
+	NylonMethodMetricObserve: 401,
 }
 
 const (
@@ -4,6 +4,7 @@ import (
 	"encoding/binary"
 	"encoding/json"
 	"strconv"
+	"unicode/utf8"
 
 	"github.com/AssetsArt/nylon/sdk/go/fbs/nylon_plugin"
 	flatbuffers "github.com/google/flatbuffers/go"
@@ -324,6 +325,65 @@ func (r *Request) Timestamp() int64 {
 	return timestamp
 }
 
+// RouteInfo returns the route that matched the request, or nil if none did.
+func (r *Request) RouteInfo() *RouteInfo {
+	ctx := r.ctx
+	methodID := MethodIDMapping[NylonMethodReadRouteInfo]
+
+	ctx.mu.Lock()
+	defer ctx.mu.Unlock()
+
+	go func() {
+		RequestMethod(ctx.sessionID, 0, NylonMethodReadRouteInfo, nil)
+	}()
+
+	ctx.cond.Wait()
+
+	var info *RouteInfo
+	json.Unmarshal(ctx.dataMap[methodID], &info)
+	return info
+}
+
+// StreamBody asks for the request body in chunks in the RequestBodyFilter
+// phase instead of buffering it. Call it before Next().
+func (r *Request) StreamBody() error {
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodReadRequestBodyStream, nil)
+}
+
+// SetPath rewrites the path sent upstream, keeping the query.
+func (r *Request) SetPath(path string) error {
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodSetRequestPath, []byte(path))
+}
+
+// SetMethod changes the method sent upstream.
+func (r *Request) SetMethod(method string) error {
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodSetRequestMethod, []byte(method))
+}
+
+// SetQuery replaces the query string sent upstream; empty removes it.
+func (r *Request) SetQuery(query string) error {
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodSetRequestQuery, []byte(query))
+}
+
+// SetHeader sets a header sent upstream; an empty value removes it.
+func (r *Request) SetHeader(key, value string) error {
+	builder := flatbuffers.NewBuilder(0)
+	headerKey := builder.CreateString(key)
+	headerValue := builder.CreateString(value)
+	nylon_plugin.HeaderKeyValueStart(builder)
+	nylon_plugin.HeaderKeyValueAddKey(builder, headerKey)
+	nylon_plugin.HeaderKeyValueAddValue(builder, headerValue)
+	builder.Finish(nylon_plugin.HeaderKeyValueEnd(builder))
+
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodSetRequestHeader, builder.FinishedBytes())
+}
+
+// SetUpstream sends this request to an HTTP service by name or to an
+// address such as "10.0.0.5:8080" or "https://api.internal:443".
+func (r *Request) SetUpstream(target string) error {
+	return RequestMethod(r.ctx.sessionID, 0, NylonMethodSetUpstream, []byte(target))
+}
+
 func (r *Response) Status() int {
 	ctx := r.ctx
 	methodID := MethodIDMapping[NylonMethodReadResponseStatus]
@@ -431,6 +491,64 @@ func (r *Response) Error() string {
 }
 
 // WebSocket send helpers
+// Protocol returns the subprotocol agreed in the handshake, or "" when none was selected.
+func (ws *WebSocketConn) Protocol() string {
+	return ws.ctx.wsProtocol
+}
+
+// ID returns the connection id other nodes know this connection by.
+func (ws *WebSocketConn) ID() string {
+	return ws.ctx.wsConnID
+}
+
+// SetMetadata merges values into the metadata of this connection, e.g. the user id after authentication.
+func (ws *WebSocketConn) SetMetadata(metadata map[string]string) error {
+	data, err := json.Marshal(metadata)
+	if err != nil {
+		return err
+	}
+	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketSetConnectionMetadata, data)
+}
+
+// Metadata returns the metadata of a connection on any node; an empty id means this connection.
+// It returns nil when the connection is gone.
+func (ws *WebSocketConn) Metadata(connectionID string) (map[string]string, error) {
+	ws.ctx.mu.Lock()
+	defer ws.ctx.mu.Unlock()
+	go RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketReadConnectionMetadata, []byte(connectionID))
+	ws.ctx.cond.Wait()
+
+	var metadata map[string]string
+	if err := json.Unmarshal(ws.ctx.dataMap[MethodIDMapping[NylonMethodWebSocketReadConnectionMetadata]], &metadata); err != nil {
+		return nil, err
+	}
+	return metadata, nil
+}
+
+// RoomSize returns how many connections are in a room across all nodes.
+func (ws *WebSocketConn) RoomSize(room string) (int, error) {
+	ws.ctx.mu.Lock()
+	defer ws.ctx.mu.Unlock()
+	go RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketRoomSize, []byte(room))
+	ws.ctx.cond.Wait()
+
+	return strconv.Atoi(string(ws.ctx.dataMap[MethodIDMapping[NylonMethodWebSocketRoomSize]]))
+}
+
+// RoomMembers returns the connections in a room with their metadata.
+func (ws *WebSocketConn) RoomMembers(room string) ([]RoomMember, error) {
+	ws.ctx.mu.Lock()
+	defer ws.ctx.mu.Unlock()
+	go RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketRoomMembers, []byte(room))
+	ws.ctx.cond.Wait()
+
+	var members []RoomMember
+	if err := json.Unmarshal(ws.ctx.dataMap[MethodIDMapping[NylonMethodWebSocketRoomMembers]], &members); err != nil {
+		return nil, err
+	}
+	return members, nil
+}
+
 func (ws *WebSocketConn) SendText(msg string) error {
 	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketSendText, []byte(msg))
 }
@@ -443,6 +561,29 @@ func (ws *WebSocketConn) Close() error {
 	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketClose, nil)
 }
 
+// maxCloseReason is the longest reason that fits a close frame next to the code
+const maxCloseReason = 123
+
+// CloseWithStatus closes the connection with a status code (e.g. 1000) and a reason, cut to 123 bytes.
+func (ws *WebSocketConn) CloseWithStatus(code uint16, reason string) error {
+	if len(reason) > maxCloseReason {
+		reason = reason[:maxCloseReason]
+		for len(reason) > 0 && !utf8.ValidString(reason) {
+			reason = reason[:len(reason)-1]
+		}
+	}
+	data := make([]byte, 2, 2+len(reason))
+	binary.BigEndian.PutUint16(data, code)
+	data = append(data, []byte(reason)...)
+	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketClose, data)
+}
+
+// CloseStatus returns the status code and reason the connection closed with; valid in OnClose.
+// 1005 means the client sent no code and 1006 that it went away without a close frame.
+func (ws *WebSocketConn) CloseStatus() (uint16, string) {
+	return ws.ctx.wsCloseCode, ws.ctx.wsCloseReason
+}
+
 // Room helpers
 func (ws *WebSocketConn) JoinRoom(room string) error {
 	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketJoinRoom, []byte(room))
@@ -461,6 +602,24 @@ func (ws *WebSocketConn) BroadcastText(room string, message string) error {
 	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketBroadcastRoomText, data)
 }
 
+// BroadcastAllText sends a message to every connection on every node, except the connection with id exclude ("" for none).
+func (ws *WebSocketConn) BroadcastAllText(message string, exclude string) error {
+	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketBroadcastAllText, targetPayload(exclude, []byte(message)))
+}
+
+// BroadcastAllBinary is BroadcastAllText for binary messages.
+func (ws *WebSocketConn) BroadcastAllBinary(payload []byte, exclude string) error {
+	return RequestMethod(ws.ctx.sessionID, 0, NylonMethodWebSocketBroadcastAllBinary, targetPayload(exclude, payload))
+}
+
+// targetPayload joins a room or connection id and a message: target + NUL + payload
+func targetPayload(target string, payload []byte) []byte {
+	data := make([]byte, 0, len(target)+1+len(payload))
+	data = append(data, []byte(target)...)
+	data = append(data, 0)
+	return append(data, payload...)
+}
+
 func (ws *WebSocketConn) BroadcastBinary(room string, payload []byte) error {
 	data := make([]byte, 0, len(room)+1+len(payload))
 	data = append(data, []byte(room)...)
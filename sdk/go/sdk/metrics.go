@@ -0,0 +1,51 @@
+package sdk
+
+import (
+	"github.com/AssetsArt/nylon/sdk/go/fbs/nylon_plugin"
+	flatbuffers "github.com/google/flatbuffers/go"
+)
+
+func (ctx *NylonHttpPluginCtx) emitMetric(method NylonMethods, name string, value float64) {
+	builder := flatbuffers.NewBuilder(0)
+	metricName := builder.CreateString(name)
+	nylon_plugin.MetricStart(builder)
+	nylon_plugin.MetricAddName(builder, metricName)
+	nylon_plugin.MetricAddValue(builder, value)
+	builder.Finish(nylon_plugin.MetricEnd(builder))
+
+	RequestMethod(ctx.sessionID, 0, method, builder.FinishedBytes())
+}
+
+// Incr adds value to a counter exported as nylon_plugin_counter_total{plugin, metric}.
+func (ctx *NylonHttpPluginCtx) Incr(name string, value float64) {
+	ctx.emitMetric(NylonMethodMetricIncr, name, value)
+}
+
+// Observe records value in a histogram exported as nylon_plugin_histogram{plugin, metric}.
+func (ctx *NylonHttpPluginCtx) Observe(name string, value float64) {
+	ctx.emitMetric(NylonMethodMetricObserve, name, value)
+}
+
+func (p *PhaseRequestFilter) Incr(name string, value float64) {
+	p.ctx.Incr(name, value)
+}
+
+func (p *PhaseRequestFilter) Observe(name string, value float64) {
+	p.ctx.Observe(name, value)
+}
+
+func (p *PhaseResponseFilter) Incr(name string, value float64) {
+	p.ctx.Incr(name, value)
+}
+
+func (p *PhaseResponseFilter) Observe(name string, value float64) {
+	p.ctx.Observe(name, value)
+}
+
+func (p *PhaseLogging) Incr(name string, value float64) {
+	p.ctx.Incr(name, value)
+}
+
+func (p *PhaseLogging) Observe(name string, value float64) {
+	p.ctx.Observe(name, value)
+}
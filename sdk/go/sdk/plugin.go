@@ -29,6 +29,7 @@ static inline void call_event_method(data_event_fn cb, const FfiBuffer* ffiBuffe
 import "C"
 import (
 	"encoding/json"
+	"errors"
 	"fmt"
 	"sync"
 	"sync/atomic"
@@ -147,6 +148,15 @@ func register_session_stream(sessionID C.uint32_t, entry *C.char, length C.uint3
 		logging: func(ctx *PhaseLogging) {
 			ctx.Next()
 		},
+		requestBodyFilter: func(ctx *PhaseRequestBodyFilter) {
+			ctx.Next()
+		},
+		upstreamRequestFilter: func(ctx *PhaseUpstreamRequestFilter) {
+			ctx.Next()
+		},
+		connectedToUpstream: func(ctx *PhaseConnectedToUpstream) {
+			ctx.Next()
+		},
 	}
 	handler(phase)
 	streamSessions.Store(sid, phase)
@@ -167,6 +177,9 @@ func event_stream(ffiBuffer *C.FfiBuffer) {
 	switch ffiBuffer.phase {
 	case 1:
 		go func() {
+			if phaseHandler.upstreamPhases {
+				RequestMethod(sid, 0, NylonMethodSubscribeUpstreamPhases, nil)
+			}
 			phaseHandler.requestFilter(&PhaseRequestFilter{
 				ctx: phaseHandler.http_ctx,
 			})
@@ -189,6 +202,24 @@ func event_stream(ffiBuffer *C.FfiBuffer) {
 				ctx: phaseHandler.http_ctx,
 			})
 		}()
+	case 5:
+		go func() {
+			phaseHandler.requestBodyFilter(&PhaseRequestBodyFilter{
+				ctx: phaseHandler.http_ctx,
+			})
+		}()
+	case 6:
+		go func() {
+			phaseHandler.upstreamRequestFilter(&PhaseUpstreamRequestFilter{
+				ctx: phaseHandler.http_ctx,
+			})
+		}()
+	case 7:
+		go func() {
+			phaseHandler.connectedToUpstream(&PhaseConnectedToUpstream{
+				ctx: phaseHandler.http_ctx,
+			})
+		}()
 	default:
 		ctx := phaseHandler.http_ctx
 		ctx.mu.Lock()
@@ -201,15 +232,50 @@ func event_stream(ffiBuffer *C.FfiBuffer) {
 		switch method {
 		case MethodIDMapping[NylonMethodWebSocketOnOpen]:
 			ctx.wsUpgraded = true
+			if length > 0 {
+				var open struct {
+					ConnectionID string `json:"connection_id"`
+					Protocol     string `json:"protocol"`
+				}
+				json.Unmarshal(C.GoBytes(unsafe.Pointer(data), C.int(length)), &open)
+				ctx.wsConnID = open.ConnectionID
+				ctx.wsProtocol = open.Protocol
+			}
+			if ctx.wsCallbacks != nil && (ctx.wsCallbacks.OnRoomJoin != nil || ctx.wsCallbacks.OnRoomLeave != nil) {
+				RequestMethod(ctx.sessionID, 0, NylonMethodWebSocketWatchPresence, nil)
+			}
 			if ctx.wsCallbacks != nil && ctx.wsCallbacks.OnOpen != nil {
 				go ctx.wsCallbacks.OnOpen(&WebSocketConn{ctx: ctx})
 			}
 			return
 		case MethodIDMapping[NylonMethodWebSocketOnClose]:
+			if length > 0 {
+				var closed struct {
+					Code   uint16 `json:"code"`
+					Reason string `json:"reason"`
+				}
+				json.Unmarshal(C.GoBytes(unsafe.Pointer(data), C.int(length)), &closed)
+				ctx.wsCloseCode = closed.Code
+				ctx.wsCloseReason = closed.Reason
+			}
 			if ctx.wsCallbacks != nil && ctx.wsCallbacks.OnClose != nil {
 				go ctx.wsCallbacks.OnClose(&WebSocketConn{ctx: ctx})
 			}
 			return
+		case MethodIDMapping[NylonMethodWebSocketOnRoomJoin], MethodIDMapping[NylonMethodWebSocketOnRoomLeave]:
+			var presence RoomPresence
+			json.Unmarshal(C.GoBytes(unsafe.Pointer(data), C.int(length)), &presence)
+			if ctx.wsCallbacks == nil {
+				return
+			}
+			callback := ctx.wsCallbacks.OnRoomLeave
+			if method == MethodIDMapping[NylonMethodWebSocketOnRoomJoin] {
+				callback = ctx.wsCallbacks.OnRoomJoin
+			}
+			if callback != nil {
+				go callback(&WebSocketConn{ctx: ctx}, presence)
+			}
+			return
 		case MethodIDMapping[NylonMethodWebSocketOnError]:
 			msg := C.GoStringN((*C.char)(unsafe.Pointer(data)), C.int(length))
 			if ctx.wsCallbacks != nil && ctx.wsCallbacks.OnError != nil {
@@ -304,6 +370,28 @@ func (ctx *NylonHttpPluginCtx) GetPayload() map[string]any {
 	return payloadMap
 }
 
+// Fetch performs an outbound HTTP request through Nylon's connection pool.
+func (ctx *NylonHttpPluginCtx) Fetch(req FetchRequest) (*FetchResponse, error) {
+	data, err := json.Marshal(req)
+	if err != nil {
+		return nil, err
+	}
+
+	ctx.mu.Lock()
+	defer ctx.mu.Unlock()
+	go RequestMethod(ctx.sessionID, 0, NylonMethodHttpFetch, data)
+	ctx.cond.Wait()
+
+	var res FetchResponse
+	if err := json.Unmarshal(ctx.dataMap[MethodIDMapping[NylonMethodHttpFetch]], &res); err != nil {
+		return nil, err
+	}
+	if res.Error != "" {
+		return nil, errors.New(res.Error)
+	}
+	return &res, nil
+}
+
 func (ctx *NylonHttpPluginCtx) Next() {
 	go RequestMethod(ctx.sessionID, 0, NylonMethodNext, nil)
 }
@@ -313,13 +401,17 @@ func (ctx *NylonHttpPluginCtx) End() {
 }
 
 type PhaseHandler struct {
-	SessionId          int32
-	cb                 C.data_event_fn
-	http_ctx           *NylonHttpPluginCtx
-	requestFilter      func(ctx *PhaseRequestFilter)
-	responseFilter     func(ctx *PhaseResponseFilter)
-	responseBodyFilter func(ctx *PhaseResponseBodyFilter)
-	logging            func(ctx *PhaseLogging)
+	SessionId             int32
+	cb                    C.data_event_fn
+	http_ctx              *NylonHttpPluginCtx
+	requestFilter         func(ctx *PhaseRequestFilter)
+	responseFilter        func(ctx *PhaseResponseFilter)
+	responseBodyFilter    func(ctx *PhaseResponseBodyFilter)
+	logging               func(ctx *PhaseLogging)
+	requestBodyFilter     func(ctx *PhaseRequestBodyFilter)
+	upstreamRequestFilter func(ctx *PhaseUpstreamRequestFilter)
+	connectedToUpstream   func(ctx *PhaseConnectedToUpstream)
+	upstreamPhases        bool
 }
 
 func (p *NylonPlugin) AddPhaseHandler(phaseName string, phaseHandler func(phase *PhaseHandler)) {
@@ -341,3 +433,23 @@ func (p *PhaseHandler) ResponseBodyFilter(phaseResponseBodyFilter func(responseB
 func (p *PhaseHandler) Logging(phaseLogging func(logging *PhaseLogging)) {
 	p.logging = phaseLogging
 }
+
+// RequestBodyFilter runs for every request body chunk once the request
+// filter called Request().StreamBody().
+func (p *PhaseHandler) RequestBodyFilter(phaseRequestBodyFilter func(requestBodyFilter *PhaseRequestBodyFilter)) {
+	p.requestBodyFilter = phaseRequestBodyFilter
+}
+
+// UpstreamRequestFilter runs right before the request header is sent to
+// the backend; changes made with Request().SetHeader() etc. are what it gets.
+func (p *PhaseHandler) UpstreamRequestFilter(phaseUpstreamRequestFilter func(upstreamRequestFilter *PhaseUpstreamRequestFilter)) {
+	p.upstreamRequestFilter = phaseUpstreamRequestFilter
+	p.upstreamPhases = true
+}
+
+// ConnectedToUpstream runs once a connection to the backend is established
+// or taken from the pool.
+func (p *PhaseHandler) ConnectedToUpstream(phaseConnectedToUpstream func(connectedToUpstream *PhaseConnectedToUpstream)) {
+	p.connectedToUpstream = phaseConnectedToUpstream
+	p.upstreamPhases = true
+}
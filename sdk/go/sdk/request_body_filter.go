@@ -0,0 +1,51 @@
+package sdk
+
+func (p *PhaseRequestBodyFilter) Request() *Request {
+	return &Request{
+		ctx: p.ctx,
+	}
+}
+
+func (p *PhaseRequestBodyFilter) Response() *Response {
+	return &Response{
+		ctx: p.ctx,
+	}
+}
+
+func (p *PhaseRequestBodyFilter) GetPayload() map[string]any {
+	return p.ctx.GetPayload()
+}
+
+// Chunk returns the current body chunk and whether it is the last one.
+func (p *PhaseRequestBodyFilter) Chunk() ([]byte, bool) {
+	ctx := p.ctx
+	methodID := MethodIDMapping[NylonMethodReadRequestBodyChunk]
+
+	ctx.mu.Lock()
+	defer ctx.mu.Unlock()
+
+	go func() {
+		RequestMethod(ctx.sessionID, 0, NylonMethodReadRequestBodyChunk, nil)
+	}()
+
+	ctx.cond.Wait()
+	data := ctx.dataMap[methodID]
+	if len(data) == 0 {
+		return nil, true
+	}
+	return data[1:], data[0] == 1
+}
+
+// SetChunk replaces the chunk forwarded upstream. An empty chunk drops it.
+func (p *PhaseRequestBodyFilter) SetChunk(chunk []byte) error {
+	return RequestMethod(p.ctx.sessionID, 0, NylonMethodSetRequestBodyChunk, chunk)
+}
+
+func (p *PhaseRequestBodyFilter) Next() {
+	p.ctx.Next()
+}
+
+// End rejects the upload with the status set on the response (400 if none).
+func (p *PhaseRequestBodyFilter) End() {
+	p.ctx.End()
+}
@@ -1,5 +1,7 @@
 package sdk
 
+import "strings"
+
 func (ctx *PhaseRequestFilter) Request() *Request {
 	return &Request{
 		ctx: ctx.ctx,
@@ -16,6 +18,10 @@ func (p *PhaseRequestFilter) GetPayload() map[string]any {
 	return p.ctx.GetPayload()
 }
 
+func (p *PhaseRequestFilter) Fetch(req FetchRequest) (*FetchResponse, error) {
+	return p.ctx.Fetch(req)
+}
+
 func (p *PhaseRequestFilter) Next() {
 	p.ctx.Next()
 }
@@ -25,6 +31,22 @@ func (p *PhaseRequestFilter) End() {
 }
 
 // WebSocket helpers
+
+// WebSocketProtocols returns the subprotocols the client offered, in order.
+func (p *PhaseRequestFilter) WebSocketProtocols() []string {
+	protocols := []string{}
+	for _, protocol := range strings.Split(p.Request().Header("Sec-WebSocket-Protocol"), ",") {
+		if protocol = strings.TrimSpace(protocol); protocol != "" {
+			protocols = append(protocols, protocol)
+		}
+	}
+	return protocols
+}
+
+// WebSocketSelectProtocol picks the subprotocol echoed in the handshake; call it before WebSocketUpgrade.
+func (p *PhaseRequestFilter) WebSocketSelectProtocol(protocol string) error {
+	return RequestMethod(p.ctx.sessionID, 0, NylonMethodWebSocketSelectProtocol, []byte(protocol))
+}
 func (p *PhaseRequestFilter) WebSocketUpgrade(cbs WebSocketCallbacks) error {
 	// Store callbacks in context for dispatch before requesting upgrade
 	// This ensures callbacks are available when events arrive
@@ -16,8 +16,12 @@ type NylonHttpPluginCtx struct {
 	dataMap map[uint32][]byte
 
 	// WebSocket state
-	wsCallbacks *WebSocketCallbacks
-	wsUpgraded  bool
+	wsCallbacks   *WebSocketCallbacks
+	wsUpgraded    bool
+	wsProtocol    string
+	wsConnID      string
+	wsCloseCode   uint16
+	wsCloseReason string
 }
 
 type Headers struct {
@@ -32,6 +36,32 @@ type Request struct {
 	ctx *NylonHttpPluginCtx
 }
 
+// RouteInfo describes the route a request matched
+type RouteInfo struct {
+	Route       string  `json:"route"`
+	Path        string  `json:"path"`
+	Service     string  `json:"service"`
+	ServiceType string  `json:"service_type"`
+	Rewrite     *string `json:"rewrite"`
+}
+
+// FetchRequest is an outbound request made by Nylon for the plugin
+type FetchRequest struct {
+	Method    string            `json:"method,omitempty"`
+	URL       string            `json:"url"`
+	Headers   map[string]string `json:"headers,omitempty"`
+	Body      []byte            `json:"body,omitempty"`
+	TimeoutMs int               `json:"timeout_ms,omitempty"`
+}
+
+// FetchResponse is the answer to a FetchRequest
+type FetchResponse struct {
+	Status  int               `json:"status"`
+	Headers map[string]string `json:"headers"`
+	Body    []byte            `json:"body"`
+	Error   string            `json:"error,omitempty"`
+}
+
 type ResponseStream struct {
 	response *Response
 }
@@ -52,6 +82,26 @@ type PhaseLogging struct {
 	ctx *NylonHttpPluginCtx
 }
 
+type PhaseRequestBodyFilter struct {
+	ctx *NylonHttpPluginCtx
+}
+
+type PhaseUpstreamRequestFilter struct {
+	ctx *NylonHttpPluginCtx
+}
+
+type PhaseConnectedToUpstream struct {
+	ctx *NylonHttpPluginCtx
+}
+
+// UpstreamConnection describes the connection to the backend
+type UpstreamConnection struct {
+	Address string `json:"address"`
+	Reused  bool   `json:"reused"`
+	TLS     bool   `json:"tls"`
+	SNI     string `json:"sni"`
+}
+
 // WebSocket types
 
 type WebSocketConn struct {
@@ -64,4 +114,23 @@ type WebSocketCallbacks struct {
 	OnMessageBinary func(ws *WebSocketConn, data []byte)
 	OnClose         func(ws *WebSocketConn)
 	OnError         func(ws *WebSocketConn, err string)
+
+	// Presence of the other members of the rooms this connection is in
+	OnRoomJoin  func(ws *WebSocketConn, p RoomPresence)
+	OnRoomLeave func(ws *WebSocketConn, p RoomPresence)
+}
+
+// RoomPresence is a connection that joined or left a room.
+type RoomPresence struct {
+	Room         string            `json:"room"`
+	ConnectionID string            `json:"connection_id"`
+	Metadata     map[string]string `json:"metadata"`
+}
+
+// RoomMember is a connection in a room, on any node.
+type RoomMember struct {
+	ID          string            `json:"id"`
+	NodeID      string            `json:"node_id"`
+	ConnectedAt uint64            `json:"connected_at"`
+	Metadata    map[string]string `json:"metadata"`
 }
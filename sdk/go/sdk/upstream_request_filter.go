@@ -0,0 +1,27 @@
+package sdk
+
+// Request reads and edits the request sent upstream.
+func (p *PhaseUpstreamRequestFilter) Request() *Request {
+	return &Request{
+		ctx: p.ctx,
+	}
+}
+
+func (p *PhaseUpstreamRequestFilter) Response() *Response {
+	return &Response{
+		ctx: p.ctx,
+	}
+}
+
+func (p *PhaseUpstreamRequestFilter) GetPayload() map[string]any {
+	return p.ctx.GetPayload()
+}
+
+func (p *PhaseUpstreamRequestFilter) Next() {
+	p.ctx.Next()
+}
+
+// End stops the request with the status set on the response (403 if none).
+func (p *PhaseUpstreamRequestFilter) End() {
+	p.ctx.End()
+}
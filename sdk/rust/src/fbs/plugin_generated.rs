@@ -288,4 +288,1211 @@ pub mod nylon_plugin {
             ds.finish()
         }
     }
+    pub enum RemoveResponseHeaderOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct RemoveResponseHeader<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for RemoveResponseHeader<'a> {
+        type Inner = RemoveResponseHeader<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> RemoveResponseHeader<'a> {
+        pub const VT_KEY: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            RemoveResponseHeader { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args RemoveResponseHeaderArgs<'args>,
+        ) -> flatbuffers::WIPOffset<RemoveResponseHeader<'bldr>> {
+            let mut builder = RemoveResponseHeaderBuilder::new(_fbb);
+            if let Some(x) = args.key {
+                builder.add_key(x);
+            }
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn key(&self) -> Option<&'a str> {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<&str>>(RemoveResponseHeader::VT_KEY, None)
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for RemoveResponseHeader<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<&str>>("key", Self::VT_KEY, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct RemoveResponseHeaderArgs<'a> {
+        pub key: Option<flatbuffers::WIPOffset<&'a str>>,
+    }
+    impl<'a> Default for RemoveResponseHeaderArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            RemoveResponseHeaderArgs { key: None }
+        }
+    }
+
+    pub struct RemoveResponseHeaderBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> RemoveResponseHeaderBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_key(&mut self, key: flatbuffers::WIPOffset<&'b str>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(RemoveResponseHeader::VT_KEY, key);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> RemoveResponseHeaderBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            RemoveResponseHeaderBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<RemoveResponseHeader<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for RemoveResponseHeader<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("RemoveResponseHeader");
+            ds.field("key", &self.key());
+            ds.finish()
+        }
+    }
+    pub enum ResponseStatusOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct ResponseStatus<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for ResponseStatus<'a> {
+        type Inner = ResponseStatus<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> ResponseStatus<'a> {
+        pub const VT_STATUS: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            ResponseStatus { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args ResponseStatusArgs,
+        ) -> flatbuffers::WIPOffset<ResponseStatus<'bldr>> {
+            let mut builder = ResponseStatusBuilder::new(_fbb);
+            builder.add_status(args.status);
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn status(&self) -> u16 {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<u16>(ResponseStatus::VT_STATUS, Some(0))
+                    .unwrap()
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for ResponseStatus<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<u16>("status", Self::VT_STATUS, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct ResponseStatusArgs {
+        pub status: u16,
+    }
+    impl<'a> Default for ResponseStatusArgs {
+        #[inline]
+        fn default() -> Self {
+            ResponseStatusArgs { status: 0 }
+        }
+    }
+
+    pub struct ResponseStatusBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> ResponseStatusBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_status(&mut self, status: u16) {
+            self.fbb_
+                .push_slot::<u16>(ResponseStatus::VT_STATUS, status, 0);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> ResponseStatusBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            ResponseStatusBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<ResponseStatus<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for ResponseStatus<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("ResponseStatus");
+            ds.field("status", &self.status());
+            ds.finish()
+        }
+    }
+    pub enum RoomMessageOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct RoomMessage<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for RoomMessage<'a> {
+        type Inner = RoomMessage<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> RoomMessage<'a> {
+        pub const VT_ROOM: flatbuffers::VOffsetT = 4;
+        pub const VT_DATA: flatbuffers::VOffsetT = 6;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            RoomMessage { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args RoomMessageArgs<'args>,
+        ) -> flatbuffers::WIPOffset<RoomMessage<'bldr>> {
+            let mut builder = RoomMessageBuilder::new(_fbb);
+            if let Some(x) = args.data {
+                builder.add_data(x);
+            }
+            if let Some(x) = args.room {
+                builder.add_room(x);
+            }
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn room(&self) -> &'a str {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<&str>>(RoomMessage::VT_ROOM, None)
+                    .unwrap()
+            }
+        }
+        #[inline]
+        pub fn data(&self) -> Option<flatbuffers::Vector<'a, u8>> {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(
+                        RoomMessage::VT_DATA,
+                        None,
+                    )
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for RoomMessage<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<&str>>("room", Self::VT_ROOM, true)?
+                .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, u8>>>(
+                    "data",
+                    Self::VT_DATA,
+                    false,
+                )?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct RoomMessageArgs<'a> {
+        pub room: Option<flatbuffers::WIPOffset<&'a str>>,
+        pub data: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+    }
+    impl<'a> Default for RoomMessageArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            RoomMessageArgs {
+                room: None, // required field
+                data: None,
+            }
+        }
+    }
+
+    pub struct RoomMessageBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> RoomMessageBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_room(&mut self, room: flatbuffers::WIPOffset<&'b str>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(RoomMessage::VT_ROOM, room);
+        }
+        #[inline]
+        pub fn add_data(&mut self, data: flatbuffers::WIPOffset<flatbuffers::Vector<'b, u8>>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(RoomMessage::VT_DATA, data);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> RoomMessageBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            RoomMessageBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<RoomMessage<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            self.fbb_.required(o, RoomMessage::VT_ROOM, "room");
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for RoomMessage<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("RoomMessage");
+            ds.field("room", &self.room());
+            ds.field("data", &self.data());
+            ds.finish()
+        }
+    }
+    pub enum AbiVersionOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct AbiVersion<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for AbiVersion<'a> {
+        type Inner = AbiVersion<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> AbiVersion<'a> {
+        pub const VT_VERSION: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            AbiVersion { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args AbiVersionArgs,
+        ) -> flatbuffers::WIPOffset<AbiVersion<'bldr>> {
+            let mut builder = AbiVersionBuilder::new(_fbb);
+            builder.add_version(args.version);
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn version(&self) -> u16 {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<u16>(AbiVersion::VT_VERSION, Some(0))
+                    .unwrap()
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for AbiVersion<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<u16>("version", Self::VT_VERSION, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct AbiVersionArgs {
+        pub version: u16,
+    }
+    impl<'a> Default for AbiVersionArgs {
+        #[inline]
+        fn default() -> Self {
+            AbiVersionArgs { version: 0 }
+        }
+    }
+
+    pub struct AbiVersionBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> AbiVersionBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_version(&mut self, version: u16) {
+            self.fbb_
+                .push_slot::<u16>(AbiVersion::VT_VERSION, version, 0);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> AbiVersionBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            AbiVersionBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<AbiVersion<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for AbiVersion<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("AbiVersion");
+            ds.field("version", &self.version());
+            ds.finish()
+        }
+    }
+    pub enum MetricOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct Metric<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for Metric<'a> {
+        type Inner = Metric<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> Metric<'a> {
+        pub const VT_NAME: flatbuffers::VOffsetT = 4;
+        pub const VT_VALUE: flatbuffers::VOffsetT = 6;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            Metric { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args MetricArgs<'args>,
+        ) -> flatbuffers::WIPOffset<Metric<'bldr>> {
+            let mut builder = MetricBuilder::new(_fbb);
+            builder.add_value(args.value);
+            if let Some(x) = args.name {
+                builder.add_name(x);
+            }
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn name(&self) -> &'a str {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<&str>>(Metric::VT_NAME, None)
+                    .unwrap()
+            }
+        }
+        #[inline]
+        pub fn value(&self) -> f64 {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe { self._tab.get::<f64>(Metric::VT_VALUE, Some(1.0)).unwrap() }
+        }
+    }
+
+    impl flatbuffers::Verifiable for Metric<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<&str>>("name", Self::VT_NAME, true)?
+                .visit_field::<f64>("value", Self::VT_VALUE, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct MetricArgs<'a> {
+        pub name: Option<flatbuffers::WIPOffset<&'a str>>,
+        pub value: f64,
+    }
+    impl<'a> Default for MetricArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            MetricArgs {
+                name: None, // required field
+                value: 1.0,
+            }
+        }
+    }
+
+    pub struct MetricBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> MetricBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_name(&mut self, name: flatbuffers::WIPOffset<&'b str>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(Metric::VT_NAME, name);
+        }
+        #[inline]
+        pub fn add_value(&mut self, value: f64) {
+            self.fbb_.push_slot::<f64>(Metric::VT_VALUE, value, 1.0);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> MetricBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            MetricBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<Metric<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            self.fbb_.required(o, Metric::VT_NAME, "name");
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for Metric<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("Metric");
+            ds.field("name", &self.name());
+            ds.field("value", &self.value());
+            ds.finish()
+        }
+    }
+    pub enum TextOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct Text<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for Text<'a> {
+        type Inner = Text<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> Text<'a> {
+        pub const VT_VALUE: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            Text { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args TextArgs<'args>,
+        ) -> flatbuffers::WIPOffset<Text<'bldr>> {
+            let mut builder = TextBuilder::new(_fbb);
+            if let Some(x) = args.value {
+                builder.add_value(x);
+            }
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn value(&self) -> &'a str {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<&str>>(Text::VT_VALUE, None)
+                    .unwrap()
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for Text<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<&str>>("value", Self::VT_VALUE, true)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct TextArgs<'a> {
+        pub value: Option<flatbuffers::WIPOffset<&'a str>>,
+    }
+    impl<'a> Default for TextArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            TextArgs {
+                value: None, // required field
+            }
+        }
+    }
+
+    pub struct TextBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> TextBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_value(&mut self, value: flatbuffers::WIPOffset<&'b str>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(Text::VT_VALUE, value);
+        }
+        #[inline]
+        pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>) -> TextBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            TextBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<Text<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            self.fbb_.required(o, Text::VT_VALUE, "value");
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for Text<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("Text");
+            ds.field("value", &self.value());
+            ds.finish()
+        }
+    }
+    pub enum BytesOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct Bytes<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for Bytes<'a> {
+        type Inner = Bytes<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> Bytes<'a> {
+        pub const VT_DATA: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            Bytes { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args BytesArgs<'args>,
+        ) -> flatbuffers::WIPOffset<Bytes<'bldr>> {
+            let mut builder = BytesBuilder::new(_fbb);
+            if let Some(x) = args.data {
+                builder.add_data(x);
+            }
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn data(&self) -> Option<flatbuffers::Vector<'a, u8>> {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(
+                        Bytes::VT_DATA,
+                        None,
+                    )
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for Bytes<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, u8>>>(
+                    "data",
+                    Self::VT_DATA,
+                    false,
+                )?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct BytesArgs<'a> {
+        pub data: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+    }
+    impl<'a> Default for BytesArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            BytesArgs { data: None }
+        }
+    }
+
+    pub struct BytesBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> BytesBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_data(&mut self, data: flatbuffers::WIPOffset<flatbuffers::Vector<'b, u8>>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(Bytes::VT_DATA, data);
+        }
+        #[inline]
+        pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>) -> BytesBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            BytesBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<Bytes<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for Bytes<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("Bytes");
+            ds.field("data", &self.data());
+            ds.finish()
+        }
+    }
+    pub enum NumberOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct Number<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for Number<'a> {
+        type Inner = Number<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> Number<'a> {
+        pub const VT_VALUE: flatbuffers::VOffsetT = 4;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            Number { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args NumberArgs,
+        ) -> flatbuffers::WIPOffset<Number<'bldr>> {
+            let mut builder = NumberBuilder::new(_fbb);
+            builder.add_value(args.value);
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn value(&self) -> i64 {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe { self._tab.get::<i64>(Number::VT_VALUE, Some(0)).unwrap() }
+        }
+    }
+
+    impl flatbuffers::Verifiable for Number<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<i64>("value", Self::VT_VALUE, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct NumberArgs {
+        pub value: i64,
+    }
+    impl<'a> Default for NumberArgs {
+        #[inline]
+        fn default() -> Self {
+            NumberArgs { value: 0 }
+        }
+    }
+
+    pub struct NumberBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> NumberBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_value(&mut self, value: i64) {
+            self.fbb_.push_slot::<i64>(Number::VT_VALUE, value, 0);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> NumberBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            NumberBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<Number<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for Number<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("Number");
+            ds.field("value", &self.value());
+            ds.finish()
+        }
+    }
+    pub enum BodyChunkOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct BodyChunk<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for BodyChunk<'a> {
+        type Inner = BodyChunk<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> BodyChunk<'a> {
+        pub const VT_DATA: flatbuffers::VOffsetT = 4;
+        pub const VT_LAST: flatbuffers::VOffsetT = 6;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            BodyChunk { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args BodyChunkArgs<'args>,
+        ) -> flatbuffers::WIPOffset<BodyChunk<'bldr>> {
+            let mut builder = BodyChunkBuilder::new(_fbb);
+            if let Some(x) = args.data {
+                builder.add_data(x);
+            }
+            builder.add_last(args.last);
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn data(&self) -> Option<flatbuffers::Vector<'a, u8>> {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(
+                        BodyChunk::VT_DATA,
+                        None,
+                    )
+            }
+        }
+        #[inline]
+        pub fn last(&self) -> bool {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<bool>(BodyChunk::VT_LAST, Some(false))
+                    .unwrap()
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for BodyChunk<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, u8>>>(
+                    "data",
+                    Self::VT_DATA,
+                    false,
+                )?
+                .visit_field::<bool>("last", Self::VT_LAST, false)?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct BodyChunkArgs<'a> {
+        pub data: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+        pub last: bool,
+    }
+    impl<'a> Default for BodyChunkArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            BodyChunkArgs {
+                data: None,
+                last: false,
+            }
+        }
+    }
+
+    pub struct BodyChunkBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> BodyChunkBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_data(&mut self, data: flatbuffers::WIPOffset<flatbuffers::Vector<'b, u8>>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(BodyChunk::VT_DATA, data);
+        }
+        #[inline]
+        pub fn add_last(&mut self, last: bool) {
+            self.fbb_.push_slot::<bool>(BodyChunk::VT_LAST, last, false);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> BodyChunkBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            BodyChunkBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<BodyChunk<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for BodyChunk<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("BodyChunk");
+            ds.field("data", &self.data());
+            ds.field("last", &self.last());
+            ds.finish()
+        }
+    }
+    pub enum CloseFrameOffset {}
+    #[derive(Copy, Clone, PartialEq)]
+
+    pub struct CloseFrame<'a> {
+        pub _tab: flatbuffers::Table<'a>,
+    }
+
+    impl<'a> flatbuffers::Follow<'a> for CloseFrame<'a> {
+        type Inner = CloseFrame<'a>;
+        #[inline]
+        unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            Self {
+                _tab: flatbuffers::Table::new(buf, loc),
+            }
+        }
+    }
+
+    impl<'a> CloseFrame<'a> {
+        pub const VT_CODE: flatbuffers::VOffsetT = 4;
+        pub const VT_REASON: flatbuffers::VOffsetT = 6;
+
+        #[inline]
+        pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+            CloseFrame { _tab: table }
+        }
+        #[allow(unused_mut)]
+        pub fn create<
+            'bldr: 'args,
+            'args: 'mut_bldr,
+            'mut_bldr,
+            A: flatbuffers::Allocator + 'bldr,
+        >(
+            _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr, A>,
+            args: &'args CloseFrameArgs<'args>,
+        ) -> flatbuffers::WIPOffset<CloseFrame<'bldr>> {
+            let mut builder = CloseFrameBuilder::new(_fbb);
+            if let Some(x) = args.reason {
+                builder.add_reason(x);
+            }
+            builder.add_code(args.code);
+            builder.finish()
+        }
+
+        #[inline]
+        pub fn code(&self) -> u16 {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe { self._tab.get::<u16>(CloseFrame::VT_CODE, Some(0)).unwrap() }
+        }
+        #[inline]
+        pub fn reason(&self) -> Option<&'a str> {
+            // Safety:
+            // Created from valid Table for this object
+            // which contains a valid value in this slot
+            unsafe {
+                self._tab
+                    .get::<flatbuffers::ForwardsUOffset<&str>>(CloseFrame::VT_REASON, None)
+            }
+        }
+    }
+
+    impl flatbuffers::Verifiable for CloseFrame<'_> {
+        #[inline]
+        fn run_verifier(
+            v: &mut flatbuffers::Verifier,
+            pos: usize,
+        ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+            use self::flatbuffers::Verifiable;
+            v.visit_table(pos)?
+                .visit_field::<u16>("code", Self::VT_CODE, false)?
+                .visit_field::<flatbuffers::ForwardsUOffset<&str>>(
+                    "reason",
+                    Self::VT_REASON,
+                    false,
+                )?
+                .finish();
+            Ok(())
+        }
+    }
+    pub struct CloseFrameArgs<'a> {
+        pub code: u16,
+        pub reason: Option<flatbuffers::WIPOffset<&'a str>>,
+    }
+    impl<'a> Default for CloseFrameArgs<'a> {
+        #[inline]
+        fn default() -> Self {
+            CloseFrameArgs {
+                code: 0,
+                reason: None,
+            }
+        }
+    }
+
+    pub struct CloseFrameBuilder<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> {
+        fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+    }
+    impl<'a: 'b, 'b, A: flatbuffers::Allocator + 'a> CloseFrameBuilder<'a, 'b, A> {
+        #[inline]
+        pub fn add_code(&mut self, code: u16) {
+            self.fbb_.push_slot::<u16>(CloseFrame::VT_CODE, code, 0);
+        }
+        #[inline]
+        pub fn add_reason(&mut self, reason: flatbuffers::WIPOffset<&'b str>) {
+            self.fbb_
+                .push_slot_always::<flatbuffers::WIPOffset<_>>(CloseFrame::VT_REASON, reason);
+        }
+        #[inline]
+        pub fn new(
+            _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a, A>,
+        ) -> CloseFrameBuilder<'a, 'b, A> {
+            let start = _fbb.start_table();
+            CloseFrameBuilder {
+                fbb_: _fbb,
+                start_: start,
+            }
+        }
+        #[inline]
+        pub fn finish(self) -> flatbuffers::WIPOffset<CloseFrame<'a>> {
+            let o = self.fbb_.end_table(self.start_);
+            flatbuffers::WIPOffset::new(o.value())
+        }
+    }
+
+    impl core::fmt::Debug for CloseFrame<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let mut ds = f.debug_struct("CloseFrame");
+            ds.field("code", &self.code());
+            ds.field("reason", &self.reason());
+            ds.finish()
+        }
+    }
 } // pub mod nylon_plugin